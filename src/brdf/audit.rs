@@ -0,0 +1,110 @@
+use brdf::{Brdf, Material};
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+use std::fmt;
+
+// cosines of the view angles to the normal the albedo is measured at, from head-on to grazing
+pub const AUDIT_VIEW_COSINES: [f32; 6] = [1.0, 0.8, 0.6, 0.4, 0.2, 0.05];
+// albedo this far above one is energy gain, below it's the error of the integration
+pub const ENERGY_GAIN_TOLERANCE: f32 = 0.01;
+// the integration grid: lobe picks times directions per side
+const LOBE_STRATA: usize = 8;
+const DIRECTION_STRATA: usize = 32;
+
+// Reflectance of a material integrated over all the incoming directions, for every view angle of
+// `AUDIT_VIEW_COSINES`. A material reflecting more than it gets makes every bounce brighter, the
+// paths stop converging and the render fills with fireflies, while nothing in the material looks wrong.
+// Only the material itself is audited, the textures resolved per hit can scale it up or down
+#[derive(Debug, Clone)]
+pub struct AlbedoAudit {
+    pub albedo: Vec<(f32, Vec3f)>, // view cosine and the albedo seen from there
+}
+
+impl AlbedoAudit {
+    pub fn new(material: &Material) -> AlbedoAudit {
+        AlbedoAudit {
+            albedo: AUDIT_VIEW_COSINES.iter().map(|&cos_view| (cos_view, directional_albedo(material, cos_view))).collect()
+        }
+    }
+
+    // the brightest component over all the view angles
+    pub fn max_albedo(&self) -> f32 {
+        self.albedo.iter().fold(0.0, |max: f32, &(_, albedo)| max.max(albedo.fold(f32::max)))
+    }
+
+    pub fn gains_energy(&self) -> bool {
+        self.max_albedo() > 1.0 + ENERGY_GAIN_TOLERANCE
+    }
+}
+
+impl fmt::Display for AlbedoAudit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(cos_view, albedo) in &self.albedo {
+            write!(f, " {:>4.0}°: {:.3}", cos_view.acos().to_degrees(), albedo.fold(f32::max))?;
+        }
+        if self.gains_energy() {
+            write!(f, "  gains energy")?;
+        }
+        Ok(())
+    }
+}
+
+// Fraction of the light reflected towards a view `cos_view` off the normal: the integral of
+// brdf * cos over the hemisphere, estimated with the material's own importance sampling on a
+// stratified grid, so it's exact for lambert and needs no random numbers
+pub fn directional_albedo(material: &Material, cos_view: f32) -> Vec3f {
+    let normal = Vec3f::new(0.0, 0.0, 1.0);
+    let view = Vec3f::new((1.0 - cos_view * cos_view).max(0.0).sqrt(), 0.0, -cos_view);
+    let brdf = match Brdf::new(&view, &normal, material) {
+        Some(brdf) => brdf,
+        None       => return Vec3f::zero()
+    };
+    let stratum = |i: usize, strata: usize| (i as f32 + 0.5) / strata as f32;
+    let mut sum = Vec3f::zero();
+    for lobe in 0..LOBE_STRATA {
+        for i in 0..DIRECTION_STRATA {
+            for j in 0..DIRECTION_STRATA {
+                let rnd = (stratum(lobe, LOBE_STRATA), stratum(i, DIRECTION_STRATA), stratum(j, DIRECTION_STRATA));
+                if let Some(sample) = brdf.sample(rnd) {
+                    if sample.pdf > 0.0 {
+                        sum = sum + sample.radiance / sample.pdf;
+                    }
+                }
+            }
+        }
+    }
+    sum / (LOBE_STRATA * DIRECTION_STRATA * DIRECTION_STRATA) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlbedoAudit, directional_albedo};
+    use brdf::Material;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::Vec3f;
+
+    #[test]
+    fn bright_materials_gain_energy() {
+        for &cos_view in [1.0, 0.3].iter() {
+            let albedo = directional_albedo(&WHITE_DIFFUSE, cos_view);
+            assert!((albedo.x - 0.99).abs() < 1e-3, "{:?}", albedo);
+        }
+        assert!(!AlbedoAudit::new(&WHITE_DIFFUSE).gains_energy());
+
+        let mut plastic = Material::new_identity();
+        plastic.diffuse = Vec3f::new(0.5, 0.5, 0.5);
+        plastic.specular = Vec3f::new(0.4, 0.4, 0.4);
+        plastic.phong_exp = 20.0;
+        let audit = AlbedoAudit::new(&plastic);
+        assert!(!audit.gains_energy(), "{}", audit);
+        // the lobe dips under the horizon at grazing angles and loses some of its light
+        assert!(audit.albedo[0].1.x > audit.albedo[5].1.x);
+
+        // the lobes share the albedo out, it's a single overbright one that breaks
+        plastic.diffuse = Vec3f::new(1.2, 0.5, 0.5);
+        plastic.specular = Vec3f::new(0.0, 0.0, 0.0);
+        let audit = AlbedoAudit::new(&plastic);
+        assert!(audit.gains_energy(), "{}", audit);
+        assert!(audit.to_string().contains("gains energy"));
+    }
+}
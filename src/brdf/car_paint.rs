@@ -0,0 +1,179 @@
+use brdf::{BrdfEval, Material, folded_lobe_pdf, lobe_pdf, sample_folded_lobe};
+use geometry::SurfaceIntersection;
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+use std::f32::consts::FRAC_1_PI;
+use utility::{cos_hemisphere_sample, hash_u64, luminance, pow_cos_hemisphere_sample};
+
+// Metallic paint: the diffuse and phong lobes of the material are the base coat,
+// sparkling mirror flakes are embedded into it and everything is under a clear coat
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CarPaint {
+    pub flake_color: Vec3f,
+    pub flake_density: f32, // share of flake cells holding a flake
+    pub flake_scale: f32, // flake cells per uv unit
+    pub flake_spread: f32, // phong exponent of flake tilts around the normal
+    pub flake_exp: f32, // phong exponent of the reflection of a single flake
+    pub clearcoat: f32, // coat reflectance at normal incidence, 0.04 for ior 1.5
+    pub clearcoat_exp: f32,
+    pub flake_normal: Option<Vec3f>, // local normal of the flake under the hit point, set by at()
+}
+
+struct LobeWeights {
+    diffuse: f32,
+    phong: f32,
+    flakes: f32,
+    clearcoat: f32,
+}
+
+impl CarPaint {
+    pub fn new(flake_color: Vec3f) -> CarPaint {
+        CarPaint {
+            flake_color: flake_color,
+            flake_density: 0.3,
+            flake_scale: 400.0,
+            flake_spread: 20.0,
+            flake_exp: 2000.0,
+            clearcoat: 0.04,
+            clearcoat_exp: 1000.0,
+            flake_normal: None,
+        }
+    }
+
+    // picks the flake under the hit point, the same cell always gets the same flake
+    pub fn at(&self, isect: &SurfaceIntersection) -> CarPaint {
+        let cell_x = (isect.uv.x * self.flake_scale).floor() as i64 as u64;
+        let cell_y = (isect.uv.y * self.flake_scale).floor() as i64 as u64;
+        let hash = hash_u64(cell_x ^ hash_u64(cell_y));
+        let rnd = |shift: u32| ((hash >> shift) & 0x1FFFFF) as f32 / (1 << 21) as f32;
+
+        let mut paint = *self;
+        paint.flake_normal = if rnd(0) < self.flake_density {
+            Some(pow_cos_hemisphere_sample(self.flake_spread, (rnd(21), rnd(42))))
+        } else {
+            None
+        };
+        paint
+    }
+
+    pub fn eval_local(&self, material: &Material, wi_local: &Vec3f, wo_local: &Vec3f) -> BrdfEval {
+        let weights = match self.lobe_weights(material, wo_local) {
+            Some(weights) => weights,
+            None          => return BrdfEval { radiance: Zero::zero(), pdf: 0.0 }
+        };
+        let refl_local = wo_local.reflect_local();
+        let transmitted = (1.0 - self.fresnel(wo_local.z)) * (1.0 - self.fresnel(wi_local.z));
+
+        let diffuse_pdf = wi_local.z.max(0.0) * FRAC_1_PI;
+        let phong_pdf = folded_lobe_pdf(material.phong_exp, wi_local, &refl_local);
+        let clearcoat_pdf = folded_lobe_pdf(self.clearcoat_exp, wi_local, &refl_local);
+        let mut base = material.diffuse * diffuse_pdf
+                     + material.specular * lobe_pdf(material.phong_exp, wi_local, &refl_local);
+        let mut pdf = weights.diffuse * diffuse_pdf
+                    + weights.phong * phong_pdf
+                    + weights.clearcoat * clearcoat_pdf;
+        if let Some(flake_refl) = self.flake_reflection(wo_local) {
+            base = base + self.flake_color * lobe_pdf(self.flake_exp, wi_local, &flake_refl);
+            pdf += weights.flakes * folded_lobe_pdf(self.flake_exp, wi_local, &flake_refl);
+        }
+
+        let coat = self.fresnel(wo_local.z) * lobe_pdf(self.clearcoat_exp, wi_local, &refl_local);
+        BrdfEval {
+            radiance: base * transmitted + Vec3f::new(coat, coat, coat),
+            pdf: pdf
+        }
+    }
+
+    pub fn sample_local(&self, material: &Material, wo_local: &Vec3f, rnd: (f32, f32, f32)) -> Option<Vec3f> {
+        let weights = match self.lobe_weights(material, wo_local) {
+            Some(weights) => weights,
+            None          => return None
+        };
+        let sample_rnds = (rnd.1, rnd.2);
+        let refl_local = wo_local.reflect_local();
+
+        let mut pick = rnd.0;
+        if pick < weights.diffuse {
+            return Some(cos_hemisphere_sample(sample_rnds));
+        }
+        pick -= weights.diffuse;
+        if pick < weights.phong {
+            return Some(sample_folded_lobe(material.phong_exp, &refl_local, sample_rnds));
+        }
+        pick -= weights.phong;
+        if pick < weights.flakes {
+            if let Some(flake_refl) = self.flake_reflection(wo_local) {
+                return Some(sample_folded_lobe(self.flake_exp, &flake_refl, sample_rnds));
+            }
+        }
+        Some(sample_folded_lobe(self.clearcoat_exp, &refl_local, sample_rnds))
+    }
+
+    // Schlick's approximation
+    fn fresnel(&self, cos_theta: f32) -> f32 {
+        let c = 1.0 - cos_theta.max(0.0).min(1.0);
+        self.clearcoat + (1.0 - self.clearcoat) * c * c * c * c * c
+    }
+
+    // mirror direction of the flake, None if there's no flake or it reflects into the surface
+    fn flake_reflection(&self, wo_local: &Vec3f) -> Option<Vec3f> {
+        self.flake_normal.and_then(|m| {
+            let refl = m * (2.0 * wo_local.dot(&m)) - *wo_local;
+            if refl.z > 0.0 { Some(refl) } else { None }
+        })
+    }
+
+    // lobe selection probabilities, proportional to the light they reflect at normal incidence
+    fn lobe_weights(&self, material: &Material, wo_local: &Vec3f) -> Option<LobeWeights> {
+        let coat = self.fresnel(wo_local.z);
+        let flakes = if self.flake_reflection(wo_local).is_some() { luminance(&self.flake_color) } else { 0.0 };
+        let weights = LobeWeights {
+            diffuse: luminance(&material.diffuse) * (1.0 - coat),
+            phong: luminance(&material.specular) * (1.0 - coat),
+            flakes: flakes * (1.0 - coat),
+            clearcoat: coat,
+        };
+        let total = weights.diffuse + weights.phong + weights.flakes + weights.clearcoat;
+        if total < 1e-9 {
+            None
+        } else {
+            Some(LobeWeights {
+                diffuse: weights.diffuse / total,
+                phong: weights.phong / total,
+                flakes: weights.flakes / total,
+                clearcoat: weights.clearcoat / total,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CarPaint;
+    use brdf::{Brdf, Material};
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    #[test]
+    fn car_paint_sample_pdf_matches_eval() {
+        let mut paint = CarPaint::new(Vec3f::new(0.8, 0.8, 0.8));
+        paint.flake_normal = Some(Vec3f::new(0.1, 0.05, 1.0).normalize());
+        let mut material = Material::new_identity();
+        material.diffuse = Vec3f::new(0.5, 0.05, 0.05);
+        material.specular = Vec3f::new(0.2, 0.2, 0.2);
+        material.phong_exp = 50.0;
+        material.car_paint = Some(paint);
+
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let out_dir = Vec3f::new(0.4, 0.1, -1.0).normalize();
+        let brdf = Brdf::new(&out_dir, &normal, &material).unwrap();
+        for i in 0..64 {
+            let rnd = ((i as f32 + 0.5) / 64.0, (i as f32 * 5.0 % 64.0 + 0.5) / 64.0, 0.4);
+            if let Some(sample) = brdf.sample(rnd) {
+                let eval = brdf.eval(&sample.wi).unwrap();
+                assert!(sample.wi.dot(&normal) > 0.0);
+                assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use math::Vec3f;
+
+// Metal: the Fresnel term of the complex index of refraction eta + i k, per channel. Takes the place of
+// Schlick's term in the microfacet lobe and colors the phong lobe and the ideal mirror by the Fresnel
+// of the view direction; `specular` and `mirror` stay the weights of these, white for a bare metal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conductor {
+    pub eta: Vec3f,
+    pub k: Vec3f, // extinction, how fast the light dies out in the metal
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3f, k: Vec3f) -> Conductor {
+        Conductor { eta: eta, k: k }
+    }
+
+    // The artist friendly mapping of Gulbrandsen (2014): `reflectance` is the color at normal incidence,
+    // `edge_tint` bends the color toward the grazing angles, white for the plain Fresnel of the metal
+    pub fn from_reflectance(reflectance: Vec3f, edge_tint: Vec3f) -> Conductor {
+        let channel = |r: f32, g: f32| {
+            let r = r.max(0.0).min(0.99);
+            let n = g * (1.0 - r) / (1.0 + r) + (1.0 - g) * (1.0 + r.sqrt()) / (1.0 - r.sqrt());
+            let k2 = (r * (n + 1.0) * (n + 1.0) - (n - 1.0) * (n - 1.0)) / (1.0 - r);
+            (n, k2.max(0.0).sqrt())
+        };
+        let (x, y, z) = (channel(reflectance.x, edge_tint.x), channel(reflectance.y, edge_tint.y),
+                         channel(reflectance.z, edge_tint.z));
+        Conductor { eta: Vec3f::new(x.0, y.0, z.0), k: Vec3f::new(x.1, y.1, z.1) }
+    }
+
+    // share of the light reflected at the angle of `cos_i` from the normal, from the air
+    pub fn fresnel(&self, cos_i: f32) -> Vec3f {
+        let cos_i = cos_i.max(0.0).min(1.0);
+        Vec3f::new(fresnel_conductor(cos_i, self.eta.x, self.k.x),
+                   fresnel_conductor(cos_i, self.eta.y, self.k.y),
+                   fresnel_conductor(cos_i, self.eta.z, self.k.z))
+    }
+}
+
+// the exact unpolarized Fresnel of one wavelength
+fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let (t1, t2) = (a2b2 + cos2, 2.0 * cos_i * a);
+    let perpendicular = (t1 - t2) / (t1 + t2);
+    let (t3, t4) = (cos2 * a2b2 + sin2 * sin2, t2 * sin2);
+    let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+    0.5 * (parallel + perpendicular)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Conductor;
+    use materials_and_colors::GOLD;
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    #[test]
+    fn gold_is_yellow_head_on_and_white_at_grazing_angles() {
+        let gold = GOLD.conductor.unwrap();
+        let head_on = gold.fresnel(1.0);
+        assert!(head_on.x > 0.9 && head_on.z < 0.5 && head_on.y > head_on.z, "{:?}", head_on);
+        let grazing = gold.fresnel(0.01);
+        assert!(grazing.fold(f32::min) > 0.9, "{:?}", grazing);
+
+        // the artist friendly colors come back at normal incidence
+        let reflectance = Vec3f::new(0.95, 0.64, 0.54);
+        let copper = Conductor::from_reflectance(reflectance, Vec3f::new(1.0, 0.8, 0.6));
+        assert!((copper.fresnel(1.0) - reflectance).norm() < 1e-3, "{:?}", copper.fresnel(1.0));
+    }
+}
@@ -0,0 +1,135 @@
+use brdf::{BrdfEval, Microfacet};
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+
+// Smooth boundary of a transparent medium, glass or water: the Fresnel term splits the light between
+// the mirror reflection and the refraction, both ideal. Replaces all the other lobes of the material.
+// Hits from the inside are fine, the normal is flipped and the index inverted for them.
+// With microfacets it's frosted glass: both go through the microfacet normals (Walter et al. 2007)
+// and spread into lobes, which are no more delta and get the light sampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dielectric {
+    pub ior: f32, // of the inside, the outside is air
+    pub transmittance: Vec3f, // tints the refracted light, white for clear glass
+    pub rough: Option<Microfacet>, // None for the smooth boundary
+}
+
+impl Dielectric {
+    pub fn new(ior: f32) -> Dielectric {
+        Dielectric { ior: ior, transmittance: Vec3f::new(1.0, 1.0, 1.0), rough: None }
+    }
+
+    pub fn with_transmittance(mut self, transmittance: Vec3f) -> Dielectric {
+        self.transmittance = transmittance;
+        self
+    }
+
+    pub fn with_roughness(mut self, microfacet: Microfacet) -> Dielectric {
+        self.rough = Some(microfacet);
+        self
+    }
+
+    // bsdf * |cos(theta_i)| of the rough boundary and the pdf of `sample_rough_local`: the reflection
+    // when `wi_local` is above the surface, the refraction when it's under
+    pub fn eval_rough_local(&self, rough: &Microfacet, wi_local: &Vec3f, wo_local: &Vec3f, eta: f32) -> BrdfEval {
+        let nothing = BrdfEval { radiance: Zero::zero(), pdf: 0.0 };
+        let reflected = wi_local.z > 0.0;
+        // the half vector of the refraction weighs the directions by the indices of their sides
+        let half = if reflected { *wi_local + *wo_local } else { *wo_local + *wi_local * eta };
+        if half.norm() < 1e-6 {
+            return nothing;
+        }
+        let m = if half.z < 0.0 { -half.normalize() } else { half.normalize() };
+        let (cos_o, cos_i) = (wo_local.dot(&m), wi_local.dot(&m));
+        // both directions are on the same sides of the microfacet as of the surface
+        if cos_o <= 0.0 || cos_i * wi_local.z <= 0.0 {
+            return nothing;
+        }
+        let fresnel = fresnel_dielectric(cos_o, eta);
+        let d = rough.d(&m);
+        let g2 = rough.g2(wi_local, wo_local);
+        let normal_pdf = rough.normal_pdf(wo_local, &m);
+        if reflected {
+            BrdfEval {
+                radiance: Vec3f::new(1.0, 1.0, 1.0) * (fresnel * d * g2 / (4.0 * wo_local.z)),
+                pdf: fresnel * normal_pdf / (4.0 * cos_o)
+            }
+        } else {
+            // the change from the microfacet normal to the refracted direction brings in eta^2, which
+            // cancels with the squeeze of the radiance on the far side in the radiance but not in the pdf
+            let denom = cos_o + eta * cos_i;
+            let denom2 = denom * denom;
+            BrdfEval {
+                radiance: self.transmittance * ((1.0 - fresnel) * d * g2 * cos_o * -cos_i / (wo_local.z * denom2)),
+                pdf: (1.0 - fresnel) * normal_pdf * eta * eta * -cos_i / denom2
+            }
+        }
+    }
+
+    // reflected or refracted by a sampled microfacet normal, picked by the Fresnel term of the normal;
+    // None when the direction would be on the wrong side of the microfacet
+    pub fn sample_rough_local(&self, rough: &Microfacet, wo_local: &Vec3f, eta: f32, rnd: (f32, f32, f32))
+        -> Option<Vec3f> {
+        let m = rough.sample_normal(wo_local, (rnd.1, rnd.2));
+        let cos_o = wo_local.dot(&m);
+        if cos_o <= 0.0 {
+            None
+        } else if rnd.0 < fresnel_dielectric(cos_o, eta) {
+            Some(m * (2.0 * cos_o) - *wo_local)
+        } else {
+            refract(wo_local, &m, eta)
+        }
+    }
+}
+
+// Share of the light reflected by the boundary, `cos_i` is of the side the light is looked at from and
+// `eta` the index of the far side over the one of the near side; 1 past the critical angle
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+// `wo_local` bent through the boundary into the far side (negative z), None past the critical angle
+pub fn refract_local(wo_local: &Vec3f, eta: f32) -> Option<Vec3f> {
+    refract(wo_local, &Vec3f::new(0.0, 0.0, 1.0), eta)
+}
+
+// the same through the boundary of the normal `m`
+pub fn refract(wo: &Vec3f, m: &Vec3f, eta: f32) -> Option<Vec3f> {
+    let cos_o = wo.dot(m);
+    let sin2_t = (1.0 - cos_o * cos_o).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        None
+    } else {
+        Some(*m * (cos_o / eta - (1.0 - sin2_t).sqrt()) - *wo / eta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fresnel_dielectric, refract_local};
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    #[test]
+    fn fresnel_and_snell_of_glass() {
+        // 4% at normal incidence from either side
+        assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-6);
+        assert!((fresnel_dielectric(1.0, 1.0 / 1.5) - 0.04).abs() < 1e-6);
+        // everything at grazing angles, and past the critical angle of 41.8 degrees from the inside
+        assert!(fresnel_dielectric(0.01, 1.5) > 0.9);
+        assert_eq!(fresnel_dielectric(45f32.to_radians().cos(), 1.0 / 1.5), 1.0);
+        assert!(refract_local(&Vec3f::new(1.0, 0.0, 1.0).normalize(), 1.0 / 1.5).is_none());
+
+        let wo = Vec3f::new(0.6, 0.0, 0.8);
+        let wi = refract_local(&wo, 1.5).unwrap();
+        assert!((wi.norm() - 1.0).abs() < 1e-6 && wi.z < 0.0);
+        assert!((wo.x - 1.5 * -wi.x).abs() < 1e-6, "{:?}", wi);
+    }
+}
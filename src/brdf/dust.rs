@@ -0,0 +1,53 @@
+use math::{Vec3f, clamp};
+use math::vector_traits::*;
+use std::f32::consts::FRAC_1_PI;
+
+// Asperity scattering of dust and fuzz (Koenderink & Pont): sparse scatterers over the surface,
+// which light up at grazing angles where the view crosses more of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dust {
+    pub color: Vec3f,
+    pub coverage: f32, // share of the surface under dust, in [0, 1]
+    pub up: Vec3f, // dust settles on surfaces facing this way
+    pub up_facing: f32, // 0 - even coverage, 1 - none on faces looking away from `up`
+}
+
+impl Dust {
+    pub fn new(color: Vec3f, coverage: f32) -> Dust {
+        Dust {
+            color: color,
+            coverage: coverage,
+            up: Vec3f::new(0.0, 1.0, 0.0),
+            up_facing: 0.5,
+        }
+    }
+
+    pub fn coverage_at(&self, normal: &Vec3f) -> f32 {
+        let facing = normal.dot(&self.up).max(0.0);
+        clamp(self.coverage * (1.0 - self.up_facing + self.up_facing * facing), 0.0, 1.0)
+    }
+
+    // brdf * cos(theta_i), the albedo goes from color / 2 head-on to color at grazing view
+    pub fn eval_local(&self, wi_local: &Vec3f, wo_local: &Vec3f) -> Vec3f {
+        let (cos_i, cos_o) = (wi_local.z, wo_local.z);
+        let scatterers = cos_i + cos_o - cos_i * cos_o;
+        self.color * (0.5 * FRAC_1_PI * cos_i / scatterers.max(1e-4))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dust;
+    use math::Vec3f;
+
+    #[test]
+    fn dust_settles_on_top() {
+        let dust = Dust::new(Vec3f::new(0.5, 0.5, 0.5), 0.8);
+        let top = dust.coverage_at(&Vec3f::new(0.0, 1.0, 0.0));
+        let side = dust.coverage_at(&Vec3f::new(1.0, 0.0, 0.0));
+        let bottom = dust.coverage_at(&Vec3f::new(0.0, -1.0, 0.0));
+        assert!((top - 0.8).abs() < 1e-6);
+        assert!(side < top);
+        assert_eq!(side, bottom);
+    }
+}
@@ -0,0 +1,256 @@
+use brdf::rgl::RglBrdf;
+use distribution::Distribution2D;
+use math::Vec3f;
+use math::vector_traits::*;
+use memory::{self, MemoryCategory, Reservation};
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::path::Path;
+use utility::luminance;
+
+// MERL layout: 90 theta_half x 90 theta_diff x 180 phi_diff, phi_diff is halved by reciprocity
+const THETA_H_RES: usize = 90;
+const THETA_D_RES: usize = 90;
+const PHI_D_RES: usize = 180;
+const MERL_SAMPLES_NB: usize = THETA_H_RES * THETA_D_RES * PHI_D_RES;
+const MERL_SCALE: [f32; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// importance sampling tables: one (phi_i, theta_i) table per theta_o bin
+const SAMPLING_THETA_O_BINS: usize = 16;
+const SAMPLING_THETA_BINS: usize = 32;
+const SAMPLING_PHI_BINS: usize = 64;
+
+// isotropic measured brdf tabulated in Rusinkiewicz half/diff coordinates
+pub struct MeasuredBrdf {
+    data: Vec<Vec3f>,
+    sampling: Vec<Distribution2D>,
+    _memory: Reservation<'static>,
+}
+
+impl MeasuredBrdf {
+    // loads the binary format of the MERL BRDF database
+    pub fn load_merl<P: AsRef<Path>>(path: P) -> io::Result<MeasuredBrdf> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut dims = [0usize; 3];
+        for dim in dims.iter_mut() {
+            let mut buf = [0u8; 4];
+            reader.read_exact(&mut buf)?;
+            *dim = (buf[0] as u32 | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24) as usize;
+        }
+        if dims[0] * dims[1] * dims[2] != MERL_SAMPLES_NB {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("unexpected MERL dimensions {:?}", dims)));
+        }
+
+        let bytes = MERL_SAMPLES_NB * mem::size_of::<Vec3f>();
+        let memory = memory::global().reserve(MemoryCategory::Texture, bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+        let mut raw = vec![0u8; MERL_SAMPLES_NB * 3 * 8];
+        reader.read_exact(&mut raw)?;
+        let channel = |c: usize, i: usize| -> f32 {
+            let offset = (c * MERL_SAMPLES_NB + i) * 8;
+            let mut bits = 0u64;
+            for b in 0..8 {
+                bits |= (raw[offset + b] as u64) << (8 * b);
+            }
+            // negative values mark missing measurements
+            (f64::from_bits(bits) as f32 * MERL_SCALE[c]).max(0.0)
+        };
+        let data = (0..MERL_SAMPLES_NB)
+            .map(|i| Vec3f::new(channel(0, i), channel(1, i), channel(2, i)))
+            .collect();
+
+        Ok(MeasuredBrdf::from_data(data, memory))
+    }
+
+    // Loads the RGB tensor files of the RGL material database. They are parametrized along the
+    // visible normals of each incident direction, which doesn't fit the half/diff table, so the brdf
+    // is evaluated at the centers of the MERL cells and sampled from there like the MERL data
+    pub fn load_rgl<P: AsRef<Path>>(path: P) -> io::Result<MeasuredBrdf> {
+        let rgl = RglBrdf::load(path)?;
+        let bytes = MERL_SAMPLES_NB * mem::size_of::<Vec3f>();
+        let memory = memory::global().reserve(MemoryCategory::Texture, bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+
+        let mut data = Vec::with_capacity(MERL_SAMPLES_NB);
+        for theta_h_idx in 0..THETA_H_RES {
+            let theta_h = ((theta_h_idx as f32 + 0.5) / THETA_H_RES as f32).powi(2) * FRAC_PI_2;
+            for theta_d_idx in 0..THETA_D_RES {
+                let theta_d = (theta_d_idx as f32 + 0.5) / THETA_D_RES as f32 * FRAC_PI_2;
+                for phi_d_idx in 0..PHI_D_RES {
+                    let phi_d = (phi_d_idx as f32 + 0.5) / PHI_D_RES as f32 * PI;
+                    let (wi_local, wo_local) = half_diff_directions(theta_h, theta_d, phi_d);
+                    data.push(if wo_local.z > 0.0 {
+                        rgl.eval(&wi_local, &wo_local) * (1.0 / wo_local.z)
+                    } else {
+                        Vec3f::new(0.0, 0.0, 0.0)
+                    });
+                }
+            }
+        }
+        Ok(MeasuredBrdf::from_data(data, memory))
+    }
+
+    // the MERL binaries or the RGL tensor files, told apart by their extension
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<MeasuredBrdf> {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("bsdf") => MeasuredBrdf::load_rgl(path),
+            _            => MeasuredBrdf::load_merl(path),
+        }
+    }
+
+    fn from_data(data: Vec<Vec3f>, memory: Reservation<'static>) -> MeasuredBrdf {
+        let mut brdf = MeasuredBrdf { data: data, sampling: Vec::new(), _memory: memory };
+        brdf.sampling = (0..SAMPLING_THETA_O_BINS).map(|bin| brdf.build_sampling_table(bin)).collect();
+        brdf
+    }
+
+    // brdf value (without cosine) for local directions
+    pub fn value(&self, wi_local: &Vec3f, wo_local: &Vec3f) -> Vec3f {
+        let (theta_h, theta_d, phi_d) = half_diff_coords(wi_local, wo_local);
+        self.data[merl_index(theta_h, theta_d, phi_d)]
+    }
+
+    pub fn sample(&self, wo_local: &Vec3f, rnd: (f32, f32)) -> (Vec3f, f32) {
+        let table = &self.sampling[theta_o_bin(wo_local)];
+        let cell = table.sample(rnd);
+        let (cos_lo, cos_hi) = theta_cell_cos(cell.row);
+        let cos_theta = cos_lo + (cos_hi - cos_lo) * cell.remapped.1;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi_step = 2.0 * PI / SAMPLING_PHI_BINS as f32;
+        let phi = (cell.col as f32 + cell.remapped.0) * phi_step + wo_local.y.atan2(wo_local.x);
+        let wi_local = Vec3f::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+        (wi_local, cell.prob / cell_solid_angle(cell.row))
+    }
+
+    pub fn pdf(&self, wi_local: &Vec3f, wo_local: &Vec3f) -> f32 {
+        if wi_local.z <= 0.0 {
+            return 0.0;
+        }
+        let theta = wi_local.z.min(1.0).acos();
+        let row = ((theta / FRAC_PI_2 * SAMPLING_THETA_BINS as f32) as usize).min(SAMPLING_THETA_BINS - 1);
+        let mut phi = wi_local.y.atan2(wi_local.x) - wo_local.y.atan2(wo_local.x);
+        while phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+        let col = ((phi / (2.0 * PI) * SAMPLING_PHI_BINS as f32) as usize).min(SAMPLING_PHI_BINS - 1);
+        self.sampling[theta_o_bin(wo_local)].prob(col, row) / cell_solid_angle(row)
+    }
+
+    fn build_sampling_table(&self, theta_o_bin: usize) -> Distribution2D {
+        let theta_o = (theta_o_bin as f32 + 0.5) / SAMPLING_THETA_O_BINS as f32 * FRAC_PI_2;
+        let wo_local = Vec3f::new(theta_o.sin(), 0.0, theta_o.cos());
+        let mut func = Vec::with_capacity(SAMPLING_THETA_BINS * SAMPLING_PHI_BINS);
+        for row in 0..SAMPLING_THETA_BINS {
+            let theta = (row as f32 + 0.5) / SAMPLING_THETA_BINS as f32 * FRAC_PI_2;
+            for col in 0..SAMPLING_PHI_BINS {
+                let phi = (col as f32 + 0.5) / SAMPLING_PHI_BINS as f32 * 2.0 * PI;
+                let wi_local = Vec3f::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+                let f = luminance(&self.value(&wi_local, &wo_local));
+                func.push(f * wi_local.z * cell_solid_angle(row));
+            }
+        }
+        // cell centers may miss narrow peaks, so no cell gets zero probability
+        let floor = func.iter().fold(0.0, |sum, f| sum + f) * 1e-3 / func.len() as f32;
+        for f in func.iter_mut() {
+            *f = f.max(0.0) + floor.max(1e-12);
+        }
+        Distribution2D::new(&func, SAMPLING_PHI_BINS, SAMPLING_THETA_BINS)
+    }
+}
+
+impl fmt::Debug for MeasuredBrdf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MeasuredBrdf {{ samples: {} }}", self.data.len())
+    }
+}
+
+fn theta_o_bin(wo_local: &Vec3f) -> usize {
+    let theta_o = wo_local.z.max(0.0).min(1.0).acos();
+    ((theta_o / FRAC_PI_2 * SAMPLING_THETA_O_BINS as f32) as usize).min(SAMPLING_THETA_O_BINS - 1)
+}
+
+// cosines at the borders of the theta cell, the upper one first
+fn theta_cell_cos(row: usize) -> (f32, f32) {
+    let step = FRAC_PI_2 / SAMPLING_THETA_BINS as f32;
+    ((row as f32 * step).cos(), ((row + 1) as f32 * step).cos())
+}
+
+fn cell_solid_angle(row: usize) -> f32 {
+    let (cos_lo, cos_hi) = theta_cell_cos(row);
+    (cos_lo - cos_hi) * 2.0 * PI / SAMPLING_PHI_BINS as f32
+}
+
+fn rotate(v: &Vec3f, axis: &Vec3f, angle: f32) -> Vec3f {
+    let (sin, cos) = angle.sin_cos();
+    *v * cos + *axis * (axis.dot(v) * (1.0 - cos)) + axis.cross(v) * sin
+}
+
+fn half_diff_coords(wi_local: &Vec3f, wo_local: &Vec3f) -> (f32, f32, f32) {
+    let half = (*wi_local + *wo_local).normalize();
+    let theta_h = half.z.max(-1.0).min(1.0).acos();
+    let phi_h = half.y.atan2(half.x);
+    let tmp = rotate(wi_local, &Vec3f::new(0.0, 0.0, 1.0), -phi_h);
+    let diff = rotate(&tmp, &Vec3f::new(0.0, 1.0, 0.0), -theta_h);
+    let theta_d = diff.z.max(-1.0).min(1.0).acos();
+    let phi_d = diff.y.atan2(diff.x);
+    (theta_h, theta_d, phi_d)
+}
+
+// the directions of the half/diff coordinates with the half vector at phi = 0
+fn half_diff_directions(theta_h: f32, theta_d: f32, phi_d: f32) -> (Vec3f, Vec3f) {
+    let diff = Vec3f::new(theta_d.sin() * phi_d.cos(), theta_d.sin() * phi_d.sin(), theta_d.cos());
+    let wi_local = rotate(&diff, &Vec3f::new(0.0, 1.0, 0.0), theta_h);
+    let half = Vec3f::new(theta_h.sin(), 0.0, theta_h.cos());
+    (wi_local, half * (2.0 * half.dot(&wi_local)) - wi_local)
+}
+
+fn merl_index(theta_h: f32, theta_d: f32, phi_d: f32) -> usize {
+    // theta_half is sampled non-uniformly, denser near the specular peak
+    let theta_h_idx = if theta_h <= 0.0 {
+        0
+    } else {
+        ((theta_h / FRAC_PI_2 * THETA_H_RES as f32 * THETA_H_RES as f32).sqrt() as usize).min(THETA_H_RES - 1)
+    };
+    let theta_d_idx = ((theta_d / FRAC_PI_2 * THETA_D_RES as f32) as usize).min(THETA_D_RES - 1);
+    let phi_d = if phi_d < 0.0 { phi_d + PI } else { phi_d };
+    let phi_d_idx = ((phi_d / PI * PHI_D_RES as f32) as usize).min(PHI_D_RES - 1);
+    phi_d_idx + theta_d_idx * PHI_D_RES + theta_h_idx * PHI_D_RES * THETA_D_RES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{half_diff_coords, half_diff_directions, MeasuredBrdf, MERL_SAMPLES_NB};
+    use math::Vec3f;
+    use math::vector_traits::*;
+    use memory::{self, MemoryCategory};
+    use std::f32::consts::FRAC_1_PI;
+
+    #[test]
+    fn constant_brdf_sampling_pdf_is_consistent() {
+        let memory = memory::global().empty_reservation(MemoryCategory::Texture);
+        let albedo = Vec3f::new(0.5, 0.5, 0.5) * FRAC_1_PI;
+        let brdf = MeasuredBrdf::from_data(vec![albedo; MERL_SAMPLES_NB], memory);
+        let wo = Vec3f::new(0.5, 0.1, 0.8).normalize();
+        for i in 0..16 {
+            let rnd = ((i as f32 + 0.5) / 16.0, (i as f32 * 7.0 % 16.0 + 0.5) / 16.0);
+            let (wi, pdf) = brdf.sample(&wo, rnd);
+            assert!(wi.z >= 0.0);
+            assert!((brdf.pdf(&wi, &wo) - pdf).abs() <= 1e-3 * pdf);
+            assert!((brdf.value(&wi, &wo) - albedo).norm() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn half_diff_directions_invert_the_coordinates() {
+        let (theta_h, theta_d, phi_d) = (0.4, 0.7, 1.2);
+        let (wi, wo) = half_diff_directions(theta_h, theta_d, phi_d);
+        let coords = half_diff_coords(&wi, &wo);
+        assert!((coords.0 - theta_h).abs() < 1e-4 && (coords.1 - theta_d).abs() < 1e-4 && (coords.2 - phi_d).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,194 @@
+use brdf::BrdfEval;
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+use std::f32::consts::PI;
+
+// Microfacet specular lobe: a distribution of the normals with the height-correlated Smith
+// shadowing-masking of it. Takes the place of the phong lobe of the material, `specular` is its
+// reflectance at normal incidence with Schlick's Fresnel over it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Microfacet {
+    pub distribution: MicrofacetDistribution,
+    pub roughness: f32, // perceptual, alpha = roughness^2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MicrofacetDistribution {
+    // GGX (Trowbridge-Reitz), long tails; sampled from the normals visible from the "out" direction
+    // (Heitz 2018), so no sample is wasted on the back of the microfacets
+    Ggx,
+    // Beckmann, the gaussian slopes of the older renderers; sampled from D(m) cos(m)
+    Beckmann,
+}
+
+impl Microfacet {
+    pub fn ggx(roughness: f32) -> Microfacet {
+        Microfacet { distribution: MicrofacetDistribution::Ggx, roughness: roughness }
+    }
+
+    pub fn beckmann(roughness: f32) -> Microfacet {
+        Microfacet { distribution: MicrofacetDistribution::Beckmann, roughness: roughness }
+    }
+
+    // too smooth a lobe doesn't fit into a float
+    pub fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
+
+    // brdf * cos(theta_i) and the pdf of `sample_local`
+    pub fn eval_local(&self, specular: &Vec3f, wi_local: &Vec3f, wo_local: &Vec3f) -> BrdfEval {
+        self.eval_local_with(&|cos| schlick(specular, cos), wi_local, wo_local)
+    }
+
+    // the same with a Fresnel term of the cosine between the light and the microfacet normal
+    pub fn eval_local_with(&self, fresnel: &Fn(f32) -> Vec3f, wi_local: &Vec3f, wo_local: &Vec3f) -> BrdfEval {
+        if wi_local.z <= 0.0 || wo_local.z <= 0.0 {
+            return BrdfEval { radiance: Zero::zero(), pdf: 0.0 };
+        }
+        let m = (*wi_local + *wo_local).normalize();
+        let d = self.d(&m);
+        let fresnel = fresnel(wi_local.dot(&m));
+        let g2 = self.g2(wi_local, wo_local);
+        // the reflection divides the density of the normals by 4 (wo.m)
+        let pdf = self.normal_pdf(wo_local, &m) / (4.0 * wo_local.dot(&m)).max(1e-8);
+        BrdfEval {
+            radiance: fresnel * (d * g2 / (4.0 * wo_local.z)),
+            pdf: pdf
+        }
+    }
+
+    // the "out" direction mirrored by a sampled microfacet normal, may go under the surface
+    pub fn sample_local(&self, wo_local: &Vec3f, rnd: (f32, f32)) -> Vec3f {
+        let m = self.sample_normal(wo_local, rnd);
+        m * (2.0 * wo_local.dot(&m)) - *wo_local
+    }
+
+    // a microfacet normal, of the density `normal_pdf`
+    pub fn sample_normal(&self, wo_local: &Vec3f, rnd: (f32, f32)) -> Vec3f {
+        match self.distribution {
+            MicrofacetDistribution::Ggx      => self.sample_visible_ggx(wo_local, rnd),
+            MicrofacetDistribution::Beckmann => self.sample_beckmann(rnd)
+        }
+    }
+
+    pub fn normal_pdf(&self, wo_local: &Vec3f, m: &Vec3f) -> f32 {
+        match self.distribution {
+            // the visible normals have the density G1 * D * (wo.m) / wo.z
+            MicrofacetDistribution::Ggx =>
+                self.d(m) * wo_local.dot(m).max(0.0) / ((1.0 + self.lambda(wo_local)) * wo_local.z),
+            MicrofacetDistribution::Beckmann => self.d(m) * m.z
+        }
+    }
+
+    // height-correlated shadowing-masking, the directions may be on either side of the surface
+    pub fn g2(&self, wi_local: &Vec3f, wo_local: &Vec3f) -> f32 {
+        1.0 / (1.0 + self.lambda(wi_local) + self.lambda(wo_local))
+    }
+
+    fn sample_visible_ggx(&self, wo_local: &Vec3f, rnd: (f32, f32)) -> Vec3f {
+        let alpha = self.alpha();
+        // the visible normals of the stretched hemisphere are the ones of a disk, squashed on its far half
+        let vh = Vec3f::new(alpha * wo_local.x, alpha * wo_local.y, wo_local.z).normalize();
+        let len2 = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if len2 > 0.0 { Vec3f::new(-vh.y, vh.x, 0.0) / len2.sqrt() } else { Vec3f::new(1.0, 0.0, 0.0) };
+        let t2 = vh.cross(&t1);
+        let (r, phi) = (rnd.0.sqrt(), 2.0 * PI * rnd.1);
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        Vec3f::new(alpha * nh.x, alpha * nh.y, nh.z.max(1e-6)).normalize()
+    }
+
+    fn sample_beckmann(&self, rnd: (f32, f32)) -> Vec3f {
+        let alpha = self.alpha();
+        let tan2 = -alpha * alpha * (1.0 - rnd.0).max(1e-12).ln();
+        let cos_theta = 1.0 / (1.0 + tan2).sqrt();
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rnd.1;
+        Vec3f::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+    }
+
+    pub fn d(&self, m: &Vec3f) -> f32 {
+        let alpha2 = self.alpha() * self.alpha();
+        let cos2 = m.z * m.z;
+        match self.distribution {
+            MicrofacetDistribution::Ggx => {
+                let denom = cos2 * (alpha2 - 1.0) + 1.0;
+                alpha2 / (PI * denom * denom)
+            },
+            MicrofacetDistribution::Beckmann => {
+                if cos2 <= 0.0 {
+                    return 0.0;
+                }
+                let tan2 = (1.0 - cos2) / cos2;
+                (-tan2 / alpha2).exp() / (PI * alpha2 * cos2 * cos2)
+            }
+        }
+    }
+
+    fn lambda(&self, v: &Vec3f) -> f32 {
+        let tan2 = (v.x * v.x + v.y * v.y) / (v.z * v.z);
+        match self.distribution {
+            MicrofacetDistribution::Ggx => 0.5 * ((1.0 + self.alpha() * self.alpha() * tan2).sqrt() - 1.0),
+            // Walter's rational fit
+            MicrofacetDistribution::Beckmann => {
+                let a = 1.0 / (self.alpha() * tan2.sqrt());
+                if a >= 1.6 { 0.0 } else { (1.0 - 1.259 * a + 0.396 * a * a) / (3.535 * a + 2.181 * a * a) }
+            }
+        }
+    }
+}
+
+fn schlick(f0: &Vec3f, cos_theta: f32) -> Vec3f {
+    let c = 1.0 - cos_theta.max(0.0).min(1.0);
+    let c5 = c * c * c * c * c;
+    *f0 + (Vec3f::new(1.0, 1.0, 1.0) - *f0) * c5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Microfacet;
+    use brdf::{Brdf, Material};
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    // albedo of a white mirror of the microfacets, checking that the samples match eval on the way
+    fn albedo(microfacet: Microfacet) -> f32 {
+        let mut material = Material::new_identity();
+        material.specular = Vec3f::new(1.0, 1.0, 1.0);
+        material.microfacet = Some(microfacet);
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let brdf = Brdf::new(&Vec3f::new(0.5, 0.0, -1.0).normalize(), &normal, &material).unwrap();
+        let n = 64;
+        let mut albedo = 0.0;
+        for i in 0..n * n {
+            let rnd = (0.5, ((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32);
+            if let Some(sample) = brdf.sample(rnd) {
+                let eval = brdf.eval(&sample.wi).unwrap();
+                assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf);
+                albedo += sample.radiance.x / sample.pdf;
+            }
+        }
+        albedo / (n * n) as f32
+    }
+
+    #[test]
+    fn ggx_samples_match_eval_and_keep_energy() {
+        // a white mirror of microfacets loses only the light shadowed between them
+        let albedo = albedo(Microfacet::ggx(0.5));
+        assert!(albedo > 0.85 && albedo <= 1.0, "{}", albedo);
+    }
+
+    #[test]
+    fn beckmann_is_ggx_without_the_tails() {
+        let albedo = albedo(Microfacet::beckmann(0.5));
+        assert!(albedo > 0.85 && albedo <= 1.0, "{}", albedo);
+        // the same peak width, but far from the mirror direction only GGX still reflects something
+        let (wo, far) = (Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.9, 0.0, 0.3).normalize());
+        let specular = Vec3f::new(1.0, 1.0, 1.0);
+        let ggx = Microfacet::ggx(0.3).eval_local(&specular, &far, &wo).radiance.x;
+        let beckmann = Microfacet::beckmann(0.3).eval_local(&specular, &far, &wo).radiance.x;
+        assert!(beckmann < 0.1 * ggx, "{} {}", beckmann, ggx);
+    }
+}
@@ -0,0 +1,861 @@
+use math::{Vec3f, Zero, EPS_COSINE};
+use math::vector_traits::*;
+use utility::{cos_hemisphere_sample, luminance, pow_cos_hemisphere_sample, Sampler};
+use std::f32;
+use std::f32::consts::FRAC_1_PI;
+use std::sync::Arc;
+use dirt::Dirt;
+use geometry::{Frame, SurfaceIntersection};
+use self::dielectric::{fresnel_dielectric, refract_local};
+
+pub mod audit;
+pub mod car_paint;
+pub mod conductor;
+pub mod dielectric;
+pub mod dust;
+pub mod measured;
+pub mod microfacet;
+pub mod rgl;
+pub mod sheen;
+pub mod textured;
+pub mod variation;
+pub use self::audit::AlbedoAudit;
+pub use self::car_paint::CarPaint;
+pub use self::conductor::Conductor;
+pub use self::dielectric::Dielectric;
+pub use self::dust::Dust;
+pub use self::measured::MeasuredBrdf;
+pub use self::microfacet::{Microfacet, MicrofacetDistribution};
+pub use self::sheen::Sheen;
+pub use self::textured::{ChannelMap, NormalMapConvention, Projection, TextureSet, TextureSource};
+pub use self::variation::MaterialVariation;
+
+// phong lobes this sharp are treated as perfect mirrors
+pub const SPECULAR_PHONG_EXP: f32 = 1000.0;
+// the wireframe of the surfaces without triangles is their uv grid, with this many cells along u and v;
+// a sphere's v spans half the angle its u does
+const WIREFRAME_UV_CELLS: (f32, f32) = (24.0, 12.0);
+
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub diffuse: Vec3f,
+    pub specular: Vec3f,
+    pub phong_exp: f32,
+    pub mirror: Vec3f, // reflectance of an ideal mirror component, a delta eval never sees
+    pub microfacet: Option<Microfacet>, // replaces the phong lobe if set
+    pub conductor: Option<Conductor>, // Fresnel of a metal over the phong or microfacet lobe and the mirror
+    pub dielectric: Option<Dielectric>, // glass, replaces all of the lobes if set
+    pub measured: Option<Arc<MeasuredBrdf>>, // replaces the analytic lobes if set
+    pub textures: Option<Arc<TextureSet>>, // resolved per hit by Scene::get_surface_material
+    pub car_paint: Option<CarPaint>, // flakes and clear coat over the analytic lobes
+    pub sheen: Option<Sheen>, // fabric sheen added on top of any of the above
+    pub dust: Option<Dust>, // blended over everything else
+    pub dirt: Option<Dirt>, // grime in the crevices, probed at the hit by Scene::get_surface_material
+    pub variation: Option<MaterialVariation>, // jitter between objects sharing the material
+}
+
+// replaces the material of an object to look for tessellation and uv issues
+#[derive(Debug, Clone, PartialEq, Copy)]
+pub enum InspectionMaterial {
+    Wireframe { width: f32, color: Vec3f }, // width is in barycentric units, or of a uv grid cell
+    UvChecker { scale: f32 }, // checker cells per uv unit
+}
+
+#[derive(Debug, Clone)]
+pub struct Brdf {
+    material: Material,
+    own_basis: Frame,
+    wo_local: Vec3f, // "out" in physical meaning, in fact - incoming
+    probs: Probabilities,
+    eps_cosine: f32, // directions closer to the surface than this are rejected
+    eta: f32, // index of the far side of the surface over the one of the near side, 1 but for dielectrics
+}
+
+#[derive(Debug, Clone)]
+pub struct BrdfSample {
+    pub wi: Vec3f, // "in" in physical meaning, i.e. from light to eye
+    pub radiance: Vec3f, // brdf * cos(theta), not divided by pdf
+    pub pdf: f32, // solid angle pdf of the whole brdf, selection probability included
+    // from the ideal mirror: radiance / pdf is the weight, but eval and other samples can't reach it
+    pub delta: bool,
+    pub kind: BounceKind, // of the lobe the direction was sampled from
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BounceKind {
+    Diffuse,
+    Glossy,
+    Specular,
+}
+
+#[derive(Debug, Clone)]
+pub struct BrdfEval {
+    pub radiance: Vec3f,
+    pub pdf: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Probabilities {
+    diffuse: f32,
+    phong: f32,
+    mirror: f32,
+    continuation: f32,
+    sheen: f32, // picks the sheen layer, diffuse and phong split the rest
+    dust: f32, // both the dust coverage and the probability to pick it
+}
+
+impl Brdf {
+    pub fn new(out_dir_world: &Vec3f, hit_normal: &Vec3f, material: &Material) -> Option<Brdf> {
+        Brdf::new_with_eps(out_dir_world, hit_normal, material, EPS_COSINE)
+    }
+
+    pub fn new_with_eps(out_dir_world: &Vec3f, hit_normal: &Vec3f, material: &Material, eps_cosine: f32)
+        -> Option<Brdf> {
+        // a dielectric is looked at from the inside as well, through the flipped normal
+        let inside = material.dielectric.is_some() && out_dir_world.dot(hit_normal) > 0.0;
+        let normal = if inside { -*hit_normal } else { *hit_normal };
+        let eta = material.dielectric.map_or(1.0, |dielectric| if inside { 1.0 / dielectric.ior } else { dielectric.ior });
+        let own_basis = Frame::from_z(&normal);
+        let wo_local = own_basis.to_local(&-*out_dir_world);
+        if wo_local.z < eps_cosine {
+            None
+        } else {
+            Some(Brdf {
+                material: material.clone(),
+                own_basis: own_basis,
+                wo_local: wo_local,
+                probs: Probabilities::new(material, &normal),
+                eps_cosine: eps_cosine,
+                eta: eta,
+            })
+        }
+    }
+
+    pub fn sample(&self, rnd: (f32, f32, f32)) -> Option<BrdfSample> {
+        let layers_prob = self.probs.sheen + self.probs.dust;
+        if rnd.0 < layers_prob {
+            // layers are wide, cosine sampling is good enough for them
+            let wi = self.own_basis.to_world(&cos_hemisphere_sample((rnd.1, rnd.2)));
+            return self.eval(&wi).map(|eval| BrdfSample {
+                wi: wi, radiance: eval.radiance, pdf: eval.pdf, delta: false, kind: BounceKind::Diffuse
+            });
+        }
+
+        let rnd = ((rnd.0 - layers_prob) / (1.0 - layers_prob), rnd.1, rnd.2);
+        self.base_sample(rnd).map(|base| {
+            if base.delta {
+                // sheen has nothing at the mirror direction, dust covers the mirror as anything else
+                return BrdfSample {
+                    wi: base.wi,
+                    radiance: base.radiance * (1.0 - self.probs.dust),
+                    pdf: base.pdf * (1.0 - layers_prob),
+                    delta: true,
+                    kind: base.kind
+                };
+            }
+            let wi_local = self.own_basis.to_local(&base.wi).normalize();
+            let eval = self.add_layers(&wi_local, BrdfEval { radiance: base.radiance, pdf: base.pdf });
+            BrdfSample { wi: base.wi, radiance: eval.radiance, pdf: eval.pdf, delta: false, kind: base.kind }
+        })
+    }
+
+    // `sample` with the numbers of `sampler`
+    pub fn sample_with<R: Sampler>(&self, sampler: &mut R) -> Option<BrdfSample> {
+        let (u, v) = sampler.next_2d();
+        self.sample((u, v, sampler.next_1d()))
+    }
+
+    // nothing but the ideal mirror or a smooth dielectric, there is no point in sampling lights for it
+    pub fn is_delta(&self) -> bool {
+        let smooth_dielectric = self.material.dielectric.map_or(false, |dielectric| dielectric.rough.is_none());
+        (smooth_dielectric || self.probs.mirror >= 1.0 - 1e-6) && self.probs.sheen + self.probs.dust <= 0.0
+    }
+
+    // of everything but the ideal mirror and the smooth dielectric
+    pub fn eval(&self, wi: &Vec3f) -> Option<BrdfEval> {
+        let wi_local = self.own_basis.to_local(wi).normalize();
+        self.base_eval(&wi_local).map(|base| self.add_layers(&wi_local, base))
+    }
+
+    // sheen goes on top of whatever the base lobes are, then dust covers all of it;
+    // the layers only reflect, under the surface they are of no help to the light refracted by frosted glass
+    fn add_layers(&self, wi_local: &Vec3f, base: BrdfEval) -> BrdfEval {
+        let above = wi_local.z > 0.0;
+        let mut radiance = base.radiance;
+        if let Some(ref sheen) = self.material.sheen {
+            if above {
+                radiance = radiance + sheen.eval_local(&self.material, wi_local, &self.wo_local);
+            }
+        }
+        if let Some(ref dust) = self.material.dust {
+            let coverage = self.probs.dust;
+            radiance = radiance * (1.0 - coverage);
+            if above {
+                radiance = radiance + dust.eval_local(wi_local, &self.wo_local) * coverage;
+            }
+        }
+        let layers_prob = self.probs.sheen + self.probs.dust;
+        BrdfEval {
+            radiance: radiance,
+            pdf: base.pdf * (1.0 - layers_prob) + self.lambert_pdf(wi_local) * layers_prob
+        }
+    }
+
+    // sample of the lobes under the layers, radiance and pdf are of these lobes only
+    fn base_sample(&self, rnd: (f32, f32, f32)) -> Option<BrdfSample> {
+        let sample_rnds = (rnd.1, rnd.2);
+        if let Some(ref dielectric) = self.material.dielectric {
+            return self.dielectric_sample(dielectric, rnd);
+        }
+        if let Some(ref measured) = self.material.measured {
+            return self.measured_sample(measured, sample_rnds);
+        }
+        if let Some(ref paint) = self.material.car_paint {
+            return self.car_paint_sample(paint, rnd);
+        }
+
+        if rnd.0 > self.probs.diffuse + self.probs.phong && self.probs.mirror > 0.0 {
+            return self.mirror_sample();
+        }
+        let (component, selection_prob) = if rnd.0 < self.probs.diffuse {
+            (self.lambert_sample(sample_rnds), self.probs.diffuse)
+        } else {
+            (self.phong_sample(sample_rnds), self.probs.phong)
+        };
+
+        let component = match component {
+            Some(component) => component,
+            None            => return None
+        };
+
+        match self.base_eval(&self.own_basis.to_local(&component.wi).normalize()) {
+            Some(ref eval) if eval.pdf > 0.0 => {
+                // the whole brdf can't be less likely (or darker) than the part we sampled
+                debug_assert!(eval.pdf >= component.pdf * selection_prob * (1.0 - 1e-3),
+                              "brdf eval pdf {} < sampled component pdf {}",
+                              eval.pdf, component.pdf * selection_prob);
+                debug_assert!((eval.radiance - component.radiance)
+                                  .fold(f32::min) >= -1e-3 * eval.radiance.fold(f32::max),
+                              "brdf eval {:?} < sampled component {:?}",
+                              eval.radiance, component.radiance);
+                Some(BrdfSample {
+                    wi: component.wi,
+                    radiance: eval.radiance,
+                    pdf: eval.pdf,
+                    delta: false,
+                    kind: component.kind
+                })
+            },
+            _ => None
+        }
+    }
+
+    fn base_eval(&self, wi_local: &Vec3f) -> Option<BrdfEval> {
+        if let Some(ref dielectric) = self.material.dielectric {
+            return match dielectric.rough {
+                // frosted glass refracts into the far side as well
+                Some(ref rough) if wi_local.z.abs() >= self.eps_cosine =>
+                    Some(dielectric.eval_rough_local(rough, wi_local, &self.wo_local, self.eta)),
+                None if wi_local.z >= self.eps_cosine => Some(BrdfEval { radiance: Zero::zero(), pdf: 0.0 }),
+                _ => None
+            };
+        }
+        if wi_local.z < self.eps_cosine {
+            None
+        } else if let Some(ref measured) = self.material.measured {
+            Some(BrdfEval {
+                radiance: measured.value(wi_local, &self.wo_local) * wi_local.z,
+                pdf: measured.pdf(wi_local, &self.wo_local)
+            })
+        } else if let Some(ref paint) = self.material.car_paint {
+            Some(paint.eval_local(&self.material, wi_local, &self.wo_local))
+        } else {
+            let lambert = self.lambert_eval(wi_local);
+            let phong = self.phong_eval(wi_local);
+            Some(BrdfEval {
+                // the lobes add up, the selection probabilities only weight their pdfs
+                radiance: lambert.radiance + phong.radiance,
+                pdf: lambert.pdf * self.probs.diffuse + phong.pdf * self.probs.phong
+            })
+        }
+    }
+
+    pub fn normal(&self) -> Vec3f {
+        self.own_basis.normal()
+    }
+
+    // pdf of sampling the "out" direction if `wi` was the out one, for bidirectional methods
+    pub fn reverse_pdf(&self, wi: &Vec3f) -> f32 {
+        let wo = self.own_basis.to_world(&self.wo_local);
+        Brdf::new_with_eps(&-*wi, &self.normal(), &self.material, self.eps_cosine)
+            .and_then(|reverse| reverse.eval(&wo))
+            .map_or(0.0, |eval| eval.pdf)
+    }
+
+    fn measured_sample(&self, measured: &MeasuredBrdf, rnd: (f32, f32)) -> Option<BrdfSample> {
+        let (wi_local, pdf) = measured.sample(&self.wo_local, rnd);
+        if wi_local.z < self.eps_cosine || pdf <= 0.0 {
+            None
+        } else {
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: measured.value(&wi_local, &self.wo_local) * wi_local.z,
+                pdf: pdf,
+                delta: false,
+                kind: BounceKind::Glossy
+            })
+        }
+    }
+
+    fn car_paint_sample(&self, paint: &CarPaint, rnd: (f32, f32, f32)) -> Option<BrdfSample> {
+        let wi_local = match paint.sample_local(&self.material, &self.wo_local, rnd) {
+            Some(wi_local) => wi_local,
+            None           => return None
+        };
+        let eval = paint.eval_local(&self.material, &wi_local, &self.wo_local);
+        if wi_local.z < self.eps_cosine || eval.pdf <= 0.0 {
+            None
+        } else {
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: eval.radiance,
+                pdf: eval.pdf,
+                delta: false,
+                kind: BounceKind::Glossy
+            })
+        }
+    }
+
+    fn lambert_sample(&self, rnd: (f32, f32)) -> Option<BrdfSample> {
+        let wi_local = cos_hemisphere_sample(rnd);
+        let pdf = self.lambert_pdf(&wi_local);
+        if wi_local.z < self.eps_cosine {
+            None
+        } else {
+            let wi = self.own_basis.to_world(&wi_local);
+            Some(BrdfSample {
+                wi: wi,
+                radiance: self.material.diffuse * pdf,
+                pdf: pdf,
+                delta: false,
+                kind: BounceKind::Diffuse
+            })
+        }
+    }
+
+    fn phong_sample(&self, rnd: (f32, f32)) -> Option<BrdfSample> {
+        if let Some(ref microfacet) = self.material.microfacet {
+            return self.microfacet_sample(microfacet, rnd);
+        }
+        // get dir around refl. dir, move it to normals basis and then move it to world coords
+        let refl_local = self.wo_local.reflect_local();
+        let wi_local = sample_folded_lobe(self.material.phong_exp, &refl_local, rnd);
+        let pdf = self.phong_pdf(&wi_local, &refl_local);
+        if wi_local.z < self.eps_cosine || pdf <= 0.0 {
+            None
+        } else {
+            let lobe_pdf = self.phong_lobe_pdf(&wi_local, &refl_local);
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: self.material.specular * lobe_pdf,
+                pdf: pdf,
+                delta: false,
+                // as sharp as the mirror, see `Material::is_specular`
+                kind: if self.material.phong_exp >= SPECULAR_PHONG_EXP { BounceKind::Specular } else { BounceKind::Glossy }
+            })
+        }
+    }
+
+    fn microfacet_sample(&self, microfacet: &Microfacet, rnd: (f32, f32)) -> Option<BrdfSample> {
+        let wi_local = microfacet.sample_local(&self.wo_local, rnd);
+        if wi_local.z < self.eps_cosine {
+            return None;
+        }
+        let eval = self.microfacet_eval(microfacet, &wi_local);
+        if eval.pdf <= 0.0 {
+            None
+        } else {
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: eval.radiance,
+                pdf: eval.pdf,
+                delta: false,
+                kind: BounceKind::Glossy
+            })
+        }
+    }
+
+    // Fresnel picks the reflection or the refraction, so the weight of either is the transmittance at most
+    fn dielectric_sample(&self, dielectric: &Dielectric, rnd: (f32, f32, f32)) -> Option<BrdfSample> {
+        if let Some(ref rough) = dielectric.rough {
+            let wi_local = dielectric.sample_rough_local(rough, &self.wo_local, self.eta, rnd)?;
+            return match self.base_eval(&wi_local) {
+                Some(eval) if eval.pdf > 0.0 => Some(BrdfSample {
+                    wi: self.own_basis.to_world(&wi_local),
+                    radiance: eval.radiance,
+                    pdf: eval.pdf,
+                    delta: false,
+                    kind: BounceKind::Glossy
+                }),
+                _ => None
+            };
+        }
+        let rnd = rnd.0;
+        let fresnel = fresnel_dielectric(self.wo_local.z, self.eta);
+        let (wi_local, radiance, pdf) = if rnd < fresnel {
+            (self.wo_local.reflect_local(), Vec3f::new(fresnel, fresnel, fresnel), fresnel)
+        } else {
+            // radiance is squeezed into the smaller solid angle on the denser side
+            let wi_local = refract_local(&self.wo_local, self.eta)?;
+            (wi_local, dielectric.transmittance * ((1.0 - fresnel) / (self.eta * self.eta)), 1.0 - fresnel)
+        };
+        if wi_local.z.abs() < self.eps_cosine {
+            None
+        } else {
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: radiance,
+                pdf: pdf,
+                delta: true,
+                kind: BounceKind::Specular
+            })
+        }
+    }
+
+    fn mirror_sample(&self) -> Option<BrdfSample> {
+        let wi_local = self.wo_local.reflect_local();
+        if wi_local.z < self.eps_cosine {
+            None
+        } else {
+            Some(BrdfSample {
+                wi: self.own_basis.to_world(&wi_local),
+                radiance: self.material.mirror * self.conductor_fresnel(),
+                pdf: self.probs.mirror,
+                delta: true,
+                kind: BounceKind::Specular
+            })
+        }
+    }
+
+    fn lambert_eval(&self, wi_local: &Vec3f) -> BrdfEval {
+        let pdf = self.lambert_pdf(wi_local);
+        BrdfEval {
+            radiance: self.material.diffuse * pdf,
+            pdf: pdf,
+        }
+    }
+
+    fn phong_eval(&self, wi_local: &Vec3f) -> BrdfEval {
+        if let Some(ref microfacet) = self.material.microfacet {
+            return self.microfacet_eval(microfacet, wi_local);
+        }
+        let refl_local = self.wo_local.reflect_local();
+        BrdfEval {
+            radiance: self.material.specular * self.conductor_fresnel() * self.phong_lobe_pdf(wi_local, &refl_local),
+            pdf: self.phong_pdf(wi_local, &refl_local)
+        }
+    }
+
+    // the metal takes the Fresnel of every microfacet, Schlick's term of `specular` is for the rest
+    fn microfacet_eval(&self, microfacet: &Microfacet, wi_local: &Vec3f) -> BrdfEval {
+        match self.material.conductor {
+            Some(ref conductor) => {
+                let specular = self.material.specular;
+                microfacet.eval_local_with(&|cos| specular * conductor.fresnel(cos), wi_local, &self.wo_local)
+            },
+            None => microfacet.eval_local(&self.material.specular, wi_local, &self.wo_local)
+        }
+    }
+
+    // the lobes around the mirror direction see the metal at the angle of the view, white without one
+    fn conductor_fresnel(&self) -> Vec3f {
+        self.material.conductor.map_or(Vec3f::new(1.0, 1.0, 1.0), |conductor| conductor.fresnel(self.wo_local.z))
+    }
+
+    fn lambert_pdf(&self, wi_local: &Vec3f) -> f32 {
+        let cos_theta = wi_local.z.max(0.0);
+        cos_theta * FRAC_1_PI
+    }
+
+    fn phong_pdf(&self, wi_local: &Vec3f, refl_local: &Vec3f) -> f32 {
+        folded_lobe_pdf(self.material.phong_exp, wi_local, refl_local)
+    }
+
+    fn phong_lobe_pdf(&self, wi_local: &Vec3f, refl_local: &Vec3f) -> f32 {
+        lobe_pdf(self.material.phong_exp, wi_local, refl_local)
+    }
+}
+
+// normalized cos^n lobe around `axis`
+fn lobe_pdf(n: f32, wi_local: &Vec3f, axis: &Vec3f) -> f32 {
+    let cos_theta = wi_local.dot(axis).max(0.0);
+    cos_theta.powf(n) * (n + 1.0) * 0.5 * FRAC_1_PI
+}
+
+// direction from the cos^n lobe around `axis`, the part under the surface is folded back
+fn sample_folded_lobe(n: f32, axis: &Vec3f, rnd: (f32, f32)) -> Vec3f {
+    let wi_lobe = pow_cos_hemisphere_sample(n, rnd);
+    fold_to_upper_hemisphere(&Frame::from_z(axis).to_world(&wi_lobe))
+}
+
+// pdf of sample_folded_lobe: every direction gets the density of itself and of its mirror image
+fn folded_lobe_pdf(n: f32, wi_local: &Vec3f, axis: &Vec3f) -> f32 {
+    let mirrored = Vec3f::new(wi_local.x, wi_local.y, -wi_local.z);
+    lobe_pdf(n, wi_local, axis) + lobe_pdf(n, &mirrored, axis)
+}
+
+fn fold_to_upper_hemisphere(v: &Vec3f) -> Vec3f {
+    Vec3f::new(v.x, v.y, v.z.abs())
+}
+
+impl Material {
+    pub fn new_identity() -> Material {
+        Material {
+            diffuse: Zero::zero(),
+            specular: Zero::zero(),
+            phong_exp: 0.0,
+            mirror: Zero::zero(),
+            microfacet: None,
+            conductor: None,
+            dielectric: None,
+            measured: None,
+            textures: None,
+            car_paint: None,
+            sheen: None,
+            dust: None,
+            dirt: None,
+            variation: None
+        }
+    }
+
+    // a mirror for all practical purposes: a sharp phong lobe or the ideal mirror and nothing else,
+    // or a smooth dielectric, which only reflects and refracts ideally
+    pub fn is_specular(&self) -> bool {
+        if let Some(ref dielectric) = self.dielectric {
+            return dielectric.rough.is_none() && self.sheen.is_none() && self.dust.is_none();
+        }
+        let sharp = self.microfacet.is_none() && self.phong_exp >= SPECULAR_PHONG_EXP
+            || self.albedo_specular() < 1e-3 && self.albedo_mirror() > 0.0;
+        self.measured.is_none() && self.car_paint.is_none() && self.sheen.is_none() && self.dust.is_none()
+            && self.textures.is_none() && sharp && self.albedo_diffuse() < 1e-3
+    }
+
+    fn albedo_diffuse(&self) -> f32 {
+        luminance(&self.diffuse)
+    }
+
+    fn albedo_specular(&self) -> f32 {
+        luminance(&self.specular)
+    }
+
+    fn albedo_mirror(&self) -> f32 {
+        luminance(&self.mirror)
+    }
+
+    fn total_albedo(&self) -> f32 {
+        self.albedo_specular() + self.albedo_diffuse() + self.albedo_mirror()
+    }
+}
+
+impl InspectionMaterial {
+    pub fn apply(&self, base: &Material, isect: &SurfaceIntersection) -> Material {
+        match *self {
+            InspectionMaterial::Wireframe { width, color } => {
+                // how far the hit is from the nearest edge
+                let edge_dist = match isect.barycentric {
+                    Some(bary) => bary.fold(f32::min),
+                    None       => {
+                        let line_dist = |x: f32| (x - x.round()).abs();
+                        line_dist(isect.uv.x * WIREFRAME_UV_CELLS.0).min(line_dist(isect.uv.y * WIREFRAME_UV_CELLS.1))
+                    }
+                };
+                if edge_dist < width {
+                    Material {
+                        diffuse: color,
+                        specular: Zero::zero(),
+                        phong_exp: 1.0,
+                        mirror: Zero::zero(),
+                        microfacet: None,
+                        conductor: None,
+                        dielectric: None,
+                        measured: None,
+                        textures: None,
+                        car_paint: None,
+                        sheen: None,
+                        dust: None,
+                        dirt: None,
+                        variation: None
+                    }
+                } else {
+                    base.clone()
+                }
+            },
+            InspectionMaterial::UvChecker { scale } => {
+                let cell = (isect.uv.x * scale).floor() + (isect.uv.y * scale).floor();
+                let albedo = if cell as i64 % 2 == 0 { 0.8 } else { 0.2 };
+                Material {
+                    diffuse: Vec3f::new(albedo, albedo, albedo),
+                    specular: Zero::zero(),
+                    phong_exp: 1.0,
+                    mirror: Zero::zero(),
+                    microfacet: None,
+                    conductor: None,
+                    dielectric: None,
+                    measured: None,
+                    textures: None,
+                    car_paint: None,
+                    sheen: None,
+                    dust: None,
+                    dirt: None,
+                    variation: None
+                }
+            }
+        }
+    }
+}
+
+impl Probabilities {
+    fn new(mat: &Material, normal: &Vec3f) -> Probabilities {
+        let albedo_diffuse = mat.albedo_diffuse();
+        let albedo_specular = mat.albedo_specular();
+        let total_albedo = mat.total_albedo();
+        let sheen = mat.sheen.map_or(0.0, |sheen| {
+            // measured and car paint lobes don't tell their albedo
+            let base_albedo = if mat.measured.is_some() || mat.car_paint.is_some() { 1.0 } else { total_albedo };
+            let sheen_albedo = luminance(&sheen.color(mat));
+            if sheen_albedo > 0.0 { sheen_albedo / (sheen_albedo + base_albedo) } else { 0.0 }
+        });
+        let dust = mat.dust.map_or(0.0, |dust| dust.coverage_at(normal));
+        let sheen = sheen * (1.0 - dust);
+        if total_albedo < 1.0e-9 {
+            Probabilities {
+                diffuse: 0.0,
+                phong: 0.0,
+                mirror: 0.0,
+                continuation: 0.0,
+                sheen: sheen,
+                dust: dust
+            }
+        } else {
+            Probabilities {
+                diffuse: albedo_diffuse / total_albedo,
+                phong: albedo_specular / total_albedo,
+                mirror: mat.albedo_mirror() / total_albedo,
+                continuation: total_albedo,
+                sheen: sheen,
+                dust: dust
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BounceKind, Brdf, InspectionMaterial, Material};
+    use geometry::{GeometryList, GeometryManager, Ray, Sphere, Surface};
+    use math::Vec3f;
+    use math::vector_traits::*;
+    use scene::SurfaceProperties;
+    use std::f32::consts::PI;
+
+    fn glossy() -> Material {
+        Material {
+            diffuse: Vec3f::new(0.0, 0.0, 0.0),
+            specular: Vec3f::new(0.9, 0.9, 0.9),
+            phong_exp: 10.0,
+            mirror: Vec3f::new(0.0, 0.0, 0.0),
+            microfacet: None,
+            conductor: None,
+            dielectric: None,
+            measured: None,
+            textures: None,
+            car_paint: None,
+            sheen: None,
+            dust: None,
+            dirt: None,
+            variation: None
+        }
+    }
+
+    #[test]
+    fn phong_sample_at_grazing_angle_stays_above_surface() {
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let out_dir = Vec3f::new(1.0, 0.0, -0.05).normalize();
+        let brdf = Brdf::new(&out_dir, &normal, &glossy()).unwrap();
+        for i in 0..32 {
+            for j in 0..32 {
+                let rnd = (0.5, (i as f32 + 0.5) / 32.0, (j as f32 + 0.5) / 32.0);
+                let sample = brdf.sample(rnd).expect("phong sample was rejected");
+                assert!(sample.wi.dot(&normal) > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn phong_sample_pdf_matches_eval() {
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let out_dir = Vec3f::new(1.0, 0.0, -0.2).normalize();
+        let brdf = Brdf::new(&out_dir, &normal, &glossy()).unwrap();
+        for i in 0..16 {
+            let rnd = (0.5, (i as f32 + 0.5) / 16.0, 0.3);
+            let sample = brdf.sample(rnd).unwrap();
+            let eval = brdf.eval(&sample.wi).unwrap();
+            assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf);
+            assert!((sample.radiance - eval.radiance).norm() <= 1e-3 * eval.radiance.norm());
+        }
+    }
+
+    #[test]
+    fn ideal_mirror_is_a_delta_next_to_the_diffuse() {
+        let mut material = Material::new_identity();
+        material.diffuse = Vec3f::new(0.3, 0.3, 0.3);
+        material.mirror = Vec3f::new(0.6, 0.6, 0.6);
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let out_dir = Vec3f::new(0.5, 0.0, -1.0).normalize();
+        let brdf = Brdf::new(&out_dir, &normal, &material).unwrap();
+        let (n, mut albedo, mut deltas) = (64, 0.0, 0);
+        for i in 0..n * n {
+            let rnd = ((i % n) as f32 / n as f32, ((i / n) as f32 + 0.5) / n as f32, 0.37);
+            if let Some(sample) = brdf.sample(rnd) {
+                if sample.delta {
+                    // exactly the mirror direction, and nothing eval can see
+                    assert!((sample.wi - Vec3f::new(0.5, 0.0, 1.0).normalize()).norm() < 1e-5);
+                    let eval = brdf.eval(&sample.wi).unwrap().radiance.x;
+                    assert!((eval - material.diffuse.x * sample.wi.z / PI).abs() < 1e-5, "{}", eval);
+                    deltas += 1;
+                }
+                albedo += sample.radiance.x / sample.pdf;
+            }
+        }
+        assert!((deltas as f32 / (n * n) as f32 - 2.0 / 3.0).abs() < 0.02, "{}", deltas);
+        // the lobes add up, their share of the albedo is only how often they're picked
+        albedo /= (n * n) as f32;
+        assert!((albedo - (0.3 + 0.6)).abs() < 0.02, "{}", albedo);
+    }
+
+    #[test]
+    fn samples_know_their_lobe() {
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let out_dir = Vec3f::new(0.5, 0.0, -1.0).normalize();
+        // the tails of a wide phong lobe are as unlikely as a diffuse bounce, they're glossy all the same
+        let glossy = Brdf::new(&out_dir, &normal, &glossy()).unwrap();
+        let mut diffuse_mirror = Material::new_identity();
+        diffuse_mirror.diffuse = Vec3f::new(0.3, 0.3, 0.3);
+        diffuse_mirror.mirror = Vec3f::new(0.6, 0.6, 0.6);
+        let diffuse_mirror = Brdf::new(&out_dir, &normal, &diffuse_mirror).unwrap();
+        let n = 32;
+        for i in 0..n * n {
+            let rnd = ((i % n) as f32 / n as f32, ((i / n) as f32 + 0.5) / n as f32, 0.37);
+            if let Some(sample) = glossy.sample(rnd) {
+                assert_eq!(sample.kind, BounceKind::Glossy, "{}", sample.pdf);
+            }
+            if let Some(sample) = diffuse_mirror.sample(rnd) {
+                assert_eq!(sample.kind, if sample.delta { BounceKind::Specular } else { BounceKind::Diffuse });
+            }
+        }
+    }
+
+    #[test]
+    fn glass_slab_passes_the_light_through() {
+        let glass = ::materials_and_colors::GLASS;
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let dir = Vec3f::new(0.6, 0.0, -0.8);
+        // into the slab and out of its bottom, whose normal looks the other way
+        let enter = Brdf::new(&dir, &normal, &glass).unwrap().sample((0.99, 0.5, 0.5)).unwrap();
+        assert!(enter.delta && enter.wi.z < 0.0 && enter.wi.x < dir.x);
+        let weight_in = enter.radiance.x / enter.pdf;
+        assert!((weight_in - 1.0 / 2.25).abs() < 1e-5, "{}", weight_in);
+        let leave = Brdf::new(&enter.wi, &-normal, &glass).unwrap().sample((0.99, 0.5, 0.5)).unwrap();
+        assert!((leave.wi - dir).norm() < 1e-5, "{:?}", leave.wi);
+        assert!((weight_in * leave.radiance.x / leave.pdf - 1.0).abs() < 1e-5);
+        // hit from the inside, past the critical angle, it only reflects
+        let inside = Brdf::new(&Vec3f::new(0.8, 0.0, 0.6), &normal, &glass).unwrap();
+        for i in 0..8 {
+            let sample = inside.sample((i as f32 / 8.0, 0.5, 0.5)).unwrap();
+            assert!(sample.wi.z < 0.0 && (sample.radiance.x / sample.pdf - 1.0).abs() < 1e-6);
+        }
+        // lights can't be sampled through it
+        assert!(Brdf::new(&dir, &normal, &glass).unwrap().is_delta());
+    }
+
+    #[test]
+    fn frosted_glass_samples_match_eval_and_keep_energy() {
+        let frosted = ::materials_and_colors::FROSTED_GLASS;
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        // from the outside and from the inside, below the critical angle
+        let sides = [(Vec3f::new(0.5, 0.0, -1.0).normalize(), 1.5), (Vec3f::new(0.2, 0.0, 1.0).normalize(), 1.0 / 1.5)];
+        for &(dir, eta) in &sides {
+            let brdf = Brdf::new(&dir, &normal, &frosted).unwrap();
+            assert!(!brdf.is_delta());
+            let (mut reflected, mut refracted) = (0.0, 0.0);
+            let n = 64;
+            for i in 0..n * n {
+                let grid = (((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32);
+                let rnd = (((i * 7) % n) as f32 / n as f32, grid.0, grid.1);
+                if let Some(sample) = brdf.sample(rnd) {
+                    assert!(!sample.delta);
+                    let eval = brdf.eval(&sample.wi).unwrap();
+                    assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf, "{} {}", sample.pdf, eval.pdf);
+                    if sample.wi.dot(&brdf.normal()) > 0.0 {
+                        reflected += sample.radiance.x / sample.pdf;
+                    } else {
+                        refracted += sample.radiance.x / sample.pdf;
+                    }
+                }
+            }
+            reflected /= (n * n) as f32;
+            refracted /= (n * n) as f32;
+            // most of it goes through, the squeeze of the radiance aside nothing is gained and little lost
+            let energy = reflected + refracted * eta * eta;
+            assert!(energy > 0.85 && energy <= 1.01, "{} {}", reflected, refracted);
+            assert!(refracted * eta * eta > 0.7, "{}", refracted);
+        }
+    }
+
+    #[test]
+    fn mixed_sample_uses_whole_brdf_pdf() {
+        let material = Material {
+            diffuse: Vec3f::new(0.5, 0.5, 0.5),
+            specular: Vec3f::new(0.5, 0.5, 0.5),
+            phong_exp: 20.0,
+            mirror: Vec3f::new(0.0, 0.0, 0.0),
+            microfacet: None,
+            conductor: None,
+            dielectric: None,
+            measured: None,
+            textures: None,
+            car_paint: None,
+            sheen: None,
+            dust: None,
+            dirt: None,
+            variation: None
+        };
+        let normal = Vec3f::new(0.0, 1.0, 0.0);
+        let out_dir = Vec3f::new(0.3, -1.0, 0.1).normalize();
+        let brdf = Brdf::new(&out_dir, &normal, &material).unwrap();
+        for i in 0..16 {
+            let rnd = ((i as f32 + 0.5) / 16.0, 0.7, (i as f32 + 0.5) / 16.0);
+            if let Some(sample) = brdf.sample(rnd) {
+                let eval = brdf.eval(&sample.wi).unwrap();
+                assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf);
+            }
+        }
+    }
+
+    #[test]
+    fn wireframe_of_a_sphere_is_its_uv_grid() {
+        let mut geos = GeometryList::new();
+        let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 };
+        geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
+        let wireframe = InspectionMaterial::Wireframe { width: 0.05, color: Vec3f::new(1.0, 0.0, 0.0) };
+        // the polar and azimuthal angles of the point, the equator is a line of the grid
+        let diffuse_at = |theta: f32, phi: f32| {
+            let point = Vec3f::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            let isect = geos.nearest_intersection(&Ray { orig: point * 2.0, dir: -point }).unwrap();
+            wireframe.apply(&Material::new_identity(), &isect).diffuse
+        };
+        let phi = (6.5 / 24.0 - 0.5) * 2.0 * PI;
+        assert_eq!(diffuse_at(0.5 * PI, phi), Vec3f::new(1.0, 0.0, 0.0));
+        assert_eq!(diffuse_at(6.5 / 12.0 * PI, phi), Vec3f::new(0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,331 @@
+use math::Vec3f;
+use math::vector_traits::*;
+use std::collections::HashMap;
+use std::f32::consts::{FRAC_2_PI, PI};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const TENSOR_MAGIC: &'static [u8] = b"tensor_file\0";
+const DTYPE_UINT8: u8 = 1;
+const DTYPE_FLOAT32: u8 = 10;
+
+// Brdf of the RGL material database (Dupuy and Jakob 2018) from its RGB tensor files. The values
+// are stored in the sample space of a warp following the visible normals: a direction pair is
+// mapped back through the inverse warp, and the value found there is scaled by the Jacobian
+// of that parametrization
+pub struct RglBrdf {
+    ndf: Warp2D,
+    sigma: Warp2D,
+    vndf: Warp2D,
+    rgb: Warp2D,
+    jacobian: bool,
+}
+
+impl RglBrdf {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<RglBrdf> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let mut fields = parse_tensor_file(&bytes)?;
+        let mut take = |name: &str| fields.remove(name).ok_or_else(|| invalid(format!("the field {} is missing", name)));
+
+        let theta_i = take("theta_i")?;
+        let phi_i = take("phi_i")?;
+        let ndf = take("ndf")?;
+        let sigma = take("sigma")?;
+        let vndf = take("vndf")?;
+        let rgb = take("rgb").map_err(|_| invalid("only the RGB files are supported".to_string()))?;
+        let jacobian = take("jacobian").map(|field| field.values.first().map_or(false, |&v| v != 0.0)).unwrap_or(false);
+
+        // the brdfs are tabulated isotropic, an anisotropic material would lose its orientation
+        if phi_i.values.len() > 2 {
+            return Err(invalid("only isotropic RGL materials are supported".to_string()));
+        }
+        let (phi_nb, theta_nb) = (phi_i.values.len(), theta_i.values.len());
+        if ndf.shape.len() != 2 || sigma.shape.len() != 2 || vndf.shape.len() != 4 || rgb.shape.len() != 5
+            || vndf.shape[..2] != [phi_nb, theta_nb] || rgb.shape[..3] != [phi_nb, theta_nb, 3] {
+            return Err(invalid("unexpected RGL tensor shapes".to_string()));
+        }
+        // the warps interpolate between neighbouring values
+        if [&ndf, &sigma, &vndf, &rgb].iter().any(|f| f.shape[f.shape.len() - 2..].iter().any(|&n| n < 2)) {
+            return Err(invalid("the RGL tables need 2 values a side at least".to_string()));
+        }
+
+        let angles = vec![phi_i.values, theta_i.values];
+        let mut channels = angles.clone();
+        channels.push(vec![0.0, 1.0, 2.0]);
+        Ok(RglBrdf {
+            ndf: Warp2D::new((ndf.shape[1], ndf.shape[0]), ndf.values, Vec::new(), false),
+            sigma: Warp2D::new((sigma.shape[1], sigma.shape[0]), sigma.values, Vec::new(), false),
+            vndf: Warp2D::new((vndf.shape[3], vndf.shape[2]), vndf.values, angles, true),
+            rgb: Warp2D::new((rgb.shape[4], rgb.shape[3]), rgb.values, channels, false),
+            jacobian: jacobian,
+        })
+    }
+
+    // brdf value times the cosine of `wo_local`
+    pub fn eval(&self, wi_local: &Vec3f, wo_local: &Vec3f) -> Vec3f {
+        if wi_local.z <= 0.0 || wo_local.z <= 0.0 {
+            return Vec3f::new(0.0, 0.0, 0.0);
+        }
+        let (theta_i, phi_i) = (elevation(wi_local), wi_local.y.atan2(wi_local.x));
+        let u_wi = (theta_to_u(theta_i), phi_to_u(phi_i));
+        let half = (*wi_local + *wo_local).normalize();
+        let u_phi_h = phi_to_u(half.y.atan2(half.x) - phi_i);
+        let u_half = (theta_to_u(elevation(&half)), u_phi_h - u_phi_h.floor());
+
+        let sample = self.vndf.invert(u_half, &[phi_i, theta_i]);
+        let channel = |c: usize| self.rgb.eval(sample, &[phi_i, theta_i, c as f32]);
+        let value = Vec3f::new(channel(0), channel(1), channel(2));
+        if self.jacobian {
+            value * (self.ndf.eval(u_half, &[]) / (4.0 * self.sigma.eval(u_wi, &[])))
+        } else {
+            value
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn elevation(v: &Vec3f) -> f32 {
+    v.z.max(-1.0).min(1.0).acos()
+}
+
+// the warps are parametrized by the square root of theta, denser near the normal
+fn theta_to_u(theta: f32) -> f32 {
+    (theta * FRAC_2_PI).sqrt()
+}
+
+fn phi_to_u(phi: f32) -> f32 {
+    (phi + PI) * 0.5 / PI
+}
+
+struct TensorField {
+    shape: Vec<usize>,
+    values: Vec<f32>,
+}
+
+// the tensor file of Mitsuba: a header listing the named fields, then their little endian data
+fn parse_tensor_file(bytes: &[u8]) -> io::Result<HashMap<String, TensorField>> {
+    if !bytes.starts_with(TENSOR_MAGIC) {
+        return Err(invalid("not a tensor file".to_string()));
+    }
+    let read = |offset: usize, size: usize| -> io::Result<u64> {
+        let end = offset.checked_add(size).ok_or_else(|| invalid("truncated tensor file".to_string()))?;
+        let chunk = bytes.get(offset..end).ok_or_else(|| invalid("truncated tensor file".to_string()))?;
+        Ok(chunk.iter().rev().fold(0u64, |v, &b| v << 8 | b as u64))
+    };
+
+    // the magic, then the major and minor version bytes
+    let mut pos = TENSOR_MAGIC.len() + 2;
+    let fields_nb = read(pos, 4)?;
+    pos += 4;
+    let mut fields = HashMap::new();
+    for _ in 0..fields_nb {
+        let name_len = read(pos, 2)? as usize;
+        let name = bytes.get(pos + 2..pos + 2 + name_len).ok_or_else(|| invalid("truncated tensor file".to_string()))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        pos += 2 + name_len;
+        let ndim = read(pos, 2)? as usize;
+        let dtype = read(pos + 2, 1)? as u8;
+        let offset = read(pos + 3, 8)? as usize;
+        pos += 11;
+        let mut shape = Vec::with_capacity(ndim);
+        for _ in 0..ndim {
+            shape.push(read(pos, 8)? as usize);
+            pos += 8;
+        }
+
+        let count = shape.iter().product::<usize>();
+        let values = match dtype {
+            DTYPE_FLOAT32 => (0..count)
+                .map(|i| read(offset + 4 * i, 4).map(|bits| f32::from_bits(bits as u32)))
+                .collect::<io::Result<Vec<_>>>()?,
+            DTYPE_UINT8 => (0..count).map(|i| read(offset + i, 1).map(|v| v as f32)).collect::<io::Result<Vec<_>>>()?,
+            // the fields not used, like the description, may have other types
+            _ => Vec::new(),
+        };
+        fields.insert(name, TensorField { shape: shape, values: values });
+    }
+    Ok(fields)
+}
+
+// Piecewise bilinear function over [0, 1]^2, interpolated between slices taken at the values of
+// its parameters (the Marginal2D of Mitsuba). Normalized, it's a distribution and `invert` maps
+// a point back to the sample the warp takes there
+struct Warp2D {
+    size: (usize, usize),
+    params: Vec<Vec<f32>>,
+    strides: Vec<usize>, // in slices, per parameter
+    data: Vec<f32>,
+    scale: f32, // the density of normalized data
+    marginal_cdf: Vec<f32>,
+    conditional_cdf: Vec<f32>,
+}
+
+impl Warp2D {
+    fn new(size: (usize, usize), mut data: Vec<f32>, params: Vec<Vec<f32>>, normalize: bool) -> Warp2D {
+        let (nx, ny) = size;
+        let values_nb = nx * ny;
+        let mut strides = vec![0; params.len()];
+        let mut slices_nb = 1;
+        for dim in (0..params.len()).rev() {
+            strides[dim] = slices_nb;
+            slices_nb *= params[dim].len();
+        }
+        data.resize(slices_nb * values_nb, 0.0);
+
+        let mut marginal_cdf = Vec::new();
+        let mut conditional_cdf = Vec::new();
+        let mut scale = 1.0;
+        if normalize {
+            marginal_cdf = vec![0.0; slices_nb * ny];
+            conditional_cdf = vec![0.0; slices_nb * values_nb];
+            for slice in 0..slices_nb {
+                let values = &mut data[slice * values_nb..(slice + 1) * values_nb];
+                let conditional = &mut conditional_cdf[slice * values_nb..(slice + 1) * values_nb];
+                let marginal = &mut marginal_cdf[slice * ny..(slice + 1) * ny];
+                for y in 0..ny {
+                    let mut sum = 0.0f64;
+                    for x in 0..nx - 1 {
+                        let i = y * nx + x;
+                        sum += 0.5 * (values[i] as f64 + values[i + 1] as f64);
+                        conditional[i + 1] = sum as f32;
+                    }
+                }
+                let mut sum = 0.0f64;
+                for y in 0..ny - 1 {
+                    sum += 0.5 * (conditional[(y + 1) * nx - 1] as f64 + conditional[(y + 2) * nx - 1] as f64);
+                    marginal[y + 1] = sum as f32;
+                }
+                let norm = 1.0 / marginal[ny - 1];
+                for v in values.iter_mut().chain(conditional.iter_mut()).chain(marginal.iter_mut()) {
+                    *v *= norm;
+                }
+            }
+            scale = ((nx - 1) * (ny - 1)) as f32;
+        }
+
+        Warp2D {
+            size: size,
+            params: params,
+            strides: strides,
+            data: data,
+            scale: scale,
+            marginal_cdf: marginal_cdf,
+            conditional_cdf: conditional_cdf,
+        }
+    }
+
+    // the slices around the parameters with their weights
+    fn slices(&self, param: &[f32]) -> Vec<(usize, f32)> {
+        let mut slices = vec![(0, 1.0)];
+        for (dim, values) in self.params.iter().enumerate() {
+            if values.len() < 2 {
+                continue;
+            }
+            let idx = values[1..values.len() - 1].iter().take_while(|&&v| v <= param[dim]).count();
+            let (p0, p1) = (values[idx], values[idx + 1]);
+            let t = ((param[dim] - p0) / (p1 - p0)).max(0.0).min(1.0);
+            let stride = self.strides[dim];
+            slices = slices.into_iter()
+                .flat_map(|(slice, w)| vec![(slice + idx * stride, w * (1.0 - t)), (slice + (idx + 1) * stride, w * t)])
+                .collect();
+        }
+        slices
+    }
+
+    fn lookup(slices: &[(usize, f32)], array: &[f32], index: usize, slice_size: usize) -> f32 {
+        slices.iter().map(|&(slice, w)| array[slice * slice_size + index] * w).sum()
+    }
+
+    // the patch the point is in and the position in it
+    fn patch(&self, pos: (f32, f32)) -> (usize, f32, f32) {
+        let (nx, ny) = self.size;
+        let (px, py) = (pos.0 * (nx - 1) as f32, pos.1 * (ny - 1) as f32);
+        let x = (px.max(0.0) as usize).min(nx - 2);
+        let y = (py.max(0.0) as usize).min(ny - 2);
+        (y * nx + x, px - x as f32, py - y as f32)
+    }
+
+    fn eval(&self, pos: (f32, f32), param: &[f32]) -> f32 {
+        let slices = self.slices(param);
+        let (idx, wx, wy) = self.patch(pos);
+        let nx = self.size.0;
+        let values_nb = nx * self.size.1;
+        let v = |i: usize| Warp2D::lookup(&slices, &self.data, i, values_nb);
+        let (v00, v10, v01, v11) = (v(idx), v(idx + 1), v(idx + nx), v(idx + nx + 1));
+        ((1.0 - wy) * ((1.0 - wx) * v00 + wx * v10) + wy * ((1.0 - wx) * v01 + wx * v11)) * self.scale
+    }
+
+    fn invert(&self, pos: (f32, f32), param: &[f32]) -> (f32, f32) {
+        let slices = self.slices(param);
+        let (idx, mut sx, sy) = self.patch(pos);
+        let (nx, ny) = self.size;
+        let values_nb = nx * ny;
+        let v = |array: &[f32], i: usize| Warp2D::lookup(&slices, array, i, values_nb);
+
+        let (v00, v10, v01, v11) = (v(&self.data, idx), v(&self.data, idx + 1), v(&self.data, idx + nx), v(&self.data, idx + nx + 1));
+        let (c0, c1) = ((1.0 - sy) * v00 + sy * v01, (1.0 - sy) * v10 + sy * v11);
+        sx *= c0 + 0.5 * sx * (c1 - c0);
+        sx += (1.0 - sy) * v(&self.conditional_cdf, idx) + sy * v(&self.conditional_cdf, idx + nx);
+
+        let row = idx / nx;
+        let (r0, r1) = (v(&self.conditional_cdf, row * nx + nx - 1), v(&self.conditional_cdf, row * nx + 2 * nx - 1));
+        sx /= (1.0 - sy) * r0 + sy * r1;
+        let y = sy * (r0 + 0.5 * sy * (r1 - r0)) + Warp2D::lookup(&slices, &self.marginal_cdf, row, ny);
+        (sx, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RglBrdf;
+    use math::Vec3f;
+    use math::vector_traits::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    // a tensor file of float fields, the data follows the header in the order of the fields
+    fn tensor_file(fields: &[(&str, Vec<u64>, f32)]) -> Vec<u8> {
+        let mut offset = fields.iter().fold(12 + 2 + 4, |len, &(name, ref shape, _)| len + 2 + name.len() + 2 + 1 + 8 + 8 * shape.len());
+        let mut header = b"tensor_file\0\x01\x00".to_vec();
+        header.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+        let mut data = Vec::new();
+        for &(name, ref shape, value) in fields {
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(name.as_bytes());
+            header.extend_from_slice(&(shape.len() as u16).to_le_bytes());
+            header.push(10);
+            header.extend_from_slice(&(offset as u64).to_le_bytes());
+            for dim in shape {
+                header.extend_from_slice(&dim.to_le_bytes());
+            }
+            let count = shape.iter().product::<u64>() as usize;
+            for _ in 0..count {
+                data.extend_from_slice(&value.to_bits().to_le_bytes());
+            }
+            offset += 4 * count;
+        }
+        header.extend_from_slice(&data);
+        header
+    }
+
+    #[test]
+    fn constant_tensor_file_gives_a_constant_brdf() {
+        let bytes = tensor_file(&[("theta_i", vec![4], 0.5), ("phi_i", vec![1], 0.0), ("jacobian", vec![1], 1.0),
+                                  ("ndf", vec![8, 8], 2.0), ("sigma", vec![8, 8], 0.25),
+                                  ("vndf", vec![1, 4, 8, 8], 1.0), ("rgb", vec![1, 4, 3, 8, 8], 0.25)]);
+        let path = ::std::env::temp_dir().join("xray_constant.bsdf");
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        let brdf = RglBrdf::load(&path).unwrap();
+
+        let wi = Vec3f::new(0.3, 0.2, 0.9).normalize();
+        let wo = Vec3f::new(-0.5, 0.1, 0.7).normalize();
+        // the rgb values scaled by ndf / (4 sigma)
+        assert!((brdf.eval(&wi, &wo) - Vec3f::new(0.5, 0.5, 0.5)).norm() < 1e-5);
+        assert_eq!(brdf.eval(&Vec3f::new(0.0, 0.6, -0.8), &wo), Vec3f::new(0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,72 @@
+use brdf::Material;
+use math::Vec3f;
+use std::f32::consts::PI;
+use utility::luminance;
+
+// Charlie sheen (Estevez & Kulla) with Neubelt's visibility term:
+// the retroreflective rim of fabrics made of tiny fibers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sheen {
+    pub intensity: f32,
+    pub tint: f32, // 0 - white sheen, 1 - sheen of the diffuse color
+    pub roughness: f32, // perceptual, in (0, 1]
+}
+
+impl Sheen {
+    pub fn new(intensity: f32, tint: f32, roughness: f32) -> Sheen {
+        Sheen { intensity: intensity, tint: tint, roughness: roughness }
+    }
+
+    pub fn color(&self, material: &Material) -> Vec3f {
+        let white = Vec3f::new(1.0, 1.0, 1.0);
+        let lum = luminance(&material.diffuse);
+        let tint_color = if lum > 0.0 { material.diffuse / lum } else { white };
+        (white * (1.0 - self.tint) + tint_color * self.tint) * self.intensity
+    }
+
+    // brdf * cos(theta_i)
+    pub fn eval_local(&self, material: &Material, wi_local: &Vec3f, wo_local: &Vec3f) -> Vec3f {
+        let (cos_i, cos_o) = (wi_local.z, wo_local.z);
+        let half = *wi_local + *wo_local;
+        let cos_h2 = half.z * half.z / (half.x * half.x + half.y * half.y + half.z * half.z);
+        let sin_h2 = (1.0 - cos_h2).max(0.0);
+
+        let alpha = (self.roughness * self.roughness).max(1e-3);
+        let inv_alpha = 1.0 / alpha;
+        let d = (2.0 + inv_alpha) * sin_h2.powf(inv_alpha * 0.5) / (2.0 * PI);
+        let v = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+        self.color(material) * (d * v * cos_i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sheen;
+    use brdf::{Brdf, Material};
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    #[test]
+    fn sheen_is_brighter_at_grazing_angles() {
+        let mut material = Material::new_identity();
+        material.diffuse = Vec3f::new(0.3, 0.1, 0.1);
+        material.sheen = Some(Sheen::new(1.0, 0.5, 0.5));
+        let normal = Vec3f::new(0.0, 0.0, 1.0);
+        let head_on = Brdf::new(&Vec3f::new(0.0, 0.0, -1.0), &normal, &material).unwrap();
+        let grazing = Brdf::new(&Vec3f::new(1.0, 0.0, -0.1).normalize(), &normal, &material).unwrap();
+        let wi = Vec3f::new(-1.0, 0.0, 0.3).normalize();
+        let sheen_of = |brdf: &Brdf| {
+            let eval = brdf.eval(&wi).unwrap();
+            eval.radiance.y - material.diffuse.y * wi.z * ::std::f32::consts::FRAC_1_PI
+        };
+        assert!(sheen_of(&grazing) > sheen_of(&head_on));
+
+        for i in 0..16 {
+            let rnd = ((i as f32 + 0.5) / 16.0, 0.3, (i as f32 + 0.5) / 16.0);
+            if let Some(sample) = grazing.sample(rnd) {
+                let eval = grazing.eval(&sample.wi).unwrap();
+                assert!((sample.pdf - eval.pdf).abs() <= 1e-3 * eval.pdf);
+            }
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use brdf::Material;
+use geometry::SurfaceIntersection;
+use math::{Vec2f, Vec3f, Zero, clamp, ortho};
+use math::vector_traits::*;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use texture::Texture;
+
+const DIELECTRIC_REFLECTANCE: f32 = 0.04;
+const HEIGHT_DELTA_UV: f32 = 1e-3;
+
+// where the values of a map come from
+#[derive(Debug, Clone)]
+pub enum TextureSource {
+    Image(Arc<Texture>),
+    VertexColor, // white on objects without vertex colors
+    // mesh curvature as gray: 0.5 is flat, brighter is convex (edges), darker is concave (cavities)
+    Curvature { scale: f32 }, // curvature which maps to white, 1 / radius
+    AmbientOcclusion, // baked by Mesh::bake_occlusion, white elsewhere
+    BentNormal, // world space, encoded like normal maps: n * 0.5 + 0.5
+}
+
+// one channel of a (possibly packed) texture
+#[derive(Debug, Clone)]
+pub struct ChannelMap {
+    pub texture: TextureSource,
+    pub channel: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NormalMapConvention {
+    OpenGl, // green points up, Substance/Blender default
+    DirectX, // green points down, Unreal/3ds Max
+}
+
+// where texture coordinates come from, all but Uv work for objects without uv
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    Uv,
+    Planar { origin: Vec3f, u_axis: Vec3f, v_axis: Vec3f }, // axis length is texture repeats per unit
+    Spherical { center: Vec3f },
+    Box { scale: f32 }, // planar along the axis closest to the normal
+    Triplanar { scale: f32, sharpness: f32 }, // three planar projections blended by the normal
+}
+
+// texture coordinates of one of the planes of a projection
+#[derive(Debug, Clone, Copy)]
+struct ProjectedCoord {
+    weight: f32,
+    uv: Vec2f,
+    dpdu: Vec3f,
+}
+
+// Metallic/roughness map set. Maps which aren't set keep the values of the base material
+#[derive(Debug, Clone)]
+pub struct TextureSet {
+    pub projection: Projection,
+    pub base_color: Option<TextureSource>,
+    pub normal: Option<Arc<Texture>>,
+    pub normal_convention: NormalMapConvention,
+    pub roughness: Option<ChannelMap>,
+    pub metallic: Option<ChannelMap>,
+    pub occlusion: Option<ChannelMap>, // darkens the diffuse lobe
+    pub height: Option<ChannelMap>,
+    pub height_scale: f32,
+    // LEAN mapping: slope variance of the normal map, a mip chain of it for footprints of more and more
+    // texels; the variance under the footprint of a hit widens the specular lobe
+    pub normal_variance: Option<Arc<Vec<Texture>>>,
+}
+
+impl TextureSet {
+    pub fn new() -> TextureSet {
+        TextureSet {
+            projection: Projection::Uv,
+            base_color: None,
+            normal: None,
+            normal_convention: NormalMapConvention::OpenGl,
+            roughness: None,
+            metallic: None,
+            occlusion: None,
+            height: None,
+            height_scale: 1.0,
+            normal_variance: None,
+        }
+    }
+
+    // glTF/Unreal packing: occlusion in red, roughness in green, metallic in blue
+    pub fn with_orm(mut self, orm: Arc<Texture>) -> TextureSet {
+        self.occlusion = Some(ChannelMap { texture: TextureSource::Image(orm.clone()), channel: 0 });
+        self.roughness = Some(ChannelMap { texture: TextureSource::Image(orm.clone()), channel: 1 });
+        self.metallic = Some(ChannelMap { texture: TextureSource::Image(orm), channel: 2 });
+        self
+    }
+
+    pub fn with_projection(mut self, projection: Projection) -> TextureSet {
+        self.projection = projection;
+        self
+    }
+
+    pub fn with_base_color<T: Into<TextureSource>>(mut self, source: T) -> TextureSet {
+        self.base_color = Some(source.into());
+        self
+    }
+
+    pub fn with_normal(mut self, tex: Arc<Texture>, convention: NormalMapConvention) -> TextureSet {
+        self.normal = Some(tex);
+        self.normal_convention = convention;
+        self
+    }
+
+    // Filters the normal map for shading seen from afar: the normals the footprint of a hit covers
+    // become a wider specular lobe (LEAN mapping, Olano & Baker 2010), so sparkly surfaces don't alias
+    // under small lights. The footprint is the one of the ray cone at the hit (see `apply_filtered`),
+    // at least `footprint` texels. Call after `with_normal`
+    pub fn with_normal_filtering(mut self, footprint: usize) -> TextureSet {
+        self.normal_variance = self.normal.as_ref().map(|tex| {
+            Arc::new(slope_variance(tex, self.normal_convention, footprint))
+        });
+        self
+    }
+
+    pub fn with_roughness(mut self, map: ChannelMap) -> TextureSet {
+        self.roughness = Some(map);
+        self
+    }
+
+    pub fn with_occlusion(mut self, map: ChannelMap) -> TextureSet {
+        self.occlusion = Some(map);
+        self
+    }
+
+    pub fn with_metallic(mut self, map: ChannelMap) -> TextureSet {
+        self.metallic = Some(map);
+        self
+    }
+
+    pub fn with_height(mut self, map: ChannelMap, scale: f32) -> TextureSet {
+        self.height = Some(map);
+        self.height_scale = scale;
+        self
+    }
+
+    // Maps `apply` and `shading_normal` never look at on `base`: the lobes a map changes aren't there
+    pub fn unused_maps(&self, base: &Material) -> Vec<&'static str> {
+        let mut unused = Vec::new();
+        // glass and measured brdfs replace the analytic lobes the maps change, they only keep the normal
+        let analytic = base.dielectric.is_none() && base.measured.is_none();
+        let colored = self.base_color.is_some() || self.metallic.is_some();
+        let diffuse = colored || base.diffuse.fold(f32::max) > 0.0;
+        let glossy = colored || base.specular.fold(f32::max) > 0.0;
+        if self.base_color.is_some() && !analytic {
+            unused.push("base color");
+        }
+        if self.metallic.is_some() && !analytic {
+            unused.push("metallic");
+        }
+        if self.occlusion.is_some() && !(analytic && diffuse) {
+            unused.push("occlusion");
+        }
+        if self.roughness.is_some() && !(analytic && glossy) {
+            unused.push("roughness");
+        }
+        if self.height.is_some() && self.height_scale == 0.0 {
+            unused.push("height");
+        }
+        if self.normal_variance.is_some() && !(analytic && glossy && self.normal.is_some()) {
+            unused.push("normal variance");
+        }
+        unused
+    }
+
+    // constant material the maps give at the hit point
+    pub fn apply(&self, base: &Material, isect: &SurfaceIntersection) -> Material {
+        self.apply_filtered(base, isect, 0.0)
+    }
+
+    // `apply` for a hit seen through a ray cone `footprint` wide there, in world units;
+    // the normal map is filtered over it
+    pub fn apply_filtered(&self, base: &Material, isect: &SurfaceIntersection, footprint: f32) -> Material {
+        let mut material = base.clone();
+        material.textures = None;
+
+        let coords = self.projection.project(isect);
+        let base_color = self.base_color.as_ref().map(|tex| blend(&coords, |c| tex.lookup(&c.uv, isect)));
+        let metallic = self.metallic.as_ref().map(|map| blend(&coords, |c| lookup(map, &c.uv, isect)));
+        if base_color.is_some() || metallic.is_some() {
+            let color = base_color.unwrap_or(base.diffuse);
+            let metallic = metallic.unwrap_or(0.0);
+            let f0 = Vec3f::new(DIELECTRIC_REFLECTANCE, DIELECTRIC_REFLECTANCE, DIELECTRIC_REFLECTANCE);
+            material.diffuse = color * (1.0 - metallic);
+            material.specular = f0 * (1.0 - metallic) + color * metallic;
+        }
+
+        if let Some(ref map) = self.occlusion {
+            material.diffuse = material.diffuse * blend(&coords, |c| lookup(map, &c.uv, isect));
+        }
+
+        if let Some(ref map) = self.roughness {
+            let roughness = blend(&coords, |c| lookup(map, &c.uv, isect));
+            material.phong_exp = roughness_to_phong_exp(roughness);
+            if let Some(ref mut microfacet) = material.microfacet {
+                microfacet.roughness = roughness;
+            }
+        }
+
+        if let Some(ref levels) = self.normal_variance {
+            // a texel of the map is |dpdu| / width long on the surface
+            let texels = |c: &ProjectedCoord| footprint * levels[0].width() as f32 / c.dpdu.norm().max(1e-12);
+            let variance = blend(&coords, |c| lean_variance(levels, &c.uv, texels(c)));
+            material.phong_exp = widen_phong_exp(material.phong_exp, variance);
+        }
+        material
+    }
+
+    pub fn shading_normal(&self, isect: &SurfaceIntersection) -> Vec3f {
+        let normal = isect.normal;
+        if self.normal.is_none() && self.height.is_none() {
+            return normal;
+        }
+
+        // every plane of the projection perturbs the normal in its own tangent frame
+        let coords = self.projection.project(isect);
+        let shading_normal = blend(&coords, |c| self.perturbed_normal(isect, c));
+        if shading_normal.sqnorm() > 1e-12 { shading_normal.normalize() } else { normal }
+    }
+
+    fn perturbed_normal(&self, isect: &SurfaceIntersection, coord: &ProjectedCoord) -> Vec3f {
+        let normal = isect.normal;
+        let tangent = coord.dpdu - normal * normal.dot(&coord.dpdu);
+        let tangent = if tangent.sqnorm() > 1e-12 { tangent.normalize() } else { ortho(&normal) };
+        let bitangent = normal.cross(&tangent);
+
+        let mut shading_normal = normal;
+        if let Some(ref tex) = self.normal {
+            let mut n = tex.lookup(&coord.uv) * 2.0 - Vec3f::new(1.0, 1.0, 1.0);
+            if self.normal_convention == NormalMapConvention::DirectX {
+                n.y = -n.y;
+            }
+            shading_normal = tangent * n.x + bitangent * n.y + normal * n.z;
+        }
+
+        if let Some(ref map) = self.height {
+            let uv = coord.uv;
+            let h = lookup(map, &uv, isect);
+            let du = Vec2f::new(uv.x + HEIGHT_DELTA_UV, uv.y);
+            let dv = Vec2f::new(uv.x, uv.y + HEIGHT_DELTA_UV);
+            let dhdu = (lookup(map, &du, isect) - h) / HEIGHT_DELTA_UV * self.height_scale;
+            let dhdv = (lookup(map, &dv, isect) - h) / HEIGHT_DELTA_UV * self.height_scale;
+            shading_normal = shading_normal - tangent * dhdu - bitangent * dhdv;
+        }
+        shading_normal
+    }
+}
+
+impl Projection {
+    // unused planes get zero weight
+    fn project(&self, isect: &SurfaceIntersection) -> [ProjectedCoord; 3] {
+        let unused = ProjectedCoord { weight: 0.0, uv: Vec2f::new(0.0, 0.0), dpdu: Vec3f::zero() };
+        let mut coords = [unused; 3];
+        let p = isect.position;
+        match *self {
+            Projection::Uv => {
+                coords[0] = ProjectedCoord { weight: 1.0, uv: isect.uv, dpdu: isect.dpdu };
+            },
+            Projection::Planar { origin, u_axis, v_axis } => {
+                let d = p - origin;
+                coords[0] = ProjectedCoord { weight: 1.0, uv: Vec2f::new(d.dot(&u_axis), d.dot(&v_axis)), dpdu: u_axis };
+            },
+            Projection::Spherical { center } => {
+                let d = (p - center).normalize();
+                let uv = Vec2f::new(0.5 + d.z.atan2(d.x) / (2.0 * PI), 0.5 + d.y.max(-1.0).min(1.0).asin() / PI);
+                coords[0] = ProjectedCoord { weight: 1.0, uv: uv, dpdu: Vec3f::new(-d.z, 0.0, d.x) };
+            },
+            Projection::Box { scale } => {
+                let n = isect.normal.map(|c| c.abs());
+                let axis = if n.x >= n.y && n.x >= n.z { 0 } else if n.y >= n.z { 1 } else { 2 };
+                coords[axis] = axis_coord(axis, &p, scale, 1.0);
+            },
+            Projection::Triplanar { scale, sharpness } => {
+                let n = isect.normal.map(|c| c.abs().powf(sharpness));
+                let sum = n.x + n.y + n.z;
+                for axis in 0..3 {
+                    coords[axis] = axis_coord(axis, &p, scale, n[axis] / sum);
+                }
+            }
+        }
+        coords
+    }
+}
+
+// planar projection along one of the world axes
+fn axis_coord(axis: usize, p: &Vec3f, scale: f32, weight: f32) -> ProjectedCoord {
+    let (uv, dpdu) = match axis {
+        0 => (Vec2f::new(p.z, p.y), Vec3f::new(0.0, 0.0, 1.0)),
+        1 => (Vec2f::new(p.x, p.z), Vec3f::new(1.0, 0.0, 0.0)),
+        _ => (Vec2f::new(p.x, p.y), Vec3f::new(1.0, 0.0, 0.0)),
+    };
+    ProjectedCoord { weight: weight, uv: uv * scale, dpdu: dpdu }
+}
+
+fn blend<T, F>(coords: &[ProjectedCoord; 3], f: F) -> T
+    where T: Zero + ::std::ops::Add<T, Output = T> + ::std::ops::Mul<f32, Output = T>,
+          F: Fn(&ProjectedCoord) -> T {
+    coords.iter()
+        .filter(|c| c.weight > 0.0)
+        .fold(T::zero(), |sum, c| sum + f(c) * c.weight)
+}
+
+impl TextureSource {
+    pub fn lookup(&self, uv: &Vec2f, isect: &SurfaceIntersection) -> Vec3f {
+        match *self {
+            TextureSource::Image(ref tex) => tex.lookup(uv),
+            TextureSource::VertexColor    => isect.vertex.map_or(Vec3f::new(1.0, 1.0, 1.0), |v| v.color),
+            TextureSource::Curvature { scale } => {
+                let curvature = isect.vertex.map_or(0.0, |v| v.curvature);
+                let gray = 0.5 + 0.5 * clamp(curvature / scale, -1.0, 1.0);
+                Vec3f::new(gray, gray, gray)
+            },
+            TextureSource::AmbientOcclusion => {
+                let occlusion = isect.vertex.map_or(1.0, |v| v.occlusion);
+                Vec3f::new(occlusion, occlusion, occlusion)
+            },
+            TextureSource::BentNormal => {
+                let normal = isect.vertex.map_or(isect.normal, |v| v.bent_normal);
+                normal * 0.5 + Vec3f::new(0.5, 0.5, 0.5)
+            }
+        }
+    }
+}
+
+impl From<Arc<Texture>> for TextureSource {
+    fn from(tex: Arc<Texture>) -> TextureSource {
+        TextureSource::Image(tex)
+    }
+}
+
+fn lookup(map: &ChannelMap, uv: &Vec2f, isect: &SurfaceIntersection) -> f32 {
+    let texel = map.texture.lookup(uv, isect);
+    match map.channel {
+        0 => texel.x,
+        1 => texel.y,
+        _ => texel.z
+    }
+}
+
+// perceptual roughness to the Phong exponent of the Blinn-Phong/Beckmann fit
+pub fn roughness_to_phong_exp(roughness: f32) -> f32 {
+    let alpha = (roughness * roughness).max(1e-3);
+    (2.0 / (alpha * alpha) - 2.0).max(1.0)
+}
+
+// Blinn-Phong lobe of the exponent convolved with a slope distribution of the variance,
+// the lobe's alpha^2 = 2 / (n + 2) is the slope variance of the microfacets
+pub fn widen_phong_exp(phong_exp: f32, slope_variance: f32) -> f32 {
+    if slope_variance <= 0.0 {
+        return phong_exp;
+    }
+    let alpha2 = 2.0 / (phong_exp + 2.0) + slope_variance;
+    (2.0 / alpha2 - 2.0).max(1.0).min(phong_exp)
+}
+
+// Variance of the normal map slopes (x/z, y/z) in a box of `footprint` texels around every texel,
+// from box filtered first and second moments; sum of both axes in every channel. Then the same from
+// the moments halved again and again, every level for footprints twice as wide. Every UDIM tile on its own
+fn slope_variance(normal: &Texture, convention: NormalMapConvention, footprint: usize) -> Vec<Texture> {
+    let moments = normal.map_tiles(&|width, height, texels| (width, height, box_moments(width, height, texels, convention, footprint)))
+        .expect("same size as the normal map");
+    let variance = |moments: &Texture| moments.map_tiles(&|width, height, texels| {
+        (width, height, texels.iter().map(|m| {
+            let variance = (m.z - m.x * m.x - m.y * m.y).max(0.0);
+            Vec3f::new(variance, variance, variance)
+        }).collect())
+    });
+    let mut levels = vec![variance(&moments).expect("same size as the normal map")];
+    let mut moments = moments;
+    // the levels which don't fit into the memory budget are left out, the filtering stops short of them
+    while moments.width() > 1 || moments.height() > 1 {
+        match moments.downsampled().and_then(|next| variance(&next).map(|level| (next, level))) {
+            Ok((next, level)) => { levels.push(level); moments = next; },
+            Err(_)            => break
+        }
+    }
+    levels
+}
+
+fn box_moments(width: usize, height: usize, normals: &[Vec3f], convention: NormalMapConvention, footprint: usize) -> Vec<Vec3f> {
+    let moments = normals.iter().map(|n| {
+        let mut n = *n * 2.0 - Vec3f::new(1.0, 1.0, 1.0);
+        if convention == NormalMapConvention::DirectX {
+            n.y = -n.y;
+        }
+        let z = n.z.max(0.1);
+        let (sx, sy) = (n.x / z, n.y / z);
+        Vec3f::new(sx, sy, sx * sx + sy * sy)
+    }).collect::<Vec<_>>();
+
+    // separable box filter, the texture wraps around
+    let radius = (footprint / 2) as isize;
+    let k = 1.0 / (2 * radius + 1) as f32;
+    let wrap = |i: isize, n: usize| ((i % n as isize + n as isize) % n as isize) as usize;
+    let mut rows = vec![Vec3f::zero(); width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let sum = (-radius..radius + 1)
+                .fold(Vec3f::zero(), |sum, d| sum + moments[y * width + wrap(x as isize + d, width)]);
+            rows[y * width + x] = sum * k;
+        }
+    }
+    let mut filtered = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            filtered.push((-radius..radius + 1)
+                .fold(Vec3f::zero(), |sum, d| sum + rows[wrap(y as isize + d, height) * width + x]) * k);
+        }
+    }
+    filtered
+}
+
+// slope variance at `uv` under a footprint `texels` wide, between the two levels closest to it
+fn lean_variance(levels: &[Texture], uv: &Vec2f, texels: f32) -> f32 {
+    let level = texels.max(1.0).log2().min((levels.len() - 1) as f32);
+    let (fine, t) = (level.floor() as usize, level.fract());
+    if t > 0.0 {
+        levels[fine].lookup(uv).x * (1.0 - t) + levels[fine + 1].lookup(uv).x * t
+    } else {
+        levels[fine].lookup(uv).x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelMap, NormalMapConvention, Projection, TextureSet, TextureSource};
+    use math::vector_traits::*;
+    use brdf::Material;
+    use geometry::{SurfaceIntersection, VertexAttributes};
+    use math::{Vec2f, Vec3f};
+    use scene::SurfaceProperties;
+    use std::sync::Arc;
+    use texture::Texture;
+
+    fn isect() -> SurfaceIntersection {
+        SurfaceIntersection {
+            normal: Vec3f::new(0.0, 0.0, 1.0),
+            dist: 1.0,
+            uv: Vec2f::new(0.5, 0.5),
+            dpdu: Vec3f::new(1.0, 0.0, 0.0),
+            barycentric: None,
+            vertex: None,
+            position: Vec3f::new(0.0, 0.0, 0.0),
+            surface: SurfaceProperties::Material(0),
+        }
+    }
+
+    #[test]
+    fn metal_has_no_diffuse() {
+        let orm = Arc::new(Texture::new_constant(Vec3f::new(1.0, 0.5, 1.0)));
+        let color = Arc::new(Texture::new_constant(Vec3f::new(0.9, 0.6, 0.2)));
+        let set = TextureSet::new().with_base_color(color).with_orm(orm);
+        let material = set.apply(&Material::new_identity(), &isect());
+        assert_eq!(material.diffuse, Vec3f::new(0.0, 0.0, 0.0));
+        assert_eq!(material.specular, Vec3f::new(0.9, 0.6, 0.2));
+        assert!(material.phong_exp > 1.0);
+    }
+
+    #[test]
+    fn directx_normal_map_flips_green() {
+        let up = Arc::new(Texture::new_constant(Vec3f::new(0.5, 1.0, 0.5)));
+        let opengl = TextureSet::new().with_normal(up.clone(), NormalMapConvention::OpenGl);
+        let directx = TextureSet::new().with_normal(up, NormalMapConvention::DirectX);
+        assert!(opengl.shading_normal(&isect()).y > 0.5);
+        assert!(directx.shading_normal(&isect()).y < -0.5);
+
+        let flat = Arc::new(Texture::new_constant(Vec3f::new(0.3, 0.3, 0.3)));
+        let flat = ChannelMap { texture: flat.into(), channel: 0 };
+        let bumped = TextureSet::new().with_height(flat, 5.0);
+        assert_eq!(bumped.shading_normal(&isect()), Vec3f::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn triplanar_weights_follow_the_normal() {
+        let mut hit = isect();
+        hit.normal = Vec3f::new(0.0, 1.0, 0.0);
+        let coords = Projection::Triplanar { scale: 1.0, sharpness: 4.0 }.project(&hit);
+        assert_eq!((coords[0].weight, coords[1].weight, coords[2].weight), (0.0, 1.0, 0.0));
+
+        hit.normal = Vec3f::new(1.0, 1.0, 0.0).normalize();
+        let coords = Projection::Triplanar { scale: 1.0, sharpness: 4.0 }.project(&hit);
+        assert!((coords[0].weight - 0.5).abs() < 1e-5 && (coords[1].weight - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn vertex_colors_are_a_texture_source() {
+        let mut hit = isect();
+        let mut vertex = VertexAttributes::new();
+        vertex.color = Vec3f::new(0.2, 0.4, 0.6);
+        hit.vertex = Some(vertex);
+        let set = TextureSet::new().with_base_color(TextureSource::VertexColor);
+        assert_eq!(set.apply(&Material::new_identity(), &hit).diffuse, Vec3f::new(0.2, 0.4, 0.6));
+        assert_eq!(set.apply(&Material::new_identity(), &isect()).diffuse, Vec3f::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn filtered_normals_widen_the_highlight() {
+        let mut base = Material::new_identity();
+        base.phong_exp = 1000.0;
+        let flat = Arc::new(Texture::new_constant(Vec3f::new(0.5, 0.5, 1.0)));
+        let flat = TextureSet::new().with_normal(flat, NormalMapConvention::OpenGl).with_normal_filtering(3);
+        assert_eq!(flat.apply(&base, &isect()).phong_exp, 1000.0);
+
+        // a checker of normals tilted to the left and to the right
+        let texels = (0..16).map(|i| if (i % 4 + i / 4) % 2 == 0 {
+            Vec3f::new(0.2, 0.5, 0.9)
+        } else {
+            Vec3f::new(0.8, 0.5, 0.9)
+        }).collect();
+        let bumpy = Arc::new(Texture::new(4, 4, texels).unwrap());
+        let sharp = TextureSet::new().with_normal(bumpy.clone(), NormalMapConvention::OpenGl).with_normal_filtering(1);
+        assert_eq!(sharp.apply(&base, &isect()).phong_exp, 1000.0);
+        let filtered = TextureSet::new().with_normal(bumpy, NormalMapConvention::OpenGl).with_normal_filtering(3);
+        let phong_exp = filtered.apply(&base, &isect()).phong_exp;
+        assert!(phong_exp > 1.0 && phong_exp < 10.0);
+
+        // seen from afar, the cone covers the checker even with the narrowest filter
+        let far = sharp.apply_filtered(&base, &isect(), 0.5).phong_exp;
+        assert!(far > 1.0 && far < 10.0, "{}", far);
+        assert_eq!(sharp.apply_filtered(&base, &isect(), 0.1).phong_exp, 1000.0);
+    }
+
+    #[test]
+    fn every_udim_tile_is_filtered() {
+        let mut base = Material::new_identity();
+        base.phong_exp = 1000.0;
+        let dir = ::std::env::temp_dir().join("xray_lean_udim_test");
+        ::std::fs::create_dir_all(&dir).unwrap();
+        // a flat first tile and a checker of tilted normals in the second one
+        for &(udim, bumpy) in [(1001, false), (1002, true)].iter() {
+            let mut ppm = b"P6 4 4 255\n".to_vec();
+            for i in 0..16 {
+                ppm.extend_from_slice(match (bumpy, (i % 4 + i / 4) % 2 == 0) {
+                    (false, _)    => &[128, 128, 255],
+                    (true, true)  => &[51, 128, 230],
+                    (true, false) => &[204, 128, 230]
+                });
+            }
+            ::std::fs::write(dir.join(format!("normal.{}.ppm", udim)), ppm).unwrap();
+        }
+        let normal = Texture::load(dir.join("normal.<UDIM>.ppm"), false).unwrap();
+        let set = TextureSet::new().with_normal(Arc::new(normal), NormalMapConvention::OpenGl).with_normal_filtering(3);
+        let mut hit = isect();
+        assert!(set.apply(&base, &hit).phong_exp > 990.0);
+        hit.uv = Vec2f::new(1.5, 0.5);
+        assert!(set.apply(&base, &hit).phong_exp < 10.0);
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
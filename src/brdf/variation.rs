@@ -0,0 +1,88 @@
+use brdf::Material;
+use math::Vec3f;
+use utility::hash_u64;
+
+// Every object gets its own MaterialID, so objects added with the same material
+// still look different: the id picks where each parameter lands inside of its range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MaterialVariation {
+    pub hue: f32, // max hue shift, in turns
+    pub value: f32, // max relative brightness change of the diffuse color
+    pub roughness: f32, // max phong exponent change, in octaves
+}
+
+impl MaterialVariation {
+    pub fn new(hue: f32, value: f32, roughness: f32) -> MaterialVariation {
+        MaterialVariation { hue: hue, value: value, roughness: roughness }
+    }
+
+    pub fn apply(&self, material: &Material, instance: u64) -> Material {
+        let hash = hash_u64(instance);
+        // three signed randoms in [-1, 1)
+        let rnd = |shift: u32| ((hash >> shift) & 0x1FFFFF) as f32 / (1 << 20) as f32 - 1.0;
+
+        let mut material = material.clone();
+        material.variation = None;
+        let (h, s, v) = rgb_to_hsv(&material.diffuse);
+        let h = (h + self.hue * rnd(0)).rem_euclid(1.0);
+        let v = v * (1.0 + self.value * rnd(21)).max(0.0);
+        material.diffuse = hsv_to_rgb(h, s, v);
+        material.phong_exp = (material.phong_exp * (self.roughness * rnd(42)).exp2()).max(1.0);
+        material
+    }
+}
+
+fn rgb_to_hsv(c: &Vec3f) -> (f32, f32, f32) {
+    let max = c.x.max(c.y).max(c.z);
+    let min = c.x.min(c.y).min(c.z);
+    let delta = max - min;
+    let h = if delta <= 0.0 {
+        0.0
+    } else if max == c.x {
+        ((c.y - c.z) / delta / 6.0).rem_euclid(1.0)
+    } else if max == c.y {
+        ((c.z - c.x) / delta + 2.0) / 6.0
+    } else {
+        ((c.x - c.y) / delta + 4.0) / 6.0
+    };
+    let s = if max > 0.0 { delta / max } else { 0.0 };
+    (h, s, max)
+}
+
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> Vec3f {
+    let sector = h * 6.0;
+    let f = sector - sector.floor();
+    let (p, q, t) = (v * (1.0 - s), v * (1.0 - s * f), v * (1.0 - s * (1.0 - f)));
+    match sector as i32 % 6 {
+        0 => Vec3f::new(v, t, p),
+        1 => Vec3f::new(q, v, p),
+        2 => Vec3f::new(p, v, t),
+        3 => Vec3f::new(p, q, v),
+        4 => Vec3f::new(t, p, v),
+        _ => Vec3f::new(v, p, q),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MaterialVariation, hsv_to_rgb, rgb_to_hsv};
+    use brdf::Material;
+    use math::Vec3f;
+    use math::vector_traits::*;
+
+    #[test]
+    fn variation_is_stable_per_instance() {
+        let c = Vec3f::new(0.2, 0.6, 0.3);
+        let (h, s, v) = rgb_to_hsv(&c);
+        assert!((hsv_to_rgb(h, s, v) - c).norm() < 1e-5);
+
+        let mut material = Material::new_identity();
+        material.diffuse = c;
+        material.phong_exp = 10.0;
+        let variation = MaterialVariation::new(0.1, 0.2, 1.0);
+        let a = variation.apply(&material, 1);
+        assert_eq!(a.diffuse, variation.apply(&material, 1).diffuse);
+        assert!(a.diffuse != variation.apply(&material, 2).diffuse);
+        assert!(a.phong_exp >= 5.0 && a.phong_exp <= 20.0);
+    }
+}
@@ -0,0 +1,209 @@
+use checkpoint::Checkpoint;
+use framebuffer::{RgbFrameBuffer, TiledFrameBuffer, FRAME_TILE_SIZE};
+use interrupt::interrupted;
+use math::Vec2u;
+use render::CpuMtRender;
+use scene::Scene;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &'static str = "manifest.txt";
+const MANIFEST_HEADER: &'static str = "xray buckets 1";
+
+// A render split into buckets which are written to `dir` as soon as they're finished, every one
+// in its own file in the checkpoint format. The manifest lists the settings and, appended line by
+// line, the buckets on disk, so a crashed or interrupted render loses only the buckets it was on:
+// running it again skips the listed ones. `merge` puts the buckets together into the final frame
+#[derive(Debug, Clone)]
+pub struct BucketRender {
+    pub dir: PathBuf,
+    pub resolution: Vec2u,
+    pub bucket_size: usize, // best a multiple of the frame tile size, the buckets then don't share tiles
+    pub spp: usize,
+    pub seed: u32,
+}
+
+impl BucketRender {
+    pub fn new<P: AsRef<Path>>(dir: P, resolution: Vec2u, spp: usize, seed: u32) -> BucketRender {
+        BucketRender {
+            dir: dir.as_ref().to_path_buf(),
+            resolution: resolution,
+            bucket_size: FRAME_TILE_SIZE,
+            spp: spp,
+            seed: seed,
+        }
+    }
+
+    // small buckets for the tests, the frame tiles everywhere else
+    #[cfg(test)]
+    pub fn with_bucket_size(mut self, bucket_size: usize) -> BucketRender {
+        self.bucket_size = bucket_size.max(1);
+        self
+    }
+
+    // bounds of every bucket, row by row; `max` is excluded
+    pub fn buckets(&self) -> Vec<(Vec2u, Vec2u)> {
+        let mut buckets = Vec::new();
+        for y in (0..self.resolution.y).step_by(self.bucket_size) {
+            for x in (0..self.resolution.x).step_by(self.bucket_size) {
+                let max = Vec2u::new((x + self.bucket_size).min(self.resolution.x),
+                                     (y + self.bucket_size).min(self.resolution.y));
+                buckets.push((Vec2u::new(x, y), max));
+            }
+        }
+        buckets
+    }
+
+    fn bucket_path(&self, bucket_nb: usize) -> PathBuf {
+        self.dir.join(format!("bucket_{:05}.bin", bucket_nb))
+    }
+
+    fn header(&self) -> Vec<String> {
+        vec![MANIFEST_HEADER.to_string(),
+             format!("resolution {} {}", self.resolution.x, self.resolution.y),
+             format!("bucket_size {}", self.bucket_size),
+             format!("spp {}", self.spp),
+             format!("seed {}", self.seed)]
+    }
+
+    // Buckets listed in the manifest as done, none if there's no manifest yet. A manifest of other
+    // settings is an error, its buckets wouldn't fit. A torn last line is what a crash during
+    // the append leaves, it's ignored and the bucket is rendered again
+    pub fn finished(&self) -> io::Result<Vec<bool>> {
+        let mut done = vec![false; self.buckets().len()];
+        let file = match File::open(self.dir.join(MANIFEST)) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(done),
+            Err(err) => return Err(err)
+        };
+        let lines = BufReader::new(file).lines().collect::<io::Result<Vec<_>>>()?;
+        let header = self.header();
+        if lines.len() < header.len() || lines[..header.len()] != header[..] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{} is a manifest of another render", self.dir.join(MANIFEST).display())));
+        }
+        for line in &lines[header.len()..] {
+            let bucket_nb = line.split_whitespace().nth(1).and_then(|nb| nb.parse::<usize>().ok());
+            match (line.starts_with("done "), bucket_nb) {
+                (true, Some(bucket_nb)) if bucket_nb < done.len() => done[bucket_nb] = true,
+                _ => {}
+            }
+        }
+        Ok(done)
+    }
+
+    // Renders the buckets which aren't on disk yet, stops after the current one on Ctrl+C.
+    // Returns the number of the buckets still missing
+    pub fn render<S: Scene, R: CpuMtRender<S>>(&self, ren: &R, progress: &mut FnMut(usize, usize)) -> io::Result<usize> {
+        fs::create_dir_all(&self.dir)?;
+        let mut done = self.finished()?;
+        let manifest_path = self.dir.join(MANIFEST);
+        if !manifest_path.exists() {
+            let mut manifest = File::create(&manifest_path)?;
+            for line in self.header() {
+                writeln!(manifest, "{}", line)?;
+            }
+            manifest.sync_all()?;
+        }
+        let mut manifest = OpenOptions::new().append(true).open(&manifest_path)?;
+        // the torn line of a crash is ended, so the next one starts on its own
+        if !fs::read_to_string(&manifest_path)?.ends_with('\n') {
+            writeln!(manifest)?;
+        }
+        let buckets = self.buckets();
+        for (bucket_nb, &(min, max)) in buckets.iter().enumerate() {
+            if done[bucket_nb] {
+                continue;
+            }
+            if interrupted() {
+                break;
+            }
+            let mut tiles = TiledFrameBuffer::new(self.resolution);
+            ren.iterate_over_region(0, self.spp, min, max, &mut tiles)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            let bucket = Checkpoint::new(0, self.spp as u32, self.seed, tiles.crop(min, max));
+            // the bucket is complete on disk before the manifest lists it
+            let tmp_path = self.bucket_path(bucket_nb).with_extension("tmp");
+            bucket.save(&tmp_path)?;
+            fs::rename(&tmp_path, self.bucket_path(bucket_nb))?;
+            writeln!(manifest, "done {}", bucket_nb)?;
+            manifest.sync_all()?;
+            done[bucket_nb] = true;
+            progress(done.iter().filter(|&&done| done).count(), buckets.len());
+        }
+        Ok(done.iter().filter(|&&done| !done).count())
+    }
+
+    // the whole frame of sample sums, an error if any bucket is missing
+    pub fn merge(&self) -> io::Result<RgbFrameBuffer> {
+        let done = self.finished()?;
+        let mut frame = RgbFrameBuffer::new(self.resolution);
+        for (bucket_nb, (min, max)) in self.buckets().into_iter().enumerate() {
+            if !done[bucket_nb] {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("bucket {} isn't rendered", bucket_nb)));
+            }
+            let size = Vec2u::new(max.x - min.x, max.y - min.y);
+            let bucket = Checkpoint::load_for(self.bucket_path(bucket_nb), size, self.seed)?;
+            if bucket.spp as usize != self.spp {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bucket {} doesn't fit the render", bucket_nb)));
+            }
+            for (i, pix) in bucket.frame.as_slice().iter().enumerate() {
+                frame.set_color((min.x + i % size.x, min.y + i / size.x), *pix);
+            }
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BucketRender;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuMtRender, CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    #[test]
+    fn interrupted_render_resumes() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.2, 0.2, 0.2) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, -2.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        let res = Vec2u::new(40, 30);
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(0.0, 0.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = CpuPtMis::new(cam.clone(), scene);
+        let mut whole = cam.build_rgb_framebuffer();
+        ren.iterate_over_screen(0, 2, &mut whole);
+
+        let dir = ::std::env::temp_dir().join("xray_buckets_test");
+        let _ = fs::remove_dir_all(&dir);
+        let buckets = BucketRender::new(&dir, res, 2, ren.get_seed()).with_bucket_size(16);
+        assert_eq!(buckets.buckets().len(), 6);
+        assert_eq!(buckets.render(&ren, &mut |_, _| {}).unwrap(), 0);
+        // a crash after two buckets, in the middle of the third line
+        let manifest = fs::read_to_string(dir.join("manifest.txt")).unwrap();
+        let lines = manifest.lines().collect::<Vec<_>>();
+        fs::write(dir.join("manifest.txt"), format!("{}\n", lines[..lines.len() - 4].join("\n"))).unwrap();
+        OpenOptions::new().append(true).open(dir.join("manifest.txt")).unwrap().write_all(b"do").unwrap();
+        assert_eq!(buckets.finished().unwrap().iter().filter(|&&done| done).count(), 2);
+        assert!(buckets.merge().is_err());
+
+        let mut rendered = 0;
+        assert_eq!(buckets.render(&ren, &mut |_, _| rendered += 1).unwrap(), 0);
+        assert_eq!(rendered, 4);
+        assert!(fs::read_to_string(dir.join("manifest.txt")).unwrap().contains("\ndo\ndone "));
+        assert_eq!(buckets.merge().unwrap().as_slice(), whole.as_slice());
+        // settings of another render don't mix with these buckets
+        assert!(BucketRender::new(&dir, res, 4, ren.get_seed()).with_bucket_size(16).finished().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
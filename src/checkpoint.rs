@@ -0,0 +1,252 @@
+use framebuffer::RgbFrameBuffer;
+use math::{Vec2u, Vec3f};
+use render::{BounceCounts, PathStatsFrame};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &'static [u8; 8] = b"XRAYCKPT";
+const VERSION: u32 = 2;
+
+// Everything needed to continue a progressive render: sample sums and where the sampling stopped.
+// The samples of a pixel only depend on the seed, the iteration and the pixel (see `Scene::get_seed`),
+// so a resumed render goes on with the ones the uninterrupted render would have taken; it ends with
+// the same image in the deterministic mode, otherwise the watchdog may drop other samples.
+// The other frames of the render go along under their names: the AOVs ("albedo", "normal", "depth"),
+// the light groups ("group 0", ...) and the layers ("layer <name> color", "layer <name> alpha")
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub iter_nb: u32,
+    pub spp: u32,
+    pub seed: u32,
+    pub frame: RgbFrameBuffer,
+    pub passes: Vec<(String, RgbFrameBuffer)>,
+    pub path_stats: Option<PathStatsFrame>,
+}
+
+impl Checkpoint {
+    pub fn new(iter_nb: u32, spp: u32, seed: u32, frame: RgbFrameBuffer) -> Checkpoint {
+        Checkpoint { iter_nb: iter_nb, spp: spp, seed: seed, frame: frame, passes: Vec::new(), path_stats: None }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let resolution = self.frame.resolution();
+        if let Some(&(ref name, _)) = self.passes.iter().find(|&&(_, ref pass)| pass.resolution() != resolution) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the pass {} isn't of the frame size", name)));
+        }
+        if self.path_stats.as_ref().map_or(false, |stats| stats.resolution() != resolution) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the path stats aren't of the frame size"));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        let header = [VERSION, resolution.x as u32, resolution.y as u32, self.iter_nb, self.spp, self.seed,
+                      self.passes.len() as u32, self.path_stats.is_some() as u32];
+        for &v in header.iter() {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        write_frame(&mut out, &self.frame)?;
+        for &(ref name, ref pass) in self.passes.iter() {
+            out.write_all(&(name.len() as u32).to_le_bytes())?;
+            out.write_all(name.as_bytes())?;
+            write_frame(&mut out, pass)?;
+        }
+        if let Some(ref stats) = self.path_stats {
+            out.write_all(&(stats.spp() as u32).to_le_bytes())?;
+            for counts in stats.counts() {
+                for &v in [counts.diffuse, counts.glossy, counts.specular].iter() {
+                    out.write_all(&v.to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        Checkpoint::read(path, None)
+    }
+
+    // the checkpoint of a render of `resolution` and `seed`, any other is an error before its frame is read
+    pub fn load_for<P: AsRef<Path>>(path: P, resolution: Vec2u, seed: u32) -> io::Result<Checkpoint> {
+        Checkpoint::read(path, Some((resolution, seed)))
+    }
+
+    fn read<P: AsRef<Path>>(path: P, expected: Option<(Vec2u, u32)>) -> io::Result<Checkpoint> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an xray checkpoint"));
+        }
+        let mut header = [0u32; 8];
+        for v in header.iter_mut() {
+            *v = read_u32(&mut input)?;
+        }
+        if header[0] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("unsupported checkpoint version {}", header[0])));
+        }
+        let resolution = Vec2u::new(header[1] as usize, header[2] as usize);
+        if let Some((expected_resolution, expected_seed)) = expected {
+            if resolution != expected_resolution {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("the checkpoint is of {}x{}, the render of {}x{}", resolution.x, resolution.y,
+                                                  expected_resolution.x, expected_resolution.y)));
+            }
+            if header[5] != expected_seed {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("the checkpoint has the seed {}, the render {}", header[5], expected_seed)));
+            }
+        }
+
+        // a torn or a foreign file can't make it allocate more than the file holds
+        let pixels_nb = header[1] as u64 * header[2] as u64;
+        let mut left = file_len - (MAGIC.len() + header.len() * 4) as u64;
+        let mut take = |bytes: u64| if bytes <= left {
+            left -= bytes;
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                               format!("a checkpoint of {}x{} can't be {} bytes", header[1], header[2], file_len)))
+        };
+
+        take(pixels_nb * 12)?;
+        let frame = read_frame(&mut input, resolution)?;
+        let mut passes = Vec::new();
+        for _ in 0..header[6] {
+            take(4)?;
+            let name_len = read_u32(&mut input)?;
+            take(name_len as u64 + pixels_nb * 12)?;
+            let mut name = vec![0u8; name_len as usize];
+            input.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            passes.push((name, read_frame(&mut input, resolution)?));
+        }
+        let path_stats = if header[7] != 0 {
+            take(4 + pixels_nb * 12)?;
+            let spp = read_u32(&mut input)? as usize;
+            let mut counts = Vec::with_capacity(pixels_nb as usize);
+            for _ in 0..pixels_nb {
+                let (diffuse, glossy, specular) = (read_u32(&mut input)?, read_u32(&mut input)?, read_u32(&mut input)?);
+                counts.push(BounceCounts { diffuse: diffuse, glossy: glossy, specular: specular });
+            }
+            Some(PathStatsFrame::from_counts(resolution, counts, spp))
+        } else {
+            None
+        };
+        if left != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("a checkpoint of {}x{} can't be {} bytes", header[1], header[2], file_len)));
+        }
+        Ok(Checkpoint { iter_nb: header[3], spp: header[4], seed: header[5], frame: frame, passes: passes, path_stats: path_stats })
+    }
+}
+
+fn write_frame<W: Write>(out: &mut W, frame: &RgbFrameBuffer) -> io::Result<()> {
+    for pix in frame.as_slice() {
+        for &c in [pix.x, pix.y, pix.z].iter() {
+            out.write_all(&c.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_frame<R: Read>(input: &mut R, resolution: Vec2u) -> io::Result<RgbFrameBuffer> {
+    let mut frame = RgbFrameBuffer::new(resolution);
+    for pix in frame.as_mut_slice().iter_mut() {
+        let (x, y, z) = (read_f32(input)?, read_f32(input)?, read_f32(input)?);
+        *pix = Vec3f::new(x, y, z);
+    }
+    Ok(frame)
+}
+
+// averaged samples as a little endian PFM, any HDR viewer opens it
+pub fn save_pfm<P: AsRef<Path>>(frame: &RgbFrameBuffer, k: f32, path: P) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let resolution = frame.resolution();
+    write!(out, "PF\n{} {}\n-1.0\n", resolution.x, resolution.y)?;
+    // pfm rows go from the bottom to the top
+    for row in frame.as_slice().chunks(resolution.x).rev() {
+        for pix in row {
+            for &c in [pix.x, pix.y, pix.z].iter() {
+                out.write_all(&(c * k).to_le_bytes())?;
+            }
+        }
+    }
+    out.flush()
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(input: &mut R) -> io::Result<f32> {
+    read_u32(input).map(f32::from_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoint;
+    use framebuffer::RgbFrameBuffer;
+    use math::{Vec2u, Vec3f};
+    use render::{BounceCounts, PathStatsFrame};
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut frame = RgbFrameBuffer::new(Vec2u::new(3, 2));
+        frame.set_color((2, 1), Vec3f::new(1.5, 2.5, 3.5));
+        let checkpoint = Checkpoint::new(7, 14, 3, frame);
+        let path = ::std::env::temp_dir().join("xray_checkpoint_test.bin");
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        assert_eq!((loaded.iter_nb, loaded.spp, loaded.seed), (7, 14, 3));
+        assert_eq!(loaded.frame.as_slice(), checkpoint.frame.as_slice());
+
+        // of another render
+        assert!(Checkpoint::load_for(&path, Vec2u::new(3, 2), 3).is_ok());
+        assert!(Checkpoint::load_for(&path, Vec2u::new(2, 3), 3).is_err());
+        assert!(Checkpoint::load_for(&path, Vec2u::new(3, 2), 4).is_err());
+        // a header of a huge frame without its pixels
+        let mut bytes = ::std::fs::read(&path).unwrap();
+        bytes[12..16].copy_from_slice(&100000u32.to_le_bytes());
+        bytes[16..20].copy_from_slice(&100000u32.to_le_bytes());
+        ::std::fs::write(&path, &bytes).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+    }
+
+    #[test]
+    fn passes_and_path_stats_are_kept() {
+        let res = Vec2u::new(2, 2);
+        let mut checkpoint = Checkpoint::new(3, 3, 1, RgbFrameBuffer::new(res));
+        let mut albedo = RgbFrameBuffer::new(res);
+        albedo.set_color((1, 0), Vec3f::new(0.25, 0.5, 0.75));
+        checkpoint.passes.push(("albedo".to_string(), albedo));
+        checkpoint.passes.push(("group 0".to_string(), RgbFrameBuffer::new(res)));
+        let mut counts = vec![BounceCounts::default(); 4];
+        counts[3].glossy = 5;
+        checkpoint.path_stats = Some(PathStatsFrame::from_counts(res, counts, 3));
+        let path = ::std::env::temp_dir().join("xray_checkpoint_passes_test.bin");
+        checkpoint.save(&path).unwrap();
+
+        let loaded = Checkpoint::load(&path).unwrap();
+        let names = loaded.passes.iter().map(|&(ref name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["albedo", "group 0"]);
+        assert_eq!(loaded.passes[0].1.as_slice(), checkpoint.passes[0].1.as_slice());
+        let stats = loaded.path_stats.unwrap();
+        assert_eq!(stats.spp(), 3);
+        assert_eq!(stats.counts()[3].glossy, 5);
+
+        // a pass of another size
+        checkpoint.passes.push(("depth".to_string(), RgbFrameBuffer::new(Vec2u::new(1, 1))));
+        assert!(checkpoint.save(&path).is_err());
+        // a torn pass
+        checkpoint.passes.pop();
+        checkpoint.save(&path).unwrap();
+        let bytes = ::std::fs::read(&path).unwrap();
+        ::std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+    }
+}
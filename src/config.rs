@@ -0,0 +1,194 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &'static str = "xray.toml";
+
+// how the frame is brought to the display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    Log, // by the log average luminance of the frame
+    Linear, // clamped as it is
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Device {
+    Cpu,
+}
+
+// Renderer defaults and per user preferences, from an xray.toml in the working directory or else in the
+// home directory. The flags of the command line win over the file. Everything is optional:
+//   threads = 8
+//   output_dir = "renders"
+//   tone_map = "log"      # or "linear"
+//   device = "cpu"
+//   memory_budget = 4096  # MiB the geometry, the frames, the textures and the photons may take together
+// Only flat `key = value` lines are read, with integers and strings, and # comments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub threads: Option<usize>,
+    pub output_dir: Option<PathBuf>,
+    pub tone_map: Option<ToneMap>,
+    pub device: Option<Device>,
+    pub memory_budget: Option<usize>, // MiB, see `memory::MemoryBudget`
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config { threads: None, output_dir: None, tone_map: None, device: None, memory_budget: None }
+    }
+
+    pub fn parse(text: &str) -> io::Result<Config> {
+        let mut config = Config::new();
+        for (line_nb, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |msg: &str| invalid_data(format!("line {}: {}", line_nb + 1, msg));
+            let pos = match line.find('=') {
+                Some(pos) => pos,
+                None      => return Err(error("tables and bare keys aren't supported, only key = value"))
+            };
+            let (key, value) = (line[..pos].trim(), line[pos + 1..].trim());
+            match key {
+                "threads"    => config.threads = Some(value.parse().ok().filter(|&n| n > 0)
+                                                      .ok_or_else(|| error("threads needs a positive integer"))?),
+                "output_dir" => config.output_dir = Some(string_value(value).map(PathBuf::from)
+                                                        .ok_or_else(|| error("output_dir needs a string"))?),
+                "tone_map"   => config.tone_map = Some(string_value(value).and_then(|name| parse_tone_map(&name))
+                                                      .ok_or_else(|| error("tone_map needs \"log\" or \"linear\""))?),
+                "device"     => config.device = Some(string_value(value).and_then(|name| parse_device(&name))
+                                                    .ok_or_else(|| error("device needs \"cpu\""))?),
+                "memory_budget" => config.memory_budget = Some(value.parse().ok().filter(|&mb| mb > 0)
+                                                               .ok_or_else(|| error("memory_budget needs a positive integer"))?),
+                _            => return Err(error(&format!("unknown key {:?}", key)))
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Config::parse(&text)
+    }
+
+    // the config of the working directory, or else of the home directory, or the empty one
+    pub fn find() -> io::Result<Config> {
+        let dirs = env::current_dir().ok().into_iter().chain(env::var_os("HOME").map(PathBuf::from));
+        for dir in dirs {
+            let path = dir.join(CONFIG_FILE_NAME);
+            if path.is_file() {
+                return Config::load(&path).map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)));
+            }
+        }
+        Ok(Config::new())
+    }
+
+    // `--threads 8 --output-dir renders --tone-map linear --device cpu --memory-budget 4096` replace the values of the file
+    pub fn with_args(mut self, args: &[String]) -> io::Result<Config> {
+        let value = |flag: &str| args.iter().position(|arg| arg == flag).map(|pos| {
+            args.get(pos + 1).cloned().ok_or_else(|| invalid_data(format!("{} needs a value", flag)))
+        });
+        if let Some(threads) = value("--threads") {
+            self.threads = Some(threads?.parse().ok().filter(|&n| n > 0)
+                .ok_or_else(|| invalid_data("--threads needs a positive integer".to_string()))?);
+        }
+        if let Some(dir) = value("--output-dir") {
+            self.output_dir = Some(PathBuf::from(dir?));
+        }
+        if let Some(name) = value("--tone-map") {
+            self.tone_map = Some(parse_tone_map(&name?).ok_or_else(|| invalid_data("--tone-map needs log or linear".to_string()))?);
+        }
+        if let Some(name) = value("--device") {
+            self.device = Some(parse_device(&name?).ok_or_else(|| invalid_data("--device needs cpu".to_string()))?);
+        }
+        if let Some(budget) = value("--memory-budget") {
+            self.memory_budget = Some(budget?.parse().ok().filter(|&mb| mb > 0)
+                .ok_or_else(|| invalid_data("--memory-budget needs a positive integer".to_string()))?);
+        }
+        Ok(self)
+    }
+
+    // where an output file named `name` goes, an absolute path stays as it is
+    pub fn output_path(&self, name: &str) -> PathBuf {
+        match self.output_dir {
+            Some(ref dir) => dir.join(name),
+            None          => PathBuf::from(name)
+        }
+    }
+}
+
+fn parse_tone_map(name: &str) -> Option<ToneMap> {
+    match name {
+        "log"    => Some(ToneMap::Log),
+        "linear" => Some(ToneMap::Linear),
+        _        => None
+    }
+}
+
+fn parse_device(name: &str) -> Option<Device> {
+    match name {
+        "cpu" => Some(Device::Cpu),
+        _     => None
+    }
+}
+
+// the line up to a # which isn't in a string
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (pos, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..pos],
+            _ => {}
+        }
+    }
+    line
+}
+
+// a basic string without escapes, the quotes removed
+fn string_value(value: &str) -> Option<String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') && !value[1..value.len() - 1].contains('"') {
+        Some(value[1..value.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Device, ToneMap};
+    use std::path::PathBuf;
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let text = "# defaults\n\nthreads = 6\noutput_dir = \"out # renders\"  # where the partial renders go\ntone_map = \"linear\"\nmemory_budget = 512\n";
+        let config = Config::parse(text).unwrap();
+        assert_eq!(config, Config {
+            threads: Some(6),
+            output_dir: Some(PathBuf::from("out # renders")),
+            tone_map: Some(ToneMap::Linear),
+            device: None,
+            memory_budget: Some(512),
+        });
+        assert_eq!(config.output_path("a.pfm"), PathBuf::from("out # renders").join("a.pfm"));
+
+        let args = ["xray", "--threads", "2", "--device", "cpu", "--memory-budget", "64"].iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let config = config.with_args(&args).unwrap();
+        assert_eq!((config.threads, config.device, config.tone_map), (Some(2), Some(Device::Cpu), Some(ToneMap::Linear)));
+        assert_eq!(config.memory_budget, Some(64));
+        assert!(config.with_args(&["--tone-map".to_string(), "filmic".to_string()]).is_err());
+
+        for text in ["[render]", "threads = 0", "threads = \"4\"", "tone_map = log", "gpu = 1"].iter() {
+            let err = Config::parse(text).unwrap_err();
+            assert!(err.to_string().starts_with("line 1: "), "{}", err);
+        }
+    }
+}
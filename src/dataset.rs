@@ -0,0 +1,264 @@
+use camera::{Camera, CameraBuilder, PerspectiveCamera};
+use checkpoint::save_pfm;
+use framebuffer::RgbFrameBuffer;
+use math::vector_traits::*;
+use math::{Vec2f, Vec2u, Vec3f};
+use rand::Rng;
+use render::{PixelSampler, Render, RenderSettings, pixel_rng};
+use scene::{LightID, MaterialID, Scene, SurfaceProperties};
+use std::f32::consts::PI;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use utility::{hash_u64, seeded_rng};
+
+// room around the scene's bounding sphere in random views
+const VIEW_PADDING: f32 = 0.1;
+// range of the camera elevation, in radians
+const MIN_ELEVATION: f32 = -PI / 8.0;
+const MAX_ELEVATION: f32 = PI / 3.0;
+// every channel of the diffuse colors is scaled by a random factor from this up to 1
+const MIN_TINT: f32 = 0.5;
+const LIGHT_SCALES: (f32, f32) = (0.5, 2.0);
+
+// Training data for denoisers and other models: every sample is a random view of a randomized
+// scene, rendered noisy and clean, with the features denoisers take (albedo, normal and depth
+// of the first hit). Images are PFM, `NNNNN_meta.json` tells what was randomized
+#[derive(Debug, Clone)]
+pub struct DatasetSettings {
+    pub samples: usize,
+    pub resolution: Vec2u,
+    pub noisy_spp: usize,
+    pub clean_spp: usize,
+    pub seed: u32,
+    pub chunk: FrameChunk, // of the samples this run writes
+}
+
+// Chunk `index` of `count` of a batch of frames, `i/N` on the command line with i from 1 to N. The
+// chunks are contiguous and differ in length by one frame at most, so N render nodes given 1/N to N/N
+// write every frame exactly once without a wrapper script dividing the range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameChunk {
+    pub index: usize, // from 1
+    pub count: usize,
+}
+
+// what was randomized for a sample
+#[derive(Debug, Clone)]
+pub struct SampleMetadata {
+    pub index: usize,
+    pub scene_seed: u32,
+    pub camera_pos: Vec3f,
+    pub camera_dir: Vec3f,
+    pub tints: Vec<Vec3f>, // of the diffuse color of every material
+    pub light_scale: f32,
+}
+
+// per-pixel sums of the first hit features, like a frame they're divided by the spp
+pub struct Aovs {
+    pub albedo: RgbFrameBuffer,
+    pub normal: RgbFrameBuffer, // facing the camera, in world space
+    pub depth: RgbFrameBuffer, // distance along the ray, in every channel
+}
+
+impl Aovs {
+    pub fn new(resolution: Vec2u) -> Aovs {
+        Aovs {
+            albedo: RgbFrameBuffer::new(resolution),
+            normal: RgbFrameBuffer::new(resolution),
+            depth: RgbFrameBuffer::new(resolution),
+        }
+    }
+}
+
+// Adds the features of `spp` camera rays per pixel, with the jitter the renderers with `settings` use for
+// `iter_nb`. Lights have white albedo, misses leave zeros
+pub fn accumulate_aovs<S: Scene>(camera: &PerspectiveCamera, scene: &S, settings: &RenderSettings, iter_nb: usize,
+                                 spp: usize, aovs: &mut Aovs) {
+    let res_x = aovs.albedo.resolution().x;
+    let seed = scene.get_seed();
+    let pixels_nb = aovs.albedo.as_slice().len();
+    let mut sampler = settings.sampler.new_sampler(seed, settings.deterministic);
+    for pix_nb in 0..pixels_nb {
+        let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+        sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+        for i in 0..spp {
+            let ray = camera.ray_from_screen(&(Vec2f::new(x as f32, y as f32) + sampler.start_sample(i)));
+            let isect = match scene.nearest_intersection(&ray) {
+                Some(isect) => isect,
+                None        => continue
+            };
+            let (albedo, normal) = match isect.surface {
+                SurfaceProperties::Material(m_id) => {
+                    let material = scene.get_surface_material(m_id, &isect);
+                    (material.diffuse + material.specular, scene.get_shading_normal(m_id, &isect))
+                },
+                SurfaceProperties::Light(_) => (Vec3f::new(1.0, 1.0, 1.0), isect.normal)
+            };
+            let normal = if normal.dot(&ray.dir) > 0.0 { -normal } else { normal };
+            aovs.albedo.as_mut_slice()[pix_nb] = aovs.albedo.as_slice()[pix_nb] + albedo;
+            aovs.normal.as_mut_slice()[pix_nb] = aovs.normal.as_slice()[pix_nb] + normal;
+            aovs.depth.as_mut_slice()[pix_nb] = aovs.depth.as_slice()[pix_nb] + Vec3f::new(isect.dist, isect.dist, isect.dist);
+        }
+    }
+}
+
+impl FrameChunk {
+    // all the frames
+    pub fn whole() -> FrameChunk {
+        FrameChunk { index: 1, count: 1 }
+    }
+
+    // the frames of the chunk out of `frames_nb`
+    pub fn frames(&self, frames_nb: usize) -> Range<usize> {
+        (frames_nb * (self.index - 1) / self.count)..(frames_nb * self.index / self.count)
+    }
+}
+
+impl FromStr for FrameChunk {
+    type Err = String;
+
+    fn from_str(arg: &str) -> Result<FrameChunk, String> {
+        let mut parts = arg.splitn(2, '/').map(|part| part.trim().parse::<usize>().ok());
+        match (parts.next(), parts.next()) {
+            (Some(Some(index)), Some(Some(count))) if index >= 1 && index <= count =>
+                Ok(FrameChunk { index: index, count: count }),
+            _ => Err(format!("{} isn't a chunk i/N with 1 <= i <= N", arg))
+        }
+    }
+}
+
+// Writes the samples of `settings.chunk` out of `settings.samples` into `out_dir`, `make_scene` builds
+// the scene to randomize every time. Sample `i` only depends on the settings' seed and `i`, so a dataset
+// can be extended, and split over machines which write into the same directory
+pub fn export_dataset<S, R, F, P>(settings: &DatasetSettings, mut make_scene: F, out_dir: P) -> io::Result<()>
+    where S: Scene, R: Render<S>, F: FnMut() -> S, P: AsRef<Path> {
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    for index in settings.chunk.frames(settings.samples) {
+        let (scene, camera, metadata) = randomize(settings, make_scene(), index);
+        let mut noisy = camera.build_rgb_framebuffer();
+        let mut clean = camera.build_rgb_framebuffer();
+        let mut aovs = Aovs::new(settings.resolution);
+        let ren = R::new(camera.clone(), scene);
+        // different iterations, so the noise of the clean image has nothing to do with the noisy one
+        ren.iterate(1, settings.noisy_spp, &mut noisy);
+        ren.iterate(2, settings.clean_spp, &mut clean);
+        accumulate_aovs(&camera, ren.get_scene(), ren.settings(), 1, settings.noisy_spp, &mut aovs);
+
+        let prefix = format!("{:05}", index);
+        let k = 1.0 / settings.noisy_spp as f32;
+        save_pfm(&noisy, k, out_dir.join(format!("{}_noisy.pfm", prefix)))?;
+        save_pfm(&clean, 1.0 / settings.clean_spp as f32, out_dir.join(format!("{}_clean.pfm", prefix)))?;
+        save_pfm(&aovs.albedo, k, out_dir.join(format!("{}_albedo.pfm", prefix)))?;
+        save_pfm(&aovs.normal, k, out_dir.join(format!("{}_normal.pfm", prefix)))?;
+        save_pfm(&aovs.depth, k, out_dir.join(format!("{}_depth.pfm", prefix)))?;
+        write_metadata(settings, &metadata, out_dir.join(format!("{}_meta.json", prefix)))?;
+    }
+    Ok(())
+}
+
+fn randomize<S: Scene>(settings: &DatasetSettings, mut scene: S, index: usize) -> (S, PerspectiveCamera, SampleMetadata) {
+    let mut rng = seeded_rng(settings.seed, index as u64);
+    let scene_seed = hash_u64(((settings.seed as u64) << 32) | index as u64) as u32;
+    scene.set_seed(scene_seed);
+
+    let tints = (0..scene.get_materials_nb() as MaterialID).map(|m_id| {
+        let tint = Vec3f::new(rng.gen_range(MIN_TINT, 1.0), rng.gen_range(MIN_TINT, 1.0), rng.gen_range(MIN_TINT, 1.0));
+        scene.edit_material(m_id, |material| material.diffuse = material.diffuse * tint);
+        tint
+    }).collect();
+    let light_scale = rng.gen_range(LIGHT_SCALES.0, LIGHT_SCALES.1);
+    for light_id in 0..scene.get_lights_nb() as LightID {
+        if let Some(intensity) = scene.get_light(light_id).intensity() {
+            scene.set_light_intensity(light_id, intensity * light_scale);
+        }
+    }
+    scene.commit_changes();
+
+    let (azimuth, elevation) = (rng.gen_range(0.0, 2.0 * PI), rng.gen_range(MIN_ELEVATION, MAX_ELEVATION));
+    let dir = -Vec3f::new(elevation.cos() * azimuth.cos(), elevation.sin(), elevation.cos() * azimuth.sin());
+    let mut camera = CameraBuilder::<PerspectiveCamera>::new()
+        .with_view_size(settings.resolution)
+        .build();
+    camera.frame_scene(&scene, &dir, VIEW_PADDING);
+
+    let metadata = SampleMetadata {
+        index: index,
+        scene_seed: scene_seed,
+        camera_pos: camera.get_position(),
+        camera_dir: dir,
+        tints: tints,
+        light_scale: light_scale,
+    };
+    (scene, camera, metadata)
+}
+
+fn write_metadata<P: AsRef<Path>>(settings: &DatasetSettings, metadata: &SampleMetadata, path: P) -> io::Result<()> {
+    let json_vec = |v: &Vec3f| format!("[{}, {}, {}]", v.x, v.y, v.z);
+    let tints = metadata.tints.iter().map(|tint| json_vec(tint)).collect::<Vec<_>>().join(", ");
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"index\": {},", metadata.index)?;
+    writeln!(out, "  \"resolution\": [{}, {}],", settings.resolution.x, settings.resolution.y)?;
+    writeln!(out, "  \"noisy_spp\": {},", settings.noisy_spp)?;
+    writeln!(out, "  \"clean_spp\": {},", settings.clean_spp)?;
+    writeln!(out, "  \"dataset_seed\": {},", settings.seed)?;
+    writeln!(out, "  \"scene_seed\": {},", metadata.scene_seed)?;
+    writeln!(out, "  \"camera_pos\": {},", json_vec(&metadata.camera_pos))?;
+    writeln!(out, "  \"camera_dir\": {},", json_vec(&metadata.camera_dir))?;
+    writeln!(out, "  \"diffuse_tints\": [{}],", tints)?;
+    writeln!(out, "  \"light_scale\": {}", metadata.light_scale)?;
+    writeln!(out, "}}")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatasetSettings, FrameChunk, export_dataset};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::CpuPtDl;
+    use scene::{DefaultScene, Scene};
+    use std::fs;
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.1, 0.1, 0.1) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        scene
+    }
+
+    #[test]
+    fn samples_are_reproducible() {
+        let settings = DatasetSettings {
+            samples: 2, resolution: Vec2u::new(4, 4), noisy_spp: 1, clean_spp: 4, seed: 5, chunk: FrameChunk::whole()
+        };
+        let dirs = [::std::env::temp_dir().join("xray_dataset_test_a"), ::std::env::temp_dir().join("xray_dataset_test_b")];
+        for dir in dirs.iter() {
+            export_dataset::<_, CpuPtDl<_>, _, _>(&settings, scene, dir).unwrap();
+        }
+        for name in ["00001_noisy.pfm", "00001_clean.pfm", "00001_albedo.pfm", "00001_normal.pfm",
+                     "00001_depth.pfm", "00001_meta.json"].iter() {
+            let (a, b) = (fs::read(dirs[0].join(name)).unwrap(), fs::read(dirs[1].join(name)).unwrap());
+            assert!(a == b, "{} differs", name);
+        }
+        let meta = fs::read_to_string(dirs[0].join("00000_meta.json")).unwrap();
+        assert!(meta.contains("\"clean_spp\": 4,") && meta.contains("\"light_scale\""));
+    }
+
+    #[test]
+    fn chunks_cover_the_frames_once() {
+        assert_eq!("2/3".parse(), Ok(FrameChunk { index: 2, count: 3 }));
+        assert!("0/3".parse::<FrameChunk>().is_err() && "4/3".parse::<FrameChunk>().is_err());
+        assert!("2".parse::<FrameChunk>().is_err() && "a/b".parse::<FrameChunk>().is_err());
+        let frames = (1..5).flat_map(|index| FrameChunk { index: index, count: 4 }.frames(10)).collect::<Vec<_>>();
+        assert_eq!(frames, (0..10).collect::<Vec<_>>());
+        assert_eq!(FrameChunk { index: 1, count: 4 }.frames(10), 0..2);
+        assert_eq!(FrameChunk { index: 3, count: 4 }.frames(2), 1..1);
+    }
+}
@@ -0,0 +1,111 @@
+use brdf::Material;
+use geometry::{Frame, Ray, SurfaceIntersection};
+use math::Vec3f;
+use rand::Rng;
+use scene::Scene;
+use utility::{cos_hemisphere_sample, hash_u64, seeded_rng};
+
+// Grime in the crevices of an object, without uv or baking: the share of short probe rays from the hit
+// point which hit something within `distance` blends the material toward `color`. A material input
+// (see `Material::dirt`), probed at every hit it's resolved at: nothing is cached, the probes of a hit
+// are its own and the noise of few of them averages out with the samples of the pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dirt {
+    pub color: Vec3f,
+    pub distance: f32, // probes hitting something further away don't count
+    pub samples: usize, // probes per hit
+    pub amount: f32, // blend toward the color of a fully occluded point
+    pub contrast: f32, // power of the occlusion, higher keeps the dirt to the deepest corners
+}
+
+impl Dirt {
+    pub fn new(color: Vec3f, distance: f32) -> Dirt {
+        Dirt {
+            color: color,
+            distance: distance,
+            samples: 8,
+            amount: 1.0,
+            contrast: 1.0,
+        }
+    }
+
+    pub fn with_samples(mut self, samples: usize) -> Dirt {
+        self.samples = samples.max(1);
+        self
+    }
+
+    pub fn with_amount(mut self, amount: f32) -> Dirt {
+        self.amount = amount;
+        self
+    }
+
+    pub fn with_contrast(mut self, contrast: f32) -> Dirt {
+        self.contrast = contrast;
+        self
+    }
+
+    pub fn apply<S: Scene>(&self, material: &Material, scene: &S, isect: &SurfaceIntersection) -> Material {
+        let occlusion = self.occlusion(scene, isect);
+        let blend = (self.amount * occlusion.powf(self.contrast)).max(0.0).min(1.0);
+        let mut material = material.clone();
+        material.diffuse = material.diffuse * (1.0 - blend) + self.color * blend;
+        material.specular = material.specular * (1.0 - blend);
+        material.mirror = material.mirror * (1.0 - blend);
+        material
+    }
+
+    // share of the probes hitting something, 0 in the open and 1 deep in a crack
+    pub fn occlusion<S: Scene>(&self, scene: &S, isect: &SurfaceIntersection) -> f32 {
+        // the same hit casts the same probes, in the deterministic mode too
+        let p = isect.position;
+        let hash = hash_u64(hash_u64(hash_u64(p.x.to_bits() as u64) ^ p.y.to_bits() as u64) ^ p.z.to_bits() as u64);
+        let mut rng = seeded_rng(scene.get_seed() ^ hash as u32, hash >> 32);
+        let frame = Frame::from_z(&isect.normal);
+        let mut hits = 0;
+        for _ in 0..self.samples {
+            let dir = frame.to_world(&cos_hemisphere_sample((rng.next_f32(), rng.next_f32())));
+            if scene.was_occluded(&Ray { orig: isect.position, dir: dir }, self.distance) {
+                hits += 1;
+            }
+        }
+        hits as f32 / self.samples as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dirt;
+    use geometry::{GeometryList, Plane, Ray};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::Vec3f;
+    use scene::{DefaultScene, Scene, SurfaceProperties};
+
+    #[test]
+    fn dirt_gathers_in_the_corner() {
+        // a floor and a wall meeting along the z axis
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        let floor = scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) },
+                                     WHITE_DIFFUSE).unwrap();
+        scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(1.0, 0.0, 0.0) }, WHITE_DIFFUSE)
+            .unwrap();
+        let dirt = Dirt::new(Vec3f::new(0.1, 0.08, 0.05), 0.5).with_samples(256);
+        scene.edit_material(floor, |material| material.dirt = Some(dirt));
+
+        let floor_at = |x: f32| {
+            let ray = Ray { orig: Vec3f::new(x, 1.0, 0.3), dir: Vec3f::new(0.0, -1.0, 0.0) };
+            let isect = scene.nearest_intersection(&ray).unwrap();
+            assert_eq!(isect.surface, SurfaceProperties::Material(floor));
+            scene.get_surface_material(floor, &isect).diffuse.x
+        };
+        let (corner, open) = (floor_at(0.01), floor_at(2.0));
+        assert_eq!(open, WHITE_DIFFUSE.diffuse.x);
+        // half of the cosine weighted hemisphere goes into the wall at the corner
+        assert!(corner < 0.6 * open && corner > 0.1, "{}", corner);
+        // a hit probes the same directions every time
+        assert_eq!(floor_at(0.01), corner);
+        // and its own, next to the corner the dirt fades with the distance, without steps
+        let steps = (1..8).map(|i| floor_at(0.01 + 0.002 * i as f32)).collect::<Vec<_>>();
+        assert!(steps.windows(2).any(|pair| pair[0] != pair[1]), "{:?}", steps);
+    }
+}
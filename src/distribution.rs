@@ -0,0 +1,143 @@
+
+// piecewise constant distribution over [0, 1) split into func.len() cells
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+// piecewise constant distribution over [0, 1)^2, func is stored row by row
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>, // one per row
+    marginal: Distribution1D, // over rows
+}
+
+pub struct DiscreteSample {
+    pub idx: usize,
+    pub prob: f32, // probability to pick the cell
+    pub remapped: f32, // position inside of the cell, in [0, 1)
+}
+
+pub struct DiscreteSample2D {
+    pub col: usize,
+    pub row: usize,
+    pub prob: f32,
+    pub remapped: (f32, f32),
+}
+
+impl Distribution1D {
+    // negative weights are treated as zero, all-zero weights give the uniform distribution
+    pub fn new(func: Vec<f32>) -> Distribution1D {
+        assert!(!func.is_empty());
+        let n = func.len();
+        let func = func.into_iter().map(|f| f.max(0.0)).collect::<Vec<_>>();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in 0..n {
+            let prev = cdf[i];
+            cdf.push(prev + func[i]);
+        }
+
+        let integral = cdf[n];
+        if integral > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        } else {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        }
+        cdf[n] = 1.0;
+
+        Distribution1D { func: func, cdf: cdf, integral: integral }
+    }
+
+    pub fn len(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    pub fn sample(&self, rnd: f32) -> DiscreteSample {
+        // last cdf entry <= rnd, empty cells are never picked
+        let mut lo = 0;
+        let mut hi = self.func.len();
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.cdf[mid] <= rnd {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let prob = self.prob(lo);
+        let remapped = if prob > 0.0 { (rnd - self.cdf[lo]) / prob } else { 0.0 };
+        DiscreteSample { idx: lo, prob: prob, remapped: remapped.max(0.0).min(0.99999994) }
+    }
+
+    pub fn prob(&self, idx: usize) -> f32 {
+        self.cdf[idx + 1] - self.cdf[idx]
+    }
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], cols: usize, rows: usize) -> Distribution2D {
+        assert_eq!(func.len(), cols * rows);
+        let conditional = func.chunks(cols)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect::<Vec<_>>();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Distribution2D { conditional: conditional, marginal: marginal }
+    }
+
+    pub fn sample(&self, rnd: (f32, f32)) -> DiscreteSample2D {
+        let row = self.marginal.sample(rnd.1);
+        let col = self.conditional[row.idx].sample(rnd.0);
+        DiscreteSample2D {
+            col: col.idx,
+            row: row.idx,
+            prob: row.prob * col.prob,
+            remapped: (col.remapped, row.remapped),
+        }
+    }
+
+    pub fn prob(&self, col: usize, row: usize) -> f32 {
+        self.marginal.prob(row) * self.conditional[row].prob(col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Distribution1D, Distribution2D};
+
+    #[test]
+    fn empty_cells_are_never_sampled() {
+        let d = Distribution1D::new(vec![1.0, 0.0, 3.0]);
+        for i in 0..100 {
+            let s = d.sample(i as f32 / 100.0);
+            assert!(s.idx != 1);
+            assert_eq!(s.prob, d.prob(s.idx));
+        }
+        assert!((d.prob(2) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn probabilities_2d_sum_to_one() {
+        let d = Distribution2D::new(&[1.0, 2.0, 0.0, 4.0, 5.0, 6.0], 3, 2);
+        let mut sum = 0.0;
+        for row in 0..2 {
+            for col in 0..3 {
+                sum += d.prob(col, row);
+            }
+        }
+        assert!((sum - 1.0).abs() < 1e-5);
+        let s = d.sample((0.99, 0.99));
+        assert_eq!((s.col, s.row), (2, 1));
+    }
+}
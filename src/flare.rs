@@ -0,0 +1,205 @@
+use framebuffer::RgbFrameBuffer;
+use math::vector_traits::*;
+use math::{Vec2f, Vec3f};
+use utility::luminance;
+
+// radiance a pixel needs to start a flare
+const THRESHOLD: f32 = 20.0;
+const MAX_SOURCES: usize = 8;
+// bright pixels closer than this to a source (of the frame diagonal) are merged into it
+const MERGE_DISTANCE: f32 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlareShape {
+    Disc,
+    Ring(f32), // thickness, of the radius
+    Glow, // gaussian, the radius is 3 sigma
+}
+
+// One element of the flare. Elements lie on the line from the source through the center
+// of the frame, as the reflections between lens surfaces do
+#[derive(Debug, Clone)]
+pub struct FlareElement {
+    pub position: f32, // along the line: 0 is the source, 1 the center, 2 the source mirrored
+    pub radius: f32, // of the frame diagonal
+    pub color: Vec3f, // fraction of the source's energy
+    pub shape: FlareShape,
+}
+
+// a bright spot of the frame: its center in raster space and the energy above the threshold
+#[derive(Debug, Clone)]
+pub struct FlareSource {
+    pub pos: Vec2f,
+    pub energy: Vec3f,
+}
+
+// Lens flare postprocess: bright sources are found in the frame and every element of the flare
+// is composited for every source. The elements are data, the default ones are a glow around
+// the source and a few tinted ghosts
+#[derive(Debug, Clone)]
+pub struct LensFlare {
+    pub elements: Vec<FlareElement>,
+    pub threshold: f32,
+    pub max_sources: usize,
+}
+
+impl LensFlare {
+    pub fn new() -> LensFlare {
+        let element = |position, radius, color: (f32, f32, f32), shape| FlareElement {
+            position: position,
+            radius: radius,
+            color: Vec3f::new(color.0, color.1, color.2),
+            shape: shape,
+        };
+        LensFlare {
+            elements: vec![
+                element(0.0, 0.05, (0.3, 0.3, 0.3), FlareShape::Glow),
+                element(0.6, 0.02, (0.02, 0.03, 0.05), FlareShape::Disc),
+                element(1.3, 0.04, (0.04, 0.03, 0.02), FlareShape::Disc),
+                element(1.7, 0.03, (0.01, 0.03, 0.02), FlareShape::Ring(0.2)),
+                element(2.0, 0.08, (0.03, 0.02, 0.04), FlareShape::Ring(0.1)),
+            ],
+            threshold: THRESHOLD,
+            max_sources: MAX_SOURCES,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_elements(mut self, elements: Vec<FlareElement>) -> LensFlare {
+        self.elements = elements;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_threshold(mut self, threshold: f32) -> LensFlare {
+        self.threshold = threshold;
+        self
+    }
+
+    // Adds the flare of the bright sources of the frame. `k` normalizes the frame, as in `to_yxy_inplace`
+    pub fn apply(&self, frame: &mut RgbFrameBuffer, k: f32) {
+        let sources = self.find_sources(frame, k);
+        self.composite(&sources, frame, k);
+    }
+
+    // Pixels brighter than the threshold, brightest first, merged into the source they're near.
+    // Only the part above the threshold counts, so the flare fades in
+    pub fn find_sources(&self, frame: &RgbFrameBuffer, k: f32) -> Vec<FlareSource> {
+        let res = frame.resolution();
+        let merge_distance = MERGE_DISTANCE * diagonal(frame);
+        let mut bright = frame.as_slice().iter().enumerate()
+            .map(|(pix_nb, pix)| (pix_nb, *pix * k))
+            .filter(|&(_, pix)| luminance(&pix) > self.threshold)
+            .collect::<Vec<_>>();
+        bright.sort_by(|a, b| luminance(&b.1).partial_cmp(&luminance(&a.1)).unwrap());
+
+        // the centers are weighted by the energy, `weights` are its luminance
+        let mut sources: Vec<FlareSource> = Vec::new();
+        let mut weights: Vec<f32> = Vec::new();
+        for (pix_nb, pix) in bright {
+            let pos = Vec2f::new((pix_nb % res.x) as f32 + 0.5, (pix_nb / res.x) as f32 + 0.5);
+            let energy = pix * ((luminance(&pix) - self.threshold) / luminance(&pix));
+            let weight = luminance(&energy);
+            match sources.iter().position(|source| (source.pos - pos).norm() < merge_distance) {
+                Some(idx) => {
+                    let total = weights[idx] + weight;
+                    sources[idx].pos = (sources[idx].pos * weights[idx] + pos * weight) / total;
+                    sources[idx].energy = sources[idx].energy + energy;
+                    weights[idx] = total;
+                },
+                None if sources.len() < self.max_sources => {
+                    sources.push(FlareSource { pos: pos, energy: energy });
+                    weights.push(weight);
+                },
+                None => {}
+            }
+        }
+        sources
+    }
+
+    // Adds the elements of every source, each of them gets `color` times the source's energy in total
+    pub fn composite(&self, sources: &[FlareSource], frame: &mut RgbFrameBuffer, k: f32) {
+        let res = frame.resolution();
+        let diagonal = diagonal(frame);
+        let center = Vec2f::new(res.x as f32 * 0.5, res.y as f32 * 0.5);
+        for source in sources {
+            for element in self.elements.iter() {
+                let pos = source.pos + (center - source.pos) * element.position;
+                let radius = (element.radius * diagonal).max(0.5);
+                let (x0, x1) = ((pos.x - radius).floor() as i64, (pos.x + radius).ceil() as i64);
+                let (y0, y1) = ((pos.y - radius).floor() as i64, (pos.y + radius).ceil() as i64);
+                let weight = |x: i64, y: i64| {
+                    let dist = (Vec2f::new(x as f32 + 0.5, y as f32 + 0.5) - pos).norm() / radius;
+                    shape_weight(element.shape, dist)
+                };
+                // normalized over the whole element, the parts off the frame are lost
+                let mut total = 0.0;
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        total += weight(x, y);
+                    }
+                }
+                if total <= 0.0 {
+                    continue;
+                }
+                let color = source.energy * element.color / (total * k);
+                for y in y0.max(0)..y1.min(res.y as i64) {
+                    for x in x0.max(0)..x1.min(res.x as i64) {
+                        let w = weight(x, y);
+                        if w > 0.0 {
+                            frame.add_color((x as usize, y as usize), color * w);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// `dist` is relative to the radius
+fn shape_weight(shape: FlareShape, dist: f32) -> f32 {
+    match shape {
+        FlareShape::Disc                  => if dist <= 1.0 { 1.0 } else { 0.0 },
+        FlareShape::Ring(thickness)       => if dist <= 1.0 && dist >= 1.0 - thickness { 1.0 } else { 0.0 },
+        FlareShape::Glow if dist <= 1.0   => (-4.5 * dist * dist).exp(),
+        FlareShape::Glow                  => 0.0
+    }
+}
+
+fn diagonal(frame: &RgbFrameBuffer) -> f32 {
+    let res = frame.resolution();
+    ((res.x * res.x + res.y * res.y) as f32).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlareElement, FlareShape, LensFlare};
+    use framebuffer::RgbFrameBuffer;
+    use math::{Vec2u, Vec3f};
+
+    #[test]
+    fn flare_keeps_the_source_energy() {
+        let mut frame = RgbFrameBuffer::new(Vec2u::new(64, 64));
+        // two spp of a 2x1 spot with 100 of radiance
+        frame.set_color((10, 20), Vec3f::new(200.0, 200.0, 200.0));
+        frame.set_color((11, 20), Vec3f::new(200.0, 200.0, 200.0));
+        let flare = LensFlare::new().with_threshold(40.0).with_elements(vec![
+            FlareElement { position: 1.0, radius: 0.1, color: Vec3f::new(0.5, 0.5, 0.5), shape: FlareShape::Disc },
+            FlareElement { position: 2.0, radius: 0.05, color: Vec3f::new(0.1, 0.1, 0.1), shape: FlareShape::Ring(0.3) },
+        ]);
+
+        let sources = flare.find_sources(&frame, 0.5);
+        assert_eq!(sources.len(), 1);
+        assert!((sources[0].pos.x - 11.0).abs() < 1e-4 && (sources[0].pos.y - 20.5).abs() < 1e-4, "{:?}", sources[0].pos);
+        assert!((sources[0].energy.x - 120.0).abs() < 1e-3, "{:?}", sources[0].energy);
+
+        let before = frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        flare.apply(&mut frame, 0.5);
+        let after = frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        // in frame units, before the normalization
+        assert!((after - before - 120.0 * 0.6 / 0.5).abs() < 1e-2, "{}", after - before);
+        // the disc is centered on the frame, the ring on the mirrored source
+        assert!(frame.as_slice()[32 * 64 + 32].x > 0.0);
+        assert!(frame.as_slice()[43 * 64 + 57].x > 0.0 && frame.as_slice()[43 * 64 + 53].x == 0.0);
+    }
+}
@@ -1,8 +1,11 @@
 #![allow(dead_code)]
-use math::{Vec3f, Vec2u, Zero, Mat3f};
+use math::{Vec3f, Vec2f, Vec2u, Zero, Mat3f};
 use math::vector_traits::*;
+use memory::{self, MemoryCategory, OutOfBudget, Reservation};
 use std::borrow::Borrow;
+use std::mem;
 use std::f32::{EPSILON, INFINITY};
+use utility::luminance;
 
 #[derive(Debug, Clone)]
 pub struct RgbFrameBuffer {
@@ -42,6 +45,13 @@ impl RgbFrameBuffer {
         self.buffer[idx] = color;
     }
 
+    // adds the color to the pixel the raster position is in, positions out of the frame are dropped
+    pub fn splat(&mut self, pos: &Vec2f, color: Vec3f) {
+        if pos.x >= 0.0 && pos.y >= 0.0 && (pos.x as usize) < self.resolution.x && (pos.y as usize) < self.resolution.y {
+            self.add_color((pos.x as usize, pos.y as usize), color);
+        }
+    }
+
     pub fn idx(&self, coords: (usize, usize)) -> usize {
         assert!(coords.0 < self.resolution.x);
         assert!(coords.1 < self.resolution.y);
@@ -56,37 +66,20 @@ impl RgbFrameBuffer {
         self.buffer.as_mut()
     }
 
-    pub fn to_yxy_inplace(&self, frame: &mut YxyFrameBuffer, k: f32) -> FrameLuminosity {
-        assert!(self.resolution == frame.resolution);
-
-        let rgb_to_yxy = Mat3f::new(
-            0.5141364, 0.3238786,  0.16036376,
-            0.265068,  0.67023428, 0.06409157,
-            0.0241188, 0.1228178,  0.84442666,
-        );
-
-        let mut max = EPSILON;
-        let mut min = INFINITY;
-        let mut sum = 0.0;
-
-        for i in 0..self.buffer.len() {
-            let result = rgb_to_yxy * (self.buffer[i] * k);
-
-            let w = result.fold(|x, y| x + y);
-            frame.buffer[i] = if w > 0.0 {
-                Vec3f::new(result.y, result.x / w, result.y / w)
-            } else {
-                Zero::zero()
-            };
+    pub fn resolution(&self) -> Vec2u {
+        self.resolution
+    }
 
-            max = max.max(frame.buffer[i].x);
-            min = min.min(frame.buffer[i].x);
-            sum += (2.3e-5 + frame.buffer[i].x).ln();
+    // drops the accumulated samples, e.g. after the scene was edited
+    pub fn clear(&mut self) {
+        for pix in self.buffer.iter_mut() {
+            *pix = Zero::zero();
         }
+    }
 
-        let log_avg = sum / (self.resolution.x * self.resolution.y) as f32;
-
-        FrameLuminosity { min: min, max: max, log_avg: log_avg }
+    pub fn to_yxy_inplace(&self, frame: &mut YxyFrameBuffer, k: f32) -> FrameLuminosity {
+        assert!(self.resolution == frame.resolution);
+        sums_to_yxy(|i| self.buffer[i], frame, k)
     }
 
     pub unsafe fn as_yxy(self) -> YxyFrameBuffer {
@@ -94,6 +87,38 @@ impl RgbFrameBuffer {
     }
 }
 
+// converts the sums of every pixel, whatever keeps them, scaled by `k`
+fn sums_to_yxy<F: Fn(usize) -> Vec3f>(sum: F, frame: &mut YxyFrameBuffer, k: f32) -> FrameLuminosity {
+    let rgb_to_yxy = Mat3f::new(
+        0.5141364, 0.3238786,  0.16036376,
+        0.265068,  0.67023428, 0.06409157,
+        0.0241188, 0.1228178,  0.84442666,
+    );
+
+    let mut max = EPSILON;
+    let mut min = INFINITY;
+    let mut log_sum = 0.0;
+
+    for i in 0..frame.buffer.len() {
+        let result = rgb_to_yxy * (sum(i) * k);
+
+        let w = result.fold(|x, y| x + y);
+        frame.buffer[i] = if w > 0.0 {
+            Vec3f::new(result.y, result.x / w, result.y / w)
+        } else {
+            Zero::zero()
+        };
+
+        max = max.max(frame.buffer[i].x);
+        min = min.min(frame.buffer[i].x);
+        log_sum += (2.3e-5 + frame.buffer[i].x).ln();
+    }
+
+    let log_avg = log_sum / (frame.resolution.x * frame.resolution.y) as f32;
+
+    FrameLuminosity { min: min, max: max, log_avg: log_avg }
+}
+
 impl YxyFrameBuffer {
     pub fn new(resolution: Vec2u) -> YxyFrameBuffer {
         let n = resolution.x * resolution.y;
@@ -157,6 +182,363 @@ impl YxyFrameBuffer {
     }
 }
 
+// Accumulation in half floats, for renders so big that the f32 frame dominates the memory: 6 bytes
+// a pixel instead of 12. It keeps the mean of the frames added to it rather than their sum, a sum of
+// thousands of samples would be out of the few bits of f16. The compensated one keeps the rounding
+// error of every update in another f16 (Kahan summation), without it the mean stops moving once
+// the updates are below its precision
+#[derive(Debug)]
+pub struct HalfFrameBuffer {
+    mean: Vec<[u16; 3]>,
+    compensation: Vec<[u16; 3]>, // empty unless compensated
+    spp: usize,
+    resolution: Vec2u,
+    _memory: Reservation<'static>,
+}
+
+impl HalfFrameBuffer {
+    pub fn new(resolution: Vec2u) -> Result<HalfFrameBuffer, OutOfBudget> {
+        let n = resolution.x * resolution.y;
+        let memory = memory::global().reserve(MemoryCategory::Framebuffer, n * mem::size_of::<[u16; 3]>())?;
+        Ok(HalfFrameBuffer {
+            mean: vec![[0; 3]; n],
+            compensation: Vec::new(),
+            spp: 0,
+            resolution: resolution,
+            _memory: memory,
+        })
+    }
+
+    pub fn with_compensation(mut self) -> Result<HalfFrameBuffer, OutOfBudget> {
+        if self.compensation.is_empty() {
+            self._memory.grow(self.mean.len() * mem::size_of::<[u16; 3]>())?;
+            self.compensation = vec![[0; 3]; self.mean.len()];
+        }
+        Ok(self)
+    }
+
+    pub fn resolution(&self) -> Vec2u {
+        self.resolution
+    }
+
+    pub fn spp(&self) -> usize {
+        self.spp
+    }
+
+    // `frame` holds the sums of `spp` samples, as the renderers leave them
+    pub fn add_frame(&mut self, frame: &RgbFrameBuffer, spp: usize) {
+        assert!(self.resolution == frame.resolution);
+        if spp == 0 {
+            return;
+        }
+        let total = (self.spp + spp) as f32;
+        for (i, sum) in frame.buffer.iter().enumerate() {
+            self.add_sum(i, *sum, spp, total);
+        }
+        self.spp += spp;
+    }
+
+    // Adds `spp` samples to every pixel without a whole f32 frame: `render` fills the pixels from `min` up
+    // to `max` (excluded), a band of tile rows at a time, and only the tiles of that band are allocated
+    pub fn add_bands(&mut self, spp: usize, render: &mut FnMut(Vec2u, Vec2u, &mut TiledFrameBuffer) -> Result<(), OutOfBudget>)
+                     -> Result<(), OutOfBudget> {
+        if spp == 0 {
+            return Ok(());
+        }
+        let total = (self.spp + spp) as f32;
+        let mut y0 = 0;
+        while y0 < self.resolution.y {
+            let (min, max) = (Vec2u::new(0, y0), Vec2u::new(self.resolution.x, (y0 + FRAME_TILE_SIZE).min(self.resolution.y)));
+            let mut band = TiledFrameBuffer::new(self.resolution);
+            render(min, max, &mut band)?;
+            for y in min.y..max.y {
+                for x in 0..max.x {
+                    self.add_sum(x + y * self.resolution.x, band.get_color((x, y)), spp, total);
+                }
+            }
+            y0 = max.y;
+        }
+        self.spp += spp;
+        Ok(())
+    }
+
+    // `sum` is of `spp` samples, the pixel then has `total` of them
+    fn add_sum(&mut self, i: usize, sum: Vec3f, spp: usize, total: f32) {
+        let sum = [sum.x, sum.y, sum.z];
+        let compensated = !self.compensation.is_empty();
+        for c in 0..3 {
+            let mean = f16_to_f32(self.mean[i][c]);
+            let delta = (sum[c] - mean * spp as f32) / total;
+            if compensated {
+                let y = delta - f16_to_f32(self.compensation[i][c]);
+                let t = f32_to_f16(mean + y);
+                self.compensation[i][c] = f32_to_f16((f16_to_f32(t) - mean) - y);
+                self.mean[i][c] = t;
+            } else {
+                self.mean[i][c] = f32_to_f16(mean + delta);
+            }
+        }
+    }
+
+    // the sum of the `spp()` samples of the pixel
+    pub fn sum(&self, i: usize) -> Vec3f {
+        let compensated = !self.compensation.is_empty();
+        let channel = |c: usize| {
+            let mean = f16_to_f32(self.mean[i][c]);
+            if compensated { mean - f16_to_f32(self.compensation[i][c]) } else { mean }
+        };
+        Vec3f::new(channel(0), channel(1), channel(2)) * self.spp as f32
+    }
+
+    // the sums of `spp()` samples, like the frame the renderers would have accumulated
+    pub fn to_rgb(&self) -> RgbFrameBuffer {
+        RgbFrameBuffer {
+            buffer: (0..self.mean.len()).map(|i| self.sum(i)).collect(),
+            resolution: self.resolution,
+        }
+    }
+
+    pub fn to_yxy_inplace(&self, frame: &mut YxyFrameBuffer, k: f32) -> FrameLuminosity {
+        assert!(self.resolution == frame.resolution);
+        sums_to_yxy(|i| self.sum(i), frame, k)
+    }
+}
+
+// Sample sums with what adaptive sampling needs to know of every pixel: the number of its samples,
+// which differs from pixel to pixel, and the sum of the squared luminance of the samples for their
+// variance. A pixel is converged once the confidence interval of its mean is narrow enough
+#[derive(Debug, Clone)]
+pub struct AdaptiveFrameBuffer {
+    sums: Vec<Vec3f>,
+    lum_sq_sums: Vec<f32>,
+    samples: Vec<u32>,
+    resolution: Vec2u,
+}
+
+impl AdaptiveFrameBuffer {
+    pub fn new(resolution: Vec2u) -> AdaptiveFrameBuffer {
+        let n = resolution.x * resolution.y;
+        AdaptiveFrameBuffer {
+            sums: vec![Zero::zero(); n],
+            lum_sq_sums: vec![0.0; n],
+            samples: vec![0; n],
+            resolution: resolution,
+        }
+    }
+
+    pub fn resolution(&self) -> Vec2u {
+        self.resolution
+    }
+
+    pub fn add_sample(&mut self, pix_nb: usize, color: Vec3f) {
+        add_pixel_sample((&mut self.sums[pix_nb], &mut self.lum_sq_sums[pix_nb], &mut self.samples[pix_nb]), color);
+    }
+
+    pub fn samples(&self, pix_nb: usize) -> u32 {
+        self.samples[pix_nb]
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().fold(0, |sum, &samples| sum + samples as u64)
+    }
+
+    pub fn mean(&self, pix_nb: usize) -> Vec3f {
+        if self.samples[pix_nb] == 0 { Zero::zero() } else { self.sums[pix_nb] / self.samples[pix_nb] as f32 }
+    }
+
+    // unbiased variance of the luminance of the pixel's samples, infinite before two of them
+    pub fn variance(&self, pix_nb: usize) -> f32 {
+        let n = self.samples[pix_nb] as f32;
+        if n < 2.0 {
+            return INFINITY;
+        }
+        let mean = luminance(&self.sums[pix_nb]) / n;
+        ((self.lum_sq_sums[pix_nb] - mean * mean * n) / (n - 1.0)).max(0.0)
+    }
+
+    // Whether the 95% confidence interval of the pixel's mean luminance is at most `threshold` of the
+    // mean. Less than `min_samples` can't tell, a few samples which all missed the light look converged
+    pub fn converged(&self, pix_nb: usize, threshold: f32, min_samples: u32) -> bool {
+        let n = self.samples[pix_nb];
+        if n < min_samples.max(2) {
+            return false;
+        }
+        let half_width = 1.96 * (self.variance(pix_nb) / n as f32).sqrt();
+        half_width <= threshold * luminance(&self.mean(pix_nb))
+    }
+
+    pub fn converged_nb(&self, threshold: f32, min_samples: u32) -> usize {
+        (0..self.samples.len()).filter(|&pix_nb| self.converged(pix_nb, threshold, min_samples)).count()
+    }
+
+    // runs of `len` pixels, with their sums, squared luminance sums and sample counts
+    pub fn chunks_mut(&mut self, len: usize) -> Vec<(&mut [Vec3f], &mut [f32], &mut [u32])> {
+        self.sums.chunks_mut(len).zip(self.lum_sq_sums.chunks_mut(len)).zip(self.samples.chunks_mut(len))
+            .map(|((sums, lum_sq_sums), samples)| (sums, lum_sq_sums, samples))
+            .collect()
+    }
+
+    // the means as sums of `spp` samples, the frame the renderers would leave with `spp` everywhere
+    pub fn write_means(&self, frame: &mut RgbFrameBuffer, spp: usize) {
+        assert!(self.resolution == frame.resolution);
+        for (pix_nb, pix) in frame.buffer.iter_mut().enumerate() {
+            *pix = self.mean(pix_nb) * spp as f32;
+        }
+    }
+}
+
+// adds a sample to the pixel of an `AdaptiveFrameBuffer` chunk
+pub fn add_pixel_sample(pixel: (&mut Vec3f, &mut f32, &mut u32), color: Vec3f) {
+    let lum = luminance(&color);
+    *pixel.0 = *pixel.0 + color;
+    *pixel.1 += lum * lum;
+    *pixel.2 += 1;
+}
+
+// side of the tiles of `TiledFrameBuffer`, in pixels
+pub const FRAME_TILE_SIZE: usize = 64;
+
+// Frame of square tiles which are only allocated once something is written into them, so a crop of
+// a huge render, or the tiles one worker of a distributed render gets, cost only their own memory.
+// Pixels of the tiles nobody wrote to are black
+#[derive(Debug)]
+pub struct TiledFrameBuffer {
+    tiles: Vec<Option<Vec<Vec3f>>>, // rows of FRAME_TILE_SIZE, the border tiles are full size too
+    tiles_nb: Vec2u,
+    resolution: Vec2u,
+    memory: Reservation<'static>,
+}
+
+impl TiledFrameBuffer {
+    pub fn new(resolution: Vec2u) -> TiledFrameBuffer {
+        let tiles_nb = Vec2u::new((resolution.x + FRAME_TILE_SIZE - 1) / FRAME_TILE_SIZE,
+                                  (resolution.y + FRAME_TILE_SIZE - 1) / FRAME_TILE_SIZE);
+        TiledFrameBuffer {
+            tiles: (0..tiles_nb.x * tiles_nb.y).map(|_| None).collect(),
+            tiles_nb: tiles_nb,
+            resolution: resolution,
+            memory: memory::global().empty_reservation(MemoryCategory::Framebuffer),
+        }
+    }
+
+    pub fn resolution(&self) -> Vec2u {
+        self.resolution
+    }
+
+    pub fn allocated_tiles(&self) -> usize {
+        self.tiles.iter().filter(|tile| tile.is_some()).count()
+    }
+
+    // the tile and the pixel in it
+    fn locate(&self, coords: (usize, usize)) -> (usize, usize) {
+        assert!(coords.0 < self.resolution.x);
+        assert!(coords.1 < self.resolution.y);
+        let tile = coords.0 / FRAME_TILE_SIZE + coords.1 / FRAME_TILE_SIZE * self.tiles_nb.x;
+        (tile, coords.0 % FRAME_TILE_SIZE + coords.1 % FRAME_TILE_SIZE * FRAME_TILE_SIZE)
+    }
+
+    fn alloc_tile(&mut self, tile: usize) -> Result<(), OutOfBudget> {
+        if self.tiles[tile].is_none() {
+            let n = FRAME_TILE_SIZE * FRAME_TILE_SIZE;
+            self.memory.grow(n * mem::size_of::<Vec3f>())?;
+            self.tiles[tile] = Some(vec![Zero::zero(); n]);
+        }
+        Ok(())
+    }
+
+    // allocates the tiles the pixels from `min` up to `max` (excluded) are in
+    pub fn alloc_region(&mut self, min: Vec2u, max: Vec2u) -> Result<(), OutOfBudget> {
+        let max = Vec2u::new(max.x.min(self.resolution.x), max.y.min(self.resolution.y));
+        if min.x >= max.x || min.y >= max.y {
+            return Ok(());
+        }
+        for ty in (min.y / FRAME_TILE_SIZE)..((max.y - 1) / FRAME_TILE_SIZE + 1) {
+            for tx in (min.x / FRAME_TILE_SIZE)..((max.x - 1) / FRAME_TILE_SIZE + 1) {
+                self.alloc_tile(tx + ty * self.tiles_nb.x)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_color(&mut self, coords: (usize, usize), color: Vec3f) -> Result<(), OutOfBudget> {
+        let (tile, idx) = self.locate(coords);
+        self.alloc_tile(tile)?;
+        if let Some(ref mut pixels) = self.tiles[tile] {
+            pixels[idx] = pixels[idx] + color;
+        }
+        Ok(())
+    }
+
+    pub fn get_color(&self, coords: (usize, usize)) -> Vec3f {
+        let (tile, idx) = self.locate(coords);
+        self.tiles[tile].as_ref().map_or(Zero::zero(), |pixels| pixels[idx])
+    }
+
+    // the allocated tiles with the position of their first pixel, to be filled in parallel
+    pub fn tiles_mut(&mut self) -> Vec<(Vec2u, &mut [Vec3f])> {
+        let tiles_x = self.tiles_nb.x;
+        self.tiles.iter_mut().enumerate()
+            .filter_map(|(tile, pixels)| pixels.as_mut().map(|pixels| {
+                (Vec2u::new(tile % tiles_x * FRAME_TILE_SIZE, tile / tiles_x * FRAME_TILE_SIZE), pixels.as_mut_slice())
+            }))
+            .collect()
+    }
+
+    // the pixels from `min` up to `max` (excluded) as a frame of their own
+    pub fn crop(&self, min: Vec2u, max: Vec2u) -> RgbFrameBuffer {
+        let max = Vec2u::new(max.x.min(self.resolution.x), max.y.min(self.resolution.y));
+        let size = Vec2u::new(max.x.saturating_sub(min.x), max.y.saturating_sub(min.y));
+        let mut frame = RgbFrameBuffer::new(size);
+        for y in 0..size.y {
+            for x in 0..size.x {
+                frame.set_color((x, y), self.get_color((min.x + x, min.y + y)));
+            }
+        }
+        frame
+    }
+}
+
+// rounded to the nearest, ties to even; too big values become infinities, too small ones subnormals
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exp == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    // the bits below `shift` are rounded away, a carry into the exponent is right too
+    let round = |value: u32, shift: u32| {
+        let (kept, rest, halfway) = (value >> shift, value & ((1 << shift) - 1), 1 << (shift - 1));
+        if rest > halfway || (rest == halfway && kept & 1 == 1) { kept + 1 } else { kept }
+    };
+    if half_exp <= 0 {
+        if half_exp < -10 {
+            return sign;
+        }
+        return sign | round(mantissa | 0x80_0000, (14 - half_exp) as u32) as u16;
+    }
+    sign | round(((half_exp as u32) << 23) | mantissa, 13) as u16
+}
+
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    match exp {
+        0    => {
+            let value = mantissa as f32 / (1 << 24) as f32;
+            if sign != 0 { -value } else { value }
+        },
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (mantissa << 13)),
+        _    => f32::from_bits(sign | ((exp + 112) << 23) | (mantissa << 13))
+    }
+}
+
 impl Borrow<[Vec3f]> for RgbFrameBuffer {
     fn borrow(&self) -> &[Vec3f] {
         self.as_slice()
@@ -193,3 +575,95 @@ pub fn log_tone_mapping(frame: &mut YxyFrameBuffer, lum: FrameLuminosity) {
         pix.x = ((pix.x + 1.0).ln() / interpol) / divider;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveFrameBuffer, HalfFrameBuffer, RgbFrameBuffer, f16_to_f32, f32_to_f16};
+    use math::{Vec2u, Vec3f};
+
+    #[test]
+    fn half_floats_round_trip() {
+        for &x in [0.0, 1.0, -2.5, 0.1, 65504.0, 6.1e-5, 6e-8, 1e-3].iter() {
+            let back = f16_to_f32(f32_to_f16(x));
+            assert!((back - x).abs() <= x.abs() / 1024.0 + 6e-8, "{} -> {}", x, back);
+        }
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 1.0 / 2048.0), 0x3c00); // a tie rounds to the even 1.0
+        assert_eq!(f32_to_f16(1.0 + 3.0 / 2048.0), 0x3c02);
+        assert!(f16_to_f32(f32_to_f16(1e6)).is_infinite());
+    }
+
+    #[test]
+    fn compensated_mean_keeps_converging() {
+        let res = Vec2u::new(2, 1);
+        let mut plain = HalfFrameBuffer::new(res).unwrap();
+        let mut compensated = HalfFrameBuffer::new(res).unwrap().with_compensation().unwrap();
+        let mut frame = RgbFrameBuffer::new(res);
+        // 4 spp an iteration, the mean of the iterations is 0.2 in the first pixel and 10 in the other one
+        let iterations = 4096;
+        for iter in 0..iterations {
+            let v = if iter % 2 == 0 { 0.1 } else { 0.3 };
+            frame.set_color((0, 0), Vec3f::new(v, v, v) * 4.0);
+            frame.set_color((1, 0), Vec3f::new(10.0, 10.0, 10.0) * 4.0);
+            plain.add_frame(&frame, 4);
+            compensated.add_frame(&frame, 4);
+        }
+        let k = 1.0 / (4 * iterations) as f32;
+        let result = compensated.to_rgb();
+        assert!((result.as_slice()[0].x * k - 0.2).abs() < 1e-4, "{}", result.as_slice()[0].x * k);
+        assert!((result.as_slice()[1].x * k - 10.0).abs() < 1e-2, "{}", result.as_slice()[1].x * k);
+        // a sum of them wouldn't even fit into f16
+        assert!(f16_to_f32(f32_to_f16(10.0 * 4.0 * iterations as f32)).is_infinite());
+        assert!((plain.to_rgb().as_slice()[0].x * k - 0.2).abs() > (result.as_slice()[0].x * k - 0.2).abs());
+    }
+
+    #[test]
+    fn bands_add_up_like_a_whole_frame() {
+        // more than a band high and not a whole number of tiles wide
+        let res = Vec2u::new(70, 150);
+        let color = |x: usize, y: usize| Vec3f::new(x as f32, y as f32, 1.0) * 0.01;
+        let mut frame = RgbFrameBuffer::new(res);
+        for y in 0..res.y {
+            for x in 0..res.x {
+                frame.set_color((x, y), color(x, y) * 2.0);
+            }
+        }
+        let mut whole = HalfFrameBuffer::new(res).unwrap();
+        whole.add_frame(&frame, 2);
+        let mut banded = HalfFrameBuffer::new(res).unwrap();
+        let mut bands = 0;
+        banded.add_bands(2, &mut |min, max, band| {
+            bands += 1;
+            for y in min.y..max.y {
+                for x in min.x..max.x {
+                    band.add_color((x, y), color(x, y) * 2.0)?;
+                }
+            }
+            // the band is all that is allocated
+            assert!(band.allocated_tiles() <= 2);
+            Ok(())
+        }).unwrap();
+        assert_eq!(bands, 3);
+        assert_eq!(banded.spp(), 2);
+        assert_eq!(banded.to_rgb().as_slice(), whole.to_rgb().as_slice());
+    }
+
+    #[test]
+    fn noisy_pixels_are_not_converged() {
+        let mut frame = AdaptiveFrameBuffer::new(Vec2u::new(2, 1));
+        for i in 0..64 {
+            frame.add_sample(0, Vec3f::new(0.5, 0.5, 0.5));
+            let v = if i % 2 == 0 { 0.0 } else { 1.0 };
+            frame.add_sample(1, Vec3f::new(v, v, v));
+        }
+        assert_eq!(frame.variance(0), 0.0);
+        assert!((frame.variance(1) - 64.0 / 63.0 * 0.25).abs() < 1e-4, "{}", frame.variance(1));
+        assert!((frame.mean(1).x - 0.5).abs() < 1e-6);
+        // 1.96 * sqrt(0.254 / 64) is a quarter of the mean
+        assert!(frame.converged(0, 0.01, 16));
+        assert!(!frame.converged(1, 0.2, 16));
+        assert!(frame.converged(1, 0.3, 16));
+        assert!(!frame.converged(0, 0.01, 128));
+        assert_eq!(frame.total_samples(), 128);
+    }
+}
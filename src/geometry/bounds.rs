@@ -0,0 +1,117 @@
+use geometry::Ray;
+use math::Vec3f;
+use math::vector_traits::*;
+use std::f32;
+
+// axis aligned bounding box, an empty one has min > max
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3f,
+    pub max: Vec3f,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3f, max: Vec3f) -> Aabb {
+        Aabb { min: min, max: max }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3f::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3f::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3f]) -> Aabb {
+        points.iter().fold(Aabb::empty(), |bounds, p| bounds.grow(p))
+    }
+
+    pub fn around(center: Vec3f, half_size: Vec3f) -> Aabb {
+        Aabb { min: center - half_size, max: center + half_size }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&self, p: &Vec3f) -> Aabb {
+        Aabb {
+            min: Vec3f::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+            max: Vec3f::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z)),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        self.grow(&other.min).grow(&other.max)
+    }
+
+    pub fn expand(&self, delta: f32) -> Aabb {
+        let delta = Vec3f::new(delta, delta, delta);
+        Aabb { min: self.min - delta, max: self.max + delta }
+    }
+
+    pub fn translate(&self, offset: &Vec3f) -> Aabb {
+        Aabb { min: self.min + *offset, max: self.max + *offset }
+    }
+
+    // whether the ray goes through the box ahead of its origin, or starts in it
+    pub fn hit_by(&self, ray: &Ray) -> bool {
+        self.entry_dist(ray).is_some()
+    }
+
+    // how far along the ray it enters the box, 0 if it starts in it
+    pub fn entry_dist(&self, ray: &Ray) -> Option<f32> {
+        let (mut near, mut far) = (0.0f32, f32::INFINITY);
+        for axis in 0..3 {
+            // a ray along a side of the box gets NaNs, which min and max skip: a hit, never a wrong miss
+            let inv = 1.0 / ray.dir[axis];
+            let (t0, t1) = ((self.min[axis] - ray.orig[axis]) * inv, (self.max[axis] - ray.orig[axis]) * inv);
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        if near <= far { Some(near) } else { None }
+    }
+
+    pub fn center(&self) -> Vec3f {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn diagonal(&self) -> Vec3f {
+        self.max - self.min
+    }
+
+    pub fn corners(&self) -> [Vec3f; 8] {
+        let (a, b) = (self.min, self.max);
+        [Vec3f::new(a.x, a.y, a.z), Vec3f::new(b.x, a.y, a.z), Vec3f::new(a.x, b.y, a.z), Vec3f::new(b.x, b.y, a.z),
+         Vec3f::new(a.x, a.y, b.z), Vec3f::new(b.x, a.y, b.z), Vec3f::new(a.x, b.y, b.z), Vec3f::new(b.x, b.y, b.z)]
+    }
+
+    // the smallest sphere around the box: its radius is half the diagonal, not the whole one
+    pub fn bounding_sphere(&self) -> (Vec3f, f32) {
+        (self.center(), self.diagonal().norm() * 0.5)
+    }
+}
+
+// Ritter's sphere: at most ~5% bigger than the minimal one, exact for two points
+pub fn bounding_sphere(points: &[Vec3f]) -> Option<(Vec3f, f32)> {
+    let first = match points.first() {
+        Some(p) => *p,
+        None    => return None
+    };
+    let farthest_from = |from: &Vec3f| points.iter()
+        .fold(*from, |best, p| if (*p - *from).sqnorm() > (best - *from).sqnorm() { *p } else { best });
+    let a = farthest_from(&first);
+    let b = farthest_from(&a);
+    let mut center = (a + b) * 0.5;
+    let mut radius = (b - a).norm() * 0.5;
+    for p in points.iter() {
+        let dist = (*p - center).norm();
+        if dist > radius {
+            // grow just enough to take `p` in, keeping the opposite side in place
+            let new_radius = (radius + dist) * 0.5;
+            center = center + (*p - center) * ((new_radius - radius) / dist);
+            radius = new_radius;
+        }
+    }
+    Some((center, radius))
+}
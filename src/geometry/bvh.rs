@@ -0,0 +1,128 @@
+use geometry::{Aabb, Intersection, Ray};
+use math::Vec3f;
+use numa;
+use std::cmp::Ordering;
+use std::mem;
+
+// primitives per leaf, a few triangle tests cost less than another level of boxes
+const MAX_LEAF_SIZE: usize = 4;
+// the median split halves the primitives every level, the stack never gets this deep
+const MAX_STACK: usize = 64;
+
+// Bounding volume hierarchy over primitives given by their bounds. Nodes are split at the median
+// of the centers along their longest axis, which keeps the tree balanced without a cost model.
+// The primitives of a leaf are consecutive: the owner puts its primitives in the order `build`
+// returns, and the leaves index them directly
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Aabb,
+    first: u32, // the first primitive of a leaf, the second child of an inner node (the first one follows it)
+    count: u32, // 0 for inner nodes
+}
+
+impl Bvh {
+    // the tree and the order the primitives have to be put in
+    pub fn build(bounds: &[Aabb]) -> (Bvh, Vec<usize>) {
+        let centers = bounds.iter().map(|b| b.center()).collect::<Vec<_>>();
+        let mut order = (0..bounds.len()).collect::<Vec<_>>();
+        let mut nodes = Vec::with_capacity(2 * bounds.len() / MAX_LEAF_SIZE + 1);
+        if !bounds.is_empty() {
+            build_node(bounds, &centers, &mut order, 0, &mut nodes);
+        }
+        (Bvh { nodes: nodes }, order)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.nodes.len() * mem::size_of::<BvhNode>()
+    }
+
+    // every thread walks the nodes
+    pub fn place_shared(&self) {
+        numa::global().place_shared(&self.nodes);
+    }
+
+    pub fn translate(&mut self, offset: &Vec3f) {
+        for node in self.nodes.iter_mut() {
+            node.bounds = node.bounds.translate(offset);
+        }
+    }
+
+    // the nearest of the hits `hit` finds in the primitives the ray may go through, with its primitive
+    pub fn intersect<F: FnMut(usize) -> Option<Intersection>>(&self, ray: &Ray, mut hit: F) -> Option<(Intersection, usize)> {
+        let mut nearest: Option<(Intersection, usize)> = None;
+        self.walk(ray, ::std::f32::INFINITY, &mut |first, count| {
+            for prim in first..first + count {
+                if let Some(isect) = hit(prim) {
+                    if nearest.map_or(true, |(n, _)| isect.dist < n.dist) {
+                        nearest = Some((isect, prim));
+                    }
+                }
+            }
+            nearest.map_or(::std::f32::INFINITY, |(n, _)| n.dist)
+        });
+        nearest
+    }
+
+    // whether `hit` is true for any primitive of the leaves the ray enters closer than `max_dist`
+    pub fn any<F: FnMut(usize) -> bool>(&self, ray: &Ray, max_dist: f32, mut hit: F) -> bool {
+        let mut found = false;
+        self.walk(ray, max_dist, &mut |first, count| {
+            found = (first..first + count).any(|prim| hit(prim));
+            if found { 0.0 } else { max_dist }
+        });
+        found
+    }
+
+    // Calls `leaf` with the primitives of the leaves the ray enters closer than `max_dist`,
+    // it returns how far the ray is still of interest; the nodes entered farther than that are skipped
+    fn walk(&self, ray: &Ray, mut max_dist: f32, leaf: &mut FnMut(usize, usize) -> f32) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = [0u32; MAX_STACK];
+        let mut top = 1;
+        while top > 0 {
+            top -= 1;
+            let node_nb = stack[top] as usize;
+            let node = &self.nodes[node_nb];
+            match node.bounds.entry_dist(ray) {
+                Some(near) if near <= max_dist => {},
+                _                              => continue
+            }
+            if node.count > 0 {
+                max_dist = leaf(node.first as usize, node.count as usize);
+                if max_dist <= 0.0 {
+                    return;
+                }
+            } else {
+                stack[top] = node.first;
+                stack[top + 1] = node_nb as u32 + 1;
+                top += 2;
+            }
+        }
+    }
+}
+
+fn build_node(bounds: &[Aabb], centers: &[Vec3f], order: &mut [usize], offset: usize, nodes: &mut Vec<BvhNode>) {
+    let node_nb = nodes.len();
+    let node_bounds = order.iter().fold(Aabb::empty(), |b, &prim| b.union(&bounds[prim]));
+    nodes.push(BvhNode { bounds: node_bounds, first: offset as u32, count: order.len() as u32 });
+    if order.len() <= MAX_LEAF_SIZE {
+        return;
+    }
+
+    let extent = order.iter().fold(Aabb::empty(), |b, &prim| b.grow(&centers[prim])).diagonal();
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    order.sort_by(|&a, &b| centers[a][axis].partial_cmp(&centers[b][axis]).unwrap_or(Ordering::Equal));
+    let mid = order.len() / 2;
+    let (left, right) = order.split_at_mut(mid);
+    build_node(bounds, centers, left, offset, nodes);
+    nodes[node_nb].first = nodes.len() as u32;
+    nodes[node_nb].count = 0;
+    build_node(bounds, centers, right, offset + mid, nodes);
+}
@@ -1,5 +1,6 @@
 #[allow(unused_imports)]
 use math::{smin_exp, smin_poly, smin_pow};
+use geometry::Aabb;
 use math::Vec3f;
 use scene::SurfaceProperties;
 
@@ -16,12 +17,30 @@ pub trait DField {
         let dfdz = self.dist(&(p + dz)) - self.dist(&(p - dz));
         Vec3f { x: dfdx, y: dfdy, z: dfdz } / (2.0 * delta)
     }
+
+    // None when the field has no finite zero set or it's unknown
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    // false if the field can't be moved
+    fn translate(&mut self, _offset: &Vec3f) -> bool {
+        false
+    }
 }
 
 pub trait Isosurface {
     fn dist(&self, point: &Vec3f) -> f32;
     fn grad(&self, p: &Vec3f, delta: f32) -> Vec3f;
     fn surface_properties(&self) -> SurfaceProperties;
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn translate(&mut self, _offset: &Vec3f) -> bool {
+        false
+    }
 }
 
 pub struct DFieldsSubstr<A, B>
@@ -66,6 +85,15 @@ impl<A, B> DField for DFieldsSubstr<A, B>
         let point = *point - self.pos;
         self.a.dist(&point).max(-self.b.dist(&point))
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.a.bounds().map(|a| a.translate(&self.pos))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.pos = self.pos + *offset;
+        true
+    }
 }
 
 impl<A, B> DField for DFieldsUnion<A, B>
@@ -74,6 +102,18 @@ impl<A, B> DField for DFieldsUnion<A, B>
         let point = *point - self.pos;
         self.a.dist(&point).min(self.b.dist(&point))
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match (self.a.bounds(), self.b.bounds()) {
+            (Some(a), Some(b)) => Some(a.union(&b).translate(&self.pos)),
+            _                  => None
+        }
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.pos = self.pos + *offset;
+        true
+    }
 }
 
 impl<A, B> DField for DFieldsBlend<A, B>
@@ -82,6 +122,19 @@ impl<A, B> DField for DFieldsBlend<A, B>
         let point = *point - self.pos;
         smin_poly(self.a.dist(&point), self.b.dist(&point), self.k)
     }
+
+    // the blend only fills the gap between the shapes, it bulges out by less than k
+    fn bounds(&self) -> Option<Aabb> {
+        match (self.a.bounds(), self.b.bounds()) {
+            (Some(a), Some(b)) => Some(a.union(&b).expand(self.k).translate(&self.pos)),
+            _                  => None
+        }
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.pos = self.pos + *offset;
+        true
+    }
 }
 
 impl<D, F> DField for DFieldDisplace<D, F>
@@ -106,5 +159,13 @@ impl<D> Isosurface for DFieldIsosurface<D> where D: DField {
     fn surface_properties(&self) -> SurfaceProperties {
         self.properties
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.dfield.bounds()
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.dfield.translate(offset)
+    }
 }
 
@@ -0,0 +1,73 @@
+use geometry::{Aabb, Geometry, GeometryIssue, Intersection, Mesh, Ray};
+use math::Vec3f;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// A mesh shared by several objects, every one moved by an offset of its own: the rays are moved the
+// other way, so the mesh data is there only once
+#[derive(Debug, Clone)]
+pub struct MeshInstance {
+    pub mesh: Arc<Mesh>,
+    pub offset: Vec3f,
+}
+
+impl MeshInstance {
+    pub fn new(mesh: Arc<Mesh>, offset: Vec3f) -> MeshInstance {
+        MeshInstance { mesh: mesh, offset: offset }
+    }
+
+    fn to_mesh(&self, ray: &Ray) -> Ray {
+        Ray { orig: ray.orig - self.offset, dir: ray.dir }
+    }
+}
+
+impl Geometry for MeshInstance {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.mesh.intersect(&self.to_mesh(ray))
+    }
+
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.mesh.occludes(&self.to_mesh(ray), max_dist)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.mesh.bounds().map(|bounds| bounds.translate(&self.offset))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.offset = self.offset + *offset;
+        true
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        self.mesh.check()
+    }
+}
+
+// Exporters bake the copies of an asset into meshes of their own. Every mesh which is a moved copy
+// of an earlier one becomes an instance of that one, found by `Mesh::shape_hash` and confirmed vertex
+// by vertex; the meshes come back in their order, with the number of the copies dropped.
+// The instances only translate, rotated or scaled copies stay meshes of their own
+pub fn instance_copies(meshes: Vec<Mesh>) -> (Vec<MeshInstance>, usize) {
+    let mut originals: HashMap<u64, Vec<Arc<Mesh>>> = HashMap::new();
+    let mut instances = Vec::with_capacity(meshes.len());
+    let mut copies = 0;
+    for mesh in meshes.into_iter() {
+        let same_hash = originals.entry(mesh.shape_hash()).or_insert_with(Vec::new);
+        let original = same_hash.iter()
+            .filter_map(|original| original.offset_to(&mesh).map(|offset| (original.clone(), offset)))
+            .next();
+        match original {
+            Some((original, offset)) => {
+                instances.push(MeshInstance::new(original, offset));
+                copies += 1;
+            },
+            None => {
+                let mesh = Arc::new(mesh);
+                same_hash.push(mesh.clone());
+                instances.push(MeshInstance::new(mesh, Vec3f::new(0.0, 0.0, 0.0)));
+            }
+        }
+    }
+    (instances, copies)
+}
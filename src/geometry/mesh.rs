@@ -0,0 +1,530 @@
+use geometry::{Aabb, Bvh, Frame, Geometry, GeometryIssue, Intersection, Ray, Triangle, EPS_RAY_GEO};
+use math::{Vec3f, Zero, is_finite};
+use math::vector_traits::*;
+use memory::{self, MemoryCategory, Reservation};
+use numa;
+use rand::Rng;
+use scene::Scene;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::path::Path;
+use utility::{cos_hemisphere_sample, hash_u64, seeded_rng};
+
+// per vertex data interpolated over triangles, meshes without it in the file get the defaults
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VertexAttributes {
+    pub color: Vec3f,
+    pub curvature: f32, // mean curvature, 1 / radius: positive on convex parts, negative in cavities
+    pub occlusion: f32, // baked ambient occlusion, 1 - nothing around
+    pub bent_normal: Vec3f, // mean unoccluded direction, the vertex normal until baked
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OcclusionBake {
+    pub samples: usize, // rays per vertex
+    pub max_dist: f32, // farther hits don't occlude
+    pub seed: u32,
+}
+
+// triangle mesh loaded from OBJ or ASCII PLY, vertex colors are read from both
+pub struct Mesh {
+    vertices: Vec<Vec3f>,
+    faces: Vec<[usize; 3]>,
+    triangles: Vec<Triangle>, // faces with non-zero area, ready for intersection, in the order of `bvh`
+    triangle_faces: Vec<usize>,
+    bvh: Bvh,
+    attributes: Vec<VertexAttributes>,
+    has_colors: bool,
+    _memory: Reservation<'static>,
+}
+
+impl VertexAttributes {
+    pub fn new() -> VertexAttributes {
+        VertexAttributes {
+            color: Vec3f::new(1.0, 1.0, 1.0),
+            curvature: 0.0,
+            occlusion: 1.0,
+            bent_normal: Vec3f::zero(),
+        }
+    }
+
+    fn interpolate(v: [&VertexAttributes; 3], bary: &Vec3f) -> VertexAttributes {
+        VertexAttributes {
+            color: v[0].color * bary.x + v[1].color * bary.y + v[2].color * bary.z,
+            curvature: v[0].curvature * bary.x + v[1].curvature * bary.y + v[2].curvature * bary.z,
+            occlusion: v[0].occlusion * bary.x + v[1].occlusion * bary.y + v[2].occlusion * bary.z,
+            bent_normal: v[0].bent_normal * bary.x + v[1].bent_normal * bary.y + v[2].bent_normal * bary.z,
+        }
+    }
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vec3f>, faces: Vec<[usize; 3]>, colors: Option<Vec<Vec3f>>) -> io::Result<Mesh> {
+        if let Some(face) = faces.iter().find(|face| face.iter().any(|&idx| idx >= vertices.len())) {
+            return Err(invalid_data(format!("face {:?} refers to a missing vertex", face)));
+        }
+        let has_colors = colors.is_some();
+        let mut attributes = vec![VertexAttributes::new(); vertices.len()];
+        if let Some(colors) = colors {
+            if colors.len() != vertices.len() {
+                return Err(invalid_data(format!("{} colors for {} vertices", colors.len(), vertices.len())));
+            }
+            for (attr, color) in attributes.iter_mut().zip(colors.into_iter()) {
+                attr.color = color;
+            }
+        }
+        let normals = vertex_normals(&vertices, &faces);
+        for (attr, curvature) in attributes.iter_mut().zip(vertex_curvature(&vertices, &faces, &normals).into_iter()) {
+            attr.curvature = curvature;
+        }
+        for (attr, normal) in attributes.iter_mut().zip(normals.iter()) {
+            attr.bent_normal = *normal;
+        }
+
+        let mut triangles = Vec::with_capacity(faces.len());
+        let mut triangle_faces = Vec::with_capacity(faces.len());
+        for (idx, face) in faces.iter().enumerate() {
+            let (a, b, c) = (vertices[face[0]], vertices[face[1]], vertices[face[2]]);
+            if (b - a).cross(&(c - a)).sqnorm() > 0.0 {
+                triangles.push(Triangle::new(a, b, c));
+                triangle_faces.push(idx);
+            }
+        }
+        let (bvh, order) = Bvh::build(&triangles.iter().map(|t| Aabb::from_points(&t.vert)).collect::<Vec<_>>());
+        let triangles = order.iter().map(|&idx| triangles[idx].clone()).collect::<Vec<_>>();
+        let triangle_faces = order.iter().map(|&idx| triangle_faces[idx]).collect::<Vec<_>>();
+
+        let bytes = vertices.len() * (mem::size_of::<Vec3f>() + mem::size_of::<VertexAttributes>())
+                  + faces.len() * mem::size_of::<[usize; 3]>()
+                  + triangles.len() * (mem::size_of::<Triangle>() + mem::size_of::<usize>())
+                  + bvh.bytes();
+        let memory = memory::global().reserve(MemoryCategory::Geometry, bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        // the intersection data, every thread walks it
+        numa::global().place_shared(&vertices);
+        numa::global().place_shared(&triangles);
+        bvh.place_shared();
+
+        Ok(Mesh {
+            vertices: vertices,
+            faces: faces,
+            triangles: triangles,
+            triangle_faces: triangle_faces,
+            bvh: bvh,
+            attributes: attributes,
+            has_colors: has_colors,
+            _memory: memory,
+        })
+    }
+
+    // the format is picked by the extension
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Mesh> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) {
+            Some(ref ext) if ext == "obj" => load_obj(reader),
+            Some(ref ext) if ext == "ply" => load_ply(reader),
+            _ => Err(invalid_data(format!("unsupported mesh format of {:?}", path)))
+        }
+    }
+
+    // every object ("o" or "g") of an OBJ file as a mesh of its own, with only the vertices it uses;
+    // other formats have one object
+    pub fn load_objects<P: AsRef<Path>>(path: P) -> io::Result<Vec<Mesh>> {
+        let path = path.as_ref();
+        match path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_lowercase()) {
+            Some(ref ext) if ext == "obj" => {
+                let obj = parse_obj(BufReader::new(File::open(path)?))?;
+                let mut ends = obj.objects.iter().skip(1).cloned().collect::<Vec<_>>();
+                ends.push(obj.faces.len());
+                obj.objects.iter().zip(ends.into_iter())
+                    .filter(|&(&start, end)| end > start)
+                    .map(|(&start, end)| obj.object_mesh(start, end))
+                    .collect()
+            },
+            _ => Mesh::load(path).map(|mesh| vec![mesh])
+        }
+    }
+
+    // The same for every copy of the mesh moved anywhere: the vertices relative to the first one on a
+    // grid much coarser than their rounding (see `hash_cell`), the faces and the colors. Copies landing
+    // on the two sides of a grid line hash differently and stay copies, nothing worse. Only moves:
+    // rotated or scaled copies hash differently
+    pub fn shape_hash(&self) -> u64 {
+        let mut hash = hash_u64(self.vertices.len() as u64 ^ (self.faces.len() as u64) << 32);
+        let origin = match self.vertices.first() {
+            Some(origin) => *origin,
+            None         => return hash
+        };
+        let cell = self.hash_cell();
+        let mut add = |value: u64| hash = hash_u64(hash ^ value);
+        for vertex in self.vertices.iter() {
+            let relative = (*vertex - origin) / cell;
+            add((relative.x.round() as i64) as u64);
+            add((relative.y.round() as i64) as u64);
+            add((relative.z.round() as i64) as u64);
+        }
+        for face in self.faces.iter() {
+            add(face[0] as u64 | (face[1] as u64) << 21 | (face[2] as u64) << 42);
+        }
+        if self.has_colors {
+            for attr in self.attributes.iter() {
+                add(attr.color.x.to_bits() as u64 | (attr.color.y.to_bits() as u64) << 32);
+                add(attr.color.z.to_bits() as u64);
+            }
+        }
+        hash
+    }
+
+    // the offset moving this mesh onto `other`, if `other` is a moved copy of it
+    pub fn offset_to(&self, other: &Mesh) -> Option<Vec3f> {
+        if self.vertices.len() != other.vertices.len() || self.faces != other.faces || self.has_colors != other.has_colors {
+            return None;
+        }
+        let offset = match (self.vertices.first(), other.vertices.first()) {
+            (Some(a), Some(b)) => *b - *a,
+            _                  => return Some(Vec3f::zero())
+        };
+        // the move rounds every vertex on its own
+        let tolerance = 4.0 * self.shape_tolerance().max(other.shape_tolerance());
+        let moved = self.vertices.iter().zip(other.vertices.iter()).all(|(a, b)| (*a + offset - *b).norm() <= tolerance);
+        let colored = !self.has_colors
+            || self.attributes.iter().zip(other.attributes.iter()).all(|(a, b)| a.color == b.color);
+        if moved && colored { Some(offset) } else { None }
+    }
+
+    // how far the vertices of a moved copy may be from the exact move: they're rounded at the magnitude of
+    // their coordinates, which far from the origin is much more than the size of the mesh allows
+    fn shape_tolerance(&self) -> f32 {
+        let size = Aabb::from_points(&self.vertices).diagonal().norm();
+        let magnitude = self.vertices.iter().fold(0.0f32, |max, v| max.max(v.x.abs()).max(v.y.abs()).max(v.z.abs()));
+        (size * 1e-6).max(magnitude * f32::EPSILON * 8.0).max(f32::MIN_POSITIVE)
+    }
+
+    // the grid of `shape_hash`: a 64th of the size rounded up to a power of two, so the copies whose sizes
+    // differ in the last bits still share it. It can't follow the tolerance, which differs between the
+    // copies, but it's coarser than that up to ~16000 sizes from the origin
+    fn hash_cell(&self) -> f32 {
+        let size = Aabb::from_points(&self.vertices).diagonal().norm();
+        2.0f32.powi((size / 64.0).max(f32::MIN_POSITIVE).log2().ceil() as i32)
+    }
+
+    pub fn vertices(&self) -> &[Vec3f] {
+        &self.vertices
+    }
+
+    pub fn faces(&self) -> &[[usize; 3]] {
+        &self.faces
+    }
+
+    pub fn has_colors(&self) -> bool {
+        self.has_colors
+    }
+
+    pub fn attributes(&self) -> &[VertexAttributes] {
+        &self.attributes
+    }
+
+    // Bakes occlusion and bent normals into the vertices with rays traced through `scene`
+    // and the mesh itself, so it's called before the mesh is added to the scene
+    pub fn bake_occlusion<S: Scene>(&mut self, scene: &S, bake: &OcclusionBake) {
+        let normals = vertex_normals(&self.vertices, &self.faces);
+        for (idx, normal) in normals.iter().enumerate() {
+            if normal.sqnorm() == 0.0 {
+                continue;
+            }
+            let frame = Frame::from_z(normal);
+            let mut rng = seeded_rng(bake.seed, idx as u64);
+            let mut open = 0;
+            let mut bent_normal = Vec3f::zero();
+            for _ in 0..bake.samples {
+                let dir = frame.to_world(&cos_hemisphere_sample((rng.next_f32(), rng.next_f32())));
+                let ray = Ray { orig: self.vertices[idx], dir: dir };
+                // rays start on the mesh, it must not occlude them right away
+                let self_hit = self.intersect(&ray.advance(EPS_RAY_GEO))
+                    .map_or(false, |isect| isect.dist < bake.max_dist);
+                if !self_hit && !scene.was_occluded(&ray, bake.max_dist) {
+                    open += 1;
+                    bent_normal = bent_normal + dir;
+                }
+            }
+
+            let attr = &mut self.attributes[idx];
+            attr.occlusion = open as f32 / bake.samples.max(1) as f32;
+            attr.bent_normal = if bent_normal.sqnorm() > 0.0 { bent_normal.normalize() } else { *normal };
+        }
+    }
+}
+
+impl Geometry for Mesh {
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&self.vertices))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        for vertex in self.vertices.iter_mut() {
+            *vertex = *vertex + *offset;
+        }
+        for triangle in self.triangles.iter_mut() {
+            triangle.translate(offset);
+        }
+        self.bvh.translate(offset);
+        true
+    }
+
+    // faces without area are left out of the intersections, the holes they leave are reported
+    fn check(&self) -> Vec<GeometryIssue> {
+        let mut issues = self.vertices.iter().enumerate()
+            .filter(|&(_, vertex)| !is_finite(vertex))
+            .map(|(idx, _)| GeometryIssue::NanVertex(idx))
+            .collect::<Vec<_>>();
+        for (idx, face) in self.faces.iter().enumerate() {
+            let (a, b, c) = (self.vertices[face[0]], self.vertices[face[1]], self.vertices[face[2]]);
+            if [a, b, c].iter().all(is_finite) && (b - a).cross(&(c - a)).sqnorm() == 0.0 {
+                issues.push(GeometryIssue::DegenerateNormal(idx));
+            }
+        }
+        issues
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let nearest = self.bvh.intersect(ray, |idx| self.triangles[idx].intersect(ray));
+        nearest.map(|(mut isect, idx)| {
+            let face = &self.faces[self.triangle_faces[idx]];
+            if let Some(bary) = isect.barycentric {
+                let attributes = [&self.attributes[face[0]], &self.attributes[face[1]], &self.attributes[face[2]]];
+                isect.vertex = Some(VertexAttributes::interpolate(attributes, &bary));
+            }
+            isect
+        })
+    }
+
+    // the first triangle in range will do, no attributes
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.bvh.any(ray, max_dist, |idx| self.triangles[idx].intersect(ray).map_or(false, |isect| isect.dist < max_dist))
+    }
+}
+
+impl fmt::Debug for Mesh {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mesh {{ vertices: {}, faces: {} }}", self.vertices.len(), self.faces.len())
+    }
+}
+
+// area weighted average of face normals
+fn vertex_normals(vertices: &[Vec3f], faces: &[[usize; 3]]) -> Vec<Vec3f> {
+    let mut normals = vec![Vec3f::zero(); vertices.len()];
+    for face in faces.iter() {
+        let (a, b, c) = (vertices[face[0]], vertices[face[1]], vertices[face[2]]);
+        let normal = (b - a).cross(&(c - a));
+        for &idx in face.iter() {
+            normals[idx] = normals[idx] + normal;
+        }
+    }
+    normals.into_iter()
+        .map(|n| if n.sqnorm() > 0.0 { n.normalize() } else { n })
+        .collect()
+}
+
+// How fast the normal turns along the edges around a vertex: (n_j - n_i)·(p_j - p_i) / |p_j - p_i|^2
+// gives 1 / R for every edge of a sphere of radius R, it's averaged over the edges
+fn vertex_curvature(vertices: &[Vec3f], faces: &[[usize; 3]], normals: &[Vec3f]) -> Vec<f32> {
+    let mut sums = vec![(0.0, 0usize); vertices.len()];
+    for face in faces.iter() {
+        for k in 0..3 {
+            let (i, j) = (face[k], face[(k + 1) % 3]);
+            let edge = vertices[j] - vertices[i];
+            let len2 = edge.sqnorm();
+            if len2 <= 0.0 {
+                continue;
+            }
+            let curvature = (normals[j] - normals[i]).dot(&edge) / len2;
+            // every edge is seen from both of its ends
+            for &v in [i, j].iter() {
+                sums[v].0 += curvature;
+                sums[v].1 += 1;
+            }
+        }
+    }
+    sums.into_iter().map(|(sum, n)| if n > 0 { sum / n as f32 } else { 0.0 }).collect()
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse<T: ::std::str::FromStr>(token: Option<&str>, line: &str) -> io::Result<T> {
+    token.and_then(|t| t.parse().ok()).ok_or_else(|| invalid_data(format!("can't parse {:?}", line)))
+}
+
+// polygons are split into fans
+fn triangulate(polygon: &[usize], faces: &mut Vec<[usize; 3]>) {
+    for i in 1..polygon.len().saturating_sub(1) {
+        faces.push([polygon[0], polygon[i], polygon[i + 1]]);
+    }
+}
+
+// the whole of an OBJ file, the vertices are shared by its objects
+struct ObjData {
+    vertices: Vec<Vec3f>,
+    colors: Option<Vec<Vec3f>>,
+    faces: Vec<[usize; 3]>,
+    objects: Vec<usize>, // first face of every object
+}
+
+impl ObjData {
+    // the faces of `start..end` with the vertices they use, renumbered in the order of use
+    fn object_mesh(&self, start: usize, end: usize) -> io::Result<Mesh> {
+        let mut remap = HashMap::new();
+        let mut used = Vec::new();
+        let faces = self.faces[start..end].iter().map(|face| {
+            let mut local = [0; 3];
+            for k in 0..3 {
+                local[k] = *remap.entry(face[k]).or_insert_with(|| {
+                    used.push(face[k]);
+                    used.len() - 1
+                });
+            }
+            local
+        }).collect::<Vec<_>>();
+        let vertices = used.iter().map(|&idx| self.vertices[idx]).collect();
+        let colors = self.colors.as_ref().map(|colors| used.iter().map(|&idx| colors[idx]).collect());
+        Mesh::new(vertices, faces, colors)
+    }
+}
+
+// "v x y z [r g b]" vertices (the MeshLab/ZBrush color extension) and "f" polygons,
+// the vertices without a color in a file with some get the default one
+fn load_obj<R: BufRead>(reader: R) -> io::Result<Mesh> {
+    let obj = parse_obj(reader)?;
+    Mesh::new(obj.vertices, obj.faces, obj.colors)
+}
+
+// as `load_obj`, "o" and "g" lines start the objects of `load_objects`
+fn parse_obj<R: BufRead>(reader: R) -> io::Result<ObjData> {
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut faces = Vec::new();
+    let mut objects = vec![0];
+    for line in reader.lines() {
+        let line = line?;
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("o") | Some("g") => {
+                if *objects.last().unwrap() < faces.len() {
+                    objects.push(faces.len());
+                }
+            },
+            Some("v") => {
+                let values = tokens.map(|t| t.parse::<f32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid_data(format!("can't parse {:?}", line)))?;
+                if values.len() < 3 {
+                    return Err(invalid_data(format!("can't parse {:?}", line)));
+                }
+                vertices.push(Vec3f::new(values[0], values[1], values[2]));
+                if values.len() >= 6 {
+                    // the vertices without a color so far get the default one
+                    colors.resize(vertices.len() - 1, VertexAttributes::new().color);
+                    colors.push(Vec3f::new(values[3], values[4], values[5]));
+                }
+            },
+            Some("f") => {
+                let mut polygon = Vec::new();
+                for token in tokens {
+                    // "v", "v/vt", "v//vn" or "v/vt/vn", negative indices count from the end
+                    let idx: i64 = parse(token.split('/').next(), &line)?;
+                    let idx = if idx < 0 { vertices.len() as i64 + idx } else { idx - 1 };
+                    if idx < 0 {
+                        return Err(invalid_data(format!("bad vertex index in {:?}", line)));
+                    }
+                    polygon.push(idx as usize);
+                }
+                triangulate(&polygon, &mut faces);
+            },
+            _ => {}
+        }
+    }
+    let colors = if colors.is_empty() {
+        None
+    } else {
+        colors.resize(vertices.len(), VertexAttributes::new().color);
+        Some(colors)
+    };
+    Ok(ObjData { vertices: vertices, colors: colors, faces: faces, objects: objects })
+}
+
+fn load_ply<R: BufRead>(reader: R) -> io::Result<Mesh> {
+    let mut lines = reader.lines();
+    let mut next_line = move || -> io::Result<String> {
+        match lines.next() {
+            Some(line) => line,
+            None       => Err(invalid_data("unexpected end of PLY file".to_string()))
+        }
+    };
+
+    if next_line()?.trim() != "ply" {
+        return Err(invalid_data("not a PLY file".to_string()));
+    }
+    // header: elements with their properties, only "vertex" and "face" are used
+    let mut elements: Vec<(String, usize, Vec<(String, String)>)> = Vec::new();
+    loop {
+        let line = next_line()?;
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.first().map(|t| *t) {
+            Some("format") if tokens.get(1) != Some(&"ascii") => {
+                return Err(invalid_data(format!("only ascii PLY is supported, got {:?}", line)));
+            },
+            Some("element") => {
+                let count = parse(tokens.get(2).map(|t| *t), &line)?;
+                elements.push((tokens.get(1).unwrap_or(&"").to_string(), count, Vec::new()));
+            },
+            Some("property") => {
+                let name = tokens.last().unwrap_or(&"").to_string();
+                let kind = tokens.get(1).unwrap_or(&"").to_string();
+                match elements.last_mut() {
+                    Some(element) => element.2.push((name, kind)),
+                    None          => return Err(invalid_data(format!("property out of element: {:?}", line)))
+                }
+            },
+            Some("end_header") => break,
+            _ => {}
+        }
+    }
+
+    let mut vertices = Vec::new();
+    let mut colors = Vec::new();
+    let mut faces = Vec::new();
+    for &(ref name, count, ref properties) in elements.iter() {
+        let column = |prop: &str| properties.iter().position(|&(ref name, _)| name == prop);
+        let (x, y, z) = (column("x"), column("y"), column("z"));
+        let rgb = (column("red"), column("green"), column("blue"));
+        for _ in 0..count {
+            let line = next_line()?;
+            let values = line.split_whitespace().collect::<Vec<_>>();
+            let value = |col: Option<usize>| -> io::Result<f32> {
+                parse(col.and_then(|c| values.get(c).map(|v| *v)), &line)
+            };
+            if name == "vertex" {
+                vertices.push(Vec3f::new(value(x)?, value(y)?, value(z)?));
+                if let (Some(r), Some(_), Some(_)) = rgb {
+                    // 8-bit colors are the common case, float ones are already in [0, 1]
+                    let scale = if properties[r].1.contains("char") { 1.0 / 255.0 } else { 1.0 };
+                    colors.push(Vec3f::new(value(rgb.0)?, value(rgb.1)?, value(rgb.2)?) * scale);
+                }
+            } else if name == "face" {
+                let n: usize = parse(values.first().map(|v| *v), &line)?;
+                let polygon = values.iter().skip(1).take(n)
+                    .map(|v| v.parse::<usize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid_data(format!("can't parse {:?}", line)))?;
+                triangulate(&polygon, &mut faces);
+            }
+        }
+    }
+    let colors = if colors.is_empty() { None } else { Some(colors) };
+    Mesh::new(vertices, faces, colors)
+}
@@ -1,11 +1,21 @@
 #![allow(dead_code)]
 use math::vector_traits::*;
-use math::{Vec2f, Vec3f, ortho};
-use scene::SurfaceProperties;
+use math::{Interval, Vec2f, Vec3f, Zero, clamp, gamma, is_finite, ortho, quadratic_roots, EPS_COSINE};
+use memory::{self, MemoryCategory, OutOfBudget, Reservation};
+use scene::{MaterialID, SurfaceProperties};
 use std::f32;
+use std::mem;
 
+pub mod bounds;
+pub mod bvh;
 pub mod distance_fields;
+pub mod instance;
+pub mod mesh;
+pub use self::bounds::{Aabb, bounding_sphere};
+pub use self::bvh::Bvh;
 pub use self::distance_fields::*;
+pub use self::instance::{MeshInstance, instance_copies};
+pub use self::mesh::{Mesh, OcclusionBake, VertexAttributes};
 
 #[cfg(test)]
 mod tests;
@@ -15,18 +25,53 @@ pub const EPS_RAY_GEO: f32 = 1e-4;
 pub const EPS_RAY_DF: f32 = 1e-2;
 pub const DELTA_GRAD: f32 = 1e-4;
 pub const MAX_DFIELD_STEPS: usize = 1024;
+// the EPS_RAY_* constants are right for scenes of about this radius
+pub const REFERENCE_SCENE_RADIUS: f32 = 50.0;
+
+// where the search for the next hit goes on past a rejected one `dist` along the ray; far from the
+// origin `eps` may be below the precision of `dist`, the step always moves
+pub fn past_hit(dist: f32, eps: f32) -> f32 {
+    dist + eps.max(dist.abs() * f32::EPSILON * 4.0)
+}
+
+// Offsets which keep rays from hitting the surface they start on,
+// they have to follow the scene scale or tiny scenes leak and huge ones get acne
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Epsilons {
+    pub ray_geo: f32,
+    pub ray_df: f32,
+    pub dist_field: f32, // the hit threshold of sphere tracing
+    pub grad: f32, // the step of the distance field gradients
+    pub cosine: f32,
+}
 
 #[derive(Debug, Clone, Copy)]
 pub struct SurfaceIntersection {
     pub normal: Vec3f, // normal at intersection point
     pub dist: f32, // distance to nearest intersection point
+    pub uv: Vec2f, // surface parametrization at intersection point
+    pub dpdu: Vec3f, // tangent along u
+    pub barycentric: Option<Vec3f>, // only triangles have them
+    pub vertex: Option<VertexAttributes>, // only meshes have them
+    pub position: Vec3f, // hit point in world space, set by GeometryManager::nearest_intersection
     pub surface: SurfaceProperties,
 }
 
+// broken geometry data, found by `Geometry::check`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeometryIssue {
+    NanVertex(usize), // a coordinate of the vertex isn't finite
+    DegenerateNormal(usize), // the face has no area, or the sphere no radius, so there's no normal to shade with
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Intersection {
     pub normal: Vec3f, // normal at intersection point
     pub dist: f32, // distance to nearest intersection point
+    pub uv: Vec2f, // surface parametrization at intersection point
+    pub dpdu: Vec3f, // tangent along u
+    pub barycentric: Option<Vec3f>, // only triangles have them
+    pub vertex: Option<VertexAttributes>, // only meshes have them
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +80,24 @@ pub struct Surface<G: Geometry> {
     pub properties: SurfaceProperties,
 }
 
+// Surface whose hits go through `filter` first: it may modify the hit or reject
+// it by returning false, then the search continues behind the rejected hit
+pub struct FilteredSurface<S, F>
+    where S: GeometrySurface,
+          F: Fn(&Ray, &mut SurfaceIntersection) -> bool {
+    pub surface: S,
+    pub filter: F,
+}
+
+// cuts away everything in the half-space the normal points to,
+// i.e. the points where normal.dot(p) > offset
+#[derive(Debug, Clone, Copy)]
+pub struct ClipPlane {
+    pub normal: Vec3f,
+    pub offset: f32,
+    pub capped: bool, // show the cross-section of the solids the plane cuts
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub orig: Vec3f, // origin
@@ -53,6 +116,13 @@ pub struct Triangle {
     pub normal: Vec3f,
 }
 
+// infinite plane through `point`, its uv are the coordinates along it in world units
+#[derive(Debug, Clone)]
+pub struct Plane {
+    pub point: Vec3f,
+    pub normal: Vec3f,
+}
+
 #[derive(Debug, Clone)]
 pub struct Frame {
     ox: Vec3f,
@@ -62,7 +132,10 @@ pub struct Frame {
 
 pub struct GeometryList {
     geometries: Vec<Box<GeometrySurface>>,
-    dfields: Vec<Box<Isosurface>>
+    dfields: Vec<Box<Isosurface>>,
+    clip_planes: Vec<ClipPlane>,
+    eps: Epsilons,
+    memory: Reservation<'static>,
 }
 
 pub struct Torus {
@@ -86,18 +159,76 @@ pub struct RoundBox {
 
 pub trait Geometry {
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+
+    // any hit closer than `max_dist`, for shadow rays; geometry with many primitives stops at the first one
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.intersect(ray).map_or(false, |isect| isect.dist < max_dist)
+    }
+
+    // None for unbounded geometry or when the bounds are unknown
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    // only closed geometry has an inside, it's used for capping of clipped solids
+    fn contains(&self, _point: &Vec3f) -> bool {
+        false
+    }
+
+    // false if the geometry can't be moved
+    fn translate(&mut self, _offset: &Vec3f) -> bool {
+        false
+    }
+
+    // what's wrong with the data of the geometry, analytic shapes are fine unless told otherwise
+    fn check(&self) -> Vec<GeometryIssue> {
+        Vec::new()
+    }
 }
 
 pub trait GeometrySurface {
     fn intersect(&self, ray: &Ray) -> Option<SurfaceIntersection>;
+
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.intersect(ray).map_or(false, |isect| isect.dist < max_dist)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+
+    fn properties_inside(&self, _point: &Vec3f) -> Option<SurfaceProperties> {
+        None
+    }
+
+    fn properties(&self) -> SurfaceProperties;
+
+    fn translate(&mut self, _offset: &Vec3f) -> bool {
+        false
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        Vec::new()
+    }
 }
 
 pub trait GeometryManager {
     fn new() -> Self;
     fn nearest_intersection(&self, ray: &Ray) -> Option<SurfaceIntersection>;
     fn was_occluded(&self, ray: &Ray, dist: f32) -> bool;
-    fn add_geometry<G>(&mut self, object: G) where G: GeometrySurface + 'static;
-    fn add_isosurface<I>(&mut self, object: I) where I: Isosurface + 'static;
+    fn add_geometry<G>(&mut self, object: G) -> Result<(), OutOfBudget>
+        where G: GeometrySurface + 'static;
+    fn add_isosurface<I>(&mut self, object: I) -> Result<(), OutOfBudget>
+        where I: Isosurface + 'static;
+    fn add_clip_plane(&mut self, plane: ClipPlane);
+    // bounds of every object that has them
+    fn object_bounds(&self) -> Vec<Aabb>;
+    fn get_epsilons(&self) -> Epsilons;
+    fn set_epsilons(&mut self, eps: Epsilons);
+    // moves every surface and isosurface of the material, false if some of them can't be moved
+    fn translate_object(&mut self, m_id: MaterialID, offset: &Vec3f) -> bool;
+    // the issues of every surface with its properties, isosurfaces aren't checked
+    fn check_surfaces(&self) -> Vec<(SurfaceProperties, GeometryIssue)>;
 }
 
 
@@ -106,9 +237,109 @@ impl<G> GeometrySurface for Surface<G> where G: Geometry {
         self.geometry.intersect(ray).map(|isect| SurfaceIntersection {
             normal: isect.normal,
             dist: isect.dist,
+            uv: isect.uv,
+            dpdu: isect.dpdu,
+            barycentric: isect.barycentric,
+            vertex: isect.vertex,
+            position: Vec3f::zero(),
             surface: self.properties,
         })
     }
+
+    fn occludes(&self, ray: &Ray, max_dist: f32) -> bool {
+        self.geometry.occludes(ray, max_dist)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.geometry.bounds()
+    }
+
+    fn properties_inside(&self, point: &Vec3f) -> Option<SurfaceProperties> {
+        if self.geometry.contains(point) {
+            Some(self.properties)
+        } else {
+            None
+        }
+    }
+
+    fn properties(&self) -> SurfaceProperties {
+        self.properties
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.geometry.translate(offset)
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        self.geometry.check()
+    }
+}
+
+impl<S, F> GeometrySurface for FilteredSurface<S, F>
+    where S: GeometrySurface,
+          F: Fn(&Ray, &mut SurfaceIntersection) -> bool {
+    fn intersect(&self, ray: &Ray) -> Option<SurfaceIntersection> {
+        // every rejected hit is stepped over until one is taken or the ray leaves the surface
+        let mut skipped = 0.0;
+        loop {
+            let next_ray = ray.advance(skipped);
+            let mut isect = match self.surface.intersect(&next_ray) {
+                Some(isect) => isect,
+                None        => return None
+            };
+            isect.dist += skipped;
+            if (self.filter)(ray, &mut isect) {
+                return Some(isect);
+            }
+            skipped = past_hit(isect.dist, EPS_RAY_GEO);
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.surface.bounds()
+    }
+
+    fn properties_inside(&self, point: &Vec3f) -> Option<SurfaceProperties> {
+        self.surface.properties_inside(point)
+    }
+
+    fn properties(&self) -> SurfaceProperties {
+        self.surface.properties()
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.surface.translate(offset)
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        self.surface.check()
+    }
+}
+
+impl Epsilons {
+    pub fn new() -> Epsilons {
+        Epsilons {
+            ray_geo: EPS_RAY_GEO,
+            ray_df: EPS_RAY_DF,
+            dist_field: EPS_DIST_FIELD,
+            grad: DELTA_GRAD,
+            cosine: EPS_COSINE,
+        }
+    }
+
+    // offsets for a scene inside the given sphere; they never go below
+    // what f32 can resolve at the scene's distance from the origin
+    pub fn for_scene(center: &Vec3f, radius: f32) -> Epsilons {
+        let scale = radius / REFERENCE_SCENE_RADIUS;
+        let precision = (center.norm() + radius) * 16.0 * f32::EPSILON;
+        Epsilons {
+            ray_geo: (EPS_RAY_GEO * scale).max(precision),
+            ray_df: (EPS_RAY_DF * scale).max(precision),
+            dist_field: (EPS_DIST_FIELD * scale).max(precision),
+            grad: (DELTA_GRAD * scale).max(precision),
+            cosine: EPS_COSINE,
+        }
+    }
 }
 
 impl Ray {
@@ -121,6 +352,15 @@ impl DField for Sphere {
     fn dist(&self, point: &Vec3f) -> f32 {
         (*point - self.center).norm() - self.radius
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.center, Vec3f::new(self.radius, self.radius, self.radius)))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.center = self.center + *offset;
+        true
+    }
 }
 
 impl DField for Torus {
@@ -129,6 +369,16 @@ impl DField for Torus {
         let q = Vec2::new(Vec2::new(point.x, point.y).norm() - self.radius, point.z);
         q.norm() - self.thickness
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = self.radius + self.thickness;
+        Some(Aabb::around(self.center, Vec3f::new(r, r, self.thickness)))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.center = self.center + *offset;
+        true
+    }
 }
 
 impl DField for RoundBox {
@@ -137,6 +387,15 @@ impl DField for RoundBox {
         let abs_pb = p.zip(&self.dim, |x, y| (x.abs() - y).max(0.0));
         abs_pb.norm() - self.r
     }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.pos, self.dim).expand(self.r))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.pos = self.pos + *offset;
+        true
+    }
 }
 
 impl Sphere {
@@ -146,32 +405,65 @@ impl Sphere {
 }
 
 impl Geometry for Sphere {
+    fn contains(&self, point: &Vec3f) -> bool {
+        (*point - self.center).sqnorm() < self.r2()
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.center, Vec3f::new(self.radius, self.radius, self.radius)))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        self.center = self.center + *offset;
+        true
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        let mut issues = Vec::new();
+        if !is_finite(&self.center) || !self.radius.is_finite() {
+            issues.push(GeometryIssue::NanVertex(0));
+        } else if self.radius <= 0.0 {
+            issues.push(GeometryIssue::DegenerateNormal(0));
+        }
+        issues
+    }
+
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         let p = ray.orig - self.center;
 
         let r2 = self.r2();
         let p_d = p.dot(&ray.dir);
 
-        if p_d > 0.0 || p.dot(&p) < r2 {
+        // rays from inside, refracted into the sphere, leave through the far side; the ones which head
+        // out are the rays from the surface which start just under it because of rounding
+        if p_d > 0.0 {
             return None;
         }
+        let inside = p.dot(&p) < r2;
 
-        let a = p - ray.dir * p_d;
-        let a2 = a.dot(&a);
-
-        if a2 > r2 {
+        // the discriminant from the distance of the center to the ray, which keeps its precision
+        // for grazing rays, and the products of sums instead of differences of squares
+        let a_norm = (p - ray.dir * p_d).norm();
+        if a_norm > self.radius {
             return None;
         }
+        let p_norm = p.norm();
+        let h = ((self.radius - a_norm) * (self.radius + a_norm)).sqrt();
+        let (near, far) = quadratic_roots(1.0, 2.0 * p_d, (p_norm - self.radius) * (p_norm + self.radius), 2.0 * h)?;
+        let dist = if inside { far } else { near };
+        let i = p + ray.dir * dist;
 
-        let h = (r2 - a2).sqrt();
-        let i = a - ray.dir * h;
-
-        let intersection = self.center + i;
         let normal = i.normalize();
+        let u = 0.5 + normal.z.atan2(normal.x) * 0.5 * f32::consts::FRAC_1_PI;
+        let v = clamp(normal.y, -1.0, 1.0).acos() * f32::consts::FRAC_1_PI;
 
         Some(Intersection {
             normal: normal,
-            dist: (intersection - ray.orig).norm(),
+            dist: dist,
+            uv: Vec2f::new(u, v),
+            dpdu: Vec3f::new(-normal.z, 0.0, normal.x),
+            barycentric: None,
+            vertex: None,
         })
     }
 }
@@ -186,6 +478,25 @@ impl Triangle {
 }
 
 impl Geometry for Triangle {
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::from_points(&self.vert))
+    }
+
+    fn translate(&mut self, offset: &Vec3f) -> bool {
+        for vert in self.vert.iter_mut() {
+            *vert = *vert + *offset;
+        }
+        true
+    }
+
+    fn check(&self) -> Vec<GeometryIssue> {
+        let mut issues = (0..3).filter(|&idx| !is_finite(&self.vert[idx])).map(GeometryIssue::NanVertex).collect::<Vec<_>>();
+        if issues.is_empty() && !is_finite(&self.normal) {
+            issues.push(GeometryIssue::DegenerateNormal(0));
+        }
+        issues
+    }
+
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         let ao = self.vert[0] - ray.orig;
         let bo = self.vert[1] - ray.orig;
@@ -201,13 +512,23 @@ impl Geometry for Triangle {
 
         if ((v0d <= 0.0)  && (v1d <= 0.0)  && (v2d <= 0.0)) ||
            ((v0d >= 0.0) && (v1d >= 0.0) && (v2d >= 0.0)) {
+            // ao carries the rounding of the subtraction, a hit which may be at or behind the origin
+            // when the rounding errors are counted is the surface the ray starts on
+            let ao_error = Interval::with_error(0.0, gamma(1) * self.normal.map(f32::abs).dot(&ao.map(f32::abs)));
+            let dist_bounds = (Interval::dot(&self.normal, &ao) + ao_error) / Interval::dot(&self.normal, &ray.dir);
             let dist = self.normal.dot(&ao) / self.normal.dot(&ray.dir);
-            if dist <= 0.0 {
+            let sum = v0d + v1d + v2d;
+            if dist_bounds.low <= 0.0 || sum == 0.0 {
                 None
             } else {
+                let barycentric = Vec3f::new(v0d, v2d, v1d) / sum;
                 Some(Intersection {
                     normal: self.normal,
                     dist: dist,
+                    uv: Vec2f::new(barycentric.y, barycentric.z),
+                    dpdu: self.vert[1] - self.vert[0],
+                    barycentric: Some(barycentric),
+                    vertex: None,
                 })
             }
         } else {
@@ -216,7 +537,117 @@ impl Geometry for Triangle {
     }
 }
 
+impl Geometry for Plane {
+    fn check(&self) -> Vec<GeometryIssue> {
+        if !is_finite(&self.point) {
+            vec![GeometryIssue::NanVertex(0)]
+        } else if !is_finite(&self.normal) || self.normal.sqnorm() == 0.0 {
+            vec![GeometryIssue::DegenerateNormal(0)]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        let cos = self.normal.dot(&ray.dir);
+        if cos == 0.0 {
+            return None;
+        }
+        let dist = self.normal.dot(&(self.point - ray.orig)) / cos;
+        if !(dist > 0.0) {
+            return None;
+        }
+        let frame = Frame::from_z(&self.normal);
+        let offset = ray.orig + ray.dir * dist - self.point;
+        Some(Intersection {
+            normal: frame.normal(),
+            dist: dist,
+            uv: Vec2f::new(offset.dot(&frame.binormal()), offset.dot(&frame.tangent())),
+            dpdu: frame.binormal(),
+            barycentric: None,
+            vertex: None,
+        })
+    }
+}
+
 impl GeometryList {
+    // part of the ray which isn't cut away: (enter dist, exit dist, plane we enter through)
+    fn clip_interval(&self, ray: &Ray) -> Option<(f32, f32, Option<&ClipPlane>)> {
+        let mut t_enter = 0.0;
+        let mut t_exit = f32::INFINITY;
+        let mut enter_plane = None;
+        for plane in self.clip_planes.iter() {
+            let dir_proj = plane.normal.dot(&ray.dir);
+            let dist_to_plane = plane.offset - plane.normal.dot(&ray.orig);
+            if dir_proj.abs() < 1e-9 {
+                if dist_to_plane < 0.0 {
+                    return None;
+                }
+                continue;
+            }
+            let t = dist_to_plane / dir_proj;
+            if dir_proj > 0.0 {
+                t_exit = t_exit.min(t);
+            } else if t > t_enter {
+                t_enter = t;
+                enter_plane = Some(plane);
+            }
+        }
+
+        if t_enter > t_exit {
+            None
+        } else {
+            Some((t_enter, t_exit, enter_plane))
+        }
+    }
+
+    fn properties_inside(&self, point: &Vec3f) -> Option<SurfaceProperties> {
+        self.geometries.iter()
+            .filter_map(|g| g.properties_inside(point))
+            .next()
+            .or_else(|| self.dfields.iter()
+                .find(|df| df.dist(point) < 0.0)
+                .map(|df| df.surface_properties()))
+    }
+
+    fn nearest_unclipped_isect(&self, ray: &Ray) -> Option<SurfaceIntersection> {
+        let ray_geo = ray.advance(self.eps.ray_geo);
+        let isect = self.nearest_geo_isect(&ray_geo);
+        let ray_df = ray.advance(self.eps.ray_df);
+        self.nearest_isosuface_isect(&ray_df, isect.map_or(10000.0, |isec| isec.dist)).or(isect)
+    }
+
+    fn nearest_clipped_isect(&self, ray: &Ray) -> Option<SurfaceIntersection> {
+        let (t_enter, t_exit, enter_plane) = match self.clip_interval(ray) {
+            Some(interval) => interval,
+            None           => return None
+        };
+
+        let ray_inside = ray.advance(t_enter);
+        if let Some(plane) = enter_plane {
+            // rays starting on a cap must not hit it again
+            if plane.capped && t_enter > self.eps.ray_geo {
+                if let Some(properties) = self.properties_inside(&ray_inside.orig) {
+                    return Some(SurfaceIntersection {
+                        normal: plane.normal,
+                        dist: t_enter,
+                        uv: Vec2f::new(0.0, 0.0),
+                        dpdu: ortho(&plane.normal),
+                        barycentric: None,
+                        vertex: None,
+                        position: Vec3f::zero(),
+                        surface: properties
+                    });
+                }
+            }
+        }
+
+        self.nearest_unclipped_isect(&ray_inside).and_then(|mut isect| {
+            isect.dist += t_enter;
+            if isect.dist <= t_exit { Some(isect) } else { None }
+        })
+    }
+
     fn nearest_geo_isect(&self, ray: &Ray) -> Option<SurfaceIntersection> {
         self.geometries.iter()
             .map(|ref g| g.intersect(&ray))
@@ -238,14 +669,19 @@ impl GeometryList {
 
             let mut d = max_dist;
             for ref df in self.dfields.iter() {
-                // let grad = df.grad(&new_point, DELTA_GRAD);
+                // let grad = df.grad(&new_point, self.eps.grad);
                 let dist = df.dist(&new_point)/* / grad.norm()*/;
-                if dist < EPS_DIST_FIELD {
+                if dist < self.eps.dist_field {
                     let new_point = ray.orig + ray.dir * (t + dist);
-                    let grad = df.grad(&new_point, DELTA_GRAD);
+                    let grad = df.grad(&new_point, self.eps.grad);
                     return Some(SurfaceIntersection {
                         normal: grad.normalize(),
                         dist: t + dist,
+                        uv: Vec2f::new(0.0, 0.0),
+                        dpdu: ortho(&grad),
+                        barycentric: None,
+                        vertex: None,
+                        position: Vec3f::zero(),
                         surface: df.surface_properties()
                     })
                 }
@@ -265,41 +701,95 @@ impl GeometryManager for GeometryList {
     fn new() -> GeometryList {
         GeometryList {
             geometries: Vec::new(),
-            dfields: Vec::new()
+            dfields: Vec::new(),
+            clip_planes: Vec::new(),
+            eps: Epsilons::new(),
+            memory: memory::global().empty_reservation(MemoryCategory::Geometry),
         }
     }
 
     fn nearest_intersection(&self, ray: &Ray) -> Option<SurfaceIntersection> {
-        let ray_geo = ray.advance(EPS_RAY_GEO);;
-        let isect = self.nearest_geo_isect(&ray_geo);
-        let ray_df = ray.advance(EPS_RAY_DF);
-        self.nearest_isosuface_isect(&ray_df, isect.map_or(10000.0, |isec| isec.dist)).or(isect)
+        let isect = if self.clip_planes.is_empty() {
+            self.nearest_unclipped_isect(ray)
+        } else {
+            self.nearest_clipped_isect(ray)
+        };
+        isect.map(|mut isect| {
+            isect.position = ray.orig + ray.dir * isect.dist;
+            isect
+        })
     }
 
     fn was_occluded(&self, ray: &Ray, dist: f32) -> bool {
-        let ray_geo = ray.advance(EPS_RAY_GEO);
-        let dist_geo = dist - 2.0 * EPS_RAY_GEO;
-        let occluded_by_geo = self.geometries.iter()
-            .map(|ref g| g.intersect(&ray_geo))
-            .any(|isect| isect.map_or(false, |isec| {
-                isec.dist < dist_geo
-            }));
+        if !self.clip_planes.is_empty() {
+            return self.nearest_clipped_isect(ray)
+                .map_or(false, |isect| isect.dist < dist - 2.0 * self.eps.ray_geo);
+        }
+
+        let ray_geo = ray.advance(self.eps.ray_geo);
+        let dist_geo = dist - 2.0 * self.eps.ray_geo;
+        let occluded_by_geo = self.geometries.iter().any(|g| g.occludes(&ray_geo, dist_geo));
 
         if occluded_by_geo {
             true
         } else {
-            let ray_df = ray.advance(EPS_RAY_DF);
-            let dist_df = dist - 2.0 * EPS_RAY_DF;
+            let ray_df = ray.advance(self.eps.ray_df);
+            let dist_df = dist - 2.0 * self.eps.ray_df;
             self.nearest_isosuface_isect(&ray_df, dist_df).is_some()
         }
     }
 
-    fn add_geometry<G>(&mut self, object: G) where G: GeometrySurface + 'static {
+    fn add_geometry<G>(&mut self, object: G) -> Result<(), OutOfBudget>
+        where G: GeometrySurface + 'static {
+        self.memory.grow(mem::size_of::<G>() + mem::size_of::<Box<GeometrySurface>>())?;
         self.geometries.push(Box::new(object));
+        Ok(())
     }
 
-    fn add_isosurface<I>(&mut self, object: I) where I: Isosurface + 'static {
+    fn add_isosurface<I>(&mut self, object: I) -> Result<(), OutOfBudget>
+        where I: Isosurface + 'static {
+        self.memory.grow(mem::size_of::<I>() + mem::size_of::<Box<Isosurface>>())?;
         self.dfields.push(Box::new(object));
+        Ok(())
+    }
+
+    fn add_clip_plane(&mut self, plane: ClipPlane) {
+        self.clip_planes.push(plane);
+    }
+
+    fn object_bounds(&self) -> Vec<Aabb> {
+        let geo_bounds = self.geometries.iter().filter_map(|g| g.bounds());
+        let df_bounds = self.dfields.iter().filter_map(|df| df.bounds());
+        geo_bounds.chain(df_bounds).collect()
+    }
+
+    fn get_epsilons(&self) -> Epsilons {
+        self.eps
+    }
+
+    fn set_epsilons(&mut self, eps: Epsilons) {
+        self.eps = eps;
+    }
+
+    fn translate_object(&mut self, m_id: MaterialID, offset: &Vec3f) -> bool {
+        let is_object = |properties: SurfaceProperties| match properties {
+            SurfaceProperties::Material(id) => id == m_id,
+            SurfaceProperties::Light(_)     => false
+        };
+        let mut moved = true;
+        for geometry in self.geometries.iter_mut().filter(|g| is_object(g.properties())) {
+            moved &= geometry.translate(offset);
+        }
+        for dfield in self.dfields.iter_mut().filter(|df| is_object(df.surface_properties())) {
+            moved &= dfield.translate(offset);
+        }
+        moved
+    }
+
+    fn check_surfaces(&self) -> Vec<(SurfaceProperties, GeometryIssue)> {
+        self.geometries.iter()
+            .flat_map(|geometry| geometry.check().into_iter().map(move |issue| (geometry.properties(), issue)))
+            .collect()
     }
 }
 
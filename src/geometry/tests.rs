@@ -1,12 +1,15 @@
 use super::*;
 use math::Vec3f;
 use scene::SurfaceProperties;
+use nalgebra::ApproxEq;
+use rand::Rng;
+use utility::seeded_rng;
 
 #[test]
 fn occlusion_sphere() {
     let mut geos = GeometryList::new();
     let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
-    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) });
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
     let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
     assert!(geos.was_occluded(&ray, 4.0));
     assert!(!geos.was_occluded(&ray, 3.0 - EPS_RAY_DF));
@@ -17,7 +20,7 @@ fn occlusion_sphere() {
 fn occlusion_sphere_on_surface() {
     let mut geos = GeometryList::new();
     let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
-    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) });
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
     let ray = Ray { orig: Vec3f::new(0.0, 0.0, -6.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
     assert!(!geos.was_occluded(&ray, EPS_RAY_DF));
 }
@@ -26,7 +29,7 @@ fn occlusion_sphere_on_surface() {
 fn occlusion_sphere_near_surface() {
     let mut geos = GeometryList::new();
     let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
-    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) });
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
     let ray = Ray { orig: Vec3f::new(0.0, 0.0, -2.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
     assert!(!geos.was_occluded(&ray.advance(-EPS_RAY_GEO * 2.0), EPS_RAY_GEO));
 }
@@ -36,10 +39,10 @@ fn occlusion_tri_sphere() {
     let mut geos = GeometryList::new();
     let ident_mat = SurfaceProperties::Material(0);
     let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
-    geos.add_geometry(Surface { geometry: sphere, properties: ident_mat });
+    geos.add_geometry(Surface { geometry: sphere, properties: ident_mat }).unwrap();
 
     let tri = Triangle::new(Vec3f::new(1.0, -1.0, -3.0) , Vec3f::new(-1.0, -1.0, -3.0), Vec3f::new(-1.0, 1.0, -3.0));
-    geos.add_geometry(Surface { geometry: tri, properties: ident_mat });
+    geos.add_geometry(Surface { geometry: tri, properties: ident_mat }).unwrap();
 
     let ray = Ray { orig: Vec3f::new(0.0, 0.0, 2.1), dir: Vec3f::new(0.0, 0.0, 1.0) };
     assert!(!geos.was_occluded(&ray, 0.01));
@@ -54,7 +57,7 @@ fn occlusion_tri() {
     let ident_mat = SurfaceProperties::Material(0);
 
     let tri = Triangle::new(Vec3f::new(1.0, -1.0, -3.0) , Vec3f::new(-1.0, -1.0, -3.0), Vec3f::new(-1.0, 1.0, -3.0));
-    geos.add_geometry(Surface { geometry: tri, properties: ident_mat });
+    geos.add_geometry(Surface { geometry: tri, properties: ident_mat }).unwrap();
 
     let ray = Ray { orig: Vec3f::new(0.0, 0.0, 2.1), dir: Vec3f::new(0.0, 0.0, 1.0) };
     let was_occluded = if let Some(isect) = geos.nearest_intersection(&ray) {
@@ -73,3 +76,397 @@ fn occlusion_tri() {
     let ray_from_tri = Ray { orig: Vec3f::new(0.0, 0.0, -3.5), dir: Vec3f::new(0.0, 0.0, 1.0) };
     assert!(geos.was_occluded(&ray_from_tri, 2.0));
 }
+
+#[test]
+fn filtered_tri_cutout() {
+    let mut geos = GeometryList::new();
+    let tri = Triangle::new(Vec3f::new(1.0, -1.0, -3.0) , Vec3f::new(-1.0, -1.0, -3.0), Vec3f::new(-1.0, 1.0, -3.0));
+    let cutout = FilteredSurface {
+        surface: Surface { geometry: tri, properties: SurfaceProperties::Material(0) },
+        filter: |ray: &Ray, isect: &mut SurfaceIntersection| (ray.orig + ray.dir * isect.dist).x < -0.5
+    };
+    geos.add_geometry(cutout).unwrap();
+
+    let ray_through_hole = Ray { orig: Vec3f::new(-0.2, -0.5, 0.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    assert!(geos.nearest_intersection(&ray_through_hole).is_none());
+    assert!(!geos.was_occluded(&ray_through_hole, 10.0));
+
+    let ray = Ray { orig: Vec3f::new(-0.8, -0.5, 0.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    assert!(geos.was_occluded(&ray, 10.0));
+}
+
+#[test]
+fn filtered_sphere_hit_behind_rejected() {
+    let mut geos = GeometryList::new();
+    let near = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 };
+    let far = Sphere { center: Vec3f::new(0.0, 0.0, 5.0), radius: 1.0 };
+    let near = Surface { geometry: near, properties: SurfaceProperties::Material(0) };
+    let far = Surface { geometry: far, properties: SurfaceProperties::Material(1) };
+    let cull_near = FilteredSurface {
+        surface: near,
+        // the near sphere is in [4, 6], both of its sides are rejected
+        filter: |_: &Ray, isect: &mut SurfaceIntersection| isect.dist > 7.0
+    };
+    geos.add_geometry(cull_near).unwrap();
+    geos.add_geometry(far).unwrap();
+
+    let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    let isect = geos.nearest_intersection(&ray).unwrap();
+    assert!((isect.dist - 9.0).abs() < 1e-3);
+}
+
+#[test]
+fn filtered_hits_are_stepped_over_until_the_ray_misses() {
+    use std::cell::Cell;
+    // 40 slabs one after another, the filter only takes the last one
+    let slab = |z: f32| vec![Vec3f::new(-1.0, -1.0, z), Vec3f::new(1.0, -1.0, z), Vec3f::new(0.0, 1.0, z)];
+    let vertices = (0..40).flat_map(|i| slab(i as f32)).collect::<Vec<_>>();
+    let faces = (0..40).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect::<Vec<_>>();
+    let mesh = Mesh::new(vertices, faces, None).unwrap();
+    let rejected = Cell::new(0);
+    let last_only = FilteredSurface {
+        surface: Surface { geometry: mesh, properties: SurfaceProperties::Material(0) },
+        filter: |_: &Ray, isect: &mut SurfaceIntersection| {
+            let taken = isect.dist > 39.5;
+            if !taken { rejected.set(rejected.get() + 1); }
+            taken
+        }
+    };
+    let ray = Ray { orig: Vec3f::new(0.0, 0.0, -1.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    let isect = last_only.intersect(&ray).unwrap();
+    assert!((isect.dist - 40.0).abs() < 1e-3);
+    assert_eq!(rejected.get(), 39);
+    // and past the last slab there's nothing
+    assert!(last_only.intersect(&Ray { orig: Vec3f::new(0.0, 0.0, 39.5), dir: Vec3f::new(0.0, 0.0, 1.0) }).is_none());
+}
+
+#[test]
+fn hidden_objects_are_stepped_over_until_the_ray_misses() {
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use scene::{DefaultScene, LayerVisibility, RenderLayer, Scene};
+
+    let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+    let hidden = (0..40).map(|i| {
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 3.0 * i as f32), radius: 1.0 }, WHITE_DIFFUSE).unwrap()
+    }).collect::<Vec<_>>();
+    let visible = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 120.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+    scene.commit_changes();
+    scene.set_render_layer(Some(RenderLayer::new("last", LayerVisibility::Visible).with_objects(&hidden, LayerVisibility::Hidden)));
+
+    let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    let isect = scene.nearest_intersection(&ray).unwrap();
+    assert_eq!(isect.surface, SurfaceProperties::Material(visible));
+    assert!((isect.dist - 124.0).abs() < 1e-2);
+}
+
+#[test]
+fn clipped_sphere_cap() {
+    let mut geos = GeometryList::new();
+    let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
+    geos.add_clip_plane(ClipPlane { normal: Vec3f::new(0.0, 0.0, -1.0), offset: 0.0, capped: true });
+
+    let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    let isect = geos.nearest_intersection(&ray).unwrap();
+    assert!((isect.dist - 5.0).abs() < 1e-4);
+    assert!(isect.normal.approx_eq(&Vec3f::new(0.0, 0.0, -1.0)));
+
+    let ray_beside = Ray { orig: Vec3f::new(3.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    assert!(geos.nearest_intersection(&ray_beside).is_none());
+}
+
+#[test]
+fn clipped_geometry_casts_no_shadow() {
+    let mut geos = GeometryList::new();
+    let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
+    geos.add_clip_plane(ClipPlane { normal: Vec3f::new(1.0, 0.0, 0.0), offset: -3.0, capped: false });
+
+    let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    assert!(!geos.was_occluded(&ray, 10.0));
+}
+
+#[test]
+fn triangle_barycentric() {
+    let tri = Triangle::new(Vec3f::new(1.0, -1.0, -3.0) , Vec3f::new(-1.0, -1.0, -3.0), Vec3f::new(-1.0, 1.0, -3.0));
+    let ray = Ray { orig: Vec3f::new(0.9, -0.95, 0.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    let bary = tri.intersect(&ray).unwrap().barycentric.unwrap();
+    assert!(bary.x > 0.9 && bary.y < 0.1 && bary.z < 0.1);
+
+    let ray = Ray { orig: Vec3f::new(-0.95, 0.9, 0.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    let bary = tri.intersect(&ray).unwrap().barycentric.unwrap();
+    assert!(bary.z > 0.9 && bary.x < 0.1 && bary.y < 0.1);
+}
+
+#[test]
+fn mesh_interpolates_vertex_colors() {
+    let path = ::std::env::temp_dir().join("xray_vertex_colors.obj");
+    ::std::fs::write(&path, "v 0 0 0 1 0 0\nv 1 0 0 0 1 0\nv 1 1 0 0 0 1\nv 0 1 0 0 0 1\nf 1 2 3 4\n").unwrap();
+    let mesh = Mesh::load(&path).unwrap();
+    assert_eq!(mesh.faces().len(), 2);
+    assert!(mesh.has_colors());
+
+    let ray = Ray { orig: Vec3f::new(0.5, 0.25, 1.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    let color = mesh.intersect(&ray).unwrap().vertex.unwrap().color;
+    assert!(color.approx_eq(&Vec3f::new(0.5, 0.25, 0.25)));
+}
+
+#[test]
+fn mesh_defaults_missing_vertex_colors() {
+    let path = ::std::env::temp_dir().join("xray_some_vertex_colors.obj");
+    ::std::fs::write(&path, "v 0 0 0\nv 1 0 0 0 1 0\nv 1 1 0\nf 1 2 3\n").unwrap();
+    let mesh = Mesh::load(&path).unwrap();
+    assert!(mesh.has_colors());
+    let colors = mesh.attributes().iter().map(|attr| attr.color).collect::<Vec<_>>();
+    assert_eq!(colors, vec![Vec3f::new(1.0, 1.0, 1.0), Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(1.0, 1.0, 1.0)]);
+}
+
+#[test]
+fn mesh_bvh_finds_the_nearest_triangle() {
+    // a bumpy grid, folded so that rays cross it several times
+    let n = 24;
+    let vertices = (0..(n + 1) * (n + 1))
+        .map(|idx| {
+            let (x, y) = ((idx % (n + 1)) as f32 / n as f32, (idx / (n + 1)) as f32 / n as f32);
+            Vec3f::new(x, y, (x * 17.0).sin() * (y * 11.0).cos() * 0.2)
+        })
+        .collect::<Vec<_>>();
+    let mut faces = Vec::new();
+    for y in 0..n {
+        for x in 0..n {
+            let v = y * (n + 1) + x;
+            faces.push([v, v + 1, v + n + 2]);
+            faces.push([v, v + n + 2, v + n + 1]);
+        }
+    }
+    let triangles = faces.iter().map(|f| Triangle::new(vertices[f[0]], vertices[f[1]], vertices[f[2]])).collect::<Vec<_>>();
+    let mesh = Mesh::new(vertices, faces, None).unwrap();
+
+    let mut rng = seeded_rng(5, 0);
+    for _ in 0..500 {
+        let orig = Vec3f::new(rng.next_f32() * 1.4 - 0.2, rng.next_f32() * 1.4 - 0.2, 0.5);
+        let target = Vec3f::new(rng.next_f32(), rng.next_f32(), -0.3);
+        let ray = Ray { orig: orig, dir: (target - orig).normalize() };
+        let brute = triangles.iter().filter_map(|t| t.intersect(&ray)).map(|isect| isect.dist)
+            .fold(None, |nearest: Option<f32>, dist| Some(nearest.map_or(dist, |n| n.min(dist))));
+        assert_eq!(mesh.intersect(&ray).map(|isect| isect.dist), brute);
+        if let Some(dist) = brute {
+            assert!(mesh.occludes(&ray, dist * 1.01) && !mesh.occludes(&ray, dist * 0.99));
+        }
+    }
+}
+
+#[test]
+fn mesh_occludes_like_it_intersects() {
+    // two layers of a quad, the shadow ray stops at either
+    let vertices = vec![Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0),
+                        Vec3f::new(0.0, 0.0, -1.0), Vec3f::new(1.0, 0.0, -1.0), Vec3f::new(0.0, 1.0, -1.0)];
+    let mesh = Mesh::new(vertices, vec![[0, 1, 2], [3, 4, 5]], None).unwrap();
+    let ray = Ray { orig: Vec3f::new(0.25, 0.25, 1.0), dir: Vec3f::new(0.0, 0.0, -1.0) };
+    assert!(mesh.occludes(&ray, 1.5) && mesh.occludes(&ray, 3.0));
+    assert!(!mesh.occludes(&ray, 0.9));
+    let mut geos = GeometryList::new();
+    geos.add_geometry(Surface { geometry: mesh, properties: SurfaceProperties::Material(0) }).unwrap();
+    assert!(geos.was_occluded(&ray, 1.5));
+    assert!(!geos.was_occluded(&ray, 0.9));
+}
+
+#[test]
+fn octahedron_is_convex() {
+    let vertices = vec![
+        Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(-1.0, 0.0, 0.0),
+        Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, -1.0, 0.0),
+        Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.0, 0.0, -1.0),
+    ];
+    let faces = vec![
+        [0, 2, 4], [2, 1, 4], [1, 3, 4], [3, 0, 4],
+        [2, 0, 5], [1, 2, 5], [3, 1, 5], [0, 3, 5],
+    ];
+    let mesh = Mesh::new(vertices, faces, None).unwrap();
+    for attr in mesh.attributes() {
+        assert!((attr.curvature - 1.0).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn baked_occlusion_sees_the_scene() {
+    use light::BackgroundLight;
+    use scene::{DefaultScene, Scene};
+
+    let quad = || Mesh::new(vec![
+        Vec3f::new(-1.0, 0.0, -1.0), Vec3f::new(1.0, 0.0, -1.0),
+        Vec3f::new(1.0, 0.0, 1.0), Vec3f::new(-1.0, 0.0, 1.0),
+    ], vec![[0, 2, 1], [0, 3, 2]], None).unwrap();
+    let bake = OcclusionBake { samples: 64, max_dist: 10.0, seed: 1 };
+
+    let mut scene: DefaultScene<GeometryList> = DefaultScene::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+    let mut open = quad();
+    open.bake_occlusion(&scene, &bake);
+    assert!(open.attributes().iter().all(|attr| attr.occlusion == 1.0));
+
+    let roof = Triangle::new(Vec3f::new(-50.0, 1.0, -50.0), Vec3f::new(50.0, 1.0, -50.0), Vec3f::new(0.0, 1.0, 50.0));
+    scene.add_object(roof, ::materials_and_colors::WHITE_DIFFUSE).unwrap();
+    let mut covered = quad();
+    covered.bake_occlusion(&scene, &bake);
+    assert!(covered.attributes().iter().all(|attr| attr.occlusion < 0.1));
+}
+
+#[test]
+fn baked_copies_become_instances() {
+    // three objects of the same box at different places, one of them far from the origin where the
+    // coordinates are rounded coarser, and a fourth one which is bigger
+    let path = ::std::env::temp_dir().join("xray_baked_copies.obj");
+    let mut obj = String::new();
+    for (idx, &(x, size)) in [(0.0, 1.0), (5.25, 1.0), (10.0, 2.0), (1000.3, 1.0)].iter().enumerate() {
+        obj.push_str(&format!("o box{}\n", idx));
+        for corner in 0..8 {
+            obj.push_str(&format!("v {} {} {}\n", x + size * (corner & 1) as f32, size * (corner >> 1 & 1) as f32,
+                                  size * (corner >> 2 & 1) as f32));
+        }
+        let base = idx * 8 + 1;
+        for face in [[0, 2, 3, 1], [4, 5, 7, 6], [0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3]].iter() {
+            obj.push_str(&format!("f {} {} {} {}\n", base + face[0], base + face[1], base + face[2], base + face[3]));
+        }
+    }
+    ::std::fs::write(&path, obj).unwrap();
+    let meshes = Mesh::load_objects(&path).unwrap();
+    assert_eq!(meshes.len(), 4);
+    assert!(meshes.iter().all(|mesh| mesh.vertices().len() == 8 && mesh.faces().len() == 12));
+    assert_eq!(meshes[0].shape_hash(), meshes[1].shape_hash());
+    assert_eq!(meshes[0].shape_hash(), meshes[3].shape_hash());
+    assert!(meshes[0].offset_to(&meshes[2]).is_none());
+
+    let (instances, copies) = instance_copies(meshes);
+    assert_eq!(copies, 2);
+    assert!(::std::sync::Arc::ptr_eq(&instances[0].mesh, &instances[1].mesh));
+    assert!(::std::sync::Arc::ptr_eq(&instances[0].mesh, &instances[3].mesh));
+    assert!(instances[1].offset.approx_eq(&Vec3f::new(5.25, 0.0, 0.0)));
+    assert!((instances[3].offset - Vec3f::new(1000.3, 0.0, 0.0)).norm() < 1e-3);
+    // the instance is where the copy was
+    let ray = Ray { orig: Vec3f::new(5.75, 0.5, -3.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    assert!((instances[1].intersect(&ray).unwrap().dist - 3.0).abs() < 1e-5);
+    assert!(instances[0].intersect(&ray).is_none());
+    assert_eq!(instances[1].bounds().unwrap().min.x, 5.25);
+}
+
+#[test]
+fn epsilons_follow_scene_scale() {
+    let tiny = Epsilons::for_scene(&Vec3f::new(0.0, 0.0, 0.0), REFERENCE_SCENE_RADIUS * 1e-3);
+    assert!((tiny.ray_geo - EPS_RAY_GEO * 1e-3).abs() < 1e-9);
+    assert!((tiny.dist_field - EPS_DIST_FIELD * 1e-3).abs() < 1e-9);
+    assert!((tiny.grad - DELTA_GRAD * 1e-3).abs() < 1e-9);
+    // far from the origin f32 can't resolve small offsets
+    let far = Epsilons::for_scene(&Vec3f::new(1e6, 0.0, 0.0), 1.0);
+    assert!(far.ray_geo > 1.0);
+
+    let mut geos = GeometryList::new();
+    let sphere = Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 2.0 };
+    geos.add_geometry(Surface { geometry: sphere, properties: SurfaceProperties::Material(0) }).unwrap();
+    let bounds = geos.object_bounds();
+    assert_eq!(bounds, vec![Aabb::new(Vec3f::new(-2.0, -2.0, -2.0), Vec3f::new(2.0, 2.0, 2.0))]);
+}
+
+#[test]
+fn bounding_spheres() {
+    let bounds = Aabb::new(Vec3f::new(-1.0, -2.0, -2.0), Vec3f::new(1.0, 2.0, 2.0));
+    let (center, radius) = bounds.bounding_sphere();
+    assert_eq!(center, Vec3f::new(0.0, 0.0, 0.0));
+    assert!((radius - 3.0).abs() < 1e-6);
+
+    let points = [Vec3f::new(-4.0, 0.0, 0.0), Vec3f::new(4.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 1.0)];
+    let (center, radius) = bounding_sphere(&points).unwrap();
+    assert!(center.approx_eq(&Vec3f::new(0.0, 0.0, 0.0)));
+    assert!((radius - 4.0).abs() < 1e-6);
+}
+
+#[test]
+fn scene_edits_are_tracked() {
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use scene::{DefaultScene, Scene, SceneChanges};
+
+    let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+    let ball = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+    assert!(!scene.commit_changes().any());
+
+    assert!(scene.move_object(ball, &Vec3f::new(0.0, 0.0, 0.0)));
+    assert!(!scene.commit_changes().any());
+
+    let eps = scene.get_epsilons();
+    assert!(scene.move_object(ball, &Vec3f::new(1e5, 0.0, 0.0)));
+    let ray = Ray { orig: Vec3f::new(1e5, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+    assert!(scene.nearest_intersection(&ray).is_some());
+    // the escape bounds are still around the old place, the ray mustn't miss by them
+    assert!(!scene.escapes(&ray));
+    assert_eq!(scene.get_epsilons(), eps);
+    assert_eq!(scene.commit_changes(), SceneChanges { geometry: true, materials: false, lights: false });
+    // refitted: the object is far from the origin now
+    assert!(scene.get_epsilons().ray_geo > eps.ray_geo);
+    assert!(scene.get_object_bounds(ball).unwrap().center().approx_eq(&Vec3f::new(1e5, 0.0, 0.0)));
+    assert!(!scene.escapes(&ray));
+    assert!(scene.escapes(&Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) }));
+
+    scene.edit_material(ball, |material| material.phong_exp = 10.0);
+    assert!(scene.set_light_intensity(0, Vec3f::new(1.0, 1.0, 1.0)));
+    assert_eq!(scene.commit_changes(), SceneChanges { geometry: false, materials: true, lights: true });
+    assert_eq!(scene.get_material(ball).phong_exp, 10.0);
+
+    // additions grow the epsilons at once, the commit only fits them tighter
+    let eps = scene.get_epsilons();
+    scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 1e6), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+    let grown = scene.get_epsilons();
+    assert!(grown.ray_geo > eps.ray_geo);
+    assert!(!scene.commit_changes().any());
+    assert!(scene.get_epsilons().ray_geo > eps.ray_geo);
+    assert!(scene.get_epsilons().ray_geo <= grown.ray_geo);
+}
+
+#[test]
+fn tiny_isosurfaces_are_traced_at_their_scale() {
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use scene::{DefaultScene, Scene};
+
+    let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+    let radius = 1e-3;
+    scene.add_isosurface(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: radius }, WHITE_DIFFUSE).unwrap();
+    // no commit_changes: the epsilons follow the added objects
+    assert!(scene.get_epsilons().dist_field < EPS_DIST_FIELD * 0.1);
+
+    // passes 5% of the radius above the sphere, the fixed threshold called it a hit
+    let grazing = Ray { orig: Vec3f::new(-0.01, 0.0, radius * 1.05), dir: Vec3f::new(1.0, 0.0, 0.0) };
+    assert!(scene.nearest_intersection(&grazing).is_none());
+
+    let ray = Ray { orig: Vec3f::new(-0.01, 0.0, radius * 0.6), dir: Vec3f::new(1.0, 0.0, 0.0) };
+    let isect = scene.nearest_intersection(&ray).unwrap();
+    let expected = Vec3f::new(-radius * 0.8, 0.0, radius * 0.6) / radius;
+    assert!((isect.dist - (0.01 - radius * 0.8)).abs() < radius * 1e-3);
+    assert!(isect.normal.dot(&expected) > 0.9999);
+}
+
+#[test]
+fn sphere_hits_stay_precise() {
+    // the same hits in f64: the nearer root of |p + t d|^2 = r^2
+    let reference = |sphere: &Sphere, ray: &Ray| {
+        let p = [(ray.orig.x - sphere.center.x) as f64, (ray.orig.y - sphere.center.y) as f64, (ray.orig.z - sphere.center.z) as f64];
+        let d = [ray.dir.x as f64, ray.dir.y as f64, ray.dir.z as f64];
+        let p_d = p[0] * d[0] + p[1] * d[1] + p[2] * d[2];
+        let d_d = d[0] * d[0] + d[1] * d[1] + d[2] * d[2];
+        let c = p[0] * p[0] + p[1] * p[1] + p[2] * p[2] - (sphere.radius as f64).powi(2);
+        (-p_d - (p_d * p_d - d_d * c).sqrt()) / d_d
+    };
+    let sphere = Sphere { center: Vec3f::new(3.0, -2.0, 1.0), radius: 2.0 };
+    // grazing the silhouette, starting just off the surface, and from far away
+    let rays = [
+        Ray { orig: Vec3f::new(3.0 + 1.9999, -2.0, -50.0), dir: Vec3f::new(0.0, 0.0, 1.0) },
+        Ray { orig: Vec3f::new(3.0, -2.0, 1.0 - 2.0001), dir: Vec3f::new(0.001, 0.0, 1.0).normalize() },
+        Ray { orig: Vec3f::new(-400.0, 300.0, 1.0), dir: Vec3f::new(403.0, -302.0, 0.0).normalize() },
+    ];
+    for ray in rays.iter() {
+        let isect = sphere.intersect(ray).unwrap();
+        let exact = reference(&sphere, ray);
+        assert!((isect.dist as f64 - exact).abs() < 1e-5 * exact, "{} instead of {}", isect.dist, exact);
+        assert!((isect.normal.norm() - 1.0).abs() < 1e-5);
+    }
+}
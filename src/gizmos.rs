@@ -0,0 +1,132 @@
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use light::LightShape;
+use math::{Vec2f, Vec3f};
+use math::vector_traits::*;
+use scene::Scene;
+
+// half of the cross drawn at a point light, in pixels
+const CROSS_SIZE: f32 = 6.0;
+
+// Lines drawn over the tone mapped frame to inspect a scene, so they stay visible whatever the exposure
+// and are never part of the render. Segments with an end behind the camera or out of the view aren't drawn
+pub struct Gizmos {
+    segments: Vec<(Vec2f, Vec2f, Vec3f)>, // raster positions and color
+}
+
+impl Gizmos {
+    // where the lights of the scene are: a cross at every point light and a wireframe over every area
+    // light, in the hue of the light
+    pub fn lights<S: Scene>(scene: &S, camera: &PerspectiveCamera) -> Gizmos {
+        let mut segments = Vec::new();
+        for light_id in 0..scene.get_lights_nb() {
+            let light = scene.get_light(light_id as i32);
+            // white for the lights which don't tell their color
+            let color = light.intensity().map_or(Vec3f::new(1.0, 1.0, 1.0), |intensity| {
+                let max_comp = intensity.fold(f32::max);
+                if max_comp > 0.0 { intensity / max_comp } else { Vec3f::new(1.0, 1.0, 1.0) }
+            });
+            match light.shape() {
+                Some(LightShape::Point(pos)) => if let Some(center) = camera.world_to_raster(&pos) {
+                    let (dx, dy) = (Vec2f::new(CROSS_SIZE, 0.0), Vec2f::new(0.0, CROSS_SIZE));
+                    segments.push((center - dx, center + dx, color));
+                    segments.push((center - dy, center + dy, color));
+                },
+                Some(LightShape::Wire(wire)) => {
+                    segments.extend(wire.iter().filter_map(|&(from, to)| project(camera, &from, &to, color)));
+                },
+                None => {}
+            }
+        }
+        Gizmos { segments: segments }
+    }
+
+    // the world axes from the origin, `length` long: x red, y green, z blue
+    pub fn world_axes(camera: &PerspectiveCamera, length: f32) -> Gizmos {
+        let origin = Vec3f::new(0.0, 0.0, 0.0);
+        let axes = [
+            (Vec3f::new(length, 0.0, 0.0), Vec3f::new(1.0, 0.0, 0.0)),
+            (Vec3f::new(0.0, length, 0.0), Vec3f::new(0.0, 1.0, 0.0)),
+            (Vec3f::new(0.0, 0.0, length), Vec3f::new(0.0, 0.0, 1.0)),
+        ];
+        let (steps, mut segments) = (16, Vec::new());
+        // in pieces, so an axis leaving the view is drawn up to the border
+        for &(end, color) in axes.iter() {
+            segments.extend((0..steps).filter_map(|step| {
+                let from = origin + end * (step as f32 / steps as f32);
+                let to = origin + end * ((step + 1) as f32 / steps as f32);
+                project(camera, &from, &to, color)
+            }));
+        }
+        Gizmos { segments: segments }
+    }
+
+    pub fn merge(mut self, other: Gizmos) -> Gizmos {
+        self.segments.extend(other.segments);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    // paints the segments over `frame`, pixels off the frame are skipped
+    pub fn draw(&self, frame: &mut RgbFrameBuffer) {
+        let res = frame.resolution();
+        for &(from, to, color) in &self.segments {
+            let steps = (to.x - from.x).abs().max((to.y - from.y).abs()).ceil().max(1.0) as usize;
+            for step in 0..steps + 1 {
+                let pos = from + (to - from) * (step as f32 / steps as f32);
+                if pos.x >= 0.0 && pos.y >= 0.0 && (pos.x as usize) < res.x && (pos.y as usize) < res.y {
+                    frame.set_color((pos.x as usize, pos.y as usize), color);
+                }
+            }
+        }
+    }
+}
+
+fn project(camera: &PerspectiveCamera, from: &Vec3f, to: &Vec3f, color: Vec3f) -> Option<(Vec2f, Vec2f, Vec3f)> {
+    match (camera.world_to_raster(from), camera.world_to_raster(to)) {
+        (Some(from), Some(to)) => Some((from, to, color)),
+        _                      => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Gizmos;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use math::{Vec2u, Vec3f};
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn lights_are_drawn_where_they_are() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_light(PointLight { position: Vec3f::new(-1.0, 0.0, 0.0), intensity: Vec3f::new(10.0, 5.0, 0.0) });
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 0.0, 0.0), radius: 0.5 }, Vec3f::new(1.0, 1.0, 1.0))
+            .unwrap();
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(64, 64))
+            .with_pos(Vec3f::new(0.0, 0.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let gizmos = Gizmos::lights(&scene, &cam);
+        assert!(!gizmos.is_empty());
+        let mut frame = cam.build_rgb_framebuffer();
+        gizmos.draw(&mut frame);
+
+        // the cross in the hue of the point light, the wire of the sphere on the other side
+        let point = cam.world_to_raster(&Vec3f::new(-1.0, 0.0, 0.0)).unwrap();
+        assert_eq!(frame.as_slice()[point.y as usize * 64 + point.x as usize], Vec3f::new(1.0, 0.5, 0.0));
+        let sphere = cam.world_to_raster(&Vec3f::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((sphere.x < 32.0) != (point.x < 32.0));
+        let (near, far) = frame.as_slice().iter().enumerate()
+            .filter(|&(_, pix)| *pix == Vec3f::new(1.0, 1.0, 1.0))
+            .fold((0, 0), |(near, far), (pix_nb, _)| {
+                if ((pix_nb % 64) < 32) == (sphere.x < 32.0) { (near + 1, far) } else { (near, far + 1) }
+            });
+        assert!(near > 20 && far == 0, "{} {}", near, far);
+    }
+}
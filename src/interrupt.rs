@@ -0,0 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// The first Ctrl+C only raises a flag, so the render loop can save its progress and stop;
+// the second one kills the process as usual
+pub fn install_sigint_handler() {
+    install();
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(unix)]
+fn install() {
+    extern "C" fn on_sigint(_: ::libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        unsafe { ::libc::signal(::libc::SIGINT, ::libc::SIG_DFL); }
+    }
+    unsafe { ::libc::signal(::libc::SIGINT, on_sigint as extern "C" fn(::libc::c_int) as ::libc::sighandler_t); }
+}
+
+#[cfg(not(unix))]
+fn install() {}
@@ -1,10 +1,13 @@
 #![allow(dead_code)]
-use math::Vec3f;
+use distribution::Distribution2D;
+use math::{Vec3f, Zero};
 use math::vector_traits::*;
-use geometry::{Frame, Geometry, Ray, Sphere};
+use geometry::{Aabb, Frame, Geometry, Ray, Sphere};
+use texture::Texture;
 use utility::*;
 use std::f32::consts::{FRAC_1_PI, PI};
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct BackgroundLight {
@@ -17,11 +20,41 @@ pub struct PointLight {
     pub position: Vec3f,
 }
 
-pub struct Illumination {
+// Lat-long environment map around the scene, +y is up and the top row of the map looks up.
+// Texels are constant radiance over their solid angle, so light sampling follows them exactly
+#[derive(Clone)]
+pub struct EnvironmentLight {
+    pub map: Arc<Texture>,
+    pub intensity: Vec3f, // scales the map
+    pub rotation: f32, // of the map around +y, in radians
+    sampling: Distribution2D, // over texels, row by row
+    mips: Arc<Vec<Texture>>, // halved again and again down to one texel, for `radiate_filtered`
+}
+
+// Disc of constant radiance around `direction`, which points toward the sun
+#[derive(Debug, Clone)]
+pub struct SunLight {
+    pub direction: Vec3f,
+    pub cos_radius: f32, // of the angular radius
     pub radiance: Vec3f,
+}
+
+// Sun and sky as one background light. Both parts have their own sampling, every light sample
+// picks one of them and is weighted by the pdfs of both (one-sample MIS with the balance heuristic).
+// The sun takes a few texels of an environment map at most, its own sampling covers the whole
+// disc, so it stops dominating the variance
+#[derive(Debug, Clone)]
+pub struct SunSkyLight<L: Light> {
+    pub sun: SunLight,
+    pub sky: L,
+    sun_prob: f32, // of picking the sun for a light sample
+}
+
+pub struct Illumination {
+    pub radiance: Vec3f, // incoming radiance, not divided by pdf
     pub l_dir: Vec3f,
     pub l_dist: f32,
-    pub pdf: f32,
+    pub pdf: f32, // solid angle pdf of l_dir, light selection isn't included
 }
 
 pub struct Radiation {
@@ -29,22 +62,89 @@ pub struct Radiation {
     pub pdf: f32,
 }
 
+// start of a light path
+pub struct Emission {
+    pub radiance: Vec3f, // along the ray, not divided by pdf or multiplied by cos_light
+    pub ray: Ray,
+    pub cos_light: f32, // between the ray and the light's normal, 1 for point lights
+    pub pdf: f32, // area pdf of the origin times solid angle pdf of the direction, light selection isn't included
+}
+
 #[derive(Debug)]
 pub struct LuminousObject<L: Luminous + Geometry + Debug> {
     pub object: L,
     pub intensity: Vec3f,
 }
 
+// where a light is, for the gizmos drawn over the frame to inspect the lighting (see `gizmos::Gizmos::lights`)
+#[derive(Debug, Clone)]
+pub enum LightShape {
+    Point(Vec3f),
+    Wire(Vec<(Vec3f, Vec3f)>), // segments over the surface of an area light
+}
+
 pub trait Light : Debug {
     // out_ray - "out" in physical meaning, in trace from eye to light it's "incoming"
     fn radiate(&self, out_ray: &Ray) -> Option<Radiation>; //< for brdf sampling
     fn illuminate(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> Option<Illumination>; //< for light sampling
+
+    // `radiate` for a ray standing for a cone of directions `spread` radians wide (see `RenderSettings::
+    // with_ray_differentials`); lights with detail finer than that may average it over the cone
+    fn radiate_filtered(&self, out_ray: &Ray, _spread: f32) -> Option<Radiation> {
+        self.radiate(out_ray)
+    }
+
+    // `illuminate` with the numbers of `sampler`
+    fn sample_illumination(&self, hit_pnt: &Vec3f, sampler: &mut Sampler) -> Option<Illumination> {
+        self.illuminate(hit_pnt, sampler.next_2d())
+    }
+
+    // for bidirectional methods, lights which can't start light paths don't emit
+    fn emit(&self, _rnd: (f32, f32, f32, f32)) -> Option<Emission> {
+        None
+    }
+    // pdf of `emit` giving `dir` from `pos` on the light and the cosine there
+    fn emission_pdf(&self, _pos: &Vec3f, _dir: &Vec3f) -> Option<(f32, f32)> {
+        None
+    }
+    // can't be hit by rays
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    // None and false for lights which have no single intensity
+    fn intensity(&self) -> Option<Vec3f> {
+        None
+    }
+    fn set_intensity(&mut self, _intensity: Vec3f) -> bool {
+        false
+    }
+
+    // lights at infinity have no place to draw
+    fn shape(&self) -> Option<LightShape> {
+        None
+    }
+
+    // Luminance of the flux of the light, for the light selection by power. `scene_radius` bounds what a
+    // light at infinity can shine on. None for the lights which can't tell, they get the mean of the others
+    fn power(&self, _scene_radius: f32) -> Option<f32> {
+        None
+    }
+
+    // where the light is, for the light tree; None for the lights at infinity
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
 }
 
 pub trait Luminous {
     // dir from hit_pnt, weight and pdf
     fn select_dir(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> (Vec3f, f32, f32);
     fn dir_pdf(&self, ray: &Ray) -> f32;
+    // uniformly distributed point on the surface and the normal there
+    fn sample_surface(&self, rnd: (f32, f32)) -> (Vec3f, Vec3f);
+    fn surface_normal(&self, pos: &Vec3f) -> Vec3f;
+    fn surface_area(&self) -> f32;
 }
 
 ///@FIXME something wrong with direct lighting (aka next event estimation)
@@ -65,6 +165,244 @@ impl Light for BackgroundLight {
             pdf: pdf
         })
     }
+
+    fn intensity(&self) -> Option<Vec3f> {
+        Some(self.intensity)
+    }
+
+    fn set_intensity(&mut self, intensity: Vec3f) -> bool {
+        self.intensity = intensity;
+        true
+    }
+
+    // what crosses the disc of the scene from all the directions
+    fn power(&self, scene_radius: f32) -> Option<f32> {
+        Some(PI * PI * scene_radius * scene_radius * luminance(&self.intensity))
+    }
+}
+
+impl EnvironmentLight {
+    pub fn new(map: Arc<Texture>, intensity: Vec3f) -> EnvironmentLight {
+        let sampling = EnvironmentLight::build_sampling(&map, false);
+        let mips = Arc::new(EnvironmentLight::build_mips(&map));
+        EnvironmentLight { map: map, intensity: intensity, rotation: 0.0, sampling: sampling, mips: mips }
+    }
+
+    // turns the map around +y by `angle` radians, the sampling turns with it
+    pub fn with_rotation(mut self, angle: f32) -> EnvironmentLight {
+        self.rotation = angle;
+        self
+    }
+
+    // MIS compensation (Karlik et al. 2019): light sampling skips the part of the map below its
+    // average, brdf sampling covers that part well enough, so light samples go where they help.
+    // Only for renderers which combine both strategies with MIS, alone it misses the dim parts
+    pub fn with_mis_compensation(mut self) -> EnvironmentLight {
+        self.sampling = EnvironmentLight::build_sampling(&self.map, true);
+        self
+    }
+
+    fn build_sampling(map: &Texture, compensated: bool) -> Distribution2D {
+        let (width, height) = (map.width(), map.height());
+        let sin_thetas = (0..height).map(|row| ((row as f32 + 0.5) / height as f32 * PI).sin()).collect::<Vec<_>>();
+        let mut func = (0..width * height)
+            .map(|idx| luminance(&map.texel(idx % width, idx / width)).max(0.0))
+            .collect::<Vec<_>>();
+        if compensated {
+            // the average is over the sphere, rows near the poles cover less of it
+            let (sum, weight) = func.iter().enumerate()
+                .fold((0.0, 0.0), |(sum, weight), (idx, f)| (sum + f * sin_thetas[idx / width], weight + sin_thetas[idx / width]));
+            let average = sum / weight;
+            for f in func.iter_mut() {
+                *f = (*f - average).max(0.0);
+            }
+        }
+        // a constant map compensates to nothing, which is sampled uniformly
+        for (idx, f) in func.iter_mut().enumerate() {
+            *f *= sin_thetas[idx / width];
+        }
+        Distribution2D::new(&func, width, height)
+    }
+
+    // the levels which don't fit into the memory budget are left out, the filtering stops short of them
+    fn build_mips(map: &Texture) -> Vec<Texture> {
+        let mut mips: Vec<Texture> = Vec::new();
+        loop {
+            let next = {
+                let last = mips.last().unwrap_or(map);
+                if last.width() == 1 && last.height() == 1 {
+                    break;
+                }
+                last.downsampled_latlong()
+            };
+            match next {
+                Ok(next) => mips.push(next),
+                Err(_)   => break
+            }
+        }
+        mips
+    }
+
+    // texel column and row the direction is in
+    fn texel_of(&self, dir: &Vec3f) -> (usize, usize) {
+        self.texel_in(dir, self.map.width(), self.map.height())
+    }
+
+    // the same in a map of another size, a level of the mip chain
+    fn texel_in(&self, dir: &Vec3f, width: usize, height: usize) -> (usize, usize) {
+        let u = ((dir.z.atan2(dir.x) - self.rotation) / (2.0 * PI)).fract();
+        let u = if u < 0.0 { u + 1.0 } else { u };
+        let v = dir.y.max(-1.0).min(1.0).acos() / PI;
+        (((u * width as f32) as usize).min(width - 1), ((v * height as f32) as usize).min(height - 1))
+    }
+
+    // solid angle pdf of the light sampling picking `dir`
+    fn pdf(&self, dir: &Vec3f, col: usize, row: usize) -> f32 {
+        let sin_theta = (1.0 - dir.y * dir.y).max(0.0).sqrt();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let cells = (self.map.width() * self.map.height()) as f32;
+        self.sampling.prob(col, row) * cells / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Debug for EnvironmentLight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EnvironmentLight {{ map: {}x{}, intensity: {:?}, rotation: {} }}", self.map.width(), self.map.height(),
+               self.intensity, self.rotation)
+    }
+}
+
+impl Light for EnvironmentLight {
+    fn radiate(&self, out_ray: &Ray) -> Option<Radiation> {
+        let (col, row) = self.texel_of(&out_ray.dir);
+        Some(Radiation {
+            radiance: self.map.texel(col, row) * self.intensity,
+            pdf: self.pdf(&out_ray.dir, col, row),
+        })
+    }
+
+    // The level of the mip chain with texels about as wide as the cone, blended with the finer one.
+    // The pdf stays the one of the direction, light sampling doesn't know of the filtering
+    fn radiate_filtered(&self, out_ray: &Ray, spread: f32) -> Option<Radiation> {
+        let (col, row) = self.texel_of(&out_ray.dir);
+        let texel_angle = PI / self.map.height() as f32;
+        let level = (spread / texel_angle).max(1.0).log2().min(self.mips.len() as f32);
+        let texel = |level: usize| if level == 0 {
+            self.map.texel(col, row)
+        } else {
+            let mip = &self.mips[level - 1];
+            let (col, row) = self.texel_in(&out_ray.dir, mip.width(), mip.height());
+            mip.texel(col, row)
+        };
+        let (fine, t) = (level.floor() as usize, level.fract());
+        let texel = if t > 0.0 { texel(fine) * (1.0 - t) + texel(fine + 1) * t } else { texel(fine) };
+        Some(Radiation {
+            radiance: texel * self.intensity,
+            pdf: self.pdf(&out_ray.dir, col, row),
+        })
+    }
+
+    fn illuminate(&self, _hit_pnt: &Vec3f, rnd: (f32, f32)) -> Option<Illumination> {
+        let sample = self.sampling.sample(rnd);
+        let (width, height) = (self.map.width() as f32, self.map.height() as f32);
+        let phi = (sample.col as f32 + sample.remapped.0) / width * 2.0 * PI + self.rotation;
+        let theta = (sample.row as f32 + sample.remapped.1) / height * PI;
+        let dir = Vec3f::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        let pdf = self.pdf(&dir, sample.col, sample.row);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(Illumination {
+            radiance: self.map.texel(sample.col, sample.row) * self.intensity,
+            l_dir: dir,
+            l_dist: 1e38,
+            pdf: pdf
+        })
+    }
+
+    fn intensity(&self) -> Option<Vec3f> {
+        Some(self.intensity)
+    }
+
+    fn set_intensity(&mut self, intensity: Vec3f) -> bool {
+        self.intensity = intensity;
+        true
+    }
+}
+
+impl SunLight {
+    // `angular_radius` is in radians, the real sun's is about 0.0047
+    pub fn new(direction: Vec3f, angular_radius: f32, radiance: Vec3f) -> SunLight {
+        SunLight { direction: direction.normalize(), cos_radius: angular_radius.cos(), radiance: radiance }
+    }
+
+    pub fn contains(&self, dir: &Vec3f) -> bool {
+        dir.dot(&self.direction) >= self.cos_radius
+    }
+
+    // uniform over the disc
+    pub fn sample(&self, rnd: (f32, f32)) -> Vec3f {
+        Frame::from_z(&self.direction).to_world(&uniform_cone_sample(self.cos_radius, rnd)).normalize()
+    }
+
+    pub fn pdf(&self, dir: &Vec3f) -> f32 {
+        if self.contains(dir) { FRAC_1_PI * 0.5 / (1.0 - self.cos_radius) } else { 0.0 }
+    }
+}
+
+impl<L> SunSkyLight<L> where L: Light {
+    pub fn new(sun: SunLight, sky: L) -> SunSkyLight<L> {
+        SunSkyLight { sun: sun, sky: sky, sun_prob: 0.5 }
+    }
+
+    pub fn with_sun_prob(mut self, sun_prob: f32) -> SunSkyLight<L> {
+        self.sun_prob = sun_prob.max(0.0).min(1.0);
+        self
+    }
+
+    fn radiation(&self, ray: &Ray, sky: Option<Radiation>) -> Radiation {
+        let sky = sky.unwrap_or(Radiation { radiance: Vec3f::zero(), pdf: 0.0 });
+        let sun = if self.sun.contains(&ray.dir) { self.sun.radiance } else { Vec3f::zero() };
+        Radiation {
+            radiance: sky.radiance + sun,
+            pdf: self.sun_prob * self.sun.pdf(&ray.dir) + (1.0 - self.sun_prob) * sky.pdf,
+        }
+    }
+}
+
+impl<L> Light for SunSkyLight<L> where L: Light {
+    fn radiate(&self, out_ray: &Ray) -> Option<Radiation> {
+        Some(self.radiation(out_ray, self.sky.radiate(out_ray)))
+    }
+
+    // the sun disc has its own sampling, only the sky is filtered
+    fn radiate_filtered(&self, out_ray: &Ray, spread: f32) -> Option<Radiation> {
+        Some(self.radiation(out_ray, self.sky.radiate_filtered(out_ray, spread)))
+    }
+
+    fn illuminate(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> Option<Illumination> {
+        let (ray, sky) = if rnd.0 < self.sun_prob {
+            let ray = Ray { orig: *hit_pnt, dir: self.sun.sample((rnd.0 / self.sun_prob, rnd.1)) };
+            (ray, self.sky.radiate(&ray))
+        } else {
+            let remapped = (rnd.0 - self.sun_prob) / (1.0 - self.sun_prob);
+            let illum = self.sky.illuminate(hit_pnt, (remapped.min(0.99999994), rnd.1))?;
+            let sky = Radiation { radiance: illum.radiance, pdf: illum.pdf };
+            (Ray { orig: *hit_pnt, dir: illum.l_dir }, Some(sky))
+        };
+        let rad = self.radiation(&ray, sky);
+        if rad.pdf <= 0.0 {
+            return None;
+        }
+        Some(Illumination {
+            radiance: rad.radiance,
+            l_dir: ray.dir,
+            l_dist: 1e38,
+            pdf: rad.pdf
+        })
+    }
 }
 
 impl Light for PointLight {
@@ -83,19 +421,65 @@ impl Light for PointLight {
             pdf: 1.0,
         })
     }
+
+    fn emit(&self, rnd: (f32, f32, f32, f32)) -> Option<Emission> {
+        Some(Emission {
+            radiance: self.intensity * FRAC_1_PI,
+            ray: Ray { orig: self.position, dir: uniform_sphere_sample((rnd.0, rnd.1)) },
+            cos_light: 1.0,
+            pdf: uniform_sphere_pdf_w(),
+        })
+    }
+
+    fn emission_pdf(&self, _pos: &Vec3f, _dir: &Vec3f) -> Option<(f32, f32)> {
+        Some((uniform_sphere_pdf_w(), 1.0))
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn intensity(&self) -> Option<Vec3f> {
+        Some(self.intensity)
+    }
+
+    fn set_intensity(&mut self, intensity: Vec3f) -> bool {
+        self.intensity = intensity;
+        true
+    }
+
+    fn shape(&self) -> Option<LightShape> {
+        Some(LightShape::Point(self.position))
+    }
+
+    // the intensity of `illuminate` over the whole sphere of directions
+    fn power(&self, _scene_radius: f32) -> Option<f32> {
+        Some(4.0 * PI * luminance(&self.intensity))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::around(self.position, Vec3f::zero()))
+    }
+}
+
+// cosine of the half angle of the cone a sphere of sin^2 of that angle `sin2_theta_max` fills, and 1 minus
+// it without the cancellation, which makes it zero for the spheres far away
+fn cone_of(sin2_theta_max: f32) -> (f32, f32) {
+    let sin2_theta_max = sin2_theta_max.min(1.0);
+    let cos_theta_max = (1.0 - sin2_theta_max).sqrt();
+    (cos_theta_max, sin2_theta_max / (1.0 + cos_theta_max))
 }
 
 impl Luminous for Sphere {
     fn select_dir(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> (Vec3f, f32, f32) { // dir, weight and pdf
         let w = self.center - *hit_pnt;
         let w2 = w.sqnorm();
-        let cos_theta_max = (1.0 - (self.r2() / w2).min(1.0)).sqrt();
-        let frac = 1.0 - cos_theta_max;
+        let (cos_theta_max, frac) = cone_of(self.r2() / w2);
         let omega = 2.0 * PI * frac;
         let w_basis = Frame::from_z(&w);
         let ld_local = uniform_cone_sample(cos_theta_max, rnd);
         let ld = w_basis.to_world(&ld_local).normalize();
-        let pdf = FRAC_1_PI * 0.5 / (1.0 - cos_theta_max);
+        let pdf = FRAC_1_PI * 0.5 / frac;
         (ld, omega, pdf)
     }
 
@@ -103,11 +487,24 @@ impl Luminous for Sphere {
         let w = self.center - ray.orig;
         let w2 = w.sqnorm();
         // let cos_theta = ray.dir.dot(&w).abs();
-        let cos_theta_max = (1.0 - (self.r2() / w2).min(1.0)).sqrt();
+        let (_, frac) = cone_of(self.r2() / w2);
         // let sin_theta_max2 = (self.r2() / w2).min(1.0).max(0.0);
-        (FRAC_1_PI * 0.5 / (1.0 - cos_theta_max)).max(0.0)
+        (FRAC_1_PI * 0.5 / frac).max(0.0)
         // cos_theta * FRAC_1_PI / sin_theta_max2
     }
+
+    fn sample_surface(&self, rnd: (f32, f32)) -> (Vec3f, Vec3f) {
+        let normal = uniform_sphere_sample(rnd);
+        (self.center + normal * self.radius, normal)
+    }
+
+    fn surface_normal(&self, pos: &Vec3f) -> Vec3f {
+        (*pos - self.center).normalize()
+    }
+
+    fn surface_area(&self) -> f32 {
+        4.0 * PI * self.r2()
+    }
 }
 
 impl<L> Light for LuminousObject<L> where L: Luminous + Geometry + Debug {
@@ -119,10 +516,10 @@ impl<L> Light for LuminousObject<L> where L: Luminous + Geometry + Debug {
     }
 
     fn illuminate(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> Option<Illumination> {
-        let (ld, omega, pdf) = self.object.select_dir(hit_pnt, rnd);
+        let (ld, _, pdf) = self.object.select_dir(hit_pnt, rnd);
         if let Some(isect) = self.object.intersect(&Ray { orig: *hit_pnt, dir: ld }) {
             Some(Illumination {
-                radiance: self.intensity * omega,
+                radiance: self.intensity,
                 l_dir: ld,
                 l_dist: isect.dist,
                 pdf: pdf
@@ -131,4 +528,166 @@ impl<L> Light for LuminousObject<L> where L: Luminous + Geometry + Debug {
             None
         }
     }
+    // cosine weighted directions from uniformly distributed points
+    fn emit(&self, rnd: (f32, f32, f32, f32)) -> Option<Emission> {
+        let (pos, normal) = self.object.sample_surface((rnd.0, rnd.1));
+        let dir_local = cos_hemisphere_sample((rnd.2, rnd.3));
+        if dir_local.z <= 0.0 {
+            return None;
+        }
+        Some(Emission {
+            radiance: self.intensity,
+            ray: Ray { orig: pos, dir: Frame::from_z(&normal).to_world(&dir_local) },
+            cos_light: dir_local.z,
+            pdf: dir_local.z * FRAC_1_PI / self.object.surface_area(),
+        })
+    }
+
+    fn emission_pdf(&self, pos: &Vec3f, dir: &Vec3f) -> Option<(f32, f32)> {
+        let cos_light = self.object.surface_normal(pos).dot(dir);
+        if cos_light <= 0.0 {
+            None
+        } else {
+            Some((cos_light * FRAC_1_PI / self.object.surface_area(), cos_light))
+        }
+    }
+    fn intensity(&self) -> Option<Vec3f> {
+        Some(self.intensity)
+    }
+
+    fn set_intensity(&mut self, intensity: Vec3f) -> bool {
+        self.intensity = intensity;
+        true
+    }
+
+    // lines of the surface sampling grid: the meridians and parallels of a sphere
+    fn shape(&self) -> Option<LightShape> {
+        let (lines, steps) = (8, 16);
+        let point = |u: f32, v: f32| self.object.sample_surface((u, v)).0;
+        let mut segments = Vec::with_capacity(2 * lines * steps);
+        for line in 0..lines {
+            let fixed = (line as f32 + 0.5) / lines as f32;
+            for step in 0..steps {
+                let (from, to) = (step as f32 / steps as f32, (step + 1) as f32 / steps as f32);
+                segments.push((point(fixed, from), point(fixed, to)));
+                segments.push((point(from, fixed), point(to, fixed)));
+            }
+        }
+        Some(LightShape::Wire(segments))
+    }
+
+    fn power(&self, _scene_radius: f32) -> Option<f32> {
+        Some(PI * self.object.surface_area() * luminance(&self.intensity))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.object.bounds()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackgroundLight, EnvironmentLight, Light, LuminousObject, PointLight, SunLight, SunSkyLight};
+    use geometry::{Ray, Sphere};
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use rand::Rng;
+    use std::f32::consts::PI;
+    use std::sync::Arc;
+    use texture::Texture;
+    use utility::seeded_rng;
+
+    // a bright sun on a dim sky
+    fn map() -> Arc<Texture> {
+        let mut texels = vec![Vec3f::new(0.5, 0.5, 0.5); 16 * 8];
+        texels[2 * 16 + 5] = Vec3f::new(100.0, 100.0, 100.0);
+        Arc::new(Texture::new(16, 8, texels).unwrap())
+    }
+
+    #[test]
+    fn lights_of_the_same_flux_have_the_same_power() {
+        // a sphere of radius r and radiance L is as intense as a point of L * pi * r^2 from every side
+        let point = PointLight { position: Vec3f::new(0.0, 0.0, 0.0), intensity: Vec3f::new(2.0, 2.0, 2.0) };
+        let sphere = LuminousObject {
+            object: Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 0.5 },
+            intensity: Vec3f::new(2.0, 2.0, 2.0) / (PI * 0.25),
+        };
+        let (point_power, sphere_power) = (point.power(1.0).unwrap(), sphere.power(1.0).unwrap());
+        assert!((point_power - sphere_power).abs() < 1e-4 * sphere_power, "{} instead of {}", point_power, sphere_power);
+    }
+
+    #[test]
+    fn sun_and_sky_irradiance() {
+        let sun = SunLight::new(Vec3f::new(0.0, 1.0, 0.0), 0.05, Vec3f::new(1000.0, 1000.0, 1000.0));
+        let light = SunSkyLight::new(sun, BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        let mut rng = seeded_rng(5, 0);
+        let samples = 100000;
+        let mut irradiance = 0.0;
+        for _ in 0..samples {
+            let illum = light.illuminate(&Vec3f::new(0.0, 0.0, 0.0), (rng.next_f32(), rng.next_f32())).unwrap();
+            let rad = light.radiate(&Ray { orig: Vec3f::new(0.0, 0.0, 0.0), dir: illum.l_dir }).unwrap();
+            assert!((rad.pdf - illum.pdf).abs() <= 1e-3 * illum.pdf, "{} instead of {}", rad.pdf, illum.pdf);
+            irradiance += illum.radiance.x * illum.l_dir.y.max(0.0) / illum.pdf;
+        }
+        // a disc of radius r straight up gives L * pi * sin(r)^2, the sky hemisphere L * pi
+        let expected = 1000.0 * PI * 0.05f32.sin().powi(2) + PI;
+        assert!((irradiance / samples as f32 - expected).abs() < 0.02 * expected, "{}", irradiance / samples as f32);
+    }
+
+    #[test]
+    fn environment_pdfs_agree() {
+        let mut rng = seeded_rng(3, 0);
+        for light in [EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0)),
+                      EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0)).with_mis_compensation(),
+                      EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0)).with_rotation(2.0)].iter() {
+            for _ in 0..100 {
+                let illum = light.illuminate(&Vec3f::new(0.0, 0.0, 0.0), (rng.next_f32(), rng.next_f32())).unwrap();
+                let rad = light.radiate(&Ray { orig: Vec3f::new(0.0, 0.0, 0.0), dir: illum.l_dir }).unwrap();
+                assert!((rad.pdf - illum.pdf).abs() <= 1e-3 * illum.pdf, "{} instead of {}", rad.pdf, illum.pdf);
+                assert_eq!(rad.radiance, illum.radiance);
+            }
+        }
+    }
+
+    #[test]
+    fn filtering_spreads_the_sun_over_the_cone() {
+        let light = EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0));
+        let (phi, theta) = (5.5 / 16.0 * 2.0 * PI, 2.5 / 8.0 * PI);
+        let ray = Ray { orig: Vec3f::new(0.0, 0.0, 0.0), dir: Vec3f::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()) };
+        let sharp = light.radiate(&ray).unwrap();
+        // a cone narrower than a texel sees the map as it is
+        let narrow = light.radiate_filtered(&ray, 0.01).unwrap();
+        assert_eq!((narrow.radiance, narrow.pdf), (sharp.radiance, sharp.pdf));
+        // a wider one the sun averaged with the sky around it, the whole sphere the average of the map
+        let wide = light.radiate_filtered(&ray, 4.0 * PI / 8.0).unwrap();
+        assert!(wide.radiance.x > 0.5 && wide.radiance.x < 0.25 * sharp.radiance.x, "{:?}", wide.radiance);
+        assert_eq!(wide.pdf, sharp.pdf);
+        // of the solid angle: the sun texel covers cos(2/8 pi) - cos(3/8 pi) of the 2 of its column
+        let sun_share = ((2.0 * PI / 8.0).cos() - (3.0 * PI / 8.0).cos()) / (2.0 * 16.0);
+        let average = 0.5 + 99.5 * sun_share;
+        assert!((light.radiate_filtered(&ray, 2.0 * PI).unwrap().radiance.x - average).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rotation_turns_the_map() {
+        // the center of the sun texel, and the same direction turned by a quarter around +y
+        let (phi, theta) = (5.5 / 16.0 * 2.0 * PI, 2.5 / 8.0 * PI);
+        let dir = |phi: f32| Vec3f::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        let ray = |phi: f32| Ray { orig: Vec3f::new(0.0, 0.0, 0.0), dir: dir(phi) };
+        let plain = EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0));
+        let rotated = plain.clone().with_rotation(0.5 * PI);
+        assert_eq!(plain.radiate(&ray(phi)).unwrap().radiance.x, 100.0);
+        assert_eq!(rotated.radiate(&ray(phi)).unwrap().radiance.x, 0.5);
+        assert_eq!(rotated.radiate(&ray(phi + 0.5 * PI)).unwrap().radiance.x, 100.0);
+        assert_eq!(rotated.radiate(&ray(phi + 0.5 * PI)).unwrap().pdf, plain.radiate(&ray(phi)).unwrap().pdf);
+    }
+
+    #[test]
+    fn compensation_skips_the_dim_sky() {
+        let sky = Ray { orig: Vec3f::new(0.0, 0.0, 0.0), dir: Vec3f::new(0.0, -1.0, 1.0).normalize() };
+        let plain = EnvironmentLight::new(map(), Vec3f::new(1.0, 1.0, 1.0));
+        let compensated = plain.clone().with_mis_compensation();
+        assert!(plain.radiate(&sky).unwrap().pdf > 0.0);
+        assert_eq!(compensated.radiate(&sky).unwrap().pdf, 0.0);
+    }
 }
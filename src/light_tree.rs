@@ -0,0 +1,219 @@
+use geometry::Aabb;
+use math::Vec3f;
+use math::vector_traits::*;
+use scene::LightID;
+
+// Binary tree over the lights with a place, the light selection of a shading point walks it down in
+// O(log n): at every node it takes the child of the larger importance (the power of the cluster over the
+// squared distance to it) more often. The lights at infinity are outside of the tree, they and the tree
+// are picked by their power, as are the lights at infinity among themselves
+#[derive(Debug, Clone)]
+pub struct LightTree {
+    nodes: Vec<LightNode>, // the left child of a node follows it
+    trails: Vec<Option<(u64, u32)>>, // per light the branches from the root (bit d is the right one at depth d) and the depth
+    infinite: Vec<(LightID, f32)>, // and the power
+    infinite_power: f32,
+}
+
+#[derive(Debug, Clone)]
+struct LightNode {
+    bounds: Aabb,
+    power: f32,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NodeKind {
+    Leaf(LightID),
+    Inner { right: usize },
+}
+
+// never a branch of probability 1 to the precision of a float, the numbers remapped at the deep nodes keep their bits
+const MAX_RND: f32 = 0.99999994;
+
+impl LightTree {
+    // `lights` are the bounds (None for the lights at infinity) and the power of every light id
+    pub fn new(lights: &[(Option<Aabb>, f32)]) -> LightTree {
+        let mut placed = lights.iter().enumerate()
+            .filter_map(|(light_id, &(bounds, power))| bounds.map(|bounds| (light_id as LightID, bounds, power.max(0.0))))
+            .collect::<Vec<_>>();
+        let infinite = lights.iter().enumerate()
+            .filter(|&(_, &(bounds, _))| bounds.is_none())
+            .map(|(light_id, &(_, power))| (light_id as LightID, power.max(0.0)))
+            .collect::<Vec<_>>();
+        let infinite_power = infinite.iter().fold(0.0, |sum, &(_, power)| sum + power);
+        let mut tree = LightTree {
+            nodes: Vec::new(),
+            trails: vec![None; lights.len()],
+            infinite: infinite,
+            infinite_power: infinite_power,
+        };
+        if !placed.is_empty() {
+            tree.build(&mut placed, 0, 0);
+        }
+        tree
+    }
+
+    // the median split along the longest axis of the centers keeps the depth at log2 of the lights
+    fn build(&mut self, lights: &mut [(LightID, Aabb, f32)], trail: u64, depth: u32) -> usize {
+        let bounds = lights.iter().fold(Aabb::empty(), |all, &(_, bounds, _)| all.union(&bounds));
+        let power = lights.iter().fold(0.0, |sum, &(_, _, power)| sum + power);
+        let node_nb = self.nodes.len();
+        if lights.len() == 1 {
+            self.nodes.push(LightNode { bounds: bounds, power: power, kind: NodeKind::Leaf(lights[0].0) });
+            self.trails[lights[0].0 as usize] = Some((trail, depth));
+            return node_nb;
+        }
+        self.nodes.push(LightNode { bounds: bounds, power: power, kind: NodeKind::Inner { right: 0 } });
+        let centers = lights.iter().fold(Aabb::empty(), |all, &(_, bounds, _)| all.grow(&bounds.center()));
+        let extent = centers.diagonal();
+        let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+        let coord = |bounds: &Aabb| match axis { 0 => bounds.center().x, 1 => bounds.center().y, _ => bounds.center().z };
+        lights.sort_by(|a, b| coord(&a.1).partial_cmp(&coord(&b.1)).unwrap_or(::std::cmp::Ordering::Equal));
+        let mid = lights.len() / 2;
+        let (left, right) = lights.split_at_mut(mid);
+        self.build(left, trail, depth + 1);
+        let right = self.build(right, trail | 1 << depth, depth + 1);
+        self.nodes[node_nb].kind = NodeKind::Inner { right: right };
+        node_nb
+    }
+
+    // how much of the cluster may reach `p`, not less than from its bounding sphere
+    fn importance(&self, node_nb: usize, p: &Vec3f) -> f32 {
+        let node = &self.nodes[node_nb];
+        let (center, radius) = node.bounds.bounding_sphere();
+        node.power / (center - *p).sqnorm().max(radius * radius).max(1e-8)
+    }
+
+    // probability of the tree against the lights at infinity, by the power; an even split
+    // when all of it is dark
+    fn tree_prob(&self) -> f32 {
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+        let tree_power = self.nodes[0].power;
+        if tree_power + self.infinite_power > 0.0 {
+            tree_power / (tree_power + self.infinite_power)
+        } else {
+            1.0 / (self.infinite.len() + 1) as f32
+        }
+    }
+
+    // share of the light at infinity `idx` in the probability of all of them
+    fn infinite_share(&self, idx: usize) -> f32 {
+        if self.infinite_power > 0.0 {
+            self.infinite[idx].1 / self.infinite_power
+        } else {
+            1.0 / self.infinite.len() as f32
+        }
+    }
+
+    // the light for the shading point `p` and `rnd` in [0, 1), and the probability to pick it; None
+    // where no light has any importance
+    pub fn pick(&self, p: &Vec3f, rnd: f32) -> Option<(LightID, f32)> {
+        if self.nodes.is_empty() && self.infinite.is_empty() {
+            return None;
+        }
+        let tree_prob = self.tree_prob();
+        let infinite_prob = 1.0 - tree_prob;
+        let mut rnd = rnd.max(0.0).min(MAX_RND);
+        if rnd < infinite_prob {
+            let mut rnd = rnd / infinite_prob;
+            let last = self.infinite.len() - 1;
+            for idx in 0..last + 1 {
+                let share = self.infinite_share(idx);
+                if rnd < share || idx == last {
+                    return if share > 0.0 { Some((self.infinite[idx].0, infinite_prob * share)) } else { None };
+                }
+                rnd -= share;
+            }
+        }
+        rnd = ((rnd - infinite_prob) / tree_prob).min(MAX_RND);
+        let (mut node_nb, mut prob) = (0, tree_prob);
+        loop {
+            match self.nodes[node_nb].kind {
+                NodeKind::Leaf(light_id) => return if prob > 0.0 { Some((light_id, prob)) } else { None },
+                NodeKind::Inner { right } => {
+                    let (left_imp, right_imp) = (self.importance(node_nb + 1, p), self.importance(right, p));
+                    if !(left_imp + right_imp > 0.0) {
+                        return None;
+                    }
+                    let left_prob = left_imp / (left_imp + right_imp);
+                    if rnd < left_prob {
+                        rnd = (rnd / left_prob).min(MAX_RND);
+                        prob *= left_prob;
+                        node_nb += 1;
+                    } else {
+                        rnd = ((rnd - left_prob) / (1.0 - left_prob)).min(MAX_RND);
+                        prob *= 1.0 - left_prob;
+                        node_nb = right;
+                    }
+                }
+            }
+        }
+    }
+
+    // probability of `pick` giving `light_id` at `p`
+    pub fn prob(&self, p: &Vec3f, light_id: LightID) -> f32 {
+        let (trail, depth) = match self.trails[light_id as usize] {
+            Some(trail) => trail,
+            None        => {
+                let idx = self.infinite.iter().position(|&(id, _)| id == light_id);
+                return idx.map_or(0.0, |idx| (1.0 - self.tree_prob()) * self.infinite_share(idx));
+            }
+        };
+        let (mut node_nb, mut prob) = (0, self.tree_prob());
+        for level in 0..depth {
+            let right = match self.nodes[node_nb].kind {
+                NodeKind::Inner { right } => right,
+                NodeKind::Leaf(_)         => break
+            };
+            let (left_imp, right_imp) = (self.importance(node_nb + 1, p), self.importance(right, p));
+            if !(left_imp + right_imp > 0.0) {
+                return 0.0;
+            }
+            if trail & 1 << level == 0 {
+                prob *= left_imp / (left_imp + right_imp);
+                node_nb += 1;
+            } else {
+                prob *= right_imp / (left_imp + right_imp);
+                node_nb = right;
+            }
+        }
+        prob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LightTree;
+    use geometry::Aabb;
+    use math::Vec3f;
+
+    fn point(x: f32) -> Option<Aabb> {
+        Some(Aabb::around(Vec3f::new(x, 0.0, 0.0), Vec3f::new(0.0, 0.0, 0.0)))
+    }
+
+    #[test]
+    fn near_lights_are_picked_more_often() {
+        let lights = (0..64).map(|i| (point(i as f32), 1.0)).chain(Some((None, 5.0))).collect::<Vec<_>>();
+        let tree = LightTree::new(&lights);
+        let p = Vec3f::new(10.2, 1.0, 0.0);
+        // the probabilities of all the lights sum to one and are the ones `pick` gives
+        let sum = (0..65).fold(0.0, |sum, light_id| sum + tree.prob(&p, light_id));
+        assert!((sum - 1.0).abs() < 1e-4, "{}", sum);
+        let n = 4096;
+        let mut counts = vec![0; 65];
+        for i in 0..n {
+            let (light_id, prob) = tree.pick(&p, (i as f32 + 0.5) / n as f32).unwrap();
+            assert!((prob - tree.prob(&p, light_id)).abs() < 1e-5);
+            counts[light_id as usize] += 1;
+        }
+        // the light at infinity has 5 of the power of 69
+        assert!((counts[64] as f32 - n as f32 * 5.0 / 69.0).abs() <= 1.0, "{}", counts[64]);
+        assert!(counts[10] > 10 * counts[40], "{:?}", counts);
+        // no importance anywhere, no light
+        let dark = LightTree::new(&[(point(0.0), 0.0), (point(1.0), 0.0)]);
+        assert!(dark.pick(&p, 0.5).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use brdf::{InspectionMaterial, Material};
+use camera::{CameraBuilder, PerspectiveCamera};
+use geometry::{GeometryList, Plane, Sphere};
+use gizmos::Gizmos;
+use light::{BackgroundLight, EnvironmentLight};
+use materials_and_colors::{MIDDLE_GRAY_DIFFUSE, MIRROR, WHITE_DIFFUSE};
+use math::{Vec2u, Vec3f};
+use memory::OutOfBudget;
+use scene::{DefaultScene, MaterialID, Scene};
+use std::sync::Arc;
+use texture::Texture;
+
+// Helpers for quick material tests, so a scene needs no set built around the objects: an infinite
+// checkered ground plane, lit and shadowed as any other object, and the world axes drawn over the
+// frame. Both are off until asked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LookDevHelpers {
+    pub ground_height: Option<f32>, // y of the ground plane
+    pub checker_size: f32, // side of a checker cell, in world units
+    pub axes_length: Option<f32>,
+}
+
+impl LookDevHelpers {
+    pub fn new() -> LookDevHelpers {
+        LookDevHelpers {
+            ground_height: None,
+            checker_size: 1.0,
+            axes_length: None,
+        }
+    }
+
+    pub fn with_ground(mut self, height: f32) -> LookDevHelpers {
+        self.ground_height = Some(height);
+        self
+    }
+
+    pub fn with_checker_size(mut self, checker_size: f32) -> LookDevHelpers {
+        self.checker_size = checker_size;
+        self
+    }
+
+    pub fn with_axes(mut self, length: f32) -> LookDevHelpers {
+        self.axes_length = Some(length);
+        self
+    }
+
+    // adds the ground plane to the scene, if there's one; the checker is an inspection material,
+    // so it shows whatever the textures of the scene are
+    pub fn add_to_scene<S: Scene>(&self, scene: &mut S) -> Result<Option<MaterialID>, OutOfBudget> {
+        let height = match self.ground_height {
+            Some(height) => height,
+            None         => return Ok(None)
+        };
+        let plane = Plane { point: Vec3f::new(0.0, height, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) };
+        let m_id = scene.add_object(plane, WHITE_DIFFUSE)?;
+        scene.set_inspection_material(m_id, Some(InspectionMaterial::UvChecker { scale: 1.0 / self.checker_size }));
+        Ok(Some(m_id))
+    }
+
+    pub fn gizmos(&self, camera: &PerspectiveCamera) -> Option<Gizmos> {
+        self.axes_length.map(|length| Gizmos::world_axes(camera, length))
+    }
+}
+
+// The material ball scene, the same conditions for every material under test: a ball of the material on
+// the checkered ground, a middle gray and a chrome ball beside it showing what the light is like, under an
+// HDRI turned by `hdri_rotation` degrees around +y and scaled by 2^`exposure`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShaderBall {
+    pub hdri_rotation: f32,
+    pub exposure: f32, // in stops
+}
+
+impl ShaderBall {
+    pub fn new() -> ShaderBall {
+        ShaderBall { hdri_rotation: 0.0, exposure: 0.0 }
+    }
+
+    pub fn with_hdri_rotation(mut self, degrees: f32) -> ShaderBall {
+        self.hdri_rotation = degrees;
+        self
+    }
+
+    pub fn with_exposure(mut self, stops: f32) -> ShaderBall {
+        self.exposure = stops;
+        self
+    }
+
+    // the scene with a ball of `material`, lit by `hdri` (a lat-long map) or by a white sky without one;
+    // the material is the first of the scene
+    pub fn build_scene(&self, material: Material, hdri: Option<Arc<Texture>>)
+        -> Result<DefaultScene<GeometryList>, OutOfBudget> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        let map = hdri.unwrap_or_else(|| Arc::new(Texture::new_constant(Vec3f::new(1.0, 1.0, 1.0))));
+        let intensity = 2.0f32.powf(self.exposure);
+        scene.set_background_light(EnvironmentLight::new(map, Vec3f::new(intensity, intensity, intensity))
+            .with_rotation(self.hdri_rotation.to_radians()));
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 1.0, 0.0), radius: 1.0 }, material)?;
+        scene.add_object(Sphere { center: Vec3f::new(-1.8, 0.35, 0.4), radius: 0.35 }, MIDDLE_GRAY_DIFFUSE)?;
+        scene.add_object(Sphere { center: Vec3f::new(1.8, 0.35, 0.4), radius: 0.35 }, MIRROR)?;
+        LookDevHelpers::new().with_ground(0.0).with_checker_size(0.25).add_to_scene(&mut scene)?;
+        Ok(scene)
+    }
+
+    // framing the three balls
+    pub fn camera(&self, res: Vec2u) -> PerspectiveCamera {
+        CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(0.0, 1.6, -6.0))
+            .with_look_at(Vec3f::new(0.0, -0.1, 1.0))
+            .with_up(Vec3f::new(0.0, 1.0, 0.0))
+            .with_fov(40.0)
+            .with_znear(0.1)
+            .with_zfar(1000.0)
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LookDevHelpers, ShaderBall};
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Ray};
+    use light::BackgroundLight;
+    use materials_and_colors::GOLDEN_SPEC;
+    use math::vector_traits::*;
+    use math::{Vec2u, Vec3f};
+    use scene::{DefaultScene, Scene, SurfaceProperties};
+    use std::sync::Arc;
+    use texture::Texture;
+
+    #[test]
+    fn ground_is_a_checker_under_the_scene() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        assert_eq!(LookDevHelpers::new().add_to_scene(&mut scene).unwrap(), None);
+        let helpers = LookDevHelpers::new().with_ground(-1.0).with_checker_size(0.5).with_axes(2.0);
+        let ground = helpers.add_to_scene(&mut scene).unwrap().unwrap();
+        scene.commit_changes();
+
+        let albedo_at = |x: f32, z: f32| {
+            let ray = Ray { orig: Vec3f::new(x, 3.0, z), dir: Vec3f::new(0.0, -1.0, 0.0) };
+            let isect = scene.nearest_intersection(&ray).unwrap();
+            assert_eq!(isect.surface, SurfaceProperties::Material(ground));
+            assert!((isect.dist - 4.0).abs() < 1e-4);
+            scene.get_surface_material(ground, &isect).diffuse.x
+        };
+        // neighbouring cells differ, cells two apart are the same
+        let (a, b, c) = (albedo_at(0.25, 0.25), albedo_at(0.75, 0.25), albedo_at(1.25, 0.25));
+        assert!(a != b && a == c, "{} {} {}", a, b, c);
+        assert!(scene.nearest_intersection(&Ray { orig: Vec3f::new(0.0, 3.0, 0.0), dir: Vec3f::new(0.0, 1.0, 0.0) }).is_none());
+
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(32, 32))
+            .with_pos(Vec3f::new(1.0, 1.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let mut frame = cam.build_rgb_framebuffer();
+        helpers.gizmos(&cam).unwrap().draw(&mut frame);
+        for color in [Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, 0.0, 1.0)].iter() {
+            assert!(frame.as_slice().iter().any(|pix| pix == color));
+        }
+    }
+
+    #[test]
+    fn shader_ball_turns_and_scales_the_hdri() {
+        // a map bright only toward +x (its first column), turned a half turn it's toward -x
+        let mut texels = vec![Vec3f::new(0.1, 0.1, 0.1); 8 * 4];
+        texels[8 + 0] = Vec3f::new(10.0, 10.0, 10.0);
+        let hdri = Arc::new(Texture::new(8, 4, texels).unwrap());
+        let toward = |ball: ShaderBall, dir: Vec3f| {
+            let scene = ball.build_scene(GOLDEN_SPEC, Some(hdri.clone())).unwrap();
+            assert_eq!(scene.get_material(0).specular, GOLDEN_SPEC.specular);
+            let ray = Ray { orig: Vec3f::new(0.0, 50.0, 0.0), dir: dir };
+            assert!(scene.nearest_intersection(&ray).is_none());
+            scene.get_background_light().radiate(&ray).unwrap().radiance.x
+        };
+        let dir = |x: f32| Vec3f::new(x, 0.8, 0.1 * x).normalize();
+        assert_eq!(toward(ShaderBall::new(), dir(1.0)), 10.0);
+        assert_eq!(toward(ShaderBall::new().with_hdri_rotation(180.0), dir(1.0)), 0.1);
+        assert_eq!(toward(ShaderBall::new().with_hdri_rotation(180.0).with_exposure(1.0), dir(-1.0)), 20.0);
+
+        // the material ball is in the middle of the frame
+        let cam = ShaderBall::new().camera(Vec2u::new(64, 48));
+        let center = cam.world_to_raster(&Vec3f::new(0.0, 1.0, 0.0)).unwrap();
+        assert!((center.x - 32.0).abs() < 1.0 && center.y > 8.0 && center.y < 40.0, "{:?}", center);
+    }
+}
@@ -3,37 +3,81 @@ extern crate nalgebra;
 extern crate num;
 extern crate rand;
 extern crate rayon;
+extern crate libc;
 
 pub mod brdf;
+pub mod buckets;
 pub mod camera;
+pub mod checkpoint;
+pub mod config;
+pub mod dataset;
+pub mod dirt;
+pub mod flare;
+pub mod distribution;
 pub mod framebuffer;
 pub mod geometry;
+pub mod gizmos;
+pub mod interrupt;
 pub mod light;
+pub mod light_tree;
+pub mod look_dev;
 pub mod math;
+pub mod memory;
+pub mod numa;
+pub mod preview;
 pub mod render;
 pub mod scene;
+pub mod scene_diff;
+pub mod sensor;
+#[cfg(feature = "stress-scenes")]
+pub mod stress;
+pub mod telemetry;
+pub mod texture;
+pub mod throttle;
 pub mod utility;
 pub mod materials_and_colors;
+pub mod overrides;
+pub mod panorama;
+pub mod portal;
+pub mod report;
 
 use sfml::graphics::{RenderWindow, Color, RenderTarget, Texture, Sprite};
 use sfml::window::{VideoMode, ContextSettings, event, window_style};
 
+use brdf::AlbedoAudit;
 use camera::{Camera, PerspectiveCamera, CameraBuilder};
 use geometry::{GeometryList, Sphere, Torus, Triangle, DFieldsSubstr, DFieldsBlend, RoundBox};
 use math::{Vec3f, Vec2u, Zero};
 use render::Render;
 use light::{PointLight, BackgroundLight};
 #[allow(unused_imports)]
-use render::{EyeLight, CpuPt, CpuPtMis, CpuPtDl};
-use scene::Scene;
+use render::{EyeLight, CpuPt, CpuPtMis, CpuPtDl, CpuRc, CpuMtRender, ImportanceMask, RenderSettings, SamplerKind};
+use scene::{LightSelection, MaterialID, Scene};
+use scene_diff::{SceneDescription, SceneDiff};
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use materials_and_colors::*;
-use framebuffer::log_tone_mapping;
+use memory::OutOfBudget;
+use preview::{PreviewEncoder, PreviewServer};
+use framebuffer::{AdaptiveFrameBuffer, HalfFrameBuffer, RgbFrameBuffer, TiledFrameBuffer, log_tone_mapping};
+use gizmos::Gizmos;
+use look_dev::{LookDevHelpers, ShaderBall};
+use telemetry::{duration_to_secs, Telemetry};
+use numa::ThreadPinning;
+use throttle::ThreadScaling;
+use checkpoint::Checkpoint;
+use report::RenderReport;
+use config::{Config, ToneMap};
+use interrupt::{install_sigint_handler, interrupted};
 
 fn f32_to_u8(f: f32) -> u8 {
     (f * 255.0) as u8
 }
 
+// samples every pixel gets before adaptive sampling may call it converged
+const ADAPTIVE_MIN_SAMPLES: u32 = 16;
+
 const CB: [Vec3f; 8] = [
     Vec3f { x: -1.0, y:  1.0, z: -1.0 }, // 0
     Vec3f { x:  1.0, y:  1.0, z: -1.0 }, // 1
@@ -45,30 +89,31 @@ const CB: [Vec3f; 8] = [
     Vec3f { x: -1.0, y: -1.0, z:  1.0 }  // 7
 ];
 
-fn add_cornell_box<S>(scene: &mut S, scale: f32) where S: Scene {
+fn add_cornell_box<S>(scene: &mut S, scale: f32) -> Result<(), OutOfBudget> where S: Scene {
     // floor
-    scene.add_object(Triangle::new(CB[5] * scale, CB[4] * scale, CB[7] * scale), WHITE_DIFFUSE);
-    scene.add_object(Triangle::new(CB[7] * scale, CB[6] * scale, CB[5] * scale), WHITE_DIFFUSE);
+    scene.add_object(Triangle::new(CB[5] * scale, CB[4] * scale, CB[7] * scale), WHITE_DIFFUSE)?;
+    scene.add_object(Triangle::new(CB[7] * scale, CB[6] * scale, CB[5] * scale), WHITE_DIFFUSE)?;
 
     // ceiling
-    scene.add_object(Triangle::new(CB[2] * scale, CB[3] * scale, CB[0] * scale), WHITE_DIFFUSE);
-    scene.add_object(Triangle::new(CB[0] * scale, CB[1] * scale, CB[2] * scale), WHITE_DIFFUSE);
+    scene.add_object(Triangle::new(CB[2] * scale, CB[3] * scale, CB[0] * scale), WHITE_DIFFUSE)?;
+    scene.add_object(Triangle::new(CB[0] * scale, CB[1] * scale, CB[2] * scale), WHITE_DIFFUSE)?;
 
     // back wall
-    scene.add_object(Triangle::new(CB[2] * scale, CB[6] * scale, CB[7] * scale), WHITE_DIFFUSE);
-    scene.add_object(Triangle::new(CB[7] * scale, CB[3] * scale, CB[2] * scale), WHITE_DIFFUSE);
+    scene.add_object(Triangle::new(CB[2] * scale, CB[6] * scale, CB[7] * scale), WHITE_DIFFUSE)?;
+    scene.add_object(Triangle::new(CB[7] * scale, CB[3] * scale, CB[2] * scale), WHITE_DIFFUSE)?;
 
     // left wall
-    scene.add_object(Triangle::new(CB[3] * scale, CB[7] * scale, CB[4] * scale), RED_DIFFUSE);
-    scene.add_object(Triangle::new(CB[4] * scale, CB[0] * scale, CB[3] * scale), RED_DIFFUSE);
+    scene.add_object(Triangle::new(CB[3] * scale, CB[7] * scale, CB[4] * scale), RED_DIFFUSE)?;
+    scene.add_object(Triangle::new(CB[4] * scale, CB[0] * scale, CB[3] * scale), RED_DIFFUSE)?;
 
     // right wall
-    scene.add_object(Triangle::new(CB[1] * scale, CB[5] * scale, CB[6] * scale), GREEN_DIFFUSE);
-    scene.add_object(Triangle::new(CB[6] * scale, CB[2] * scale, CB[1] * scale), GREEN_DIFFUSE);
+    scene.add_object(Triangle::new(CB[1] * scale, CB[5] * scale, CB[6] * scale), GREEN_DIFFUSE)?;
+    scene.add_object(Triangle::new(CB[6] * scale, CB[2] * scale, CB[1] * scale), GREEN_DIFFUSE)?;
+    Ok(())
 }
 
 #[allow(dead_code)]
-fn setup_mis_showcase() -> scene::DefaultScene<GeometryList> {
+fn setup_mis_showcase() -> Result<scene::DefaultScene<GeometryList>, OutOfBudget> {
     let mut scene = scene::DefaultScene::<GeometryList>::new(
         BackgroundLight { intensity: DAYLIGHT_COLOR * 0.25 }
     );
@@ -76,19 +121,19 @@ fn setup_mis_showcase() -> scene::DefaultScene<GeometryList> {
     scene.add_luminous_object(
         Sphere { center: Vec3f::new(0.0, 25.0, 0.0), radius: 5.0 },
         DAYLIGHT_COLOR * 40.0
-    );
+    )?;
 
-    add_cornell_box(&mut scene, 25.0);
+    add_cornell_box(&mut scene, 25.0)?;
 
-    scene.add_object(Sphere { center: Vec3f::new(-16.0, -18.0, 2.0), radius: 7.0 }, MIRROR);
-    scene.add_object(Sphere { center: Vec3f::new(0.0, -18.0, 0.0), radius: 7.0 }, WHITE_CERAMICS);
-    scene.add_object(Sphere { center: Vec3f::new(16.0, -18.0, 0.0), radius: 7.0 }, WHITE_DIFFUSE);
+    scene.add_object(Sphere { center: Vec3f::new(-16.0, -18.0, 2.0), radius: 7.0 }, MIRROR)?;
+    scene.add_object(Sphere { center: Vec3f::new(0.0, -18.0, 0.0), radius: 7.0 }, WHITE_CERAMICS)?;
+    scene.add_object(Sphere { center: Vec3f::new(16.0, -18.0, 0.0), radius: 7.0 }, WHITE_DIFFUSE)?;
 
-    scene
+    Ok(scene)
 }
 
 #[allow(dead_code)]
-fn setup_pointlight_showcase() -> scene::DefaultScene<GeometryList> {
+fn setup_pointlight_showcase() -> Result<scene::DefaultScene<GeometryList>, OutOfBudget> {
     let mut scene = scene::DefaultScene::<GeometryList>::new(
         BackgroundLight { intensity: DAYLIGHT_COLOR * 0.0 }
     );
@@ -116,19 +161,19 @@ fn setup_pointlight_showcase() -> scene::DefaultScene<GeometryList> {
     scene.add_luminous_object(
         Sphere { center: Vec3f::new(10.0, -3.0, 5.0), radius: 5.0 },
         GOLDEN_COLOR * 7.0
-    );
+    )?;
 
-    add_cornell_box(&mut scene, 25.0);
+    add_cornell_box(&mut scene, 25.0)?;
 
-    scene.add_object(Sphere { center: Vec3f::new(-16.0, -18.0, 2.0), radius: 7.0 }, MIRROR);
-    scene.add_object(Sphere { center: Vec3f::new(0.0, -18.0, 7.0), radius: 7.0 }, GOLDEN_SPEC);
-    scene.add_object(Sphere { center: Vec3f::new(16.0, -18.0, 0.0), radius: 7.0 }, SKY_BLUE_DIFFUSE);
+    scene.add_object(Sphere { center: Vec3f::new(-16.0, -18.0, 2.0), radius: 7.0 }, MIRROR)?;
+    scene.add_object(Sphere { center: Vec3f::new(0.0, -18.0, 7.0), radius: 7.0 }, GOLDEN_SPEC)?;
+    scene.add_object(Sphere { center: Vec3f::new(16.0, -18.0, 0.0), radius: 7.0 }, SKY_BLUE_DIFFUSE)?;
 
-    scene
+    Ok(scene)
 }
 
 #[allow(dead_code)]
-fn setup_df_showcase() -> scene::DefaultScene<GeometryList> {
+fn setup_df_showcase() -> Result<scene::DefaultScene<GeometryList>, OutOfBudget> {
     let mut scene = scene::DefaultScene::<GeometryList>::new(
         BackgroundLight { intensity: DAYLIGHT_COLOR * 0.5 }
     );
@@ -136,9 +181,9 @@ fn setup_df_showcase() -> scene::DefaultScene<GeometryList> {
     scene.add_luminous_object(
         Sphere { center: Vec3f::new(0.0, 25.0, 0.0), radius: 5.0 },
         DAYLIGHT_COLOR * 30.0
-    );
+    )?;
 
-    add_cornell_box(&mut scene, 25.0);
+    add_cornell_box(&mut scene, 25.0)?;
 
     scene.add_isosurface(
         DFieldsSubstr {
@@ -158,7 +203,7 @@ fn setup_df_showcase() -> scene::DefaultScene<GeometryList> {
             pos: Vec3f::new(-3.0, -7.0, 5.0),
         },
         GOLDEN_SPEC
-    );
+    )?;
 
     scene.add_isosurface(
         DFieldsSubstr {
@@ -175,7 +220,7 @@ fn setup_df_showcase() -> scene::DefaultScene<GeometryList> {
             pos: Vec3f::new(-13.0, -18.0, -5.0)
         },
         WHITE_CERAMICS
-    );
+    )?;
 
     scene.add_isosurface(
         DFieldsSubstr {
@@ -192,13 +237,13 @@ fn setup_df_showcase() -> scene::DefaultScene<GeometryList> {
             pos: Vec3f::new(12.0, -19.0, -4.0)
         },
         MIRROR
-    );
+    )?;
 
-    scene
+    Ok(scene)
 }
 
 #[allow(dead_code)]
-fn setup_df_blend_showcase() -> scene::DefaultScene<GeometryList> {
+fn setup_df_blend_showcase() -> Result<scene::DefaultScene<GeometryList>, OutOfBudget> {
     let mut scene = scene::DefaultScene::<GeometryList>::new(
         BackgroundLight { intensity: DAYLIGHT_COLOR * 0.5 }
     );
@@ -206,9 +251,9 @@ fn setup_df_blend_showcase() -> scene::DefaultScene<GeometryList> {
     scene.add_luminous_object(
         Sphere { center: Vec3f::new(0.0, 25.0, 0.0), radius: 5.0 },
         DAYLIGHT_COLOR * 30.0
-    );
+    )?;
 
-    add_cornell_box(&mut scene, 25.0);
+    add_cornell_box(&mut scene, 25.0)?;
     scene.add_isosurface(
         DFieldsBlend {
             a: Sphere { center: Vec3f::new(7.0, 0.0, 0.0), radius: 7.0 },
@@ -218,15 +263,358 @@ fn setup_df_blend_showcase() -> scene::DefaultScene<GeometryList> {
             k: 6.0
         },
         MIRROR
-    );
+    )?;
+
+    Ok(scene)
+}
 
-    scene
+// the number after `flag`, if it's there
+// `xray --stress city 1000` renders a generated scene of 1000 buildings instead, `--stress forest 5000` one of
+// 5000 trees, to time the traversal and the light picking at scale
+#[cfg(feature = "stress-scenes")]
+fn use_stress_scene(args: &[String], res: Vec2u, scene: &mut scene::DefaultScene<GeometryList>, cam: &mut PerspectiveCamera) {
+    let pos = match args.iter().position(|arg| arg == "--stress") {
+        Some(pos) => pos,
+        None      => return
+    };
+    let count = args.get(pos + 2).and_then(|count| count.parse().ok()).expect("--stress needs a kind and a count");
+    let stress = match args[pos + 1].as_str() {
+        "city"   => stress::StressScene::city(count),
+        "forest" => stress::StressScene::forest(count),
+        kind     => panic!("--stress needs city or forest, not {}", kind)
+    };
+    *scene = stress.build_scene().unwrap_or_else(|err| panic!("Cannot build the stress scene: {}", err));
+    *cam = stress.camera(res);
+    println!("stress scene: {} objects, {} lights", stress.objects_nb(), scene.get_lights_nb());
+}
+
+// `xray --import model.obj` adds every object of the file in white, the copies of an object the exporter
+// baked in share its mesh
+fn import_meshes(args: &[String], scene: &mut scene::DefaultScene<GeometryList>) {
+    let path = match args.iter().position(|arg| arg == "--import") {
+        Some(pos) => args.get(pos + 1).expect("--import needs a path"),
+        None      => return
+    };
+    let meshes = geometry::Mesh::load_objects(path).unwrap_or_else(|err| panic!("Cannot import {}: {}", path, err));
+    let (instances, copies) = geometry::instance_copies(meshes);
+    println!("imported {} objects, {} of them instances", instances.len(), copies);
+    for instance in instances {
+        scene.add_object(instance, WHITE_DIFFUSE).unwrap_or_else(|err| panic!("Cannot import {}: {}", path, err));
+    }
+}
+
+fn flag_value<T: std::str::FromStr>(args: &[String], flag: &str) -> Option<T> {
+    args.iter().position(|arg| arg == flag).map(|pos| {
+        args.get(pos + 1).and_then(|value| value.parse().ok()).unwrap_or_else(|| panic!("{} needs a number", flag))
+    })
+}
+
+// `--max-depth 8 --min-depth 3 --spp-per-iter 4 --light-clamp 100 --seed 7` tune the renderer,
+// `--clamp-direct 20 --clamp-indirect 5` cut the fireflies off, `--fixed-depth 4` turns the russian roulette
+// off for renders which can be diffed exactly, `--sampler sobol` picks the sample placement, `--deterministic`
+// renders the same image on every run, `--bounce-splits 4 --light-splits 2` split the first vertex of the MIS
+// path tracer, `--no-blue-sky` has it traverse the scene for the bounces leaving it too, `--ray-differentials`
+// has it prefilter the environment maps over the footprints of its paths
+fn render_settings(args: &[String]) -> RenderSettings {
+    let mut settings = RenderSettings::new();
+    if let Some(depth) = flag_value(args, "--max-depth") {
+        settings = settings.with_max_depth(depth);
+    }
+    if let Some(depth) = flag_value(args, "--min-depth") {
+        settings = settings.with_min_depth(depth);
+    }
+    if let Some(spp) = flag_value(args, "--spp-per-iter") {
+        settings = settings.with_spp_per_iteration(spp);
+    }
+    if let Some(clamp) = flag_value(args, "--light-clamp") {
+        settings = settings.with_light_clamp(clamp);
+    }
+    if let Some(seed) = flag_value(args, "--seed") {
+        settings = settings.with_seed(seed);
+    }
+    if let Some(depth) = flag_value(args, "--fixed-depth") {
+        settings = settings.with_fixed_depth(depth);
+    }
+    if let Some(clamp) = flag_value(args, "--clamp-direct") {
+        settings = settings.with_direct_clamp(clamp);
+    }
+    if let Some(clamp) = flag_value(args, "--clamp-indirect") {
+        settings = settings.with_indirect_clamp(clamp);
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--sampler") {
+        let sampler = match args.get(pos + 1).map(|name| name.as_str()) {
+            Some("independent") => SamplerKind::Independent,
+            Some("stratified")  => SamplerKind::Stratified,
+            Some("sobol")       => SamplerKind::Sobol,
+            Some("halton")      => SamplerKind::Halton,
+            Some("blue-noise")  => SamplerKind::BlueNoise,
+            Some("cmj")         => SamplerKind::Cmj,
+            _ => panic!("--sampler needs one of independent, stratified, sobol, halton, blue-noise, cmj")
+        };
+        settings = settings.with_sampler(sampler);
+    }
+    if args.iter().any(|arg| arg == "--deterministic") {
+        settings = settings.with_deterministic();
+    }
+    if args.iter().any(|arg| arg == "--no-blue-sky") {
+        settings = settings.with_blue_sky(false);
+    }
+    if args.iter().any(|arg| arg == "--ray-differentials") {
+        settings = settings.with_ray_differentials();
+    }
+    let bounces = flag_value(args, "--bounce-splits").unwrap_or(settings.bounce_splits);
+    let lights = flag_value(args, "--light-splits").unwrap_or(settings.light_splits);
+    settings.with_splitting(bounces, lights)
 }
 
 fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    // defaults from an xray.toml in the working or the home directory, the flags win over them
+    let config = Config::find().and_then(|config| config.with_args(&args))
+        .unwrap_or_else(|err| panic!("Cannot read the config: {}", err));
+    if let Some(budget) = config.memory_budget {
+        memory::global().set_budget(budget << 20);
+    }
+    // every file the render writes goes into the output directory of the config, if there is one
+    if let Some(ref dir) = config.output_dir {
+        std::fs::create_dir_all(dir).unwrap_or_else(|err| panic!("Cannot create {}: {}", dir.display(), err));
+    }
+    if let Some(threads) = config.threads {
+        rayon::initialize(rayon::Configuration::new().set_num_threads(threads))
+            .unwrap_or_else(|err| panic!("Cannot start {} threads: {:?}", threads, err));
+    }
+    // `xray --pin-threads spread --numa-interleave` for the nodes with several sockets: the rendering threads
+    // stay on their cpus, taken a node after another, and the pages of the meshes are spread over the memory
+    // of all the nodes instead of the one which loaded them
+    let pinning = match args.iter().position(|arg| arg == "--pin-threads").map(|pos| args.get(pos + 1).map(|name| name.as_str())) {
+        None                  => ThreadPinning::Off,
+        Some(Some("off"))     => ThreadPinning::Off,
+        Some(Some("compact")) => ThreadPinning::Compact,
+        Some(Some("spread"))  => ThreadPinning::Spread,
+        _ => panic!("--pin-threads needs one of off, compact, spread")
+    };
+    numa::global().configure(pinning, args.iter().any(|arg| arg == "--numa-interleave"));
+    let settings = render_settings(&args);
+    // `xray --report report.json` writes what the run did when it ends, whichever mode it is in, see `RenderReport`
+    let report_path = args.iter().position(|arg| arg == "--report")
+        .map(|pos| config.output_path(args.get(pos + 1).expect("--report needs a path")));
+    let started = Instant::now();
+    // `xray --diff-scenes a.scene b.scene` tells what differs between two scenes written by `--save-scene`
+    if let Some(pos) = args.iter().position(|arg| arg == "--diff-scenes") {
+        let load = |path: Option<&String>| {
+            let path = path.expect("--diff-scenes needs two paths");
+            SceneDescription::load(path).unwrap_or_else(|err| panic!("Cannot read {}: {}", path, err))
+        };
+        let (old, new) = (load(args.get(pos + 1)), load(args.get(pos + 2)));
+        print!("{}", SceneDiff::between(&old, &new));
+        let mut report = RenderReport::new(settings, Vec2u::new(0, 0), 0);
+        report.end_stage("diff", started);
+        save_report(&report, report_path.as_ref());
+        return;
+    }
+    // `xray --dataset out_dir 100` writes 100 samples of denoiser training data instead of rendering,
+    // `--frame-chunk 2/4` only the second quarter of them, for the nodes of a render farm
+    if let Some(pos) = args.iter().position(|arg| arg == "--dataset") {
+        let out_dir = config.output_path(args.get(pos + 1).expect("--dataset needs an output directory"));
+        let samples = args.get(pos + 2).and_then(|samples| samples.parse().ok()).unwrap_or(100);
+        let chunk = match args.iter().position(|arg| arg == "--frame-chunk") {
+            Some(pos) => args.get(pos + 1).expect("--frame-chunk needs i/N").parse::<dataset::FrameChunk>()
+                .unwrap_or_else(|err| panic!("Cannot split the frames: {}", err)),
+            None => dataset::FrameChunk::whole()
+        };
+        let dataset_settings = dataset::DatasetSettings {
+            samples: samples,
+            resolution: Vec2u::new(256, 256),
+            noisy_spp: 4,
+            clean_spp: 4096,
+            seed: 0,
+            chunk: chunk,
+        };
+        let make_scene = || setup_mis_showcase().unwrap_or_else(|err| panic!("Cannot build the scene: {}", err));
+        dataset::export_dataset::<_, CpuPtMis<_>, _, _>(&dataset_settings, make_scene, &out_dir)
+            .unwrap_or_else(|err| panic!("Cannot export the dataset to {}: {}", out_dir.display(), err));
+        let mut report = RenderReport::new(settings, dataset_settings.resolution, dataset_settings.seed);
+        report.end_stage("dataset", started);
+        save_report(&report, report_path.as_ref());
+        return;
+    }
+    assert!(!args.iter().any(|arg| arg == "--frame-chunk"), "--frame-chunk only splits the samples of --dataset");
+    // `xray --panorama 360 out.pfm` renders a stitched panorama around the camera position instead
+    if let Some(pos) = args.iter().position(|arg| arg == "--panorama") {
+        let horizontal_fov = args.get(pos + 1).and_then(|fov| fov.parse().ok()).expect("--panorama needs an angle");
+        let path = config.output_path(args.get(pos + 2).expect("--panorama needs an output path"));
+        let spp = 256;
+        let size = Vec2u::new(horizontal_fov as usize * 8, 360);
+        let panorama = panorama::Panorama::new(Vec3f::new(0.0, 0.0, -86.0), Vec3f::new(0.0, 0.0, 1.0), horizontal_fov, size);
+        let make_scene = || setup_mis_showcase().unwrap_or_else(|err| panic!("Cannot build the scene: {}", err));
+        let frame = panorama.render::<_, CpuPtMis<_>, _>(spp, make_scene);
+        let mut report = RenderReport::new(settings, size, 0);
+        let saving = report.end_stage("render", started);
+        checkpoint::save_pfm(&frame, 1.0 / spp as f32, &path).unwrap_or_else(|err| panic!("Cannot save {}: {}", path.display(), err));
+        report.end_stage("save", saving);
+        save_frame_report(&mut report, &frame, spp, &path, report_path.as_ref());
+        return;
+    }
+
+    // `xray --audit-materials` integrates the albedo of every material of the scene instead
+    if args.iter().any(|arg| arg == "--audit-materials") {
+        let scene = setup_mis_showcase().unwrap_or_else(|err| panic!("Cannot build the scene: {}", err));
+        for m_id in 0..scene.get_materials_nb() {
+            println!("material {}:{}", m_id, AlbedoAudit::new(scene.get_material(m_id as MaterialID)));
+        }
+        let mut report = RenderReport::new(settings, Vec2u::new(0, 0), scene.get_seed());
+        report.end_stage("audit", started);
+        save_report(&report, report_path.as_ref());
+        return;
+    }
+
     let res = Vec2u::new(1000, 1000);
     // let res = Vec2u::new(500, 500);
     // let res = Vec2u::new(250, 250);
+    // `xray --shader-ball --hdri sky.pfm --hdri-rotation 90 --exposure -1` renders a ceramic ball in the
+    // material ball scene instead, to compare materials under the same light
+    let shader_ball = if args.iter().any(|arg| arg == "--shader-ball") {
+        let mut ball = ShaderBall::new();
+        if let Some(degrees) = flag_value(&args, "--hdri-rotation") {
+            ball = ball.with_hdri_rotation(degrees);
+        }
+        if let Some(stops) = flag_value(&args, "--exposure") {
+            ball = ball.with_exposure(stops);
+        }
+        Some(ball)
+    } else {
+        None
+    };
+    let mut cam = match shader_ball {
+        Some(ball) => ball.camera(res),
+        None => CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(0.0, 0.0, -86.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .with_up(Vec3f::new(0.0, 1.0, 0.0))
+            .with_fov(45.0)
+            .with_znear(0.1)
+            .with_zfar(10000.0)
+            .build()
+    };
+
+    let mut scene = match shader_ball {
+        Some(ball) => {
+            let hdri = args.iter().position(|arg| arg == "--hdri").map(|pos| {
+                let path = args.get(pos + 1).expect("--hdri needs a path");
+                std::sync::Arc::new(texture::Texture::load(path, false).unwrap_or_else(|err| panic!("Cannot load {}: {}", path, err)))
+            });
+            ball.build_scene(WHITE_CERAMICS, hdri)
+        },
+        None => setup_mis_showcase()
+    }.unwrap_or_else(|err| panic!("Cannot build the scene: {}", err));
+    #[cfg(feature = "stress-scenes")]
+    use_stress_scene(&args, res, &mut scene, &mut cam);
+    import_meshes(&args, &mut scene);
+    // let scene = setup_df_showcase();
+    // let scene = setup_df_blend_showcase();
+    // let scene = setup_pointlight_showcase();
+    // `xray --set lights.1.intensity=5 --set camera.fov=35` overrides values of the scene as built, see `ParamOverride`
+    for (pos, _) in args.iter().enumerate().filter(|&(_, arg)| arg == "--set") {
+        let param = args.get(pos + 1).expect("--set needs a key=value");
+        param.parse::<overrides::ParamOverride>()
+            .and_then(|param| param.apply(&mut scene, &mut cam))
+            .unwrap_or_else(|err| panic!("Cannot apply --set {}: {}", param, err));
+    }
+    // `xray --ground -1 --axes 10` adds a checkered ground plane at y = -1 and draws the world axes
+    let mut helpers = LookDevHelpers::new();
+    if let Some(height) = flag_value(&args, "--ground") {
+        helpers = helpers.with_ground(height);
+    }
+    if let Some(length) = flag_value(&args, "--axes") {
+        helpers = helpers.with_axes(length);
+    }
+    helpers.add_to_scene(&mut scene).unwrap_or_else(|err| panic!("Cannot add the ground: {}", err));
+    // `xray --auto-portals` finds the windows and the doors of the room the camera is in and samples the
+    // background light through them
+    if args.iter().any(|arg| arg == "--auto-portals") {
+        let portals = portal::detect_portals(&scene, &cam.get_position(), 65536);
+        println!("portals: {} found", portals.len());
+        if !portals.is_empty() {
+            scene.add_portals(portals);
+        }
+    }
+    // `xray --power-lights` samples the bright lights more often than the dim ones, `--light-tree` also the
+    // near ones, for scenes with many lights
+    if args.iter().any(|arg| arg == "--power-lights") {
+        scene.set_light_selection(LightSelection::Power);
+    }
+    if args.iter().any(|arg| arg == "--light-tree") {
+        scene.set_light_selection(LightSelection::Tree);
+    }
+    // `xray --save-scene a.scene` writes the scene as it is about to be rendered, see `SceneDescription`
+    if let Some(pos) = args.iter().position(|arg| arg == "--save-scene") {
+        let path = config.output_path(args.get(pos + 1).expect("--save-scene needs a path"));
+        SceneDescription::of(&scene, &cam).save(&path).unwrap_or_else(|err| panic!("Cannot save {}: {}", path.display(), err));
+    }
+    let report = scene.validate();
+    if !report.is_ok() {
+        print!("The scene has issues:\n{}", report);
+    }
+
+    let mut report = RenderReport::new(settings, res, 0);
+    let mut stage_started = report.end_stage("scene", started);
+    let mut ren = CpuPtMis::new_with_settings(cam, scene, settings);
+    // `xray --primary-cache` finds the camera hits once instead of in every iteration
+    if args.iter().any(|arg| arg == "--primary-cache") {
+        ren = ren.with_primary_cache(4).unwrap_or_else(|err| panic!("Cannot cache the camera hits: {}", err));
+    }
+    let seed = ren.get_scene().get_seed();
+    report.seed = seed;
+
+    // `xray --buckets out_dir out.pfm` renders bucket by bucket to disk, continues where a previous run
+    // stopped and merges the buckets when all of them are there
+    if let Some(pos) = args.iter().position(|arg| arg == "--buckets") {
+        let dir = config.output_path(args.get(pos + 1).expect("--buckets needs an output directory"));
+        let path = config.output_path(args.get(pos + 2).expect("--buckets needs an output path"));
+        let spp = 256;
+        let buckets = buckets::BucketRender::new(&dir, res, spp, seed);
+        install_sigint_handler();
+        let missing = buckets.render(&ren, &mut |done, total| print!("\rbuckets: {}/{}", done, total))
+            .unwrap_or_else(|err| panic!("Cannot render into {}: {}", dir.display(), err));
+        println!("");
+        if missing > 0 {
+            println!("{} buckets left, run again to finish them", missing);
+            return;
+        }
+        stage_started = report.end_stage("render", stage_started);
+        let frame = buckets.merge().unwrap_or_else(|err| panic!("Cannot merge {}: {}", dir.display(), err));
+        checkpoint::save_pfm(&frame, 1.0 / spp as f32, &path).unwrap_or_else(|err| panic!("Cannot save {}: {}", path.display(), err));
+        report.end_stage("save", stage_started);
+        save_frame_report(&mut report, &frame, spp, &path, report_path.as_ref());
+        return;
+    }
+
+    // `xray --region 200 100 600 400 crop.pfm` renders only the pixels from (200, 100) up to (600, 400), with the
+    // samples they'd get in the whole frame, and saves them as an image of their own; only the frame tiles
+    // under the region are allocated
+    if let Some(pos) = args.iter().position(|arg| arg == "--region") {
+        let coord = |i: usize| args.get(pos + i).and_then(|coord| coord.parse().ok()).expect("--region needs x0 y0 x1 y1");
+        let (min, max) = (Vec2u::new(coord(1), coord(2)), Vec2u::new(coord(3), coord(4)));
+        let path = config.output_path(args.get(pos + 5).expect("--region needs an output path"));
+        let spp = 256;
+        let mut tiles = TiledFrameBuffer::new(res);
+        ren.iterate_over_region(0, spp, min, max, &mut tiles)
+            .unwrap_or_else(|err| panic!("Cannot render the region: {}", err));
+        stage_started = report.end_stage("render", stage_started);
+        let crop = tiles.crop(min, max);
+        checkpoint::save_pfm(&crop, 1.0 / spp as f32, &path)
+            .unwrap_or_else(|err| panic!("Cannot save {}: {}", path.display(), err));
+        report.end_stage("save", stage_started);
+        report.resolution = max - min;
+        save_frame_report(&mut report, &crop, spp, &path, report_path.as_ref());
+        return;
+    }
+
+    // `xray --half-frame` keeps the sums in half floats only, see below
+    let half_wanted = args.iter().any(|arg| arg == "--half-frame");
+    let mut frame = if half_wanted { RgbFrameBuffer::new(Vec2u::new(0, 0)) } else { cam.build_rgb_framebuffer() };
+    let mut yxy_frame = cam.build_yxy_framebuffer();
     let mut window = RenderWindow::new(
             VideoMode::new_init(res.x as u32, res.y as u32, 32),
             "XRay",
@@ -234,31 +622,112 @@ fn main() {
             &ContextSettings::default())
         .expect("Cannot create a new Render Window.");
 
-    let cam = CameraBuilder::<PerspectiveCamera>::new()
-        .with_view_size(res)
-        .with_pos(Vec3f::new(0.0, 0.0, -86.0))
-        .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
-        .with_up(Vec3f::new(0.0, 1.0, 0.0))
-        .with_fov(45.0)
-        .with_znear(0.1)
-        .with_zfar(10000.0)
-        .build();
-
-    let mut frame = cam.build_rgb_framebuffer();
-    let mut yxy_frame = cam.build_yxy_framebuffer();
-
-    let scene = setup_mis_showcase();
-    // let scene = setup_df_showcase();
-    // let scene = setup_df_blend_showcase();
-    // let scene = setup_pointlight_showcase();
-
-    let ren = CpuPtMis::new(cam, scene);
+    let spp_per_iter = settings.spp_per_iteration;
     let mut iter_nb = 0;
+    let mut spp = 0;
+
+    // `xray --resume xray_checkpoint.bin` continues an interrupted render
+    if let Some(pos) = args.iter().position(|arg| arg == "--resume") {
+        let path = args.get(pos + 1).expect("--resume needs a checkpoint path");
+        let checkpoint = Checkpoint::load_for(path, res, seed).unwrap_or_else(|err| panic!("Cannot load {}: {}", path, err));
+        // the progressive render only makes the beauty pass, it would drop the others on the next save
+        assert!(checkpoint.passes.is_empty() && checkpoint.path_stats.is_none(),
+                "{} has passes besides the beauty one, this render can't continue them", path);
+        iter_nb = checkpoint.iter_nb as usize;
+        spp = checkpoint.spp as usize;
+        frame = checkpoint.frame;
+    }
+    // `xray --importance mask.ppm` spends more samples where the grayscale mask is brighter
+    let mask = args.iter().position(|arg| arg == "--importance").map(|pos| {
+        let path = args.get(pos + 1).expect("--importance needs a mask path");
+        let mask = texture::Texture::load(path, false).unwrap_or_else(|err| panic!("Cannot load {}: {}", path, err));
+        ImportanceMask::new(&mask, res)
+    });
+    // `xray --flare` composites lens flares of the bright spots into the shown frame
+    let flare = if args.iter().any(|arg| arg == "--flare") { Some(flare::LensFlare::new()) } else { None };
+    // `xray --light-gizmos` marks the lights over the shown frame
+    let light_gizmos = if args.iter().any(|arg| arg == "--light-gizmos") { Some(Gizmos::lights(ren.get_scene(), &cam)) } else { None };
+    let gizmos = match (light_gizmos, helpers.gizmos(&cam)) {
+        (Some(lights), Some(axes)) => Some(lights.merge(axes)),
+        (lights, axes)             => lights.or(axes)
+    };
+    // `xray --vignetting --iso 1600` shows the frame as a camera with that lens and sensor would
+    let mut sensor = sensor::Sensor::new();
+    if args.iter().any(|arg| arg == "--vignetting") {
+        sensor = sensor.with_vignetting(sensor::Vignetting::Cos4);
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--iso") {
+        let iso = args.get(pos + 1).and_then(|iso| iso.parse().ok()).expect("--iso needs a number");
+        sensor = sensor.with_noise(sensor::SensorNoise::new(iso));
+    }
+    let use_sensor = sensor.vignetting.is_some() || sensor.noise.is_some();
+    // `xray --preview-gi 32` shows 32 iterations of the radiance cache first, then the path tracer takes
+    // over. The preview has a frame of its own, the one of the path tracer (or the resumed one) is kept
+    let mut preview = flag_value::<usize>(&args, "--preview-gi").map(|iterations| {
+        if settings.bounce_splits > 1 || settings.light_splits > 1 {
+            println!("The radiance cache of --preview-gi doesn't split, --bounce-splits and --light-splits only apply after it");
+        }
+        let scene = setup_mis_showcase().unwrap_or_else(|err| panic!("Cannot build the scene: {}", err));
+        (CpuRc::new_with_settings(cam, scene, settings), cam.build_rgb_framebuffer(), iterations)
+    });
+    let mut preview_iter_nb = 0;
+    // `xray --adaptive 0.02` stops sampling the pixels once their 95% confidence interval is within 2% of
+    // their mean; it keeps its own statistics, which a checkpoint doesn't have, so it can't resume
+    let mut adaptive = flag_value::<f32>(&args, "--adaptive").map(|threshold| (threshold, AdaptiveFrameBuffer::new(res)));
+    assert!(adaptive.is_none() || !args.iter().any(|arg| arg == "--resume"), "--adaptive can't resume a render");
+    // `xray --half-frame` sums the iterations in half floats, `--half-frame compensated` also keeps their rounding
+    // errors, see `HalfFrameBuffer`; the iterations are rendered into it a band at a time and `frame` stays empty
+    // until the sums are read back at the end
+    let mut half_frame = match args.iter().position(|arg| arg == "--half-frame").map(|pos| args.get(pos + 1).map(|kind| kind.as_str())) {
+        None => None,
+        Some(kind) => {
+            let half = HalfFrameBuffer::new(res).and_then(|half| {
+                if kind == Some("compensated") { half.with_compensation() } else { Ok(half) }
+            });
+            let mut half = half.unwrap_or_else(|err| panic!("Cannot accumulate in half floats: {}", err));
+            // the resumed sums go on in half floats
+            if spp > 0 {
+                half.add_frame(&frame, spp);
+                frame = RgbFrameBuffer::new(Vec2u::new(0, 0));
+            }
+            Some(half)
+        }
+    };
+    assert!(adaptive.is_none() || half_frame.is_none(), "--adaptive keeps its own sums, it can't use --half-frame");
+    assert!(mask.is_none() || half_frame.is_none(), "--importance needs the whole frame, it can't use --half-frame");
+    install_sigint_handler();
+    // `xray --target-cpu 0.5 --thermal-limit 85` renders on fewer threads while the machine is more than
+    // half busy or hotter than 85 degrees, for renders in the background of a laptop in use
+    let mut scaling = ThreadScaling::new(rayon::current_num_threads());
+    if let Some(utilization) = flag_value(&args, "--target-cpu") {
+        scaling = scaling.with_target_utilization(utilization);
+    }
+    if let Some(celsius) = flag_value(&args, "--thermal-limit") {
+        scaling = scaling.with_thermal_limit(celsius);
+    }
+    // `xray --preview-jpeg preview.jpg` writes the shown frame as a JPEG once a second, `--preview-fps 5` more often,
+    // `--preview-http 0.0.0.0:8080` serves it to a browser, to watch the render from another machine, and
+    // the render metrics at /metrics; `--preview-quality 50` and `--preview-progressive` for slow links
+    let preview_path = args.iter().position(|arg| arg == "--preview-jpeg").map(|pos| {
+        config.output_path(args.get(pos + 1).expect("--preview-jpeg needs a path"))
+    });
+    let preview_server = args.iter().position(|arg| arg == "--preview-http").map(|pos| {
+        let addr = args.get(pos + 1).expect("--preview-http needs an address");
+        PreviewServer::bind(addr).unwrap_or_else(|err| panic!("Cannot serve the preview on {}: {}", addr, err))
+    });
+    let mut preview_encoder = if preview_path.is_some() || preview_server.is_some() {
+        let encoder = PreviewEncoder::new(flag_value(&args, "--preview-quality").unwrap_or(75),
+                                          flag_value(&args, "--preview-fps").unwrap_or(1.0));
+        Some(if args.iter().any(|arg| arg == "--preview-progressive") { encoder.with_progressive() } else { encoder })
+    } else {
+        None
+    };
     let mut pixels = (0..(res.x * res.y * 4)).map(|_| 255u8).collect::<Vec<_>>();
+    let mut telemetry = Telemetry::new(res.x * res.y, 0.01);
 
     let mut tex = Texture::new(res.x as u32, res.y as u32).expect("cant create texture");
-    while window.is_open() {
-        iter_nb += 1;
+    stage_started = report.end_stage("setup", stage_started);
+    while window.is_open() && !interrupted() {
         for event in window.events() {
             match event {
                 event::Closed => window.close(),
@@ -266,11 +735,85 @@ fn main() {
             }
         }
 
-        ren.iterate(iter_nb, &mut frame);
-        let k = 1.0 / iter_nb as f32;
-        let frame_lum = frame.to_yxy_inplace(&mut yxy_frame, k);
-        log_tone_mapping(&mut yxy_frame, frame_lum);
-        let rgb_frame = yxy_frame.into_rgb();
+        if preview.as_ref().map_or(false, |&(_, _, iterations)| preview_iter_nb >= iterations) {
+            preview = None;
+        }
+        match preview {
+            Some((ref rc, ref mut preview_frame, _)) => {
+                preview_iter_nb += 1;
+                rc.iterate(preview_iter_nb, spp_per_iter, preview_frame);
+            },
+            None => {
+                iter_nb += 1;
+                match adaptive {
+                    Some((threshold, ref mut stats)) => {
+                        ren.iterate_adaptive(iter_nb, spp_per_iter, threshold, ADAPTIVE_MIN_SAMPLES, stats);
+                        spp += spp_per_iter;
+                        stats.write_means(&mut frame, spp);
+                    },
+                    None => {
+                        match half_frame {
+                            Some(ref mut half) => ren.iterate_into_half(iter_nb, spp_per_iter, half)
+                                .unwrap_or_else(|err| panic!("Cannot render into half floats: {}", err)),
+                            None               => ren.iterate_over_screen_masked(iter_nb, spp_per_iter, mask.as_ref(), &mut frame)
+                        }
+                        spp += spp_per_iter;
+                    }
+                }
+            }
+        }
+        let (frame_shown, spp_shown) = match preview {
+            Some((_, ref preview_frame, _)) => (preview_frame, preview_iter_nb * spp_per_iter),
+            None                            => (&frame, spp)
+        };
+        let half_shown = if preview.is_none() { half_frame.as_ref() } else { None };
+        match preview {
+            Some((ref rc, _, _)) => telemetry.record_aborts(rc.watchdog()),
+            None                 => telemetry.record_aborts(ren.watchdog())
+        }
+        telemetry.record_iteration(spp_per_iter as u32, &|pix| half_shown.map_or_else(|| frame_shown.as_slice()[pix], |half| half.sum(pix)));
+        if scaling.is_enabled() {
+            scaling.update(throttle::global());
+        }
+        let k = 1.0 / spp_shown as f32;
+        let frame_lum = if flare.is_some() || use_sensor {
+            let mut shown = half_shown.map_or_else(|| frame_shown.clone(), |half| half.to_rgb());
+            if let Some(ref flare) = flare {
+                flare.apply(&mut shown, k);
+            }
+            if use_sensor {
+                sensor.apply(&cam, &mut shown, k, seed);
+            }
+            shown.to_yxy_inplace(&mut yxy_frame, k)
+        } else if let Some(half) = half_shown {
+            half.to_yxy_inplace(&mut yxy_frame, k)
+        } else {
+            frame_shown.to_yxy_inplace(&mut yxy_frame, k)
+        };
+        if config.tone_map.unwrap_or(ToneMap::Log) == ToneMap::Log {
+            log_tone_mapping(&mut yxy_frame, frame_lum);
+        }
+        let mut rgb_frame = yxy_frame.into_rgb();
+        if let Some(ref gizmos) = gizmos {
+            gizmos.draw(&mut rgb_frame);
+        }
+        if let Some(ref mut encoder) = preview_encoder {
+            match encoder.encode_if_due(&rgb_frame) {
+                Ok(Some(jpeg)) => {
+                    if let Some(ref path) = preview_path {
+                        // renamed over the previous one when complete, a viewer never gets half of it
+                        let tmp = path.with_extension("tmp");
+                        std::fs::write(&tmp, &jpeg).and_then(|_| std::fs::rename(&tmp, path))
+                            .unwrap_or_else(|err| println!("Cannot save {}: {}", path.display(), err));
+                    }
+                    if let Some(ref server) = preview_server {
+                        server.publish(jpeg);
+                    }
+                },
+                Ok(None) => {},
+                Err(err) => println!("Cannot encode the preview: {}", err)
+            }
+        }
 
         {
             let fb = rgb_frame.as_slice();
@@ -283,7 +826,17 @@ fn main() {
             }
         }
         unsafe { yxy_frame = rgb_frame.as_yxy(); }
-        print!("\r{} spp", iter_nb);
+        let metrics = telemetry.metrics();
+        if let Some(ref server) = preview_server {
+            server.publish_metrics(metrics.to_prometheus());
+        }
+        print!("\r{} spp{}, {:.2} Msamples/s", spp_shown, if preview.is_some() { " (preview)" } else { "" }, metrics.samples_per_second * 1e-6);
+        if scaling.is_enabled() {
+            print!(", {} threads", scaling.slots());
+        }
+        if metrics.aborted_tiles > 0 || metrics.aborted_paths > 0 {
+            print!(", {} tiles and {} paths aborted", metrics.aborted_tiles, metrics.aborted_paths);
+        }
         std::io::stdout().flush().ok().expect("Could not flush stdout");
         tex.update_from_pixels(&pixels, res.x as u32, res.y as u32, 0, 0);
         let sprite = Sprite::new_with_texture(&tex).expect("cant create sprite");
@@ -291,4 +844,55 @@ fn main() {
         window.draw(&sprite);
         window.display();
     }
+    stage_started = report.end_stage("render", stage_started);
+
+    telemetry.record_aborts(ren.watchdog());
+    for abort in telemetry.tile_aborts() {
+        println!("\nwatchdog: tile {} aborted after {:.1}s", abort.tile_nb, duration_to_secs(abort.elapsed));
+    }
+    if let Some(ref half) = half_frame {
+        frame = half.to_rgb();
+    }
+    if interrupted() && spp > 0 {
+        println!("\nInterrupted, saving the partial render");
+        let checkpoint = Checkpoint::new(iter_nb as u32, spp as u32, seed, frame);
+        let (partial, saved) = (config.output_path("xray_partial.pfm"), config.output_path("xray_checkpoint.bin"));
+        checkpoint::save_pfm(&checkpoint.frame, 1.0 / spp as f32, &partial)
+            .and_then(|_| report.add_output(&partial))
+            .unwrap_or_else(|err| println!("Cannot save {}: {}", partial.display(), err));
+        checkpoint.save(&saved)
+            .and_then(|_| report.add_output(&saved))
+            .unwrap_or_else(|err| println!("Cannot save {}: {}", saved.display(), err));
+        frame = checkpoint.frame;
+        report.end_stage("save", stage_started);
+    }
+    if report_path.is_some() {
+        report.iterations = iter_nb;
+        report.spp = spp;
+        let metrics = telemetry.metrics();
+        report.aborted_tiles = metrics.aborted_tiles;
+        report.aborted_paths = metrics.aborted_paths;
+        report.tile_aborts = telemetry.tile_aborts().to_vec();
+        report.metrics = Some(metrics);
+        report.set_frame(&frame, spp);
+        save_report(&report, report_path.as_ref());
+    }
+}
+
+fn save_report(report: &RenderReport, path: Option<&PathBuf>) {
+    if let Some(path) = path {
+        report.save(path).unwrap_or_else(|err| println!("Cannot save {}: {}", path.display(), err));
+    }
+}
+
+// the report of the modes rendering a single pass of `spp` samples into the image at `output`
+fn save_frame_report(report: &mut RenderReport, frame: &RgbFrameBuffer, spp: usize, output: &Path, path: Option<&PathBuf>) {
+    if path.is_none() {
+        return;
+    }
+    report.iterations = 1;
+    report.spp = spp;
+    report.set_frame(frame, spp);
+    report.add_output(output).unwrap_or_else(|err| println!("Cannot hash {}: {}", output.display(), err));
+    save_report(report, path);
 }
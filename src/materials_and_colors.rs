@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use math::Vec3f;
-use brdf::Material;
+use brdf::{Conductor, Dielectric, Material, Microfacet, MicrofacetDistribution};
 
 pub const DAYLIGHT_COLOR: Vec3f = Vec3f { x: 0.65, y: 0.6, z: 0.45 };
 pub const EVENING_COLOR: Vec3f = Vec3f { x: 0.65, y: 0.55, z: 0.35 };
@@ -13,71 +13,360 @@ pub const SKY_BLUE_COLOR: Vec3f = Vec3f { x: 0.1, y: 0.9, z: 0.9 };
 pub const WHITE_DIFFUSE: Material = Material {
     diffuse: Vec3f { x: 0.99, y: 0.99, z: 0.99 },
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// the 18% gray of light meters, a reference for the exposure
+pub const MIDDLE_GRAY_DIFFUSE: Material = Material {
+    diffuse: Vec3f { x: 0.18, y: 0.18, z: 0.18 },
+    specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const GREEN_DIFFUSE: Material = Material {
     diffuse: GREEN_COLOR,
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const RED_DIFFUSE: Material = Material {
     diffuse: RED_COLOR,
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const SKY_BLUE_DIFFUSE: Material = Material {
     diffuse: SKY_BLUE_COLOR,
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const BLUE_DIFFUSE: Material = Material {
     diffuse: Vec3f { x: 0.2, y: 0.2, z: 0.8 },
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const MARGENTA_DIFFUSE: Material = Material {
     diffuse: MARGENTA_COLOR,
     specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
-    phong_exp: 1.0
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const DARK_MIRROR: Material = Material {
     diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 }, // Vec3f::new(0.5, 0.5, 0.2) * 0.7,
     specular: Vec3f { x: 0.50, y: 0.50, z: 0.50 }, // Vec3f::new(0.5, 0.5, 0.2) * 0.3,
-    phong_exp: 1000.0
+    phong_exp: 1000.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const GOLDEN_SPEC: Material = Material {
     diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
     specular: GOLDEN_COLOR,
-    phong_exp: 10.0
+    phong_exp: 10.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const GOLDEN_MIRROR: Material = Material {
-    diffuse: Vec3f { x: 0.5, y: 0.35, z: 0.15 },
-    specular: GOLDEN_COLOR,
-    phong_exp: 1000.0
+    diffuse: Vec3f { x: 0.17, y: 0.119, z: 0.051 },
+    specular: Vec3f { x: 0.66, y: 0.462, z: 0.0 },
+    phong_exp: 1000.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const WHITE_CERAMICS: Material = Material {
-    diffuse: Vec3f { x: 0.99, y: 0.99, z: 0.99 },
-    specular: Vec3f { x: 0.5, y: 0.5, z: 0.5 },
-    phong_exp: 1000.0
+    diffuse: Vec3f { x: 0.658, y: 0.658, z: 0.658 },
+    specular: Vec3f { x: 0.168, y: 0.168, z: 0.168 },
+    phong_exp: 1000.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const MIRROR: Material = Material {
     diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
     specular: Vec3f { x: 0.99, y: 0.99, z: 0.99 },
-    phong_exp: 10000.0
+    phong_exp: 10000.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
 
 pub const SKY_BLUE_MIRROR: Material = Material {
-    diffuse: Vec3f { x: 0.05, y: 0.45, z: 0.45 },
-    specular: SKY_BLUE_COLOR,
-    phong_exp: 10000.0
+    diffuse: Vec3f { x: 0.0167, y: 0.15, z: 0.15 },
+    specular: Vec3f { x: 0.0667, y: 0.6, z: 0.6 },
+    phong_exp: 10000.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// an ideal mirror, reflecting exactly into the mirror direction
+pub const PERFECT_MIRROR: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.99, y: 0.99, z: 0.99 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// polished chrome: a slightly bluish mirror with a faint haze around the reflections
+pub const CHROME: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 0.03, y: 0.03, z: 0.03 },
+    phong_exp: 200.0,
+    mirror: Vec3f { x: 0.55, y: 0.56, z: 0.58 },
+    microfacet: None,
+    conductor: None,
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// clear window glass
+pub const GLASS: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: Some(Dielectric { ior: 1.5, transmittance: Vec3f { x: 1.0, y: 1.0, z: 1.0 }, rough: None }),
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// sandblasted glass, lets the light through but blurs everything behind it
+pub const FROSTED_GLASS: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: None,
+    conductor: None,
+    dielectric: Some(Dielectric {
+        ior: 1.5,
+        transmittance: Vec3f { x: 1.0, y: 1.0, z: 1.0 },
+        rough: Some(Microfacet { distribution: MicrofacetDistribution::Ggx, roughness: 0.5 })
+    }),
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// polished gold, the indices are of the red, green and blue light
+pub const GOLD: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 1.0, y: 1.0, z: 1.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: Some(Microfacet { distribution: MicrofacetDistribution::Ggx, roughness: 0.2 }),
+    conductor: Some(Conductor {
+        eta: Vec3f { x: 0.143, y: 0.374, z: 1.442 },
+        k: Vec3f { x: 3.983, y: 2.385, z: 1.603 }
+    }),
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// polished copper
+pub const COPPER: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 1.0, y: 1.0, z: 1.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: Some(Microfacet { distribution: MicrofacetDistribution::Ggx, roughness: 0.2 }),
+    conductor: Some(Conductor {
+        eta: Vec3f { x: 0.200, y: 0.924, z: 1.102 },
+        k: Vec3f { x: 3.912, y: 2.452, z: 2.142 }
+    }),
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
+};
+
+// brushed aluminium, bluish white with wide highlights
+pub const ALUMINIUM: Material = Material {
+    diffuse: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    specular: Vec3f { x: 1.0, y: 1.0, z: 1.0 },
+    phong_exp: 1.0,
+    mirror: Vec3f { x: 0.0, y: 0.0, z: 0.0 },
+    microfacet: Some(Microfacet { distribution: MicrofacetDistribution::Ggx, roughness: 0.4 }),
+    conductor: Some(Conductor {
+        eta: Vec3f { x: 1.657, y: 0.880, z: 0.521 },
+        k: Vec3f { x: 9.224, y: 6.270, z: 4.837 }
+    }),
+    dielectric: None,
+    measured: None,
+    textures: None,
+    car_paint: None,
+    sheen: None,
+    dust: None,
+    dirt: None,
+    variation: None
 };
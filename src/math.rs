@@ -68,6 +68,8 @@ pub mod matrix_traits {
 
 use self::vector_traits::*;
 use self::matrix_traits::*;
+use std::f32;
+use std::ops::{Add, Div, Mul, Neg, Sub};
 
 pub type Mat4f = Mat4<f32>;
 pub type Mat3f = Mat3<f32>;
@@ -107,6 +109,11 @@ pub fn vec4_to_3<N: BaseNum>(v: &Vec4<N>) -> Vec3<N> {
     Vec3::new(v.x, v.y, v.z)
 }
 
+// none of the components is NaN or infinite
+pub fn is_finite(v: &Vec3f) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
 pub fn ortho(v: &Vec3f) -> Vec3f {
     // http://lolengine.net/blog/2013/09/21/picking-orthogonal-vector-combing-coconuts
     if v.x.abs() > v.z.abs() {
@@ -136,3 +143,177 @@ pub fn triple_sin(p: &Vec3f) -> f32 {
     p.map(|x| (1.0 * x).sin()).fold(|x, y| x * y)
     // 1.00 * (1.00 * p.x).sin() * (1.00 * p.y).sin() * (1.00 * p.z).sin()
 }
+
+// Bound of the relative rounding error of `n` f32 operations in a row, the gamma_n of PBRT:
+// the result of n operations is within (1 +- gamma(n)) of the exact one
+pub fn gamma(n: u32) -> f32 {
+    let eps = f32::EPSILON * 0.5;
+    n as f32 * eps / (1.0 - n as f32 * eps)
+}
+
+// the closest f32 above `x`
+pub fn next_float_up(x: f32) -> f32 {
+    if x.is_infinite() && x > 0.0 {
+        return x;
+    }
+    let x = if x == -0.0 { 0.0 } else { x };
+    let bits = x.to_bits();
+    f32::from_bits(if x >= 0.0 { bits + 1 } else { bits - 1 })
+}
+
+// the closest f32 below `x`
+pub fn next_float_down(x: f32) -> f32 {
+    -next_float_up(-x)
+}
+
+// Roots of a * t^2 + b * t + c, the smaller one first. The discriminant is computed in f64, where
+// the products of f32 are exact, so it doesn't fall below zero for rays grazing a surface
+pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let (a64, b64, c64) = (a as f64, b as f64, c as f64);
+    let disc = b64 * b64 - 4.0 * a64 * c64;
+    if disc < 0.0 {
+        return None;
+    }
+    quadratic_roots(a, b, c, disc.sqrt() as f32)
+}
+
+// The roots from the square root of the discriminant, for the callers who know a more precise one.
+// Kahan's form: -b +- sqrt(disc) subtracts close numbers for one of the roots, q never does
+pub fn quadratic_roots(a: f32, b: f32, c: f32, sqrt_disc: f32) -> Option<(f32, f32)> {
+    if a == 0.0 {
+        return if b == 0.0 { None } else { Some((-c / b, -c / b)) };
+    }
+    let q = if b < 0.0 { -0.5 * (b - sqrt_disc) } else { -0.5 * (b + sqrt_disc) };
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    Some(if t0 <= t1 { (t0, t1) } else { (t1, t0) })
+}
+
+// A value and the range the exact one is known to be in, the bounds are rounded outwards so the
+// range stays conservative through the operations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub low: f32,
+    pub high: f32,
+}
+
+impl Interval {
+    pub fn new(a: f32, b: f32) -> Interval {
+        Interval { low: a.min(b), high: a.max(b) }
+    }
+
+    pub fn point(x: f32) -> Interval {
+        Interval { low: x, high: x }
+    }
+
+    // `x` which can be off by `error`
+    pub fn with_error(x: f32, error: f32) -> Interval {
+        Interval { low: next_float_down(x - error.abs()), high: next_float_up(x + error.abs()) }
+    }
+
+    // dot product computed in f32 and its rounding error
+    pub fn dot(a: &Vec3f, b: &Vec3f) -> Interval {
+        let abs_dot = a.x.abs() * b.x.abs() + a.y.abs() * b.y.abs() + a.z.abs() * b.z.abs();
+        Interval::with_error(a.dot(b), gamma(3) * abs_dot)
+    }
+
+    pub fn mid(&self) -> f32 {
+        0.5 * (self.low + self.high)
+    }
+
+    pub fn width(&self) -> f32 {
+        self.high - self.low
+    }
+
+    pub fn contains(&self, x: f32) -> bool {
+        x >= self.low && x <= self.high
+    }
+
+    pub fn sqrt(&self) -> Interval {
+        Interval { low: next_float_down(self.low.max(0.0).sqrt()).max(0.0), high: next_float_up(self.high.max(0.0).sqrt()) }
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+
+    fn add(self, other: Interval) -> Interval {
+        Interval { low: next_float_down(self.low + other.low), high: next_float_up(self.high + other.high) }
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+
+    fn sub(self, other: Interval) -> Interval {
+        Interval { low: next_float_down(self.low - other.high), high: next_float_up(self.high - other.low) }
+    }
+}
+
+impl Neg for Interval {
+    type Output = Interval;
+
+    fn neg(self) -> Interval {
+        Interval { low: -self.high, high: -self.low }
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, other: Interval) -> Interval {
+        let products = [self.low * other.low, self.low * other.high, self.high * other.low, self.high * other.high];
+        Interval {
+            low: next_float_down(products.iter().fold(f32::INFINITY, |min, &p| min.min(p))),
+            high: next_float_up(products.iter().fold(f32::NEG_INFINITY, |max, &p| max.max(p))),
+        }
+    }
+}
+
+impl Div for Interval {
+    type Output = Interval;
+
+    // anything, if the divisor can be zero
+    fn div(self, other: Interval) -> Interval {
+        if other.contains(0.0) {
+            return Interval { low: f32::NEG_INFINITY, high: f32::INFINITY };
+        }
+        self * Interval { low: 1.0 / other.high, high: 1.0 / other.low }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Interval, gamma, next_float_down, next_float_up, solve_quadratic};
+
+    #[test]
+    fn quadratic_roots_keep_their_precision() {
+        let (t0, t1) = solve_quadratic(1.0, -3.0, 2.0).unwrap();
+        assert_eq!((t0, t1), (1.0, 2.0));
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_none());
+        // the small root of t^2 - 1e4 t + 1 is 1e-4; -b - sqrt(disc) in f32 cancels it to 0
+        let (small, large) = solve_quadratic(1.0, -1e4, 1.0).unwrap();
+        assert!((small - 1e-4).abs() < 1e-9 && (large - 1e4).abs() < 1e-1, "{} {}", small, large);
+        let naive = (1e4f32 - (1e8f32 - 4.0).sqrt()) * 0.5;
+        assert!((naive - 1e-4).abs() > 1e-5);
+        // a double root computed in f32 gets a negative discriminant: (1 + 1e-4)^2 isn't an f32
+        let b = -2.0 * 1.0001f32;
+        assert!(solve_quadratic(1.0, b, b * b * 0.25).is_some());
+        assert_eq!(solve_quadratic(0.0, 2.0, -4.0), Some((2.0, 2.0)));
+    }
+
+    #[test]
+    fn intervals_hold_the_exact_result() {
+        assert!(next_float_up(1.0) > 1.0 && next_float_down(1.0) < 1.0);
+        assert!(next_float_up(-0.0) > 0.0 && next_float_down(0.0) < 0.0);
+        assert!(gamma(3) > 3.0 * ::std::f32::EPSILON * 0.5);
+
+        // 0.1 isn't an f32, sums of it drift; the interval keeps the exact 1 inside
+        let tenth = Interval::with_error(0.1, 0.1 * gamma(1));
+        let sum = (0..10).fold(Interval::point(0.0), |sum, _| sum + tenth);
+        assert!(sum.contains(1.0) && sum.width() < 1e-5, "{:?}", sum);
+        let product = (tenth - Interval::point(1.0)) * Interval::new(2.0, -3.0);
+        assert!(product.contains(-0.9 * 2.0) && product.contains(0.9 * 3.0));
+        assert!((Interval::point(1.0) / Interval::new(-1.0, 1.0)).contains(1e30));
+        assert!(Interval::new(4.0, 9.0).sqrt().contains(2.0) && Interval::new(4.0, 9.0).sqrt().contains(3.0));
+    }
+}
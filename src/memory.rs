@@ -0,0 +1,156 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryCategory {
+    Geometry = 0,
+    Framebuffer = 1,
+    Texture = 2,
+    PhotonMap = 3,
+}
+
+const CATEGORIES_NB: usize = 4;
+
+pub struct MemoryBudget {
+    budget: AtomicUsize,
+    used: [AtomicUsize; CATEGORIES_NB],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfBudget {
+    pub category: MemoryCategory,
+    pub requested: usize,
+    pub used: usize,
+    pub budget: usize,
+}
+
+// bytes accounted in a budget until it's dropped
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    owner: &'a MemoryBudget,
+    category: MemoryCategory,
+    bytes: usize,
+}
+
+static GLOBAL_BUDGET: MemoryBudget = MemoryBudget {
+    budget: AtomicUsize::new(usize::MAX),
+    used: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+};
+
+// process wide budget, unlimited until set_budget() is called, by `--memory-budget` or the config
+pub fn global() -> &'static MemoryBudget {
+    &GLOBAL_BUDGET
+}
+
+impl MemoryBudget {
+    pub fn new(budget: usize) -> MemoryBudget {
+        MemoryBudget {
+            budget: AtomicUsize::new(budget),
+            used: [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)],
+        }
+    }
+
+    pub fn set_budget(&self, bytes: usize) {
+        self.budget.store(bytes, Ordering::SeqCst);
+    }
+
+    pub fn get_budget(&self) -> usize {
+        self.budget.load(Ordering::SeqCst)
+    }
+
+    pub fn used(&self, category: MemoryCategory) -> usize {
+        self.used[category as usize].load(Ordering::SeqCst)
+    }
+
+    pub fn total_used(&self) -> usize {
+        self.used.iter().fold(0, |sum, used| sum + used.load(Ordering::SeqCst))
+    }
+
+    pub fn reserve<'a>(&'a self, category: MemoryCategory, bytes: usize)
+        -> Result<Reservation<'a>, OutOfBudget> {
+        let mut reservation = self.empty_reservation(category);
+        reservation.grow(bytes).map(|_| reservation)
+    }
+
+    pub fn empty_reservation<'a>(&'a self, category: MemoryCategory) -> Reservation<'a> {
+        Reservation { owner: self, category: category, bytes: 0 }
+    }
+
+    fn try_add(&self, category: MemoryCategory, bytes: usize) -> Result<(), OutOfBudget> {
+        let counter = &self.used[category as usize];
+        counter.fetch_add(bytes, Ordering::SeqCst);
+        // other categories may grow concurrently, so the total is checked after the fact
+        let total = self.total_used();
+        let budget = self.get_budget();
+        if total > budget {
+            counter.fetch_sub(bytes, Ordering::SeqCst);
+            Err(OutOfBudget {
+                category: category,
+                requested: bytes,
+                used: total - bytes,
+                budget: budget,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn sub(&self, category: MemoryCategory, bytes: usize) {
+        self.used[category as usize].fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+impl<'a> Reservation<'a> {
+    pub fn grow(&mut self, bytes: usize) -> Result<(), OutOfBudget> {
+        self.owner.try_add(self.category, bytes).map(|_| self.bytes += bytes)
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.owner.sub(self.category, self.bytes);
+    }
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryBudget {{ used: {}, budget: {} }}", self.total_used(), self.get_budget())
+    }
+}
+
+impl fmt::Display for OutOfBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "can't reserve {} bytes of {:?} memory: {} of {} bytes are already in use",
+               self.requested, self.category, self.used, self.budget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryBudget, MemoryCategory};
+
+    #[test]
+    fn reservation_is_released_on_drop() {
+        let budget = MemoryBudget::new(100);
+        {
+            let _r = budget.reserve(MemoryCategory::Geometry, 60).unwrap();
+            assert_eq!(budget.used(MemoryCategory::Geometry), 60);
+        }
+        assert_eq!(budget.total_used(), 0);
+    }
+
+    #[test]
+    fn budget_is_shared_between_categories() {
+        let budget = MemoryBudget::new(100);
+        let _geo = budget.reserve(MemoryCategory::Geometry, 60).unwrap();
+        let err = budget.reserve(MemoryCategory::Texture, 50).unwrap_err();
+        assert_eq!(err.used, 60);
+        assert_eq!(budget.used(MemoryCategory::Texture), 0);
+        assert!(budget.reserve(MemoryCategory::Texture, 40).is_ok());
+    }
+}
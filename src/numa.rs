@@ -0,0 +1,226 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// How the rendering threads are spread over the cpus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadPinning {
+    Off, // the OS moves them around
+    Compact, // fill a node before the next, keeps a small render on one socket and its memory
+    Spread, // one node after another, every socket gets a share of the threads and of the memory bandwidth
+}
+
+// The nodes of the machine and the cpus of each, from /sys/devices/system/node. Machines without NUMA
+// (or without sysfs) are a single node with all the cpus
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaTopology {
+    pub nodes: Vec<NumaNode>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    pub id: usize, // the N of /sys/devices/system/node/nodeN, ids can have gaps
+    pub cpus: Vec<usize>,
+}
+
+impl NumaTopology {
+    pub fn detect() -> Option<NumaTopology> {
+        let mut nodes = fs::read_dir("/sys/devices/system/node").ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let node_nb = name.trim_start_matches("node").parse::<usize>().ok().filter(|_| name.starts_with("node"))?;
+                let cpus = fs::read_to_string(entry.path().join("cpulist")).ok().and_then(|list| parse_cpu_list(&list))?;
+                Some(NumaNode { id: node_nb, cpus: cpus })
+            })
+            .filter(|node| !node.cpus.is_empty())
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| node.id);
+        if nodes.is_empty() { None } else { Some(NumaTopology { nodes: nodes }) }
+    }
+
+    pub fn nodes_nb(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // the order the threads take the cpus in
+    pub fn cpu_order(&self, pinning: ThreadPinning) -> Vec<usize> {
+        match pinning {
+            ThreadPinning::Off     => Vec::new(),
+            ThreadPinning::Compact => self.nodes.iter().flat_map(|node| node.cpus.iter().cloned()).collect(),
+            ThreadPinning::Spread  => {
+                let most = self.nodes.iter().map(|node| node.cpus.len()).max().unwrap_or(0);
+                (0..most).flat_map(|i| self.nodes.iter().filter_map(move |node| node.cpus.get(i).cloned())).collect()
+            }
+        }
+    }
+
+    // the nodes as the bits of the words of an mbind mask
+    pub fn node_mask(&self) -> Vec<u64> {
+        let words = self.nodes.iter().map(|node| node.id / 64 + 1).max().unwrap_or(1);
+        let mut mask = vec![0; words];
+        for node in &self.nodes {
+            mask[node.id / 64] |= 1 << (node.id % 64);
+        }
+        mask
+    }
+}
+
+// "0-3,8,10-11" as in the cpulist files
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first = bounds.next()?.trim().parse::<usize>().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.trim().parse::<usize>().ok()?,
+            None       => first
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..last + 1);
+    }
+    Some(cpus)
+}
+
+// Where the threads of the screen loops run and where the scene data lives. Nothing is placed until
+// `configure` is called, see `--pin-threads` and `--numa-interleave` of the binary. Both are best effort,
+// where the OS refuses the render runs as it would without them
+pub struct NumaPlacement {
+    pinning: AtomicUsize, // ThreadPinning as usize
+    interleave: AtomicBool,
+    next_thread: AtomicUsize,
+    topology: Mutex<Option<NumaTopology>>, // detected once by `configure`
+}
+
+static GLOBAL_PLACEMENT: NumaPlacement = NumaPlacement {
+    pinning: AtomicUsize::new(0),
+    interleave: AtomicBool::new(false),
+    next_thread: AtomicUsize::new(0),
+    topology: Mutex::new(None),
+};
+
+thread_local!(static PINNED: Cell<bool> = Cell::new(false));
+
+// process wide placement, the screen loops pin their threads with it and the meshes place their data
+pub fn global() -> &'static NumaPlacement {
+    &GLOBAL_PLACEMENT
+}
+
+impl NumaPlacement {
+    // once, before the scene is built and the render starts
+    pub fn configure(&self, pinning: ThreadPinning, interleave: bool) {
+        let topology = if pinning != ThreadPinning::Off || interleave { NumaTopology::detect() } else { None };
+        *self.topology.lock().unwrap() = topology;
+        self.pinning.store(pinning as usize, Ordering::SeqCst);
+        self.interleave.store(interleave, Ordering::SeqCst);
+    }
+
+    pub fn pinning(&self) -> ThreadPinning {
+        match self.pinning.load(Ordering::SeqCst) {
+            1 => ThreadPinning::Compact,
+            2 => ThreadPinning::Spread,
+            _ => ThreadPinning::Off,
+        }
+    }
+
+    pub fn interleaves(&self) -> bool {
+        self.interleave.load(Ordering::SeqCst)
+    }
+
+    // The first call on a thread binds it to the next cpu of the order, later calls do nothing. The
+    // pool's threads are never told apart, so the loops call it at every tile
+    pub fn pin_current_thread(&self) {
+        let pinning = self.pinning();
+        if pinning == ThreadPinning::Off || PINNED.with(|pinned| pinned.replace(true)) {
+            return;
+        }
+        let thread_nb = self.next_thread.fetch_add(1, Ordering::SeqCst);
+        let order = self.topology.lock().unwrap().as_ref().map(|topology| topology.cpu_order(pinning)).unwrap_or_default();
+        if !order.is_empty() {
+            // a cpu taken offline leaves the thread where it was
+            let _ = set_affinity(order[thread_nb % order.len()]);
+        }
+    }
+
+    // Spreads the pages of `data` over the memory of all the nodes. The meshes are read by every thread,
+    // in one node's memory the threads of the other sockets queue on its controller and its link
+    pub fn place_shared<T>(&self, data: &[T]) {
+        if !self.interleaves() {
+            return;
+        }
+        if let Some(ref topology) = *self.topology.lock().unwrap() {
+            if topology.nodes_nb() > 1 {
+                let _ = interleave_pages(data.as_ptr() as usize, data.len() * mem::size_of::<T>(), &topology.node_mask());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(cpu: usize) -> io::Result<()> {
+    unsafe {
+        let mut set = mem::zeroed::<::libc::cpu_set_t>();
+        ::libc::CPU_SET(cpu, &mut set);
+        if ::libc::sched_setaffinity(0, mem::size_of::<::libc::cpu_set_t>(), &set) == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(_: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "thread pinning needs linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn interleave_pages(addr: usize, bytes: usize, mask: &[u64]) -> io::Result<()> {
+    const MPOL_INTERLEAVE: usize = 3;
+    const MPOL_MF_MOVE: usize = 1 << 1;
+    let page = unsafe { ::libc::sysconf(::libc::_SC_PAGESIZE) } as usize;
+    // only the whole pages of the range, the ones it shares with other data stay where they are
+    let start = (addr + page - 1) / page * page;
+    let end = (addr + bytes) / page * page;
+    if end <= start {
+        return Ok(());
+    }
+    // the kernel reads one bit less than maxnode
+    let max_node = mask.len() * 64 + 1;
+    let res = unsafe {
+        ::libc::syscall(::libc::SYS_mbind, start, end - start, MPOL_INTERLEAVE, mask.as_ptr(), max_node, MPOL_MF_MOVE)
+    };
+    if res == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interleave_pages(_: usize, _: usize, _: &[u64]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "NUMA placement needs linux"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_list, NumaNode, NumaTopology, ThreadPinning};
+
+    #[test]
+    fn threads_take_the_cpus_by_node() {
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list(""), Some(vec![]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+
+        let topology = NumaTopology { nodes: vec![NumaNode { id: 0, cpus: vec![0, 1, 2] }, NumaNode { id: 1, cpus: vec![4, 5] }] };
+        assert_eq!(topology.cpu_order(ThreadPinning::Compact), vec![0, 1, 2, 4, 5]);
+        assert_eq!(topology.cpu_order(ThreadPinning::Spread), vec![0, 4, 1, 5, 2]);
+        assert!(topology.cpu_order(ThreadPinning::Off).is_empty());
+        assert_eq!(topology.node_mask(), vec![0b11]);
+        // the ids of the nodes, not their count
+        let sparse = NumaTopology { nodes: vec![NumaNode { id: 1, cpus: vec![0] }, NumaNode { id: 65, cpus: vec![1] }] };
+        assert_eq!(sparse.node_mask(), vec![0b10, 0b10]);
+    }
+}
@@ -0,0 +1,223 @@
+use brdf::{Conductor, Microfacet, MicrofacetDistribution};
+use camera::PerspectiveCamera;
+use math::Vec3f;
+use scene::{LightID, MaterialID, Scene};
+use std::fmt;
+use std::str::FromStr;
+
+// One `key=value` setting applied over the scene as it was built, so scripts can sweep a parameter without
+// editing the scene. The keys:
+//   camera.fov (degrees), camera.position (x,y,z)
+//   lights.N.intensity (s or r,g,b), N is the light index or "background" (light 0)
+//   materials.N.diffuse, materials.N.specular (s or r,g,b), materials.N.phong_exp,
+//   materials.N.roughness (turns the phong lobe into a GGX one), materials.N.distribution (ggx or beckmann)
+//   materials.N.metal (reflectance at normal incidence, r,g,b), materials.N.edge_tint (r,g,b, of a metal)
+//   scene.seed
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamOverride {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverrideError {
+    Syntax(String), // not a `key=value`
+    UnknownKey(String),
+    BadValue { key: String, value: String },
+    NoSuchObject(String), // the light or the material index is out of the scene
+    Unsupported(String), // the light has no intensity to set, the material no microfacet lobe or no metal
+}
+
+impl FromStr for ParamOverride {
+    type Err = OverrideError;
+
+    fn from_str(arg: &str) -> Result<ParamOverride, OverrideError> {
+        match arg.find('=') {
+            Some(pos) if pos > 0 => Ok(ParamOverride { key: arg[..pos].trim().to_string(), value: arg[pos + 1..].trim().to_string() }),
+            _ => Err(OverrideError::Syntax(arg.to_string()))
+        }
+    }
+}
+
+impl ParamOverride {
+    pub fn apply<S: Scene>(&self, scene: &mut S, camera: &mut PerspectiveCamera) -> Result<(), OverrideError> {
+        let path = self.key.split('.').collect::<Vec<_>>();
+        match path.as_slice() {
+            ["camera", "fov"] => {
+                camera.set_fov(self.scalar()?);
+            },
+            ["camera", "position"] => {
+                camera.set_position(&self.vector()?);
+            },
+            ["lights", light, "intensity"] => {
+                let light_id = match *light {
+                    "background" => 0,
+                    _            => self.index(light, scene.get_lights_nb())?
+                };
+                if !scene.set_light_intensity(light_id as LightID, self.vector()?) {
+                    return Err(OverrideError::Unsupported(self.key.clone()));
+                }
+            },
+            ["materials", material, field] => {
+                let m_id = self.index(material, scene.get_materials_nb())? as MaterialID;
+                match *field {
+                    "diffuse"   => { let color = self.vector()?; scene.edit_material(m_id, |m| m.diffuse = color); },
+                    "specular"  => { let color = self.vector()?; scene.edit_material(m_id, |m| m.specular = color); },
+                    "phong_exp" => { let exp = self.scalar()?; scene.edit_material(m_id, |m| m.phong_exp = exp); },
+                    "roughness" => {
+                        let roughness = self.scalar()?;
+                        // the lobe keeps its distribution, a material without one gets a GGX lobe
+                        scene.edit_material(m_id, |m| match m.microfacet {
+                            Some(ref mut microfacet) => microfacet.roughness = roughness,
+                            None                     => m.microfacet = Some(Microfacet::ggx(roughness))
+                        });
+                    },
+                    "distribution" => {
+                        let distribution = match self.value.as_str() {
+                            "ggx"      => MicrofacetDistribution::Ggx,
+                            "beckmann" => MicrofacetDistribution::Beckmann,
+                            _          => return Err(self.bad_value())
+                        };
+                        // only a microfacet lobe has a distribution to pick, the roughness makes one
+                        if scene.get_material(m_id).microfacet.is_none() {
+                            return Err(OverrideError::Unsupported(self.key.clone()));
+                        }
+                        scene.edit_material(m_id, |m| if let Some(ref mut microfacet) = m.microfacet {
+                            microfacet.distribution = distribution;
+                        });
+                    },
+                    "metal" => {
+                        let reflectance = self.vector()?;
+                        let conductor = Conductor::from_reflectance(reflectance, Vec3f::new(1.0, 1.0, 1.0));
+                        scene.edit_material(m_id, |m| m.conductor = Some(conductor));
+                    },
+                    "edge_tint" => {
+                        let edge_tint = self.vector()?;
+                        // the metal keeps its color head-on
+                        let reflectance = match scene.get_material(m_id).conductor {
+                            Some(ref conductor) => conductor.fresnel(1.0),
+                            None                => return Err(OverrideError::Unsupported(self.key.clone()))
+                        };
+                        let conductor = Conductor::from_reflectance(reflectance, edge_tint);
+                        scene.edit_material(m_id, |m| m.conductor = Some(conductor));
+                    },
+                    _           => return Err(OverrideError::UnknownKey(self.key.clone()))
+                }
+            },
+            ["scene", "seed"] => {
+                let seed = self.value.parse().map_err(|_| self.bad_value())?;
+                scene.set_seed(seed);
+            },
+            _ => return Err(OverrideError::UnknownKey(self.key.clone()))
+        }
+        Ok(())
+    }
+
+    fn bad_value(&self) -> OverrideError {
+        OverrideError::BadValue { key: self.key.clone(), value: self.value.clone() }
+    }
+
+    fn scalar(&self) -> Result<f32, OverrideError> {
+        self.value.parse().map_err(|_| self.bad_value())
+    }
+
+    // one number for all three components or three of them
+    fn vector(&self) -> Result<Vec3f, OverrideError> {
+        let comps = self.value.split(',').map(|comp| comp.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|_| self.bad_value())?;
+        match comps.as_slice() {
+            [s]       => Ok(Vec3f::new(*s, *s, *s)),
+            [x, y, z] => Ok(Vec3f::new(*x, *y, *z)),
+            _         => Err(self.bad_value())
+        }
+    }
+
+    fn index(&self, index: &str, count: usize) -> Result<usize, OverrideError> {
+        match index.parse::<usize>() {
+            Ok(index) if index < count => Ok(index),
+            Ok(_)                      => Err(OverrideError::NoSuchObject(self.key.clone())),
+            Err(_)                     => Err(OverrideError::UnknownKey(self.key.clone()))
+        }
+    }
+}
+
+impl fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OverrideError::Syntax(ref arg) => write!(f, "{:?} isn't a key=value", arg),
+            OverrideError::UnknownKey(ref key) => write!(f, "unknown key {:?}", key),
+            OverrideError::BadValue { ref key, ref value } => write!(f, "{:?} isn't a value of {:?}", value, key),
+            OverrideError::NoSuchObject(ref key) => write!(f, "{:?} is out of the scene", key),
+            OverrideError::Unsupported(ref key) => write!(f, "{:?} can't be set on this object", key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OverrideError, ParamOverride};
+    use brdf::Microfacet;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use math::vector_traits::*;
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn overrides_set_the_scene_values() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        let mut cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(64, 64))
+            .with_pos(Vec3f::new(0.0, 0.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let point = Vec3f::new(0.5, 0.0, 0.0);
+        let before = cam.world_to_raster(&point).unwrap();
+
+        let set = |arg: &str, scene: &mut DefaultScene<GeometryList>, cam: &mut PerspectiveCamera| {
+            arg.parse::<ParamOverride>().and_then(|param| param.apply(scene, cam))
+        };
+        set("lights.1.intensity=5", &mut scene, &mut cam).unwrap();
+        set("lights.background.intensity = 0.1, 0.2, 0.3", &mut scene, &mut cam).unwrap();
+        set("materials.0.diffuse=0.5,0.25,0", &mut scene, &mut cam).unwrap();
+        set("scene.seed=7", &mut scene, &mut cam).unwrap();
+        set("camera.fov=20", &mut scene, &mut cam).unwrap();
+        assert_eq!(scene.get_light(1).intensity(), Some(Vec3f::new(5.0, 5.0, 5.0)));
+        assert_eq!(scene.get_light(0).intensity(), Some(Vec3f::new(0.1, 0.2, 0.3)));
+        assert_eq!(scene.get_material(0).diffuse, Vec3f::new(0.5, 0.25, 0.0));
+        assert_eq!(scene.get_seed(), 7);
+        // a narrower view magnifies
+        let after = cam.world_to_raster(&point).unwrap();
+        assert!((after.x - 32.0).abs() > (before.x - 32.0).abs() * 1.5, "{:?} {:?}", before, after);
+        // and moving away shrinks
+        set("camera.position=0,0,-10", &mut scene, &mut cam).unwrap();
+        assert_eq!(cam.get_position(), Vec3f::new(0.0, 0.0, -10.0));
+        assert!((cam.world_to_raster(&point).unwrap().x - 32.0).abs() < (after.x - 32.0).abs());
+
+        assert_eq!(set("camera.fov", &mut scene, &mut cam), Err(OverrideError::Syntax("camera.fov".to_string())));
+        assert_eq!(set("camera.zoom=2", &mut scene, &mut cam), Err(OverrideError::UnknownKey("camera.zoom".to_string())));
+        assert_eq!(set("lights.2.intensity=1", &mut scene, &mut cam),
+                   Err(OverrideError::NoSuchObject("lights.2.intensity".to_string())));
+        assert_eq!(set("materials.0.diffuse=1,2", &mut scene, &mut cam),
+                   Err(OverrideError::BadValue { key: "materials.0.diffuse".to_string(), value: "1,2".to_string() }));
+
+        // the edge tint bends a metal, which keeps its color head-on
+        assert_eq!(set("materials.0.edge_tint=1,0.5,0.5", &mut scene, &mut cam),
+                   Err(OverrideError::Unsupported("materials.0.edge_tint".to_string())));
+        set("materials.0.metal=0.9,0.6,0.3", &mut scene, &mut cam).unwrap();
+        set("materials.0.edge_tint=1,0.5,0.5", &mut scene, &mut cam).unwrap();
+        let head_on = scene.get_material(0).conductor.unwrap().fresnel(1.0);
+        assert!((head_on - Vec3f::new(0.9, 0.6, 0.3)).norm() < 1e-3, "{:?}", head_on);
+
+        // the roughness makes a GGX lobe once, then only changes the lobe there is
+        set("materials.0.roughness=0.5", &mut scene, &mut cam).unwrap();
+        assert_eq!(scene.get_material(0).microfacet, Some(Microfacet::ggx(0.5)));
+        set("materials.0.distribution=beckmann", &mut scene, &mut cam).unwrap();
+        set("materials.0.roughness=0.2", &mut scene, &mut cam).unwrap();
+        assert_eq!(scene.get_material(0).microfacet, Some(Microfacet::beckmann(0.2)));
+    }
+}
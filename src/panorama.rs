@@ -0,0 +1,187 @@
+use camera::{Camera, CameraBuilder, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use math::vector_traits::*;
+use math::{Vec2f, Vec2u, Vec3f, Zero};
+use render::Render;
+use scene::Scene;
+
+// a tile camera must stay well below 180 degrees wide
+const MAX_TILE_FOV: f32 = 120.0;
+
+// Panoramas wider than a perspective camera can see: the view is split into tiles around the
+// up axis, every tile is rendered with its own perspective camera a bit wider than its share,
+// and the tiles are resampled into a cylindrical projection, blended where they overlap.
+// Vertical lines stay straight and nothing is stretched towards the sides, unlike with a fisheye
+#[derive(Debug, Clone)]
+pub struct Panorama {
+    pub position: Vec3f,
+    pub dir: Vec3f, // of the center of the panorama
+    pub up: Vec3f,
+    pub horizontal_fov: f32, // degrees, up to 360
+    pub vertical_fov: f32, // degrees, at the center of every tile
+    pub resolution: Vec2u,
+    pub tiles_nb: usize,
+    pub overlap: f32, // of the tile's share, on every side
+}
+
+impl Panorama {
+    pub fn new(position: Vec3f, dir: Vec3f, horizontal_fov: f32, resolution: Vec2u) -> Panorama {
+        let mut panorama = Panorama {
+            position: position,
+            dir: dir,
+            up: Vec3f::new(0.0, 1.0, 0.0),
+            horizontal_fov: horizontal_fov.min(360.0),
+            vertical_fov: 45.0,
+            resolution: resolution,
+            tiles_nb: 1,
+            overlap: 0.1,
+        };
+        panorama.tiles_nb = panorama.min_tiles_nb();
+        panorama
+    }
+
+    pub fn with_up(mut self, up: Vec3f) -> Panorama {
+        self.up = up;
+        self
+    }
+
+    #[cfg(test)]
+    // keeps the tiles narrow enough
+    pub fn with_vertical_fov(mut self, vertical_fov: f32) -> Panorama {
+        self.vertical_fov = vertical_fov;
+        self.tiles_nb = self.tiles_nb.max(self.min_tiles_nb());
+        self
+    }
+
+    fn min_tiles_nb(&self) -> usize {
+        (self.horizontal_fov * (1.0 + 2.0 * self.overlap) / MAX_TILE_FOV).ceil().max(1.0) as usize
+    }
+
+    // the forward and right axes of the panorama's center, orthogonal to the up one
+    fn basis(&self) -> (Vec3f, Vec3f, Vec3f) {
+        let up = self.up.normalize();
+        let forward = (self.dir - up * self.dir.dot(&up)).normalize();
+        (forward, up.cross(&forward), up)
+    }
+
+    // direction of the point of the panorama at `raster`, the first row is at the top
+    pub fn raster_to_dir(&self, raster: &Vec2f) -> Vec3f {
+        let (forward, right, up) = self.basis();
+        let azimuth = (raster.x / self.resolution.x as f32 - 0.5) * self.horizontal_fov.to_radians();
+        // on the unit cylinder
+        let height = (0.5 - raster.y / self.resolution.y as f32) * 2.0 * (self.vertical_fov.to_radians() * 0.5).tan();
+        (forward * azimuth.cos() + right * azimuth.sin() + up * height).normalize()
+    }
+
+    // Cameras of the tiles, from the left to the right. The height of the cylinder shows in them as
+    // height / cos of the azimuth, so they're taller than `vertical_fov`, and as wide as the tiles
+    // are with the overlap; their pixels are as big as the panorama's at their centers
+    pub fn tile_cameras(&self) -> Vec<PerspectiveCamera> {
+        let (forward, right, up) = self.basis();
+        let share = self.horizontal_fov.to_radians() / self.tiles_nb as f32;
+        let half_width = share * (0.5 + self.overlap);
+        let half_height = ((self.vertical_fov.to_radians() * 0.5).tan() / half_width.cos()).atan();
+        let pixels_per_tan = self.resolution.x as f32 / self.horizontal_fov.to_radians();
+        let view_size = Vec2u::new((2.0 * half_width.tan() * pixels_per_tan).ceil() as usize,
+                                   (2.0 * half_height.tan() * pixels_per_tan).ceil() as usize);
+        (0..self.tiles_nb).map(|tile| {
+            let azimuth = (tile as f32 + 0.5) * share - self.horizontal_fov.to_radians() * 0.5;
+            CameraBuilder::<PerspectiveCamera>::new()
+                .with_pos(self.position)
+                .with_look_at(forward * azimuth.cos() + right * azimuth.sin())
+                .with_up(up)
+                .with_view_size(view_size)
+                .with_fov((2.0 * half_height).to_degrees())
+                .build()
+        }).collect()
+    }
+
+    // Renders every tile with `spp` samples per pixel and stitches them, the result is a frame of
+    // `spp` samples as well. `make_scene` builds the scene for the renderer of every tile
+    pub fn render<S, R, F>(&self, spp: usize, mut make_scene: F) -> RgbFrameBuffer
+        where S: Scene, R: Render<S>, F: FnMut() -> S {
+        let cameras = self.tile_cameras();
+        let tiles = cameras.iter().map(|camera| {
+            let mut tile = camera.build_rgb_framebuffer();
+            R::new(camera.clone(), make_scene()).iterate(1, spp, &mut tile);
+            tile
+        }).collect::<Vec<_>>();
+        self.stitch(&cameras, &tiles)
+    }
+
+    // Every pixel of the panorama is looked up in the tiles which see it, they're weighted by the
+    // distance to their left and right edges so the seams fade from one tile into the other
+    pub fn stitch(&self, cameras: &[PerspectiveCamera], tiles: &[RgbFrameBuffer]) -> RgbFrameBuffer {
+        let mut frame = RgbFrameBuffer::new(self.resolution);
+        let res_x = self.resolution.x;
+        for (pix_nb, pix) in frame.as_mut_slice().iter_mut().enumerate() {
+            let raster = Vec2f::new((pix_nb % res_x) as f32 + 0.5, (pix_nb / res_x) as f32 + 0.5);
+            let point = self.position + self.raster_to_dir(&raster);
+            let (color, weight) = cameras.iter().zip(tiles.iter())
+                .filter_map(|(camera, tile)| camera.world_to_raster(&point).map(|pos| (pos, tile)))
+                .fold((Vec3f::zero(), 0.0), |(color, weight), (pos, tile)| {
+                    let w = pos.x.min(tile.resolution().x as f32 - pos.x).max(1e-3);
+                    (color + sample_bilinear(tile, &pos) * w, weight + w)
+                });
+            if weight > 0.0 {
+                *pix = color / weight;
+            }
+        }
+        frame
+    }
+}
+
+// between the pixel centers, the border pixels are extended
+fn sample_bilinear(frame: &RgbFrameBuffer, pos: &Vec2f) -> Vec3f {
+    let res = frame.resolution();
+    let (x, y) = (pos.x - 0.5, pos.y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |x: f32, y: f32| {
+        let x = (x.max(0.0) as usize).min(res.x - 1);
+        let y = (y.max(0.0) as usize).min(res.y - 1);
+        frame.as_slice()[y * res.x + x]
+    };
+    (pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1.0, y0) * fx) * (1.0 - fy)
+        + (pixel(x0, y0 + 1.0) * (1.0 - fx) + pixel(x0 + 1.0, y0 + 1.0) * fx) * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Panorama;
+    use camera::Camera;
+    use math::vector_traits::*;
+    use math::{Vec2f, Vec2u, Vec3f};
+
+    #[test]
+    fn tiles_stitch_into_the_cylinder() {
+        let panorama = Panorama::new(Vec3f::new(1.0, 2.0, 3.0), Vec3f::new(0.0, 0.0, 1.0), 270.0, Vec2u::new(270, 60))
+            .with_vertical_fov(60.0);
+        assert!(panorama.tiles_nb >= 3);
+        let cameras = panorama.tile_cameras();
+        // tiles "rendered" with a smooth function of the direction, the stitched frame has to show it
+        // in the right places, the seams included
+        let color = |dir: Vec3f| Vec3f::new(dir.x + 1.0, dir.y + 1.0, dir.z + 1.0);
+        let tiles = cameras.iter().map(|camera| {
+            let mut tile = camera.build_rgb_framebuffer();
+            let res = tile.resolution();
+            for (pix_nb, pix) in tile.as_mut_slice().iter_mut().enumerate() {
+                let raster = Vec2f::new((pix_nb % res.x) as f32 + 0.5, (pix_nb / res.x) as f32 + 0.5);
+                *pix = color(camera.ray_from_screen(&raster).dir);
+            }
+            tile
+        }).collect::<Vec<_>>();
+        let frame = panorama.stitch(&cameras, &tiles);
+        for (pix_nb, pix) in frame.as_slice().iter().enumerate() {
+            let raster = Vec2f::new((pix_nb % 270) as f32 + 0.5, (pix_nb / 270) as f32 + 0.5);
+            let expected = color(panorama.raster_to_dir(&raster));
+            assert!((*pix - expected).norm() < 0.02, "{:?} instead of {:?} at {:?}", pix, expected, raster);
+        }
+        // the center looks forward, and the panorama isn't mirrored or upside down compared to the tiles
+        assert!((panorama.raster_to_dir(&Vec2f::new(135.0, 30.0)) - Vec3f::new(0.0, 0.0, 1.0)).norm() < 1e-5);
+        let size = cameras[1].get_view_size();
+        let tile_dir = |x: f32, y: f32| cameras[1].ray_from_screen(&Vec2f::new(size.x * 0.5 + x, size.y * 0.5 + y)).dir;
+        assert!(panorama.raster_to_dir(&Vec2f::new(145.0, 30.0)).x * tile_dir(10.0, 0.0).x > 0.0);
+        assert!(panorama.raster_to_dir(&Vec2f::new(135.0, 10.0)).y * tile_dir(0.0, -10.0).y > 0.0);
+    }
+}
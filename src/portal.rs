@@ -0,0 +1,326 @@
+use geometry::{Aabb, Ray};
+use light::{Emission, Illumination, Light, LightShape, Radiation};
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+use scene::Scene;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use utility::uniform_sphere_sample;
+
+// Rectangle the sky shines through into an interior: a window or a door
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Portal {
+    pub corner: Vec3f,
+    pub edge_u: Vec3f,
+    pub edge_v: Vec3f, // perpendicular to edge_u
+}
+
+// The background light of an interior, sampled through its portals: from inside of a room only the
+// directions through the openings see the sky, the light samples of the whole sky mostly end on the walls.
+// Some of the samples still go to the sky's own sampling, so the directions no portal covers (a point
+// outdoors, an opening not found) keep their pdf and nothing is lost, only noisier
+#[derive(Debug)]
+pub struct PortalLight {
+    pub sky: Box<Light>,
+    pub portals: Vec<Portal>,
+    portal_prob: f32, // of sampling through the portals rather than the sky
+}
+
+// a cluster of the escaping probes thinner than this is a gap, not an opening
+const MIN_PORTAL_RAYS: usize = 4;
+// cells along the longest side of the room the openings are found on
+const PORTAL_GRID: f32 = 32.0;
+
+impl Portal {
+    pub fn new(corner: Vec3f, edge_u: Vec3f, edge_v: Vec3f) -> Portal {
+        Portal { corner: corner, edge_u: edge_u, edge_v: edge_v }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.edge_u.cross(&self.edge_v).norm()
+    }
+
+    pub fn normal(&self) -> Vec3f {
+        self.edge_u.cross(&self.edge_v).normalize()
+    }
+
+    pub fn center(&self) -> Vec3f {
+        self.corner + (self.edge_u + self.edge_v) * 0.5
+    }
+
+    // distance along the ray to the rectangle
+    pub fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let normal = self.normal();
+        let cos = ray.dir.dot(&normal);
+        if cos.abs() < 1e-8 {
+            return None;
+        }
+        let dist = (self.corner - ray.orig).dot(&normal) / cos;
+        let local = ray.orig + ray.dir * dist - self.corner;
+        let (u, v) = (local.dot(&self.edge_u) / self.edge_u.sqnorm(), local.dot(&self.edge_v) / self.edge_v.sqnorm());
+        if dist > 0.0 && u >= 0.0 && u <= 1.0 && v >= 0.0 && v <= 1.0 { Some(dist) } else { None }
+    }
+
+    // solid angle pdf of a uniform point on the rectangle seen from `p` along `dir`, `dist` away
+    fn pdf_w(&self, dir: &Vec3f, dist: f32) -> f32 {
+        let cos = dir.dot(&self.normal()).abs();
+        if cos < 1e-8 { 0.0 } else { dist * dist / (self.area() * cos) }
+    }
+
+    // about the solid angle of the rectangle from `p`, the portals are picked by it
+    fn weight(&self, p: &Vec3f) -> f32 {
+        let to_center = self.center() - *p;
+        let dist2 = to_center.sqnorm();
+        let cos = to_center.dot(&self.normal()).abs() / dist2.sqrt().max(1e-8);
+        self.area() * cos / dist2.max(self.area())
+    }
+}
+
+impl PortalLight {
+    pub fn new(sky: Box<Light>, portals: Vec<Portal>) -> PortalLight {
+        PortalLight { sky: sky, portals: portals, portal_prob: 0.8 }
+    }
+
+    pub fn with_portal_prob(mut self, portal_prob: f32) -> PortalLight {
+        self.portal_prob = portal_prob.max(0.0).min(1.0);
+        self
+    }
+
+    fn weights(&self, p: &Vec3f) -> (Vec<f32>, f32) {
+        let weights = self.portals.iter().map(|portal| portal.weight(p)).collect::<Vec<_>>();
+        let total = weights.iter().fold(0.0, |sum, w| sum + w);
+        (weights, total)
+    }
+
+    // pdf of `illuminate` for the direction of the ray, `sky_pdf` is the one of the sky's own sampling
+    fn pdf(&self, ray: &Ray, sky_pdf: f32) -> f32 {
+        let (weights, total) = self.weights(&ray.orig);
+        if !(total > 0.0) {
+            return sky_pdf;
+        }
+        let portals_pdf = self.portals.iter().zip(weights.iter()).fold(0.0, |pdf, (portal, w)| {
+            pdf + portal.intersect(ray).map_or(0.0, |dist| w / total * portal.pdf_w(&ray.dir, dist))
+        });
+        self.portal_prob * portals_pdf + (1.0 - self.portal_prob) * sky_pdf
+    }
+}
+
+impl Light for PortalLight {
+    fn radiate(&self, out_ray: &Ray) -> Option<Radiation> {
+        self.sky.radiate(out_ray).map(|rad| Radiation { radiance: rad.radiance, pdf: self.pdf(out_ray, rad.pdf) })
+    }
+
+    fn radiate_filtered(&self, out_ray: &Ray, spread: f32) -> Option<Radiation> {
+        self.sky.radiate_filtered(out_ray, spread)
+            .map(|rad| Radiation { radiance: rad.radiance, pdf: self.pdf(out_ray, rad.pdf) })
+    }
+
+    fn illuminate(&self, hit_pnt: &Vec3f, rnd: (f32, f32)) -> Option<Illumination> {
+        let (weights, total) = self.weights(hit_pnt);
+        let portal_prob = if total > 0.0 { self.portal_prob } else { 0.0 };
+        let (dir, sky) = if rnd.0 < portal_prob {
+            // the same number picks the portal and then the point on it
+            let mut pick = rnd.0 / portal_prob * total;
+            let mut chosen = self.portals.len() - 1;
+            for (idx, w) in weights.iter().enumerate() {
+                if pick < *w {
+                    chosen = idx;
+                    break;
+                }
+                pick -= *w;
+            }
+            let u = (pick / weights[chosen]).max(0.0).min(0.99999994);
+            let portal = &self.portals[chosen];
+            let dir = (portal.corner + portal.edge_u * u + portal.edge_v * rnd.1 - *hit_pnt).normalize();
+            let sky = self.sky.radiate(&Ray { orig: *hit_pnt, dir: dir })?;
+            (dir, sky)
+        } else {
+            let remapped = (rnd.0 - portal_prob) / (1.0 - portal_prob);
+            let illum = self.sky.illuminate(hit_pnt, (remapped.min(0.99999994), rnd.1))?;
+            (illum.l_dir, Radiation { radiance: illum.radiance, pdf: illum.pdf })
+        };
+        let pdf = self.pdf(&Ray { orig: *hit_pnt, dir: dir }, sky.pdf);
+        if !(pdf > 0.0) {
+            return None;
+        }
+        Some(Illumination { radiance: sky.radiance, l_dir: dir, l_dist: 1e38, pdf: pdf })
+    }
+
+    fn emit(&self, rnd: (f32, f32, f32, f32)) -> Option<Emission> {
+        self.sky.emit(rnd)
+    }
+
+    fn emission_pdf(&self, pos: &Vec3f, dir: &Vec3f) -> Option<(f32, f32)> {
+        self.sky.emission_pdf(pos, dir)
+    }
+
+    fn intensity(&self) -> Option<Vec3f> {
+        self.sky.intensity()
+    }
+
+    fn set_intensity(&mut self, intensity: Vec3f) -> bool {
+        self.sky.set_intensity(intensity)
+    }
+
+    // the sky is still at infinity, the outlines of the portals are drawn instead
+    fn shape(&self) -> Option<LightShape> {
+        let mut wire = Vec::with_capacity(4 * self.portals.len());
+        for portal in &self.portals {
+            let (a, b) = (portal.corner, portal.corner + portal.edge_u);
+            let (c, d) = (b + portal.edge_v, portal.corner + portal.edge_v);
+            wire.extend_from_slice(&[(a, b), (b, c), (c, d), (d, a)]);
+        }
+        if wire.is_empty() { None } else { Some(LightShape::Wire(wire)) }
+    }
+
+    fn power(&self, scene_radius: f32) -> Option<f32> {
+        self.sky.power(scene_radius)
+    }
+}
+
+// Finds the openings of the room around `probe`: `rays_nb` rays go from it in all directions, the ones
+// hitting something outline the room as a box, the ones escaping leave it through a side of the box.
+// Where they cross the sides they make clusters, the rectangle around every cluster is a portal. Only
+// boxy rooms give tight portals, anything else gives larger ones, which still cover the openings
+pub fn detect_portals<S: Scene>(scene: &S, probe: &Vec3f, rays_nb: usize) -> Vec<Portal> {
+    let side = (rays_nb as f32).sqrt().ceil().max(1.0) as usize;
+    let mut room = Aabb::empty();
+    let mut probes = Vec::with_capacity(side * side);
+    for i in 0..side * side {
+        let rnd = (((i % side) as f32 + 0.5) / side as f32, ((i / side) as f32 + 0.5) / side as f32);
+        let ray = Ray { orig: *probe, dir: uniform_sphere_sample(rnd) };
+        let hit = scene.nearest_intersection(&ray).map(|isect| ray.orig + ray.dir * isect.dist);
+        if let Some(hit) = hit {
+            room = room.grow(&hit);
+        }
+        probes.push((ray.dir, hit.is_some()));
+    }
+    // outdoors or in a closed room there's nothing to find
+    if room.is_empty() || probes.iter().all(|&(_, hit)| hit) {
+        return Vec::new();
+    }
+    let room = room.grow(probe);
+    let extent = room.diagonal();
+    let cell = extent.x.max(extent.y).max(extent.z) / PORTAL_GRID;
+
+    // where the rays cross the sides of the box, by the cell of the side; a cell is open if most of its rays
+    // escape, the few slipping between two triangles of a wall don't open it
+    let mut cells = HashMap::new();
+    for &(dir, hit) in &probes {
+        let (axis, dist) = (0..3).filter(|&axis| dir[axis] != 0.0).map(|axis| {
+            let bound = if dir[axis] > 0.0 { room.max[axis] } else { room.min[axis] };
+            (axis, (bound - probe[axis]) / dir[axis])
+        }).fold((0, ::std::f32::INFINITY), |nearest, exit| if exit.1 < nearest.1 { exit } else { nearest });
+        let exit = *probe + dir * dist;
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let key = (2 * axis + if dir[axis] > 0.0 { 1 } else { 0 },
+                   ((exit[u] - room.min[u]) / cell).floor() as i32,
+                   ((exit[v] - room.min[v]) / cell).floor() as i32);
+        let entry = cells.entry(key).or_insert((Vec::new(), 0));
+        if hit {
+            entry.1 += 1;
+        } else {
+            entry.0.push(exit);
+        }
+    }
+    let mut cells = cells.into_iter()
+        .filter(|&(_, (ref exits, hits))| exits.len() > hits)
+        .map(|(key, (exits, _))| (key, exits))
+        .collect::<HashMap<_, _>>();
+
+    // the cells touching each other are one opening
+    let mut portals = Vec::new();
+    while let Some(&start) = cells.keys().next() {
+        let (face, axis) = (start.0, start.0 / 2);
+        let mut stack = vec![start];
+        let mut points = Vec::new();
+        while let Some(key) = stack.pop() {
+            if let Some(exits) = cells.remove(&key) {
+                points.extend(exits);
+                let (_, a, b) = key;
+                stack.extend_from_slice(&[(face, a - 1, b), (face, a + 1, b), (face, a, b - 1), (face, a, b + 1)]);
+            }
+        }
+        if points.len() < MIN_PORTAL_RAYS {
+            continue;
+        }
+        // half the spacing of the probes around the exits
+        let far = points.iter().fold(0.0f32, |far, exit| far.max((*exit - *probe).norm()));
+        let bounds = Aabb::from_points(&points).expand(0.5 * far * (4.0 * PI / (side * side) as f32).sqrt());
+        let (min, max) = (bounds.min, bounds.max);
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let plane = if face % 2 == 1 { room.max[axis] } else { room.min[axis] };
+        let mut corner = Vec3f::zero();
+        let (mut edge_u, mut edge_v) = (Vec3f::zero(), Vec3f::zero());
+        corner[axis] = plane;
+        corner[u] = min[u].max(room.min[u]);
+        corner[v] = min[v].max(room.min[v]);
+        edge_u[u] = max[u].min(room.max[u]) - corner[u];
+        edge_v[v] = max[v].min(room.max[v]) - corner[v];
+        portals.push(Portal::new(corner, edge_u, edge_v));
+    }
+    portals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_portals, PortalLight};
+    use geometry::{GeometryList, Ray, Triangle};
+    use light::{BackgroundLight, Light};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::Vec3f;
+    use math::vector_traits::*;
+    use scene::{DefaultScene, Scene};
+
+    // a box room of side 10 with a window of 4 by 2 in the middle of its +z wall
+    fn room_with_window() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        {
+            let mut quad = |a: Vec3f, b: Vec3f, c: Vec3f, d: Vec3f| {
+                scene.add_object(Triangle::new(a, b, c), WHITE_DIFFUSE).unwrap();
+                scene.add_object(Triangle::new(a, c, d), WHITE_DIFFUSE).unwrap();
+            };
+            let p = |x: f32, y: f32, z: f32| Vec3f::new(x, y, z);
+            quad(p(-5.0, -5.0, -5.0), p(5.0, -5.0, -5.0), p(5.0, -5.0, 5.0), p(-5.0, -5.0, 5.0));
+            quad(p(-5.0, 5.0, -5.0), p(5.0, 5.0, -5.0), p(5.0, 5.0, 5.0), p(-5.0, 5.0, 5.0));
+            quad(p(-5.0, -5.0, -5.0), p(-5.0, 5.0, -5.0), p(-5.0, 5.0, 5.0), p(-5.0, -5.0, 5.0));
+            quad(p(5.0, -5.0, -5.0), p(5.0, 5.0, -5.0), p(5.0, 5.0, 5.0), p(5.0, -5.0, 5.0));
+            quad(p(-5.0, -5.0, -5.0), p(5.0, -5.0, -5.0), p(5.0, 5.0, -5.0), p(-5.0, 5.0, -5.0));
+            // the +z wall around the window
+            quad(p(-5.0, -5.0, 5.0), p(5.0, -5.0, 5.0), p(5.0, -1.0, 5.0), p(-5.0, -1.0, 5.0));
+            quad(p(-5.0, 1.0, 5.0), p(5.0, 1.0, 5.0), p(5.0, 5.0, 5.0), p(-5.0, 5.0, 5.0));
+            quad(p(-5.0, -1.0, 5.0), p(-2.0, -1.0, 5.0), p(-2.0, 1.0, 5.0), p(-5.0, 1.0, 5.0));
+            quad(p(2.0, -1.0, 5.0), p(5.0, -1.0, 5.0), p(5.0, 1.0, 5.0), p(2.0, 1.0, 5.0));
+        }
+        scene
+    }
+
+    #[test]
+    fn window_of_a_room_becomes_a_portal() {
+        let scene = room_with_window();
+        let portals = detect_portals(&scene, &Vec3f::new(0.5, 0.3, -1.0), 100000);
+        assert_eq!(portals.len(), 1, "{:?}", portals);
+        let portal = portals[0];
+        let far = portal.corner + portal.edge_u + portal.edge_v;
+        for &(got, expected) in [(portal.corner, Vec3f::new(-2.0, -1.0, 5.0)), (far, Vec3f::new(2.0, 1.0, 5.0))].iter() {
+            assert!((got - expected).norm() < 0.5, "{:?} {:?}", portal, expected);
+        }
+
+        // most of the light samples go through the window and their pdf is the one of the hits
+        let light = PortalLight::new(Box::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) }), portals);
+        let p = Vec3f::new(0.0, -4.0, 0.0);
+        let n = 64;
+        let mut through = 0;
+        for i in 0..n * n {
+            let rnd = (((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32);
+            let illum = light.illuminate(&p, rnd).unwrap();
+            let ray = Ray { orig: p, dir: illum.l_dir };
+            let rad = light.radiate(&ray).unwrap();
+            assert!((rad.pdf - illum.pdf).abs() <= 1e-3 * illum.pdf, "{} {}", rad.pdf, illum.pdf);
+            if scene.nearest_intersection(&ray).is_none() {
+                through += 1;
+            }
+        }
+        assert!(through > n * n * 3 / 4, "{}", through);
+    }
+}
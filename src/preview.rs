@@ -0,0 +1,531 @@
+use framebuffer::RgbFrameBuffer;
+use math::Vec3f;
+use std::f32::consts::PI;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// JPEG snapshots of the tone mapped frame for remote monitoring, baseline or progressive.
+// Tone mapping adapts to the average luminance, so previews don't get brighter with spp.
+// There's no WebP: its lossy format is a whole VP8 codec, the lossless one would take more
+// bandwidth than a JPEG, and every browser shows JPEG anyway
+#[derive(Debug)]
+pub struct PreviewEncoder {
+    quality: u8,
+    progressive: bool,
+    min_interval: Duration,
+    last_frame: Option<Instant>,
+    quant_luma: [u16; 64],
+    quant_chroma: [u16; 64],
+}
+
+// Serves the latest preview over HTTP from a thread of its own: `/` is a page which reloads it,
+// `/preview.jpg` the JPEG itself and `/metrics` the render metrics for Prometheus to scrape (see
+// `RenderMetrics::to_prometheus`). One request at a time, a preview is small
+pub struct PreviewServer {
+    latest: Arc<Mutex<Option<Arc<Vec<u8>>>>>,
+    metrics: Arc<Mutex<Option<Arc<String>>>>,
+    addr: SocketAddr,
+}
+
+// the bands of the AC coefficients of the progressive scans, after the one of the DC coefficients
+const AC_BANDS: [(usize, usize); 2] = [(1, 5), (6, 63)];
+
+const ZIGZAG: [usize; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10, 17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// tables of the JPEG standard, annex K
+const QUANT_LUMA: [u16; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+const QUANT_CHROMA: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+// code and its length for every symbol
+struct HuffmanTable {
+    codes: [(u16, u8); 256],
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    acc_bits: u32,
+}
+
+impl PreviewEncoder {
+    // quality is in [1, 100] like in libjpeg, fps caps how often a frame gets encoded
+    pub fn new(quality: u8, fps: f32) -> PreviewEncoder {
+        let quality = quality.max(1).min(100) as u32;
+        // libjpeg scaling of the standard tables
+        let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+        let scaled = |table: &[u16; 64]| {
+            let mut result = [0u16; 64];
+            for (r, &q) in result.iter_mut().zip(table.iter()) {
+                *r = ((q as u32 * scale + 50) / 100).max(1).min(255) as u16;
+            }
+            result
+        };
+        PreviewEncoder {
+            quality: quality as u8,
+            progressive: false,
+            min_interval: Duration::from_millis((1000.0 / fps.max(1e-3)) as u64),
+            last_frame: None,
+            quant_luma: scaled(&QUANT_LUMA),
+            quant_chroma: scaled(&QUANT_CHROMA),
+        }
+    }
+
+    // The DC coefficients of the whole frame come first, then the AC ones band by band: a viewer on a slow
+    // link shows a blurry frame early and sharpens it as the rest arrives
+    pub fn with_progressive(mut self) -> PreviewEncoder {
+        self.progressive = true;
+        self
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    // None if the previous preview is too recent
+    pub fn encode_if_due(&mut self, frame: &RgbFrameBuffer) -> io::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        if self.last_frame.map_or(false, |last| now.duration_since(last) < self.min_interval) {
+            return Ok(None);
+        }
+        self.last_frame = Some(now);
+        let resolution = frame.resolution();
+        self.encode(frame.as_slice(), resolution.x, resolution.y).map(Some)
+    }
+
+    // `pixels` are tone mapped colors in [0, 1], row by row from the top
+    pub fn encode(&self, pixels: &[Vec3f], width: usize, height: usize) -> io::Result<Vec<u8>> {
+        assert_eq!(pixels.len(), width * height);
+        // the frame header has 16 bits for either side
+        if width == 0 || height == 0 || width > 0xffff || height > 0xffff {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a JPEG can't be {}x{}", width, height)));
+        }
+        let dc_luma = HuffmanTable::new(&DC_LUMA_BITS, &DC_VALUES);
+        let ac_luma = HuffmanTable::new(&AC_LUMA_BITS, &AC_LUMA_VALUES);
+        let dc_chroma = HuffmanTable::new(&DC_CHROMA_BITS, &DC_VALUES);
+        let ac_chroma = HuffmanTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES);
+        let tables = [(&dc_luma, &ac_luma), (&dc_chroma, &ac_chroma), (&dc_chroma, &ac_chroma)];
+        let blocks = self.quantized_blocks(pixels, width, height);
+
+        let mut out = Vec::new();
+        self.write_headers(&mut out, width, height);
+        if self.progressive {
+            out = write_scan(out, &[0, 1, 2], (0, 0), &blocks, &tables);
+            for &band in AC_BANDS.iter() {
+                for c in 0..3 {
+                    out = write_scan(out, &[c], band, &blocks, &tables);
+                }
+            }
+        } else {
+            out = write_scan(out, &[0, 1, 2], (0, 63), &blocks, &tables);
+        }
+        out.extend_from_slice(&[0xff, 0xd9]);
+        Ok(out)
+    }
+
+    // the blocks of every component row by row, their coefficients in the zigzag order
+    fn quantized_blocks(&self, pixels: &[Vec3f], width: usize, height: usize) -> [Vec<[i32; 64]>; 3] {
+        let basis = dct_basis();
+        let mut blocks = [Vec::new(), Vec::new(), Vec::new()];
+        let mut block = [[0.0f32; 64]; 3];
+        for block_y in 0..(height + 7) / 8 {
+            for block_x in 0..(width + 7) / 8 {
+                // edge blocks repeat the last row and column
+                for i in 0..64 {
+                    let x = (block_x * 8 + i % 8).min(width - 1);
+                    let y = (block_y * 8 + i / 8).min(height - 1);
+                    let (luma, cb, cr) = to_ycbcr(&pixels[y * width + x]);
+                    block[0][i] = luma - 128.0;
+                    block[1][i] = cb - 128.0;
+                    block[2][i] = cr - 128.0;
+                }
+                for c in 0..3 {
+                    let quant = if c == 0 { &self.quant_luma } else { &self.quant_chroma };
+                    blocks[c].push(quantize(&block[c], &basis, quant));
+                }
+            }
+        }
+        blocks
+    }
+
+    fn write_headers(&self, out: &mut Vec<u8>, width: usize, height: usize) {
+        out.extend_from_slice(&[0xff, 0xd8]);
+        // JFIF APP0
+        out.extend_from_slice(&[0xff, 0xe0, 0, 16, b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0]);
+
+        for (id, table) in [&self.quant_luma, &self.quant_chroma].iter().enumerate() {
+            out.extend_from_slice(&[0xff, 0xdb, 0, 67, id as u8]);
+            out.extend(ZIGZAG.iter().map(|&idx| table[idx] as u8));
+        }
+
+        // checked by `encode`
+        let (w, h) = (width as u16, height as u16);
+        let frame_marker = if self.progressive { 0xc2 } else { 0xc0 };
+        out.extend_from_slice(&[0xff, frame_marker, 0, 17, 8, (h >> 8) as u8, h as u8, (w >> 8) as u8, w as u8, 3,
+                                1, 0x11, 0, 2, 0x11, 1, 3, 0x11, 1]);
+
+        let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &DC_LUMA_BITS, &DC_VALUES),
+            (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES),
+            (0x01, &DC_CHROMA_BITS, &DC_VALUES),
+            (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+        ];
+        for &(class_id, bits, values) in tables.iter() {
+            let len = 2 + 1 + 16 + values.len();
+            out.extend_from_slice(&[0xff, 0xc4, (len >> 8) as u8, len as u8, class_id]);
+            out.extend_from_slice(bits);
+            out.extend_from_slice(values);
+        }
+    }
+}
+
+impl PreviewServer {
+    // `addr` like "0.0.0.0:8080", port 0 picks a free one
+    pub fn bind(addr: &str) -> io::Result<PreviewServer> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let (latest, metrics) = (Arc::new(Mutex::new(None)), Arc::new(Mutex::new(None)));
+        let (served, served_metrics) = (latest.clone(), metrics.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                // a client which went away is its own problem, the server goes on
+                if let Ok(stream) = stream {
+                    let jpeg = served.lock().unwrap().clone();
+                    let metrics = served_metrics.lock().unwrap().clone();
+                    let _ = respond(stream, jpeg, metrics);
+                }
+            }
+        });
+        Ok(PreviewServer { latest: latest, metrics: metrics, addr: addr })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn publish(&self, jpeg: Vec<u8>) {
+        *self.latest.lock().unwrap() = Some(Arc::new(jpeg));
+    }
+
+    // in the Prometheus text format
+    pub fn publish_metrics(&self, metrics: String) {
+        *self.metrics.lock().unwrap() = Some(Arc::new(metrics));
+    }
+}
+
+fn respond(mut stream: TcpStream, jpeg: Option<Arc<Vec<u8>>>, metrics: Option<Arc<String>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    // the request line is all that matters, the headers are read up to their end or the first 4 KB
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while request.len() < 4096 && !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf)? {
+            0 => break,
+            n => request.extend_from_slice(&buf[..n])
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, content_type, body): (&str, &str, &[u8]) = match (path, jpeg.as_ref()) {
+        ("/", _) => ("200 OK", "text/html", PREVIEW_PAGE.as_bytes()),
+        ("/preview.jpg", Some(jpeg)) => ("200 OK", "image/jpeg", jpeg.as_slice()),
+        ("/preview.jpg", None) => ("503 Service Unavailable", "text/plain", b"no preview yet"),
+        ("/metrics", _) => match metrics.as_ref() {
+            Some(metrics) => ("200 OK", "text/plain; version=0.0.4", metrics.as_bytes()),
+            None          => ("503 Service Unavailable", "text/plain", b"no metrics yet")
+        },
+        _ => ("404 Not Found", "text/plain", b"not found")
+    };
+    write!(stream, "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+           status, content_type, body.len())?;
+    stream.write_all(body)
+}
+
+// reloads the preview once a second, a new image only replaces the shown one once it's loaded
+const PREVIEW_PAGE: &str = "<!DOCTYPE html><html><head><title>xray</title></head><body style=\"margin:0;background:#000\">\
+<img id=\"p\" src=\"/preview.jpg\"><script>setInterval(function() { var i = new Image(); \
+i.onload = function() { document.getElementById('p').src = i.src; }; i.src = '/preview.jpg?' + Date.now(); }, 1000);\
+</script></body></html>";
+
+impl HuffmanTable {
+    // canonical codes, annex C
+    fn new(bits: &[u8; 16], values: &[u8]) -> HuffmanTable {
+        let mut codes = [(0u16, 0u8); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for len in 0..16 {
+            for _ in 0..bits[len] {
+                codes[values[k] as usize] = (code, len as u8 + 1);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanTable { codes: codes }
+    }
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u32) {
+        self.acc = (self.acc << len) | (value & ((1 << len) - 1));
+        self.acc_bits += len;
+        while self.acc_bits >= 8 {
+            let byte = (self.acc >> (self.acc_bits - 8)) as u8;
+            self.bytes.push(byte);
+            // 0xff in entropy coded data is followed by a stuffed zero
+            if byte == 0xff {
+                self.bytes.push(0);
+            }
+            self.acc_bits -= 8;
+        }
+        self.acc &= (1 << self.acc_bits) - 1;
+    }
+
+    fn write_symbol(&mut self, table: &HuffmanTable, symbol: u8) {
+        let (code, len) = table.codes[symbol as usize];
+        self.write(code as u32, len as u32);
+    }
+}
+
+// JFIF full range conversion from the gamma corrected color
+fn to_ycbcr(color: &Vec3f) -> (f32, f32, f32) {
+    let c = |v: f32| v.max(0.0).min(1.0) * 255.0;
+    let (r, g, b) = (c(color.x), c(color.y), c(color.z));
+    (0.299 * r + 0.587 * g + 0.114 * b,
+     128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b,
+     128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b)
+}
+
+// bits needed for the magnitude and the bits themselves, negative values are stored as one's complement
+fn magnitude(value: i32) -> (u32, u32) {
+    let abs = value.abs() as u32;
+    let category = 32 - abs.leading_zeros();
+    let bits = if value < 0 { (value - 1) as u32 } else { value as u32 };
+    (category, bits & ((1 << category) - 1))
+}
+
+// separable 2d DCT-II: rows, then columns
+fn fdct(block: &[f32; 64], basis: &[[f32; 8]; 8]) -> [f32; 64] {
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).fold(0.0, |sum, x| sum + block[y * 8 + x] * basis[u][x]);
+        }
+    }
+    let mut result = [0.0; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            result[v * 8 + u] = (0..8).fold(0.0, |sum, y| sum + rows[y * 8 + u] * basis[v][y]);
+        }
+    }
+    result
+}
+
+// orthonormal basis: c(k) * cos((2x + 1) k pi / 16)
+fn dct_basis() -> [[f32; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for k in 0..8 {
+        let c = if k == 0 { (1.0f32 / 8.0).sqrt() } else { 0.5 };
+        for x in 0..8 {
+            basis[k][x] = c * ((2 * x + 1) as f32 * k as f32 * PI / 16.0).cos();
+        }
+    }
+    basis
+}
+
+fn quantize(block: &[f32; 64], basis: &[[f32; 8]; 8], quant: &[u16; 64]) -> [i32; 64] {
+    let coeffs = fdct(block, basis);
+    let mut quantized = [0i32; 64];
+    for k in 0..64 {
+        let idx = ZIGZAG[k];
+        quantized[k] = (coeffs[idx] / quant[idx] as f32).round() as i32;
+    }
+    quantized
+}
+
+// One scan of the coefficients from `band.0` up to `band.1` (included) of the components, interleaved
+// block by block; the ones of a progressive scan of the AC coefficients are one component at a time
+fn write_scan(mut out: Vec<u8>, components: &[usize], band: (usize, usize), blocks: &[Vec<[i32; 64]>; 3],
+              tables: &[(&HuffmanTable, &HuffmanTable); 3]) -> Vec<u8> {
+    let len = 6 + 2 * components.len();
+    out.extend_from_slice(&[0xff, 0xda, 0, len as u8, components.len() as u8]);
+    for &c in components {
+        out.extend_from_slice(&[c as u8 + 1, if c == 0 { 0x00 } else { 0x11 }]);
+    }
+    out.extend_from_slice(&[band.0 as u8, band.1 as u8, 0]);
+
+    let mut bits = BitWriter { bytes: out, acc: 0, acc_bits: 0 };
+    let mut prev_dc = [0i32; 3];
+    for b in 0..blocks[0].len() {
+        for &c in components {
+            let (dc, ac) = tables[c];
+            prev_dc[c] = encode_block(&mut bits, &blocks[c][b], band, dc, ac, prev_dc[c]);
+        }
+    }
+    // pad the last byte with ones
+    let padding = (8 - bits.acc_bits % 8) % 8;
+    bits.write((1 << padding) - 1, padding);
+    bits.bytes
+}
+
+// the coefficients of the band of a quantized block; returns the dc value the next block is predicted from
+fn encode_block(bits: &mut BitWriter, quantized: &[i32; 64], band: (usize, usize), dc_table: &HuffmanTable,
+                ac_table: &HuffmanTable, prev_dc: i32) -> i32 {
+    if band.0 == 0 {
+        let (category, value) = magnitude(quantized[0] - prev_dc);
+        bits.write_symbol(dc_table, category as u8);
+        bits.write(value, category);
+    }
+
+    let mut zeros = 0;
+    for k in band.0.max(1)..band.1 + 1 {
+        if quantized[k] == 0 {
+            zeros += 1;
+            continue;
+        }
+        while zeros >= 16 {
+            bits.write_symbol(ac_table, 0xf0);
+            zeros -= 16;
+        }
+        let (category, value) = magnitude(quantized[k]);
+        bits.write_symbol(ac_table, ((zeros << 4) | category) as u8);
+        bits.write(value, category);
+        zeros = 0;
+    }
+    // the end of the block, in a progressive scan the end of a run of one block
+    if zeros > 0 {
+        bits.write_symbol(ac_table, 0x00);
+    }
+    quantized[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PreviewEncoder, PreviewServer, magnitude, AC_LUMA_BITS, AC_CHROMA_BITS};
+    use math::{Vec3f, Zero};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn standard_tables_are_complete() {
+        let count = |bits: &[u8; 16]| bits.iter().fold(0, |sum, &b| sum + b as usize);
+        assert_eq!(count(&AC_LUMA_BITS), 162);
+        assert_eq!(count(&AC_CHROMA_BITS), 162);
+        assert_eq!(magnitude(-3), (2, 0b00));
+        assert_eq!(magnitude(5), (3, 0b101));
+    }
+
+    #[test]
+    fn jpeg_has_markers_and_no_stray_ff() {
+        let pixels = (0..20 * 13).map(|i| Vec3f::new((i % 20) as f32 / 20.0, 0.5, 1.0)).collect::<Vec<_>>();
+        let jpeg = PreviewEncoder::new(75, 10.0).encode(&pixels, 20, 13).unwrap();
+        assert_eq!(&jpeg[..2], &[0xff, 0xd8]);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+        let scan = jpeg.windows(2).position(|w| w == [0xff, 0xda]).unwrap() + 14;
+        for w in jpeg[scan..jpeg.len() - 2].windows(2) {
+            assert!(w[0] != 0xff || w[1] == 0);
+        }
+    }
+
+    #[test]
+    fn progressive_jpeg_has_a_scan_per_band() {
+        let pixels = (0..20 * 13).map(|i| Vec3f::new((i % 20) as f32 / 20.0, 0.5, 1.0)).collect::<Vec<_>>();
+        let jpeg = PreviewEncoder::new(75, 10.0).with_progressive().encode(&pixels, 20, 13).unwrap();
+        assert!(jpeg.windows(2).any(|w| w == [0xff, 0xc2]));
+        // the DC of all components, then two bands of each of them
+        assert_eq!(jpeg.windows(2).filter(|w| w == &[0xff, 0xda]).count(), 7);
+        assert_eq!(&jpeg[jpeg.len() - 2..], &[0xff, 0xd9]);
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        // the header would get a width of 0
+        let pixels = vec![Vec3f::zero(); 65536];
+        assert!(PreviewEncoder::new(75, 10.0).encode(&pixels, 65536, 1).is_err());
+        assert!(PreviewEncoder::new(75, 10.0).encode(&pixels, 1, 65536).is_err());
+    }
+
+    #[test]
+    fn server_hands_out_the_latest_preview() {
+        let server = PreviewServer::bind("127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        };
+        assert!(get("/preview.jpg").starts_with(b"HTTP/1.0 503"));
+        server.publish(vec![1, 2, 3]);
+        server.publish(vec![0xff, 0xd8, 0xff, 0xd9]);
+        let response = get("/preview.jpg");
+        assert!(response.starts_with(b"HTTP/1.0 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 4\r\n"));
+        assert!(response.ends_with(&[0xff, 0xd8, 0xff, 0xd9]));
+        assert!(get("/").windows(17).any(|w| w == b"src=\"/preview.jpg"));
+        assert!(get("/elsewhere").starts_with(b"HTTP/1.0 404"));
+        assert!(get("/metrics").starts_with(b"HTTP/1.0 503"));
+        server.publish_metrics("xray_spp 8\n".to_string());
+        assert!(get("/metrics").ends_with(b"\r\n\r\nxray_spp 8\n"));
+    }
+}
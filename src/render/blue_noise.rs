@@ -0,0 +1,97 @@
+use rand::Rng;
+use utility::seeded_rng;
+
+// side of the tile, it repeats over the screen
+pub const BLUE_NOISE_SIZE: usize = 64;
+// width of the gaussian the clusters are found with, in pixels, and how far it reaches
+const SIGMA: f32 = 1.9;
+const KERNEL_RADIUS: i64 = 8;
+
+// A tile of blue noise: every value of [0, 1) once, at 1 / size^2 steps, placed so the neighbours of a
+// pixel have values far from its own. Thresholding it at any level gives evenly spread pixels with no
+// clumps and no holes. Used as the per pixel offsets of the samples, the error of neighbouring pixels
+// is anticorrelated, it looks like fine grain instead of blotches, and the eye averages it away
+pub struct BlueNoise {
+    values: Vec<f32>,
+}
+
+impl BlueNoise {
+    // Void and cluster, the ranking part: starting from a random pixel, the pixel in the largest
+    // void (the lowest energy of the gaussians around the pixels taken so far) is taken next, and
+    // the order they're taken in is their value
+    pub fn new(seed: u32) -> BlueNoise {
+        let size = BLUE_NOISE_SIZE;
+        let n = size * size;
+        let mut kernel = Vec::new();
+        for dy in -KERNEL_RADIUS..KERNEL_RADIUS + 1 {
+            for dx in -KERNEL_RADIUS..KERNEL_RADIUS + 1 {
+                kernel.push((dx, dy, (-((dx * dx + dy * dy) as f32) / (2.0 * SIGMA * SIGMA)).exp()));
+            }
+        }
+        let mut energy = vec![0.0f32; n];
+        let mut values = vec![-1.0f32; n];
+        let mut pixel = seeded_rng(seed, 0).gen_range(0, n);
+        for rank in 0..n {
+            values[pixel] = (rank as f32 + 0.5) / n as f32;
+            let (x, y) = ((pixel % size) as i64, (pixel / size) as i64);
+            for &(dx, dy, weight) in &kernel {
+                // the tile wraps around, so it tiles without seams
+                let (nx, ny) = ((x + dx + size as i64) as usize % size, (y + dy + size as i64) as usize % size);
+                energy[ny * size + nx] += weight;
+            }
+            pixel = (0..n).filter(|&pix| values[pix] < 0.0)
+                .fold(None, |best: Option<usize>, pix| match best {
+                    Some(best) if energy[best] <= energy[pix] => Some(best),
+                    _ => Some(pix)
+                })
+                .unwrap_or(0);
+        }
+        BlueNoise { values: values }
+    }
+
+    // the value of the pixel, the tile repeats in both directions
+    pub fn value(&self, x: usize, y: usize) -> f32 {
+        self.values[(y % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x % BLUE_NOISE_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlueNoise, BLUE_NOISE_SIZE};
+    use rand::Rng;
+    use utility::seeded_rng;
+
+    #[test]
+    fn neighbours_are_far_apart() {
+        let size = BLUE_NOISE_SIZE;
+        let noise = BlueNoise::new(3);
+        let mut values = (0..size * size).map(|pix| noise.value(pix % size, pix / size)).collect::<Vec<_>>();
+        // mean difference to the right neighbour, a third for white noise
+        let mut rng = seeded_rng(3, 1);
+        let white = (0..size * size).map(|_| rng.next_f32()).collect::<Vec<_>>();
+        let neighbour_difference = |value: &Fn(usize, usize) -> f32| {
+            (0..size * size).fold(0.0, |sum, pix| {
+                let (x, y) = (pix % size, pix / size);
+                sum + (value(x, y) - value(x + 1, y)).abs()
+            }) / (size * size) as f32
+        };
+        let blue = neighbour_difference(&|x, y| noise.value(x, y));
+        let white = neighbour_difference(&|x, y| white[(y % size) * size + x % size]);
+        assert!(blue > white + 0.03, "{} {}", blue, white);
+
+        // the darkest 1/64 of the pixels spread evenly: none of them closer than half their spacing
+        let darkest = (0..size * size).filter(|&pix| noise.value(pix % size, pix / size) < 1.0 / 64.0).collect::<Vec<_>>();
+        let dist = |a: usize, b: usize| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap((a % size + size - b % size) % size), wrap((a / size + size - b / size) % size));
+            (dx * dx + dy * dy).sqrt()
+        };
+        for &a in &darkest {
+            assert!(darkest.iter().filter(|&&b| b != a).all(|&b| dist(a, b) >= 4.0));
+        }
+
+        // every value once
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(values.iter().enumerate().all(|(rank, &value)| value == (rank as f32 + 0.5) / (size * size) as f32));
+    }
+}
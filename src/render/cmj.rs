@@ -0,0 +1,119 @@
+// Correlated multi-jittered sampling (Kensler, "Correlated Multi-Jittered Sampling", 2013). The `n` samples of
+// a pattern fall one in every cell of an m x (n / m) grid and one in every `1 / n` column and row, like a
+// jittered grid and n-rooks at once. The columns of a row are shuffled by the same permutation (the
+// correlated part), which spreads them more evenly than independent shuffles. Everything comes from hashing
+// the sample index with the pattern, so a sample is made on its own, with no table
+
+// the sample `index` of the `n` of the pattern `pattern`, in [0, 1)^2
+pub fn cmj(index: u32, n: u32, pattern: u32) -> (f32, f32) {
+    let m = ((n as f32).sqrt() as u32).max(1);
+    let rows = (n + m - 1) / m;
+    // the samples take `n` of the cells in a shuffled order, so with `n` not a multiple of m the missing
+    // cells are anywhere
+    let index = permute(index, m * rows, pattern.wrapping_mul(0x51633e2d));
+    let (col, row) = (index % m, index / m);
+    let sx = permute(col, m, pattern.wrapping_mul(0xa511e9b3));
+    let sy = permute(row, rows, pattern.wrapping_mul(0x63d83595));
+    let jx = random_f32(index, pattern.wrapping_mul(0xa399d265));
+    let jy = random_f32(index, pattern.wrapping_mul(0x711ad6a5));
+    let x = (col as f32 + (sy as f32 + jx) / rows as f32) / m as f32;
+    let y = (row as f32 + (sx as f32 + jy) / m as f32) / rows as f32;
+    (x.min(1.0 - ::std::f32::EPSILON), y.min(1.0 - ::std::f32::EPSILON))
+}
+
+// a permutation of [0, len) per pattern, the value at `index`
+fn permute(index: u32, len: u32, pattern: u32) -> u32 {
+    let mut w = len.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    let mut i = index;
+    // a hash which permutes the power of two range above len, values out of [0, len) are cycled through
+    loop {
+        i ^= pattern;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= pattern >> 16;
+        i ^= (i & w) >> 4;
+        i ^= pattern >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= pattern >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | pattern >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < len {
+            break;
+        }
+    }
+    (i.wrapping_add(pattern)) % len
+}
+
+// a number in [0, 1) per index and pattern
+fn random_f32(index: u32, pattern: u32) -> f32 {
+    let mut i = index;
+    i ^= pattern;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xb36534e5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93fc4795);
+    i ^= 0xdf6e307f;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | pattern >> 18);
+    i as f32 * (1.0 / 4294967808.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cmj;
+
+    #[test]
+    fn samples_fill_the_grid_and_the_strata() {
+        for &pattern in [0, 1, 0xdeadbeef].iter() {
+            let n = 16;
+            let samples = (0..n).map(|index| cmj(index, n, pattern)).collect::<Vec<_>>();
+            let sorted = |mut values: Vec<u32>| { values.sort(); values };
+            let all = (0..n).collect::<Vec<_>>();
+            // one in every cell of the 4x4 grid, one in every 1 / 16 column and row
+            assert_eq!(sorted(samples.iter().map(|&(x, y)| (y * 4.0) as u32 * 4 + (x * 4.0) as u32).collect()), all);
+            assert_eq!(sorted(samples.iter().map(|&(x, _)| (x * 16.0) as u32).collect()), all);
+            assert_eq!(sorted(samples.iter().map(|&(_, y)| (y * 16.0) as u32).collect()), all);
+        }
+        // the patterns differ
+        assert!(cmj(0, 16, 1) != cmj(0, 16, 2));
+        // with a count which isn't a square, at most one sample per cell of the 2x4 grid
+        let mut cells = (0..7).map(|index| cmj(index, 7, 3)).map(|(x, y)| (y * 4.0) as u32 * 2 + (x * 2.0) as u32).collect::<Vec<_>>();
+        cells.sort();
+        cells.dedup();
+        assert_eq!(cells.len(), 7);
+    }
+
+    #[test]
+    fn every_cell_is_sampled_when_some_are_left_out() {
+        // 5 samples in the 2x3 grid, over the patterns every cell gets its share
+        let (n, patterns) = (5, 1200);
+        let mut counts = [0; 6];
+        let mut mean = (0.0, 0.0);
+        for pattern in 0..patterns {
+            for index in 0..n {
+                let (x, y) = cmj(index, n, pattern);
+                counts[(y * 3.0) as usize * 2 + (x * 2.0) as usize] += 1;
+                mean = (mean.0 + x, mean.1 + y);
+            }
+        }
+        let expected = (patterns * n) as f32 / 6.0;
+        assert!(counts.iter().all(|&count| (count as f32 - expected).abs() < 0.1 * expected), "{:?}", counts);
+        let samples = (patterns * n) as f32;
+        assert!((mean.0 / samples - 0.5).abs() < 0.02 && (mean.1 / samples - 0.5).abs() < 0.02);
+    }
+}
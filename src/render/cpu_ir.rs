@@ -0,0 +1,252 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::Ray;
+use math::vector_traits::*;
+use math::{Vec3f, Vec2f, Zero, One};
+use render::{Render, CpuMtRender, CpuBdpt, Watchdog, RenderSettings};
+use render::cpu_bdpt::{LightVertex, VcmWeights};
+use scene::{LayerVisibility, Scene, SceneChanges, SurfaceProperties};
+use utility::{seeded_rng, Sampler};
+
+// light paths the VPLs are taken from
+const VPL_PATHS: usize = 1024;
+// VPLs closer than this light as if they were this far, which keeps the spikes of 1 / dist^2 out
+const MIN_DISTANCE: f32 = 0.1;
+// camera rays follow mirrors and glass this far to a surface the VPLs can light
+const MAX_SPECULAR_BOUNCES: u32 = 8;
+// rng streams next to the pixel ones ((iter_nb << 32) | pix_nb)
+const VPL_STREAMS: u64 = 1 << 57;
+
+// Instant radiosity: light paths are traced once, in a preprocess, and every surface they hit
+// becomes a virtual point light. Camera hits get the direct light from the light sampling and the
+// indirect one from all the VPLs, with the distance to them clamped. The indirect light is biased
+// (darker around corners), but the same VPLs light every pixel, so there's no noise in it, and it
+// makes a quick preview of the global illumination
+pub struct CpuIr<S: Scene> {
+    bdpt: CpuBdpt<S>,
+    camera: PerspectiveCamera,
+    vpls: Vec<LightVertex>, // throughput divided by the number of paths
+    vpl_paths: usize,
+    min_distance: f32,
+}
+
+impl<S> CpuIr<S> where S: Scene {
+    pub fn with_vpl_paths(mut self, vpl_paths: usize) -> CpuIr<S> {
+        self.vpl_paths = vpl_paths.max(1);
+        self.generate_vpls();
+        self
+    }
+
+    pub fn with_min_distance(mut self, min_distance: f32) -> CpuIr<S> {
+        self.min_distance = min_distance;
+        self
+    }
+
+    pub fn vpls_nb(&self) -> usize {
+        self.vpls.len()
+    }
+
+    fn generate_vpls(&mut self) {
+        let seed = self.get_scene().get_seed();
+        let mut vertices = Vec::new();
+        for path_nb in 0..self.vpl_paths {
+            let mut rng = seeded_rng(seed, VPL_STREAMS | path_nb as u64);
+            self.bdpt.trace_light_path(&VcmWeights::bdpt(), &mut vertices, &mut rng);
+        }
+        // a mirror only lights in the one direction, it can't be a point light
+        let scale = 1.0 / self.vpl_paths as f32;
+        self.vpls = vertices.into_iter().filter(|vertex| !vertex.specular).map(|mut vertex| {
+            vertex.throughput = vertex.throughput * scale;
+            vertex
+        }).collect();
+    }
+
+    fn trace<R: Sampler>(&self, ray: Ray, sampler: &mut R) -> Vec3f {
+        let scene = self.get_scene();
+        let mut ray = ray;
+        let mut throughput = Vec3f::one();
+        for bounce in 0..MAX_SPECULAR_BOUNCES {
+            let isect = match scene.nearest_intersection(&ray) {
+                Some(isect) => isect,
+                None        => return throughput * scene.get_background_light().radiate(&ray).map_or(Vec3f::zero(), |rad| rad.radiance)
+            };
+            let m_id = match isect.surface {
+                SurfaceProperties::Material(m_id) => m_id,
+                SurfaceProperties::Light(light_id) => {
+                    return throughput * scene.get_light(light_id).radiate(&ray).map_or(Vec3f::zero(), |rad| {
+                        if bounce == 0 { self.settings().clamp_light(rad.radiance) } else { rad.radiance }
+                    });
+                }
+            };
+            if scene.get_layer_visibility(m_id) == LayerVisibility::Holdout {
+                return Vec3f::zero();
+            }
+            let hit_point = ray.orig + ray.dir * isect.dist;
+            let material = scene.get_surface_material(m_id, &isect);
+            let normal = scene.get_shading_normal(m_id, &isect);
+            let brdf = match Brdf::new_with_eps(&ray.dir, &normal, &material, scene.get_epsilons().cosine) {
+                Some(brdf) => brdf,
+                None       => return Vec3f::zero()
+            };
+            if !material.is_specular() {
+                return throughput * (self.sample_light(&hit_point, &brdf, sampler) + self.gather_vpls(&hit_point, &brdf));
+            }
+            let sample = match brdf.sample_with(sampler) {
+                Some(sample) => if sample.pdf > 0.0 { sample } else { return Vec3f::zero() },
+                None         => return Vec3f::zero()
+            };
+            throughput = throughput * sample.radiance / sample.pdf;
+            ray = Ray { orig: hit_point, dir: sample.wi };
+        }
+        Vec3f::zero()
+    }
+
+    // the only estimate of the direct light, camera rays don't bring it after diffuse hits
+    fn sample_light<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, sampler: &mut R) -> Vec3f {
+        let scene = self.get_scene();
+        let (light_id, light_pick_prob) = match scene.pick_light(hit_point, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return Vec3f::zero()
+        };
+        let light = scene.get_light(light_id);
+        let illum = match light.illuminate(hit_point, sampler.next_2d()) {
+            Some(illum) => if illum.pdf > 0.0 { illum } else { return Vec3f::zero() },
+            None        => return Vec3f::zero()
+        };
+        let eval = match brdf.eval(&illum.l_dir) {
+            Some(eval) => eval,
+            None       => return Vec3f::zero()
+        };
+        if scene.was_occluded(&Ray { orig: *hit_point, dir: illum.l_dir }, illum.l_dist) {
+            return Vec3f::zero();
+        }
+        illum.radiance * eval.radiance / (light_pick_prob * illum.pdf)
+    }
+
+    fn gather_vpls(&self, hit_point: &Vec3f, brdf: &Brdf) -> Vec3f {
+        let min_dist2 = self.min_distance * self.min_distance;
+        self.vpls.iter().fold(Vec3f::zero(), |sum, vpl| {
+            let to_vpl = vpl.pos - *hit_point;
+            let dist2 = to_vpl.sqnorm();
+            let dist = dist2.sqrt();
+            let dir = to_vpl / dist;
+            let (camera_eval, light_eval) = match (brdf.eval(&dir), vpl.brdf.eval(&-dir)) {
+                (Some(camera_eval), Some(light_eval)) => (camera_eval, light_eval),
+                _                                     => return sum
+            };
+            if self.get_scene().was_occluded(&Ray { orig: *hit_point, dir: dir }, dist) {
+                return sum;
+            }
+            sum + vpl.throughput * camera_eval.radiance * light_eval.radiance / dist2.max(min_dist2)
+        })
+    }
+}
+
+unsafe impl<S> Sync for CpuIr<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuIr<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        self.trace(self.camera.ray_from_screen(&sample), sampler)
+    }
+}
+
+impl<S> Render<S> for CpuIr<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuIr<S> {
+        let mut ir = CpuIr {
+            bdpt: CpuBdpt::new_with_settings(cam.clone(), scene, settings),
+            camera: cam,
+            vpls: Vec::new(),
+            vpl_paths: VPL_PATHS,
+            min_distance: MIN_DISTANCE,
+        };
+        ir.generate_vpls();
+        ir
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        self.bdpt.watchdog()
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        self.bdpt.settings()
+    }
+
+    fn get_scene(&self) -> &S {
+        self.bdpt.get_scene()
+    }
+
+    // the VPLs stay those of the old scene until `commit_changes`
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.bdpt.get_scene_mut()
+    }
+
+    fn commit_changes(&mut self) -> SceneChanges {
+        let changes = self.bdpt.commit_changes();
+        if changes.any() {
+            self.generate_vpls();
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuIr;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, -3.0), radius: 0.5 }, Vec3f::new(10.0, 10.0, 10.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn frame_matches_the_path_tracer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -6.0))
+            .with_look_at(Vec3f::new(0.0, -4.0, 6.0))
+            .build();
+        let mut ir_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        let ir = CpuIr::new(cam.clone(), scene()).with_vpl_paths(4096).with_min_distance(0.01);
+        assert!(ir.vpls_nb() > 500);
+        ir.iterate(1, 16, &mut ir_frame);
+        CpuPtMis::new(cam, scene()).iterate(1, 4096, &mut pt_frame);
+
+        // the frame as a whole, the clamping darkens the contact of the sphere a little
+        let sum = |frame: &::framebuffer::RgbFrameBuffer, spp: f32| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x) / spp;
+        let (ir, pt) = (sum(&ir_frame, 16.0), sum(&pt_frame, 4096.0));
+        assert!((ir - pt).abs() < 0.05 * pt, "{} instead of {}", ir, pt);
+    }
+
+    #[test]
+    fn vpls_follow_the_edits() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new().with_view_size(Vec2u::new(8, 8)).build();
+        let mut ir = CpuIr::new(cam, scene()).with_vpl_paths(64);
+        let power = |ir: &CpuIr<DefaultScene<GeometryList>>| ir.vpls.iter().fold(0.0, |sum, vpl| sum + vpl.throughput.x);
+        let before = power(&ir);
+        assert!(ir.get_scene_mut().set_light_intensity(1, Vec3f::new(20.0, 20.0, 20.0)));
+        assert_eq!(power(&ir), before);
+        assert!(ir.commit_changes().lights);
+        assert!((power(&ir) - 2.0 * before).abs() < 1e-3 * before, "{} instead of {}", power(&ir), 2.0 * before);
+    }
+}
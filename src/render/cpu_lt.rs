@@ -0,0 +1,171 @@
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::Ray;
+use math::vector_traits::*;
+use math::{Vec3f, Vec2f, Zero};
+use rayon::prelude::*;
+use render::{Render, CpuMtRender, CpuBdpt, Watchdog, RenderSettings};
+use render::cpu_bdpt::{LightVertex, VcmWeights};
+use scene::{Scene, SurfaceProperties};
+use utility::{seeded_rng, Sampler};
+
+// light paths traced by one task, and tasks whose splats are kept at once
+const PATHS_PER_TASK: usize = 4096;
+const TASKS_PER_BATCH: usize = 64;
+// rng streams next to the pixel ones ((iter_nb << 32) | pix_nb)
+const LIGHT_PATH_STREAMS: u64 = 1 << 59;
+
+// Light tracing: particles are traced from the lights and every vertex they hit is connected
+// to the camera and splatted onto the pixel it's seen in. Caustics seen directly converge fast,
+// everything else is noisier than with the path tracers. Lights seen directly come from camera rays,
+// lights which can't emit particles (the background) light nothing else
+pub struct CpuLt<S: Scene> {
+    bdpt: CpuBdpt<S>,
+    camera: PerspectiveCamera,
+}
+
+impl<S> CpuLt<S> where S: Scene {
+    // splats of every vertex of the path seen by the camera, throughput of one path per sample
+    fn trace_light_path<R: Sampler>(&self, splats: &mut Vec<(Vec2f, Vec3f)>, sampler: &mut R) {
+        let mut vertices = Vec::new();
+        self.bdpt.trace_light_path(&VcmWeights::bdpt(), &mut vertices, sampler);
+        let pos = self.camera.get_position();
+        for vertex in vertices.iter().filter(|vertex| !vertex.specular) {
+            if let Some(splat) = self.connect_to_camera(&pos, vertex) {
+                splats.push(splat);
+            }
+        }
+    }
+
+    fn connect_to_camera(&self, camera_pos: &Vec3f, vertex: &LightVertex) -> Option<(Vec2f, Vec3f)> {
+        let raster = self.camera.world_to_raster(&vertex.pos)?;
+        let to_camera = *camera_pos - vertex.pos;
+        let dist2 = to_camera.sqnorm();
+        let dist = dist2.sqrt();
+        let dir = to_camera / dist;
+        // the brdf brings the cosine at the vertex, the camera the one at the image plane
+        let eval = vertex.brdf.eval(&dir)?;
+        if self.get_scene().was_occluded(&Ray { orig: vertex.pos, dir: dir }, dist) {
+            return None;
+        }
+        Some((raster, vertex.throughput * eval.radiance * (self.camera.raster_density(&-dir) / dist2)))
+    }
+}
+
+unsafe impl<S> Sync for CpuLt<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuLt<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    // only the lights seen directly, the light paths bring the rest
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, _sampler: &mut R) -> Vec3f {
+        let ray = self.camera.ray_from_screen(&sample);
+        let scene = self.get_scene();
+        match scene.nearest_intersection(&ray) {
+            Some(isect) => match isect.surface {
+                SurfaceProperties::Light(light_id) => scene.get_light(light_id).radiate(&ray).map_or(Vec3f::zero(), |rad| {
+                    self.settings().clamp_light(rad.radiance)
+                }),
+                SurfaceProperties::Material(_) => Vec3f::zero()
+            },
+            None => scene.get_background_light().radiate(&ray).map_or(Vec3f::zero(), |rad| rad.radiance)
+        }
+    }
+}
+
+impl<S> Render<S> for CpuLt<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuLt<S> {
+        CpuLt {
+            bdpt: CpuBdpt::new_with_settings(cam.clone(), scene, settings),
+            camera: cam,
+        }
+    }
+
+    // `spp` light paths per pixel, each of them splats onto the whole frame
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame);
+
+        let pixels_nb = frame.as_slice().len();
+        let paths_nb = pixels_nb * spp;
+        let tasks_nb = (paths_nb + PATHS_PER_TASK - 1) / PATHS_PER_TASK;
+        let seed = self.get_scene().get_seed();
+        for batch_start in (0..tasks_nb).step_by(TASKS_PER_BATCH) {
+            let mut tasks = (batch_start..tasks_nb.min(batch_start + TASKS_PER_BATCH)).map(|_| Vec::new()).collect::<Vec<_>>();
+            tasks.par_iter_mut().enumerate().for_each(|(i, splats)| {
+                let first = (batch_start + i) * PATHS_PER_TASK;
+                for path_nb in first..paths_nb.min(first + PATHS_PER_TASK) {
+                    let mut rng = seeded_rng(seed, LIGHT_PATH_STREAMS | ((iter_nb as u64) << 32) | path_nb as u64);
+                    self.trace_light_path(splats, &mut rng);
+                }
+            });
+            // a frame of `spp` samples per pixel, the paths of the whole frame make one sample
+            for &(pos, color) in tasks.iter().flat_map(|splats| splats.iter()) {
+                frame.splat(&pos, color / pixels_nb as f32);
+            }
+        }
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        self.bdpt.watchdog()
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        self.bdpt.settings()
+    }
+
+    fn get_scene(&self) -> &S {
+        self.bdpt.get_scene()
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.bdpt.get_scene_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuLt;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, -3.0), radius: 0.5 }, Vec3f::new(10.0, 10.0, 10.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn frame_matches_the_path_tracer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -6.0))
+            .with_look_at(Vec3f::new(0.0, -4.0, 6.0))
+            .build();
+        let mut lt_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuLt::new(cam.clone(), scene()).iterate(1, 4096, &mut lt_frame);
+        CpuPtMis::new(cam, scene()).iterate(1, 4096, &mut pt_frame);
+
+        // quarters of the frame, single pixels get too few particles
+        let quarters = |frame: &::framebuffer::RgbFrameBuffer| frame.as_slice().iter().enumerate()
+            .fold([0.0; 4], |mut sums, (pix_nb, pix)| {
+                sums[(pix_nb % 8) / 4 + 2 * (pix_nb / 32)] += pix.x;
+                sums
+            });
+        let (lt, pt) = (quarters(&lt_frame), quarters(&pt_frame));
+        for i in 0..4 {
+            assert!(pt[i] > 0.0);
+            assert!((lt[i] - pt[i]).abs() < 0.05 * pt[i], "{:?} instead of {:?}", lt, pt);
+        }
+    }
+}
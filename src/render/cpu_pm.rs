@@ -0,0 +1,287 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::{Ray, SurfaceIntersection};
+use math::vector_traits::*;
+use math::{Vec3f, Vec2f, Zero, One};
+use render::{Render, /*CpuStRender, */CpuMtRender, Watchdog, RenderSettings};
+use render::photon_map::{Photon, PhotonMap};
+use scene::{LayerVisibility, MaterialID, Scene, SurfaceProperties};
+use std::f32::consts::PI;
+use std::sync::RwLock;
+use utility::{seeded_rng, Sampler};
+
+const PHOTONS_PER_ITERATION: usize = 100000;
+const MAX_PHOTON_BOUNCES: u32 = 16;
+const MAX_SPECULAR_BOUNCES: u32 = 16; // of eye paths before they reach a surface to gather at
+const GATHER_RADIUS: f32 = 0.02; // of the scene's bounding sphere
+const PHOTON_STREAM: u64 = 0xffff_ffff; // rng stream of the photon pass, pixels use the lower ones
+
+// Photon mapping (Jensen 1996): every iteration shoots a fresh set of photons and stores
+// the ones which bounced at least once, eye paths follow mirrors to the first non-specular
+// surface and take direct light from light sampling and the rest from the photon density there.
+// Caustics come out right away, but blurred by the gather radius
+pub struct CpuPm<S: Scene> {
+    scene: S,
+    camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    photons_per_iteration: usize,
+    radius: Option<f32>,
+    photon_map: RwLock<PhotonMap>, // of the current iteration
+}
+
+impl<S> CpuPm<S> where S: Scene {
+    pub fn with_photons(mut self, photons_per_iteration: usize) -> CpuPm<S> {
+        self.photons_per_iteration = photons_per_iteration;
+        self
+    }
+
+    // smaller radii blur caustics less and need more photons
+    pub fn with_radius(mut self, radius: f32) -> CpuPm<S> {
+        self.radius = Some(radius);
+        self
+    }
+
+    fn radius(&self) -> f32 {
+        self.radius.unwrap_or_else(|| self.scene.get_bounding_sphere().map_or(1.0, |sphere| sphere.radius * GATHER_RADIUS))
+    }
+
+    fn brdf(&self, m_id: MaterialID, ray: &Ray, isect: &SurfaceIntersection) -> Option<(Brdf, bool)> {
+        let material = self.scene.get_surface_material(m_id, isect);
+        let normal = self.scene.get_shading_normal(m_id, isect);
+        Brdf::new_with_eps(&ray.dir, &normal, &material, self.scene.get_epsilons().cosine)
+            .map(|brdf| (brdf, material.is_specular()))
+    }
+
+    fn shoot_photons(&self, iter_nb: usize) -> PhotonMap {
+        let mut sampler = seeded_rng(self.scene.get_seed(), ((iter_nb as u64) << 32) | PHOTON_STREAM);
+        let mut photons = Vec::new();
+        for _ in 0..self.photons_per_iteration {
+            let (light_id, light_pick_prob) = match self.scene.pick_emitter(sampler.next_1d()) {
+                Some(picked) => picked,
+                None         => break
+            };
+            let light = self.scene.get_light(light_id);
+            let ((u1, u2), (u3, u4)) = (sampler.next_2d(), sampler.next_2d());
+            let emission = match light.emit((u1, u2, u3, u4)) {
+                Some(emission) => if emission.pdf > 0.0 { emission } else { continue },
+                None           => continue
+            };
+            let mut power = emission.radiance * (emission.cos_light
+                / (emission.pdf * light_pick_prob * self.photons_per_iteration as f32));
+            let mut ray = emission.ray;
+            let mut guard = self.watchdog.path_guard();
+            for bounce in 0..MAX_PHOTON_BOUNCES {
+                let isect = match self.scene.nearest_intersection(&ray) {
+                    Some(ref isect) if !guard.add_vertex(isect.dist) => break,
+                    Some(isect) => isect,
+                    None        => break
+                };
+                let m_id = match isect.surface {
+                    SurfaceProperties::Material(m_id) => m_id,
+                    SurfaceProperties::Light(_)       => break
+                };
+                let (brdf, specular) = match self.brdf(m_id, &ray, &isect) {
+                    Some(brdf) => brdf,
+                    None       => break
+                };
+                let hit_point = ray.orig + ray.dir * isect.dist;
+                // direct light comes from light sampling
+                if bounce > 0 && !specular {
+                    photons.push(Photon { pos: hit_point, wi: -ray.dir, power: power });
+                }
+
+                let sample = match brdf.sample_with(&mut sampler) {
+                    Some(sample) => if sample.pdf > 0.0 { sample } else { break },
+                    None         => break
+                };
+                let weight = sample.radiance / sample.pdf;
+                let survival = weight.fold(f32::max).min(1.0);
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                power = power * weight / survival;
+                ray = Ray { orig: hit_point, dir: sample.wi };
+            }
+        }
+        PhotonMap::new(photons)
+    }
+
+    fn sample_direct<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, sampler: &mut R) -> Vec3f {
+        let (light_id, light_pick_prob) = match self.scene.pick_light(hit_point, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return Vec3f::zero()
+        };
+        let light = self.scene.get_light(light_id);
+        let illum = match light.illuminate(hit_point, sampler.next_2d()) {
+            Some(illum) => if illum.pdf > 0.0 { illum } else { return Vec3f::zero() },
+            None        => return Vec3f::zero()
+        };
+        let eval = match brdf.eval(&illum.l_dir) {
+            Some(eval) => eval,
+            None       => return Vec3f::zero()
+        };
+        if self.scene.was_occluded(&Ray { orig: *hit_point, dir: illum.l_dir }, illum.l_dist) {
+            Vec3f::zero()
+        } else {
+            illum.radiance * eval.radiance / (light_pick_prob * illum.pdf)
+        }
+    }
+
+    // radiance estimate from the photons around the point, the brdf without the cosine
+    // weights the power of every photon
+    fn gather(&self, photons: &PhotonMap, hit_point: &Vec3f, brdf: &Brdf) -> Vec3f {
+        let radius = self.radius();
+        let normal = brdf.normal();
+        let mut flux = Vec3f::zero();
+        photons.for_each_near(hit_point, radius, &mut |photon| {
+            let cos = normal.dot(&photon.wi);
+            if cos > 0.0 {
+                if let Some(eval) = brdf.eval(&photon.wi) {
+                    flux = flux + eval.radiance * photon.power / cos;
+                }
+            }
+        });
+        flux / (PI * radius * radius)
+    }
+
+    fn trace<R: Sampler>(&self, ray: Ray, photons: &PhotonMap, sampler: &mut R) -> Vec3f {
+        let mut ray = ray;
+        let mut throughput = Vec3f::one();
+        let mut guard = self.watchdog.path_guard();
+        for bounce in 0..MAX_SPECULAR_BOUNCES {
+            let isect = match self.scene.nearest_intersection(&ray) {
+                Some(ref isect) if !guard.add_vertex(isect.dist) => break,
+                Some(isect) => isect,
+                None => {
+                    return self.scene.get_background_light().radiate(&ray)
+                        .map_or(Vec3f::zero(), |rad| throughput * rad.radiance);
+                }
+            };
+            let m_id = match isect.surface {
+                SurfaceProperties::Material(m_id) => m_id,
+                SurfaceProperties::Light(light_id) => {
+                    return self.scene.get_light(light_id).radiate(&ray).map_or(Vec3f::zero(), |rad| {
+                        if bounce == 0 { self.settings.clamp_light(rad.radiance) } else { throughput * rad.radiance }
+                    });
+                }
+            };
+            if bounce == 0 && self.scene.get_layer_visibility(m_id) == LayerVisibility::Holdout {
+                break;
+            }
+            let (brdf, specular) = match self.brdf(m_id, &ray, &isect) {
+                Some(brdf) => brdf,
+                None       => break
+            };
+            let hit_point = ray.orig + ray.dir * isect.dist;
+            if !specular {
+                return throughput * (self.sample_direct(&hit_point, &brdf, sampler) + self.gather(photons, &hit_point, &brdf));
+            }
+
+            match brdf.sample_with(sampler) {
+                Some(sample) => {
+                    throughput = throughput * sample.radiance / sample.pdf;
+                    ray = Ray { orig: hit_point, dir: sample.wi };
+                },
+                None => break
+            }
+        }
+        Vec3f::zero()
+    }
+}
+
+unsafe impl<S> Sync for CpuPm<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuPm<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        let photons = self.photon_map.read().unwrap();
+        self.trace(self.camera.ray_from_screen(&sample), &photons, sampler)
+    }
+}
+
+impl<S> Render<S> for CpuPm<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPm<S> {
+        CpuPm {
+            camera: cam,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            photons_per_iteration: PHOTONS_PER_ITERATION,
+            radius: None,
+            photon_map: RwLock::new(PhotonMap::new(Vec::new())),
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        *self.photon_map.write().unwrap() = self.shoot_photons(iter_nb);
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuPm;
+    use camera::{CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Ray, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use brdf::{Material, SPECULAR_PHONG_EXP};
+    use materials_and_colors::{MIRROR, WHITE_DIFFUSE};
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use render::Render;
+    use scene::{DefaultScene, Scene};
+    use std::f32::consts::PI;
+    use utility::seeded_rng;
+
+    #[test]
+    fn caustic_from_a_mirror_wall() {
+        let radiance_at_origin = |with_mirror: bool| {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+            if with_mirror {
+                // the sharpest mirror still counted as specular, sharper lobes lose float precision
+                let mirror = Material { phong_exp: SPECULAR_PHONG_EXP, ..MIRROR };
+                scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 1005.0), radius: 1000.0 }, mirror).unwrap();
+            }
+            // the blocker next to the light shades the floor around the origin, which only gets the light over
+            // the mirror and what the blocker scatters down
+            scene.add_object(Sphere { center: Vec3f::new(0.0, 3.5, 0.0), radius: 0.25 }, WHITE_DIFFUSE).unwrap();
+            scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(1000.0, 1000.0, 1000.0) });
+            let ren = CpuPm::new(CameraBuilder::<PerspectiveCamera>::new().build(), scene)
+                .with_photons(200000)
+                .with_radius(1.0);
+            let photons = ren.shoot_photons(1);
+            let ray = Ray { orig: Vec3f::new(0.0, 1.0, -1.0), dir: Vec3f::new(0.0, -1.0, 1.0).normalize() };
+            ren.trace(ray, &photons, &mut seeded_rng(0, 0)).x
+        };
+
+        // the mirror image of the light lights the floor like an ordinary point light, on top of the light
+        // of the blocker the same scene without the mirror has
+        let image = Vec3f::new(0.0, 4.0, 10.0);
+        let irradiance = 0.99 * 1000.0 / PI / image.sqnorm() * (image.y / image.norm());
+        let expected = 0.99 / PI * irradiance;
+        let radiance = radiance_at_origin(true) - radiance_at_origin(false);
+        assert!((radiance - expected).abs() < 0.3 * expected, "{} instead of {}", radiance, expected);
+    }
+}
@@ -0,0 +1,218 @@
+use camera::PerspectiveCamera;
+use distribution::Distribution1D;
+use framebuffer::RgbFrameBuffer;
+use math::{Vec3f, Vec2f};
+use rand::Rng;
+use rayon::prelude::*;
+use render::{Render, CpuMtRender, CpuPtMis, Watchdog, RenderSettings};
+use scene::Scene;
+use std::mem;
+use utility::{luminance, seeded_rng, Pcg32, Sampler};
+
+const BOOTSTRAP_PATHS: usize = 100000;
+const CHAINS: usize = 64;
+const LARGE_STEP_PROB: f32 = 0.3;
+// range of the small steps in primary sample space (Kelemen et al.)
+const MUTATION_MIN: f64 = 1.0 / 1024.0;
+const MUTATION_MAX: f64 = 1.0 / 64.0;
+// rng streams next to the pixel ones ((iter_nb << 32) | pix_nb)
+const BOOTSTRAP_STREAMS: u64 = 1 << 62;
+const CHAIN_STREAMS: u64 = 1 << 61;
+
+// Primary sample space Metropolis light transport (Kelemen et al. 2002) over the MIS path tracer:
+// a path is the sequence of random numbers it draws, starting with the pixel position, and
+// chains of paths wander through them with small mutations of all the numbers and large steps
+// to fresh ones. Bright paths are found once and explored, which is what light coming through
+// small openings needs. The bootstrap paths estimate the brightness of the whole image,
+// which sets the scale, and choose where the chains start. Both are redone every iteration
+pub struct CpuPssmlt<S: Scene> {
+    tracer: CpuPtMis<S>,
+    bootstrap_paths: usize,
+}
+
+// Gives out the numbers of `values` first, the fresh ones of `rng` drawn after them are appended. `values`
+// ends up with exactly the numbers the path drew, so the same path can be traced again, or a similar one
+// after the numbers are mutated. The numbers follow one another, there are no dimensions to jump to
+struct ReplaySampler<'a> {
+    values: Vec<u32>,
+    drawn: usize,
+    rng: &'a mut Pcg32,
+}
+
+impl<'a> Sampler for ReplaySampler<'a> {
+    fn start_dimension(&mut self, _dim: usize) {}
+
+    fn next_bits(&mut self) -> u32 {
+        if self.drawn == self.values.len() {
+            let value = self.rng.next_u32();
+            self.values.push(value);
+        }
+        self.drawn += 1;
+        self.values[self.drawn - 1]
+    }
+}
+
+// a path of a chain: its numbers, pixel, color and luminance
+struct ChainState {
+    record: Vec<u32>,
+    pix_nb: usize,
+    color: Vec3f,
+    lum: f32,
+}
+
+impl<S> CpuPssmlt<S> where S: Scene {
+    pub fn with_bootstrap(mut self, bootstrap_paths: usize) -> CpuPssmlt<S> {
+        self.bootstrap_paths = bootstrap_paths.max(1);
+        self
+    }
+
+    // traces the path of the numbers of `record`, fresh ones from `rng` past them, the first two pick
+    // the pixel position
+    fn trace_state(&self, record: Vec<u32>, rng: &mut Pcg32) -> ChainState {
+        let view = self.tracer.get_view_size();
+        let (res_x, res_y) = (view.x as usize, view.y as usize);
+        let mut sampler = ReplaySampler { values: record, drawn: 0, rng: rng };
+        let (x, y) = sampler.next_2d();
+        let sample = Vec2f::new(x * view.x, y * view.y);
+        let pix_nb = (sample.y as usize).min(res_y - 1) * res_x + (sample.x as usize).min(res_x - 1);
+        let color = self.tracer.trace_from_screen(sample, &mut sampler);
+        let mut record = mem::replace(&mut sampler.values, Vec::new());
+        record.truncate(sampler.drawn);
+        ChainState { record: record, pix_nb: pix_nb, color: color, lum: luminance(&color).max(0.0) }
+    }
+
+    fn bootstrap_state(&self, iter_nb: usize, path_nb: usize) -> ChainState {
+        let mut rng = seeded_rng(self.get_scene().get_seed(), BOOTSTRAP_STREAMS | ((iter_nb as u64) << 32) | path_nb as u64);
+        self.trace_state(Vec::new(), &mut rng)
+    }
+
+    // `mutations` steps of a chain, `scale` makes the luminance of every step's splats; `rng` drives
+    // the chain, `path_rng` gives the fresh numbers of the paths
+    fn run_chain(&self, mut current: ChainState, mutations: usize, scale: f32, rng: &mut Pcg32, path_rng: &mut Pcg32,
+                 splats: &mut Vec<(usize, Vec3f)>) {
+        for _ in 0..mutations {
+            let record = if rng.next_f32() < LARGE_STEP_PROB {
+                Vec::new()
+            } else {
+                current.record.iter().map(|&value| mutate(value, rng)).collect()
+            };
+            let proposal = self.trace_state(record, path_rng);
+            let accept = if current.lum > 0.0 { (proposal.lum / current.lum).min(1.0) } else { 1.0 };
+
+            // both paths are splatted with the probability to be the next one
+            if current.lum > 0.0 && accept < 1.0 {
+                splats.push((current.pix_nb, current.color * ((1.0 - accept) * scale / current.lum)));
+            }
+            if proposal.lum > 0.0 && accept > 0.0 {
+                splats.push((proposal.pix_nb, proposal.color * (accept * scale / proposal.lum)));
+            }
+            if rng.next_f32() < accept {
+                current = proposal;
+            }
+        }
+    }
+}
+
+// small step of one number with wrap around, its size has an exponential distribution
+fn mutate<R: Rng>(value: u32, rng: &mut R) -> u32 {
+    let u = value as f64 / 4294967296.0;
+    let delta = MUTATION_MAX * (-(MUTATION_MAX / MUTATION_MIN).ln() * rng.next_f64()).exp();
+    let u = if rng.next_f32() < 0.5 { u + delta } else { u - delta };
+    ((u - u.floor()) * 4294967296.0) as u32
+}
+
+impl<S> Render<S> for CpuPssmlt<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPssmlt<S> {
+        CpuPssmlt {
+            tracer: CpuPtMis::new_with_settings(cam, scene, settings),
+            bootstrap_paths: BOOTSTRAP_PATHS,
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        let mut lums = vec![0.0; self.bootstrap_paths];
+        lums.par_iter_mut().enumerate().for_each(|(path_nb, lum)| {
+            *lum = self.bootstrap_state(iter_nb, path_nb).lum;
+        });
+        let starts = Distribution1D::new(lums);
+        if starts.integral() <= 0.0 {
+            return;
+        }
+
+        // every step splats luminance `scale` in total, which makes the whole frame as bright
+        // as `spp` independent samples per pixel
+        let total_mutations = frame.as_slice().len() * spp;
+        let scale = starts.integral() / self.bootstrap_paths as f32;
+        let seed = self.get_scene().get_seed();
+        let mut chains = (0..CHAINS).map(|_| Vec::new()).collect::<Vec<_>>();
+        chains.par_iter_mut().enumerate().for_each(|(chain_nb, splats)| {
+            let mut rng = seeded_rng(seed, CHAIN_STREAMS | ((iter_nb as u64) << 32) | chain_nb as u64);
+            let start = starts.sample(rng.next_f32()).idx;
+            let state = self.bootstrap_state(iter_nb, start);
+            let mut path_rng: Pcg32 = rng.gen();
+            let mutations = total_mutations / CHAINS + if chain_nb < total_mutations % CHAINS { 1 } else { 0 };
+            self.run_chain(state, mutations, scale, &mut rng, &mut path_rng, splats);
+        });
+
+        let pixels = frame.as_mut_slice();
+        for &(pix_nb, color) in chains.iter().flat_map(|splats| splats.iter()) {
+            pixels[pix_nb] = pixels[pix_nb] + color;
+        }
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        self.tracer.watchdog()
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        self.tracer.settings()
+    }
+
+    fn get_scene(&self) -> &S {
+        self.tracer.get_scene()
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.tracer.get_scene_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuPssmlt;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(2.0, 3.0, -2.0), radius: 0.5 }, Vec3f::new(10.0, 10.0, 10.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn bands_match_the_path_tracer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 2.0, 3.0))
+            .with_look_at(Vec3f::new(0.0, -2.0, -3.0))
+            .build();
+        let mut mlt_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuPssmlt::new(cam.clone(), scene()).with_bootstrap(20000).iterate(1, 256, &mut mlt_frame);
+        CpuPtMis::new(cam, scene()).iterate(1, 256, &mut pt_frame);
+
+        // the light is off to the right, chains have to spend their steps unevenly over the halves
+        let halves = |frame: &::framebuffer::RgbFrameBuffer| frame.as_slice().iter().enumerate()
+            .fold((0.0, 0.0), |(left, right), (pix_nb, pix)| if pix_nb % 8 < 4 { (left + pix.x, right) } else { (left, right + pix.x) });
+        let (mlt, pt) = (halves(&mlt_frame), halves(&pt_frame));
+        assert!((mlt.0 - pt.0).abs() < 0.1 * pt.0, "{:?} instead of {:?}", mlt, pt);
+        assert!((mlt.1 - pt.1).abs() < 0.1 * pt.1, "{:?} instead of {:?}", mlt, pt);
+    }
+}
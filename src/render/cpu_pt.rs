@@ -1,46 +1,72 @@
-use brdf::Brdf;
+use brdf::{BounceKind, Brdf};
 use camera::{Camera, PerspectiveCamera};
 use framebuffer::RgbFrameBuffer;
-use math::vector_traits::*;
 use math::{Vec3f, Vec2f, Zero, One};
-use rand::{Rng, thread_rng};
-use render::{Render, /*CpuStRender, */CpuMtRender};
-use scene::{Scene, SurfaceProperties};
+use geometry::Ray;
+use render::{Render, /*CpuStRender, */CpuMtRender, LightGroupRender, PathStatsRender, BounceCounts, Watchdog, RenderSettings};
+use scene::{LayerVisibility, LightID, Scene, SurfaceProperties};
 use std::f32::consts::PI;
+use utility::Sampler;
+
+// numbers every vertex draws: the brdf sample and the roulette
+const VERTEX_DIMS: usize = 4;
 
-const MAX_PATH_LENGTH: u32 = 100;
 
 pub struct CpuPt<S: Scene> {
     scene: S,
     camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
 }
 
 unsafe impl<S> Sync for CpuPt<S> where S: Scene {}
 
-impl<S> CpuMtRender for CpuPt<S> where S: Scene {
+impl<S> CpuMtRender<S> for CpuPt<S> where S: Scene {
     fn get_view_size(&self) -> Vec2f {
         self.camera.get_view_size()
     }
 
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f {
-        let mut ray = self.camera.ray_from_screen(&sample);
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        self.trace_path(self.camera.ray_from_screen(&sample), sampler)
+    }
+}
+
+impl<S> CpuPt<S> where S: Scene {
+    // the radiance along the camera ray `ray`, with the numbers of `sampler`
+    pub fn trace_path<R: Sampler>(&self, ray: Ray, sampler: &mut R) -> Vec3f {
+        let mut color = Vec3f::zero();
+        self.trace(ray, sampler, &mut |_, radiance| color = color + radiance, &mut |_| {});
+        color
+    }
+
+    // passes the light the path finds to `emit` and the kind of every scattering to `bounce`
+    fn trace<R: Sampler>(&self, ray: Ray, sampler: &mut R, emit: &mut FnMut(LightID, Vec3f),
+                         bounce: &mut FnMut(BounceKind)) {
+        let mut ray = ray;
         let mut path_length = 0;
         let mut path_weight = Vec3f::one();
-        let mut color = Vec3f::zero();
+        let mut guard = self.watchdog.path_guard();
         'current_path: loop {
             let isect = match self.scene.nearest_intersection(&ray) {
+                Some(ref isect) if !guard.add_vertex(isect.dist) => break 'current_path,
                 Some(isect) => isect,
                 None => {
-                    self.scene.get_background_light().radiate(&ray).map(|rad| {
-                        color = rad.radiance * path_weight;
-                    });
+                    if let Some(rad) = self.scene.get_background_light().radiate(&ray) {
+                        emit(0, self.settings.clamp_contribution(rad.radiance * path_weight, path_length));
+                    }
                     break 'current_path;
                 }
             };
             let hit_point = ray.orig + ray.dir * isect.dist;
             let brdf = match isect.surface {
                 SurfaceProperties::Material(mat_id) => {
-                    match Brdf::new(&ray.dir, &isect.normal, self.scene.get_material(mat_id)) {
+                    if path_length == 0 && self.scene.get_layer_visibility(mat_id) == LayerVisibility::Holdout {
+                        break 'current_path;
+                    }
+                    let material = self.scene.get_surface_material(mat_id, &isect);
+                    let normal = self.scene.get_shading_normal(mat_id, &isect);
+                    let eps_cosine = self.scene.get_epsilons().cosine;
+                    match Brdf::new_with_eps(&ray.dir, &normal, &material, eps_cosine) {
                         Some(brdf) => brdf,
                         None       => break 'current_path
                     }
@@ -49,44 +75,82 @@ impl<S> CpuMtRender for CpuPt<S> where S: Scene {
                     if let Some(rad) = self.scene.get_light(light_id).radiate(&ray) {
                         if path_length == 0 { // caustic path
                             let max_component = rad.radiance.x.max(rad.radiance.y.max(rad.radiance.z));
-                            color = rad.radiance / max_component * PI;
+                            emit(light_id, self.settings.clamp_contribution(rad.radiance / max_component * PI, 0));
                         } else {
-                            color = path_weight * rad.radiance;
+                            emit(light_id, self.settings.clamp_contribution(path_weight * rad.radiance, path_length));
                         }
                     }
                     break 'current_path;
                 }
             };
 
-            let sample_rnds = (thread_rng().next_f32(), thread_rng().next_f32(), thread_rng().next_f32());
-            if let Some(sample) = brdf.sample(sample_rnds) {
-                path_weight = path_weight * sample.radiance;
+            sampler.start_dimension(path_length as usize * VERTEX_DIMS);
+            if let Some(sample) = brdf.sample_with(sampler) {
+                bounce(sample.kind);
+                path_weight = path_weight * sample.radiance / sample.pdf;
                 ray.dir = sample.wi;
                 ray.orig = hit_point;
             } else {
                 break 'current_path;
             }
 
-            let russian_roulette = path_weight.sqnorm() * 100.0 < thread_rng().next_f32();
-            if path_length >= MAX_PATH_LENGTH || russian_roulette {
+            let survival = self.settings.survival_probability(path_length, &path_weight);
+            if sampler.next_1d() >= survival {
                 break 'current_path;
             }
+            path_weight = path_weight / survival;
 
             path_length += 1;
         }
-        color
+    }
+}
+
+impl<S> LightGroupRender<S> for CpuPt<S> where S: Scene {
+    fn get_light_groups_nb(&self) -> usize {
+        self.scene.get_light_groups_nb()
+    }
+
+    fn trace_light_groups<R: Sampler>(&self, sample: Vec2f, groups: &mut [Vec3f], sampler: &mut R) {
+        self.trace(self.camera.ray_from_screen(&sample), sampler, &mut |light_id, radiance| {
+            let group = self.scene.get_light_group(light_id);
+            groups[group] = groups[group] + radiance;
+        }, &mut |_| {});
+    }
+}
+
+impl<S> PathStatsRender<S> for CpuPt<S> where S: Scene {
+    fn trace_path_stats<R: Sampler>(&self, sample: Vec2f, counts: &mut BounceCounts, sampler: &mut R) {
+        self.trace(self.camera.ray_from_screen(&sample), sampler, &mut |_, _| {}, &mut |kind| counts.add(kind));
     }
 }
 
 impl<S> Render<S> for CpuPt<S> where S: Scene {
-    fn new(cam: PerspectiveCamera, scene: S) -> CpuPt<S> {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPt<S> {
         CpuPt {
             camera: cam,
-            scene: scene,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
         }
     }
 
-    fn iterate(&self, iter_nb: usize, frame: &mut RgbFrameBuffer) {
-        self.iterate_over_screen(iter_nb, frame)
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
     }
 }
@@ -1,19 +1,23 @@
-use brdf::Brdf;
+use brdf::{BounceKind, Brdf};
 use camera::{Camera, PerspectiveCamera};
 use framebuffer::RgbFrameBuffer;
 use geometry::Ray;
-use math::vector_traits::*;
 use math::{Vec3f, Vec2f, Zero, One};
-use rand::{Rng, thread_rng};
-use render::{Render, /*CpuStRender, */CpuMtRender};
-use scene::{Scene, SurfaceProperties};
+use render::{Render, /*CpuStRender, */CpuMtRender, LightGroupRender, PathStatsRender, BounceCounts, Watchdog, RenderSettings};
+use scene::{LayerVisibility, LightID, Scene, SurfaceProperties};
 use std::f32::consts::PI;
+use utility::Sampler;
+
+// numbers every vertex draws: the light pick and sample, then the brdf sample and the roulette
+pub const LIGHT_DIMS: usize = 3;
+pub const VERTEX_DIMS: usize = LIGHT_DIMS + 4;
 
-const MAX_PATH_LENGTH: u32 = 100;
 
 pub struct CpuPtDl<S: Scene> {
     scene: S,
     camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
 }
 
 #[allow(dead_code)]
@@ -41,100 +45,180 @@ fn mis2(current_pdf_w: f32, other_pdf_w: f32) -> f32 {
     power_heuristic2(current_pdf_w, other_pdf_w)
 }
 
-impl<S> CpuPtDl<S> where S: Scene {
-    fn uniform_sample_one_light(&self, p: &Vec3f, brdf: &Brdf) -> Vec3f {
-        let mut ld = Vec3f::zero();
-
-        let lights_nb = self.scene.get_lights_nb() as u32;
-        let light_nb = (thread_rng().next_u32() % lights_nb) as i32;
-        let rand_light = self.scene.get_light(light_nb);
-
-        // light sampling
-        let rands = (thread_rng().next_f32(), thread_rng().next_f32());
-        if let Some(illum) = rand_light.illuminate(p, rands) {
-            if let Some(brdf_eval) = brdf.eval(&illum.l_dir) {
-                let shadow_ray = Ray { orig: *p, dir: illum.l_dir };
-                if !self.scene.was_occluded(&shadow_ray, illum.l_dist) {
-                    ld = ld + illum.radiance * brdf_eval.radiance;
-                }
-            }
-        }
-        ld * lights_nb as f32
+// The light sample of the vertex at `p`: the light, the shadow ray, its length and the contribution if
+// nothing is in the way. None at the ideal mirror and smooth glass, no light sample reaches them.
+// Shared with `CpuPtWavefront`, which traces the shadow rays of a whole wave together
+pub fn light_sample<S: Scene, R: Sampler>(scene: &S, p: &Vec3f, brdf: &Brdf, sampler: &mut R)
+    -> Option<(LightID, Ray, f32, Vec3f)> {
+    if brdf.is_delta() {
+        return None;
+    }
+    let (light_nb, light_pick_prob) = match scene.pick_light(p, sampler.next_1d()) {
+        Some(picked) => picked,
+        None         => return None
+    };
+    let illum = match scene.get_light(light_nb).illuminate(p, sampler.next_2d()) {
+        Some(illum) => illum,
+        None        => return None
+    };
+    brdf.eval(&illum.l_dir).map(|brdf_eval| {
+        let shadow_ray = Ray { orig: *p, dir: illum.l_dir };
+        (light_nb, shadow_ray, illum.l_dist, illum.radiance * brdf_eval.radiance / (illum.pdf * light_pick_prob))
+    })
+}
+
+// Light the ray of a path found on the light `hit`, or on the background if it missed everything:
+// the camera sees the lights, after that the light sampling takes it all but over the ideal mirror
+// and smooth glass (`last_delta`)
+pub fn emitted<S: Scene>(scene: &S, settings: &RenderSettings, ray: &Ray, hit: Option<LightID>, path_length: u32,
+                         path_weight: &Vec3f, last_delta: bool) -> Vec3f {
+    let light = match hit {
+        Some(light_id) => scene.get_light(light_id),
+        None           => scene.get_background_light()
+    };
+    match light.radiate(ray) {
+        Some(rad) if path_length == 0 => match hit {
+            Some(_) => {
+                let max_component = rad.radiance.x.max(rad.radiance.y.max(rad.radiance.z));
+                settings.clamp_contribution(rad.radiance / max_component * PI, 0)
+            },
+            None => settings.clamp_contribution(rad.radiance, 0)
+        },
+        Some(rad) if last_delta => settings.clamp_contribution(rad.radiance * *path_weight, path_length),
+        _ => Vec3f::zero()
     }
 }
 
 unsafe impl<S> Sync for CpuPtDl<S> where S: Scene {}
 
-impl<S> CpuMtRender for CpuPtDl<S> where S: Scene {
+impl<S> CpuMtRender<S> for CpuPtDl<S> where S: Scene {
     fn get_view_size(&self) -> Vec2f {
         self.camera.get_view_size()
     }
 
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f {
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        let mut color = Vec3f::zero();
+        self.trace(sample, sampler, &mut |_, radiance| color = color + radiance, &mut |_| {});
+        color
+    }
+}
+
+impl<S> CpuPtDl<S> where S: Scene {
+    // passes the light the path finds to `emit` and the kind of every scattering to `bounce`
+    fn trace<R: Sampler>(&self, sample: Vec2f, sampler: &mut R, emit: &mut FnMut(LightID, Vec3f),
+                         bounce: &mut FnMut(BounceKind)) {
         let mut ray = self.camera.ray_from_screen(&sample);
         let mut path_length = 0;
         let mut path_weight = Vec3f::one();
-        let mut color = Vec3f::zero();
+        // the ray came from the ideal mirror or smooth glass, no light sample reaches what it hits
+        let mut last_delta = false;
+        let mut guard = self.watchdog.path_guard();
         'current_path: loop {
             let isect = match self.scene.nearest_intersection(&ray) {
+                Some(ref isect) if !guard.add_vertex(isect.dist) => break 'current_path,
                 Some(isect) => isect,
                 None => {
-                    if path_length == 0 {
-                        self.scene.get_background_light().radiate(&ray).map(|rad| { color = rad.radiance; });
-                    }
+                    emit(0, emitted(&self.scene, &self.settings, &ray, None, path_length, &path_weight, last_delta));
                     break 'current_path;
                 }
             };
             let hit_point = ray.orig + ray.dir * isect.dist;
             let brdf = match isect.surface {
                 SurfaceProperties::Material(mat_id) => {
-                    match Brdf::new(&ray.dir, &isect.normal, self.scene.get_material(mat_id)) {
+                    if path_length == 0 && self.scene.get_layer_visibility(mat_id) == LayerVisibility::Holdout {
+                        break 'current_path;
+                    }
+                    let material = self.scene.get_surface_material(mat_id, &isect);
+                    let normal = self.scene.get_shading_normal(mat_id, &isect);
+                    let eps_cosine = self.scene.get_epsilons().cosine;
+                    match Brdf::new_with_eps(&ray.dir, &normal, &material, eps_cosine) {
                         Some(brdf) => brdf,
                         None       => break 'current_path
                     }
                 },
                 SurfaceProperties::Light(light_id) => {
-                    if path_length == 0 {
-                        if let Some(rad) = self.scene.get_light(light_id).radiate(&ray) {
-                            let max_component = rad.radiance.x.max(rad.radiance.y.max(rad.radiance.z));
-                            color = rad.radiance / max_component * PI;
-                        }
-                    }
+                    emit(light_id, emitted(&self.scene, &self.settings, &ray, Some(light_id), path_length, &path_weight, last_delta));
                     break 'current_path;
                 }
             };
 
-            color = color + self.uniform_sample_one_light(&hit_point, &brdf) * path_weight;
+            let dim = path_length as usize * VERTEX_DIMS;
+            sampler.start_dimension(dim);
+            if let Some((light_id, shadow_ray, dist, ld)) = light_sample(&self.scene, &hit_point, &brdf, sampler) {
+                if !self.scene.was_occluded(&shadow_ray, dist) {
+                    emit(light_id, self.settings.clamp_contribution(ld * path_weight, path_length + 1));
+                }
+            }
 
-            let sample_rnds = (thread_rng().next_f32(), thread_rng().next_f32(), thread_rng().next_f32());
-            if let Some(sample) = brdf.sample(sample_rnds) {
-                path_weight = path_weight * sample.radiance;
+            // the brdf sample stays in place when no light was picked
+            sampler.start_dimension(dim + LIGHT_DIMS);
+            if let Some(sample) = brdf.sample_with(sampler) {
+                bounce(sample.kind);
+                path_weight = path_weight * sample.radiance / sample.pdf;
+                last_delta = sample.delta;
                 ray.dir = sample.wi;
                 ray.orig = hit_point;
             } else {
                 break 'current_path;
             }
 
-            let russian_roulette = path_weight.sqnorm() * 100.0 < thread_rng().next_f32();
-            if path_length >= MAX_PATH_LENGTH || russian_roulette {
+            let survival = self.settings.survival_probability(path_length, &path_weight);
+            if sampler.next_1d() >= survival {
                 break 'current_path;
             }
+            path_weight = path_weight / survival;
 
             path_length += 1;
         }
-        color
+    }
+}
+
+impl<S> LightGroupRender<S> for CpuPtDl<S> where S: Scene {
+    fn get_light_groups_nb(&self) -> usize {
+        self.scene.get_light_groups_nb()
+    }
+
+    fn trace_light_groups<R: Sampler>(&self, sample: Vec2f, groups: &mut [Vec3f], sampler: &mut R) {
+        self.trace(sample, sampler, &mut |light_id, radiance| {
+            let group = self.scene.get_light_group(light_id);
+            groups[group] = groups[group] + radiance;
+        }, &mut |_| {});
+    }
+}
+
+impl<S> PathStatsRender<S> for CpuPtDl<S> where S: Scene {
+    fn trace_path_stats<R: Sampler>(&self, sample: Vec2f, counts: &mut BounceCounts, sampler: &mut R) {
+        self.trace(sample, sampler, &mut |_, _| {}, &mut |kind| counts.add(kind));
     }
 }
 
 impl<S> Render<S> for CpuPtDl<S> where S: Scene {
-    fn new(cam: PerspectiveCamera, scene: S) -> CpuPtDl<S> {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPtDl<S> {
         CpuPtDl {
             camera: cam,
-            scene: scene,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
         }
     }
 
-    fn iterate(&self, iter_nb: usize, frame: &mut RgbFrameBuffer) {
-        self.iterate_over_screen(iter_nb, frame)
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
     }
 }
@@ -0,0 +1,340 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::Ray;
+use math::{Vec3f, Vec2f, Zero, One};
+use render::{Render, CpuMtRender, Watchdog, RenderSettings};
+use render::sd_tree::{DTree, SdTree};
+use scene::{LayerVisibility, Scene, SurfaceProperties};
+use std::cell::RefCell;
+use std::sync::{Mutex, RwLock};
+use utility::{luminance, Sampler};
+
+// of the bounces where something was learned
+const GUIDE_PROB: f32 = 0.5;
+// numbers every vertex draws: the light pick and sample, the bounce (the guide choice and a direction)
+// and the roulette
+const LIGHT_DIMS: usize = 3;
+const BOUNCE_DIMS: usize = 4;
+const VERTEX_DIMS: usize = LIGHT_DIMS + BOUNCE_DIMS + 1;
+
+// power heuristic
+fn mis2(current_pdf_w: f32, other_pdf_w: f32) -> f32 {
+    let (current, other) = (current_pdf_w * current_pdf_w, other_pdf_w * other_pdf_w);
+    current / (current + other)
+}
+
+// Path tracer with path guiding: the radiance arriving at every vertex is learned in an SD-tree
+// while rendering, and bounces sample the directions it learned, mixed with the brdf sampling
+// (the pdf is the mixture's, so it stays unbiased). Every iteration trains the tree on its paths
+// and the next one samples from it, light coming through small openings is found much sooner.
+// Light samples are weighted with MIS against the mixture and trained on as well
+pub struct CpuPtGuided<S: Scene> {
+    scene: S,
+    camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    guide: RwLock<SdTree>,
+    records: Mutex<Vec<GuideRecord>>, // of the current iteration
+    guide_prob: f32,
+}
+
+struct GuideRecord {
+    pos: Vec3f,
+    dir: Vec3f,
+    value: f32, // luminance of the incoming radiance divided by the pdf
+}
+
+// the records of the paths the thread traced in its current tile, see `end_tile`
+thread_local!(static TILE_RECORDS: RefCell<Vec<GuideRecord>> = RefCell::new(Vec::new()));
+
+// a vertex of the current path and the radiance that came back along its bounce
+struct GuidedVertex {
+    pos: Vec3f,
+    dir: Vec3f,
+    pdf: f32,
+    throughput: Vec3f, // after the bounce
+    radiance: Vec3f,
+}
+
+impl<S> CpuPtGuided<S> where S: Scene {
+    pub fn with_guide_prob(mut self, guide_prob: f32) -> CpuPtGuided<S> {
+        self.guide_prob = guide_prob.max(0.0).min(1.0);
+        self
+    }
+
+    // pdf of the bounce at a vertex: the mixture of the learned directions and the brdf
+    fn bounce_pdf(&self, guide: Option<&DTree>, dir: &Vec3f, brdf_pdf: f32) -> f32 {
+        match guide {
+            Some(guide) => self.guide_prob * guide.pdf(dir) + (1.0 - self.guide_prob) * brdf_pdf,
+            None        => brdf_pdf
+        }
+    }
+
+    fn trace<R: Sampler>(&self, ray: Ray, sampler: &mut R) -> Vec3f {
+        let guide_tree = self.guide.read().unwrap();
+        let mut ray = ray;
+        let mut throughput = Vec3f::one();
+        let mut color = Vec3f::zero();
+        let mut vertices: Vec<GuidedVertex> = Vec::new();
+        let mut light_records = Vec::new();
+        // pdf of the last bounce, None after specular ones and the camera
+        let mut bounce_pdf: Option<f32> = None;
+        let mut path_length = 0;
+        let mut guard = self.watchdog.path_guard();
+        {
+            // light found by the path, credited to the vertices before it
+            let mut add = |radiance: Vec3f, vertices: &mut Vec<GuidedVertex>| {
+                color = color + radiance;
+                for vertex in vertices.iter_mut() {
+                    let t = vertex.throughput;
+                    let div = |c: f32, t: f32| if t > 0.0 { c / t } else { 0.0 };
+                    vertex.radiance = vertex.radiance + Vec3f::new(div(radiance.x, t.x), div(radiance.y, t.y), div(radiance.z, t.z));
+                }
+            };
+            loop {
+                let isect = match self.scene.nearest_intersection(&ray) {
+                    Some(ref isect) if !guard.add_vertex(isect.dist) => break,
+                    Some(isect) => isect,
+                    None => {
+                        if let Some(rad) = self.scene.get_background_light().radiate(&ray) {
+                            let weight = bounce_pdf.map_or(1.0, |pdf| mis2(pdf, rad.pdf * self.scene.light_pick_prob(&ray.orig, 0)));
+                            let radiance = self.settings.clamp_contribution(throughput * rad.radiance * weight, path_length);
+                            add(radiance, &mut vertices);
+                        }
+                        break;
+                    }
+                };
+                let hit_point = ray.orig + ray.dir * isect.dist;
+                let m_id = match isect.surface {
+                    SurfaceProperties::Material(m_id) => m_id,
+                    SurfaceProperties::Light(light_id) => {
+                        if let Some(rad) = self.scene.get_light(light_id).radiate(&ray) {
+                            let radiance = if path_length == 0 {
+                                self.settings.clamp_light(rad.radiance)
+                            } else {
+                                rad.radiance
+                            };
+                            let weight = bounce_pdf.map_or(1.0, |pdf| mis2(pdf, rad.pdf * self.scene.light_pick_prob(&ray.orig, light_id)));
+                            add(self.settings.clamp_contribution(throughput * radiance * weight, path_length), &mut vertices);
+                        }
+                        break;
+                    }
+                };
+                if path_length == 0 && self.scene.get_layer_visibility(m_id) == LayerVisibility::Holdout {
+                    break;
+                }
+                let material = self.scene.get_surface_material(m_id, &isect);
+                let normal = self.scene.get_shading_normal(m_id, &isect);
+                let brdf = match Brdf::new_with_eps(&ray.dir, &normal, &material, self.scene.get_epsilons().cosine) {
+                    Some(brdf) => brdf,
+                    None       => break
+                };
+                let specular = material.is_specular();
+                let guide = if specular { None } else { guide_tree.guide(&hit_point) };
+
+                let dim = path_length as usize * VERTEX_DIMS;
+                sampler.start_dimension(dim);
+                if !specular {
+                    let (light, record) = self.sample_light(&hit_point, &brdf, guide, sampler);
+                    add(self.settings.clamp_contribution(throughput * light, path_length + 1), &mut vertices);
+                    light_records.extend(record);
+                }
+
+                sampler.start_dimension(dim + LIGHT_DIMS);
+                let (dir, radiance, pdf, delta) = match self.sample_bounce(&brdf, guide, sampler) {
+                    Some(bounce) => bounce,
+                    None         => break
+                };
+                throughput = throughput * radiance / pdf;
+                bounce_pdf = if specular || delta { None } else { Some(pdf) };
+                if !specular && !delta {
+                    vertices.push(GuidedVertex { pos: hit_point, dir: dir, pdf: pdf, throughput: throughput, radiance: Vec3f::zero() });
+                }
+                ray = Ray { orig: hit_point, dir: dir };
+
+                let survival = self.settings.survival_probability(path_length, &throughput);
+                sampler.start_dimension(dim + LIGHT_DIMS + BOUNCE_DIMS);
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+                path_length += 1;
+            }
+        }
+
+        TILE_RECORDS.with(|records| {
+            let mut records = records.borrow_mut();
+            for vertex in vertices {
+                records.push(GuideRecord { pos: vertex.pos, dir: vertex.dir, value: luminance(&vertex.radiance) / vertex.pdf });
+            }
+            records.extend(light_records);
+        });
+        color
+    }
+
+    // Light sampling, and the record of the light arriving from the sampled direction: the brdf
+    // sampled part of the direct light is in the records of the bounces, the MIS weights split it
+    fn sample_light<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, guide: Option<&DTree>, sampler: &mut R)
+        -> (Vec3f, Option<GuideRecord>) {
+        let (light_id, light_pick_prob) = match self.scene.pick_light(hit_point, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return (Vec3f::zero(), None)
+        };
+        let light = self.scene.get_light(light_id);
+        let illum = match light.illuminate(hit_point, sampler.next_2d()) {
+            Some(illum) => if illum.pdf > 0.0 { illum } else { return (Vec3f::zero(), None) },
+            None        => return (Vec3f::zero(), None)
+        };
+        let eval = match brdf.eval(&illum.l_dir) {
+            Some(eval) => eval,
+            None       => return (Vec3f::zero(), None)
+        };
+        if self.scene.was_occluded(&Ray { orig: *hit_point, dir: illum.l_dir }, illum.l_dist) {
+            return (Vec3f::zero(), None);
+        }
+        let light_pdf = illum.pdf * light_pick_prob;
+        let weight = if light.is_delta() { 1.0 } else { mis2(light_pdf, self.bounce_pdf(guide, &illum.l_dir, eval.pdf)) };
+        let record = GuideRecord { pos: *hit_point, dir: illum.l_dir, value: luminance(&illum.radiance) * weight / light_pdf };
+        (illum.radiance * eval.radiance * (weight / light_pdf), Some(record))
+    }
+
+    // direction, brdf * cos, the mixture pdf and whether it's the ideal mirror direction
+    fn sample_bounce<R: Sampler>(&self, brdf: &Brdf, guide: Option<&DTree>, sampler: &mut R)
+        -> Option<(Vec3f, Vec3f, f32, bool)> {
+        let (dir, eval) = match guide {
+            Some(guide) if sampler.next_1d() < self.guide_prob => {
+                let dir = guide.sample(sampler.next_2d());
+                (dir, brdf.eval(&dir)?)
+            },
+            _ => {
+                let sample = brdf.sample_with(sampler)?;
+                if sample.delta {
+                    // the learned directions never hit it, only the brdf part of the mixture is left
+                    let pdf = if guide.is_some() { (1.0 - self.guide_prob) * sample.pdf } else { sample.pdf };
+                    return Some((sample.wi, sample.radiance, pdf, true));
+                }
+                (sample.wi, ::brdf::BrdfEval { radiance: sample.radiance, pdf: sample.pdf })
+            }
+        };
+        let pdf = self.bounce_pdf(guide, &dir, eval.pdf);
+        if pdf > 0.0 { Some((dir, eval.radiance, pdf, false)) } else { None }
+    }
+
+    // what the paths of the iteration learned is sampled from the next one on
+    fn train(&self) {
+        let mut records = ::std::mem::replace(&mut *self.records.lock().unwrap(), Vec::new());
+        if self.settings.deterministic {
+            // the threads pushed them in any order, the sums of the tree depend on it
+            records.sort_by_key(|record| (record.pos.x.to_bits(), record.pos.y.to_bits(), record.pos.z.to_bits(),
+                                          record.dir.x.to_bits(), record.dir.y.to_bits(), record.dir.z.to_bits(),
+                                          record.value.to_bits()));
+        }
+        let mut guide = self.guide.write().unwrap();
+        for record in records.iter().filter(|record| record.value > 0.0 && record.value.is_finite()) {
+            guide.record(&record.pos, &record.dir, record.value);
+        }
+        guide.refine();
+    }
+}
+
+unsafe impl<S> Sync for CpuPtGuided<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuPtGuided<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        self.trace(self.camera.ray_from_screen(&sample), sampler)
+    }
+
+    fn end_tile(&self) {
+        TILE_RECORDS.with(|records| self.records.lock().unwrap().extend(records.borrow_mut().drain(..)));
+    }
+}
+
+impl<S> Render<S> for CpuPtGuided<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPtGuided<S> {
+        let guide = match scene.get_bounding_sphere() {
+            Some(sphere) => {
+                let r = Vec3f::new(sphere.radius, sphere.radius, sphere.radius);
+                SdTree::new(sphere.center - r, sphere.center + r)
+            },
+            None => SdTree::new(Vec3f::new(-1.0, -1.0, -1.0), Vec3f::new(1.0, 1.0, 1.0))
+        };
+        CpuPtGuided {
+            camera: cam,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            guide: RwLock::new(guide),
+            records: Mutex::new(Vec::new()),
+            guide_prob: GUIDE_PROB,
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame);
+        self.train();
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuPtGuided;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 3.0, 1.0), radius: 0.5 }, Vec3f::new(10.0, 10.0, 10.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn trained_frame_matches_the_path_tracer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 2.0, -3.0))
+            .with_look_at(Vec3f::new(0.0, -2.0, 3.0))
+            .build();
+        let guided = CpuPtGuided::new(cam.clone(), scene());
+        let mut frame = cam.build_rgb_framebuffer();
+        for iter_nb in 1..4 {
+            frame.clear();
+            guided.iterate(iter_nb, 256, &mut frame);
+        }
+        assert!(guided.guide.read().unwrap().leaves_nb() > 1);
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam, scene()).iterate(1, 256, &mut pt_frame);
+
+        let sum = |frame: &::framebuffer::RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (guided_sum, pt_sum) = (sum(&frame), sum(&pt_frame));
+        assert!((guided_sum - pt_sum).abs() < 0.03 * pt_sum, "{} instead of {}", guided_sum, pt_sum);
+    }
+}
@@ -1,18 +1,32 @@
-use brdf::Brdf;
+use brdf::{BounceKind, Brdf, BrdfSample};
 use camera::{Camera, PerspectiveCamera};
 use framebuffer::RgbFrameBuffer;
-use geometry::Ray;
-use math::vector_traits::*;
+use geometry::{Ray, SurfaceIntersection};
 use math::{Vec3f, Vec2f, Zero, One};
-use rand::{Rng, thread_rng};
-use render::{Render, /*CpuStRender, */CpuMtRender};
-use scene::{Scene, SurfaceProperties};
+use math::vector_traits::*;
+use memory::OutOfBudget;
+use render::{Render, /*CpuStRender, */CpuMtRender, LightGroupRender, PathStatsRender, BounceCounts, Watchdog, RenderSettings};
+use render::{ChainProgress, ManifoldNee, PrimaryCache};
+use scene::{LayerVisibility, LightID, Scene, SurfaceProperties};
+use utility::Sampler;
+use std::f32::consts::FRAC_1_PI;
 
-const MAX_PATH_LENGTH: u32 = 100;
+// numbers a vertex draws, each split its own: the light pick and sample, the brdf sample and the
+// roulette, then the manifold NEE
+const LIGHT_DIMS: usize = 3;
+const BOUNCE_DIMS: usize = 4;
+const MANIFOLD_DIMS: usize = 6;
+// between the dimensions of the branches of a split bounce, more than a path draws, so in the
+// deterministic mode the branches don't draw the same numbers
+const BRANCH_DIMS: usize = 1 << 16;
 
 pub struct CpuPtMis<S: Scene> {
     scene: S,
     camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    manifold: Option<ManifoldNee>,
+    primary_cache: Option<PrimaryCache>,
 }
 
 #[allow(dead_code)]
@@ -40,130 +54,619 @@ fn mis2(current_pdf_w: f32, other_pdf_w: f32) -> f32 {
     power_heuristic2(current_pdf_w, other_pdf_w)
 }
 
+// a bounce widens the cone of the path to the one of the lobe it was sampled from, taken as
+// a cone of the solid angle 1 / pdf; the ideal mirror keeps it as it is
+fn widened_spread(spread: f32, sample: &BrdfSample) -> f32 {
+    if sample.delta { spread } else { lobe_spread(spread, sample.pdf) }
+}
+
+fn lobe_spread(spread: f32, pdf: f32) -> f32 {
+    spread.max(2.0 * (FRAC_1_PI / pdf).sqrt())
+}
+
 impl<S> CpuPtMis<S> where S: Scene {
-    fn uniform_sample_one_light(&self, p: &Vec3f, brdf: &Brdf) -> Vec3f {
+    // Light reaching diffuse and glossy surfaces over a mirror or through glass is gathered with manifold NEE
+    // instead of the light sampling at the mirror. Has to be called after the scene is built
+    pub fn with_manifold_nee(mut self) -> CpuPtMis<S> {
+        let manifold = ManifoldNee::new(&self.scene);
+        self.manifold = if manifold.has_mirrors() { Some(manifold) } else { None };
+        self
+    }
+
+    // Camera rays hit the scene once, in here, the iterations take the hits from the cache.
+    // For progressive renders with a static camera; dropped when the scene is changed
+    pub fn with_primary_cache(mut self, strata: usize) -> Result<CpuPtMis<S>, OutOfBudget> {
+        let cache = PrimaryCache::new(&self.camera, strata, &|ray| self.scene.nearest_intersection(ray))?;
+        self.primary_cache = Some(cache);
+        Ok(self)
+    }
+
+    // Light sampling at `p`, weighted against the brdf sampling of the same direction which the next
+    // bounce of the path does; `light_to_brdf` is the ratio of the light samples to the brdf samples
+    // taken at `p` and `spread` the cone of the path reaching it. The contribution and the light it came from
+    fn uniform_sample_one_light<R: Sampler>(&self, p: &Vec3f, brdf: &Brdf, light_to_brdf: f32, spread: f32, sampler: &mut R)
+        -> (LightID, Vec3f) {
         let mut ld = Vec3f::zero();
 
-        let lights_nb = self.scene.get_lights_nb() as u32;
-        let light_nb = (thread_rng().next_u32() % lights_nb) as i32;
-        let light_pick_prob = 1.0 / lights_nb as f32;
-        // let light_pick_prob = 1.0;
+        let (light_nb, light_pick_prob) = match self.scene.pick_light(p, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return (0, ld)
+        };
         let rand_light = self.scene.get_light(light_nb);
 
-        // brdf sampling
-        let sample_rnds = (thread_rng().next_f32(), thread_rng().next_f32(), thread_rng().next_f32());
-        if let Some(sample) = brdf.sample(sample_rnds) {
-            let brdf_ray = Ray { dir: sample.wi, orig: *p };
-            if let Some(isect) = self.scene.nearest_intersection(&brdf_ray) {
-                match isect.surface {
-                    SurfaceProperties::Light(light_id) if light_nb == light_id => {
-                        if let Some(rad) = rand_light.radiate(&brdf_ray) {
-                            let weight = mis2(sample.pdf, rad.pdf/* * light_pick_prob*/);
-                            ld = ld + sample.radiance * rad.radiance * weight;
-                        }
-                    },
-                    _ => {}
-                }
-            } else if light_nb == 0 {
-                rand_light.radiate(&brdf_ray).map(|rad| {
-                    let weight = mis2(sample.pdf, rad.pdf * light_pick_prob);
-                    ld = ld + sample.radiance * rad.radiance * weight;
-                });
-            };
-        }
-
-        // light sampling
-        let rands = (thread_rng().next_f32(), thread_rng().next_f32());
-        if let Some(illum) = rand_light.illuminate(p, rands) {
+        if let Some(illum) = rand_light.sample_illumination(p, sampler) {
             if let Some(brdf_eval) = brdf.eval(&illum.l_dir) {
                 let shadow_ray = Ray { orig: *p, dir: illum.l_dir };
                 if !self.scene.was_occluded(&shadow_ray, illum.l_dist) {
-                    let weight = mis2(illum.pdf * light_pick_prob, brdf_eval.pdf);
-                    ld = ld + illum.radiance * brdf_eval.radiance * weight * lights_nb as f32;
+                    let light_pdf = illum.pdf * light_pick_prob;
+                    // no brdf sample can hit a point light
+                    let weight = if rand_light.is_delta() { 1.0 } else { mis2(light_pdf * light_to_brdf, brdf_eval.pdf) };
+                    // the background the brdf sample of this direction would see, filtered over its cone,
+                    // so both strategies estimate the same light
+                    let radiance = if self.settings.ray_differentials && light_nb == 0 {
+                        rand_light.radiate_filtered(&shadow_ray, lobe_spread(spread, brdf_eval.pdf)).map_or(illum.radiance, |rad| rad.radiance)
+                    } else {
+                        illum.radiance
+                    };
+                    ld = ld + radiance * brdf_eval.radiance * weight / light_pdf;
                 }
             }
         }
-        ld
+        (light_nb, ld)
     }
-}
 
-unsafe impl<S> Sync for CpuPtMis<S> where S: Scene {}
+    // weight of the light `light_id` found by the brdf sample of `brdf_pdf` from `p`, the light sampling
+    // could've picked it with the pdf `light_pdf` (light selection not included), `light_to_brdf` times as often;
+    // no light sample reaches a light over an ideal mirror, the `delta` brdf sample takes all of it
+    fn brdf_hit_weight(&self, p: &Vec3f, brdf_pdf: f32, delta: bool, light_id: LightID, light_pdf: f32,
+                       light_to_brdf: f32) -> f32 {
+        if delta { 1.0 } else { mis2(brdf_pdf, light_pdf * self.scene.light_pick_prob(p, light_id) * light_to_brdf) }
+    }
 
-impl<S> CpuMtRender for CpuPtMis<S> where S: Scene {
-    fn get_view_size(&self) -> Vec2f {
-        self.camera.get_view_size()
+    // passes every light contribution of the path to `emit` and the kind of every scattering to `bounce`
+    fn trace<R: Sampler>(&self, sample: Vec2f, sampler: &mut R, emit: &mut FnMut(LightID, Vec3f),
+                         bounce: &mut FnMut(BounceKind)) {
+        let (ray, first_hit) = match self.primary_cache {
+            Some(ref cache) => {
+                let (center, hit) = cache.lookup(&sample);
+                (self.camera.ray_from_screen(&center), Some(hit))
+            },
+            None => (self.camera.ray_from_screen(&sample), None)
+        };
+        let path = PathState {
+            ray: ray,
+            first_hit: first_hit,
+            path_length: 0,
+            path_weight: Vec3f::one(),
+            chain: None,
+            last_pdf: 0.0,
+            last_delta: false,
+            light_to_brdf: 1.0,
+            spread: self.camera.pixel_spread(),
+            dim: 0,
+        };
+        self.trace_from(path, sampler, emit, bounce);
     }
 
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f {
-        let mut ray = self.camera.ray_from_screen(&sample);
-        let mut path_length = 0;
-        let mut path_weight = Vec3f::one();
-        let mut color = Vec3f::zero();
+    // The path from `path` on. The first vertex takes `light_splits` light samples and goes on along
+    // `bounce_splits` brdf samples (see `RenderSettings::with_splitting`), every branch is a path of its own
+    fn trace_from<R: Sampler>(&self, path: PathState, sampler: &mut R, emit: &mut FnMut(LightID, Vec3f),
+                              bounce: &mut FnMut(BounceKind)) {
+        let PathState { mut ray, mut first_hit, mut path_length, mut path_weight, mut chain, mut last_pdf,
+                        mut last_delta, mut light_to_brdf, mut spread, mut dim } = path;
+        let mut guard = self.watchdog.path_guard();
         'current_path: loop {
-            let isect = match self.scene.nearest_intersection(&ray) {
+            let hit = match first_hit.take() {
+                Some(hit) => hit,
+                // blue sky: a bounce leaving the bounds of everything is a miss without the traversal
+                None if path_length > 0 && self.settings.blue_sky && self.scene.escapes(&ray) => None,
+                None => self.scene.nearest_intersection(&ray)
+            };
+            // the light at the end of a chain was gathered by the manifold NEE at its start
+            let manifold_covers_hit = chain == Some(ChainProgress::End);
+            let isect = match hit {
+                Some(ref isect) if !guard.add_vertex(isect.dist) => break 'current_path,
                 Some(isect) => isect,
                 None => {
-                    if path_length == 0 {
-                        self.scene.get_background_light().radiate(&ray).map(|rad| { color = rad.radiance; });
+                    let background = self.scene.get_background_light();
+                    let rad = if self.settings.ray_differentials {
+                        background.radiate_filtered(&ray, spread)
+                    } else {
+                        background.radiate(&ray)
+                    };
+                    if let Some(rad) = rad {
+                        if path_length == 0 {
+                            emit(0, self.settings.clamp_contribution(rad.radiance, 0));
+                        } else if !manifold_covers_hit {
+                            let radiance = rad.radiance * path_weight * self.brdf_hit_weight(&ray.orig, last_pdf, last_delta, 0, rad.pdf, light_to_brdf);
+                            emit(0, self.settings.clamp_contribution(radiance, path_length));
+                        }
                     }
                     break 'current_path;
                 }
             };
             let hit_point = ray.orig + ray.dir * isect.dist;
-            let brdf = match isect.surface {
+            let (brdf, mat_id, specular) = match isect.surface {
                 SurfaceProperties::Material(mat_id) => {
-                    match Brdf::new(&ray.dir, &isect.normal, self.scene.get_material(mat_id)) {
-                        Some(brdf) => brdf,
+                    if path_length == 0 && self.scene.get_layer_visibility(mat_id) == LayerVisibility::Holdout {
+                        break 'current_path;
+                    }
+                    let material = if self.settings.ray_differentials {
+                        self.scene.get_filtered_material(mat_id, &isect, spread)
+                    } else {
+                        self.scene.get_surface_material(mat_id, &isect)
+                    };
+                    let normal = self.scene.get_shading_normal(mat_id, &isect);
+                    let eps_cosine = self.scene.get_epsilons().cosine;
+                    match Brdf::new_with_eps(&ray.dir, &normal, &material, eps_cosine) {
+                        Some(brdf) => (brdf, mat_id, material.is_specular()),
                         None       => break 'current_path
                     }
                 },
                 SurfaceProperties::Light(light_id) => {
                     if path_length == 0 {
                         if let Some(rad) = self.scene.get_light(light_id).radiate(&ray) {
-                            // @TODO Remove this when HDR will be implemented
-                            let max_comp = rad.radiance.fold(f32::max);
-                            if max_comp > 10.0 {
-                                color = rad.radiance / max_comp * 10.0;
-                            } else {
-                                color = rad.radiance;
-                            }
+                            emit(light_id, self.settings.clamp_contribution(self.settings.clamp_light(rad.radiance), 0));
+                        }
+                    } else if !manifold_covers_hit {
+                        if let Some(rad) = self.scene.get_light(light_id).radiate(&ray) {
+                            let weight = self.brdf_hit_weight(&ray.orig, last_pdf, last_delta, light_id, rad.pdf, light_to_brdf);
+                            let radiance = rad.radiance * path_weight * weight;
+                            emit(light_id, self.settings.clamp_contribution(radiance, path_length));
                         }
                     }
                     break 'current_path;
                 }
             };
 
-            color = color + self.uniform_sample_one_light(&hit_point, &brdf) * path_weight;
+            let (light_splits, bounce_splits) = if path_length == 0 {
+                (self.settings.light_splits, self.settings.bounce_splits)
+            } else {
+                (1, 1)
+            };
+            light_to_brdf = light_splits as f32 / bounce_splits as f32;
+            let bounce_dim = dim + light_splits * LIGHT_DIMS;
+            let manifold_dim = bounce_dim + bounce_splits * BOUNCE_DIMS;
+            let next_dim = manifold_dim + MANIFOLD_DIMS;
+            // light over this mirror was already gathered by manifold NEE at the previous vertex
+            let on_chain = match self.manifold {
+                Some(ref manifold) if specular => {
+                    let glass = self.scene.get_material(mat_id).dielectric.is_some();
+                    manifold.follow(&self.scene, chain, mat_id, glass).is_some()
+                },
+                _ => false
+            };
+            sampler.start_dimension(dim);
+            if !on_chain && !brdf.is_delta() {
+                for _ in 0..light_splits {
+                    let (light_id, ld) = self.uniform_sample_one_light(&hit_point, &brdf, light_to_brdf, spread, sampler);
+                    emit(light_id, self.settings.clamp_contribution(ld * path_weight / light_splits as f32, path_length + 1));
+                }
+            }
+            let mut manifold_sampled = false;
+            if let Some(ref manifold) = self.manifold {
+                if !specular && !brdf.is_delta() {
+                    sampler.start_dimension(manifold_dim);
+                    // when the solve fails the brdf sample keeps the light over the mirror
+                    if let Some((light_id, ld)) = manifold.sample(&self.scene, &hit_point, &brdf, sampler) {
+                        emit(light_id, self.settings.clamp_contribution(ld * path_weight, path_length + 1));
+                        manifold_sampled = true;
+                    }
+                }
+            }
+            // where the bounce in `wi` leaves the path on the chains of the manifold NEE
+            let next_chain = |wi: &Vec3f| match self.manifold {
+                Some(ref manifold) if specular => {
+                    let refracted = wi.dot(&isect.normal) * ray.dir.dot(&isect.normal) > 0.0;
+                    manifold.follow(&self.scene, chain, mat_id, refracted)
+                },
+                _ if manifold_sampled => Some(ChainProgress::Start),
+                _ => None
+            };
+
+            if bounce_splits > 1 {
+                for split in 0..bounce_splits {
+                    sampler.start_dimension(bounce_dim + split * BOUNCE_DIMS);
+                    if let Some(sample) = brdf.sample_with(sampler) {
+                        bounce(sample.kind);
+                        let weight = path_weight * sample.radiance / (sample.pdf * bounce_splits as f32);
+                        let survival = self.settings.survival_probability(path_length, &weight);
+                        if sampler.next_1d() < survival {
+                            let branch = PathState {
+                                ray: Ray { orig: hit_point, dir: sample.wi },
+                                first_hit: None,
+                                path_length: path_length + 1,
+                                path_weight: weight / survival,
+                                chain: next_chain(&sample.wi),
+                                last_pdf: sample.pdf,
+                                last_delta: sample.delta,
+                                light_to_brdf: light_to_brdf,
+                                spread: widened_spread(spread, &sample),
+                                dim: next_dim + split * BRANCH_DIMS,
+                            };
+                            self.trace_from(branch, sampler, emit, bounce);
+                        }
+                    }
+                }
+                break 'current_path;
+            }
 
-            let sample_rnds = (thread_rng().next_f32(), thread_rng().next_f32(), thread_rng().next_f32());
-            if let Some(sample) = brdf.sample(sample_rnds) {
-                path_weight = path_weight * sample.radiance;
+            sampler.start_dimension(bounce_dim);
+            if let Some(sample) = brdf.sample_with(sampler) {
+                bounce(sample.kind);
+                path_weight = path_weight * sample.radiance / sample.pdf;
+                chain = next_chain(&sample.wi);
+                last_pdf = sample.pdf;
+                last_delta = sample.delta;
+                spread = widened_spread(spread, &sample);
                 ray.dir = sample.wi;
                 ray.orig = hit_point;
             } else {
                 break 'current_path;
             }
 
-            let russian_roulette = path_weight.sqnorm() * 100.0 < thread_rng().next_f32();
-            if path_length >= MAX_PATH_LENGTH || russian_roulette {
+            let survival = self.settings.survival_probability(path_length, &path_weight);
+            if sampler.next_1d() >= survival {
                 break 'current_path;
             }
+            path_weight = path_weight / survival;
 
             path_length += 1;
+            dim = next_dim;
         }
+    }
+}
+
+// where a path goes on from: its next ray and what the vertex it came from left for the next one
+struct PathState {
+    ray: Ray,
+    first_hit: Option<Option<SurfaceIntersection>>, // of the ray, if it's known already
+    path_length: u32,
+    path_weight: Vec3f,
+    chain: Option<ChainProgress>, // along a chain of the manifold NEE
+    last_pdf: f32, // of the brdf sample the ray came from
+    last_delta: bool, // that sample was of the ideal mirror
+    light_to_brdf: f32, // light samples per brdf sample of that vertex
+    spread: f32, // width of the cone of directions the path stands for, in radians
+    dim: usize, // of the first number of the next vertex
+}
+
+unsafe impl<S> Sync for CpuPtMis<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuPtMis<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        let mut color = Vec3f::zero();
+        self.trace(sample, sampler, &mut |_, radiance| color = color + radiance, &mut |_| {});
         color
     }
 }
 
+impl<S> LightGroupRender<S> for CpuPtMis<S> where S: Scene {
+    fn get_light_groups_nb(&self) -> usize {
+        self.scene.get_light_groups_nb()
+    }
+
+    fn trace_light_groups<R: Sampler>(&self, sample: Vec2f, groups: &mut [Vec3f], sampler: &mut R) {
+        self.trace(sample, sampler, &mut |light_id, radiance| {
+            let group = self.scene.get_light_group(light_id);
+            groups[group] = groups[group] + radiance;
+        }, &mut |_| {});
+    }
+}
+
+impl<S> PathStatsRender<S> for CpuPtMis<S> where S: Scene {
+    fn trace_path_stats<R: Sampler>(&self, sample: Vec2f, counts: &mut BounceCounts, sampler: &mut R) {
+        self.trace(sample, sampler, &mut |_, _| {}, &mut |kind| counts.add(kind));
+    }
+}
+
 impl<S> Render<S> for CpuPtMis<S> where S: Scene {
-    fn new(cam: PerspectiveCamera, scene: S) -> CpuPtMis<S> {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPtMis<S> {
         CpuPtMis {
             camera: cam,
-            scene: scene,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            manifold: None,
+            primary_cache: None,
         }
     }
 
-    fn iterate(&self, iter_nb: usize, frame: &mut RgbFrameBuffer) {
-        self.iterate_over_screen(iter_nb, frame)
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    // the cached camera hits are of the old scene
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.primary_cache = None;
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use brdf::Brdf;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use framebuffer::RgbFrameBuffer;
+    use geometry::{GeometryList, Plane, Ray, Sphere, Triangle};
+    use light::BackgroundLight;
+    use materials_and_colors::{GLASS, GOLDEN_SPEC, PERFECT_MIRROR, WHITE_DIFFUSE};
+    use math::{One, Vec2u, Vec3f};
+    use math::vector_traits::*;
+    use render::{CpuPtDl, CpuPtMis, Render, RenderSettings};
+    use scene::{DefaultScene, LightSelection, Scene};
+    use super::PathState;
+    use utility::seeded_rng;
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.3, 0.3, 0.3) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, GOLDEN_SPEC).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, -6.0), radius: 0.7 }, Vec3f::new(5.0, 5.0, 5.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn lights_are_counted_once() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 3.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, -3.0, 5.0))
+            .build();
+        let mut mis_frame = cam.build_rgb_framebuffer();
+        let mut dl_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam.clone(), scene()).iterate(1, 1024, &mut mis_frame);
+        // only the light sampling, the hits of the lights after bounces don't count there
+        CpuPtDl::new(cam, scene()).iterate(1, 1024, &mut dl_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (mis, dl) = (sum(&mis_frame), sum(&dl_frame));
+        assert!((mis - dl).abs() < 0.03 * dl, "{} instead of {}", mis, dl);
+    }
+
+    #[test]
+    fn direct_lighting_sees_the_lights_over_mirrors_and_through_glass() {
+        let scene = || {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) }, WHITE_DIFFUSE)
+                .unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(-1.2, 1.0, 0.0), radius: 1.0 }, PERFECT_MIRROR).unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(1.2, 1.0, 0.0), radius: 1.0 }, GLASS).unwrap();
+            scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, 2.0), radius: 0.5 }, Vec3f::new(20.0, 20.0, 20.0))
+                .unwrap();
+            scene
+        };
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, -1.0, 1.0))
+            .build();
+        let mut mis_frame = cam.build_rgb_framebuffer();
+        let mut dl_frame = cam.build_rgb_framebuffer();
+        // the caustics of the glass are noisy, fewer paths differ by more than the tolerance
+        CpuPtMis::new(cam.clone(), scene()).iterate(0, 4096, &mut mis_frame);
+        CpuPtDl::new(cam, scene()).iterate(0, 4096, &mut dl_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (mis, dl) = (sum(&mis_frame), sum(&dl_frame));
+        assert!((mis - dl).abs() < 0.03 * mis, "{} instead of {}", dl, mis);
+    }
+
+    #[test]
+    fn refracted_rays_leave_a_glass_sphere_through_the_far_side() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let center = Vec3f::new(0.0, 0.0, 0.0);
+        scene.add_object(Sphere { center: center, radius: 1.0 }, GLASS).unwrap();
+        let ray = Ray { orig: Vec3f::new(0.4, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+        let entry = scene.nearest_intersection(&ray).unwrap();
+        let eps_cosine = scene.get_epsilons().cosine;
+        let brdf = Brdf::new_with_eps(&ray.dir, &entry.normal, &GLASS, eps_cosine).unwrap();
+        let refracted = (0..100).filter_map(|i| brdf.sample((i as f32 / 100.0, 0.5, 0.5)))
+            .find(|sample| sample.wi.dot(&entry.normal) < 0.0)
+            .unwrap();
+
+        // the chord along the refracted direction, and out of the glass again on the other side
+        let inner = Ray { orig: entry.position, dir: refracted.wi };
+        let exit = scene.nearest_intersection(&inner).expect("the refracted ray escaped the sphere");
+        let chord = -2.0 * (entry.position - center).dot(&refracted.wi);
+        assert!((exit.dist - chord).abs() < 1e-3, "{} instead of {}", exit.dist, chord);
+        assert!(((exit.position - center).norm() - 1.0).abs() < 1e-3);
+        let brdf = Brdf::new_with_eps(&inner.dir, &exit.normal, &GLASS, eps_cosine).unwrap();
+        assert!((0..100).filter_map(|i| brdf.sample((i as f32 / 100.0, 0.5, 0.5)))
+            .any(|sample| sample.wi.dot(&exit.normal) > 0.0));
+    }
+
+    #[test]
+    fn light_over_an_ideal_mirror_is_all_brdf_sampled() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) }, PERFECT_MIRROR)
+            .unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(0.0, 2.0, 2.0), radius: 0.3 }, Vec3f::new(5.0, 5.0, 5.0))
+            .unwrap();
+        let cam = CameraBuilder::<PerspectiveCamera>::new().with_view_size(Vec2u::new(1, 1)).build();
+        let render = CpuPtMis::new(cam, scene);
+        let path = PathState {
+            ray: Ray { orig: Vec3f::new(0.0, 1.0, -1.0), dir: Vec3f::new(0.0, -1.0, 1.0).normalize() },
+            first_hit: None,
+            path_length: 0,
+            path_weight: Vec3f::one(),
+            chain: None,
+            last_pdf: 0.0,
+            last_delta: false,
+            light_to_brdf: 1.0,
+            spread: 0.0,
+            dim: 0,
+        };
+        // nothing else can reach the light, so its reflection comes whole
+        let mut sum = Vec3f::new(0.0, 0.0, 0.0);
+        render.trace_from(path, &mut seeded_rng(0, 0), &mut |_, radiance| sum = sum + radiance, &mut |_| {});
+        assert!((sum.x - 0.99 * 5.0).abs() < 1e-3, "{:?}", sum);
+    }
+
+    #[test]
+    fn failed_manifold_solves_keep_the_light_over_the_mirror() {
+        // the floor is within the bounding sphere of the mirror wall, so no solve starts from it
+        let scene = || {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) },
+                             WHITE_DIFFUSE).unwrap();
+            scene.add_object(Triangle::new(Vec3f::new(10.0, 0.0, 1.0), Vec3f::new(-10.0, 0.0, 1.0),
+                                           Vec3f::new(0.0, 10.0, 1.0)), PERFECT_MIRROR).unwrap();
+            scene.add_luminous_object(Sphere { center: Vec3f::new(0.0, 3.0, -4.0), radius: 1.0 },
+                                      Vec3f::new(5.0, 5.0, 5.0)).unwrap();
+            scene
+        };
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(16, 16))
+            .with_pos(Vec3f::new(0.0, 1.0, -2.0))
+            .with_look_at(Vec3f::new(0.0, -1.0, 1.0))
+            .build();
+        let mut plain_frame = cam.build_rgb_framebuffer();
+        let mut manifold_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam.clone(), scene()).iterate(1, 256, &mut plain_frame);
+        CpuPtMis::new(cam, scene()).with_manifold_nee().iterate(1, 256, &mut manifold_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (plain, manifold) = (sum(&plain_frame), sum(&manifold_frame));
+        assert!((manifold - plain).abs() < 0.03 * plain, "{} instead of {}", manifold, plain);
+    }
+
+    #[test]
+    fn manifold_nee_gathers_the_caustic_of_a_glass_ball() {
+        let scene = || {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) },
+                             WHITE_DIFFUSE).unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(0.0, 1.5, 0.0), radius: 1.0 }, GLASS).unwrap();
+            scene.add_luminous_object(Sphere { center: Vec3f::new(0.0, 5.0, 0.0), radius: 0.7 },
+                                      Vec3f::new(5.0, 5.0, 5.0)).unwrap();
+            scene
+        };
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(16, 16))
+            .with_pos(Vec3f::new(0.0, 0.3, -4.0))
+            .with_look_at(Vec3f::new(0.0, -0.3, 4.0))
+            .build();
+        let mut plain_frame = cam.build_rgb_framebuffer();
+        let mut manifold_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam, scene()).iterate(1, 4096, &mut plain_frame);
+        CpuPtMis::new(cam, scene()).with_manifold_nee().iterate(1, 256, &mut manifold_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        // the frames sum the samples
+        let (plain, manifold) = (sum(&plain_frame) / 4096.0, sum(&manifold_frame) / 256.0);
+        assert!((manifold - plain).abs() < 0.05 * plain, "{} instead of {}", manifold, plain);
+    }
+
+    #[test]
+    fn cached_camera_hits_give_the_same_frame() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(16, 16))
+            .with_pos(Vec3f::new(0.0, 3.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, -3.0, 5.0))
+            .build();
+        let mut cached_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        let cached = CpuPtMis::new(cam.clone(), scene()).with_primary_cache(4).unwrap();
+        // most pixels see only the floor or the sphere
+        let hits_nb = cached.primary_cache.as_ref().unwrap().hits_nb();
+        assert!(hits_nb > 16 * 16 * 4 && hits_nb < 16 * 16 * 16 / 2, "{}", hits_nb);
+        cached.iterate(1, 256, &mut cached_frame);
+        CpuPtMis::new(cam, scene()).iterate(1, 256, &mut pt_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (cached, pt) = (sum(&cached_frame), sum(&pt_frame));
+        assert!((cached - pt).abs() < 0.02 * pt, "{} instead of {}", cached, pt);
+    }
+
+    #[test]
+    fn blue_sky_gives_the_same_frame() {
+        // a ray up from over the floor misses everything, one toward the sphere can't be told apart from a hit
+        let up = Ray { orig: Vec3f::new(3.0, 0.5, 0.0), dir: Vec3f::new(0.0, 1.0, 0.0) };
+        let to_sphere = Ray { orig: Vec3f::new(3.0, 0.5, 0.0), dir: Vec3f::new(-1.0, 0.0, 0.0) };
+        let mut bounded = scene();
+        // nothing escapes until the bounds are fitted
+        assert!(!bounded.escapes(&up));
+        bounded.commit_changes();
+        assert!(bounded.escapes(&up) && !bounded.escapes(&to_sphere));
+        let mut unbounded = scene();
+        unbounded.add_object(Plane { point: Vec3f::new(0.0, 10.0, 0.0), normal: Vec3f::new(0.0, -1.0, 0.0) }, WHITE_DIFFUSE)
+            .unwrap();
+        unbounded.commit_changes();
+        assert!(!unbounded.escapes(&up));
+
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 3.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, -3.0, 5.0))
+            .build();
+        let mut sky_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        let settings = RenderSettings::new().with_deterministic();
+        CpuPtMis::new_with_settings(cam.clone(), scene(), settings).iterate(1, 64, &mut sky_frame);
+        CpuPtMis::new_with_settings(cam, scene(), settings.with_blue_sky(false)).iterate(1, 64, &mut pt_frame);
+        // the escaping bounces miss in both, with the same numbers drawn
+        assert!(sky_frame.as_slice() == pt_frame.as_slice());
+    }
+
+    #[test]
+    fn split_first_bounce_keeps_the_frame() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 3.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, -3.0, 5.0))
+            .build();
+        let mut split_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        let settings = RenderSettings::new().with_splitting(4, 2);
+        CpuPtMis::new_with_settings(cam.clone(), scene(), settings).iterate(1, 256, &mut split_frame);
+        CpuPtMis::new(cam, scene()).iterate(1, 1024, &mut pt_frame);
+
+        // a split sample is worth one of the plain ones
+        let sum = |frame: &RgbFrameBuffer, spp: f32| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x) / spp;
+        let (split, pt) = (sum(&split_frame, 256.0), sum(&pt_frame, 1024.0));
+        assert!((split - pt).abs() < 0.03 * pt, "{} instead of {}", split, pt);
+    }
+
+    #[test]
+    fn light_selections_keep_the_frame() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 3.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, -3.0, 5.0))
+            .build();
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam.clone(), scene()).iterate(1, 1024, &mut pt_frame);
+        let pt = sum(&pt_frame);
+        for &selection in [LightSelection::Power, LightSelection::Tree].iter() {
+            let mut selected_scene = scene();
+            selected_scene.set_light_selection(selection);
+            if selection == LightSelection::Power {
+                // the dim background around the huge floor outshines the small sphere, which is left to the bounces
+                assert!(selected_scene.light_pick_prob(&Vec3f::new(0.0, 0.0, 0.0), 1) < 0.01);
+            }
+            let mut frame = cam.build_rgb_framebuffer();
+            CpuPtMis::new(cam.clone(), selected_scene).iterate(1, 1024, &mut frame);
+            let selected = sum(&frame);
+            assert!((selected - pt).abs() < 0.03 * pt, "{:?}: {} instead of {}", selection, selected, pt);
+        }
     }
 }
@@ -0,0 +1,316 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::{Ray, SurfaceIntersection};
+use math::{Vec3f, Vec2f, Zero, One};
+use rand::Rng;
+use rayon::prelude::*;
+use render::{Render, PathGuard, PixelSampler, Watchdog, RenderSettings, pixel_rng};
+use render::cpu_pt_dl::{emitted, light_sample, LIGHT_DIMS, VERTEX_DIMS};
+use render::stratified::STRATIFIED_DIMS;
+use scene::{LayerVisibility, Scene, SurfaceProperties};
+use utility::{seeded_rng, Pcg32, Sampler};
+
+// paths in flight at once, the queues are this long at the start of a wave
+const WAVE_PATHS: usize = 1 << 16;
+// rng streams of the paths, next to the jitter ones ((iter_nb << 32) | pix_nb)
+const WAVEFRONT_STREAMS: u64 = 1 << 56;
+
+// everything a path carries from one stage to the next
+struct PathState<'a> {
+    pix_nb: usize,
+    ray: Ray,
+    weight: Vec3f,
+    color: Vec3f,
+    length: u32,
+    last_delta: bool, // the ray came from the ideal mirror or smooth glass
+    numbers: PathNumbers,
+    guard: PathGuard<'a>,
+    isect: Option<SurfaceIntersection>,
+    // the light sample of the current vertex: the ray to the light, its length and the contribution
+    // if nothing's in the way
+    shadow: Option<(Ray, f32, Vec3f)>,
+    alive: bool,
+}
+
+// The numbers of a path in flight. The pixel's sampler moves on to the next sample while the path waits in
+// the queue, so the placed numbers of the path's sample are copied out when it starts, the dimensions
+// past them come from the path's own stream
+struct PathNumbers {
+    placed: [u32; STRATIFIED_DIMS],
+    dim: usize,
+    rng: Pcg32,
+}
+
+impl Sampler for PathNumbers {
+    fn start_dimension(&mut self, dim: usize) {
+        self.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        self.dim += 1;
+        if self.dim <= STRATIFIED_DIMS { self.placed[self.dim - 1] } else { self.rng.next_u32() }
+    }
+}
+
+// The same estimator as `CpuPtDl`, with the same vertices, but instead of following a path from the camera to its end
+// before starting the next one, all the paths of a wave make a step together: the queue of rays
+// is intersected, then the hits are shaded, then the shadow rays of the light sampling are traced,
+// and the paths which ended are taken out of the queue. Every stage runs over a long array doing
+// the same thing for every element, which keeps the scene data in the caches and is the layout
+// a GPU or SIMD traversal needs
+pub struct CpuPtWavefront<S: Scene> {
+    scene: S,
+    camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    wave_paths: usize,
+}
+
+impl<S> CpuPtWavefront<S> where S: Scene {
+    pub fn with_wave_paths(mut self, wave_paths: usize) -> CpuPtWavefront<S> {
+        self.wave_paths = wave_paths.max(1);
+        self
+    }
+
+    // camera rays of the pixels `pixels`, `spp` for every one of them
+    fn generate<'a>(&'a self, iter_nb: usize, spp: usize, pixels: ::std::ops::Range<usize>) -> Vec<PathState<'a>> {
+        let res_x = self.camera.get_view_size().x as usize;
+        let seed = self.scene.get_seed();
+        let mut paths = Vec::with_capacity(pixels.len() * spp);
+        let mut sampler = self.new_pixel_sampler();
+        for pix_nb in pixels {
+            let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+            let mut rng = pixel_rng(seed, iter_nb, pix_nb);
+            sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut rng, spp);
+            for i in 0..spp {
+                let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(i);
+                let mut placed = [0; STRATIFIED_DIMS];
+                for number in placed.iter_mut() {
+                    *number = sampler.next_bits();
+                }
+                paths.push(PathState {
+                    pix_nb: pix_nb,
+                    ray: self.camera.ray_from_screen(&sample),
+                    weight: Vec3f::one(),
+                    color: Vec3f::zero(),
+                    length: 0,
+                    last_delta: false,
+                    numbers: PathNumbers { placed: placed, dim: 0, rng: seeded_rng(rng.next_u32(), WAVEFRONT_STREAMS) },
+                    guard: self.watchdog.path_guard(),
+                    isect: None,
+                    shadow: None,
+                    alive: true,
+                });
+            }
+        }
+        paths
+    }
+
+    fn intersect(&self, paths: &mut [PathState]) {
+        paths.par_iter_mut().for_each(|path| {
+            path.isect = self.scene.nearest_intersection(&path.ray);
+        });
+    }
+
+    // emission, the light sample and the next ray of every path; ends the paths which left the scene,
+    // hit a light or were killed by the russian roulette
+    fn shade(&self, paths: &mut [PathState]) {
+        paths.par_iter_mut().for_each(|path| {
+            path.shadow = None;
+            path.alive = false;
+            let isect = match path.isect {
+                Some(ref isect) if !path.guard.add_vertex(isect.dist) => return,
+                Some(isect) => isect,
+                None => {
+                    path.color = path.color + emitted(&self.scene, &self.settings, &path.ray, None, path.length,
+                                                      &path.weight, path.last_delta);
+                    return;
+                }
+            };
+            let hit_point = path.ray.orig + path.ray.dir * isect.dist;
+            let brdf = match isect.surface {
+                SurfaceProperties::Material(mat_id) => {
+                    if path.length == 0 && self.scene.get_layer_visibility(mat_id) == LayerVisibility::Holdout {
+                        return;
+                    }
+                    let material = self.scene.get_surface_material(mat_id, &isect);
+                    let normal = self.scene.get_shading_normal(mat_id, &isect);
+                    let eps_cosine = self.scene.get_epsilons().cosine;
+                    match Brdf::new_with_eps(&path.ray.dir, &normal, &material, eps_cosine) {
+                        Some(brdf) => brdf,
+                        None       => return
+                    }
+                },
+                SurfaceProperties::Light(light_id) => {
+                    path.color = path.color + emitted(&self.scene, &self.settings, &path.ray, Some(light_id), path.length,
+                                                      &path.weight, path.last_delta);
+                    return;
+                }
+            };
+
+            let (weight, length) = (path.weight, path.length);
+            let dim = length as usize * VERTEX_DIMS;
+            path.numbers.start_dimension(dim);
+            path.shadow = light_sample(&self.scene, &hit_point, &brdf, &mut path.numbers)
+                .map(|(_, ray, dist, radiance)| (ray, dist, self.settings.clamp_contribution(radiance * weight, length + 1)));
+
+            path.numbers.start_dimension(dim + LIGHT_DIMS);
+            let sample = match brdf.sample_with(&mut path.numbers) {
+                Some(sample) => sample,
+                None         => return
+            };
+            path.weight = path.weight * sample.radiance / sample.pdf;
+            path.last_delta = sample.delta;
+            path.ray = Ray { orig: hit_point, dir: sample.wi };
+
+            let survival = self.settings.survival_probability(path.length, &path.weight);
+            path.alive = path.numbers.next_1d() < survival;
+            if path.alive {
+                path.weight = path.weight / survival;
+            }
+            path.length += 1;
+        });
+    }
+
+    fn trace_shadows(&self, paths: &mut [PathState]) {
+        paths.par_iter_mut().for_each(|path| {
+            if let Some((ray, dist, radiance)) = path.shadow.take() {
+                if !self.scene.was_occluded(&ray, dist) {
+                    path.color = path.color + radiance;
+                }
+            }
+        });
+    }
+}
+
+unsafe impl<S> Sync for CpuPtWavefront<S> where S: Scene {}
+
+impl<S> Render<S> for CpuPtWavefront<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuPtWavefront<S> {
+        CpuPtWavefront {
+            camera: cam,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            wave_paths: WAVE_PATHS,
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        if spp == 0 {
+            return;
+        }
+        let pixels_nb = frame.as_slice().len();
+        let wave_pixels = (self.wave_paths / spp).max(1);
+        let mut first_pixel = 0;
+        while first_pixel < pixels_nb {
+            let last_pixel = (first_pixel + wave_pixels).min(pixels_nb);
+            let mut paths = self.generate(iter_nb, spp, first_pixel..last_pixel);
+            while !paths.is_empty() {
+                self.intersect(&mut paths);
+                self.shade(&mut paths);
+                self.trace_shadows(&mut paths);
+                // the ended paths leave the queue with their color
+                let pixels = frame.as_mut_slice();
+                for path in paths.iter().filter(|path| !path.alive) {
+                    pixels[path.pix_nb] = pixels[path.pix_nb] + path.color;
+                }
+                paths.retain(|path| path.alive);
+            }
+            first_pixel = last_pixel;
+        }
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuPtWavefront;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use framebuffer::RgbFrameBuffer;
+    use geometry::{GeometryList, Plane, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::{GLASS, PERFECT_MIRROR, WHITE_DIFFUSE};
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtDl, Render};
+    use scene::{DefaultScene, LightSelection, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.1, 0.1, 0.1) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(1.0, 4.0, -3.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        scene
+    }
+
+    #[test]
+    fn frame_matches_the_megakernel() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -6.0))
+            .with_look_at(Vec3f::new(0.0, -4.0, 6.0))
+            .build();
+        let mut wavefront_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        // waves shorter than the frame, so they split it
+        let wavefront = CpuPtWavefront::new(cam.clone(), scene()).with_wave_paths(1000);
+        wavefront.iterate(1, 256, &mut wavefront_frame);
+        CpuPtDl::new(cam, scene()).iterate(1, 256, &mut pt_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (wavefront_sum, pt_sum) = (sum(&wavefront_frame), sum(&pt_frame));
+        assert!((wavefront_sum - pt_sum).abs() < 0.02 * pt_sum, "{} instead of {}", wavefront_sum, pt_sum);
+        assert!(wavefront_frame.as_slice().iter().all(|pix| pix.x > 0.0));
+
+        // the same numbers in every run, whatever the order of the paths in the queue
+        let mut again = RgbFrameBuffer::new(Vec2u::new(8, 8));
+        wavefront.iterate(1, 256, &mut again);
+        assert_eq!(again.as_slice(), wavefront_frame.as_slice());
+    }
+
+    #[test]
+    fn lights_over_mirrors_and_through_glass_match_the_megakernel() {
+        let scene = || {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) }, WHITE_DIFFUSE)
+                .unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(-1.2, 1.0, 0.0), radius: 1.0 }, PERFECT_MIRROR).unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(1.2, 1.0, 0.0), radius: 1.0 }, GLASS).unwrap();
+            // a dim light and a bright one, which the power selection picks more often
+            scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, 2.0), radius: 0.5 }, Vec3f::new(20.0, 20.0, 20.0))
+                .unwrap();
+            scene.add_light(PointLight { position: Vec3f::new(-3.0, 3.0, -2.0), intensity: Vec3f::new(1.0, 1.0, 1.0) });
+            scene.set_light_selection(LightSelection::Power);
+            scene
+        };
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, -1.0, 1.0))
+            .build();
+        let mut wavefront_frame = cam.build_rgb_framebuffer();
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuPtWavefront::new(cam, scene()).iterate(0, 4096, &mut wavefront_frame);
+        CpuPtDl::new(cam, scene()).iterate(0, 4096, &mut pt_frame);
+
+        let sum = |frame: &RgbFrameBuffer| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x);
+        let (wavefront_sum, pt_sum) = (sum(&wavefront_frame), sum(&pt_frame));
+        assert!((wavefront_sum - pt_sum).abs() < 0.03 * pt_sum, "{} instead of {}", wavefront_sum, pt_sum);
+    }
+}
@@ -0,0 +1,334 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::Ray;
+use math::vector_traits::*;
+use math::{Vec3f, Vec2f, Zero, One};
+use render::{Render, CpuMtRender, Watchdog, RenderSettings};
+use scene::{LayerVisibility, Scene, SurfaceProperties};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use utility::Sampler;
+
+// cells along the diameter of the scene's bounding sphere
+const CELLS_PER_DIAMETER: f32 = 128.0;
+// the samples a cell averages, older ones fade out so the cell follows the cache getting brighter
+const MAX_CELL_SAMPLES: f32 = 64.0;
+// diffuse vertices shaded after the camera hit, before the cache is looked up; at least one, the cells
+// only learn the light of the vertices which are shaded
+const SHADED_BOUNCES: u32 = 1;
+// camera rays and bounces follow mirrors and glass this far to a diffuse surface
+const MAX_SPECULAR_BOUNCES: u32 = 8;
+
+// a cell of the grid and the side of the surfaces it holds, the dominant axis of their normal
+// with its sign, so the two sides of a wall don't share the light
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct CellKey {
+    x: i32,
+    y: i32,
+    z: i32,
+    side: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    sum: Vec3f,
+    count: f32,
+}
+
+// the estimates of the paths the thread traced in its current tile, see `end_tile`
+thread_local!(static TILE_RECORDS: RefCell<Vec<(CellKey, Vec3f)>> = RefCell::new(Vec::new()));
+
+// a diffuse hit: what the cache and the light sampling need of it
+struct Vertex {
+    pos: Vec3f,
+    normal: Vec3f,
+    brdf: Brdf,
+}
+
+enum Hit {
+    Diffuse(Vertex),
+    Ended(Vec3f), // on a light, in the background or nowhere, with the light the camera sees there
+}
+
+// Radiance caching for previews of the global illumination: the light leaving the surfaces is
+// averaged in a coarse world-space hash grid. A camera path shades its first diffuse hit and the
+// next one with the light sampling, then takes the rest of the light from the cell of the hit after
+// them instead of tracing on. Every shaded vertex adds its estimate to its cell once the iteration
+// is over, so every iteration brings in one more bounce and the cache converges from dark to the
+// full light within a few of them. The cells blur the light and assume diffuse surfaces, which is
+// fine for looking around, not for the final frame
+pub struct CpuRc<S: Scene> {
+    scene: S,
+    camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    cache: RwLock<HashMap<CellKey, Cell>>,
+    records: Mutex<Vec<(CellKey, Vec3f)>>, // of the current iteration
+    cell_size: f32,
+    shaded_bounces: u32,
+}
+
+impl<S> CpuRc<S> where S: Scene {
+    pub fn with_cell_size(mut self, cell_size: f32) -> CpuRc<S> {
+        self.cell_size = cell_size;
+        self.cache.get_mut().unwrap().clear();
+        self
+    }
+
+    pub fn with_shaded_bounces(mut self, shaded_bounces: u32) -> CpuRc<S> {
+        self.shaded_bounces = shaded_bounces.max(1);
+        self
+    }
+
+    pub fn cells_nb(&self) -> usize {
+        self.cache.read().unwrap().len()
+    }
+
+    fn cell_key(&self, vertex: &Vertex) -> CellKey {
+        let cell = vertex.pos / self.cell_size;
+        let n = vertex.normal;
+        let axis = if n.x.abs() >= n.y.abs() && n.x.abs() >= n.z.abs() {
+            (0, n.x)
+        } else if n.y.abs() >= n.z.abs() {
+            (1, n.y)
+        } else {
+            (2, n.z)
+        };
+        CellKey {
+            x: cell.x.floor() as i32,
+            y: cell.y.floor() as i32,
+            z: cell.z.floor() as i32,
+            side: axis.0 * 2 + if axis.1 < 0.0 { 1 } else { 0 },
+        }
+    }
+
+    fn trace<R: Sampler>(&self, ray: Ray, sampler: &mut R) -> Vec3f {
+        let cache = self.cache.read().unwrap();
+        let mut throughput = Vec3f::one();
+        let vertex = match self.next_vertex(ray, &mut throughput, true, sampler) {
+            Hit::Diffuse(vertex) => vertex,
+            Hit::Ended(radiance) => return radiance
+        };
+        let radiance = TILE_RECORDS.with(|records| self.vertex_radiance(&cache, &vertex, 0, &mut records.borrow_mut(), sampler));
+        throughput * radiance
+    }
+
+    // Follows `ray` through mirrors and glass to the next diffuse surface, their weights are gathered
+    // in `throughput`. Lights and the background only count for camera rays, the light sampling
+    // brings them to the diffuse vertices
+    fn next_vertex<R: Sampler>(&self, ray: Ray, throughput: &mut Vec3f, camera_ray: bool, sampler: &mut R) -> Hit {
+        let scene = &self.scene;
+        let mut ray = ray;
+        for bounce in 0..MAX_SPECULAR_BOUNCES {
+            let isect = match scene.nearest_intersection(&ray) {
+                Some(isect) => isect,
+                None        => {
+                    if !camera_ray {
+                        return Hit::Ended(Vec3f::zero());
+                    }
+                    let background = scene.get_background_light().radiate(&ray).map_or(Vec3f::zero(), |rad| rad.radiance);
+                    return Hit::Ended(*throughput * background);
+                }
+            };
+            let m_id = match isect.surface {
+                SurfaceProperties::Material(m_id) => m_id,
+                SurfaceProperties::Light(light_id) => {
+                    if !camera_ray {
+                        return Hit::Ended(Vec3f::zero());
+                    }
+                    let emitted = scene.get_light(light_id).radiate(&ray).map_or(Vec3f::zero(), |rad| {
+                        if bounce == 0 { self.settings.clamp_light(rad.radiance) } else { rad.radiance }
+                    });
+                    return Hit::Ended(*throughput * emitted);
+                }
+            };
+            if camera_ray && bounce == 0 && scene.get_layer_visibility(m_id) == LayerVisibility::Holdout {
+                return Hit::Ended(Vec3f::zero());
+            }
+            let hit_point = ray.orig + ray.dir * isect.dist;
+            let material = scene.get_surface_material(m_id, &isect);
+            let normal = scene.get_shading_normal(m_id, &isect);
+            let brdf = match Brdf::new_with_eps(&ray.dir, &normal, &material, scene.get_epsilons().cosine) {
+                Some(brdf) => brdf,
+                None       => return Hit::Ended(Vec3f::zero())
+            };
+            if !material.is_specular() {
+                return Hit::Diffuse(Vertex { pos: hit_point, normal: normal, brdf: brdf });
+            }
+            let sample = match brdf.sample_with(sampler) {
+                Some(sample) => if sample.pdf > 0.0 { sample } else { return Hit::Ended(Vec3f::zero()) },
+                None         => return Hit::Ended(Vec3f::zero())
+            };
+            *throughput = *throughput * sample.radiance / sample.pdf;
+            ray = Ray { orig: hit_point, dir: sample.wi };
+        }
+        Hit::Ended(Vec3f::zero())
+    }
+
+    // Light leaving `vertex` along the ray which found it: the light sample and one bounce to the next
+    // diffuse vertex, which is shaded the same way or, after the shaded bounces, is looked up in the cache.
+    // The estimate goes to `records` for the cell of the vertex
+    fn vertex_radiance<R: Sampler>(&self, cache: &HashMap<CellKey, Cell>, vertex: &Vertex, path_length: u32,
+                                   records: &mut Vec<(CellKey, Vec3f)>, sampler: &mut R) -> Vec3f {
+        let mut radiance = self.sample_light(&vertex.pos, &vertex.brdf, sampler);
+        if path_length < self.settings.max_depth {
+            if let Some(sample) = vertex.brdf.sample_with(sampler).filter(|sample| sample.pdf > 0.0) {
+                let mut throughput = sample.radiance / sample.pdf;
+                if let Hit::Diffuse(next) = self.next_vertex(Ray { orig: vertex.pos, dir: sample.wi }, &mut throughput, false, sampler) {
+                    let incoming = if path_length < self.shaded_bounces {
+                        self.vertex_radiance(cache, &next, path_length + 1, records, sampler)
+                    } else {
+                        cache.get(&self.cell_key(&next)).map_or(Vec3f::zero(), |cell| cell.sum / cell.count)
+                    };
+                    radiance = radiance + throughput * incoming;
+                }
+            }
+        }
+        records.push((self.cell_key(vertex), radiance));
+        radiance
+    }
+
+    fn sample_light<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, sampler: &mut R) -> Vec3f {
+        let (light_id, light_pick_prob) = match self.scene.pick_light(hit_point, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return Vec3f::zero()
+        };
+        let light = self.scene.get_light(light_id);
+        let illum = match light.illuminate(hit_point, sampler.next_2d()) {
+            Some(illum) => if illum.pdf > 0.0 { illum } else { return Vec3f::zero() },
+            None        => return Vec3f::zero()
+        };
+        let eval = match brdf.eval(&illum.l_dir) {
+            Some(eval) => eval,
+            None       => return Vec3f::zero()
+        };
+        if self.scene.was_occluded(&Ray { orig: *hit_point, dir: illum.l_dir }, illum.l_dist) {
+            return Vec3f::zero();
+        }
+        illum.radiance * eval.radiance / (light_pick_prob * illum.pdf)
+    }
+
+    // the estimates of the iteration are looked up from the next one on
+    fn update(&self) {
+        let mut records = ::std::mem::replace(&mut *self.records.lock().unwrap(), Vec::new());
+        if self.settings.deterministic {
+            // the threads pushed them in any order, the sums and the cap of the cells depend on it
+            records.sort_by_key(|&(key, radiance)| (key, radiance.x.to_bits(), radiance.y.to_bits(), radiance.z.to_bits()));
+        }
+        let mut cache = self.cache.write().unwrap();
+        for (key, radiance) in records.into_iter().filter(|&(_, radiance)| radiance.fold(f32::max).is_finite()) {
+            let cell = cache.entry(key).or_insert(Cell { sum: Vec3f::zero(), count: 0.0 });
+            cell.sum = cell.sum + radiance;
+            cell.count += 1.0;
+            if cell.count > MAX_CELL_SAMPLES {
+                cell.sum = cell.sum * (MAX_CELL_SAMPLES / cell.count);
+                cell.count = MAX_CELL_SAMPLES;
+            }
+        }
+    }
+}
+
+unsafe impl<S> Sync for CpuRc<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuRc<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        self.trace(self.camera.ray_from_screen(&sample), sampler)
+    }
+
+    fn end_tile(&self) {
+        TILE_RECORDS.with(|records| self.records.lock().unwrap().extend(records.borrow_mut().drain(..)));
+    }
+}
+
+impl<S> Render<S> for CpuRc<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuRc<S> {
+        let cell_size = scene.get_bounding_sphere().map_or(1.0, |sphere| 2.0 * sphere.radius / CELLS_PER_DIAMETER);
+        CpuRc {
+            camera: cam,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            cache: RwLock::new(HashMap::new()),
+            records: Mutex::new(Vec::new()),
+            cell_size: cell_size,
+            shaded_bounces: SHADED_BOUNCES,
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame);
+        self.update();
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    // the cached light is of the old scene
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.cache.get_mut().unwrap().clear();
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuRc;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use framebuffer::RgbFrameBuffer;
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 4.0, -3.0), radius: 0.5 }, Vec3f::new(10.0, 10.0, 10.0))
+            .unwrap();
+        scene
+    }
+
+    #[test]
+    fn warmed_up_cache_matches_the_path_tracer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 1.0, 2.5))
+            .with_look_at(Vec3f::new(0.0, -0.2, -1.0))
+            .build();
+        let rc = CpuRc::new(cam.clone(), scene()).with_cell_size(0.25);
+        let mut frame = cam.build_rgb_framebuffer();
+        for iter_nb in 1..5 {
+            rc.iterate(iter_nb, 64, &mut frame);
+        }
+        assert!(rc.cells_nb() > 0);
+        let mut rc_frame = cam.build_rgb_framebuffer();
+        for iter_nb in 5..9 {
+            rc.iterate(iter_nb, 64, &mut rc_frame);
+        }
+        let mut pt_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new(cam, scene()).iterate(1, 4096, &mut pt_frame);
+
+        // the back of the sphere, lit by the floor: the direct light alone is 13% darker
+        let sum = |frame: &RgbFrameBuffer, spp: f32| frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x) / spp;
+        let (cached, pt) = (sum(&rc_frame, 256.0), sum(&pt_frame, 4096.0));
+        assert!((cached - pt).abs() < 0.05 * pt, "{} instead of {}", cached, pt);
+    }
+}
@@ -0,0 +1,212 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use math::vector_traits::*;
+use math::{Vec3f, Vec2f, Zero};
+use rayon::prelude::*;
+use render::{Render, CpuMtRender, CpuBdpt, Watchdog, RenderSettings};
+use render::cpu_bdpt::{LightVertex, SubpathState, VcmWeights, MAX_PATH_LENGTH, mis};
+use render::hash_grid::HashGrid;
+use scene::Scene;
+use std::f32::consts::PI;
+use std::sync::RwLock;
+use utility::{seeded_rng, Sampler};
+
+const MERGE_RADIUS: f32 = 0.003; // of the scene's bounding sphere, in the first iteration
+// the radius shrinks as iteration^((RADIUS_ALPHA - 1) / 2) (Knaus and Zwicker 2011)
+const RADIUS_ALPHA: f32 = 0.75;
+// rng streams next to the pixel ones ((iter_nb << 32) | pix_nb)
+const LIGHT_PATH_STREAMS: u64 = 1 << 60;
+
+// Vertex connection and merging (Georgiev et al. 2012): the light paths of the bidirectional
+// tracer are traced for the whole frame first, one per pixel, and besides the connections
+// every non-specular eye vertex merges with the light vertices around it, as photon mapping does.
+// Merging finds the specular-diffuse-specular paths connections can't, the MIS weights keep
+// each strategy where it's better. The merge radius shrinks with iterations, so it converges
+pub struct CpuVcm<S: Scene> {
+    bdpt: CpuBdpt<S>,
+    camera: PerspectiveCamera,
+    radius: Option<f32>,
+    light_paths: RwLock<LightPaths>, // of the current iteration
+}
+
+struct LightPaths {
+    vertices: Vec<LightVertex>,
+    path_ends: Vec<usize>, // the vertices of path i end at path_ends[i]
+    grid: HashGrid, // of the non-specular vertices, indices are into `mergeable`
+    mergeable: Vec<usize>,
+    weights: VcmWeights,
+    normalization: f32, // 1 / (merge area * light paths)
+}
+
+impl<S> CpuVcm<S> where S: Scene {
+    // radius of the first iteration
+    pub fn with_radius(mut self, radius: f32) -> CpuVcm<S> {
+        self.radius = Some(radius);
+        self
+    }
+
+    fn radius(&self, iter_nb: usize) -> f32 {
+        let base = self.radius.unwrap_or_else(|| {
+            self.get_scene().get_bounding_sphere().map_or(1.0, |sphere| sphere.radius * MERGE_RADIUS)
+        });
+        base * (iter_nb.max(1) as f32).powf((RADIUS_ALPHA - 1.0) * 0.5)
+    }
+
+    fn trace_light_paths(&self, iter_nb: usize, paths_nb: usize) -> LightPaths {
+        let radius = self.radius(iter_nb);
+        let eta = PI * radius * radius * paths_nb as f32;
+        let weights = VcmWeights { vm: mis(eta), vc: mis(1.0 / eta) };
+        let seed = self.get_scene().get_seed();
+        let mut paths = (0..paths_nb).map(|_| Vec::new()).collect::<Vec<_>>();
+        paths.par_iter_mut().enumerate().for_each(|(path_nb, vertices)| {
+            let mut rng = seeded_rng(seed, LIGHT_PATH_STREAMS | ((iter_nb as u64) << 32) | path_nb as u64);
+            self.bdpt.trace_light_path(&weights, vertices, &mut rng);
+        });
+
+        let mut path_ends = Vec::with_capacity(paths_nb);
+        let mut vertices = Vec::new();
+        for path in paths {
+            vertices.extend(path);
+            path_ends.push(vertices.len());
+        }
+        let mergeable = (0..vertices.len()).filter(|&idx| !vertices[idx].specular).collect::<Vec<_>>();
+        let grid = HashGrid::new(mergeable.iter().map(|&idx| vertices[idx].pos).collect(), radius);
+        LightPaths {
+            vertices: vertices,
+            path_ends: path_ends,
+            grid: grid,
+            mergeable: mergeable,
+            weights: weights,
+            normalization: 1.0 / eta,
+        }
+    }
+
+    fn trace<R: Sampler>(&self, sample: Vec2f, paths: &LightPaths, sampler: &mut R) -> Vec3f {
+        let ray = self.camera.ray_from_screen(&sample);
+        if paths.path_ends.is_empty() {
+            return self.bdpt.trace_eye(ray, &[], &paths.weights, sampler, &mut |_, _, _| Vec3f::zero());
+        }
+        // connections go to a random light path
+        let path_nb = sampler.next_index(paths.path_ends.len());
+        let start = if path_nb == 0 { 0 } else { paths.path_ends[path_nb - 1] };
+        let light_vertices = &paths.vertices[start..paths.path_ends[path_nb]];
+        self.bdpt.trace_eye(ray, light_vertices, &paths.weights, sampler, &mut |hit_point, brdf, state| {
+            merge(paths, hit_point, brdf, state)
+        })
+    }
+}
+
+// light of the light vertices around the eye vertex, weighted against the other strategies
+fn merge(paths: &LightPaths, hit_point: &Vec3f, brdf: &Brdf, state: &SubpathState) -> Vec3f {
+    let normal = brdf.normal();
+    let mut color = Vec3f::zero();
+    paths.grid.for_each_near(hit_point, &mut |idx| {
+        let vertex = &paths.vertices[paths.mergeable[idx]];
+        if vertex.path_length + state.path_length > MAX_PATH_LENGTH {
+            return;
+        }
+        let cos = normal.dot(&vertex.wi);
+        if cos <= 0.0 {
+            return;
+        }
+        let eval = match brdf.eval(&vertex.wi) {
+            Some(eval) => eval,
+            None       => return
+        };
+        let w_light = vertex.d_vcm * paths.weights.vc + vertex.d_vm * mis(eval.pdf);
+        let w_camera = state.d_vcm * paths.weights.vc + state.d_vm * mis(brdf.reverse_pdf(&vertex.wi));
+        let weight = 1.0 / (w_light + 1.0 + w_camera);
+        // the light vertex brings its own cosine
+        color = color + eval.radiance * vertex.throughput * (weight / cos);
+    });
+    color * paths.normalization
+}
+
+unsafe impl<S> Sync for CpuVcm<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for CpuVcm<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        let paths = self.light_paths.read().unwrap();
+        self.trace(sample, &paths, sampler)
+    }
+}
+
+impl<S> Render<S> for CpuVcm<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> CpuVcm<S> {
+        CpuVcm {
+            bdpt: CpuBdpt::new_with_settings(cam.clone(), scene, settings),
+            camera: cam,
+            radius: None,
+            light_paths: RwLock::new(LightPaths {
+                vertices: Vec::new(),
+                path_ends: Vec::new(),
+                grid: HashGrid::new(Vec::new(), 1.0),
+                mergeable: Vec::new(),
+                weights: VcmWeights::bdpt(),
+                normalization: 0.0,
+            }),
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        *self.light_paths.write().unwrap() = self.trace_light_paths(iter_nb, frame.as_slice().len());
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        self.bdpt.watchdog()
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        self.bdpt.settings()
+    }
+
+    fn get_scene(&self) -> &S {
+        self.bdpt.get_scene()
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        self.bdpt.get_scene_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuVcm;
+    use camera::{CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2f, Vec2u, Vec3f};
+    use render::Render;
+    use scene::{DefaultScene, Scene};
+    use utility::seeded_rng;
+
+    #[test]
+    fn direct_light_matches_the_analytic_solution() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(0.0, 2.0, 0.0), radius: 1.0 }, Vec3f::new(100.0, 100.0, 100.0))
+            .unwrap();
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(64, 64))
+            .with_pos(Vec3f::new(0.0, 1.0, -1.0))
+            .with_look_at(Vec3f::new(0.0, -1.0, 1.0))
+            .build();
+        // a large radius, so merging takes a fair share of the light
+        let ren = CpuVcm::new(cam, scene).with_radius(0.3);
+        let paths = ren.trace_light_paths(1, 100000);
+
+        // the center of the view is the origin, see the analytic solution in the BDPT tests
+        let mut rng = seeded_rng(0, 0);
+        let samples = 10000;
+        let sum = (0..samples).fold(Vec3f::new(0.0, 0.0, 0.0), |sum, _| sum + ren.trace(Vec2f::new(32.0, 32.0), &paths, &mut rng));
+        let expected = 0.99 * 100.0 * 0.25;
+        assert!((sum.x / samples as f32 - expected).abs() < 0.1 * expected, "{:?}", sum / samples as f32);
+    }
+}
@@ -0,0 +1,172 @@
+use brdf::Brdf;
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use geometry::Ray;
+use math::{Vec3f, Vec2f, Zero};
+use render::{Render, CpuMtRender, Watchdog, RenderSettings};
+use scene::{LayerVisibility, Scene, SurfaceProperties};
+use utility::Sampler;
+
+// power heuristic
+fn mis2(current_pdf_w: f32, other_pdf_w: f32) -> f32 {
+    let (current, other) = (current_pdf_w * current_pdf_w, other_pdf_w * other_pdf_w);
+    current / (current + other)
+}
+
+// Light reaching the first hit straight from the lights, nothing else: a light sample and
+// a brdf sample combined with MIS, no indirect bounces. A quick preview and the baseline
+// to compare the path tracers with when direct lighting looks wrong
+pub struct DirectLighting<S: Scene> {
+    scene: S,
+    camera: PerspectiveCamera,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+}
+
+impl<S> DirectLighting<S> where S: Scene {
+    fn trace<R: Sampler>(&self, ray: &Ray, sampler: &mut R) -> Vec3f {
+        let isect = match self.scene.nearest_intersection(ray) {
+            Some(isect) => isect,
+            None        => return self.scene.get_background_light().radiate(ray).map_or(Vec3f::zero(), |rad| rad.radiance)
+        };
+        let m_id = match isect.surface {
+            SurfaceProperties::Material(m_id) => m_id,
+            SurfaceProperties::Light(light_id) => {
+                return self.scene.get_light(light_id).radiate(ray).map_or(Vec3f::zero(), |rad| {
+                    self.settings.clamp_light(rad.radiance)
+                });
+            }
+        };
+        if self.scene.get_layer_visibility(m_id) == LayerVisibility::Holdout {
+            return Vec3f::zero();
+        }
+        let hit_point = ray.orig + ray.dir * isect.dist;
+        let material = self.scene.get_surface_material(m_id, &isect);
+        let normal = self.scene.get_shading_normal(m_id, &isect);
+        match Brdf::new_with_eps(&ray.dir, &normal, &material, self.scene.get_epsilons().cosine) {
+            Some(brdf) => self.sample_light(&hit_point, &brdf, sampler) + self.sample_brdf(&hit_point, &brdf, sampler),
+            None       => Vec3f::zero()
+        }
+    }
+
+    fn sample_light<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, sampler: &mut R) -> Vec3f {
+        let (light_id, light_pick_prob) = match self.scene.pick_light(hit_point, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return Vec3f::zero()
+        };
+        let light = self.scene.get_light(light_id);
+        let illum = match light.illuminate(hit_point, sampler.next_2d()) {
+            Some(illum) => if illum.pdf > 0.0 { illum } else { return Vec3f::zero() },
+            None        => return Vec3f::zero()
+        };
+        let eval = match brdf.eval(&illum.l_dir) {
+            Some(eval) => eval,
+            None       => return Vec3f::zero()
+        };
+        if self.scene.was_occluded(&Ray { orig: *hit_point, dir: illum.l_dir }, illum.l_dist) {
+            return Vec3f::zero();
+        }
+        let light_pdf = illum.pdf * light_pick_prob;
+        // brdf sampling can't hit point lights
+        let weight = if light.is_delta() { 1.0 } else { mis2(light_pdf, eval.pdf) };
+        illum.radiance * eval.radiance * (weight / light_pdf)
+    }
+
+    // the light the sampled direction hits, whichever it is
+    fn sample_brdf<R: Sampler>(&self, hit_point: &Vec3f, brdf: &Brdf, sampler: &mut R) -> Vec3f {
+        let sample = match brdf.sample_with(sampler) {
+            Some(sample) => if sample.pdf > 0.0 { sample } else { return Vec3f::zero() },
+            None         => return Vec3f::zero()
+        };
+        let ray = Ray { orig: *hit_point, dir: sample.wi };
+        let (light_id, rad) = match self.scene.nearest_intersection(&ray) {
+            Some(isect) => match isect.surface {
+                SurfaceProperties::Light(light_id) => (light_id, self.scene.get_light(light_id).radiate(&ray)),
+                SurfaceProperties::Material(_)     => (0, None)
+            },
+            None => (0, self.scene.get_background_light().radiate(&ray))
+        };
+        rad.map_or(Vec3f::zero(), |rad| {
+            // light sampling can't hit the ideal mirror direction
+            let weight = if sample.delta { 1.0 } else { mis2(sample.pdf, rad.pdf * self.scene.light_pick_prob(hit_point, light_id)) };
+            sample.radiance * rad.radiance * (weight / sample.pdf)
+        })
+    }
+}
+
+unsafe impl<S> Sync for DirectLighting<S> where S: Scene {}
+
+impl<S> CpuMtRender<S> for DirectLighting<S> where S: Scene {
+    fn get_view_size(&self) -> Vec2f {
+        self.camera.get_view_size()
+    }
+
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f {
+        self.trace(&self.camera.ray_from_screen(&sample), sampler)
+    }
+}
+
+impl<S> Render<S> for DirectLighting<S> where S: Scene {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> DirectLighting<S> {
+        DirectLighting {
+            camera: cam,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+        }
+    }
+
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DirectLighting;
+    use camera::{CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Ray, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use render::Render;
+    use scene::{DefaultScene, Scene};
+    use std::f32::consts::FRAC_1_PI;
+    use utility::seeded_rng;
+
+    #[test]
+    fn direct_light_matches_the_analytic_solution() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(0.0, 10.0, 0.0), radius: 1.0 }, Vec3f::new(100.0, 100.0, 100.0))
+            .unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 5.0, 0.0), intensity: Vec3f::new(50.0, 50.0, 50.0) });
+        let ren = DirectLighting::new(CameraBuilder::<PerspectiveCamera>::new().build(), scene);
+
+        // the sphere light gives albedo * L * (r / d)^2 (see the BDPT tests), the point light
+        // albedo / pi * I / pi / d^2
+        let ray = Ray { orig: Vec3f::new(0.0, 1.0, -1.0), dir: Vec3f::new(0.0, -1.0, 1.0).normalize() };
+        let mut rng = seeded_rng(0, 0);
+        let samples = 20000;
+        let sum = (0..samples).fold(Vec3f::new(0.0, 0.0, 0.0), |sum, _| sum + ren.trace(&ray, &mut rng));
+        let expected = 0.99 * 100.0 * 0.01 + 0.99 * FRAC_1_PI * 50.0 * FRAC_1_PI / 25.0;
+        assert!((sum.x / samples as f32 - expected).abs() < 0.03 * expected, "{:?}", sum / samples as f32);
+    }
+}
@@ -1,27 +1,52 @@
-use render::{Render, CpuStRender};
-use scene::{Scene, SurfaceProperties};
+use render::{Render, CpuStRender, Watchdog, RenderSettings};
+use scene::{LayerVisibility, Scene, SurfaceProperties};
 use camera::{Camera, PerspectiveCamera};
 use framebuffer::RgbFrameBuffer;
+use gizmos::Gizmos;
 use math::{Vec2f, Vec3f, vec3_from_value, Zero};
 use math::vector_traits::*;
+use utility::Sampler;
 
 pub struct EyeLight<S: Scene> {
     camera: PerspectiveCamera,
     scene: S,
+    watchdog: Watchdog,
+    settings: RenderSettings,
+    overlay: Option<RgbFrameBuffer>, // replaces the scene where it isn't black
 }
 
-impl<S> CpuStRender for EyeLight<S> where S: Scene {
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f {
+impl<S> EyeLight<S> where S: Scene {
+    // draws the light gizmos into the frame, every sample of their pixels takes their color
+    pub fn with_light_gizmos(mut self) -> EyeLight<S> {
+        let mut overlay = self.camera.build_rgb_framebuffer();
+        Gizmos::lights(&self.scene, &self.camera).draw(&mut overlay);
+        self.overlay = Some(overlay);
+        self
+    }
+}
+
+impl<S> CpuStRender<S> for EyeLight<S> where S: Scene {
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, _sampler: &mut R) -> Vec3f {
+        if let Some(ref overlay) = self.overlay {
+            let res = overlay.resolution();
+            let (x, y) = (sample.x as usize, sample.y as usize);
+            if x < res.x && y < res.y && overlay.as_slice()[y * res.x + x] != Vec3f::zero() {
+                return overlay.as_slice()[y * res.x + x];
+            }
+        }
         let ray = self.camera.ray_from_screen(&sample);
 
         if let Some(ref isect) = self.scene.nearest_intersection(&ray) {
             let l_dot_n = isect.normal.dot(&-ray.dir);
             if let SurfaceProperties::Material(mat_id) = isect.surface {
                 use geometry::Ray;
+                if self.scene.get_layer_visibility(mat_id) == LayerVisibility::Holdout {
+                    return Vec3f::zero();
+                }
                 use math::Vec3f;
                 let hit_point = ray.orig + ray.dir * isect.dist;
                 if !self.scene.was_occluded(&Ray{orig: hit_point, dir: -ray.dir}, isect.dist) {
-                    self.scene.get_material(mat_id).diffuse * l_dot_n.abs()
+                    self.scene.get_surface_material(mat_id, isect).diffuse * l_dot_n.abs()
                 } else {
                     Vec3f::zero()
                 }
@@ -39,14 +64,71 @@ impl<S> CpuStRender for EyeLight<S> where S: Scene {
 }
 
 impl<S> Render<S> for EyeLight<S> where S: Scene {
-    fn new(cam: PerspectiveCamera, scene: S) -> EyeLight<S> {
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> EyeLight<S> {
         EyeLight {
             camera: cam,
-            scene: scene,
+            scene: settings.prepare_scene(scene),
+            watchdog: Watchdog::for_max_depth(settings.max_depth),
+            settings: settings,
+            overlay: None,
         }
     }
 
-    fn iterate(&self, iter_nb: usize, frame: &mut RgbFrameBuffer) {
-        self.iterate_over_screen(iter_nb, frame)
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen(iter_nb, spp, frame)
+    }
+
+    fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    fn get_scene(&self) -> &S {
+        &self.scene
+    }
+
+    fn get_scene_mut(&mut self) -> &mut S {
+        &mut self.scene
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EyeLight;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::Render;
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn light_gizmos_are_drawn_over_the_scene() {
+        let scene = || {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+            scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+            scene.add_light(PointLight { position: Vec3f::new(0.0, 0.0, -2.0), intensity: Vec3f::new(0.0, 4.0, 2.0) });
+            scene
+        };
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(32, 32))
+            .with_pos(Vec3f::new(0.0, 0.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let light = cam.world_to_raster(&Vec3f::new(0.0, 0.0, -2.0)).unwrap();
+        let pix = light.y as usize * 32 + light.x as usize;
+
+        let mut plain = cam.build_rgb_framebuffer();
+        EyeLight::new(cam, scene()).iterate(1, 4, &mut plain);
+        let mut overlaid = cam.build_rgb_framebuffer();
+        EyeLight::new(cam, scene()).with_light_gizmos().iterate(1, 4, &mut overlaid);
+        // the sums of 4 samples of the light hue, on the sphere
+        assert!(plain.as_slice()[pix].x > 0.0);
+        assert_eq!(overlaid.as_slice()[pix], Vec3f::new(0.0, 4.0, 2.0));
+        assert_eq!(overlaid.as_slice()[0], plain.as_slice()[0]);
     }
 }
@@ -0,0 +1,113 @@
+use framebuffer::RgbFrameBuffer;
+use render::Render;
+use scene::{LightID, MaterialID, Scene};
+
+// Scalar scene parameters a render can be differentiated by, colors go channel by channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneParameter {
+    Diffuse(MaterialID, usize),
+    Specular(MaterialID, usize),
+    PhongExp(MaterialID),
+    LightIntensity(LightID, usize),
+}
+
+impl SceneParameter {
+    // None for lights which have no single intensity
+    pub fn get<S: Scene>(&self, scene: &S) -> Option<f32> {
+        match *self {
+            SceneParameter::Diffuse(m_id, channel)  => Some(scene.get_material(m_id).diffuse[channel]),
+            SceneParameter::Specular(m_id, channel) => Some(scene.get_material(m_id).specular[channel]),
+            SceneParameter::PhongExp(m_id)          => Some(scene.get_material(m_id).phong_exp),
+            SceneParameter::LightIntensity(light_id, channel) =>
+                scene.get_light(light_id).intensity().map(|intensity| intensity[channel])
+        }
+    }
+
+    pub fn set<S: Scene>(&self, scene: &mut S, value: f32) -> bool {
+        match *self {
+            SceneParameter::Diffuse(m_id, channel)  => scene.edit_material(m_id, |material| material.diffuse[channel] = value),
+            SceneParameter::Specular(m_id, channel) => scene.edit_material(m_id, |material| material.specular[channel] = value),
+            SceneParameter::PhongExp(m_id)          => scene.edit_material(m_id, |material| material.phong_exp = value),
+            SceneParameter::LightIntensity(light_id, channel) => {
+                let mut intensity = match scene.get_light(light_id).intensity() {
+                    Some(intensity) => intensity,
+                    None            => return false
+                };
+                intensity[channel] = value;
+                return scene.set_light_intensity(light_id, intensity);
+            }
+        }
+        true
+    }
+}
+
+// Experimental hook for inverse rendering: adds the derivative of `spp` samples per pixel by
+// the parameter to `gradient`, so it's normalized like a frame. It's a central difference of
+// two renders of the same `iter_nb`, where every pixel draws the same random numbers, so most of
+// the noise cancels out. Paths which change discretely with the parameter (Russian roulette,
+// lobe selection) still add some, and `step` has to keep the parameter valid both ways.
+// The parameter is restored and the changes are committed, pending ones included.
+// False if the parameter can't be changed
+pub fn iterate_gradient<S, R>(ren: &mut R, param: SceneParameter, step: f32, iter_nb: usize, spp: usize,
+                              gradient: &mut RgbFrameBuffer) -> bool
+    where S: Scene, R: Render<S> {
+    let value = match param.get(ren.get_scene()) {
+        Some(value) => value,
+        None        => return false
+    };
+    let mut frames = [RgbFrameBuffer::new(gradient.resolution()), RgbFrameBuffer::new(gradient.resolution())];
+    for (frame, &offset) in frames.iter_mut().zip([step, -step].iter()) {
+        if !param.set(ren.get_scene_mut(), value + offset) {
+            return false;
+        }
+        ren.commit_changes();
+        ren.iterate(iter_nb, spp, frame);
+    }
+    param.set(ren.get_scene_mut(), value);
+    ren.commit_changes();
+
+    let pixels = gradient.as_mut_slice().iter_mut().zip(frames[0].as_slice().iter().zip(frames[1].as_slice()));
+    for (pix, (&plus, &minus)) in pixels {
+        *pix = *pix + (plus - minus) / (2.0 * step);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SceneParameter, iterate_gradient};
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtDl, Render};
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn albedo_gradient_of_direct_light() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let floor = scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE)
+            .unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(100.0, 100.0, 100.0) });
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 2.0, 3.0))
+            .with_look_at(Vec3f::new(0.0, -2.0, -3.0))
+            .build();
+        let mut frame = cam.build_rgb_framebuffer();
+        let mut gradient = cam.build_rgb_framebuffer();
+        let mut ren = CpuPtDl::new(cam, scene);
+        ren.iterate(1, 4, &mut frame);
+        assert!(iterate_gradient(&mut ren, SceneParameter::Diffuse(floor, 0), 0.01, 1, 4, &mut gradient));
+        assert_eq!(ren.get_scene().get_material(floor).diffuse, WHITE_DIFFUSE.diffuse);
+
+        assert!(frame.as_slice().iter().any(|pix| pix.x > 0.0));
+        // the floor only gets direct light, which is linear in the albedo
+        for (pix, grad) in frame.as_slice().iter().zip(gradient.as_slice()) {
+            let expected = pix.x / WHITE_DIFFUSE.diffuse.x;
+            assert!((grad.x - expected).abs() <= 1e-2 * expected + 1e-4, "{} instead of {}", grad.x, expected);
+            assert!(grad.y.abs() <= 1e-4);
+        }
+    }
+}
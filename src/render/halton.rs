@@ -0,0 +1,85 @@
+use rand::Rng;
+use render::sobol::SOBOL_DIMS;
+use std::sync::{Arc, Mutex};
+use utility::seeded_rng;
+
+// as many dimensions as the sobol sequence has
+pub const HALTON_DIMS: usize = SOBOL_DIMS;
+const PRIMES: [u32; HALTON_DIMS] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29];
+// rng stream of the digit permutations, next to the pixel ones ((iter_nb << 32) | pix_nb)
+const HALTON_STREAMS: u64 = 1 << 54;
+
+// The Halton sequence: the dimension `d` is the radical inverse of the point index in the base of the
+// d-th prime. The digits go through a random permutation per dimension first, which breaks the lines
+// the plain sequence forms between the dimensions with the larger primes
+pub struct Halton {
+    seed: u32,
+    permutations: Vec<Vec<u32>>, // per dimension, zero stays zero so the leading zeros of the index stay zeros
+}
+
+// the sequence of the last seed asked for, every sampler of a render shares it
+static SHARED: Mutex<Option<Arc<Halton>>> = Mutex::new(None);
+
+impl Halton {
+    // the sequence of `seed`, built by the first sampler of a render and shared by the others
+    pub fn shared(seed: u32) -> Arc<Halton> {
+        let mut shared = SHARED.lock().unwrap();
+        if let Some(ref halton) = *shared {
+            if halton.seed == seed {
+                return halton.clone();
+            }
+        }
+        let halton = Arc::new(Halton::new(seed));
+        *shared = Some(halton.clone());
+        halton
+    }
+
+    pub fn new(seed: u32) -> Halton {
+        let mut rng = seeded_rng(seed, HALTON_STREAMS);
+        let permutations = PRIMES.iter().map(|&base| {
+            let mut digits = (1..base).collect::<Vec<_>>();
+            rng.shuffle(&mut digits);
+            digits.insert(0, 0);
+            digits
+        }).collect();
+        Halton { seed: seed, permutations: permutations }
+    }
+
+    // the point `index` in the dimension `dim`, in [0, 1)
+    pub fn sample(&self, index: u32, dim: usize) -> f64 {
+        let base = PRIMES[dim];
+        let inv_base = 1.0 / base as f64;
+        let mut index = index;
+        let mut value = 0.0;
+        let mut scale = inv_base;
+        while index != 0 {
+            value += self.permutations[dim][(index % base) as usize] as f64 * scale;
+            index /= base;
+            scale *= inv_base;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Halton, HALTON_DIMS, PRIMES};
+
+    #[test]
+    fn points_fill_the_strata() {
+        let halton = Halton::new(7);
+        for dim in 0..HALTON_DIMS {
+            // the first base^2 points, one in every 1 / base^2 stratum
+            let n = PRIMES[dim] * PRIMES[dim];
+            let mut strata = (0..n).map(|index| (halton.sample(index, dim) * n as f64 + 1e-9) as u32).collect::<Vec<_>>();
+            strata.sort();
+            assert_eq!(strata, (0..n).collect::<Vec<_>>(), "dimension {}", dim);
+        }
+        // the permutations differ between the seeds
+        let other = Halton::new(8);
+        assert!((1..10).any(|index| other.sample(index, 4) != halton.sample(index, 4)));
+        // the shared one is the sequence of its seed
+        let shared = Halton::shared(8);
+        assert!((1..10).all(|index| shared.sample(index, 4) == other.sample(index, 4)));
+    }
+}
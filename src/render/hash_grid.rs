@@ -0,0 +1,114 @@
+use math::vector_traits::*;
+use math::Vec3f;
+use std::f32;
+
+// Points hashed into cells of twice the query radius, so a query looks into at most 2x2x2 cells.
+// Cells which collide share a bucket, the points there are filtered by the distance anyway
+pub struct HashGrid {
+    points: Vec<Vec3f>,
+    indices: Vec<usize>, // of the points, bucket after bucket
+    bucket_ends: Vec<usize>,
+    min: Vec3f,
+    radius: f32,
+    inv_cell_size: f32,
+}
+
+impl HashGrid {
+    pub fn new(points: Vec<Vec3f>, radius: f32) -> HashGrid {
+        let min = points.iter().fold(Vec3f::new(f32::MAX, f32::MAX, f32::MAX), |min, point| min.zip(point, f32::min));
+        let mut grid = HashGrid {
+            indices: vec![0; points.len()],
+            bucket_ends: vec![0; points.len().max(1)],
+            points: points,
+            min: min,
+            radius: radius,
+            inv_cell_size: 0.5 / radius,
+        };
+        // counting sort by the bucket
+        let buckets = grid.points.iter().map(|point| grid.bucket(&grid.cell(point))).collect::<Vec<_>>();
+        for &bucket in buckets.iter() {
+            grid.bucket_ends[bucket] += 1;
+        }
+        let mut end = 0;
+        for bucket_end in grid.bucket_ends.iter_mut() {
+            end += *bucket_end;
+            *bucket_end = end;
+        }
+        for (idx, &bucket) in buckets.iter().enumerate().rev() {
+            grid.bucket_ends[bucket] -= 1;
+            grid.indices[grid.bucket_ends[bucket]] = idx;
+        }
+        // the ends were moved to the starts, shift them back
+        grid.bucket_ends.remove(0);
+        grid.bucket_ends.push(grid.points.len());
+        grid
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    // calls `f` with the index of every point within the radius of `pos`
+    pub fn for_each_near<F>(&self, pos: &Vec3f, f: &mut F) where F: FnMut(usize) {
+        if self.points.is_empty() {
+            return;
+        }
+        let r = Vec3f::new(self.radius, self.radius, self.radius);
+        let (lo, hi) = (self.cell(&(*pos - r)), self.cell(&(*pos + r)));
+        let mut buckets = Vec::with_capacity(8);
+        for x in lo.0..hi.0 + 1 {
+            for y in lo.1..hi.1 + 1 {
+                for z in lo.2..hi.2 + 1 {
+                    let bucket = self.bucket(&(x, y, z));
+                    // colliding cells mustn't report their points twice
+                    if buckets.contains(&bucket) {
+                        continue;
+                    }
+                    buckets.push(bucket);
+                    let start = if bucket == 0 { 0 } else { self.bucket_ends[bucket - 1] };
+                    for &idx in &self.indices[start..self.bucket_ends[bucket]] {
+                        if (self.points[idx] - *pos).sqnorm() <= self.radius * self.radius {
+                            f(idx);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn cell(&self, pos: &Vec3f) -> (i32, i32, i32) {
+        let rel = (*pos - self.min) * self.inv_cell_size;
+        (rel.x.floor() as i32, rel.y.floor() as i32, rel.z.floor() as i32)
+    }
+
+    fn bucket(&self, cell: &(i32, i32, i32)) -> usize {
+        let hash = (cell.0 as u32).wrapping_mul(73856093) ^ (cell.1 as u32).wrapping_mul(19349663)
+            ^ (cell.2 as u32).wrapping_mul(83492791);
+        hash as usize % self.bucket_ends.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HashGrid;
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use rand::Rng;
+    use utility::seeded_rng;
+
+    #[test]
+    fn queries_match_brute_force() {
+        let mut rng = seeded_rng(11, 0);
+        let points = (0..1000).map(|_| Vec3f::new(rng.next_f32() * 4.0, rng.next_f32(), rng.next_f32() - 0.5))
+            .collect::<Vec<_>>();
+        let grid = HashGrid::new(points.clone(), 0.1);
+        for _ in 0..20 {
+            let pos = Vec3f::new(rng.next_f32() * 4.0, rng.next_f32(), rng.next_f32() - 0.5);
+            let mut found = Vec::new();
+            grid.for_each_near(&pos, &mut |idx| found.push(idx));
+            found.sort();
+            let expected = (0..points.len()).filter(|&idx| (points[idx] - pos).norm() <= 0.1).collect::<Vec<_>>();
+            assert_eq!(found, expected);
+        }
+    }
+}
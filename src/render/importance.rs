@@ -0,0 +1,73 @@
+use math::{Vec2f, Vec2u};
+use texture::Texture;
+use utility::luminance;
+
+// the darkest parts of a mask still get about this share of the average budget
+const MIN_WEIGHT: f32 = 1.0 / 16.0;
+
+// Per-pixel sample budget from a grayscale mask, brighter pixels get more samples.
+// Weights average to one, so an iteration costs about as much as without the mask
+pub struct ImportanceMask {
+    weights: Vec<f32>,
+}
+
+impl ImportanceMask {
+    // the mask is stretched over the frame
+    pub fn new(mask: &Texture, resolution: Vec2u) -> ImportanceMask {
+        let (width, height) = (resolution.x, resolution.y);
+        let values = (0..width * height).map(|pix_nb| {
+            let (x, y) = (pix_nb % width, pix_nb / width);
+            // v goes up the mask, the first row of the frame is the top one
+            let uv = Vec2f::new((x as f32 + 0.5) / width as f32, 1.0 - (y as f32 + 0.5) / height as f32);
+            luminance(&mask.lookup(&uv)).max(0.0)
+        }).collect();
+        ImportanceMask::from_values(values)
+    }
+
+    fn from_values(values: Vec<f32>) -> ImportanceMask {
+        let mean = values.iter().sum::<f32>() / values.len().max(1) as f32;
+        if mean <= 0.0 {
+            return ImportanceMask { weights: vec![1.0; values.len()] };
+        }
+        let clamped = values.iter().map(|value| (value / mean).max(MIN_WEIGHT)).collect::<Vec<_>>();
+        // the dark pixels raised to the minimum take their samples from the others
+        let clamped_mean = clamped.iter().sum::<f32>() / clamped.len() as f32;
+        ImportanceMask { weights: clamped.iter().map(|weight| weight / clamped_mean).collect() }
+    }
+
+    pub fn weight(&self, pix_nb: usize) -> f32 {
+        self.weights[pix_nb]
+    }
+
+    // Samples of a pixel where the budget is `spp` and the scale of each of them.
+    // The count is rounded randomly and the scale makes up for it, so the pixel
+    // still sums to `spp` samples on average and the frame is normalized as usual
+    pub fn samples(&self, pix_nb: usize, spp: usize, rnd: f32) -> (usize, f32) {
+        let weight = self.weights[pix_nb];
+        ((spp as f32 * weight + rnd).floor() as usize, 1.0 / weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImportanceMask;
+
+    #[test]
+    fn budget_follows_the_mask() {
+        let mask = ImportanceMask::from_values(vec![0.0, 1.0, 3.0, 0.0]);
+        assert!((mask.weight(2) / mask.weight(1) - 3.0).abs() < 1e-6);
+        assert!((mask.weight(0) / mask.weight(1) - 1.0 / 16.0).abs() < 1e-6);
+        // the iteration costs as much as without the mask
+        assert!(((0..4).map(|pix_nb| mask.weight(pix_nb)).sum::<f32>() - 4.0).abs() < 1e-5);
+
+        // scaled samples add up to the unmasked budget on average
+        for pix_nb in 0..4 {
+            let rnds = 1000;
+            let sum = (0..rnds).fold(0.0, |sum, i| {
+                let (samples, scale) = mask.samples(pix_nb, 2, (i as f32 + 0.5) / rnds as f32);
+                sum + samples as f32 * scale
+            });
+            assert!((sum / rnds as f32 - 2.0).abs() < 1e-2);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use math::{Vec2f, Vec3f};
+use render::{PixelSampler, Render, RenderSettings, pixel_rng};
+use scene::{LayerVisibility, RenderLayer, Scene, SurfaceProperties};
+
+pub struct LayerImage {
+    pub name: String,
+    pub color: RgbFrameBuffer, // sums of `spp` samples, like the frames of Render::iterate
+    pub alpha: RgbFrameBuffer, // same for the coverage, all three channels are equal
+    pub spp: usize,
+}
+
+// Adds the coverage of `spp` camera rays per pixel: 1 for the visible objects of the current layer,
+// 0 for holdouts and misses. Jitter is the same as the one of the renderers with `settings` for the same `iter_nb`
+pub fn accumulate_coverage<S: Scene>(camera: &PerspectiveCamera, scene: &S, settings: &RenderSettings, iter_nb: usize,
+                                     spp: usize, alpha: &mut RgbFrameBuffer) {
+    let res_x = alpha.resolution().x;
+    let seed = scene.get_seed();
+    let mut sampler = settings.sampler.new_sampler(seed, settings.deterministic);
+    for (pix_nb, pix) in alpha.as_mut_slice().iter_mut().enumerate() {
+        let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+        sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+        for i in 0..spp {
+            let ray = camera.ray_from_screen(&(Vec2f::new(x as f32, y as f32) + sampler.start_sample(i)));
+            let covered = match scene.nearest_intersection(&ray).map(|isect| isect.surface) {
+                Some(SurfaceProperties::Material(m_id)) => scene.get_layer_visibility(m_id) == LayerVisibility::Visible,
+                Some(SurfaceProperties::Light(_))       => true,
+                None                                    => false
+            };
+            if covered {
+                *pix = *pix + Vec3f::new(1.0, 1.0, 1.0);
+            }
+        }
+    }
+}
+
+// Renders every layer for `iterations` iterations of one sample per pixel, the scene is left without a layer
+pub fn render_layers<S, R>(ren: &mut R, camera: &PerspectiveCamera, layers: &[RenderLayer], iterations: usize)
+    -> Vec<LayerImage>
+    where S: Scene, R: Render<S> {
+    let images = layers.iter().map(|layer| {
+        ren.get_scene_mut().set_render_layer(Some(layer.clone()));
+        let mut color = camera.build_rgb_framebuffer();
+        let mut alpha = camera.build_rgb_framebuffer();
+        for iter_nb in 1..iterations + 1 {
+            ren.iterate(iter_nb, 1, &mut color);
+            accumulate_coverage(camera, ren.get_scene(), ren.settings(), iter_nb, 1, &mut alpha);
+        }
+        LayerImage { name: layer.name.clone(), color: color, alpha: alpha, spp: iterations }
+    }).collect();
+    ren.get_scene_mut().set_render_layer(None);
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use geometry::{GeometryList, Ray, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::Vec3f;
+    use scene::{DefaultScene, LayerVisibility, RenderLayer, Scene, SurfaceProperties};
+
+    #[test]
+    fn hidden_objects_are_stepped_through() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let front = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        let back = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 5.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        let ray = Ray { orig: Vec3f::new(0.0, 0.0, -5.0), dir: Vec3f::new(0.0, 0.0, 1.0) };
+
+        scene.set_render_layer(Some(RenderLayer::new("back", LayerVisibility::Visible)
+            .with_objects(&[front], LayerVisibility::Hidden)));
+        let isect = scene.nearest_intersection(&ray).unwrap();
+        assert!((isect.dist - 9.0).abs() < 1e-3);
+        match isect.surface {
+            SurfaceProperties::Material(m_id) => assert_eq!(m_id, back),
+            _                                 => panic!("a light was hit")
+        }
+        assert!(!scene.was_occluded(&ray, 8.0));
+        assert!(scene.was_occluded(&ray, 10.0));
+
+        scene.set_render_layer(Some(RenderLayer::new("front", LayerVisibility::Holdout)
+            .with_objects(&[front], LayerVisibility::Visible)));
+        assert_eq!(scene.get_layer_visibility(back), LayerVisibility::Holdout);
+        assert!(scene.was_occluded(&ray, 8.0));
+    }
+}
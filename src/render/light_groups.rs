@@ -0,0 +1,126 @@
+use framebuffer::RgbFrameBuffer;
+use math::{Vec2f, Vec3f, Zero};
+use rayon::prelude::*;
+use render::{CpuMtRender, PixelSampler, TILE_ROWS, pixel_rng};
+use scene::Scene;
+use utility::Sampler;
+
+// Renderers which can keep the light of every light group (see Scene::set_light_group) apart,
+// so the groups can be rebalanced after the render with `relight`. The path tracers (pt, pt-dl, pt-mis)
+// are; the others mix the light of several lights in a pixel (the light paths, the photons, the caches),
+// they don't implement it and can't be asked for the groups
+pub trait LightGroupRender<S: Scene> : CpuMtRender<S> {
+    // adds `spp` samples to every pixel of the frame of every group
+    fn iterate_light_groups(&self, iter_nb: usize, spp: usize, frames: &mut [RgbFrameBuffer]) {
+        let groups_nb = self.get_light_groups_nb();
+        assert_eq!(frames.len(), groups_nb);
+        let res_x = self.get_view_size().x as usize;
+        let pixels_nb = frames[0].as_slice().len();
+        let seed = self.get_seed();
+
+        // pixel-major copy, so every pixel owns a slice of its groups
+        let mut samples = vec![Vec3f::zero(); pixels_nb * groups_nb];
+        {
+            let tile_len = res_x * TILE_ROWS;
+            let mut tiles = samples.chunks_mut(tile_len * groups_nb).collect::<Vec<_>>();
+            tiles.par_iter_mut().enumerate().for_each(|(tile_nb, tile)| {
+                let mut sampler = self.new_pixel_sampler();
+                for (i, groups) in tile.chunks_mut(groups_nb).enumerate() {
+                    let pix_nb = tile_nb * tile_len + i;
+                    let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+                    sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+                    for sample_nb in 0..spp {
+                        let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                        self.trace_light_groups(sample, groups, &mut sampler);
+                    }
+                }
+            });
+        }
+        for (group, frame) in frames.iter_mut().enumerate() {
+            for (pix_nb, pix) in frame.as_mut_slice().iter_mut().enumerate() {
+                *pix = *pix + samples[pix_nb * groups_nb + group];
+            }
+        }
+    }
+
+    fn get_light_groups_nb(&self) -> usize;
+    // adds the light of every group the path gathers to `groups`
+    fn trace_light_groups<R: Sampler>(&self, sample: Vec2f, groups: &mut [Vec3f], sampler: &mut R);
+}
+
+// sum of the group frames scaled by the group weights
+pub fn relight(frames: &[RgbFrameBuffer], weights: &[Vec3f]) -> RgbFrameBuffer {
+    assert_eq!(frames.len(), weights.len());
+    let mut result = RgbFrameBuffer::new(frames[0].resolution());
+    for (frame, weight) in frames.iter().zip(weights.iter()) {
+        for (dst, src) in result.as_mut_slice().iter_mut().zip(frame.as_slice().iter()) {
+            *dst = *dst + *src * *weight;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LightGroupRender, relight};
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use framebuffer::RgbFrameBuffer;
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use math::vector_traits::*;
+    use render::{CpuPt, CpuPtDl, CpuPtMis, Render, RenderSettings};
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn relight_rebalances_groups() {
+        let mut key = RgbFrameBuffer::new(Vec2u::new(2, 1));
+        let mut fill = RgbFrameBuffer::new(Vec2u::new(2, 1));
+        key.set_color((0, 0), Vec3f::new(1.0, 1.0, 1.0));
+        fill.set_color((0, 0), Vec3f::new(0.5, 0.5, 0.5));
+        fill.set_color((1, 0), Vec3f::new(1.0, 2.0, 3.0));
+        let weights = [Vec3f::new(2.0, 2.0, 2.0), Vec3f::new(1.0, 0.0, 1.0)];
+        let result = relight(&[key, fill], &weights);
+        assert_eq!(result.as_slice(), &[Vec3f::new(2.5, 2.0, 2.5), Vec3f::new(1.0, 0.0, 3.0)][..]);
+    }
+
+    #[test]
+    fn groups_sum_to_the_beauty_frame() {
+        // no path of pt hits a point light, only the sky is left
+        assert_groups_sum_to_the_beauty_frame::<CpuPt<_>>(1);
+        assert_groups_sum_to_the_beauty_frame::<CpuPtDl<_>>(3);
+        assert_groups_sum_to_the_beauty_frame::<CpuPtMis<_>>(3);
+    }
+
+    // `lit_nb` first groups get some light
+    fn assert_groups_sum_to_the_beauty_frame<R>(lit_nb: usize)
+        where R: LightGroupRender<DefaultScene<GeometryList>> + Render<DefaultScene<GeometryList>> {
+        // the sky, the key light and the fill light each in a group of their own
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.2, 0.3, 0.4) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(1.0, 4.0, -3.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        scene.add_light(PointLight { position: Vec3f::new(-3.0, 2.0, 1.0), intensity: Vec3f::new(2.0, 1.0, 1.0) });
+        scene.set_light_group(1, 1);
+        scene.set_light_group(2, 2);
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 0.5, -2.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = R::new_with_settings(cam, scene, RenderSettings::new().with_seed(3).with_deterministic());
+        let mut beauty = cam.build_rgb_framebuffer();
+        ren.iterate(2, 4, &mut beauty);
+        let mut groups = (0..3).map(|_| cam.build_rgb_framebuffer()).collect::<Vec<_>>();
+        ren.iterate_light_groups(2, 4, &mut groups);
+
+        // the same paths, only the order of the additions differs
+        let one = Vec3f::new(1.0, 1.0, 1.0);
+        let sum = relight(&groups, &[one, one, one]);
+        for (summed, pix) in sum.as_slice().iter().zip(beauty.as_slice().iter()) {
+            assert!((*summed - *pix).norm() <= 1e-4 * pix.norm().max(1.0), "{:?} instead of {:?}", summed, pix);
+        }
+        assert!(groups[..lit_nb].iter().all(|group| group.as_slice().iter().any(|pix| pix.x > 0.0)));
+    }
+}
@@ -0,0 +1,349 @@
+use brdf::Brdf;
+use brdf::dielectric::{fresnel_dielectric, refract};
+use geometry::{Frame, Ray, Sphere};
+use math::vector_traits::*;
+use math::{One, Vec3f};
+use scene::{LightID, MaterialID, Scene, SurfaceProperties};
+use std::f32;
+use utility::{uniform_cone_sample, Sampler};
+
+const MAX_NEWTON_STEPS: usize = 20;
+const NEWTON_DELTA: f32 = 1e-4; // finite difference step of the direction parameters
+const NEWTON_TOLERANCE: f32 = 1e-5;
+
+// Manifold next event estimation over one specular object (Zeltner et al. 2020, the biased variant):
+// a light point is connected to a shading point through a mirror, or into a glass object and out of it,
+// by solving for the direction at which the reflection or the refraction law holds at every vertex with
+// Newton iterations. The derivatives are finite differences over re-traced chains, so any geometry works,
+// smooth geometry converges better.
+// Caustics from small lights come out clean where path tracing only finds them by chance.
+pub struct ManifoldNee {
+    mirrors: Vec<(MaterialID, Sphere)>, // bounding spheres of the mirror and glass objects to seed from
+}
+
+// How far a path got along a chain of the kind the manifold NEE solves, counted from the vertex which
+// took a manifold sample. The light hit at the end of a whole chain was gathered by that sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainProgress {
+    Start,
+    Inside(MaterialID), // refracted into the glass object, leaves it by a refraction too
+    End,
+}
+
+// where the light sample is: a point on a luminous object or a direction to the background
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Point { pos: Vec3f, normal: Vec3f },
+    Direction(Vec3f),
+}
+
+// directions from x are parametrized by offsets in the plane orthogonal to the seed direction
+struct Chain<'a, S: Scene + 'a> {
+    scene: &'a S,
+    x: Vec3f,
+    object: MaterialID,
+    seed: Vec3f,
+    frame: Frame,
+}
+
+// the chain traced from x in the direction `dir`
+struct ChainEnd {
+    dir: Vec3f,
+    pos: Vec3f, // of the last specular vertex
+    out: Vec3f, // of the ray leaving it
+    throughput: Vec3f, // of the reflections and the refractions on the way
+}
+
+impl ManifoldNee {
+    // every object with a specular material and bounds takes part, the scene shouldn't change after this
+    pub fn new<S: Scene>(scene: &S) -> ManifoldNee {
+        let mirrors = (0..scene.get_materials_nb() as MaterialID)
+            .filter(|&m_id| scene.get_material(m_id).is_specular())
+            .filter_map(|m_id| scene.get_object_bounds(m_id).map(|bounds| {
+                let (center, radius) = bounds.bounding_sphere();
+                (m_id, Sphere { center: center, radius: radius })
+            }))
+            .collect();
+        ManifoldNee { mirrors: mirrors }
+    }
+
+    pub fn has_mirrors(&self) -> bool {
+        !self.mirrors.is_empty()
+    }
+
+    // The progress after the path scattered on the specular object `m_id`, through the surface if
+    // `refracted`; None once the path isn't on a chain any more
+    pub fn follow<S: Scene>(&self, scene: &S, progress: Option<ChainProgress>, m_id: MaterialID, refracted: bool)
+        -> Option<ChainProgress> {
+        if !self.mirrors.iter().any(|&(id, _)| id == m_id) {
+            return None;
+        }
+        let glass = scene.get_material(m_id).dielectric.is_some();
+        match progress {
+            Some(ChainProgress::Start) if glass && refracted => Some(ChainProgress::Inside(m_id)),
+            Some(ChainProgress::Start) if !glass && !refracted => Some(ChainProgress::End),
+            Some(ChainProgress::Inside(id)) if id == m_id && refracted => Some(ChainProgress::End),
+            _ => None
+        }
+    }
+
+    // Light coming to `x` over one specular object, already weighted by the brdf at `x`,
+    // and the light it comes from. Only one solution is looked for, so it's biased
+    // when an object shows the same light several times
+    pub fn sample<S: Scene, R: Sampler>(&self, scene: &S, x: &Vec3f, brdf: &Brdf, sampler: &mut R)
+        -> Option<(LightID, Vec3f)> {
+        if self.mirrors.is_empty() {
+            return None;
+        }
+        let (m_id, ref bounds) = self.mirrors[sampler.next_index(self.mirrors.len())];
+        let to_mirror = bounds.center - *x;
+        let dist2 = to_mirror.sqnorm();
+        if dist2 <= bounds.r2() {
+            return None;
+        }
+        let cos_theta_max = (1.0 - bounds.r2() / dist2).sqrt();
+        let seed = Frame::from_z(&to_mirror)
+            .to_world(&uniform_cone_sample(cos_theta_max, sampler.next_2d()))
+            .normalize();
+        let chain = Chain::new(scene, *x, m_id, seed);
+        let seed_end = match chain.trace(0.0, 0.0) {
+            Some(end) => end,
+            None      => return None
+        };
+
+        // the light sample is taken from the end of the seed chain and stays fixed while the chain moves
+        let (light_id, light_pick_prob) = match scene.pick_light(&seed_end.pos, sampler.next_1d()) {
+            Some(picked) => picked,
+            None         => return None
+        };
+        let illum = match scene.get_light(light_id).illuminate(&seed_end.pos, sampler.next_2d()) {
+            Some(illum) => illum,
+            None        => return None
+        };
+        let (target, target_pdf) = match scene.nearest_intersection(&Ray { orig: seed_end.pos, dir: illum.l_dir }) {
+            Some(isect) => match isect.surface {
+                SurfaceProperties::Light(id) if id == light_id => {
+                    let cos_light = isect.normal.dot(&illum.l_dir).abs();
+                    let target = Target::Point { pos: isect.position, normal: isect.normal };
+                    (target, illum.pdf * cos_light / (isect.dist * isect.dist))
+                },
+                _ => return None
+            },
+            None if light_id == 0 => (Target::Direction(illum.l_dir), illum.pdf),
+            None => return None
+        };
+        if target_pdf <= 0.0 {
+            return None;
+        }
+
+        let (a, b) = match chain.solve(&target, &seed_end) {
+            Some(solution) => solution,
+            None           => return None
+        };
+        let end = match chain.trace(a, b) {
+            Some(end) => end,
+            None      => return None
+        };
+        let visible = match target {
+            Target::Point { pos, .. } => {
+                let to_light = pos - end.pos;
+                let dist = to_light.norm();
+                !scene.was_occluded(&Ray { orig: end.pos, dir: to_light / dist }, dist)
+            },
+            Target::Direction(dir) => scene.nearest_intersection(&Ray { orig: end.pos, dir: dir }).is_none()
+        };
+        let eval = match brdf.eval(&end.dir) {
+            Some(eval) => eval,
+            None       => return None
+        };
+        let jacobian = chain.direction_per_target(a, b, &target);
+        if !visible || !jacobian.is_finite() {
+            return None;
+        }
+
+        let selection = self.mirrors.len() as f32 / light_pick_prob;
+        let radiance = illum.radiance * end.throughput * eval.radiance * (jacobian * selection / target_pdf);
+        Some((light_id, radiance))
+    }
+}
+
+impl<'a, S: Scene> Chain<'a, S> {
+    fn new(scene: &'a S, x: Vec3f, object: MaterialID, seed: Vec3f) -> Chain<'a, S> {
+        Chain { scene: scene, x: x, object: object, seed: seed, frame: Frame::from_z(&seed) }
+    }
+
+    fn dir(&self, a: f32, b: f32) -> Vec3f {
+        (self.seed + self.frame.to_world(&Vec3f::new(a, b, 0.0))).normalize()
+    }
+
+    // the chain in the direction, if the object is what the direction sees: a mirror reflects it once,
+    // a glass object refracts it in and out
+    fn trace(&self, a: f32, b: f32) -> Option<ChainEnd> {
+        let dir = self.dir(a, b);
+        let material = self.scene.get_material(self.object);
+        let vertices = if material.dielectric.is_some() { 2 } else { 1 };
+        let mut ray = Ray { orig: self.x, dir: dir };
+        let mut throughput = Vec3f::one();
+        for _ in 0..vertices {
+            let isect = match self.scene.nearest_intersection(&ray) {
+                Some(isect) => isect,
+                None        => return None
+            };
+            match isect.surface {
+                SurfaceProperties::Material(m_id) if m_id == self.object => {},
+                _ => return None
+            }
+            let entering = isect.normal.dot(&ray.dir) < 0.0;
+            let normal = if entering { isect.normal } else { -isect.normal };
+            let out = match material.dielectric {
+                Some(dielectric) => {
+                    let eta = if entering { dielectric.ior } else { 1.0 / dielectric.ior };
+                    let wo = -ray.dir;
+                    // radiance is squeezed into the smaller solid angle on the denser side, see `Brdf`
+                    let fresnel = fresnel_dielectric(wo.dot(&normal), eta);
+                    throughput = throughput * dielectric.transmittance * ((1.0 - fresnel) / (eta * eta));
+                    refract(&wo, &normal, eta)?
+                },
+                None => {
+                    throughput = throughput * (material.specular + material.mirror);
+                    ray.dir.reflect_global(&normal)
+                }
+            };
+            ray = Ray { orig: isect.position, dir: out };
+        }
+        Some(ChainEnd { dir: dir, pos: ray.orig, out: ray.dir, throughput: throughput })
+    }
+
+    // the ray leaving the chain has to head to the target, the mismatch in the plane
+    // orthogonal to `frame`
+    fn constraint(&self, a: f32, b: f32, target: &Target, frame: &Frame) -> Option<(f32, f32)> {
+        self.trace(a, b).map(|end| {
+            let to_target = match *target {
+                Target::Point { pos, .. } => (pos - end.pos).normalize(),
+                Target::Direction(dir)    => dir
+            };
+            let local = frame.to_local(&(end.out - to_target));
+            (local.x, local.y)
+        })
+    }
+
+    fn solve(&self, target: &Target, seed_end: &ChainEnd) -> Option<(f32, f32)> {
+        let frame = Frame::from_z(&seed_end.out);
+        let (mut a, mut b) = (0.0, 0.0);
+        for _ in 0..MAX_NEWTON_STEPS {
+            let c = match self.constraint(a, b, target, &frame) {
+                Some(c) => c,
+                None    => return None
+            };
+            if c.0.abs() + c.1.abs() < NEWTON_TOLERANCE {
+                return Some((a, b));
+            }
+            let (ca, cb) = match (self.constraint(a + NEWTON_DELTA, b, target, &frame),
+                                  self.constraint(a, b + NEWTON_DELTA, target, &frame)) {
+                (Some(ca), Some(cb)) => (ca, cb),
+                _                    => return None
+            };
+            let (j00, j01) = ((ca.0 - c.0) / NEWTON_DELTA, (cb.0 - c.0) / NEWTON_DELTA);
+            let (j10, j11) = ((ca.1 - c.1) / NEWTON_DELTA, (cb.1 - c.1) / NEWTON_DELTA);
+            let det = j00 * j11 - j01 * j10;
+            if det.abs() < 1e-12 {
+                return None;
+            }
+            a -= (j11 * c.0 - j01 * c.1) / det;
+            b -= (j00 * c.1 - j10 * c.0) / det;
+        }
+        None
+    }
+
+    // |d omega_x / d target| at the solution: solid angle at x per unit of light area
+    // (or of solid angle of the background), by tracing two neighbouring chains
+    fn direction_per_target(&self, a: f32, b: f32, target: &Target) -> f32 {
+        let ends = [(a, b), (a + NEWTON_DELTA, b), (a, b + NEWTON_DELTA)].iter()
+            .filter_map(|&(a, b)| self.trace(a, b).and_then(|chain| {
+                let end = match *target {
+                    Target::Point { pos, normal } => {
+                        let cos = chain.out.dot(&normal);
+                        if cos.abs() < 1e-6 {
+                            return None;
+                        }
+                        chain.pos + chain.out * ((pos - chain.pos).dot(&normal) / cos)
+                    },
+                    Target::Direction(_) => chain.out
+                };
+                Some((chain.dir, end))
+            }))
+            .collect::<Vec<_>>();
+        if ends.len() < 3 {
+            return f32::INFINITY;
+        }
+        let solid_angle = (ends[1].0 - ends[0].0).cross(&(ends[2].0 - ends[0].0)).norm();
+        let target_area = (ends[1].1 - ends[0].1).cross(&(ends[2].1 - ends[0].1)).norm();
+        if target_area > 0.0 { solid_angle / target_area } else { f32::INFINITY }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chain, ChainProgress, ManifoldNee, Target};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::{GLASS, MIRROR};
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn newton_finds_the_reflection_point() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let mirror = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, MIRROR).unwrap();
+        let x = Vec3f::new(-3.0, 2.0, 0.0);
+        let light = Vec3f::new(3.0, 2.0, 0.0);
+        let seed = (Vec3f::new(0.3, 0.9, 0.2) - x).normalize();
+        let chain = Chain::new(&scene, x, mirror, seed);
+        let target = Target::Point { pos: light, normal: Vec3f::new(0.0, -1.0, 0.0) };
+        let (a, b) = chain.solve(&target, &chain.trace(0.0, 0.0).unwrap()).expect("no solution");
+        // the setup is symmetric, the reflection happens on top of the sphere
+        let end = chain.trace(a, b).unwrap();
+        assert!(end.pos.x.abs() < 1e-3 && end.pos.z.abs() < 1e-3);
+        assert!((end.pos.y - 1.0).abs() < 1e-3);
+        assert!(chain.direction_per_target(a, b, &target).is_finite());
+    }
+
+    #[test]
+    fn newton_finds_the_path_through_glass() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let glass = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, GLASS).unwrap();
+        let x = Vec3f::new(0.3, 0.0, -3.0);
+        let light = Vec3f::new(-0.2, 0.1, 4.0);
+        let chain = Chain::new(&scene, x, glass, (Vec3f::new(0.1, 0.0, 0.0) - x).normalize());
+        let target = Target::Point { pos: light, normal: Vec3f::new(0.0, 0.0, -1.0) };
+        let (a, b) = chain.solve(&target, &chain.trace(0.0, 0.0).unwrap()).expect("no solution");
+        // the chain leaves the far side of the sphere towards the light
+        let end = chain.trace(a, b).unwrap();
+        assert!((end.pos.norm() - 1.0).abs() < 1e-3 && end.pos.z > 0.0);
+        assert!(end.out.dot(&(light - end.pos).normalize()) > 1.0 - 1e-6);
+        // two refractions, 4% reflected by each at about the normal incidence
+        assert!(end.throughput.x < 0.93 && end.throughput.x > 0.85);
+        assert!(chain.direction_per_target(a, b, &target).is_finite());
+    }
+
+    #[test]
+    fn only_whole_chains_cover_the_light_hit() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let mirror = scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, MIRROR).unwrap();
+        let glass = scene.add_object(Sphere { center: Vec3f::new(5.0, 0.0, 0.0), radius: 1.0 }, GLASS).unwrap();
+        let manifold = ManifoldNee::new(&scene);
+        let start = Some(ChainProgress::Start);
+        assert_eq!(manifold.follow(&scene, start, mirror, false), Some(ChainProgress::End));
+        assert_eq!(manifold.follow(&scene, start, mirror, true), None);
+        // the glass is entered and left
+        let inside = manifold.follow(&scene, start, glass, true);
+        assert_eq!(inside, Some(ChainProgress::Inside(glass)));
+        assert_eq!(manifold.follow(&scene, inside, glass, true), Some(ChainProgress::End));
+        assert_eq!(manifold.follow(&scene, inside, glass, false), None);
+        assert_eq!(manifold.follow(&scene, start, glass, false), None);
+        // a second object after a whole chain is a path the manifold NEE didn't solve
+        assert_eq!(manifold.follow(&scene, Some(ChainProgress::End), mirror, false), None);
+        assert_eq!(manifold.follow(&scene, None, mirror, false), None);
+    }
+}
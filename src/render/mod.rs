@@ -1,55 +1,427 @@
 #![allow(dead_code)]
 use camera::PerspectiveCamera;
-use framebuffer::RgbFrameBuffer;
-use math::{Vec2f, Vec3f};
-use rand::{Rng, thread_rng};
-use scene::Scene;
+use framebuffer::{AdaptiveFrameBuffer, HalfFrameBuffer, RgbFrameBuffer, TiledFrameBuffer, FRAME_TILE_SIZE, add_pixel_sample};
+use math::{Vec2f, Vec2u, Vec3f};
+use rand::Rng;
+use memory::OutOfBudget;
+use scene::{Scene, SceneChanges};
+use numa;
+use throttle;
+use utility::{seeded_rng, Pcg32, Sampler};
 use rayon::prelude::*;
+use std::time::Instant;
 
+mod blue_noise;
+mod cmj;
 mod cpu_pt_mis;
+mod cpu_bdpt;
+mod cpu_ir;
+mod cpu_lt;
+mod cpu_pm;
+mod cpu_pssmlt;
+mod cpu_rc;
+mod cpu_vcm;
+mod direct_lighting;
 mod eyelight;
+mod gradient;
+mod hash_grid;
+mod importance;
 mod cpu_pt;
 mod cpu_pt_dl;
+mod cpu_pt_guided;
+mod cpu_pt_wavefront;
+mod layers;
+mod light_groups;
+mod manifold;
+mod path_stats;
+mod photon_map;
+mod primary_cache;
+mod sd_tree;
+mod halton;
+mod settings;
+mod sobol;
+mod stratified;
+mod watchdog;
 
 pub use self::cpu_pt_mis::CpuPtMis;
+pub use self::cpu_bdpt::CpuBdpt;
+pub use self::cpu_ir::CpuIr;
+pub use self::cpu_lt::CpuLt;
+pub use self::cpu_pm::CpuPm;
+pub use self::cpu_pssmlt::CpuPssmlt;
+pub use self::cpu_rc::CpuRc;
+pub use self::cpu_vcm::CpuVcm;
+pub use self::direct_lighting::DirectLighting;
 pub use self::eyelight::EyeLight;
+pub use self::gradient::{SceneParameter, iterate_gradient};
+pub use self::hash_grid::HashGrid;
+pub use self::importance::ImportanceMask;
 pub use self::cpu_pt::CpuPt;
 pub use self::cpu_pt_dl::CpuPtDl;
+pub use self::cpu_pt_guided::CpuPtGuided;
+pub use self::cpu_pt_wavefront::CpuPtWavefront;
+pub use self::layers::{LayerImage, accumulate_coverage, render_layers};
+pub use self::light_groups::{LightGroupRender, relight};
+pub use self::manifold::{ChainProgress, ManifoldNee};
+pub use self::photon_map::{Photon, PhotonMap};
+pub use self::primary_cache::PrimaryCache;
+pub use self::path_stats::{BounceCounts, PathStatsFrame, PathStatsRender, heat_color};
+pub use self::settings::RenderSettings;
+pub use self::stratified::{PixelSampler, SamplerKind};
+pub use self::watchdog::{Watchdog, PathGuard, TileAbort};
 
+// rows of pixels rendered by one task, the unit the watchdog times
+const TILE_ROWS: usize = 8;
 pub trait Render<S: Scene> {
-    fn new(cam: PerspectiveCamera, scene: S) -> Self;
-    fn iterate(&self, iter_nb: usize, frame: &mut RgbFrameBuffer);
+    fn new(cam: PerspectiveCamera, scene: S) -> Self where Self: Sized {
+        Self::new_with_settings(cam, scene, RenderSettings::new())
+    }
+    fn new_with_settings(cam: PerspectiveCamera, scene: S, settings: RenderSettings) -> Self;
+    fn settings(&self) -> &RenderSettings;
+    // adds `spp` samples to every pixel of `frame`
+    fn iterate(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer);
+    fn watchdog(&self) -> &Watchdog;
+    fn get_scene(&self) -> &S;
+    fn get_scene_mut(&mut self) -> &mut S;
+
+    // commits the edits made through `get_scene_mut`, renderers keeping data derived from the scene
+    // bring it up to date here
+    fn commit_changes(&mut self) -> SceneChanges {
+        self.get_scene_mut().commit_changes()
+    }
+
+    fn get_seed(&self) -> u32 {
+        self.get_scene().get_seed()
+    }
+
+    fn get_sampler(&self) -> SamplerKind {
+        self.settings().sampler
+    }
+
+    fn is_deterministic(&self) -> bool {
+        self.settings().deterministic
+    }
+
+    // the screen loops make one per tile and start it for every pixel, see `pixel_rng`
+    fn new_pixel_sampler(&self) -> Box<PixelSampler> {
+        self.get_sampler().new_sampler(self.get_seed(), self.is_deterministic())
+    }
+}
+
+// the jitter stream of the pixel `pix_nb` in the iteration `iter_nb`, the samplers place its samples with it
+pub fn pixel_rng(seed: u32, iter_nb: usize, pix_nb: usize) -> Pcg32 {
+    seeded_rng(seed, ((iter_nb as u64) << 32) | pix_nb as u64)
 }
 
-pub trait CpuStRender {
-    fn iterate_over_screen(&self, _iter_nb: usize, frame: &mut RgbFrameBuffer) {
+pub trait CpuStRender<S: Scene>: Render<S> {
+    fn iterate_over_screen(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
         let res_x = self.get_view_size().x as usize;
+        let seed = self.get_seed();
+        let mut sampler = self.new_pixel_sampler();
         frame.as_mut_slice().iter_mut().enumerate().all(|(pix_nb, pix)| {
             let (x, y) = (pix_nb % res_x, pix_nb / res_x);
-            let jitter = Vec2f::new(thread_rng().next_f32(), thread_rng().next_f32());
-            let sample = Vec2f::new(x as f32, y as f32) + jitter;
-            let color = self.trace_from_screen(sample);
-            *pix = *pix + color;
+            sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+            for sample_nb in 0..spp {
+                let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                let color = self.trace_from_screen(sample, &mut sampler);
+                *pix = *pix + color;
+            }
             true
         });
     }
 
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f;
+    // the numbers of the path are drawn from `sampler`
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f;
     fn get_view_size(&self) -> Vec2f;
 }
 
-pub trait CpuMtRender where Self: Sync {
-    fn iterate_over_screen(&self, _iter_nb: usize, frame: &mut RgbFrameBuffer) {
+pub trait CpuMtRender<S: Scene>: Render<S> + Sync {
+    fn iterate_over_screen(&self, iter_nb: usize, spp: usize, frame: &mut RgbFrameBuffer) {
+        self.iterate_over_screen_masked(iter_nb, spp, None, frame)
+    }
+
+    // `spp` is the average budget, the mask moves samples between pixels
+    fn iterate_over_screen_masked(&self, iter_nb: usize, spp: usize, mask: Option<&ImportanceMask>,
+                                  frame: &mut RgbFrameBuffer) {
         let res_x = self.get_view_size().x as usize;
-        frame.as_mut_slice().par_iter_mut().enumerate().for_each(|(pix_nb, pix)| {
-            let (x, y) = (pix_nb % res_x, pix_nb / res_x);
-            let jitter = Vec2f::new(thread_rng().next_f32(), thread_rng().next_f32());
-            let sample = Vec2f::new(x as f32, y as f32) + jitter;
-            let color = self.trace_from_screen(sample);
-            *pix = *pix + color;
+        let seed = self.get_seed();
+        let watchdog = self.watchdog();
+        // no time limit in the deterministic mode, it'd make the image depend on the machine
+        let deterministic = self.is_deterministic();
+        let tile_len = res_x * TILE_ROWS;
+        let mut tiles = frame.as_mut_slice().chunks_mut(tile_len).collect::<Vec<_>>();
+        tiles.par_iter_mut().enumerate().for_each(|(tile_nb, tile)| {
+            numa::global().pin_current_thread();
+            let _slot = throttle::global().acquire();
+            let started = Instant::now();
+            let mut sampler = self.new_pixel_sampler();
+            for (i, pix) in tile.iter_mut().enumerate() {
+                // pixels left after an abort just miss this iteration's samples
+                if !deterministic && watchdog.tile_expired(tile_nb, &started) {
+                    break;
+                }
+                let pix_nb = tile_nb * tile_len + i;
+                let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+                let mut rng = pixel_rng(seed, iter_nb, pix_nb);
+                let (samples_nb, scale) = match mask {
+                    Some(mask) => mask.samples(pix_nb, spp, rng.next_f32()),
+                    None       => (spp, 1.0)
+                };
+                sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut rng, samples_nb);
+                for sample_nb in 0..samples_nb {
+                    let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                    let color = self.trace_from_screen(sample, &mut sampler);
+                    *pix = *pix + color * scale;
+                }
+            }
+            self.end_tile();
+        });
+    }
+
+    // Adds `spp` samples to the pixels of `frame` which aren't converged yet (see `AdaptiveFrameBuffer::converged`),
+    // the noisy parts of the frame get the samples the clean ones don't need. Returns the number of the pixels sampled
+    fn iterate_adaptive(&self, iter_nb: usize, spp: usize, threshold: f32, min_samples: u32,
+                        frame: &mut AdaptiveFrameBuffer) -> usize {
+        let res_x = self.get_view_size().x as usize;
+        let seed = self.get_seed();
+        let watchdog = self.watchdog();
+        let deterministic = self.is_deterministic();
+        let tile_len = res_x * TILE_ROWS;
+        let res = frame.resolution();
+        let converged = (0..res.x * res.y).map(|pix_nb| frame.converged(pix_nb, threshold, min_samples)).collect::<Vec<_>>();
+        let mut tiles = frame.chunks_mut(tile_len);
+        tiles.par_iter_mut().enumerate().for_each(|(tile_nb, tile)| {
+            numa::global().pin_current_thread();
+            let _slot = throttle::global().acquire();
+            let started = Instant::now();
+            let mut sampler = self.new_pixel_sampler();
+            for i in 0..tile.0.len() {
+                let pix_nb = tile_nb * tile_len + i;
+                if converged[pix_nb] {
+                    continue;
+                }
+                if !deterministic && watchdog.tile_expired(tile_nb, &started) {
+                    break;
+                }
+                let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+                sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+                for sample_nb in 0..spp {
+                    let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                    let color = self.trace_from_screen(sample, &mut sampler);
+                    add_pixel_sample((&mut tile.0[i], &mut tile.1[i], &mut tile.2[i]), color);
+                }
+            }
+            self.end_tile();
+        });
+        converged.iter().filter(|&&converged| !converged).count()
+    }
+
+    // Renders the pixels from `min` up to `max` (excluded) into `frame`, only their tiles get allocated.
+    // They get the same samples as in a whole frame, so crops and distributed tiles fit together
+    fn iterate_over_region(&self, iter_nb: usize, spp: usize, min: Vec2u, max: Vec2u,
+                           frame: &mut TiledFrameBuffer) -> Result<(), OutOfBudget> {
+        frame.alloc_region(min, max)?;
+        let res_x = self.get_view_size().x as usize;
+        let seed = self.get_seed();
+        let watchdog = self.watchdog();
+        let deterministic = self.is_deterministic();
+        let mut tiles = frame.tiles_mut();
+        tiles.par_iter_mut().enumerate().for_each(|(tile_nb, tile)| {
+            numa::global().pin_current_thread();
+            let _slot = throttle::global().acquire();
+            let started = Instant::now();
+            let origin = tile.0;
+            let mut sampler = self.new_pixel_sampler();
+            for (i, pix) in tile.1.iter_mut().enumerate() {
+                let (x, y) = (origin.x + i % FRAME_TILE_SIZE, origin.y + i / FRAME_TILE_SIZE);
+                if x < min.x || x >= max.x || y < min.y || y >= max.y {
+                    continue;
+                }
+                if !deterministic && watchdog.tile_expired(tile_nb, &started) {
+                    break;
+                }
+                let pix_nb = y * res_x + x;
+                sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+                for sample_nb in 0..spp {
+                    let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                    *pix = *pix + self.trace_from_screen(sample, &mut sampler);
+                }
+            }
+            self.end_tile();
         });
+        Ok(())
     }
 
-    fn trace_from_screen(&self, sample: Vec2f) -> Vec3f;
+    // Adds an iteration to the half float sums a band at a time, with the same samples as `iterate`;
+    // the whole frame is never in f32
+    fn iterate_into_half(&self, iter_nb: usize, spp: usize, frame: &mut HalfFrameBuffer) -> Result<(), OutOfBudget> {
+        frame.add_bands(spp, &mut |min, max, band| self.iterate_over_region(iter_nb, spp, min, max, band))
+    }
+
+    // Called on the thread of a tile once the screen loops are done with it: renderers gathering data
+    // while tracing keep it per thread and merge it here, once a tile instead of once a path
+    fn end_tile(&self) {}
+
+    // the numbers of the path are drawn from `sampler`
+    fn trace_from_screen<R: Sampler>(&self, sample: Vec2f, sampler: &mut R) -> Vec3f;
     fn get_view_size(&self) -> Vec2f;
 }
+
+#[cfg(test)]
+mod tests {
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use framebuffer::{AdaptiveFrameBuffer, TiledFrameBuffer, FRAME_TILE_SIZE};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuBdpt, CpuIr, CpuLt, CpuMtRender, CpuPm, CpuPssmlt, CpuPt, CpuPtDl, CpuPtGuided, CpuPtMis,
+                 CpuPtWavefront, CpuRc, CpuVcm, DirectLighting, EyeLight, Render, RenderSettings};
+    use scene::{DefaultScene, LightSelection, Scene};
+
+    type TestScene = DefaultScene<GeometryList>;
+
+    // two iterations of the deterministic mode with the scene seed `seed`
+    fn seeded_frame<R: Render<TestScene>>(seed: u32) -> Vec<Vec3f> {
+        let mut scene = TestScene::new(BackgroundLight { intensity: Vec3f::new(0.2, 0.2, 0.2) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_luminous_object(Sphere { center: Vec3f::new(1.0, 3.0, -2.0), radius: 0.5 }, Vec3f::new(4.0, 4.0, 4.0))
+            .unwrap();
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 0.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = R::new_with_settings(cam.clone(), scene, RenderSettings::new().with_seed(seed).with_deterministic());
+        let mut frame = cam.build_rgb_framebuffer();
+        for iter_nb in 0..2 {
+            ren.iterate(iter_nb, 2, &mut frame);
+        }
+        frame.as_slice().to_vec()
+    }
+
+    fn assert_seed_repeats_the_frame<R: Render<TestScene>>(name: &str) {
+        let frame = seeded_frame::<R>(7);
+        assert!(seeded_frame::<R>(7) == frame, "{}", name);
+        assert!(seeded_frame::<R>(8) != frame, "{}", name);
+    }
+
+    #[test]
+    fn the_seed_makes_the_frame() {
+        // the jitters, the path numbers and the light paths all come from the seed
+        assert_seed_repeats_the_frame::<EyeLight<TestScene>>("eyelight");
+        assert_seed_repeats_the_frame::<CpuPt<TestScene>>("pt");
+        assert_seed_repeats_the_frame::<CpuPtMis<TestScene>>("pt-mis");
+        assert_seed_repeats_the_frame::<CpuPtDl<TestScene>>("pt-dl");
+        assert_seed_repeats_the_frame::<CpuPtGuided<TestScene>>("pt-guided");
+        assert_seed_repeats_the_frame::<CpuPtWavefront<TestScene>>("pt-wavefront");
+        assert_seed_repeats_the_frame::<DirectLighting<TestScene>>("direct");
+        assert_seed_repeats_the_frame::<CpuRc<TestScene>>("rc");
+        assert_seed_repeats_the_frame::<CpuPm<TestScene>>("pm");
+        assert_seed_repeats_the_frame::<CpuIr<TestScene>>("ir");
+        assert_seed_repeats_the_frame::<CpuBdpt<TestScene>>("bdpt");
+        assert_seed_repeats_the_frame::<CpuVcm<TestScene>>("vcm");
+        assert_seed_repeats_the_frame::<CpuLt<TestScene>>("lt");
+        assert_seed_repeats_the_frame::<CpuPssmlt<TestScene>>("pssmlt");
+    }
+
+    #[test]
+    fn region_matches_the_whole_frame() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.2, 0.2, 0.2) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, -2.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        let res = Vec2u::new(3 * FRAME_TILE_SIZE, 2 * FRAME_TILE_SIZE);
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(0.0, 0.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = CpuPtMis::new(cam.clone(), scene);
+        let mut whole = cam.build_rgb_framebuffer();
+        ren.iterate_over_screen(3, 2, &mut whole);
+
+        // a crop across the border of two tiles
+        let (min, max) = (Vec2u::new(FRAME_TILE_SIZE - 8, 20), Vec2u::new(FRAME_TILE_SIZE + 8, 40));
+        let mut tiled = TiledFrameBuffer::new(res);
+        ren.iterate_over_region(3, 2, min, max, &mut tiled).unwrap();
+        assert_eq!(tiled.allocated_tiles(), 2);
+        let crop = tiled.crop(min, max);
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                assert_eq!(crop.as_slice()[(y - min.y) * 16 + x - min.x], whole.as_slice()[y * res.x + x]);
+            }
+        }
+        assert_eq!(tiled.get_color((min.x - 1, min.y)), Vec3f::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn converged_pixels_stop_sampling() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.2, 0.2, 0.2) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, -2.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        let res = Vec2u::new(16, 16);
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(0.0, 0.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = CpuPtMis::new(cam, scene);
+        let mut frame = AdaptiveFrameBuffer::new(res);
+        let mut sampled = Vec::new();
+        for iter_nb in 0..8 {
+            sampled.push(ren.iterate_adaptive(iter_nb, 4, 0.02, 8, &mut frame));
+        }
+        assert_eq!(sampled[0], 16 * 16);
+        assert!(sampled[7] < sampled[1]);
+
+        // the background is the same in every sample, it's done after the minimum,
+        // the lit sphere in the middle keeps going
+        let (corner, center) = (0, 8 * 16 + 8);
+        assert_eq!(frame.samples(corner), 8);
+        assert!((frame.mean(corner).x - 0.2).abs() < 1e-6);
+        assert_eq!(frame.samples(center), 32);
+        assert!(frame.converged_nb(0.02, 8) >= 16 * 16 - sampled[7]);
+    }
+
+    // the mean of the frame of 64 iterations, the lights picked with `selection`
+    fn frame_mean<R: Render<TestScene>>(selection: LightSelection, configure: &Fn(R) -> R) -> f32 {
+        let iterations = 64;
+        let mut scene = TestScene::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 999.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(2.0, 3.0, -2.0), intensity: Vec3f::new(20.0, 20.0, 20.0) });
+        scene.add_light(PointLight { position: Vec3f::new(-2.0, 2.0, -1.0), intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_luminous_object(Sphere { center: Vec3f::new(-1.0, 3.0, 1.0), radius: 0.5 }, Vec3f::new(2.0, 2.0, 2.0))
+            .unwrap();
+        scene.set_light_selection(selection);
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(16, 16))
+            .with_pos(Vec3f::new(0.0, 0.0, -4.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = configure(R::new_with_settings(cam.clone(), scene, RenderSettings::new().with_seed(1).with_deterministic()));
+        let mut frame = cam.build_rgb_framebuffer();
+        for iter_nb in 0..iterations {
+            ren.iterate(iter_nb, 1, &mut frame);
+        }
+        frame.as_slice().iter().map(|pix| pix.x).sum::<f32>() / (frame.as_slice().len() * iterations) as f32
+    }
+
+    fn assert_selection_keeps_the_mean<R: Render<TestScene>>(name: &str, configure: &Fn(R) -> R) {
+        let uniform = frame_mean(LightSelection::Uniform, configure);
+        for &selection in [LightSelection::Power, LightSelection::Tree].iter() {
+            let picked = frame_mean(selection, configure);
+            assert!((picked - uniform).abs() < 0.05 * uniform, "{} {:?}: {} instead of {}", name, selection, picked, uniform);
+        }
+    }
+
+    #[test]
+    fn light_selection_keeps_the_mean() {
+        assert_selection_keeps_the_mean::<CpuPtMis<TestScene>>("pt-mis", &|ren| ren);
+        assert_selection_keeps_the_mean::<CpuPtWavefront<TestScene>>("pt-wavefront", &|ren| ren);
+        assert_selection_keeps_the_mean::<CpuBdpt<TestScene>>("bdpt", &|ren| ren);
+        assert_selection_keeps_the_mean::<CpuVcm<TestScene>>("vcm", &|ren| ren);
+        assert_selection_keeps_the_mean::<CpuPm<TestScene>>("pm", &|ren| ren.with_photons(2000));
+        assert_selection_keeps_the_mean::<CpuRc<TestScene>>("rc", &|ren| ren);
+    }
+}
@@ -0,0 +1,170 @@
+use brdf::BounceKind;
+use framebuffer::RgbFrameBuffer;
+use math::{Vec2f, Vec2u, Vec3f};
+use rayon::prelude::*;
+use render::{CpuMtRender, PixelSampler, TILE_ROWS, pixel_rng};
+use scene::Scene;
+use utility::Sampler;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BounceCounts {
+    pub diffuse: u32,
+    pub glossy: u32,
+    pub specular: u32,
+}
+
+// bounce counts of every pixel over all the samples it got
+#[derive(Debug, Clone)]
+pub struct PathStatsFrame {
+    counts: Vec<BounceCounts>,
+    resolution: Vec2u,
+    spp: usize,
+}
+
+// Renderers which can report how their paths scatter, to see where the time and the noise go.
+// The path tracers (pt, pt-dl, pt-mis) are, the others don't trace a path per sample
+pub trait PathStatsRender<S: Scene> : CpuMtRender<S> {
+    fn iterate_path_stats(&self, iter_nb: usize, spp: usize, stats: &mut PathStatsFrame) {
+        let res_x = self.get_view_size().x as usize;
+        let seed = self.get_seed();
+        let tile_len = res_x * TILE_ROWS;
+        let mut tiles = stats.counts.chunks_mut(tile_len).collect::<Vec<_>>();
+        tiles.par_iter_mut().enumerate().for_each(|(tile_nb, tile)| {
+            let mut sampler = self.new_pixel_sampler();
+            for (i, counts) in tile.iter_mut().enumerate() {
+                let pix_nb = tile_nb * tile_len + i;
+                let (x, y) = (pix_nb % res_x, pix_nb / res_x);
+                sampler.start_pixel(iter_nb, pix_nb, (x, y), &mut pixel_rng(seed, iter_nb, pix_nb), spp);
+                for sample_nb in 0..spp {
+                    let sample = Vec2f::new(x as f32, y as f32) + sampler.start_sample(sample_nb);
+                    self.trace_path_stats(sample, counts, &mut sampler);
+                }
+            }
+        });
+        stats.spp += spp;
+    }
+
+    fn trace_path_stats<R: Sampler>(&self, sample: Vec2f, counts: &mut BounceCounts, sampler: &mut R);
+}
+
+impl BounceCounts {
+    pub fn add(&mut self, kind: BounceKind) {
+        match kind {
+            BounceKind::Diffuse  => self.diffuse += 1,
+            BounceKind::Glossy   => self.glossy += 1,
+            BounceKind::Specular => self.specular += 1,
+        }
+    }
+
+    pub fn get(&self, kind: BounceKind) -> u32 {
+        match kind {
+            BounceKind::Diffuse  => self.diffuse,
+            BounceKind::Glossy   => self.glossy,
+            BounceKind::Specular => self.specular,
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.diffuse + self.glossy + self.specular
+    }
+}
+
+impl PathStatsFrame {
+    pub fn new(resolution: Vec2u) -> PathStatsFrame {
+        PathStatsFrame {
+            counts: vec![BounceCounts::default(); resolution.x * resolution.y],
+            resolution: resolution,
+            spp: 0,
+        }
+    }
+
+    // the counts of every pixel after `spp` samples, e.g. of a checkpoint
+    pub fn from_counts(resolution: Vec2u, counts: Vec<BounceCounts>, spp: usize) -> PathStatsFrame {
+        assert_eq!(counts.len(), resolution.x * resolution.y);
+        PathStatsFrame { counts: counts, resolution: resolution, spp: spp }
+    }
+
+    pub fn resolution(&self) -> Vec2u {
+        self.resolution
+    }
+
+    pub fn counts(&self) -> &[BounceCounts] {
+        &self.counts
+    }
+
+    // bounces of the kind per sample, None counts all of them; scaled to the worst pixel
+    pub fn heatmap(&self, kind: Option<BounceKind>) -> RgbFrameBuffer {
+        let per_pixel = self.counts.iter()
+            .map(|counts| kind.map_or(counts.total(), |kind| counts.get(kind)))
+            .collect::<Vec<_>>();
+        let max = per_pixel.iter().cloned().max().unwrap_or(0).max(1);
+        let mut frame = RgbFrameBuffer::new(self.resolution);
+        for (pix, &count) in frame.as_mut_slice().iter_mut().zip(per_pixel.iter()) {
+            *pix = heat_color(count as f32 / max as f32);
+        }
+        frame
+    }
+
+    pub fn spp(&self) -> usize {
+        self.spp
+    }
+}
+
+// false color for t in [0, 1]: black, blue, cyan, green, yellow, red
+pub fn heat_color(t: f32) -> Vec3f {
+    let ramp = [Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.0, 1.0, 1.0),
+                Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(1.0, 1.0, 0.0), Vec3f::new(1.0, 0.0, 0.0)];
+    let x = t.max(0.0).min(1.0) * (ramp.len() - 1) as f32;
+    let idx = (x as usize).min(ramp.len() - 2);
+    let f = x - idx as f32;
+    ramp[idx] * (1.0 - f) + ramp[idx + 1] * f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BounceCounts, PathStatsFrame, PathStatsRender, heat_color};
+    use brdf::BounceKind;
+    use camera::{CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::BackgroundLight;
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPt, CpuPtDl, CpuPtMis, Render, RenderSettings};
+    use scene::{DefaultScene, Scene};
+
+    #[test]
+    fn heatmap_is_scaled_to_the_worst_pixel() {
+        let mut stats = PathStatsFrame::new(Vec2u::new(2, 1));
+        stats.counts[0] = BounceCounts { diffuse: 1, glossy: 0, specular: 4 };
+        stats.counts[1] = BounceCounts { diffuse: 2, glossy: 0, specular: 0 };
+        let heatmap = stats.heatmap(Some(BounceKind::Specular));
+        assert_eq!(heatmap.as_slice(), &[heat_color(1.0), heat_color(0.0)][..]);
+        assert_eq!(heat_color(1.0), Vec3f::new(1.0, 0.0, 0.0));
+        assert_eq!(stats.heatmap(None).as_slice()[1], heat_color(0.4));
+    }
+
+    #[test]
+    fn every_path_tracer_counts_the_bounces() {
+        assert_counts_diffuse_bounces::<CpuPt<_>>();
+        assert_counts_diffuse_bounces::<CpuPtDl<_>>();
+        assert_counts_diffuse_bounces::<CpuPtMis<_>>();
+    }
+
+    fn assert_counts_diffuse_bounces<R>()
+        where R: PathStatsRender<DefaultScene<GeometryList>> + Render<DefaultScene<GeometryList>> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(4, 4))
+            .with_pos(Vec3f::new(0.0, 0.0, -2.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let ren = R::new_with_settings(cam, scene, RenderSettings::new().with_seed(5));
+        let mut stats = PathStatsFrame::new(Vec2u::new(4, 4));
+        ren.iterate_path_stats(1, 4, &mut stats);
+        assert_eq!(stats.spp(), 4);
+        // the middle pixels see the sphere
+        assert!(stats.counts()[5].diffuse > 0);
+        assert!(stats.counts().iter().all(|counts| counts.glossy == 0 && counts.specular == 0));
+    }
+}
@@ -0,0 +1,105 @@
+use math::vector_traits::*;
+use math::Vec3f;
+use std::cmp::Ordering;
+use std::f32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub pos: Vec3f,
+    pub wi: Vec3f, // toward where the photon came from, like BrdfSample::wi
+    pub power: Vec3f,
+}
+
+// Balanced kd-tree kept in a single array: the median of every range is the node
+// which splits the rest of the range along the longest axis of its bounds
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>, // split axis of the node at the same index
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap { photons: photons, axes: axes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // calls `f` for every photon within `radius` of `pos`
+    pub fn for_each_near<F>(&self, pos: &Vec3f, radius: f32, f: &mut F) where F: FnMut(&Photon) {
+        self.query(0, self.photons.len(), pos, radius, f);
+    }
+
+    fn query<F>(&self, lo: usize, hi: usize, pos: &Vec3f, radius: f32, f: &mut F) where F: FnMut(&Photon) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        if (photon.pos - *pos).sqnorm() <= radius * radius {
+            f(photon);
+        }
+        let axis = self.axes[mid];
+        let delta = pos[axis] - photon.pos[axis];
+        if delta <= radius {
+            self.query(lo, mid, pos, radius, f);
+        }
+        if delta >= -radius {
+            self.query(mid + 1, hi, pos, radius, f);
+        }
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let (min, max) = photons.iter().fold((Vec3f::new(f32::MAX, f32::MAX, f32::MAX), Vec3f::new(f32::MIN, f32::MIN, f32::MIN)),
+        |(min, max), photon| (min.zip(&photon.pos, f32::min), max.zip(&photon.pos, f32::max)));
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    photons.sort_by(|a, b| a.pos[axis].partial_cmp(&b.pos[axis]).unwrap_or(Ordering::Equal));
+
+    let mid = photons.len() / 2;
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Photon, PhotonMap};
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use rand::Rng;
+    use utility::seeded_rng;
+
+    #[test]
+    fn range_query_matches_brute_force() {
+        let mut rng = seeded_rng(7, 0);
+        let photons = (0..1000).map(|_| Photon {
+            pos: Vec3f::new(rng.next_f32(), rng.next_f32() * 4.0, rng.next_f32()),
+            wi: Vec3f::new(0.0, 0.0, 1.0),
+            power: Vec3f::new(1.0, 1.0, 1.0),
+        }).collect::<Vec<_>>();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), 1000);
+
+        for _ in 0..20 {
+            let pos = Vec3f::new(rng.next_f32(), rng.next_f32() * 4.0, rng.next_f32());
+            let mut found = 0;
+            map.for_each_near(&pos, 0.2, &mut |_| found += 1);
+            let expected = photons.iter().filter(|photon| (photon.pos - pos).norm() <= 0.2).count();
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use camera::{Camera, PerspectiveCamera};
+use geometry::{Ray, SurfaceIntersection};
+use math::Vec2f;
+use memory::{self, MemoryCategory, OutOfBudget, Reservation};
+use rayon::prelude::*;
+use scene::SurfaceProperties;
+use std::mem;
+
+// camera hits of a row of pixels; a pixel has `strata * strata` of them, row by row
+struct Row {
+    pixels: Vec<(u32, u8)>, // first hit, strata per side
+    hits: Vec<Option<SurfaceIntersection>>,
+}
+
+// First hits of the camera rays, found once and reused by every iteration while the camera and
+// the scene stay the same. The samples of a pixel are snapped to the centers of its strata, so
+// the cache only has to hold the hits of the centers. Pixels with edges in them keep all the
+// strata, the ones seeing a single material (or nothing) get a quarter of them: their hits differ
+// only by the texture lookups, which need fewer positions to stay smooth
+pub struct PrimaryCache {
+    res_x: usize,
+    rows: Vec<Row>,
+    _reservation: Reservation<'static>,
+}
+
+impl PrimaryCache {
+    // `strata` per side of the pixels with edges; the worst case, all pixels with edges, is reserved.
+    // `intersect` finds the nearest hit in the scene
+    pub fn new(camera: &PerspectiveCamera, strata: usize, intersect: &(Fn(&Ray) -> Option<SurfaceIntersection> + Sync))
+        -> Result<PrimaryCache, OutOfBudget> {
+        let strata = strata.max(1).min(255);
+        let res = camera.get_view_size();
+        let (res_x, res_y) = (res.x as usize, res.y as usize);
+        let bytes = res_x * res_y * (strata * strata * mem::size_of::<Option<SurfaceIntersection>>() + mem::size_of::<(u32, u8)>());
+        let reservation = memory::global().reserve(MemoryCategory::Framebuffer, bytes)?;
+
+        let mut rows = (0..res_y).map(|_| Row { pixels: Vec::with_capacity(res_x), hits: Vec::new() }).collect::<Vec<_>>();
+        rows.par_iter_mut().enumerate().for_each(|(y, row)| {
+            for x in 0..res_x {
+                let hits = pixel_hits(camera, intersect, x, y, strata);
+                let coarse = strata / 2;
+                let pixel_strata = if coarse >= 1 && single_surface(&hits) { coarse } else { strata };
+                let hits = if pixel_strata == strata { hits } else { pixel_hits(camera, intersect, x, y, coarse) };
+                row.pixels.push((row.hits.len() as u32, pixel_strata as u8));
+                row.hits.extend(hits);
+            }
+            row.hits.shrink_to_fit();
+        });
+        Ok(PrimaryCache { res_x: res_x, rows: rows, _reservation: reservation })
+    }
+
+    // the center of the stratum `sample` falls in and the hit of the ray through it
+    pub fn lookup(&self, sample: &Vec2f) -> (Vec2f, Option<SurfaceIntersection>) {
+        let x = (sample.x.max(0.0) as usize).min(self.res_x - 1);
+        let y = (sample.y.max(0.0) as usize).min(self.rows.len() - 1);
+        let row = &self.rows[y];
+        let (first, strata) = row.pixels[x];
+        let strata = strata as usize;
+        let stratum = |pos: f32, pixel: usize| ((pos - pixel as f32).max(0.0) * strata as f32).min(strata as f32 - 1.0) as usize;
+        let (sx, sy) = (stratum(sample.x, x), stratum(sample.y, y));
+        let center = Vec2f::new(x as f32 + (sx as f32 + 0.5) / strata as f32, y as f32 + (sy as f32 + 0.5) / strata as f32);
+        (center, row.hits[first as usize + sy * strata + sx])
+    }
+
+    pub fn hits_nb(&self) -> usize {
+        self.rows.iter().fold(0, |sum, row| sum + row.hits.len())
+    }
+}
+
+fn pixel_hits(camera: &PerspectiveCamera, intersect: &Fn(&Ray) -> Option<SurfaceIntersection>, x: usize, y: usize,
+              strata: usize) -> Vec<Option<SurfaceIntersection>> {
+    let mut hits = Vec::with_capacity(strata * strata);
+    for sy in 0..strata {
+        for sx in 0..strata {
+            let center = Vec2f::new(x as f32 + (sx as f32 + 0.5) / strata as f32, y as f32 + (sy as f32 + 0.5) / strata as f32);
+            hits.push(intersect(&camera.ray_from_screen(&center)));
+        }
+    }
+    hits
+}
+
+// all the hits are of one material or light, or all of them missed
+fn single_surface(hits: &[Option<SurfaceIntersection>]) -> bool {
+    let same = |a: &Option<SurfaceIntersection>, b: &Option<SurfaceIntersection>| match (a, b) {
+        (&None, &None) => true,
+        (&Some(ref a), &Some(ref b)) => match (a.surface, b.surface) {
+            (SurfaceProperties::Material(a), SurfaceProperties::Material(b)) => a == b,
+            (SurfaceProperties::Light(a), SurfaceProperties::Light(b)) => a == b,
+            _ => false
+        },
+        _ => false
+    };
+    hits.iter().all(|hit| same(hit, &hits[0]))
+}
@@ -0,0 +1,310 @@
+use math::vector_traits::*;
+use math::Vec3f;
+use std::f32::consts::PI;
+
+// a quad with more than this fraction of the flux is split for the next iteration
+const QUAD_SUBDIVISION: f32 = 0.01;
+const MAX_QUAD_DEPTH: usize = 20;
+// a spatial leaf which got more samples in an iteration is split in two
+const SPATIAL_SAMPLES: usize = 4000;
+const MAX_SPATIAL_DEPTH: usize = 24;
+
+// Quadtree over the directions, in cylindrical coordinates: (cos theta + 1) / 2 and phi / 2pi,
+// which keep the solid angle, so pdfs over the square are 4pi times the ones over the sphere.
+// Every node keeps the flux of its 4 children, child c is (c & 1, c >> 1) of the node's square
+#[derive(Debug, Clone)]
+pub struct DTree {
+    nodes: Vec<QuadNode>,
+    samples: usize,
+}
+
+#[derive(Debug, Clone)]
+struct QuadNode {
+    sums: [f32; 4],
+    children: [usize; 4], // 0 for leaves, the root is never a child
+}
+
+// Spatial binary tree over the scene's box, split in the middle with alternating axes, and
+// a directional quadtree in every leaf (Mueller et al. 2017, "Practical path guiding").
+// The sampling trees are what the previous training iteration learned, the building ones
+// gather the current iteration and already have the structure refined from it
+#[derive(Debug, Clone)]
+pub struct SdTree {
+    min: Vec3f,
+    size: Vec3f,
+    nodes: Vec<SpatialNode>,
+    sampling: Vec<DTree>,
+    building: Vec<DTree>,
+}
+
+#[derive(Debug, Clone)]
+struct SpatialNode {
+    children: [usize; 2], // 0 for leaves
+    leaf: usize, // index of the leaf's trees
+    depth: usize,
+}
+
+fn dir_to_square(dir: &Vec3f) -> (f32, f32) {
+    let phi = dir.y.atan2(dir.x);
+    let v = if phi < 0.0 { phi / (2.0 * PI) + 1.0 } else { phi / (2.0 * PI) };
+    (((dir.z.max(-1.0).min(1.0) + 1.0) * 0.5).min(0.99999994), v.min(0.99999994))
+}
+
+fn square_to_dir(p: (f32, f32)) -> Vec3f {
+    let cos_theta = 2.0 * p.0 - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * p.1;
+    Vec3f::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+fn quadrant(p: (f32, f32)) -> (usize, (f32, f32)) {
+    let (x, y) = (if p.0 < 0.5 { 0 } else { 1 }, if p.1 < 0.5 { 0 } else { 1 });
+    (x + 2 * y, (p.0 * 2.0 - x as f32, p.1 * 2.0 - y as f32))
+}
+
+impl QuadNode {
+    fn leaf() -> QuadNode {
+        QuadNode { sums: [0.0; 4], children: [0; 4] }
+    }
+
+    fn total(&self) -> f32 {
+        self.sums.iter().fold(0.0, |sum, s| sum + s)
+    }
+}
+
+impl DTree {
+    pub fn new() -> DTree {
+        DTree { nodes: vec![QuadNode::leaf()], samples: 0 }
+    }
+
+    pub fn flux(&self) -> f32 {
+        self.nodes[0].total()
+    }
+
+    // `value` is the radiance from `dir` divided by the pdf it was sampled with
+    pub fn record(&mut self, dir: &Vec3f, value: f32) {
+        let mut p = dir_to_square(dir);
+        let mut node = 0;
+        loop {
+            let (child, child_p) = quadrant(p);
+            self.nodes[node].sums[child] += value;
+            node = self.nodes[node].children[child];
+            if node == 0 {
+                break;
+            }
+            p = child_p;
+        }
+        self.samples += 1;
+    }
+
+    // solid angle pdf, uniform before anything was recorded
+    pub fn pdf(&self, dir: &Vec3f) -> f32 {
+        let mut p = dir_to_square(dir);
+        let mut density = 1.0;
+        let mut node = 0;
+        loop {
+            let total = self.nodes[node].total();
+            if total <= 0.0 {
+                break;
+            }
+            let (child, child_p) = quadrant(p);
+            density *= 4.0 * self.nodes[node].sums[child] / total;
+            node = self.nodes[node].children[child];
+            if node == 0 {
+                break;
+            }
+            p = child_p;
+        }
+        density / (4.0 * PI)
+    }
+
+    // quads are picked by their flux, the point is uniform in the last one
+    pub fn sample(&self, rnd: (f32, f32)) -> Vec3f {
+        let (mut origin, mut size) = ((0.0, 0.0), 1.0);
+        let mut rnd = rnd;
+        let mut node = 0;
+        loop {
+            let sums = self.nodes[node].sums;
+            let total = sums[0] + sums[1] + sums[2] + sums[3];
+            if total <= 0.0 {
+                break;
+            }
+            // the column, then the row in it
+            let right = (sums[1] + sums[3]) / total;
+            let x = if rnd.0 < 1.0 - right { 0 } else { 1 };
+            rnd.0 = if x == 0 { rnd.0 / (1.0 - right) } else { (rnd.0 - (1.0 - right)) / right };
+            let column = sums[x] + sums[x + 2];
+            let bottom = if column > 0.0 { sums[x + 2] / column } else { 0.5 };
+            let y = if rnd.1 < 1.0 - bottom { 0 } else { 1 };
+            rnd.1 = if y == 0 { rnd.1 / (1.0 - bottom) } else { (rnd.1 - (1.0 - bottom)) / bottom };
+            rnd = (rnd.0.max(0.0).min(0.99999994), rnd.1.max(0.0).min(0.99999994));
+
+            size *= 0.5;
+            origin = (origin.0 + x as f32 * size, origin.1 + y as f32 * size);
+            node = self.nodes[node].children[x + 2 * y];
+            if node == 0 {
+                break;
+            }
+        }
+        square_to_dir((origin.0 + rnd.0 * size, origin.1 + rnd.1 * size))
+    }
+
+    // empty tree, its quads are split where this one has much of the flux
+    fn refined(&self) -> DTree {
+        let mut tree = DTree::new();
+        let total = self.flux();
+        if total > 0.0 {
+            let sums = self.nodes[0].sums;
+            self.refine_node(Some(0), sums, 0, 1, total, &mut tree);
+        }
+        tree
+    }
+
+    // `node` of this tree (None below its leaves, where the flux is split evenly) maps to `new_node`
+    fn refine_node(&self, node: Option<usize>, sums: [f32; 4], new_node: usize, depth: usize, total: f32, tree: &mut DTree) {
+        if depth >= MAX_QUAD_DEPTH {
+            return;
+        }
+        for child in 0..4 {
+            if sums[child] / total <= QUAD_SUBDIVISION {
+                continue;
+            }
+            let new_child = tree.nodes.len();
+            tree.nodes.push(QuadNode::leaf());
+            tree.nodes[new_node].children[child] = new_child;
+            let old_child = node.map_or(0, |node| self.nodes[node].children[child]);
+            if old_child != 0 {
+                let child_sums = self.nodes[old_child].sums;
+                self.refine_node(Some(old_child), child_sums, new_child, depth + 1, total, tree);
+            } else {
+                let quarter = sums[child] * 0.25;
+                self.refine_node(None, [quarter; 4], new_child, depth + 1, total, tree);
+            }
+        }
+    }
+}
+
+impl SdTree {
+    pub fn new(min: Vec3f, max: Vec3f) -> SdTree {
+        SdTree {
+            min: min,
+            size: (max - min).zip(&Vec3f::new(1e-6, 1e-6, 1e-6), f32::max),
+            nodes: vec![SpatialNode { children: [0; 2], leaf: 0, depth: 0 }],
+            sampling: vec![DTree::new()],
+            building: vec![DTree::new()],
+        }
+    }
+
+    fn leaf(&self, pos: &Vec3f) -> usize {
+        let mut p = (*pos - self.min) / self.size;
+        let mut node = 0;
+        while self.nodes[node].children[0] != 0 {
+            let axis = self.nodes[node].depth % 3;
+            let upper = p[axis] >= 0.5;
+            p[axis] = if upper { p[axis] * 2.0 - 1.0 } else { p[axis] * 2.0 };
+            node = self.nodes[node].children[upper as usize];
+        }
+        self.nodes[node].leaf
+    }
+
+    // the directions learned around `pos`, None if nothing was learned there yet
+    pub fn guide(&self, pos: &Vec3f) -> Option<&DTree> {
+        let tree = &self.sampling[self.leaf(pos)];
+        if tree.flux() > 0.0 { Some(tree) } else { None }
+    }
+
+    pub fn record(&mut self, pos: &Vec3f, dir: &Vec3f, value: f32) {
+        let leaf = self.leaf(pos);
+        self.building[leaf].record(dir, value);
+    }
+
+    // Ends a training iteration: what was gathered is sampled from now on, leaves which got
+    // many samples are split and the building trees are refined where the flux is
+    pub fn refine(&mut self) {
+        for node in 0..self.nodes.len() {
+            let (leaf, depth) = (self.nodes[node].leaf, self.nodes[node].depth);
+            if self.nodes[node].children[0] != 0 || depth >= MAX_SPATIAL_DEPTH || self.building[leaf].samples <= SPATIAL_SAMPLES {
+                continue;
+            }
+            // both halves start with what the leaf learned
+            let mut half = self.building[leaf].clone();
+            half.samples /= 2;
+            self.building[leaf] = half.clone();
+            self.sampling.push(self.sampling[leaf].clone());
+            self.building.push(half);
+            let first = self.nodes.len();
+            self.nodes.push(SpatialNode { children: [0; 2], leaf: leaf, depth: depth + 1 });
+            self.nodes.push(SpatialNode { children: [0; 2], leaf: self.building.len() - 1, depth: depth + 1 });
+            self.nodes[node].children = [first, first + 1];
+        }
+        for (sampling, building) in self.sampling.iter_mut().zip(self.building.iter_mut()) {
+            let refined = building.refined();
+            *sampling = ::std::mem::replace(building, refined);
+        }
+    }
+
+    pub fn leaves_nb(&self) -> usize {
+        self.building.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DTree, SdTree, square_to_dir};
+    use math::vector_traits::*;
+    use math::Vec3f;
+    use rand::Rng;
+    use std::f32::consts::PI;
+    use utility::{seeded_rng, uniform_sphere_sample};
+
+    #[test]
+    fn learned_pdf_is_normalized_and_sampled() {
+        let light = Vec3f::new(0.3, 0.5, 0.8).normalize();
+        let mut rng = seeded_rng(2, 0);
+        let mut tree = DTree::new();
+        // a few rounds of training toward a bright spot over a dim sky
+        for _ in 0..4 {
+            let mut building = tree.refined();
+            for _ in 0..20000 {
+                let dir = uniform_sphere_sample((rng.next_f32(), rng.next_f32()));
+                let radiance = if dir.dot(&light) > 0.95 { 100.0 } else { 1.0 };
+                building.record(&dir, radiance * 4.0 * PI);
+            }
+            tree = building;
+        }
+        assert!(tree.pdf(&-light) < 0.5 / (4.0 * PI));
+
+        // the square keeps the solid angle, so a grid over it integrates the pdf
+        let grid = 512;
+        let integral = (0..grid * grid).fold(0.0, |sum, idx| {
+            let p = (((idx % grid) as f32 + 0.5) / grid as f32, ((idx / grid) as f32 + 0.5) / grid as f32);
+            sum + tree.pdf(&square_to_dir(p)) * 4.0 * PI
+        }) / (grid * grid) as f32;
+        assert!((integral - 1.0).abs() < 1e-2, "{}", integral);
+
+        let samples = 20000;
+        let mut toward_light = 0;
+        for _ in 0..samples {
+            let sampled = tree.sample((rng.next_f32(), rng.next_f32()));
+            assert!(tree.pdf(&sampled) > 0.0);
+            if sampled.dot(&light) > 0.95 {
+                toward_light += 1;
+            }
+        }
+        // the spot is 2.5% of the sphere and has 70% of the flux
+        assert!(toward_light as f32 / samples as f32 > 0.6, "{}", toward_light);
+    }
+
+    #[test]
+    fn busy_leaves_are_split() {
+        let mut tree = SdTree::new(Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 1.0, 1.0));
+        let mut rng = seeded_rng(3, 0);
+        for _ in 0..10000 {
+            let pos = Vec3f::new(rng.next_f32(), rng.next_f32(), rng.next_f32());
+            tree.record(&pos, &Vec3f::new(0.0, 0.0, 1.0), 1.0);
+        }
+        tree.refine();
+        assert_eq!(tree.leaves_nb(), 2);
+        assert!(tree.guide(&Vec3f::new(0.1, 0.5, 0.5)).is_some() && tree.guide(&Vec3f::new(0.9, 0.5, 0.5)).is_some());
+    }
+}
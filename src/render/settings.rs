@@ -0,0 +1,295 @@
+use math::vector_traits::*;
+use math::Vec3f;
+use render::SamplerKind;
+use scene::Scene;
+use utility::luminance;
+
+// Knobs of the renderers which used to be constants in every one of them. The defaults are the
+// old constants, so `Render::new` renders as before, but for the roulette which starts after 3 bounces
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    pub max_depth: u32, // bounces after which a path tracer ends the path
+    pub min_depth: u32, // bounces before the russian roulette can end a path, see `survival_probability`
+    pub spp_per_iteration: usize, // samples a progressive render adds to every pixel at a time
+    pub light_clamp: f32, // brightest component of a light seen straight from the camera
+    pub seed: Option<u32>, // replaces the scene's
+    pub direct_clamp: Option<f32>, // brightest component of a sample of the light which scattered at most once
+    pub indirect_clamp: Option<f32>, // the same for the light which scattered more
+    pub sampler: SamplerKind, // placement of the samples of the screen loops
+    pub deterministic: bool, // see `with_deterministic`
+    pub bounce_splits: usize, // brdf samples the first vertex of a path goes on along, see `with_splitting`
+    pub light_splits: usize, // light samples taken at the first vertex
+    pub blue_sky: bool, // see `with_blue_sky`
+    pub ray_differentials: bool, // see `with_ray_differentials`
+}
+
+impl RenderSettings {
+    pub fn new() -> RenderSettings {
+        RenderSettings {
+            max_depth: 100,
+            min_depth: 3,
+            spp_per_iteration: 1,
+            light_clamp: 10.0,
+            seed: None,
+            direct_clamp: None,
+            indirect_clamp: None,
+            sampler: SamplerKind::Cmj,
+            deterministic: false,
+            bounce_splits: 1,
+            light_splits: 1,
+            blue_sky: true,
+            ray_differentials: false,
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: u32) -> RenderSettings {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_min_depth(mut self, min_depth: u32) -> RenderSettings {
+        self.min_depth = min_depth;
+        self
+    }
+
+    // Debug mode: no russian roulette, every path which doesn't leave the scene or hit a light goes on
+    // for exactly `depth` bounces. The paths don't depend on their throughput any more, so a change of
+    // an integrator shows up as an exact difference of two renders with the same seed, not as noise
+    pub fn with_fixed_depth(mut self, depth: u32) -> RenderSettings {
+        self.max_depth = depth;
+        self.min_depth = depth;
+        self
+    }
+
+    pub fn is_fixed_depth(&self) -> bool {
+        self.min_depth >= self.max_depth
+    }
+
+    pub fn with_spp_per_iteration(mut self, spp_per_iteration: usize) -> RenderSettings {
+        self.spp_per_iteration = spp_per_iteration.max(1);
+        self
+    }
+
+    pub fn with_light_clamp(mut self, light_clamp: f32) -> RenderSettings {
+        self.light_clamp = light_clamp;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> RenderSettings {
+        self.seed = Some(seed);
+        self
+    }
+
+    // Reproducible mode: every number of a camera path comes from the seed, the pixel, the iteration, the
+    // sample and its dimension alone (see `PixelSampler`), the watchdog doesn't drop the
+    // tiles which run too long, and what the caching renderers learn in an iteration is sorted before it's
+    // used. The same scene and settings then give the same image, bit for bit, on every run and any number
+    // of threads: for regression tests and for chasing a single noisy pixel
+    pub fn with_deterministic(mut self) -> RenderSettings {
+        self.deterministic = true;
+        self
+    }
+
+    // Splitting: the first vertex of a camera path takes `lights` light samples and goes on along `bounces`
+    // brdf samples, every one a path of its own, each weighted by the share of its kind. The first bounce
+    // usually carries most of the noise, so a few branches there are cheaper than as many camera samples.
+    // Only `CpuPtMis` splits, the other renderers ignore both counts
+    pub fn with_splitting(mut self, bounces: usize, lights: usize) -> RenderSettings {
+        self.bounce_splits = bounces.max(1);
+        self.light_splits = lights.max(1);
+        self
+    }
+
+    // Blue sky termination: a bounce missing the bounds of every object goes to the background light
+    // straight away, the geometry isn't traversed (or marched) to find nothing. The image stays the same,
+    // off is only for timing it
+    pub fn with_blue_sky(mut self, blue_sky: bool) -> RenderSettings {
+        self.blue_sky = blue_sky;
+        self
+    }
+
+    // Ray differentials: a path carries the width of the cone of directions it stands for, a pixel from
+    // the camera widened by every glossy or diffuse bounce by the width of the lobe it was sampled from,
+    // and the environment maps it hits or samples and the filtered normal maps (see `TextureSet::
+    // with_normal_filtering`) are prefiltered over that cone. A sharp HDR sun seen through a rough
+    // reflection is then a blur rather than a firefly now and then; slightly biased, the average stays
+    pub fn with_ray_differentials(mut self) -> RenderSettings {
+        self.ray_differentials = true;
+        self
+    }
+
+    pub fn with_direct_clamp(mut self, direct_clamp: f32) -> RenderSettings {
+        self.direct_clamp = Some(direct_clamp);
+        self
+    }
+
+    pub fn with_indirect_clamp(mut self, indirect_clamp: f32) -> RenderSettings {
+        self.indirect_clamp = Some(indirect_clamp);
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: SamplerKind) -> RenderSettings {
+        self.sampler = sampler;
+        self
+    }
+
+    // the scene ready to render: with the seed of the settings, if they have one, and its
+    // bounds and epsilons fitted to everything added to it
+    pub fn prepare_scene<S: Scene>(&self, mut scene: S) -> S {
+        if let Some(seed) = self.seed {
+            scene.set_seed(seed);
+        }
+        scene.commit_changes();
+        scene
+    }
+
+    // @TODO Remove this when HDR will be implemented
+    pub fn clamp_light(&self, radiance: Vec3f) -> Vec3f {
+        let max_comp = radiance.fold(f32::max);
+        if max_comp > self.light_clamp { radiance / max_comp * self.light_clamp } else { radiance }
+    }
+
+    // Firefly clamping of a contribution to a pixel, before it's added to the frame. `bounces` is the
+    // number of times the light scattered on the way to the camera: none or once is direct light, more
+    // is indirect. The brightest component is scaled down to the clamp, the hue stays
+    pub fn clamp_contribution(&self, radiance: Vec3f, bounces: u32) -> Vec3f {
+        let clamp = if bounces <= 1 { self.direct_clamp } else { self.indirect_clamp };
+        match clamp {
+            Some(clamp) => {
+                let max_comp = radiance.fold(f32::max);
+                if max_comp > clamp { radiance * (clamp / max_comp) } else { radiance }
+            },
+            None => radiance
+        }
+    }
+
+    // Probability a path goes on after `path_length` bounces with the throughput `weight`: never past the
+    // max depth, always before the min depth, and in between the russian roulette keeps it with the
+    // luminance of the throughput (at most 1), so the dark paths end early and the bright ones don't.
+    // A path which goes on divides its throughput by it, which keeps the estimate unbiased
+    pub fn survival_probability(&self, path_length: u32, weight: &Vec3f) -> f32 {
+        if path_length >= self.max_depth {
+            0.0
+        } else if path_length < self.min_depth {
+            1.0
+        } else {
+            luminance(weight).min(1.0).max(0.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RenderSettings;
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use render::{CpuPtMis, Render};
+    use scene::{DefaultScene, Scene};
+
+    fn scene() -> DefaultScene<GeometryList> {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(1.0, 4.0, -3.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        scene
+    }
+
+    #[test]
+    fn settings_reach_the_renderer() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 0.5, -2.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let direct = CpuPtMis::new_with_settings(cam.clone(), scene(), RenderSettings::new().with_max_depth(0).with_seed(5));
+        assert_eq!(direct.get_scene().get_seed(), 5);
+        let mut direct_frame = cam.build_rgb_framebuffer();
+        direct.iterate(1, 1, &mut direct_frame);
+        let mut full_frame = cam.build_rgb_framebuffer();
+        CpuPtMis::new_with_settings(cam, scene(), RenderSettings::new().with_min_depth(4).with_seed(5))
+            .iterate(1, 1, &mut full_frame);
+
+        // one path per pixel from the same numbers: the first vertex gets the same direct light,
+        // the longer paths add the bounces, which the sphere filling the view takes from the lit floor
+        let pixels = direct_frame.as_slice().iter().zip(full_frame.as_slice().iter());
+        assert!(pixels.clone().all(|(direct, full)| full.x >= direct.x));
+        assert!(pixels.clone().any(|(direct, full)| full.x > direct.x));
+        assert!(direct_frame.as_slice().iter().any(|pix| pix.x > 0.0));
+    }
+
+    #[test]
+    fn clamps_split_direct_and_indirect_light() {
+        let settings = RenderSettings::new().with_direct_clamp(2.0);
+        assert_eq!(settings.clamp_contribution(Vec3f::new(4.0, 2.0, 1.0), 1), Vec3f::new(2.0, 1.0, 0.5));
+        assert_eq!(settings.clamp_contribution(Vec3f::new(4.0, 2.0, 1.0), 2), Vec3f::new(4.0, 2.0, 1.0));
+
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 0.5, -2.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        let render = |settings: RenderSettings| {
+            let mut frame = cam.build_rgb_framebuffer();
+            CpuPtMis::new_with_settings(cam.clone(), scene(), settings.with_seed(5)).iterate(1, 1, &mut frame);
+            frame
+        };
+        // no indirect light left, and the point light can't be hit by the bounces: the frame is the
+        // direct light of the first vertices
+        let clamped = render(RenderSettings::new().with_min_depth(4).with_indirect_clamp(0.0));
+        let direct = render(RenderSettings::new().with_max_depth(0));
+        assert_eq!(clamped.as_slice(), direct.as_slice());
+        let full = render(RenderSettings::new().with_min_depth(4));
+        assert!(full.as_slice() != clamped.as_slice());
+    }
+
+    #[test]
+    fn deterministic_renders_repeat() {
+        // `warm_up` iterations before leave the threads in another state
+        let render = |warm_up: usize| {
+            let cam = CameraBuilder::<PerspectiveCamera>::new()
+                .with_view_size(Vec2u::new(8, 8))
+                .with_pos(Vec3f::new(0.0, 4.0, -6.0))
+                .with_look_at(Vec3f::new(0.0, -4.0, 6.0))
+                .build();
+            let ren = CpuPtMis::new_with_settings(cam.clone(), scene(), RenderSettings::new().with_deterministic());
+            for iter_nb in 0..warm_up {
+                ren.iterate(iter_nb, 3, &mut cam.build_rgb_framebuffer());
+            }
+            let mut frame = cam.build_rgb_framebuffer();
+            ren.iterate(7, 4, &mut frame);
+            frame.as_slice().to_vec()
+        };
+        let frame = render(0);
+        assert!(frame.iter().any(|pix| pix.x > 0.0));
+        assert_eq!(::std::thread::spawn(move || render(2)).join().unwrap(), frame);
+    }
+
+    #[test]
+    fn fixed_depth_ignores_the_roulette() {
+        let settings = RenderSettings::new().with_fixed_depth(3);
+        assert!(settings.is_fixed_depth());
+        assert!(!RenderSettings::new().is_fixed_depth());
+        let (black, white) = (Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 1.0, 1.0));
+        assert!((0..3).all(|path_length| settings.survival_probability(path_length, &black) == 1.0));
+        assert_eq!(settings.survival_probability(3, &white), 0.0);
+    }
+
+    #[test]
+    fn roulette_keeps_the_mean() {
+        let settings = RenderSettings::new().with_min_depth(2);
+        let weight = Vec3f::new(0.1, 0.3, 0.2);
+        assert_eq!(settings.survival_probability(1, &weight), 1.0);
+        let survival = settings.survival_probability(2, &weight);
+        assert!((survival - 0.2503).abs() < 1e-3, "{}", survival);
+        assert_eq!(settings.survival_probability(2, &Vec3f::new(4.0, 4.0, 4.0)), 1.0);
+        // the paths which go on carry the weight of the ones which ended
+        let n = 10000;
+        let sum = (0..n).map(|i| (i as f32 + 0.5) / n as f32)
+            .filter(|&rnd| rnd < survival)
+            .fold(0.0, |sum, _| sum + weight.y / survival);
+        assert!((sum / n as f32 - weight.y).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,102 @@
+use render::stratified::STRATIFIED_DIMS;
+
+// dimensions with direction numbers: the two of the jitter, then the stratified path numbers
+pub const SOBOL_DIMS: usize = 2 + STRATIFIED_DIMS;
+
+// Primitive polynomials (degree, coefficients) and the initial direction numbers of the dimensions
+// after the first one, from Joe and Kuo's new-joe-kuo-6.21201 table
+const POLYNOMIALS: [(usize, u32, [u32; 5]); SOBOL_DIMS - 1] = [
+    (1, 0, [1, 0, 0, 0, 0]),
+    (2, 1, [1, 3, 0, 0, 0]),
+    (3, 1, [1, 3, 1, 0, 0]),
+    (3, 2, [1, 1, 1, 0, 0]),
+    (4, 1, [1, 1, 3, 3, 0]),
+    (4, 4, [1, 3, 5, 13, 0]),
+    (5, 2, [1, 1, 5, 5, 17]),
+    (5, 4, [1, 1, 5, 5, 5]),
+    (5, 7, [1, 1, 7, 11, 19]),
+];
+
+// The Sobol sequence: every dimension of the first 2^m points puts exactly one point in each 1 / 2^m
+// interval, and the first two dimensions one in each cell of any 2^a x 2^b grid with a + b = m, so a
+// progressive render keeps filling the gaps the earlier samples left instead of landing at random
+pub struct Sobol {
+    directions: [[u32; 32]; SOBOL_DIMS],
+}
+
+// the direction numbers are the same for every render and every seed, the compiler builds them
+pub static SOBOL: Sobol = Sobol::new();
+
+impl Sobol {
+    pub const fn new() -> Sobol {
+        let mut directions = [[0; 32]; SOBOL_DIMS];
+        // the first dimension is the van der Corput sequence
+        let mut bit = 0;
+        while bit < 32 {
+            directions[0][bit] = 1 << (31 - bit);
+            bit += 1;
+        }
+        let mut dim = 0;
+        while dim < SOBOL_DIMS - 1 {
+            let (degree, coefficients, initial) = POLYNOMIALS[dim];
+            let v = &mut directions[dim + 1];
+            let mut bit = 0;
+            while bit < degree {
+                v[bit] = initial[bit] << (31 - bit);
+                bit += 1;
+            }
+            while bit < 32 {
+                v[bit] = v[bit - degree] ^ (v[bit - degree] >> degree);
+                let mut k = 1;
+                while k < degree {
+                    if (coefficients >> (degree - 1 - k)) & 1 == 1 {
+                        v[bit] ^= v[bit - k];
+                    }
+                    k += 1;
+                }
+                bit += 1;
+            }
+            dim += 1;
+        }
+        Sobol { directions: directions }
+    }
+
+    // the point `index` in the dimension `dim`, as a fraction of 2^32
+    pub fn sample(&self, index: u32, dim: usize) -> u32 {
+        let mut value = 0;
+        let mut index = index;
+        let mut bit = 0;
+        while index != 0 {
+            if index & 1 == 1 {
+                value ^= self.directions[dim][bit];
+            }
+            index >>= 1;
+            bit += 1;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sobol, SOBOL_DIMS};
+
+    #[test]
+    fn points_fill_the_strata() {
+        let sobol = Sobol::new();
+        let n = 64;
+        for dim in 0..SOBOL_DIMS {
+            let mut strata = (0..n).map(|index| (sobol.sample(index, dim) >> 26) as u32).collect::<Vec<_>>();
+            strata.sort();
+            assert_eq!(strata, (0..n).collect::<Vec<_>>(), "dimension {}", dim);
+        }
+        // 8x8 and 4x16 grids of the first two dimensions, one point in every cell
+        for &(x_bits, y_bits) in [(3, 3), (2, 4)].iter() {
+            let mut cells = (0..n).map(|index| {
+                (sobol.sample(index, 0) >> (32 - x_bits)) << y_bits | sobol.sample(index, 1) >> (32 - y_bits)
+            }).collect::<Vec<_>>();
+            cells.sort();
+            assert_eq!(cells, (0..n).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,636 @@
+use math::Vec2f;
+use rand::Rng;
+use render::blue_noise::{BlueNoise, BLUE_NOISE_SIZE};
+use render::cmj::cmj;
+use render::halton::{Halton, HALTON_DIMS};
+use render::sobol::{SOBOL, SOBOL_DIMS};
+use std::sync::Arc;
+use utility::{hash_u64, seeded_rng, Pcg32, Sampler};
+
+// numbers of every path stratified across the samples of a pixel: the first vertex's light pick,
+// light sample, BRDF sample and roulette in the path tracers, the rest of the path is random
+pub const STRATIFIED_DIMS: usize = 8;
+// rng streams of the per pixel scrambling of the sobol and halton points, next to the pixel ones
+// ((iter_nb << 32) | pix_nb)
+const SCRAMBLE_STREAMS: u64 = 1 << 55;
+// rng stream of the per dimension shifts of the blue noise tile
+const BLUE_NOISE_STREAMS: u64 = 1 << 53;
+// streams of the path numbers past the stratified ones, next to the jitter streams
+const SAMPLE_STREAMS: u64 = 1 << 63;
+
+// the tile is the same for every render, it's built once per thread
+thread_local!(static BLUE_NOISE: BlueNoise = BlueNoise::new(0));
+
+// how the samples of a pixel are placed, see `PixelSampler`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent, // every number on its own
+    Stratified, // the samples of an iteration share out the strata
+    Sobol, // the samples of all the iterations are the points of one scrambled sobol sequence
+    Halton, // the same with the halton sequence
+    BlueNoise, // the sobol sequence, rotated per pixel by a blue noise tile
+    Cmj, // correlated multi-jittered patterns, the default
+}
+
+impl SamplerKind {
+    pub fn new_sampler(self, seed: u32, deterministic: bool) -> Box<PixelSampler> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed, deterministic)),
+            SamplerKind::Stratified  => Box::new(StratifiedSampler::new(seed, deterministic)),
+            SamplerKind::Sobol       => Box::new(SobolSampler::new(seed, deterministic)),
+            SamplerKind::Halton      => Box::new(HaltonSampler::new(seed, deterministic)),
+            SamplerKind::BlueNoise   => Box::new(BlueNoiseSampler::new(seed, deterministic)),
+            SamplerKind::Cmj         => Box::new(CmjSampler::new(seed, deterministic)),
+        }
+    }
+}
+
+// The samples a pixel gets in one iteration, and the numbers of their paths. Instead of independent numbers,
+// the placing samplers give the jitters and the first `STRATIFIED_DIMS` numbers a path draws better spread
+// values over the `spp` samples, the rest of the path draws from the pixel's own numbers (see `PathNumbers`).
+// The screen loops make one per tile (see `SamplerKind::new_sampler`) and start it for every pixel
+pub trait PixelSampler: Sampler {
+    // `spp` samples of the pixel for `iter_nb`, `rng` is the jitter stream of the pixel
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, pix_pos: (usize, usize), rng: &mut Pcg32, spp: usize);
+    // the jitter of the sample `i`, the numbers drawn next are the ones of this sample from the first dimension
+    fn start_sample(&mut self, i: usize) -> Vec2f;
+}
+
+impl Sampler for Box<PixelSampler> {
+    fn start_dimension(&mut self, dim: usize) {
+        (**self).start_dimension(dim)
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        (**self).next_bits()
+    }
+}
+
+impl PixelSampler for Box<PixelSampler> {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, pix_pos: (usize, usize), rng: &mut Pcg32, spp: usize) {
+        (**self).start_pixel(iter_nb, pix_nb, pix_pos, rng, spp)
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        (**self).start_sample(i)
+    }
+}
+
+// The numbers of the paths of a pixel no sampler places: the pixel's own stream, so a pixel draws the same
+// numbers whenever it's rendered for the same iteration, on any thread. In the deterministic mode (see
+// `RenderSettings::with_deterministic`) they are hashes of the seed, the pixel, the sample and the dimension
+// instead: a number depends on nothing else, not on the numbers drawn before it, so a single number of a
+// single sample can be tracked down
+struct PathNumbers {
+    seed: u32,
+    deterministic: bool,
+    rng: Pcg32,
+    key: u64, // of the hashed numbers of the pixel
+    sample_nb: usize,
+    dim: usize,
+}
+
+impl PathNumbers {
+    fn new(seed: u32, deterministic: bool) -> PathNumbers {
+        PathNumbers {
+            seed: seed,
+            deterministic: deterministic,
+            rng: seeded_rng(seed, SAMPLE_STREAMS),
+            key: 0,
+            sample_nb: 0,
+            dim: 0,
+        }
+    }
+
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize) {
+        let stream = SAMPLE_STREAMS | ((iter_nb as u64) << 32) | pix_nb as u64;
+        if self.deterministic {
+            self.key = hash_u64(hash_u64(self.seed as u64) ^ stream);
+        } else {
+            self.rng = seeded_rng(self.seed, stream);
+        }
+        self.sample_nb = 0;
+        self.dim = 0;
+    }
+
+    fn start_sample(&mut self, i: usize) {
+        self.sample_nb = i;
+        self.dim = 0;
+    }
+
+    // the dimension of the number drawn now, the next one is drawn from the dimension after it
+    fn next_dim(&mut self) -> usize {
+        self.dim += 1;
+        self.dim - 1
+    }
+
+    fn unplaced(&mut self, dim: usize) -> u32 {
+        if self.deterministic {
+            (hash_u64(hash_u64(self.key ^ self.sample_nb as u64) ^ dim as u64) >> 32) as u32
+        } else {
+            self.rng.next_u32()
+        }
+    }
+}
+
+// every number on its own, the jitters from the pixel's jitter stream
+pub struct IndependentSampler {
+    numbers: PathNumbers,
+    jitters: Vec<Vec2f>,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u32, deterministic: bool) -> IndependentSampler {
+        IndependentSampler { numbers: PathNumbers::new(seed, deterministic), jitters: Vec::new() }
+    }
+}
+
+impl PixelSampler for IndependentSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, _pix_pos: (usize, usize), rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        self.jitters = (0..spp).map(|_| Vec2f::new(rng.next_f32(), rng.next_f32())).collect();
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        self.jitters[i]
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        self.numbers.unplaced(dim)
+    }
+}
+
+// The `spp` samples share out the strata: their jitters are a jittered grid over the pixel (or n-rooks if
+// `spp` isn't a square), and each of the placed numbers falls in a different `1 / spp` stratum for every
+// sample, the strata shuffled per dimension (latin hypercube). A single sample is as the independent one,
+// there's nothing to stratify
+pub struct StratifiedSampler {
+    numbers: PathNumbers,
+    jitters: Vec<Vec2f>,
+    strata: Vec<u32>, // STRATIFIED_DIMS per sample
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u32, deterministic: bool) -> StratifiedSampler {
+        StratifiedSampler { numbers: PathNumbers::new(seed, deterministic), jitters: Vec::new(), strata: Vec::new() }
+    }
+}
+
+impl PixelSampler for StratifiedSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, _pix_pos: (usize, usize), rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        self.strata.clear();
+        if spp <= 1 {
+            self.jitters = (0..spp).map(|_| Vec2f::new(rng.next_f32(), rng.next_f32())).collect();
+            return;
+        }
+        let side = (spp as f32).sqrt() as usize;
+        self.jitters = if side * side == spp {
+            let mut cells = (0..spp).collect::<Vec<_>>();
+            rng.shuffle(&mut cells);
+            cells.iter().map(|&cell| {
+                let (x, y) = ((cell % side) as f32, (cell / side) as f32);
+                Vec2f::new((x + rng.next_f32()) / side as f32, (y + rng.next_f32()) / side as f32)
+            }).collect()
+        } else {
+            let (xs, ys) = (stratified_f32(rng, spp), stratified_f32(rng, spp));
+            xs.into_iter().zip(ys).map(|(x, y)| Vec2f::new(x, y)).collect()
+        };
+
+        self.strata.resize(spp * STRATIFIED_DIMS, 0);
+        for dim in 0..STRATIFIED_DIMS {
+            let mut order = (0..spp as u64).collect::<Vec<_>>();
+            rng.shuffle(&mut order);
+            for (sample, &stratum) in order.iter().enumerate() {
+                // exactly in [stratum, stratum + 1) / spp of the u32 range
+                self.strata[sample * STRATIFIED_DIMS + dim] = (((stratum << 32) + rng.next_u32() as u64) / spp as u64) as u32;
+            }
+        }
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        self.jitters[i]
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        if dim < STRATIFIED_DIMS && !self.strata.is_empty() {
+            self.strata[self.numbers.sample_nb * STRATIFIED_DIMS + dim]
+        } else {
+            self.numbers.unplaced(dim)
+        }
+    }
+}
+
+// The jitter and every pair of the placed numbers get a correlated multi-jittered pattern each (see `cmj`),
+// stratified in 2D and in 1D as well. The samples are made on their own, nothing is kept per pixel but the
+// patterns
+pub struct CmjSampler {
+    numbers: PathNumbers,
+    spp: u32,
+    jitter_pattern: u32,
+    patterns: [u32; STRATIFIED_DIMS / 2],
+}
+
+impl CmjSampler {
+    pub fn new(seed: u32, deterministic: bool) -> CmjSampler {
+        CmjSampler { numbers: PathNumbers::new(seed, deterministic), spp: 0, jitter_pattern: 0, patterns: [0; STRATIFIED_DIMS / 2] }
+    }
+}
+
+impl PixelSampler for CmjSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, _pix_pos: (usize, usize), rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        self.spp = spp as u32;
+        self.jitter_pattern = rng.next_u32();
+        for pattern in self.patterns.iter_mut() {
+            *pattern = rng.next_u32();
+        }
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        let (x, y) = cmj(i as u32, self.spp, self.jitter_pattern);
+        Vec2f::new(x, y)
+    }
+}
+
+impl Sampler for CmjSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        if dim < STRATIFIED_DIMS {
+            let (a, b) = cmj(self.numbers.sample_nb as u32, self.spp, self.patterns[dim / 2]);
+            to_u32(if dim % 2 == 0 { a } else { b } as f64)
+        } else {
+            self.numbers.unplaced(dim)
+        }
+    }
+}
+
+// The placed numbers of the samples are the points of the sobol sequence from `iter_nb * spp` on, so a
+// progressive render with a constant `spp` continues it. The points are xor scrambled per pixel, neighbours
+// don't repeat the pattern
+pub struct SobolSampler {
+    numbers: PathNumbers,
+    scramble: [u32; SOBOL_DIMS],
+    first: usize, // index of the point of the first sample
+}
+
+impl SobolSampler {
+    pub fn new(seed: u32, deterministic: bool) -> SobolSampler {
+        SobolSampler { numbers: PathNumbers::new(seed, deterministic), scramble: [0; SOBOL_DIMS], first: 0 }
+    }
+
+    fn point(&self, dim: usize) -> u32 {
+        SOBOL.sample((self.first + self.numbers.sample_nb) as u32, dim) ^ self.scramble[dim]
+    }
+}
+
+impl PixelSampler for SobolSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, _pix_pos: (usize, usize), _rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        let mut scramble_rng = seeded_rng(self.numbers.seed, SCRAMBLE_STREAMS | pix_nb as u64);
+        for scramble in self.scramble.iter_mut() {
+            *scramble = scramble_rng.next_u32();
+        }
+        self.first = iter_nb * spp;
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        Vec2f::new(to_f32(self.point(0)), to_f32(self.point(1)))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        if dim < STRATIFIED_DIMS { self.point(2 + dim) } else { self.numbers.unplaced(dim) }
+    }
+}
+
+// The same with the halton sequence, shifted per pixel by a random offset modulo one (Cranley-Patterson
+// rotation) instead of the scrambling
+pub struct HaltonSampler {
+    numbers: PathNumbers,
+    halton: Arc<Halton>,
+    rotation: [f64; HALTON_DIMS],
+    first: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u32, deterministic: bool) -> HaltonSampler {
+        HaltonSampler { numbers: PathNumbers::new(seed, deterministic), halton: Halton::shared(seed), rotation: [0.0; HALTON_DIMS], first: 0 }
+    }
+
+    fn point(&self, dim: usize) -> u32 {
+        to_u32((self.halton.sample((self.first + self.numbers.sample_nb) as u32, dim) + self.rotation[dim]).fract())
+    }
+}
+
+impl PixelSampler for HaltonSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, _pix_pos: (usize, usize), _rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        let mut rotation_rng = seeded_rng(self.numbers.seed, SCRAMBLE_STREAMS | pix_nb as u64);
+        for rotation in self.rotation.iter_mut() {
+            *rotation = rotation_rng.next_f64();
+        }
+        self.first = iter_nb * spp;
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        Vec2f::new(to_f32(self.point(0)), to_f32(self.point(1)))
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        if dim < STRATIFIED_DIMS { self.point(2 + dim) } else { self.numbers.unplaced(dim) }
+    }
+}
+
+// The sobol points again, but rotated by the values of a blue noise tile at the pixel, shifted over the
+// screen per dimension so the dimensions don't share them: the error of a pixel is then far from its
+// neighbours' and the low sample counts of the progressive preview look like fine grain instead of blotches
+pub struct BlueNoiseSampler {
+    numbers: PathNumbers,
+    shifts: [(usize, usize); SOBOL_DIMS], // of the tile, per dimension
+    rotation: [f64; SOBOL_DIMS],
+    first: usize,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u32, deterministic: bool) -> BlueNoiseSampler {
+        let mut shift_rng = seeded_rng(seed, BLUE_NOISE_STREAMS);
+        let mut shifts = [(0, 0); SOBOL_DIMS];
+        for shift in shifts.iter_mut() {
+            *shift = (shift_rng.gen_range(0, BLUE_NOISE_SIZE), shift_rng.gen_range(0, BLUE_NOISE_SIZE));
+        }
+        BlueNoiseSampler {
+            numbers: PathNumbers::new(seed, deterministic),
+            shifts: shifts,
+            rotation: [0.0; SOBOL_DIMS],
+            first: 0,
+        }
+    }
+
+    fn point(&self, dim: usize) -> u32 {
+        let point = SOBOL.sample((self.first + self.numbers.sample_nb) as u32, dim) as f64 / (1u64 << 32) as f64;
+        to_u32((point + self.rotation[dim]).fract())
+    }
+}
+
+impl PixelSampler for BlueNoiseSampler {
+    fn start_pixel(&mut self, iter_nb: usize, pix_nb: usize, (x, y): (usize, usize), _rng: &mut Pcg32, spp: usize) {
+        self.numbers.start_pixel(iter_nb, pix_nb);
+        let (shifts, rotation) = (&self.shifts, &mut self.rotation);
+        BLUE_NOISE.with(|noise| for (rotation, &(shift_x, shift_y)) in rotation.iter_mut().zip(shifts.iter()) {
+            *rotation = noise.value(x + shift_x, y + shift_y) as f64;
+        });
+        self.first = iter_nb * spp;
+    }
+
+    fn start_sample(&mut self, i: usize) -> Vec2f {
+        self.numbers.start_sample(i);
+        Vec2f::new(to_f32(self.point(0)), to_f32(self.point(1)))
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_dimension(&mut self, dim: usize) {
+        self.numbers.dim = dim;
+    }
+
+    fn next_bits(&mut self) -> u32 {
+        let dim = self.numbers.next_dim();
+        if dim < STRATIFIED_DIMS { self.point(2 + dim) } else { self.numbers.unplaced(dim) }
+    }
+}
+
+// from the high bits, as `Sampler::next_1d` does
+fn to_f32(value: u32) -> f32 {
+    (value >> 8) as f32 / (1 << 24) as f32
+}
+
+// a fraction of [0, 1) as a fraction of 2^32
+fn to_u32(value: f64) -> u32 {
+    ((value * (1u64 << 32) as f64) as u64).min(u32::max_value() as u64) as u32
+}
+
+// `n` numbers in [0, 1), one in every `1 / n` stratum, shuffled
+fn stratified_f32(rng: &mut Pcg32, n: usize) -> Vec<f32> {
+    let mut values = (0..n).map(|i| ((i as f32 + rng.next_f32()) / n as f32).min(1.0 - ::std::f32::EPSILON)).collect::<Vec<_>>();
+    rng.shuffle(&mut values);
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PixelSampler, SamplerKind, STRATIFIED_DIMS};
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::{Vec2u, Vec3f};
+    use rand::Rng;
+    use render::{CpuPtMis, Render, RenderSettings};
+    use scene::{DefaultScene, Scene};
+    use utility::{seeded_rng, Sampler};
+
+    // the strata of [0, 1) the values fall in, sorted
+    fn strata(values: &[f32]) -> Vec<usize> {
+        let mut strata = values.iter().map(|&value| (value * values.len() as f32) as usize).collect::<Vec<_>>();
+        strata.sort();
+        strata
+    }
+
+    #[test]
+    fn samples_cover_every_stratum_once() {
+        for &spp in [6, 16].iter() {
+            let mut sampler = SamplerKind::Stratified.new_sampler(3, false);
+            sampler.start_pixel(0, 0, (0, 0), &mut seeded_rng(3, 17), spp);
+            let mut dims = vec![Vec::new(); STRATIFIED_DIMS + 1];
+            let mut jitters = Vec::new();
+            for i in 0..spp {
+                jitters.push(sampler.start_sample(i));
+                for dim in dims.iter_mut() {
+                    dim.push(sampler.next_1d());
+                }
+            }
+            let all = (0..spp).collect::<Vec<_>>();
+            for dim in &dims[..STRATIFIED_DIMS] {
+                assert_eq!(strata(dim), all, "{} spp", spp);
+            }
+            if spp == 16 {
+                // past the stratified dimensions the numbers are the pixel's own
+                assert!(strata(&dims[STRATIFIED_DIMS]) != all);
+                // the jittered grid: one sample in every cell of the 4x4 grid
+                let mut cells = jitters.iter().map(|jitter| (jitter.y * 4.0) as usize * 4 + (jitter.x * 4.0) as usize).collect::<Vec<_>>();
+                cells.sort();
+                assert_eq!(cells, all);
+            } else {
+                assert_eq!(strata(&jitters.iter().map(|jitter| jitter.x).collect::<Vec<_>>()), all);
+                assert_eq!(strata(&jitters.iter().map(|jitter| jitter.y).collect::<Vec<_>>()), all);
+            }
+        }
+    }
+
+    #[test]
+    fn sampler_dimensions_are_the_strata() {
+        let mut sampler = SamplerKind::Stratified.new_sampler(5, false);
+        sampler.start_pixel(0, 1, (1, 0), &mut seeded_rng(5, 1), 4);
+        let strata = (0..STRATIFIED_DIMS).map(|dim| {
+            sampler.start_dimension(dim);
+            sampler.next_bits()
+        }).collect::<Vec<_>>();
+        sampler.start_sample(0);
+        sampler.start_dimension(2);
+        assert_eq!(sampler.next_bits(), strata[2]);
+        assert_eq!(sampler.next_bits(), strata[3]);
+        // past the strata the numbers are the pixel's stream
+        sampler.start_dimension(STRATIFIED_DIMS);
+        let mut rng = seeded_rng(5, (1 << 63) | 1);
+        assert_eq!(sampler.next_bits(), rng.next_u32());
+        assert_eq!(sampler.next_bits(), rng.next_u32());
+    }
+
+    #[test]
+    fn hashed_numbers_depend_on_the_dimension_only() {
+        let numbers = |sampler: &mut Box<PixelSampler>, sample_nb: usize| {
+            sampler.start_sample(sample_nb);
+            (0..6).map(|_| sampler.next_bits()).collect::<Vec<_>>()
+        };
+        let mut sampler = SamplerKind::Independent.new_sampler(5, true);
+        sampler.start_pixel(0, 1, (1, 0), &mut seeded_rng(5, 1), 4);
+        let first = numbers(&mut sampler, 3);
+        // whatever was drawn before
+        numbers(&mut sampler, 2);
+        sampler.start_sample(3);
+        sampler.start_dimension(4);
+        assert_eq!(sampler.next_bits(), first[4]);
+        // another sampler of the same pixel, the sample alone changes them
+        let mut other = SamplerKind::Independent.new_sampler(5, true);
+        other.start_pixel(0, 1, (1, 0), &mut seeded_rng(5, 1), 4);
+        assert_eq!(numbers(&mut other, 3), first);
+        assert!(numbers(&mut other, 2)[4] != first[4]);
+    }
+
+    // the first jitters of the pixel over `iters` iterations of `spp` samples
+    fn jitters_x(kind: SamplerKind, pix_nb: usize, pix_pos: (usize, usize), iters: usize, spp: usize) -> Vec<f32> {
+        let mut sampler = kind.new_sampler(3, false);
+        let mut rng = seeded_rng(3, 17);
+        (0..iters).flat_map(|iter_nb| {
+            sampler.start_pixel(iter_nb, pix_nb, pix_pos, &mut rng, spp);
+            (0..spp).map(|i| sampler.start_sample(i).x).collect::<Vec<_>>()
+        }).collect()
+    }
+
+    #[test]
+    fn sobol_samples_continue_over_iterations() {
+        assert_eq!(strata(&jitters_x(SamplerKind::Sobol, 5, (5, 0), 2, 4)), (0..8).collect::<Vec<_>>());
+        // another pixel is scrambled differently
+        assert!(jitters_x(SamplerKind::Sobol, 6, (6, 0), 1, 4)[0] != jitters_x(SamplerKind::Sobol, 5, (5, 0), 1, 4)[0]);
+
+        // the halton points are rotated, but by the same offset in every iteration: the gaps between them stay
+        let halton = jitters_x(SamplerKind::Halton, 5, (5, 0), 2, 4);
+        let offset = (halton[0] * 8.0).fract() / 8.0;
+        let shifted = halton.iter().map(|&x| (x - offset + 1.0).fract()).collect::<Vec<_>>();
+        assert_eq!(strata(&shifted), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sequence_samplers_place_the_path_numbers() {
+        for &kind in [SamplerKind::Sobol, SamplerKind::Cmj].iter() {
+            let mut sampler = kind.new_sampler(3, false);
+            sampler.start_pixel(1, 5, (5, 0), &mut seeded_rng(3, 17), 16);
+            let mut dims = vec![Vec::new(); STRATIFIED_DIMS];
+            for i in 0..16 {
+                sampler.start_sample(i);
+                for dim in dims.iter_mut() {
+                    dim.push(sampler.next_1d());
+                }
+            }
+            for dim in &dims {
+                assert_eq!(strata(dim), (0..16).collect::<Vec<_>>(), "{:?}", kind);
+            }
+            // a vertex which skips numbers leaves the later ones in place
+            sampler.start_sample(7);
+            sampler.start_dimension(5);
+            assert_eq!(sampler.next_1d(), dims[5][7], "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn blue_noise_pixels_differ_from_their_neighbours() {
+        // the first jitter of every pixel of a 32x32 screen, against the mean difference of white noise (a third)
+        let size = 32;
+        let jitters = (0..size * size).map(|pix_nb| {
+            jitters_x(SamplerKind::BlueNoise, pix_nb, (pix_nb % size, pix_nb / size), 1, 1)[0]
+        }).collect::<Vec<_>>();
+        let neighbour_difference = (0..size * size).filter(|pix_nb| pix_nb % size != size - 1)
+            .fold(0.0, |sum, pix_nb| sum + (jitters[pix_nb] - jitters[pix_nb + 1]).abs()) / ((size - 1) * size) as f32;
+        assert!(neighbour_difference > 0.36, "{}", neighbour_difference);
+
+        // and the sequence continues over the iterations as the sobol one
+        let jitters = jitters_x(SamplerKind::BlueNoise, 5, (5, 0), 2, 4);
+        let offset = (jitters[0] * 8.0).fract() / 8.0;
+        let shifted = jitters.iter().map(|&x| (x - offset + 1.0).fract()).collect::<Vec<_>>();
+        assert_eq!(strata(&shifted), (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn samplers_agree_on_the_mean() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(8, 8))
+            .with_pos(Vec3f::new(0.0, 4.0, -6.0))
+            .with_look_at(Vec3f::new(0.0, -4.0, 6.0))
+            .build();
+        let render = |sampler: SamplerKind| {
+            let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.1, 0.1, 0.1) });
+            scene.add_object(Sphere { center: Vec3f::new(0.0, -1000.0, 0.0), radius: 1000.0 }, WHITE_DIFFUSE).unwrap();
+            scene.add_object(Sphere { center: Vec3f::new(0.0, 0.5, 0.0), radius: 0.5 }, WHITE_DIFFUSE).unwrap();
+            scene.add_light(PointLight { position: Vec3f::new(1.0, 4.0, -3.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+            let mut frame = cam.build_rgb_framebuffer();
+            let ren = CpuPtMis::new_with_settings(cam.clone(), scene, RenderSettings::new().with_sampler(sampler));
+            for iter_nb in 0..4 {
+                ren.iterate(iter_nb, 16, &mut frame);
+            }
+            frame.as_slice().iter().fold(0.0, |sum, pix| sum + pix.x)
+        };
+        let independent = render(SamplerKind::Independent);
+        for &sampler in [SamplerKind::Stratified, SamplerKind::Sobol, SamplerKind::Halton, SamplerKind::BlueNoise,
+                              SamplerKind::Cmj].iter() {
+            let sum = render(sampler);
+            assert!((sum - independent).abs() < 0.02 * independent, "{:?}: {} instead of {}", sampler, sum, independent);
+        }
+    }
+}
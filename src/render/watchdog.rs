@@ -0,0 +1,164 @@
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// A path whose vertices keep landing on the origin of the previous segment is stuck,
+// usually between coplanar or overlapping surfaces
+const STUCK_SEGMENT_LENGTH: f32 = 1e-6;
+
+// a tile that ran out of time, kept until the main thread takes it (see `Watchdog::take_tile_aborts`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TileAbort {
+    pub tile_nb: usize,
+    pub elapsed: Duration,
+}
+
+// Keeps pathological tiles and paths from hanging the whole render:
+// a tile running over its time limit drops the rest of its pixels for the current iteration,
+// a path with too many vertices or stuck in place is terminated.
+// Everything aborted is counted, so it shows up as a number instead of a frozen window.
+// Nothing is printed from the worker threads, the aborted tiles are reported by the caller
+pub struct Watchdog {
+    tile_time_limit: Duration,
+    max_path_vertices: u32,
+    max_stuck_segments: u32,
+    aborted_tiles: AtomicUsize,
+    aborted_paths: AtomicUsize,
+    tile_aborts: Mutex<Vec<TileAbort>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            tile_time_limit: Duration::from_secs(30),
+            max_path_vertices: 1024,
+            max_stuck_segments: 8,
+            aborted_tiles: AtomicUsize::new(0),
+            aborted_paths: AtomicUsize::new(0),
+            tile_aborts: Mutex::new(Vec::new()),
+        }
+    }
+
+    // room for every vertex of a path ending after `max_depth` bounces, never less than the default
+    pub fn for_max_depth(max_depth: u32) -> Watchdog {
+        let watchdog = Watchdog::new();
+        let vertices = max_depth.saturating_add(1).max(watchdog.max_path_vertices);
+        watchdog.with_max_path_vertices(vertices)
+    }
+
+    pub fn with_tile_time_limit(mut self, limit: Duration) -> Watchdog {
+        self.tile_time_limit = limit;
+        self
+    }
+
+    pub fn with_max_path_vertices(mut self, vertices: u32) -> Watchdog {
+        self.max_path_vertices = vertices;
+        self
+    }
+
+    pub fn with_max_stuck_segments(mut self, segments: u32) -> Watchdog {
+        self.max_stuck_segments = segments;
+        self
+    }
+
+    // true once the tile has been rendering for too long; records the abort
+    pub fn tile_expired(&self, tile_nb: usize, started: &Instant) -> bool {
+        let elapsed = started.elapsed();
+        if elapsed <= self.tile_time_limit {
+            return false;
+        }
+        self.aborted_tiles.fetch_add(1, Ordering::Relaxed);
+        self.tile_aborts.lock().unwrap().push(TileAbort { tile_nb: tile_nb, elapsed: elapsed });
+        true
+    }
+
+    // the tiles aborted since the last call
+    pub fn take_tile_aborts(&self) -> Vec<TileAbort> {
+        mem::replace(&mut *self.tile_aborts.lock().unwrap(), Vec::new())
+    }
+
+    pub fn path_guard<'a>(&'a self) -> PathGuard<'a> {
+        PathGuard { watchdog: self, vertices: 0, stuck_segments: 0 }
+    }
+
+    pub fn aborted_tiles(&self) -> usize {
+        self.aborted_tiles.load(Ordering::Relaxed)
+    }
+
+    pub fn aborted_paths(&self) -> usize {
+        self.aborted_paths.load(Ordering::Relaxed)
+    }
+}
+
+pub struct PathGuard<'a> {
+    watchdog: &'a Watchdog,
+    vertices: u32,
+    stuck_segments: u32,
+}
+
+impl<'a> PathGuard<'a> {
+    // to be called for every found path vertex with the length of the segment leading to it,
+    // false means the path has to be terminated
+    pub fn add_vertex(&mut self, segment_length: f32) -> bool {
+        self.vertices += 1;
+        if segment_length < STUCK_SEGMENT_LENGTH {
+            self.stuck_segments += 1;
+        } else {
+            self.stuck_segments = 0;
+        }
+        let sane = self.vertices <= self.watchdog.max_path_vertices
+            && self.stuck_segments <= self.watchdog.max_stuck_segments
+            && segment_length.is_finite();
+        if !sane {
+            self.watchdog.aborted_paths.fetch_add(1, Ordering::Relaxed);
+        }
+        sane
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchdog;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn stuck_paths_are_aborted() {
+        let watchdog = Watchdog::new().with_max_stuck_segments(2);
+        let mut guard = watchdog.path_guard();
+        assert!(guard.add_vertex(1.0));
+        assert!(guard.add_vertex(0.0));
+        assert!(guard.add_vertex(0.0));
+        assert!(!guard.add_vertex(0.0));
+
+        let mut guard = watchdog.path_guard();
+        for _ in 0..10 {
+            assert!(guard.add_vertex(0.0) && guard.add_vertex(1.0));
+        }
+        assert_eq!(watchdog.aborted_paths(), 1);
+    }
+
+    #[test]
+    fn vertex_limit_follows_the_max_depth() {
+        let watchdog = Watchdog::for_max_depth(2000);
+        let mut guard = watchdog.path_guard();
+        assert!((0..2001).all(|_| guard.add_vertex(1.0)));
+        assert!(!guard.add_vertex(1.0));
+
+        let watchdog = Watchdog::for_max_depth(100);
+        let mut guard = watchdog.path_guard();
+        assert!((0..1024).all(|_| guard.add_vertex(1.0)));
+    }
+
+    #[test]
+    fn expired_tiles_are_taken_once() {
+        let watchdog = Watchdog::new().with_tile_time_limit(Duration::from_secs(0));
+        let started = Instant::now() - Duration::from_millis(10);
+        assert!(watchdog.tile_expired(3, &started));
+        let aborts = watchdog.take_tile_aborts();
+        assert_eq!(aborts.len(), 1);
+        assert_eq!(aborts[0].tile_nb, 3);
+        assert!(watchdog.take_tile_aborts().is_empty());
+        assert_eq!(watchdog.aborted_tiles(), 1);
+    }
+}
@@ -0,0 +1,234 @@
+use framebuffer::RgbFrameBuffer;
+use math::Vec2u;
+use render::{RenderSettings, TileAbort};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use telemetry::{duration_to_secs, RenderMetrics};
+use utility::luminance;
+
+// What a render did, written as JSON next to the images so a build farm can track the timings and
+// compare two runs without opening them. The hashes are FNV-1a over the bytes of the output files and
+// over the accumulated frame, equal hashes of two deterministic renders mean equal images
+#[derive(Debug, Clone)]
+pub struct RenderReport {
+    pub settings: RenderSettings,
+    pub resolution: Vec2u,
+    pub seed: u32,
+    pub iterations: usize,
+    pub spp: usize,
+    pub aborted_tiles: usize,
+    pub aborted_paths: usize,
+    pub tile_aborts: Vec<TileAbort>,
+    pub metrics: Option<RenderMetrics>,
+    stages: Vec<(String, Duration)>,
+    frame: Option<FrameStats>,
+    outputs: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FrameStats {
+    mean_luminance: f32,
+    max_luminance: f32,
+    invalid_pixels: usize, // with a NaN or an infinite component
+    hash: u64,
+}
+
+impl RenderReport {
+    pub fn new(settings: RenderSettings, resolution: Vec2u, seed: u32) -> RenderReport {
+        RenderReport {
+            settings: settings,
+            resolution: resolution,
+            seed: seed,
+            iterations: 0,
+            spp: 0,
+            aborted_tiles: 0,
+            aborted_paths: 0,
+            tile_aborts: Vec::new(),
+            metrics: None,
+            stages: Vec::new(),
+            frame: None,
+            outputs: Vec::new(),
+        }
+    }
+
+    pub fn add_stage(&mut self, name: &str, time: Duration) {
+        self.stages.push((name.to_string(), time));
+    }
+
+    // the stage `name` ran from `started` until now, returns now for the next stage
+    pub fn end_stage(&mut self, name: &str, started: Instant) -> Instant {
+        let now = Instant::now();
+        self.add_stage(name, now.duration_since(started));
+        now
+    }
+
+    // `frame` holds the sums of `spp` samples per pixel
+    pub fn set_frame(&mut self, frame: &RgbFrameBuffer, spp: usize) {
+        let k = 1.0 / spp.max(1) as f32;
+        let pixels = frame.as_slice();
+        let (mut sum, mut max, mut invalid) = (0.0f64, 0.0f32, 0);
+        let mut hash = FNV_OFFSET;
+        for pix in pixels {
+            for &comp in [pix.x, pix.y, pix.z].iter() {
+                let bits = comp.to_bits();
+                hash = fnv1a(hash, &[bits as u8, (bits >> 8) as u8, (bits >> 16) as u8, (bits >> 24) as u8]);
+            }
+            if !(pix.x.is_finite() && pix.y.is_finite() && pix.z.is_finite()) {
+                invalid += 1;
+                continue;
+            }
+            let lum = luminance(pix) * k;
+            sum += lum as f64;
+            max = max.max(lum);
+        }
+        let valid = pixels.len() - invalid;
+        self.frame = Some(FrameStats {
+            mean_luminance: if valid > 0 { (sum / valid as f64) as f32 } else { 0.0 },
+            max_luminance: max,
+            invalid_pixels: invalid,
+            hash: hash,
+        });
+    }
+
+    // hashes the file written at `path`
+    pub fn add_output<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        File::open(path.as_ref())?.read_to_end(&mut bytes)?;
+        self.outputs.push((path.as_ref().display().to_string(), fnv1a(FNV_OFFSET, &bytes)));
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let settings = &self.settings;
+        let mut out = String::new();
+        writeln!(out, "{{").unwrap();
+        writeln!(out, "  \"resolution\": [{}, {}],", self.resolution.x, self.resolution.y).unwrap();
+        writeln!(out, "  \"seed\": {},", self.seed).unwrap();
+        writeln!(out, "  \"settings\": {{").unwrap();
+        writeln!(out, "    \"max_depth\": {},", settings.max_depth).unwrap();
+        writeln!(out, "    \"min_depth\": {},", settings.min_depth).unwrap();
+        writeln!(out, "    \"spp_per_iteration\": {},", settings.spp_per_iteration).unwrap();
+        writeln!(out, "    \"light_clamp\": {},", json_number(settings.light_clamp)).unwrap();
+        writeln!(out, "    \"direct_clamp\": {},", settings.direct_clamp.map_or("null".to_string(), json_number)).unwrap();
+        writeln!(out, "    \"indirect_clamp\": {},", settings.indirect_clamp.map_or("null".to_string(), json_number)).unwrap();
+        writeln!(out, "    \"sampler\": {},", json_string(&format!("{:?}", settings.sampler))).unwrap();
+        writeln!(out, "    \"deterministic\": {},", settings.deterministic).unwrap();
+        writeln!(out, "    \"bounce_splits\": {},", settings.bounce_splits).unwrap();
+        writeln!(out, "    \"light_splits\": {},", settings.light_splits).unwrap();
+        writeln!(out, "    \"blue_sky\": {},", settings.blue_sky).unwrap();
+        writeln!(out, "    \"ray_differentials\": {}", settings.ray_differentials).unwrap();
+        writeln!(out, "  }},").unwrap();
+        let stages = self.stages.iter()
+            .map(|&(ref name, time)| format!("    {}: {}", json_string(name), json_number(duration_to_secs(time))))
+            .collect::<Vec<_>>();
+        writeln!(out, "  \"stage_seconds\": {{{}}},", json_block(&stages)).unwrap();
+        writeln!(out, "  \"iterations\": {},", self.iterations).unwrap();
+        writeln!(out, "  \"spp\": {},", self.spp).unwrap();
+        writeln!(out, "  \"samples\": {},", self.spp * self.resolution.x * self.resolution.y).unwrap();
+        let mut stats = vec![
+            format!("    \"aborted_tiles\": {}", self.aborted_tiles),
+            format!("    \"aborted_paths\": {}", self.aborted_paths),
+        ];
+        if !self.tile_aborts.is_empty() {
+            let aborts = self.tile_aborts.iter()
+                .map(|abort| format!("{{\"tile\": {}, \"seconds\": {}}}", abort.tile_nb, json_number(duration_to_secs(abort.elapsed))))
+                .collect::<Vec<_>>();
+            stats.push(format!("    \"tile_aborts\": [{}]", aborts.join(", ")));
+        }
+        if let Some(ref metrics) = self.metrics {
+            stats.push(format!("    \"samples_per_second\": {}", json_number(metrics.samples_per_second)));
+            stats.push(format!("    \"relative_error\": {}", metrics.relative_error.map_or("null".to_string(), json_number)));
+            stats.push(format!("    \"memory_used_bytes\": {}", metrics.memory_used));
+        }
+        if let Some(frame) = self.frame {
+            stats.push(format!("    \"mean_luminance\": {}", json_number(frame.mean_luminance)));
+            stats.push(format!("    \"max_luminance\": {}", json_number(frame.max_luminance)));
+            stats.push(format!("    \"invalid_pixels\": {}", frame.invalid_pixels));
+        }
+        writeln!(out, "  \"statistics\": {{{}}},", json_block(&stats)).unwrap();
+        let mut hashes = self.frame.iter().map(|frame| format!("    \"frame\": \"{:016x}\"", frame.hash)).collect::<Vec<_>>();
+        hashes.extend(self.outputs.iter().map(|&(ref path, hash)| format!("    {}: \"{:016x}\"", json_string(path), hash)));
+        writeln!(out, "  \"hashes\": {{{}}}", json_block(&hashes)).unwrap();
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(self.to_json().as_bytes())?;
+        out.flush()
+    }
+}
+
+// the members of an object or an array, a line each
+fn json_block(lines: &[String]) -> String {
+    if lines.is_empty() { String::new() } else { format!("\n{}\n  ", lines.join(",\n")) }
+}
+
+// JSON has no NaN or infinities
+fn json_number(value: f32) -> String {
+    if value.is_finite() { format!("{}", value) } else { "null".to_string() }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c    => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RenderReport;
+    use framebuffer::RgbFrameBuffer;
+    use math::{Vec2u, Vec3f};
+    use render::{RenderSettings, TileAbort};
+    use std::time::Duration;
+
+    #[test]
+    fn report_is_json_with_the_frame_hash() {
+        let res = Vec2u::new(2, 1);
+        let mut frame = RgbFrameBuffer::new(res);
+        frame.add_color((0, 0), Vec3f::new(1.0, 1.0, 1.0));
+        frame.add_color((1, 0), Vec3f::new(::std::f32::NAN, 0.0, 0.0));
+        let mut report = RenderReport::new(RenderSettings::new().with_deterministic(), res, 7);
+        report.add_stage("render \"main\"", Duration::from_millis(1500));
+        report.spp = 4;
+        report.tile_aborts.push(TileAbort { tile_nb: 3, elapsed: Duration::from_millis(500) });
+        report.set_frame(&frame, 4);
+        let json = report.to_json();
+        assert!(json.contains("\"render \\\"main\\\"\": 1.5"), "{}", json);
+        assert!(json.contains("\"samples\": 8,"));
+        assert!(json.contains("\"deterministic\": true"));
+        assert!(json.contains("\"direct_clamp\": null,"));
+        assert!(json.contains("\"mean_luminance\": 0.25,"));
+        assert!(json.contains("\"invalid_pixels\": 1"));
+        assert!(json.contains("\"tile_aborts\": [{\"tile\": 3, \"seconds\": 0.5}]"), "{}", json);
+
+        // the same frame hashes the same, one more sample doesn't
+        let hash = |json: &str| json.lines().find(|line| line.contains("\"frame\"")).unwrap().to_string();
+        let mut again = RenderReport::new(RenderSettings::new(), res, 7);
+        again.set_frame(&frame, 4);
+        assert_eq!(hash(&again.to_json()), hash(&json));
+        frame.add_color((0, 0), Vec3f::new(1.0, 1.0, 1.0));
+        again.set_frame(&frame, 4);
+        assert!(hash(&again.to_json()) != hash(&json));
+    }
+}
@@ -1,17 +1,25 @@
 #![allow(dead_code)]
-use brdf::Material;
+use brdf::{InspectionMaterial, Material};
+use brdf::audit::AlbedoAudit;
 use geometry::{
-    Geometry, GeometryManager, Ray, Surface, SurfaceIntersection,
-    DField, DFieldIsosurface
+    Geometry, GeometryIssue, GeometryManager, Ray, Surface, SurfaceIntersection,
+    DField, DFieldIsosurface, FilteredSurface, ClipPlane, Epsilons, Aabb, Sphere, bounding_sphere,
+    past_hit
 };
+use distribution::Distribution1D;
 use light::{Light, BackgroundLight, LuminousObject, Luminous};
-use math::Vec3f;
-use std::fmt::Debug;
+use light_tree::LightTree;
+use math::{Vec3f, Zero};
+use math::vector_traits::*;
+use memory::OutOfBudget;
+use portal::{Portal, PortalLight};
+use std::fmt::{self, Debug};
+use std::mem;
 
 pub type MaterialID = i32;
 pub type LightID = i32;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SurfaceProperties {
     Material(MaterialID),
     Light(LightID),
@@ -21,61 +29,290 @@ pub enum SurfaceProperties {
 pub struct DefaultScene<T> where T: GeometryManager {
     geo_mgr: T,
     materials: Vec<Material>,
+    object_bounds: Vec<Option<Aabb>>, // per material id, every object has a material of its own
+    escape_bounds: Option<Vec<Aabb>>, // of the objects and the lights, None if some object is unbounded
+    bounds_stale: bool, // objects were added since the epsilons and the escape bounds were fitted
+    added_bounds: Aabb, // of the objects, grown as they are added
+    inspection_materials: Vec<Option<InspectionMaterial>>,
     lights: Vec<Box<Light>>,
+    light_groups: Vec<usize>, // group of every light
+    light_selection: LightSelection,
+    light_pick: LightPick, // built for the light selection
+    light_pick_stale: bool, // lights were added since it was built, they're picked uniformly until commit_changes
+    seed: u32,
+    eps_overrides: EpsilonOverrides,
+    layer: Option<RenderLayer>,
+    changes: SceneChanges, // since the last commit_changes
+}
+
+// what the edits since the last `Scene::commit_changes` touched
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SceneChanges {
+    pub geometry: bool,
+    pub materials: bool,
+    pub lights: bool,
+}
+
+// Something in the scene which renders wrong or costs time for nothing, found by `Scene::validate`.
+// Objects are identified by their surface: the material id of an object or the light id of a luminous one
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneIssue {
+    NanVertex { surface: SurfaceProperties, vertex: usize },
+    DegenerateNormal { surface: SurfaceProperties, face: usize },
+    ZeroPowerLight { light: LightID },
+    AlbedoAboveOne { material: MaterialID, albedo: Vec3f }, // the material reflects more light than it gets
+    UnreferencedTexture { material: MaterialID, map: &'static str }, // the shading never looks at it
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SceneReport {
+    pub issues: Vec<SceneIssue>,
+}
+
+// How the light sampling picks the one light it samples at a vertex
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightSelection {
+    Uniform, // every light as often, the default
+    Power, // proportionally to `Light::power`, the dim lights of a scene with many lights are hardly ever picked
+    Tree, // by power and distance to the shading point, see `LightTree`, for thousands of lights
+}
+
+#[derive(Debug, Clone)]
+enum LightPick {
+    Uniform,
+    Power(Distribution1D),
+    Tree(LightTree, Distribution1D), // the power distribution picks the lights the light paths start from
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerVisibility {
+    Visible,
+    Holdout, // black and transparent for the camera, but still reflects and casts shadows
+    Hidden, // not there at all
+}
+
+// Subset of the objects rendered into an image of its own, like render layers of DCC tools.
+// Objects are identified by the ids `add_object` and friends return, lights are always there
+#[derive(Debug, Clone)]
+pub struct RenderLayer {
+    pub name: String,
+    default: LayerVisibility,
+    overrides: Vec<(MaterialID, LayerVisibility)>,
+}
+
+// fixed values for some of the epsilons, the rest follow the scene scale
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EpsilonOverrides {
+    pub ray_geo: Option<f32>,
+    pub ray_df: Option<f32>,
+    pub cosine: Option<f32>,
 }
 
 pub trait Scene {
     fn nearest_intersection(&self, ray: &Ray) -> Option<SurfaceIntersection>;
     fn was_occluded(&self, ray: &Ray, dist: f32) -> bool;
 
-    fn add_object<G>(&mut self, geo: G, material: Material) where G: Geometry + 'static;
-    fn add_isosurface<D>(&mut self, dfield: D, material: Material)
+    fn add_object<G>(&mut self, geo: G, material: Material) -> Result<MaterialID, OutOfBudget>
+        where G: Geometry + 'static;
+    fn add_filtered_object<G, F>(&mut self, geo: G, material: Material, filter: F)
+        -> Result<MaterialID, OutOfBudget>
+        where G: Geometry + 'static,
+              F: Fn(&Ray, &mut SurfaceIntersection) -> bool + 'static;
+    fn add_isosurface<D>(&mut self, dfield: D, material: Material) -> Result<MaterialID, OutOfBudget>
         where D: DField + 'static;
     fn add_light<L>(&mut self, light: L) where L: Light + 'static;
-    fn add_luminous_object<G>(&mut self, geo: G, intensity: Vec3f)
+    fn add_clip_plane(&mut self, plane: ClipPlane);
+    fn add_luminous_object<G>(&mut self, geo: G, intensity: Vec3f) -> Result<(), OutOfBudget>
         where G: Geometry + Luminous + Clone + Debug + 'static;
 
     fn get_material(&self, m_id: MaterialID) -> &Material;
+    fn get_materials_nb(&self) -> usize;
+    fn get_object_bounds(&self, m_id: MaterialID) -> Option<Aabb>;
+    // material with textures, variation, dirt and paint flakes resolved and the inspection override applied, if there is one
+    fn get_surface_material(&self, m_id: MaterialID, isect: &SurfaceIntersection) -> Material;
+    // `get_surface_material` for a ray standing for a cone of directions `spread` radians wide (see
+    // `RenderSettings::with_ray_differentials`), the maps are filtered over the footprint of the cone
+    fn get_filtered_material(&self, m_id: MaterialID, isect: &SurfaceIntersection, spread: f32) -> Material;
+    // normal perturbed by the normal and height maps of the material
+    fn get_shading_normal(&self, m_id: MaterialID, isect: &SurfaceIntersection) -> Vec3f;
+    fn set_inspection_material(&mut self, m_id: MaterialID, inspection: Option<InspectionMaterial>);
     fn get_light(&self, m_id: LightID) -> &Box<Light>;
     fn get_lights_nb(&self) -> usize;
     fn get_background_light(&self) -> &Box<Light>;
+    fn set_light_selection(&mut self, selection: LightSelection);
+    fn get_light_selection(&self) -> LightSelection;
+    // the light the light sampling at `p` takes for `rnd` in [0, 1) and the probability to pick it,
+    // None where no light reaches `p`
+    fn pick_light(&self, p: &Vec3f, rnd: f32) -> Option<(LightID, f32)>;
+    // probability of `pick_light` at `p` giving the light, for the MIS weights of the lights the bounces hit
+    fn light_pick_prob(&self, p: &Vec3f, light_id: LightID) -> f32;
+    // the light a light path or a photon starts from for `rnd` in [0, 1): like `pick_light` without
+    // a point to pick for, by power for all but the uniform selection. None when no light emits
+    fn pick_emitter(&self, rnd: f32) -> Option<(LightID, f32)>;
+    fn emitter_pick_prob(&self, light_id: LightID) -> f32;
+    // lights of a group end up in a separate image with the light group renderers, all of them are in group 0 at first
+    fn set_light_group(&mut self, light_id: LightID, group: usize);
+    fn get_light_group(&self, light_id: LightID) -> usize;
+    fn get_light_groups_nb(&self) -> usize;
+
+    // all the randomness of a render derives from it: the jitters and the path numbers of the pixels
+    // (see `render::PixelSampler`), the light paths and the procedural content. In the deterministic
+    // mode the same seed gives the same frame, otherwise the watchdog may still drop samples
+    fn get_seed(&self) -> u32;
+    fn set_seed(&mut self, seed: u32);
+
+    // bounds of everything bounded, unbounded isosurfaces and lights without geometry don't count
+    fn get_bounds(&self) -> Aabb;
+    // sphere around the bounded objects, tighter than the one around `get_bounds`; None for an empty scene
+    fn get_bounding_sphere(&self) -> Option<Sphere>;
+    // true if the ray surely hits nothing, it misses the bounds of every object; false if it can't tell
+    fn escapes(&self, ray: &Ray) -> bool;
+
+    // ray offsets derived from the scene size, with the overrides applied. Added objects count from
+    // the next `commit_changes` on, the renderers do it when they get the scene
+    fn get_epsilons(&self) -> Epsilons;
+    fn set_epsilon_overrides(&mut self, overrides: EpsilonOverrides);
+
+    // restricts intersections to the objects of the layer, None renders everything
+    fn set_render_layer(&mut self, layer: Option<RenderLayer>);
+    fn get_layer_visibility(&self, m_id: MaterialID) -> LayerVisibility;
+
+    // In-place edits for optimization loops. They only record what changed, `commit_changes`
+    // refits the bounds and epsilons after moves and additions and tells whether the accumulated image is stale.
+    // Moves fail for geometry which can't be translated and leave such objects partly moved
+    fn move_object(&mut self, m_id: MaterialID, offset: &Vec3f) -> bool;
+    fn edit_material<F>(&mut self, m_id: MaterialID, edit: F) where F: FnOnce(&mut Material);
+    fn set_light_intensity(&mut self, light_id: LightID, intensity: Vec3f) -> bool;
+    fn commit_changes(&mut self) -> SceneChanges;
+
+    // sanity checks of the geometry, lights and materials, to be looked at before rendering
+    fn validate(&self) -> SceneReport;
 }
 
 impl<T> Scene for DefaultScene<T> where T: GeometryManager {
     fn nearest_intersection(&self, ray: &Ray) -> Option<SurfaceIntersection> {
-        self.geo_mgr.nearest_intersection(ray)
+        if self.layer.is_none() {
+            return self.geo_mgr.nearest_intersection(ray);
+        }
+        // hidden objects are stepped through like the rejected hits of filtered surfaces
+        let mut skipped = 0.0;
+        loop {
+            let mut isect = match self.geo_mgr.nearest_intersection(&ray.advance(skipped)) {
+                Some(isect) => isect,
+                None        => return None
+            };
+            isect.dist += skipped;
+            match isect.surface {
+                SurfaceProperties::Material(m_id) if self.get_layer_visibility(m_id) == LayerVisibility::Hidden => {
+                    skipped = past_hit(isect.dist, self.get_epsilons().ray_geo);
+                },
+                _ => return Some(isect)
+            }
+        }
     }
 
     fn was_occluded(&self, ray: &Ray, dist: f32) -> bool {
-        self.geo_mgr.was_occluded(&ray, dist)
+        if self.layer.is_none() {
+            return self.geo_mgr.was_occluded(&ray, dist);
+        }
+        let eps = self.get_epsilons().ray_geo;
+        self.nearest_intersection(ray).map_or(false, |isect| isect.dist < dist - 2.0 * eps)
     }
 
-    fn add_object<G>(&mut self, geo: G, material: Material)
+    fn add_object<G>(&mut self, geo: G, material: Material) -> Result<MaterialID, OutOfBudget>
         where G: Geometry + 'static {
         let material_id = self.materials.len() as i32;
-        self.materials.push(material);
+        let bounds = geo.bounds();
         self.geo_mgr.add_geometry(Surface {
             geometry: geo,
             properties: SurfaceProperties::Material(material_id)
-        })
+        })?;
+        self.materials.push(material);
+        self.inspection_materials.push(None);
+        self.object_bounds.push(bounds);
+        self.grow_epsilons(bounds);
+        Ok(material_id)
     }
 
-    fn add_isosurface<D>(&mut self, dfield: D, material: Material)
-        where D: DField + 'static {
+    fn add_filtered_object<G, F>(&mut self, geo: G, material: Material, filter: F)
+        -> Result<MaterialID, OutOfBudget>
+        where G: Geometry + 'static,
+              F: Fn(&Ray, &mut SurfaceIntersection) -> bool + 'static {
         let material_id = self.materials.len() as i32;
+        let bounds = geo.bounds();
+        self.geo_mgr.add_geometry(FilteredSurface {
+            surface: Surface {
+                geometry: geo,
+                properties: SurfaceProperties::Material(material_id)
+            },
+            filter: filter
+        })?;
         self.materials.push(material);
+        self.inspection_materials.push(None);
+        self.object_bounds.push(bounds);
+        self.grow_epsilons(bounds);
+        Ok(material_id)
+    }
+
+    fn add_isosurface<D>(&mut self, dfield: D, material: Material) -> Result<MaterialID, OutOfBudget>
+        where D: DField + 'static {
+        let material_id = self.materials.len() as i32;
+        let bounds = dfield.bounds();
         self.geo_mgr.add_isosurface(DFieldIsosurface {
             dfield: dfield,
             properties: SurfaceProperties::Material(material_id)
-        })
+        })?;
+        self.materials.push(material);
+        self.inspection_materials.push(None);
+        self.object_bounds.push(bounds);
+        self.grow_epsilons(bounds);
+        Ok(material_id)
     }
 
     fn get_material(&self, m_id: MaterialID) -> &Material {
         &self.materials[m_id as usize]
     }
 
+    fn get_materials_nb(&self) -> usize {
+        self.materials.len()
+    }
+
+    fn get_object_bounds(&self, m_id: MaterialID) -> Option<Aabb> {
+        self.object_bounds[m_id as usize]
+    }
+
+    fn get_surface_material(&self, m_id: MaterialID, isect: &SurfaceIntersection) -> Material {
+        self.material_at(m_id, isect, 0.0)
+    }
+
+    // the cone is taken from the origin of the ray, how wide it already was there is left out
+    fn get_filtered_material(&self, m_id: MaterialID, isect: &SurfaceIntersection, spread: f32) -> Material {
+        self.material_at(m_id, isect, spread * isect.dist)
+    }
+
+    fn get_shading_normal(&self, m_id: MaterialID, isect: &SurfaceIntersection) -> Vec3f {
+        // inspection materials show the geometry as it is
+        if self.inspection_materials[m_id as usize].is_some() {
+            return isect.normal;
+        }
+        match self.get_material(m_id).textures {
+            Some(ref textures) => textures.shading_normal(isect),
+            None               => isect.normal
+        }
+    }
+
+    fn set_inspection_material(&mut self, m_id: MaterialID, inspection: Option<InspectionMaterial>) {
+        self.inspection_materials[m_id as usize] = inspection;
+    }
+
     fn add_light<L>(&mut self, light: L) where L: Light + 'static {
         self.lights.push(Box::new(light));
+        self.light_groups.push(0);
+        self.light_pick_stale = true;
+    }
+
+    fn add_clip_plane(&mut self, plane: ClipPlane) {
+        self.geo_mgr.add_clip_plane(plane);
     }
 
     fn get_light(&self, m_id: LightID) -> &Box<Light> {
@@ -90,24 +327,477 @@ impl<T> Scene for DefaultScene<T> where T: GeometryManager {
         &self.lights[0]
     }
 
-    fn add_luminous_object<G>(&mut self, geo: G, intensity: Vec3f)
+    fn set_light_selection(&mut self, selection: LightSelection) {
+        self.light_selection = selection;
+        self.update_light_pick();
+    }
+
+    fn get_light_selection(&self) -> LightSelection {
+        self.light_selection
+    }
+
+    fn pick_light(&self, p: &Vec3f, rnd: f32) -> Option<(LightID, f32)> {
+        match (self.light_pick_stale, &self.light_pick) {
+            (false, &LightPick::Tree(ref tree, _)) => tree.pick(p, rnd),
+            _ => self.pick_emitter(rnd)
+        }
+    }
+
+    fn light_pick_prob(&self, p: &Vec3f, light_id: LightID) -> f32 {
+        match (self.light_pick_stale, &self.light_pick) {
+            (false, &LightPick::Tree(ref tree, _)) => tree.prob(p, light_id),
+            _ => self.emitter_pick_prob(light_id)
+        }
+    }
+
+    fn pick_emitter(&self, rnd: f32) -> Option<(LightID, f32)> {
+        match (self.light_pick_stale, &self.light_pick) {
+            (false, &LightPick::Power(ref pick)) | (false, &LightPick::Tree(_, ref pick)) => {
+                let picked = pick.sample(rnd);
+                if picked.prob > 0.0 { Some((picked.idx as LightID, picked.prob)) } else { None }
+            },
+            _ => {
+                let lights_nb = self.lights.len();
+                Some((((rnd * lights_nb as f32) as usize).min(lights_nb - 1) as LightID, 1.0 / lights_nb as f32))
+            }
+        }
+    }
+
+    fn emitter_pick_prob(&self, light_id: LightID) -> f32 {
+        match (self.light_pick_stale, &self.light_pick) {
+            (false, &LightPick::Power(ref pick)) | (false, &LightPick::Tree(_, ref pick)) => pick.prob(light_id as usize),
+            _ => 1.0 / self.lights.len() as f32
+        }
+    }
+
+    fn set_light_group(&mut self, light_id: LightID, group: usize) {
+        self.light_groups[light_id as usize] = group;
+    }
+
+    fn get_light_group(&self, light_id: LightID) -> usize {
+        self.light_groups[light_id as usize]
+    }
+
+    fn get_light_groups_nb(&self) -> usize {
+        self.light_groups.iter().map(|&group| group + 1).max().unwrap_or(1)
+    }
+
+    fn get_seed(&self) -> u32 {
+        self.seed
+    }
+
+    fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    fn get_bounds(&self) -> Aabb {
+        self.geo_mgr.object_bounds().iter().fold(Aabb::empty(), |all, bounds| all.union(bounds))
+    }
+
+    fn get_bounding_sphere(&self) -> Option<Sphere> {
+        let bounds = self.get_bounds();
+        if bounds.is_empty() {
+            return None;
+        }
+        let corners = self.geo_mgr.object_bounds().iter()
+            .flat_map(|bounds| bounds.corners().to_vec())
+            .collect::<Vec<_>>();
+        // Ritter's sphere may be a bit bigger than the box's one for a single box
+        let (box_center, box_radius) = bounds.bounding_sphere();
+        let (center, radius) = bounding_sphere(&corners)
+            .map_or((box_center, box_radius), |(c, r)| if r < box_radius { (c, r) } else { (box_center, box_radius) });
+        Some(Sphere { center: center, radius: radius })
+    }
+
+    fn escapes(&self, ray: &Ray) -> bool {
+        if self.bounds_stale {
+            return false;
+        }
+        self.escape_bounds.as_ref().map_or(false, |bounds| !bounds.iter().any(|bounds| bounds.hit_by(ray)))
+    }
+
+    fn get_epsilons(&self) -> Epsilons {
+        self.geo_mgr.get_epsilons()
+    }
+
+    fn set_render_layer(&mut self, layer: Option<RenderLayer>) {
+        self.layer = layer;
+    }
+
+    fn get_layer_visibility(&self, m_id: MaterialID) -> LayerVisibility {
+        self.layer.as_ref().map_or(LayerVisibility::Visible, |layer| layer.visibility(m_id))
+    }
+
+    fn set_epsilon_overrides(&mut self, overrides: EpsilonOverrides) {
+        self.eps_overrides = overrides;
+        self.update_epsilons();
+    }
+
+    fn move_object(&mut self, m_id: MaterialID, offset: &Vec3f) -> bool {
+        if *offset == Vec3f::zero() {
+            return true;
+        }
+        let moved = self.geo_mgr.translate_object(m_id, offset);
+        let bounds = &mut self.object_bounds[m_id as usize];
+        *bounds = bounds.map(|bounds| bounds.translate(offset));
+        self.changes.geometry = true;
+        self.bounds_stale = true;
+        moved
+    }
+
+    fn edit_material<F>(&mut self, m_id: MaterialID, edit: F) where F: FnOnce(&mut Material) {
+        edit(&mut self.materials[m_id as usize]);
+        self.changes.materials = true;
+    }
+
+    fn set_light_intensity(&mut self, light_id: LightID, intensity: Vec3f) -> bool {
+        let changed = self.lights[light_id as usize].set_intensity(intensity);
+        self.changes.lights |= changed;
+        changed
+    }
+
+    fn commit_changes(&mut self) -> SceneChanges {
+        let changes = self.changes;
+        if changes.geometry || self.bounds_stale {
+            self.update_epsilons();
+        }
+        // the power of the lights at infinity follows the size of the scene
+        if changes.geometry || changes.lights || self.light_pick_stale {
+            self.update_light_pick();
+        }
+        self.changes = SceneChanges::default();
+        changes
+    }
+
+    fn validate(&self) -> SceneReport {
+        let mut issues = self.geo_mgr.check_surfaces().into_iter().map(|(surface, issue)| match issue {
+            GeometryIssue::NanVertex(vertex) => SceneIssue::NanVertex { surface: surface, vertex: vertex },
+            GeometryIssue::DegenerateNormal(face) => SceneIssue::DegenerateNormal { surface: surface, face: face },
+        }).collect::<Vec<_>>();
+        // a black background is a common choice, not a mistake
+        for (light_id, light) in self.lights.iter().enumerate().skip(1) {
+            if let Some(intensity) = light.intensity() {
+                if !(intensity.x > 0.0 || intensity.y > 0.0 || intensity.z > 0.0) {
+                    issues.push(SceneIssue::ZeroPowerLight { light: light_id as LightID });
+                }
+            }
+        }
+        for (m_id, material) in self.materials.iter().enumerate() {
+            let m_id = m_id as MaterialID;
+            let audit = AlbedoAudit::new(material);
+            if audit.gains_energy() {
+                // of the view angle reflecting the most
+                let albedo = audit.albedo.iter().map(|&(_, albedo)| albedo)
+                    .fold(Vec3f::zero(), |max, albedo| if albedo.fold(f32::max) > max.fold(f32::max) { albedo } else { max });
+                issues.push(SceneIssue::AlbedoAboveOne { material: m_id, albedo: albedo });
+            }
+            if let Some(ref textures) = material.textures {
+                for map in textures.unused_maps(material) {
+                    issues.push(SceneIssue::UnreferencedTexture { material: m_id, map: map });
+                }
+            }
+        }
+        SceneReport { issues: issues }
+    }
+
+    fn add_luminous_object<G>(&mut self, geo: G, intensity: Vec3f) -> Result<(), OutOfBudget>
         where G: Geometry + Luminous + Clone + Debug + 'static {
         let light_id = self.lights.len() as i32;
         let light = LuminousObject { object: geo.clone(), intensity: intensity };
-        self.lights.push(Box::new(light));
+        let bounds = geo.bounds();
         self.geo_mgr.add_geometry(Surface {
             geometry: geo,
             properties: SurfaceProperties::Light(light_id)
-        })
+        })?;
+        self.lights.push(Box::new(light));
+        self.light_groups.push(0);
+        self.grow_epsilons(bounds);
+        self.light_pick_stale = true;
+        Ok(())
     }
 }
 
 impl<T: GeometryManager> DefaultScene<T> {
+    // the material at the hit, the maps filtered over a footprint that wide in world units
+    fn material_at(&self, m_id: MaterialID, isect: &SurfaceIntersection, footprint: f32) -> Material {
+        let material = self.get_material(m_id);
+        let mut material = match material.textures {
+            Some(ref textures) => textures.apply_filtered(material, isect, footprint),
+            None               => material.clone()
+        };
+        if let Some(variation) = material.variation {
+            material = variation.apply(&material, m_id as u64 ^ (self.seed as u64) << 32);
+        }
+        if let Some(dirt) = material.dirt {
+            material = dirt.apply(&material, self, isect);
+        }
+        material.car_paint = material.car_paint.map(|paint| paint.at(isect));
+        match self.inspection_materials[m_id as usize] {
+            Some(ref inspection) => inspection.apply(&material, isect),
+            None                 => material
+        }
+    }
+
     pub fn new(backlight: BackgroundLight) -> DefaultScene<T> {
         DefaultScene {
             geo_mgr: T::new(),
             materials: Vec::new(),
-            lights: vec![Box::new(backlight)]
+            object_bounds: Vec::new(),
+            escape_bounds: Some(Vec::new()),
+            bounds_stale: false,
+            added_bounds: Aabb::empty(),
+            inspection_materials: Vec::new(),
+            lights: vec![Box::new(backlight)],
+            light_groups: vec![0],
+            light_selection: LightSelection::Uniform,
+            light_pick: LightPick::Uniform,
+            light_pick_stale: false,
+            seed: 0,
+            eps_overrides: EpsilonOverrides::default(),
+            layer: None,
+            changes: SceneChanges::default(),
+        }
+    }
+
+    // light 0, which lights whatever rays miss
+    pub fn set_background_light<L: Light + 'static>(&mut self, light: L) {
+        self.lights[0] = Box::new(light);
+        self.changes.lights = true;
+        self.light_pick_stale = true;
+    }
+
+    // light 0 gets sampled through the openings of an interior, see `PortalLight`
+    pub fn add_portals(&mut self, portals: Vec<Portal>) {
+        let sky = mem::replace(&mut self.lights[0], Box::new(BackgroundLight { intensity: Vec3f::zero() }));
+        self.set_background_light(PortalLight::new(sky, portals));
+    }
+
+    fn update_light_pick(&mut self) {
+        self.light_pick_stale = false;
+        if self.light_selection == LightSelection::Uniform {
+            self.light_pick = LightPick::Uniform;
+            return;
         }
+        let radius = self.get_bounding_sphere().map_or(1.0, |sphere| sphere.radius);
+        let powers = self.lights.iter().map(|light| light.power(radius)).collect::<Vec<_>>();
+        let known = powers.iter().filter_map(|&power| power).collect::<Vec<_>>();
+        let mean = if known.is_empty() { 1.0 } else { known.iter().sum::<f32>() / known.len() as f32 };
+        let powers = powers.into_iter().map(|power| power.unwrap_or(mean)).collect::<Vec<_>>();
+        // all dark or unknown is the uniform selection again, see `Distribution1D::new`
+        let by_power = Distribution1D::new(powers.clone());
+        self.light_pick = match self.light_selection {
+            LightSelection::Power => LightPick::Power(by_power),
+            _ => {
+                let lights = self.lights.iter().map(|light| light.bounds()).zip(powers).collect::<Vec<_>>();
+                LightPick::Tree(LightTree::new(&lights), by_power)
+            }
+        };
+    }
+
+    // the epsilons follow every added object at once, from the box around all of them;
+    // the tighter sphere and the escape bounds wait for commit_changes, Ritter's sphere is O(n)
+    fn grow_epsilons(&mut self, bounds: Option<Aabb>) {
+        self.bounds_stale = true;
+        if let Some(bounds) = bounds {
+            self.added_bounds = self.added_bounds.union(&bounds);
+            let (center, radius) = self.added_bounds.bounding_sphere();
+            self.set_scaled_epsilons(Epsilons::for_scene(&center, radius));
+        }
+    }
+
+    fn set_scaled_epsilons(&mut self, eps: Epsilons) {
+        let overrides = self.eps_overrides;
+        let eps = Epsilons {
+            ray_geo: overrides.ray_geo.unwrap_or(eps.ray_geo),
+            ray_df: overrides.ray_df.unwrap_or(eps.ray_df),
+            cosine: overrides.cosine.unwrap_or(eps.cosine),
+            ..eps
+        };
+        self.geo_mgr.set_epsilons(eps);
+    }
+
+    fn update_epsilons(&mut self) {
+        self.added_bounds = self.get_bounds();
+        let eps = match self.get_bounding_sphere() {
+            Some(sphere) => Epsilons::for_scene(&sphere.center, sphere.radius),
+            None         => Epsilons::new()
+        };
+        self.set_scaled_epsilons(eps);
+        let eps = self.get_epsilons();
+        self.bounds_stale = false;
+
+        // the luminous objects are spheres, always bounded; grown by the error of the hit points on the surfaces
+        let margin = 2.0 * eps.ray_geo;
+        self.escape_bounds = self.object_bounds.iter()
+            .map(|bounds| bounds.map(|bounds| bounds.expand(margin)))
+            .collect::<Option<Vec<_>>>()
+            .map(|mut bounds| {
+                bounds.extend(self.lights.iter().filter_map(|light| light.bounds()).map(|bounds| bounds.expand(margin)));
+                bounds
+            });
+    }
+}
+
+impl SceneReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for SceneIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |surface: SurfaceProperties| match surface {
+            SurfaceProperties::Material(m_id) => format!("object {}", m_id),
+            SurfaceProperties::Light(light_id) => format!("light {}", light_id),
+        };
+        match *self {
+            SceneIssue::NanVertex { surface, vertex } => write!(f, "{}: vertex {} isn't finite", name(surface), vertex),
+            SceneIssue::DegenerateNormal { surface, face } => write!(f, "{}: face {} has no normal", name(surface), face),
+            SceneIssue::ZeroPowerLight { light } => write!(f, "light {} emits nothing", light),
+            SceneIssue::AlbedoAboveOne { material, albedo } =>
+                write!(f, "material {}: albedo {:?} is above one", material, (albedo.x, albedo.y, albedo.z)),
+            SceneIssue::UnreferencedTexture { material, map } => write!(f, "material {}: the {} map is never used", material, map),
+        }
+    }
+}
+
+impl fmt::Display for SceneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl RenderLayer {
+    // `default` is the visibility of the objects the layer doesn't mention
+    pub fn new(name: &str, default: LayerVisibility) -> RenderLayer {
+        RenderLayer { name: name.to_string(), default: default, overrides: Vec::new() }
+    }
+
+    pub fn with_objects(mut self, objects: &[MaterialID], visibility: LayerVisibility) -> RenderLayer {
+        self.overrides.retain(|&(m_id, _)| !objects.contains(&m_id));
+        self.overrides.extend(objects.iter().map(|&m_id| (m_id, visibility)));
+        self
+    }
+
+    pub fn visibility(&self, m_id: MaterialID) -> LayerVisibility {
+        self.overrides.iter()
+            .find(|&&(id, _)| id == m_id)
+            .map_or(self.default, |&(_, visibility)| visibility)
+    }
+}
+
+impl SceneChanges {
+    // the image rendered before the changes is no good anymore
+    pub fn any(&self) -> bool {
+        self.geometry || self.materials || self.lights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DefaultScene, LightSelection, Scene, SceneIssue, SurfaceProperties};
+    use brdf::{ChannelMap, TextureSet, TextureSource};
+    use geometry::{GeometryList, Mesh, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::WHITE_DIFFUSE;
+    use math::Vec3f;
+    use math::vector_traits::*;
+    use std::f32::NAN;
+    use std::sync::Arc;
+    use texture::Texture;
+
+    #[test]
+    fn validation_finds_the_broken_parts() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(10.0, 10.0, 10.0) });
+        assert!(scene.validate().is_ok());
+
+        let vertices = vec![Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0),
+                            Vec3f::new(2.0, 0.0, 0.0), Vec3f::new(NAN, 0.0, 0.0)];
+        // a face, one along a line and one with the broken vertex
+        let mesh = Mesh::new(vertices, vec![[0, 1, 2], [0, 1, 3], [0, 1, 4]], None).unwrap();
+        let mesh_id = scene.add_object(mesh, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        let mut shiny = WHITE_DIFFUSE;
+        shiny.diffuse = Vec3f::new(0.5, 0.5, 0.5);
+        shiny.specular = Vec3f::new(0.5, 0.0, 0.0);
+        shiny.textures = Some(Arc::new(TextureSet {
+            normal_variance: Some(Arc::new(vec![Texture::new_constant(Vec3f::new(0.0, 0.0, 0.0))])),
+            ..TextureSet::new()
+        }));
+        let shiny_id = scene.add_object(Sphere { center: Vec3f::new(3.0, 0.0, 0.0), radius: 1.0 }, shiny).unwrap();
+        // no glossy lobe for the map to change
+        let mut matte = WHITE_DIFFUSE;
+        matte.textures = Some(Arc::new(TextureSet::new().with_roughness(ChannelMap {
+            texture: TextureSource::Image(Arc::new(Texture::new_constant(Vec3f::new(0.5, 0.5, 0.5)))),
+            channel: 0,
+        })));
+        let matte_id = scene.add_object(Sphere { center: Vec3f::new(6.0, 0.0, 0.0), radius: 1.0 }, matte).unwrap();
+        // the lobes of the shiny one add up to less than one, this single lobe is overbright
+        let mut bright = WHITE_DIFFUSE;
+        bright.diffuse = Vec3f::new(1.2, 0.5, 0.5);
+        let bright_id = scene.add_object(Sphere { center: Vec3f::new(9.0, 0.0, 0.0), radius: 1.0 }, bright).unwrap();
+
+        let report = scene.validate();
+        // the audit integrates the lobes, lambert exactly
+        let albedo_issues = report.issues.iter().filter_map(|issue| match *issue {
+            SceneIssue::AlbedoAboveOne { material, albedo } => Some((material, albedo)),
+            _ => None
+        }).collect::<Vec<_>>();
+        assert_eq!(albedo_issues.len(), 1);
+        assert_eq!(albedo_issues[0].0, bright_id);
+        assert!((albedo_issues[0].1 - Vec3f::new(1.2, 0.5, 0.5)).norm() < 1e-3, "{:?}", albedo_issues[0].1);
+        let issues = report.issues.iter().filter(|issue| match **issue {
+            SceneIssue::AlbedoAboveOne { .. } => false,
+            _ => true
+        }).cloned().collect::<Vec<_>>();
+        assert_eq!(issues, vec![
+            SceneIssue::NanVertex { surface: SurfaceProperties::Material(mesh_id), vertex: 4 },
+            SceneIssue::DegenerateNormal { surface: SurfaceProperties::Material(mesh_id), face: 1 },
+            SceneIssue::ZeroPowerLight { light: 2 },
+            SceneIssue::UnreferencedTexture { material: shiny_id, map: "normal variance" },
+            SceneIssue::UnreferencedTexture { material: matte_id, map: "roughness" },
+        ]);
+        assert!(report.to_string().contains("light 2 emits nothing"));
+    }
+
+    #[test]
+    fn lights_are_picked_by_power() {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(0.0, 0.0, 0.0) });
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 1.0, 0.0), intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 2.0, 0.0), intensity: Vec3f::new(3.0, 3.0, 3.0) });
+        let p = Vec3f::new(0.0, 0.0, 0.0);
+        assert_eq!(scene.pick_light(&p, 0.9), Some((2, 1.0 / 3.0)));
+
+        scene.set_light_selection(LightSelection::Power);
+        // the black background is never picked
+        assert_eq!(scene.light_pick_prob(&p, 0), 0.0);
+        assert!((scene.light_pick_prob(&p, 2) - 0.75).abs() < 1e-6);
+        assert_eq!(scene.pick_light(&p, 0.0).map(|(light_id, _)| light_id), Some(1));
+        assert_eq!(scene.pick_light(&p, 0.3).map(|(light_id, _)| light_id), Some(2));
+        // an edited light moves the odds once the change is committed
+        scene.set_light_intensity(1, Vec3f::new(3.0, 3.0, 3.0));
+        scene.commit_changes();
+        assert!((scene.light_pick_prob(&p, 1) - 0.5).abs() < 1e-6);
+        // the tree takes the distance too, the black background has no power against it
+        scene.set_light_selection(LightSelection::Tree);
+        assert_eq!(scene.light_pick_prob(&p, 0), 0.0);
+        assert!((scene.light_pick_prob(&p, 1) + scene.light_pick_prob(&p, 2) - 1.0).abs() < 1e-6);
+        assert!(scene.light_pick_prob(&p, 1) > 2.0 * scene.light_pick_prob(&p, 2));
+        // the light paths start from the lights by power, there's no point to pick for
+        assert!((scene.emitter_pick_prob(1) - 0.5).abs() < 1e-6);
+        assert_eq!(scene.pick_emitter(0.0).map(|(light_id, _)| light_id), Some(1));
+
+        // added lights are picked uniformly until the pick is rebuilt, once for all of them
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 3.0, 0.0), intensity: Vec3f::new(6.0, 6.0, 6.0) });
+        assert_eq!(scene.light_pick_prob(&p, 3), 0.25);
+        assert_eq!(scene.pick_emitter(0.9), Some((3, 0.25)));
+        scene.commit_changes();
+        assert!((scene.emitter_pick_prob(3) - 0.5).abs() < 1e-6);
+        assert!(scene.light_pick_prob(&p, 1) > scene.light_pick_prob(&p, 3));
     }
 }
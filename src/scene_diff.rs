@@ -0,0 +1,372 @@
+use brdf::{Material, MicrofacetDistribution};
+use camera::PerspectiveCamera;
+use geometry::Aabb;
+use math::Vec3f;
+use scene::{LightID, MaterialID, Scene};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// A scene as flat `key = value` lines, to find out why two renders differ: `xray --save-scene a.scene`
+// writes the scene as it was built, imports and `--set` overrides included, and `xray --diff-scenes
+// a.scene b.scene` compares two of them. The keys are the ones of `ParamOverride` where there is one:
+// the camera, the seed, the light intensities and the diffuse, specular, phong_exp, roughness and
+// distribution of the materials can be set back with `--set`; the bounds, the metal's eta and k, the
+// glass and the rest are there to be compared only. The objects go under the `materials.N` of the
+// material they were added with, the part of a key before its last dot is the object it is of.
+// The materials and the lights are matched by what they are rather than by their index, an object
+// added or removed in the middle doesn't make all the ones after it differ
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDescription {
+    values: Vec<(String, String)>, // in the order they were written
+}
+
+// what happened to one object (`materials.3`, `lights.1`, `camera`) from the old scene to the new one
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectChange {
+    Added(String),
+    Removed(String),
+    // the fields which differ, with the old and the new value, None where there is no value
+    Modified(String, Vec<(String, Option<String>, Option<String>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDiff {
+    pub changes: Vec<ObjectChange>,
+}
+
+// an object with its fields and their values
+type Object<'a> = (&'a str, Vec<(&'a str, &'a str)>);
+
+impl SceneDescription {
+    pub fn of<S: Scene>(scene: &S, camera: &PerspectiveCamera) -> SceneDescription {
+        let mut description = SceneDescription { values: Vec::new() };
+        description.push("camera.position", vector(&camera.get_position()));
+        description.push("camera.fov", camera.get_fov().to_string());
+        description.push("scene.seed", scene.get_seed().to_string());
+        description.push("scene.light_selection", format!("{:?}", scene.get_light_selection()));
+        for m_id in 0..scene.get_materials_nb() as MaterialID {
+            let prefix = format!("materials.{}", m_id);
+            if let Some(bounds) = scene.get_object_bounds(m_id) {
+                description.push(&format!("{}.bounds", prefix), bounds_value(&bounds));
+            }
+            description.push_material(&prefix, scene.get_material(m_id));
+        }
+        for light_id in 0..scene.get_lights_nb() as LightID {
+            let prefix = format!("lights.{}", light_id);
+            let light = scene.get_light(light_id);
+            description.push(&format!("{}.delta", prefix), light.is_delta().to_string());
+            if let Some(intensity) = light.intensity() {
+                description.push(&format!("{}.intensity", prefix), vector(&intensity));
+            }
+            if let Some(bounds) = light.bounds() {
+                description.push(&format!("{}.bounds", prefix), bounds_value(&bounds));
+            }
+            description.push(&format!("{}.group", prefix), scene.get_light_group(light_id).to_string());
+        }
+        description
+    }
+
+    pub fn parse(text: &str) -> io::Result<SceneDescription> {
+        let mut description = SceneDescription { values: Vec::new() };
+        for (line_nb, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.find('=') {
+                Some(pos) => description.push(line[..pos].trim(), line[pos + 1..].trim().to_string()),
+                None      => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                       format!("line {}: not a key = value", line_nb + 1)))
+            }
+        }
+        Ok(description)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<SceneDescription> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        SceneDescription::parse(&text)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(self.to_string().as_bytes())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref value)| value.as_str())
+    }
+
+    fn push(&mut self, key: &str, value: String) {
+        self.values.push((key.to_string(), value));
+    }
+
+    fn push_material(&mut self, prefix: &str, material: &Material) {
+        let mut field = |name: &str, value: String| self.push(&format!("{}.{}", prefix, name), value);
+        field("diffuse", vector(&material.diffuse));
+        field("specular", vector(&material.specular));
+        field("phong_exp", material.phong_exp.to_string());
+        field("mirror", vector(&material.mirror));
+        if let Some(ref microfacet) = material.microfacet {
+            field("roughness", microfacet.roughness.to_string());
+            field("distribution", match microfacet.distribution {
+                MicrofacetDistribution::Ggx      => "ggx".to_string(),
+                MicrofacetDistribution::Beckmann => "beckmann".to_string()
+            });
+        }
+        if let Some(ref conductor) = material.conductor {
+            field("metal_eta", vector(&conductor.eta));
+            field("metal_k", vector(&conductor.k));
+        }
+        if let Some(ref dielectric) = material.dielectric {
+            field("ior", dielectric.ior.to_string());
+            field("transmittance", vector(&dielectric.transmittance));
+            if let Some(ref rough) = dielectric.rough {
+                field("glass_roughness", rough.roughness.to_string());
+            }
+        }
+        // the measured data and the textures are too big to list, only whether they are there
+        if material.measured.is_some() {
+            field("measured", "yes".to_string());
+        }
+        if material.textures.is_some() {
+            field("textures", "yes".to_string());
+        }
+        if let Some(ref paint) = material.car_paint {
+            field("car_paint", format!("{:?}", paint));
+        }
+        if let Some(ref sheen) = material.sheen {
+            field("sheen", format!("{:?}", sheen));
+        }
+        if let Some(ref dust) = material.dust {
+            field("dust", format!("{:?}", dust));
+        }
+        if let Some(ref dirt) = material.dirt {
+            field("dirt", format!("{:?}", dirt));
+        }
+        if let Some(ref variation) = material.variation {
+            field("variation", format!("{:?}", variation));
+        }
+    }
+
+    // the objects in the order of their first keys, each with its fields
+    fn objects<'a>(&'a self) -> Vec<Object<'a>> {
+        let mut objects: Vec<Object> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for &(ref key, ref value) in &self.values {
+            let (object, field) = match key.rfind('.') {
+                Some(pos) => (&key[..pos], &key[pos + 1..]),
+                None      => ("", key.as_str())
+            };
+            let pos = *positions.entry(object).or_insert_with(|| {
+                objects.push((object, Vec::new()));
+                objects.len() - 1
+            });
+            objects[pos].1.push((field, value.as_str()));
+        }
+        objects
+    }
+}
+
+impl fmt::Display for SceneDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref key, ref value) in &self.values {
+            writeln!(f, "{} = {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl SceneDiff {
+    pub fn between(old: &SceneDescription, new: &SceneDescription) -> SceneDiff {
+        let (old_objects, new_objects) = (old.objects(), new.objects());
+        let pairs = match_objects(&old_objects, &new_objects);
+        let (paired_old, paired_new) = (pairs.iter().cloned().collect::<HashMap<_, _>>(),
+                                        pairs.iter().map(|&(_, n)| n).collect::<HashSet<_>>());
+        let mut changes = Vec::new();
+        for (old_idx, &(name, ref old_fields)) in old_objects.iter().enumerate() {
+            let (new_name, new_fields) = match paired_old.get(&old_idx) {
+                Some(&new_idx) => (new_objects[new_idx].0, new_objects[new_idx].1.as_slice()),
+                None           => { changes.push(ObjectChange::Removed(name.to_string())); continue; }
+            };
+            let mut modified = Vec::new();
+            let names = old_fields.iter().chain(new_fields.iter()).map(|&(field, _)| field);
+            for field in names {
+                let (before, after) = (value_of(old_fields, field), value_of(new_fields, field));
+                if before != after && !modified.iter().any(|&(ref f, _, _)| f == field) {
+                    modified.push((field.to_string(), before, after));
+                }
+            }
+            if !modified.is_empty() {
+                let name = if name == new_name { name.to_string() } else { format!("{} -> {}", name, new_name) };
+                changes.push(ObjectChange::Modified(name, modified));
+            }
+        }
+        for (new_idx, &(name, _)) in new_objects.iter().enumerate() {
+            if !paired_new.contains(&new_idx) {
+                changes.push(ObjectChange::Added(name.to_string()));
+            }
+        }
+        SceneDiff { changes: changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for SceneDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "the scenes are the same");
+        }
+        let or_none = |value: &Option<String>| value.clone().unwrap_or_else(|| "(none)".to_string());
+        for change in &self.changes {
+            match *change {
+                ObjectChange::Added(ref name) => writeln!(f, "+ {}", name)?,
+                ObjectChange::Removed(ref name) => writeln!(f, "- {}", name)?,
+                ObjectChange::Modified(ref name, ref fields) => {
+                    writeln!(f, "~ {}", name)?;
+                    for &(ref field, ref before, ref after) in fields {
+                        writeln!(f, "    {}: {} -> {}", field, or_none(before), or_none(after))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The old and the new object which are the same one, by their indices. The objects of a collection
+// (`materials.N`, `lights.N`) are paired by what they are: first the unchanged ones, then the ones in
+// the same place (their bounds), then what is left of them in their order; the others by their names
+fn match_objects(old: &[Object], new: &[Object]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let (mut old_left, mut new_left) = (vec![true; old.len()], vec![true; new.len()]);
+    let passes: [&Fn(&Object) -> Option<String>; 4] = [
+        &|&(name, _)| if collection(name).is_none() { Some(name.to_string()) } else { None },
+        &|&(name, ref fields)| collection(name).map(|coll| {
+            let mut fields = fields.clone();
+            fields.sort();
+            format!("{} {:?}", coll, fields)
+        }),
+        &|&(name, ref fields)| collection(name).and_then(|coll| value_of(fields, "bounds").map(|bounds| format!("{} {}", coll, bounds))),
+        &|&(name, _)| collection(name).map(String::from),
+    ];
+    for key in passes.iter() {
+        // the new objects left of every key, the first one last
+        let mut unpaired: HashMap<String, Vec<usize>> = HashMap::new();
+        for n in (0..new.len()).rev().filter(|&n| new_left[n]) {
+            if let Some(key) = key(&new[n]) {
+                unpaired.entry(key).or_insert_with(Vec::new).push(n);
+            }
+        }
+        for o in 0..old.len() {
+            if !old_left[o] {
+                continue;
+            }
+            if let Some(n) = key(&old[o]).and_then(|key| unpaired.get_mut(&key).and_then(|left| left.pop())) {
+                pairs.push((o, n));
+                old_left[o] = false;
+                new_left[n] = false;
+            }
+        }
+    }
+    pairs
+}
+
+// `materials` of `materials.3`, None of the objects which aren't numbered
+fn collection(name: &str) -> Option<&str> {
+    name.rfind('.').and_then(|pos| name[pos + 1..].parse::<usize>().ok().map(|_| &name[..pos]))
+}
+
+fn value_of(fields: &[(&str, &str)], field: &str) -> Option<String> {
+    fields.iter().find(|&&(f, _)| f == field).map(|&(_, value)| value.to_string())
+}
+
+// as `ParamOverride` reads the vectors
+fn vector(v: &Vec3f) -> String {
+    format!("{},{},{}", v.x, v.y, v.z)
+}
+
+fn bounds_value(bounds: &Aabb) -> String {
+    format!("{} .. {}", vector(&bounds.min), vector(&bounds.max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ObjectChange, SceneDescription, SceneDiff};
+    use camera::{CameraBuilder, PerspectiveCamera};
+    use geometry::{GeometryList, Sphere};
+    use light::{BackgroundLight, PointLight};
+    use materials_and_colors::{GOLD, WHITE_DIFFUSE};
+    use math::{Vec2u, Vec3f};
+    use overrides::ParamOverride;
+    use scene::{DefaultScene, Scene};
+
+    fn scene_and_camera() -> (DefaultScene<GeometryList>, PerspectiveCamera) {
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        scene.add_object(Sphere { center: Vec3f::new(0.0, 0.0, 0.0), radius: 1.0 }, WHITE_DIFFUSE).unwrap();
+        scene.add_light(PointLight { position: Vec3f::new(0.0, 4.0, 0.0), intensity: Vec3f::new(1.0, 1.0, 1.0) });
+        let cam = CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(Vec2u::new(64, 64))
+            .with_pos(Vec3f::new(0.0, 0.0, -5.0))
+            .with_look_at(Vec3f::new(0.0, 0.0, 1.0))
+            .build();
+        (scene, cam)
+    }
+
+    #[test]
+    fn diff_tells_what_changed_between_two_scenes() {
+        let (scene, cam) = scene_and_camera();
+        let old = SceneDescription::of(&scene, &cam);
+        // written and read back it's the same scene
+        let reread = SceneDescription::parse(&old.to_string()).unwrap();
+        assert_eq!(reread, old);
+        assert!(SceneDiff::between(&old, &reread).is_empty());
+
+        let (mut scene, mut cam) = scene_and_camera();
+        "materials.0.diffuse=0.5,0.25,0".parse::<ParamOverride>().unwrap().apply(&mut scene, &mut cam).unwrap();
+        scene.add_object(Sphere { center: Vec3f::new(3.0, 0.0, 0.0), radius: 1.0 }, GOLD).unwrap();
+        let new = SceneDescription::of(&scene, &cam);
+        let diff = SceneDiff::between(&old, &new);
+        assert_eq!(diff.changes, vec![
+            ObjectChange::Modified("materials.0".to_string(),
+                                   vec![("diffuse".to_string(), old.get("materials.0.diffuse").map(String::from),
+                                         Some("0.5,0.25,0".to_string()))]),
+            ObjectChange::Added("materials.1".to_string()),
+        ]);
+        // and the other way around
+        let back = SceneDiff::between(&new, &old);
+        assert_eq!(back.changes[1], ObjectChange::Removed("materials.1".to_string()));
+        assert!(back.to_string().contains("    diffuse: 0.5,0.25,0 -> "), "{}", back);
+
+        assert!(SceneDescription::parse("camera.fov = 45\nnot a value").is_err());
+    }
+
+    #[test]
+    fn objects_are_matched_by_what_they_are() {
+        let ball = |x: f32| Sphere { center: Vec3f::new(x, 0.0, 0.0), radius: 1.0 };
+        let (mut scene, cam) = scene_and_camera();
+        scene.add_object(ball(3.0), WHITE_DIFFUSE).unwrap();
+        scene.add_object(ball(6.0), WHITE_DIFFUSE).unwrap();
+        let old = SceneDescription::of(&scene, &cam);
+        // the ball in the middle is gone, the last one is gold now
+        let (mut scene, cam) = scene_and_camera();
+        scene.add_object(ball(6.0), GOLD).unwrap();
+        let new = SceneDescription::of(&scene, &cam);
+        let diff = SceneDiff::between(&old, &new);
+        assert_eq!(diff.changes.len(), 2, "{}", diff);
+        assert_eq!(diff.changes[0], ObjectChange::Removed("materials.1".to_string()));
+        match diff.changes[1] {
+            ObjectChange::Modified(ref name, ref fields) => {
+                assert_eq!(name, "materials.2 -> materials.1");
+                assert!(fields.iter().any(|&(ref field, _, _)| field == "diffuse"));
+                assert!(!fields.iter().any(|&(ref field, _, _)| field == "bounds"));
+            },
+            ref change => panic!("{:?}", change)
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use camera::{Camera, PerspectiveCamera};
+use framebuffer::RgbFrameBuffer;
+use math::vector_traits::*;
+use math::{Vec2f, Vec3f};
+use rand::Rng;
+use rand::distributions::normal::StandardNormal;
+use std::f32::consts::PI;
+use utility::seeded_rng;
+
+// the ISO the electron count is given for
+const BASE_ISO: f32 = 100.0;
+// electrons a pixel collects from a radiance of 1 at the base ISO, and the read noise of a modern sensor
+const ELECTRONS_PER_UNIT: f32 = 1000.0;
+const READ_NOISE: f32 = 3.0;
+// below this the photon count is drawn from the Poisson distribution, above it from the normal one
+const POISSON_LIMIT: f32 = 30.0;
+// rng stream of the noise, next to the render ones
+const SENSOR_NOISE_STREAM: u64 = 1 << 58;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Vignetting {
+    // natural falloff of a thin lens, cos^4 of the angle to the optical axis
+    Cos4,
+    // cos^4 and the lens barrel cutting off the aperture: the barrel is a circle of `barrel_radius`
+    // (of the aperture radius) `barrel_distance` (of the aperture radius) away from the aperture,
+    // off the axis its shadow moves by `barrel_distance * tan(angle)`
+    Lens { barrel_radius: f32, barrel_distance: f32 },
+}
+
+impl Vignetting {
+    // fraction of the light reaching the sensor at the angle with the cosine `cos` to the axis
+    pub fn factor(&self, cos: f32) -> f32 {
+        let cos2 = cos * cos;
+        match *self {
+            Vignetting::Cos4 => cos2 * cos2,
+            Vignetting::Lens { barrel_radius, barrel_distance } => {
+                let tan = (1.0 - cos2).max(0.0).sqrt() / cos;
+                let on_axis = PI * barrel_radius.min(1.0).powi(2);
+                cos2 * cos2 * discs_overlap(1.0, barrel_radius, barrel_distance * tan) / on_axis
+            }
+        }
+    }
+}
+
+// Photon shot noise and read noise of a sensor at `iso`: a higher ISO collects fewer electrons
+// for the same brightness, so the frame gets noisier
+#[derive(Debug, Clone, Copy)]
+pub struct SensorNoise {
+    pub iso: f32,
+    pub electrons_per_unit: f32, // at the base ISO
+    pub read_noise: f32, // electrons
+}
+
+impl SensorNoise {
+    pub fn new(iso: f32) -> SensorNoise {
+        SensorNoise {
+            iso: iso,
+            electrons_per_unit: ELECTRONS_PER_UNIT,
+            read_noise: READ_NOISE,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        self.electrons_per_unit * BASE_ISO / self.iso
+    }
+
+    // `value` is radiance, so is the result. Values don't go below zero, as after the black level
+    pub fn apply_to<R: Rng>(&self, value: f32, rng: &mut R) -> f32 {
+        let gain = self.gain();
+        let mean = value.max(0.0) * gain;
+        let StandardNormal(read) = rng.gen::<StandardNormal>();
+        let electrons = sample_photons(mean, rng) + read as f32 * self.read_noise;
+        electrons.max(0.0) / gain
+    }
+}
+
+// Postprocess matching a render to a real camera: vignetting of the lens and noise of the sensor,
+// both optional. Like the lens flare it's applied to a copy of the frame which is shown
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    pub vignetting: Option<Vignetting>,
+    pub noise: Option<SensorNoise>,
+}
+
+impl Sensor {
+    pub fn new() -> Sensor {
+        Sensor {
+            vignetting: None,
+            noise: None,
+        }
+    }
+
+    pub fn with_vignetting(mut self, vignetting: Vignetting) -> Sensor {
+        self.vignetting = Some(vignetting);
+        self
+    }
+
+    pub fn with_noise(mut self, noise: SensorNoise) -> Sensor {
+        self.noise = Some(noise);
+        self
+    }
+
+    // `k` normalizes the frame, as in `to_yxy_inplace`; `seed` picks the noise
+    pub fn apply(&self, camera: &PerspectiveCamera, frame: &mut RgbFrameBuffer, k: f32, seed: u32) {
+        let res = frame.resolution();
+        if let Some(vignetting) = self.vignetting {
+            let size = camera.get_view_size();
+            let axis = camera.ray_from_screen(&Vec2f::new(size.x * 0.5, size.y * 0.5)).dir;
+            // the frame may be of another resolution than the camera's view
+            let scale = Vec2f::new(size.x / res.x as f32, size.y / res.y as f32);
+            for (pix_nb, pix) in frame.as_mut_slice().iter_mut().enumerate() {
+                let raster = Vec2f::new(((pix_nb % res.x) as f32 + 0.5) * scale.x, ((pix_nb / res.x) as f32 + 0.5) * scale.y);
+                let cos = camera.ray_from_screen(&raster).dir.dot(&axis);
+                *pix = *pix * vignetting.factor(cos);
+            }
+        }
+        if let Some(noise) = self.noise {
+            let mut rng = seeded_rng(seed, SENSOR_NOISE_STREAM);
+            for pix in frame.as_mut_slice().iter_mut() {
+                let value = *pix * k;
+                *pix = Vec3f::new(noise.apply_to(value.x, &mut rng),
+                                  noise.apply_to(value.y, &mut rng),
+                                  noise.apply_to(value.z, &mut rng)) / k;
+            }
+        }
+    }
+}
+
+// area of the overlap of two discs with the radii `r0` and `r1` and centers `dist` apart
+fn discs_overlap(r0: f32, r1: f32, dist: f32) -> f32 {
+    if dist >= r0 + r1 {
+        return 0.0;
+    }
+    if dist <= (r0 - r1).abs() {
+        return PI * r0.min(r1).powi(2);
+    }
+    let (r0_2, r1_2, dist_2) = (r0 * r0, r1 * r1, dist * dist);
+    let a0 = ((dist_2 + r0_2 - r1_2) / (2.0 * dist * r0)).max(-1.0).min(1.0).acos();
+    let a1 = ((dist_2 + r1_2 - r0_2) / (2.0 * dist * r1)).max(-1.0).min(1.0).acos();
+    let kite = 0.5 * ((-dist + r0 + r1) * (dist + r0 - r1) * (dist - r0 + r1) * (dist + r0 + r1)).max(0.0).sqrt();
+    r0_2 * a0 + r1_2 * a1 - kite
+}
+
+// electrons of the photons arriving with the `mean` count
+fn sample_photons<R: Rng>(mean: f32, rng: &mut R) -> f32 {
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    if mean > POISSON_LIMIT {
+        let StandardNormal(z) = rng.gen::<StandardNormal>();
+        return mean + z as f32 * mean.sqrt();
+    }
+    // Knuth's: count the uniform numbers whose product stays above e^-mean
+    let limit = (-mean).exp();
+    let mut count = 0;
+    let mut product = rng.next_f32();
+    while product > limit {
+        count += 1;
+        product *= rng.next_f32();
+    }
+    count as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discs_overlap, SensorNoise, Sensor, Vignetting};
+    use camera::{Camera, CameraBuilder, PerspectiveCamera};
+    use math::{Vec2u, Vec3f};
+    use std::f32::consts::PI;
+    use utility::seeded_rng;
+
+    #[test]
+    fn vignetting_darkens_the_corners() {
+        let cam = CameraBuilder::<PerspectiveCamera>::new().with_view_size(Vec2u::new(64, 64)).with_fov(90.0).build();
+        let mut frame = cam.build_rgb_framebuffer();
+        for pix in frame.as_mut_slice().iter_mut() {
+            *pix = Vec3f::new(1.0, 1.0, 1.0);
+        }
+        Sensor::new().with_vignetting(Vignetting::Cos4).apply(&cam, &mut frame, 1.0, 0);
+        // the corner pixel is ~sqrt(2) away from the axis at the unit distance
+        let corner = frame.as_slice()[0].x;
+        let tan = (2.0f32 * (31.5 / 32.0) * (31.5 / 32.0)).sqrt();
+        assert!((corner - 1.0 / (1.0 + tan * tan).powi(2)).abs() < 1e-3, "{}", corner);
+        assert!(frame.as_slice()[32 * 64 + 32].x > 0.99);
+
+        // the barrel cuts off more than cos^4 alone, nothing on the axis
+        let lens = Vignetting::Lens { barrel_radius: 1.2, barrel_distance: 2.0 };
+        assert_eq!(lens.factor(1.0), 1.0);
+        assert!(lens.factor(0.7) < Vignetting::Cos4.factor(0.7));
+        assert!((discs_overlap(1.0, 1.0, 1.0) - (2.0 * PI / 3.0 - 0.75f32.sqrt())).abs() < 1e-4);
+    }
+
+    #[test]
+    fn noise_keeps_the_mean() {
+        let mut rng = seeded_rng(0, 0);
+        let samples = 20000;
+        // a dark pixel at a high ISO: 8 electrons, the Poisson part
+        for &(iso, value) in [(12800.0, 1.024), (100.0, 0.5)].iter() {
+            let noise = SensorNoise::new(iso);
+            let electrons = value * noise.gain();
+            let values = (0..samples).map(|_| noise.apply_to(value, &mut rng) * noise.gain()).collect::<Vec<_>>();
+            let mean = values.iter().fold(0.0, |sum, v| sum + v) / samples as f32;
+            let var = values.iter().fold(0.0, |sum, v| sum + (v - mean) * (v - mean)) / samples as f32;
+            // the clamping at zero shifts the dark mean a little
+            assert!((mean - electrons).abs() < 0.03 * electrons, "{} instead of {}", mean, electrons);
+            let expected_var = electrons + noise.read_noise * noise.read_noise;
+            assert!((var - expected_var).abs() < 0.1 * expected_var, "{} instead of {}", var, expected_var);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use camera::{CameraBuilder, PerspectiveCamera};
+use geometry::{GeometryList, Mesh, MeshInstance, Plane, Sphere};
+use light::BackgroundLight;
+use materials_and_colors::{DAYLIGHT_COLOR, EVENING_COLOR, GREEN_DIFFUSE, MIDDLE_GRAY_DIFFUSE, WHITE_DIFFUSE};
+use math::{Vec2u, Vec3f};
+use rand::Rng;
+use scene::{DefaultScene, Scene};
+use std::io;
+use std::sync::Arc;
+use utility::{seeded_rng, Pcg32};
+
+// Large generated scenes to time the acceleration structures and the light picking with: boxes for the
+// buildings, a box trunk and a sphere crown for the trees and small sphere lamps, spread over a square
+// ground on a grid of cells, one object per cell. The buildings and the trees come in `shapes` sizes,
+// each box mesh is there once and every object is an instance of one of them, so the scene has as many
+// objects as asked for with little memory; the sizes are drawn from the ranges with a fixed seed, the
+// same settings give the same scene
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StressScene {
+    pub seed: u32,
+    pub buildings: usize,
+    pub trees: usize,
+    pub lamps: usize,
+    pub shapes: usize, // different sizes of the buildings and of the trees
+    pub extent: f32, // half of the side of the ground
+    pub building_width: (f32, f32),
+    pub building_height: (f32, f32),
+    pub tree_height: (f32, f32),
+    pub lamp_intensity: (f32, f32),
+}
+
+impl StressScene {
+    pub fn new() -> StressScene {
+        StressScene {
+            seed: 0,
+            buildings: 0,
+            trees: 0,
+            lamps: 0,
+            shapes: 16,
+            extent: 100.0,
+            building_width: (4.0, 12.0),
+            building_height: (5.0, 60.0),
+            tree_height: (3.0, 12.0),
+            lamp_intensity: (20.0, 200.0),
+        }
+    }
+
+    // buildings with a tree for every four and a lamp for every two, on a ground growing with them
+    pub fn city(buildings: usize) -> StressScene {
+        StressScene::new()
+            .with_buildings(buildings)
+            .with_trees(buildings / 4)
+            .with_lamps(buildings / 2)
+            .with_extent(10.0 * (buildings as f32).sqrt().max(1.0))
+    }
+
+    // trees only, denser than the city, with a lamp for every ten
+    pub fn forest(trees: usize) -> StressScene {
+        StressScene::new()
+            .with_trees(trees)
+            .with_lamps(trees / 10)
+            .with_extent(4.0 * (trees as f32).sqrt().max(1.0))
+    }
+
+    pub fn with_seed(mut self, seed: u32) -> StressScene {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_buildings(mut self, buildings: usize) -> StressScene {
+        self.buildings = buildings;
+        self
+    }
+
+    pub fn with_trees(mut self, trees: usize) -> StressScene {
+        self.trees = trees;
+        self
+    }
+
+    pub fn with_lamps(mut self, lamps: usize) -> StressScene {
+        self.lamps = lamps;
+        self
+    }
+
+    pub fn with_shapes(mut self, shapes: usize) -> StressScene {
+        self.shapes = shapes;
+        self
+    }
+
+    pub fn with_extent(mut self, extent: f32) -> StressScene {
+        self.extent = extent;
+        self
+    }
+
+    pub fn with_building_size(mut self, width: (f32, f32), height: (f32, f32)) -> StressScene {
+        self.building_width = width;
+        self.building_height = height;
+        self
+    }
+
+    pub fn with_tree_height(mut self, min: f32, max: f32) -> StressScene {
+        self.tree_height = (min, max);
+        self
+    }
+
+    pub fn with_lamp_intensity(mut self, min: f32, max: f32) -> StressScene {
+        self.lamp_intensity = (min, max);
+        self
+    }
+
+    // number of objects `build_scene` adds, the ground included
+    pub fn objects_nb(&self) -> usize {
+        1 + self.buildings + 2 * self.trees + self.lamps
+    }
+
+    pub fn build_scene(&self) -> io::Result<DefaultScene<GeometryList>> {
+        let to_io = |err: ::memory::OutOfBudget| io::Error::new(io::ErrorKind::Other, err.to_string());
+        let mut scene = DefaultScene::<GeometryList>::new(BackgroundLight { intensity: DAYLIGHT_COLOR * 0.1 });
+        scene.add_object(Plane { point: Vec3f::new(0.0, 0.0, 0.0), normal: Vec3f::new(0.0, 1.0, 0.0) }, WHITE_DIFFUSE)
+            .map_err(&to_io)?;
+
+        // the cells go out in rows, shuffled so the buildings and the trees mix
+        let cells_per_row = ((self.buildings + self.trees) as f32).sqrt().ceil().max(1.0) as usize;
+        let cell = 2.0 * self.extent / cells_per_row as f32;
+        let mut rng = seeded_rng(self.seed, 0);
+        let mut cells = (0..cells_per_row * cells_per_row).collect::<Vec<_>>();
+        rng.shuffle(&mut cells);
+        let cell_center = |idx: usize| Vec3f::new(
+            -self.extent + cell * ((idx % cells_per_row) as f32 + 0.5),
+            0.0,
+            -self.extent + cell * ((idx / cells_per_row) as f32 + 0.5)
+        );
+        let range = |rng: &mut Pcg32, (min, max): (f32, f32)| min + (max - min) * rng.next_f32();
+
+        // the shapes stand on the origin, the objects are instances of them moved to their cells
+        let shapes_nb = |count: usize| self.shapes.max(1).min(count);
+        let mut buildings = Vec::with_capacity(shapes_nb(self.buildings));
+        for _ in 0..shapes_nb(self.buildings) {
+            // no wider than the cell, the street between the blocks stays
+            let width = range(&mut rng, self.building_width).min(0.8 * cell);
+            let depth = range(&mut rng, self.building_width).min(0.8 * cell);
+            let height = range(&mut rng, self.building_height);
+            buildings.push(Arc::new(box_mesh(Vec3f::new(-0.5 * width, 0.0, -0.5 * depth),
+                                             Vec3f::new(0.5 * width, height, 0.5 * depth))?));
+        }
+        let mut trees = Vec::with_capacity(shapes_nb(self.trees));
+        for _ in 0..shapes_nb(self.trees) {
+            let height = range(&mut rng, self.tree_height);
+            let crown = (0.3 * height).min(0.45 * cell);
+            let trunk = 0.05 * height;
+            let trunk_mesh = box_mesh(Vec3f::new(-trunk, 0.0, -trunk), Vec3f::new(trunk, height - crown, trunk))?;
+            trees.push((Arc::new(trunk_mesh), height, crown));
+        }
+
+        for &idx in cells.iter().take(self.buildings) {
+            let building = buildings[rng.gen_range(0, buildings.len())].clone();
+            scene.add_object(MeshInstance::new(building, cell_center(idx)), MIDDLE_GRAY_DIFFUSE)
+                .map_err(&to_io)?;
+        }
+        for &idx in cells.iter().skip(self.buildings).take(self.trees) {
+            let (ref trunk, height, crown) = trees[rng.gen_range(0, trees.len())];
+            let center = cell_center(idx);
+            scene.add_object(MeshInstance::new(trunk.clone(), center), MIDDLE_GRAY_DIFFUSE)
+                .map_err(&to_io)?;
+            scene.add_object(Sphere { center: center + Vec3f::new(0.0, height - crown, 0.0), radius: crown }, GREEN_DIFFUSE)
+                .map_err(&to_io)?;
+        }
+        // anywhere over the ground, some of them inside of the buildings as in any real scene
+        for _ in 0..self.lamps {
+            let pos = Vec3f::new(range(&mut rng, (-self.extent, self.extent)),
+                                 range(&mut rng, (3.0, 6.0)),
+                                 range(&mut rng, (-self.extent, self.extent)));
+            let intensity = range(&mut rng, self.lamp_intensity);
+            scene.add_luminous_object(Sphere { center: pos, radius: 0.3 }, EVENING_COLOR * intensity)
+                .map_err(&to_io)?;
+        }
+        Ok(scene)
+    }
+
+    // from above a corner of the ground, looking down to its center
+    pub fn camera(&self, res: Vec2u) -> PerspectiveCamera {
+        CameraBuilder::<PerspectiveCamera>::new()
+            .with_view_size(res)
+            .with_pos(Vec3f::new(-self.extent, 0.6 * self.extent, -self.extent))
+            .with_look_at(Vec3f::new(1.0, -0.45, 1.0))
+            .with_up(Vec3f::new(0.0, 1.0, 0.0))
+            .with_fov(60.0)
+            .with_znear(0.1)
+            .with_zfar(10.0 * self.extent)
+            .build()
+    }
+}
+
+// closed box between the two corners, faces turned outwards
+fn box_mesh(min: Vec3f, max: Vec3f) -> io::Result<Mesh> {
+    let corner = |i: usize| Vec3f::new(if i & 1 == 0 { min.x } else { max.x },
+                                       if i & 2 == 0 { min.y } else { max.y },
+                                       if i & 4 == 0 { min.z } else { max.z });
+    let faces = vec![
+        [0, 2, 3], [0, 3, 1], // -z
+        [4, 5, 7], [4, 7, 6], // +z
+        [0, 4, 6], [0, 6, 2], // -x
+        [1, 3, 7], [1, 7, 5], // +x
+        [0, 1, 5], [0, 5, 4], // -y
+        [2, 6, 7], [2, 7, 3], // +y
+    ];
+    Mesh::new((0..8).map(corner).collect(), faces, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StressScene;
+    use geometry::Ray;
+    use math::Vec3f;
+    use scene::Scene;
+
+    #[test]
+    fn city_has_the_objects_asked_for() {
+        let city = StressScene::city(50).with_seed(3);
+        let scene = city.build_scene().unwrap();
+        assert_eq!(scene.get_lights_nb(), 25 + 1);
+        assert_eq!(scene.get_materials_nb(), city.objects_nb() - city.lamps);
+        // the same seed builds the same scene: a ray down the middle hits at the same place
+        let ray = Ray { orig: Vec3f::new(0.5, 200.0, 0.3), dir: Vec3f::new(0.0, -1.0, 0.0) };
+        let dist = scene.nearest_intersection(&ray).unwrap().dist;
+        assert_eq!(city.build_scene().unwrap().nearest_intersection(&ray).unwrap().dist, dist);
+        // a single shape for all of them is as many objects still
+        assert_eq!(city.with_shapes(0).build_scene().unwrap().get_materials_nb(), scene.get_materials_nb());
+    }
+}
@@ -0,0 +1,218 @@
+use math::Vec3f;
+use memory;
+use render::{TileAbort, Watchdog};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use utility::luminance;
+
+const RATE_SMOOTHING: f32 = 0.2;
+
+// what a monitoring client wants to know about a running render
+#[derive(Debug, Clone)]
+pub struct RenderMetrics {
+    pub spp: u32,
+    pub samples_per_second: f32, // camera samples over all pixels, smoothed over iterations
+    pub elapsed: Duration,
+    pub relative_error: Option<f32>, // estimated mean relative error of the image
+    pub eta: Option<Duration>, // until relative_error gets down to the target
+    pub memory_used: usize,
+    pub memory_budget: usize,
+    pub aborted_tiles: usize,
+    pub aborted_paths: usize,
+}
+
+// Tracks throughput and convergence of a progressive render.
+// Monte Carlo error falls as 1 / sqrt(spp): comparing the image at spp and 2 * spp
+// gives the constant of that law, which predicts how many samples the target error needs
+#[derive(Debug)]
+pub struct Telemetry {
+    pixels_nb: usize,
+    target_error: f32,
+    start: Instant,
+    last_iteration: Instant,
+    spp: u32,
+    samples_per_second: f32,
+    snapshot: Option<(u32, Vec<f32>)>, // luminance of the estimate and its spp
+    error_constant: Option<f32>, // relative error * sqrt(spp)
+    tile_aborts: Vec<TileAbort>,
+    aborted_paths: usize,
+}
+
+impl Telemetry {
+    pub fn new(pixels_nb: usize, target_error: f32) -> Telemetry {
+        let now = Instant::now();
+        Telemetry {
+            pixels_nb: pixels_nb,
+            target_error: target_error,
+            start: now,
+            last_iteration: now,
+            spp: 0,
+            samples_per_second: 0.0,
+            snapshot: None,
+            error_constant: None,
+            tile_aborts: Vec::new(),
+            aborted_paths: 0,
+        }
+    }
+
+    // `sum` gives the sum of all samples of a pixel so far, like the renderers accumulate it
+    pub fn record_iteration(&mut self, spp_added: u32, sum: &Fn(usize) -> Vec3f) {
+        let now = Instant::now();
+        let seconds = duration_to_secs(now.duration_since(self.last_iteration));
+        self.last_iteration = now;
+        self.spp += spp_added;
+
+        if seconds > 0.0 {
+            let rate = (spp_added as usize * self.pixels_nb) as f32 / seconds;
+            self.samples_per_second = if self.samples_per_second == 0.0 {
+                rate
+            } else {
+                self.samples_per_second * (1.0 - RATE_SMOOTHING) + rate * RATE_SMOOTHING
+            };
+        }
+
+        let due = self.snapshot.as_ref().map_or(true, |&(spp, _)| self.spp >= 2 * spp);
+        if due && self.spp > 0 {
+            let k = 1.0 / self.spp as f32;
+            let current = (0..self.pixels_nb).map(|pix| luminance(&sum(pix)) * k).collect::<Vec<_>>();
+            if let Some((prev_spp, ref prev)) = self.snapshot {
+                self.error_constant = relative_difference(prev, &current).map(|diff| {
+                    // both estimates share prev_spp samples, the rest are independent: the difference
+                    // has the variance of a prev_spp estimate scaled by (1 - prev_spp / spp)
+                    let share = 1.0 - prev_spp as f32 / self.spp as f32;
+                    diff / share.sqrt() * (prev_spp as f32).sqrt()
+                });
+            }
+            self.snapshot = Some((self.spp, current));
+        }
+    }
+
+    // takes what the watchdog aborted, the workers only record it
+    pub fn record_aborts(&mut self, watchdog: &Watchdog) {
+        self.tile_aborts.extend(watchdog.take_tile_aborts());
+        self.aborted_paths = watchdog.aborted_paths();
+    }
+
+    pub fn tile_aborts(&self) -> &[TileAbort] {
+        &self.tile_aborts
+    }
+
+    pub fn metrics(&self) -> RenderMetrics {
+        let relative_error = self.error_constant.map(|c| c / (self.spp.max(1) as f32).sqrt());
+        let eta = self.error_constant.and_then(|c| {
+            if self.samples_per_second <= 0.0 {
+                return None;
+            }
+            let spp_needed = (c / self.target_error).powi(2);
+            let samples_left = (spp_needed - self.spp as f32).max(0.0) * self.pixels_nb as f32;
+            Some(secs_to_duration(samples_left / self.samples_per_second))
+        });
+        RenderMetrics {
+            spp: self.spp,
+            samples_per_second: self.samples_per_second,
+            elapsed: self.start.elapsed(),
+            relative_error: relative_error,
+            eta: eta,
+            memory_used: memory::global().total_used(),
+            memory_budget: memory::global().get_budget(),
+            aborted_tiles: self.tile_aborts.len(),
+            aborted_paths: self.aborted_paths,
+        }
+    }
+}
+
+impl RenderMetrics {
+    // Prometheus text exposition format, unknown values are left out
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: f64| {
+            writeln!(out, "# HELP xray_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE xray_{} gauge", name).unwrap();
+            writeln!(out, "xray_{} {}", name, value).unwrap();
+        };
+        gauge("spp", "Samples per pixel rendered so far.", self.spp as f64);
+        gauge("samples_per_second", "Camera samples per second.", self.samples_per_second as f64);
+        gauge("elapsed_seconds", "Time since the render started.", duration_to_secs(self.elapsed) as f64);
+        if let Some(error) = self.relative_error {
+            gauge("relative_error", "Estimated mean relative error of the image.", error as f64);
+        }
+        if let Some(eta) = self.eta {
+            gauge("eta_seconds", "Estimated time until the target error.", duration_to_secs(eta) as f64);
+        }
+        gauge("memory_used_bytes", "Memory accounted in the memory budget.", self.memory_used as f64);
+        if self.memory_budget != usize::max_value() {
+            gauge("memory_budget_bytes", "Memory budget.", self.memory_budget as f64);
+        }
+        gauge("aborted_tiles", "Tiles the watchdog cut short.", self.aborted_tiles as f64);
+        gauge("aborted_paths", "Paths the watchdog terminated.", self.aborted_paths as f64);
+        out
+    }
+}
+
+// mean absolute difference relative to the mean value
+fn relative_difference(a: &[f32], b: &[f32]) -> Option<f32> {
+    let mean = b.iter().fold(0.0, |sum, v| sum + v) / b.len() as f32;
+    if mean <= 0.0 {
+        return None;
+    }
+    let diff = a.iter().zip(b.iter()).fold(0.0, |sum, (x, y)| sum + (x - y).abs()) / b.len() as f32;
+    Some(diff / mean)
+}
+
+pub fn duration_to_secs(d: Duration) -> f32 {
+    d.as_secs() as f32 + d.subsec_nanos() as f32 * 1e-9
+}
+
+fn secs_to_duration(secs: f32) -> Duration {
+    Duration::from_millis((secs.max(0.0) * 1000.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Telemetry;
+    use framebuffer::RgbFrameBuffer;
+    use math::{Vec2u, Vec3f};
+    use rand::Rng;
+    use std::f32::consts::PI;
+    use utility::seeded_rng;
+
+    #[test]
+    fn error_estimate_falls_with_spp() {
+        let mut frame = RgbFrameBuffer::new(Vec2u::new(2, 1));
+        let mut telemetry = Telemetry::new(2, 0.01);
+        // noisy pixels converging to 0.5
+        let samples = [0.9, 0.1, 0.7, 0.3, 0.6, 0.5, 0.8, 0.2];
+        for (i, &s) in samples.iter().enumerate() {
+            frame.add_color((0, 0), Vec3f::new(s, s, s));
+            frame.add_color((1, 0), Vec3f::new(1.0 - s, 1.0 - s, 1.0 - s));
+            telemetry.record_iteration(1, &|pix| frame.as_slice()[pix]);
+            if i == 1 {
+                assert!(telemetry.metrics().relative_error.is_some());
+            }
+        }
+        let metrics = telemetry.metrics();
+        assert_eq!(metrics.spp, 8);
+        assert!(metrics.relative_error.unwrap() > 0.0);
+        assert!(metrics.to_prometheus().contains("xray_spp 8\n"));
+    }
+
+    #[test]
+    fn error_estimate_matches_the_noise() {
+        // one uniform number per pixel and sample: the mean absolute error of n of them is about
+        // sqrt(2 / pi) / sqrt(12 n), relative to the mean of 0.5
+        let pixels_nb = 4096;
+        let mut frame = RgbFrameBuffer::new(Vec2u::new(pixels_nb, 1));
+        let mut telemetry = Telemetry::new(pixels_nb, 0.01);
+        let mut rng = seeded_rng(3, 0);
+        for _ in 0..16 {
+            for x in 0..pixels_nb {
+                let s = rng.next_f32();
+                frame.add_color((x, 0), Vec3f::new(s, s, s));
+            }
+            telemetry.record_iteration(1, &|pix| frame.as_slice()[pix]);
+        }
+        let expected = (2.0 / PI).sqrt() / 12f32.sqrt() / 0.5 / 4.0;
+        let error = telemetry.metrics().relative_error.unwrap();
+        assert!((error - expected).abs() < 0.1 * expected, "{} instead of {}", error, expected);
+    }
+}
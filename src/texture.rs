@@ -0,0 +1,324 @@
+use math::{Vec2f, Vec3f, Zero};
+use memory::{self, MemoryCategory, Reservation};
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::mem;
+use std::path::Path;
+
+const UDIM_FIRST_TILE: u32 = 1001;
+const UDIM_COLUMNS: u32 = 10;
+const UDIM_MAX_TILE: u32 = 1100;
+
+// rgb image sampled with bilinear filtering and wrapping uv,
+// or a set of UDIM tiles each covering one unit square of uv space
+pub struct Texture {
+    tiles: Vec<Tile>, // sorted by udim
+    udim: bool,
+    _memory: Reservation<'static>,
+}
+
+struct Tile {
+    udim: u32,
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3f>,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, texels: Vec<Vec3f>) -> io::Result<Texture> {
+        let tile = Tile::new(UDIM_FIRST_TILE, width, height, texels)?;
+        Texture::from_tiles(vec![tile], false)
+    }
+
+    fn from_tiles(tiles: Vec<Tile>, udim: bool) -> io::Result<Texture> {
+        let bytes = tiles.iter().fold(0, |sum, tile| sum + tile.texels.len() * mem::size_of::<Vec3f>());
+        let memory = memory::global().reserve(MemoryCategory::Texture, bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(Texture { tiles: tiles, udim: udim, _memory: memory })
+    }
+
+    pub fn new_constant(color: Vec3f) -> Texture {
+        Texture::new(1, 1, vec![color]).expect("1x1 texture doesn't fit into memory budget")
+    }
+
+    // binary netpbm: P6 (8 or 16 bit, converted from sRGB if `srgb`) and PF/Pf float maps. A path with
+    // a <UDIM> token in place of the tile number, like "albedo.<UDIM>.ppm", loads the tiles of a UDIM set
+    pub fn load<P: AsRef<Path>>(path: P, srgb: bool) -> io::Result<Texture> {
+        let path = path.as_ref();
+        match path.to_str() {
+            Some(pattern) if pattern.contains("<UDIM>") => Texture::load_udim(pattern, srgb),
+            _ => Texture::from_tiles(vec![load_tile(path, srgb, UDIM_FIRST_TILE)?], false)
+        }
+    }
+
+    // tiles which aren't on disk are black; the memory is reserved once, for all of them
+    fn load_udim(pattern: &str, srgb: bool) -> io::Result<Texture> {
+        let mut tiles = Vec::new();
+        for udim in UDIM_FIRST_TILE..UDIM_MAX_TILE + 1 {
+            let path = pattern.replace("<UDIM>", &udim.to_string());
+            if Path::new(&path).exists() {
+                tiles.push(load_tile(Path::new(&path), srgb, udim)?);
+            }
+        }
+        if tiles.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no UDIM tiles for {:?}", pattern)));
+        }
+        Texture::from_tiles(tiles, true)
+    }
+
+    pub fn width(&self) -> usize {
+        self.tiles[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.tiles[0].height
+    }
+
+    pub fn texel(&self, x: usize, y: usize) -> Vec3f {
+        self.tiles[0].texel(x, y)
+    }
+
+    // v goes up the image like in OpenGL, so the first row of the file is v = 1
+    pub fn lookup(&self, uv: &Vec2f) -> Vec3f {
+        if !self.udim {
+            return self.tiles[0].lookup(uv);
+        }
+        let (u_tile, v_tile) = (uv.x.floor(), uv.y.floor());
+        if u_tile < 0.0 || v_tile < 0.0 || u_tile >= UDIM_COLUMNS as f32 {
+            return Vec3f::zero();
+        }
+        let udim = UDIM_FIRST_TILE + u_tile as u32 + UDIM_COLUMNS * v_tile as u32;
+        match self.tiles.binary_search_by_key(&udim, |tile| tile.udim) {
+            Ok(idx) => self.tiles[idx].lookup(&Vec2f::new(uv.x - u_tile, uv.y - v_tile)),
+            Err(_)  => Vec3f::zero()
+        }
+    }
+
+    // the same texture with every tile made into another one by `f`, from its size and its texels row by row
+    pub fn map_tiles(&self, f: &Fn(usize, usize, &[Vec3f]) -> (usize, usize, Vec<Vec3f>)) -> io::Result<Texture> {
+        let tiles = self.tiles.iter().map(|tile| {
+            let (width, height, texels) = f(tile.width, tile.height, &tile.texels);
+            Tile::new(tile.udim, width, height, texels)
+        }).collect::<io::Result<Vec<_>>>()?;
+        Texture::from_tiles(tiles, self.udim)
+    }
+
+    // the next level of a mip chain: every texel is the average of 2x2 of these, an odd last
+    // column or row is averaged into the one before it; every UDIM tile on its own
+    pub fn downsampled(&self) -> io::Result<Texture> {
+        self.map_tiles(&|width, height, texels| downsample(width, height, texels, &|_| 1.0))
+    }
+
+    // `downsampled` for a lat-long map: the texels are weighted by the solid angle of their row, rows
+    // near the poles cover less of the sphere. The weights add up, a level has the solid angle average
+    pub fn downsampled_latlong(&self) -> io::Result<Texture> {
+        self.map_tiles(&|width, height, texels| {
+            let rows = height as f32;
+            downsample(width, height, texels, &|row| (row as f32 / rows * PI).cos() - ((row + 1) as f32 / rows * PI).cos())
+        })
+    }
+}
+
+impl Tile {
+    fn new(udim: u32, width: usize, height: usize, texels: Vec<Vec3f>) -> io::Result<Tile> {
+        if width == 0 || height == 0 || texels.len() != width * height {
+            return Err(invalid_data(format!("{} texels don't make a {}x{} texture",
+                                            texels.len(), width, height)));
+        }
+        Ok(Tile { udim: udim, width: width, height: height, texels: texels })
+    }
+
+    fn texel(&self, x: usize, y: usize) -> Vec3f {
+        self.texels[(y % self.height) * self.width + x % self.width]
+    }
+
+    // filtering wraps around the tile, UDIM seams need a texel of padding like in any baker
+    fn lookup(&self, uv: &Vec2f) -> Vec3f {
+        let x = uv.x * self.width as f32 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let wrap = |c: f32, size: usize| (c as i64).rem_euclid(size as i64) as usize;
+        let (x0, y0) = (wrap(x0, self.width), wrap(y0, self.height));
+        let (x1, y1) = (x0 + 1, y0 + 1);
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x1, y0) * fx;
+        let bottom = self.texel(x0, y1) * (1.0 - fx) + self.texel(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn downsample(width: usize, height: usize, texels: &[Vec3f], row_weight: &Fn(usize) -> f32) -> (usize, usize, Vec<Vec3f>) {
+    let (half_width, half_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut sums = vec![Vec3f::zero(); half_width * half_height];
+    let mut weights = vec![0.0f32; half_width * half_height];
+    for y in 0..height {
+        let weight = row_weight(y);
+        for x in 0..width {
+            let idx = (y / 2).min(half_height - 1) * half_width + (x / 2).min(half_width - 1);
+            sums[idx] = sums[idx] + texels[y * width + x] * weight;
+            weights[idx] += weight;
+        }
+    }
+    (half_width, half_height, sums.iter().zip(weights.iter()).map(|(sum, weight)| *sum / *weight).collect())
+}
+
+impl fmt::Debug for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.udim {
+            write!(f, "Texture {{ {} UDIM tiles }}", self.tiles.len())
+        } else {
+            write!(f, "Texture {{ {}x{} }}", self.width(), self.height())
+        }
+    }
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// whitespace separated header token, comments start with '#'
+fn read_token<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut token = String::new();
+    let mut byte = [0u8; 1];
+    loop {
+        reader.read_exact(&mut byte)?;
+        let c = byte[0] as char;
+        if c == '#' && token.is_empty() {
+            let mut comment = String::new();
+            reader.read_line(&mut comment)?;
+        } else if c.is_whitespace() {
+            if !token.is_empty() {
+                return Ok(token);
+            }
+        } else {
+            token.push(c);
+        }
+    }
+}
+
+fn read_number<R: BufRead, T: ::std::str::FromStr>(reader: &mut R) -> io::Result<T> {
+    let token = read_token(reader)?;
+    token.parse().map_err(|_| invalid_data(format!("bad image header value {:?}", token)))
+}
+
+fn load_tile(path: &Path, srgb: bool, udim: u32) -> io::Result<Tile> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let magic = read_token(&mut reader)?;
+    let (width, height, texels) = match magic.as_str() {
+        "P6"        => load_ppm(&mut reader, srgb)?,
+        "PF" | "Pf" => load_pfm(&mut reader, magic == "PF")?,
+        _           => return Err(invalid_data(format!("unsupported image format {:?}", magic)))
+    };
+    Tile::new(udim, width, height, texels)
+}
+
+fn load_ppm<R: BufRead>(reader: &mut R, srgb: bool) -> io::Result<(usize, usize, Vec<Vec3f>)> {
+    let width: usize = read_number(reader)?;
+    let height: usize = read_number(reader)?;
+    let max_val: u32 = read_number(reader)?;
+    if max_val == 0 || max_val > 65535 {
+        return Err(invalid_data(format!("bad max value {}", max_val)));
+    }
+    let bytes_per_value = if max_val < 256 { 1 } else { 2 };
+    let mut raw = vec![0u8; width * height * 3 * bytes_per_value];
+    reader.read_exact(&mut raw)?;
+
+    let value = |i: usize| -> f32 {
+        let v = if bytes_per_value == 1 {
+            raw[i] as u32
+        } else {
+            (raw[2 * i] as u32) << 8 | raw[2 * i + 1] as u32
+        };
+        let v = v as f32 / max_val as f32;
+        if srgb { srgb_to_linear(v) } else { v }
+    };
+    let texels = (0..width * height)
+        .map(|t| Vec3f::new(value(3 * t), value(3 * t + 1), value(3 * t + 2)))
+        .collect();
+    Ok((width, height, texels))
+}
+
+fn load_pfm<R: BufRead>(reader: &mut R, rgb: bool) -> io::Result<(usize, usize, Vec<Vec3f>)> {
+    let width: usize = read_number(reader)?;
+    let height: usize = read_number(reader)?;
+    let scale: f32 = read_number(reader)?;
+    let channels = if rgb { 3 } else { 1 };
+    let mut raw = vec![0u8; width * height * channels * 4];
+    reader.read_exact(&mut raw)?;
+
+    let value = |i: usize| -> f32 {
+        let b = [raw[4 * i], raw[4 * i + 1], raw[4 * i + 2], raw[4 * i + 3]];
+        // negative scale means little endian
+        if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+    };
+    // pfm rows go from the bottom to the top
+    let mut texels = vec![Vec3f::zero(); width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = (height - 1 - y) * width + x;
+            let src = (y * width + x) * channels;
+            texels[i] = if rgb {
+                Vec3f::new(value(src), value(src + 1), value(src + 2))
+            } else {
+                let v = value(src);
+                Vec3f::new(v, v, v)
+            };
+        }
+    }
+    Ok((width, height, texels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Texture, Tile};
+    use math::{Vec2f, Vec3f};
+
+    #[test]
+    fn bilinear_lookup_wraps() {
+        let black = Vec3f::new(0.0, 0.0, 0.0);
+        let white = Vec3f::new(1.0, 1.0, 1.0);
+        let tex = Texture::new(2, 1, vec![black, white]).unwrap();
+        assert_eq!(tex.lookup(&Vec2f::new(0.25, 0.5)).x, 0.0);
+        assert_eq!(tex.lookup(&Vec2f::new(0.75, 0.5)).x, 1.0);
+        assert!((tex.lookup(&Vec2f::new(1.0, 0.5)).x - 0.5).abs() < 1e-6);
+        assert!((tex.lookup(&Vec2f::new(0.0, 0.5)).x - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn downsampling_keeps_the_average() {
+        let texels = (0..5 * 3).map(|i| Vec3f::new(i as f32, 1.0, 0.0)).collect::<Vec<_>>();
+        let texture = Texture::new(5, 3, texels).unwrap();
+        let half = texture.downsampled().unwrap();
+        assert_eq!((half.width(), half.height()), (2, 1));
+        // the first texel takes columns 0 and 1 of every row, the second the other three
+        assert_eq!(half.texel(0, 0), Vec3f::new((0.0 + 1.0 + 5.0 + 6.0 + 10.0 + 11.0) / 6.0, 1.0, 0.0));
+        assert_eq!(half.texel(1, 0).x, (2.0 + 3.0 + 4.0 + 7.0 + 8.0 + 9.0 + 12.0 + 13.0 + 14.0) / 9.0);
+
+        // every UDIM tile is a chain of its own
+        let red = Tile::new(1001, 2, 2, vec![Vec3f::new(1.0, 0.0, 0.0); 4]).unwrap();
+        let green = Tile::new(1002, 4, 2, vec![Vec3f::new(0.0, 1.0, 0.0); 8]).unwrap();
+        let half = Texture::from_tiles(vec![red, green], true).unwrap().downsampled().unwrap();
+        assert_eq!(half.lookup(&Vec2f::new(0.5, 0.5)), Vec3f::new(1.0, 0.0, 0.0));
+        assert_eq!(half.lookup(&Vec2f::new(1.5, 0.5)), Vec3f::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn udim_tiles_cover_their_uv_squares() {
+        let red = Tile::new(1001, 1, 1, vec![Vec3f::new(1.0, 0.0, 0.0)]).unwrap();
+        let green = Tile::new(1012, 1, 1, vec![Vec3f::new(0.0, 1.0, 0.0)]).unwrap();
+        let tex = Texture::from_tiles(vec![red, green], true).unwrap();
+        assert_eq!(tex.lookup(&Vec2f::new(0.5, 0.5)), Vec3f::new(1.0, 0.0, 0.0));
+        assert_eq!(tex.lookup(&Vec2f::new(1.5, 1.5)), Vec3f::new(0.0, 1.0, 0.0));
+        assert_eq!(tex.lookup(&Vec2f::new(1.5, 0.5)), Vec3f::new(0.0, 0.0, 0.0));
+    }
+}
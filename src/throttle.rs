@@ -0,0 +1,260 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::usize;
+
+// utilization within the target +- this is left alone
+const UTILIZATION_SLACK: f32 = 0.05;
+// degrees under the limit the temperature has to fall before the slots come back
+const THERMAL_HYSTERESIS: f32 = 5.0;
+
+// Caps the tiles of the screen loops rendering at once. The worker threads are the pool's, they can't
+// be stopped, but a tile over the cap waits for a slot, so the threads beyond it sleep instead of
+// rendering. Unlimited until `set_limit` is called, see `ThreadScaling` for who calls it
+pub struct ThreadThrottle {
+    limit: AtomicUsize,
+    active: Mutex<usize>,
+    freed: Condvar, // a slot was given back or the limit went up
+}
+
+// a tile rendering, the slot is given back when it's dropped
+pub struct ThrottleSlot<'a> {
+    owner: &'a ThreadThrottle,
+}
+
+static GLOBAL_THROTTLE: ThreadThrottle = ThreadThrottle {
+    limit: AtomicUsize::new(usize::MAX),
+    active: Mutex::new(0),
+    freed: Condvar::new(),
+};
+
+// process wide throttle, the screen loops take their slots from it
+pub fn global() -> &'static ThreadThrottle {
+    &GLOBAL_THROTTLE
+}
+
+impl ThreadThrottle {
+    pub fn new(limit: usize) -> ThreadThrottle {
+        ThreadThrottle { limit: AtomicUsize::new(limit), active: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    // at least one tile always renders
+    pub fn set_limit(&self, slots: usize) {
+        // under the lock, a tile between its check of the limit and its wait would miss the wake up
+        let _active = self.active.lock().unwrap();
+        self.limit.store(slots.max(1), Ordering::SeqCst);
+        self.freed.notify_all();
+    }
+
+    pub fn get_limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    pub fn active(&self) -> usize {
+        *self.active.lock().unwrap()
+    }
+
+    // waits until fewer tiles than the limit render
+    pub fn acquire<'a>(&'a self) -> ThrottleSlot<'a> {
+        let mut active = self.active.lock().unwrap();
+        while *active >= self.get_limit() {
+            active = self.freed.wait(active).unwrap();
+        }
+        *active += 1;
+        ThrottleSlot { owner: self }
+    }
+}
+
+impl<'a> Drop for ThrottleSlot<'a> {
+    fn drop(&mut self) {
+        *self.owner.active.lock().unwrap() -= 1;
+        self.owner.freed.notify_one();
+    }
+}
+
+// Moves the limit of a throttle between iterations, one slot at a time: down while the whole machine is
+// busier than `target_utilization` (the render's share plus everything else the user runs), up while it's
+// idler, and halved while the hottest thermal zone is over `thermal_limit` degrees
+#[derive(Debug, Clone)]
+pub struct ThreadScaling {
+    pub max_threads: usize,
+    pub target_utilization: Option<f32>, // of all the cpus, in [0, 1]
+    pub thermal_limit: Option<f32>, // celsius
+    slots: usize,
+    last_cpu_times: Option<CpuTimes>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
+}
+
+impl ThreadScaling {
+    pub fn new(max_threads: usize) -> ThreadScaling {
+        ThreadScaling {
+            max_threads: max_threads.max(1),
+            target_utilization: None,
+            thermal_limit: None,
+            slots: max_threads.max(1),
+            last_cpu_times: None,
+        }
+    }
+
+    pub fn with_target_utilization(mut self, utilization: f32) -> ThreadScaling {
+        self.target_utilization = Some(utilization.max(0.0).min(1.0));
+        self
+    }
+
+    pub fn with_thermal_limit(mut self, celsius: f32) -> ThreadScaling {
+        self.thermal_limit = Some(celsius);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.target_utilization.is_some() || self.thermal_limit.is_some()
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    // reads the sensors of the OS and sets the new limit of `throttle`
+    pub fn update(&mut self, throttle: &ThreadThrottle) {
+        let utilization = read_cpu_times().and_then(|times| {
+            let last = mem::replace(&mut self.last_cpu_times, Some(times));
+            last.and_then(|last| utilization_between(last, times))
+        });
+        let temperature = self.thermal_limit.and_then(|_| read_temperature());
+        self.slots = self.next_slots(utilization, temperature);
+        throttle.set_limit(self.slots);
+    }
+
+    // the slots after one step of the policy, the sensors which couldn't be read are None
+    pub fn next_slots(&self, utilization: Option<f32>, temperature: Option<f32>) -> usize {
+        if let (Some(limit), Some(temperature)) = (self.thermal_limit, temperature) {
+            if temperature >= limit {
+                return (self.slots / 2).max(1);
+            }
+            if temperature > limit - THERMAL_HYSTERESIS {
+                return self.slots;
+            }
+        }
+        let slots = match (self.target_utilization, utilization) {
+            (Some(target), Some(utilization)) if utilization > target + UTILIZATION_SLACK => self.slots.saturating_sub(1),
+            (Some(target), Some(utilization)) if utilization < target - UTILIZATION_SLACK => self.slots + 1,
+            (Some(_), _) => self.slots,
+            (None, _)    => self.slots + 1 // only cooling down
+        };
+        slots.max(1).min(self.max_threads)
+    }
+}
+
+// the share of the cpu time between the two readings which wasn't idle
+pub fn utilization_between(before: CpuTimes, after: CpuTimes) -> Option<f32> {
+    let total = after.total.saturating_sub(before.total);
+    if total == 0 { None } else { Some(after.busy.saturating_sub(before.busy) as f32 / total as f32) }
+}
+
+// the first line of /proc/stat, the times of all the cpus together; None where there's no procfs
+pub fn read_cpu_times() -> Option<CpuTimes> {
+    let mut stat = String::new();
+    File::open("/proc/stat").and_then(|mut file| file.read_to_string(&mut stat)).ok()?;
+    parse_cpu_times(&stat)
+}
+
+pub fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().next().filter(|line| line.starts_with("cpu "))?;
+    let times = line.split_whitespace().skip(1).map(|time| time.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+    if times.len() < 4 {
+        return None;
+    }
+    // user nice system idle iowait irq softirq steal ..., idle and iowait are the idle ones
+    let total = times.iter().take(8).fold(0, |sum, time| sum + time);
+    let idle = times[3] + times.get(4).cloned().unwrap_or(0);
+    Some(CpuTimes { busy: total - idle, total: total })
+}
+
+// the hottest of the thermal zones in celsius; None where the OS doesn't report them
+pub fn read_temperature() -> Option<f32> {
+    let zones = fs::read_dir("/sys/class/thermal").ok()?;
+    zones.filter_map(|zone| zone.ok())
+        .filter(|zone| zone.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|zone| fs::read_to_string(zone.path().join("temp")).ok())
+        .filter_map(|millis| millis.trim().parse::<f32>().ok())
+        .map(|millis| millis / 1000.0)
+        .fold(None, |hottest: Option<f32>, celsius| Some(hottest.map_or(celsius, |hottest| hottest.max(celsius))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_times, utilization_between, CpuTimes, ThreadScaling, ThreadThrottle};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn slots_follow_the_load_and_the_heat() {
+        let mut scaling = ThreadScaling::new(8).with_target_utilization(0.5).with_thermal_limit(90.0);
+        // the machine is busy: one slot less per update, down to one
+        for expected in [7, 6, 5].iter() {
+            scaling.slots = scaling.next_slots(Some(0.9), Some(60.0));
+            assert_eq!(scaling.slots, *expected);
+        }
+        assert_eq!(scaling.next_slots(Some(0.52), Some(60.0)), 5);
+        assert_eq!(scaling.next_slots(Some(0.2), None), 6);
+        // too hot halves them, close to the limit holds them
+        scaling.slots = scaling.next_slots(Some(0.2), Some(95.0));
+        assert_eq!(scaling.slots, 2);
+        assert_eq!(scaling.next_slots(Some(0.2), Some(87.0)), 2);
+        assert_eq!(ThreadScaling::new(1).next_slots(Some(1.0), Some(100.0)), 1);
+        // only the heat: back up to all the threads once cool
+        let mut cooling = ThreadScaling::new(4).with_thermal_limit(90.0);
+        cooling.slots = 1;
+        assert_eq!(cooling.next_slots(None, Some(50.0)), 2);
+
+        let before = parse_cpu_times("cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 1 2 3 4\n").unwrap();
+        assert_eq!(before, CpuTimes { busy: 200, total: 1000 });
+        let after = CpuTimes { busy: 500, total: 1500 };
+        assert_eq!(utilization_between(before, after), Some(0.6));
+        assert_eq!(parse_cpu_times("intr 1 2 3"), None);
+    }
+
+    #[test]
+    fn throttle_caps_the_tiles_at_once() {
+        let throttle = Arc::new(ThreadThrottle::new(2));
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(Mutex::new(0)));
+        let tiles = (0..6).map(|_| {
+            let (throttle, running, most) = (throttle.clone(), running.clone(), most.clone());
+            thread::spawn(move || {
+                let _slot = throttle.acquire();
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut most = most.lock().unwrap();
+                    *most = (*most).max(now);
+                }
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect::<Vec<_>>();
+        for tile in tiles {
+            tile.join().unwrap();
+        }
+        assert_eq!(*most.lock().unwrap(), 2);
+        assert_eq!(throttle.active(), 0);
+        // a tile waiting for a slot starts once the limit goes up
+        let held = throttle.acquire();
+        throttle.set_limit(1);
+        let waiting = {
+            let throttle = throttle.clone();
+            thread::spawn(move || { let _slot = throttle.acquire(); })
+        };
+        throttle.set_limit(2);
+        waiting.join().unwrap();
+        drop(held);
+        assert_eq!(throttle.active(), 0);
+    }
+}
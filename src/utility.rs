@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use math::{Vec3f};
+use rand::{Rand, Rng};
 use std::f32::consts::{PI, FRAC_1_PI};
 
 pub fn luminance(a_rgb: &Vec3f) -> f32 {
@@ -28,12 +29,11 @@ pub fn uniform_hemisphere_pdf_w() -> f32 {
 }
 
 pub fn uniform_sphere_sample(rnd: (f32, f32)) -> Vec3f {
-    // rnd.1 - sin^2(theta)
     let phi = rnd.0 * 2.0 * PI;
-    let cos_2theta = 1.0 - 2.0 * rnd.1;
-    let sin_2theta = 2.0 * (rnd.1 - rnd.1 * rnd.1).sqrt();
+    let cos_theta = 1.0 - 2.0 * rnd.1;
+    let sin_theta = 2.0 * (rnd.1 - rnd.1 * rnd.1).sqrt();
 
-    Vec3f::new(2.0 * phi.cos() * sin_2theta, 2.0 * phi.sin() * sin_2theta, cos_2theta)
+    Vec3f::new(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta)
 }
 
 pub fn uniform_sphere_pdf_w() -> f32 {
@@ -63,3 +63,189 @@ pub fn pow_cos_hemisphere_sample(n: f32, rnd: (f32, f32)) -> Vec3f {
 pub fn pow_cos_hemisphere_pdf_w(n: f32, cos_theta: f32) -> f32 {
     cos_theta.powf(n) * (n + 1.0) * 0.5 * FRAC_1_PI
 }
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// stateless hash for procedural patterns, which can't depend on the order of samples
+pub fn hash_u64(value: u64) -> u64 {
+    let mut state = value;
+    splitmix64(&mut state)
+}
+
+// The PCG32 generator (O'Neill, pcg-random.org): a 64 bit LCG whose state is permuted into the output.
+// Small, fast, and the increment selects one of 2^63 streams, so every pixel and iteration gets its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64, // odd
+}
+
+impl Pcg32 {
+    // as pcg32_srandom_r of the reference implementation
+    pub fn new(init_state: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32 { state: 0, inc: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(init_state);
+        rng.next_u32();
+        rng
+    }
+}
+
+impl Rng for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+impl Rand for Pcg32 {
+    fn rand<R: Rng>(rng: &mut R) -> Pcg32 {
+        Pcg32::new(rng.next_u64(), rng.next_u64())
+    }
+}
+
+// independent, reproducible random stream number `stream` of the scene seed `seed`; the streams use
+// all 64 bits, the increment of the generator keeps 63, so both the state and the increment are drawn
+// from the hashed stream
+pub fn seeded_rng(seed: u32, stream: u64) -> Pcg32 {
+    let mut state = seed as u64;
+    state = splitmix64(&mut state) ^ stream;
+    let init_state = splitmix64(&mut state);
+    Pcg32::new(init_state, splitmix64(&mut state))
+}
+
+// Where the numbers of a path come from, so the integrators don't depend on how they're made: independent,
+// stratified or from a low discrepancy sequence. The screen loops make one per tile and start it for every
+// pixel (see `render::PixelSampler`), the light paths use plain rng streams. A dimension is the position of
+// a number in the path; jumping to one keeps the numbers of the later vertices in place when a vertex draws
+// fewer of them
+pub trait Sampler {
+    fn start_dimension(&mut self, dim: usize);
+    // a number of the whole u32 range
+    fn next_bits(&mut self) -> u32;
+
+    // from the high bits, so numbers close as integers stay close as floats
+    fn next_1d(&mut self) -> f32 {
+        (self.next_bits() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let x = self.next_1d();
+        (x, self.next_1d())
+    }
+
+    // an index below `n`, from the high bits as well, so a stratified number picks a stratified index
+    fn next_index(&mut self, n: usize) -> usize {
+        ((self.next_bits() as u64 * n as u64) >> 32) as usize
+    }
+}
+
+// a stream has no dimensions, the numbers follow one another
+impl Sampler for Pcg32 {
+    fn start_dimension(&mut self, _dim: usize) {}
+
+    fn next_bits(&mut self) -> u32 {
+        self.next_u32()
+    }
+}
+
+// index below `n` from the high bits of a number, as `Sampler::next_index`
+pub fn sample_index<R: Rng>(rng: &mut R, n: usize) -> usize {
+    ((rng.next_u32() as u64 * n as u64) >> 32) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Kolmogorov-Smirnov distance of the samples to the distribution with the `cdf`
+    fn ks_distance<F: Fn(f32) -> f32>(samples: &mut Vec<f32>, cdf: F) -> f32 {
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = samples.len() as f32;
+        samples.iter().enumerate().fold(0.0, |d: f32, (i, &x)| {
+            let cdf = cdf(x);
+            d.max((cdf - i as f32 / n).abs()).max(((i + 1) as f32 / n - cdf).abs())
+        })
+    }
+
+    // the distance the KS test rejects at the 1% level
+    fn ks_limit(n: usize) -> f32 {
+        1.63 / (n as f32).sqrt()
+    }
+
+    fn correlation(a: &[f32], b: &[f32]) -> f32 {
+        let n = a.len() as f32;
+        let (mean_a, mean_b) = (a.iter().fold(0.0, |s, x| s + x) / n, b.iter().fold(0.0, |s, x| s + x) / n);
+        let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for (x, y) in a.iter().zip(b.iter()) {
+            cov += (x - mean_a) * (y - mean_b);
+            var_a += (x - mean_a) * (x - mean_a);
+            var_b += (y - mean_b) * (y - mean_b);
+        }
+        cov / (var_a * var_b).sqrt()
+    }
+
+    fn draw(seed: u32, stream: u64, n: usize) -> Vec<f32> {
+        let mut rng = seeded_rng(seed, stream);
+        (0..n).map(|_| rng.next_f32()).collect()
+    }
+
+    #[test]
+    fn streams_are_uniform() {
+        let n = 10000;
+        // pixel, sample and the renderers' own streams
+        for &stream in [0, 1, (7 << 32) | 123, 1 << 63, (1 << 62) | 5, 1 << 57].iter() {
+            let d = ks_distance(&mut draw(17, stream, n), |x| x);
+            assert!(d < ks_limit(n), "stream {:x}: {}", stream, d);
+        }
+    }
+
+    #[test]
+    fn samplers_follow_their_distributions() {
+        let n = 10000;
+        let mut rng = seeded_rng(3, 0);
+        let mut cosines = |sample: &Fn((f32, f32)) -> Vec3f| (0..n).map(|_| sample((rng.next_f32(), rng.next_f32())).z).collect::<Vec<_>>();
+        let cos_theta_max = 0.8;
+        let cases: Vec<(Vec<f32>, Box<Fn(f32) -> f32>)> = vec![
+            (cosines(&|rnd| cos_hemisphere_sample(rnd)), Box::new(|t| t * t)),
+            (cosines(&|rnd| uniform_sphere_sample(rnd)), Box::new(|t| (t + 1.0) * 0.5)),
+            (cosines(&|rnd| uniform_cone_sample(cos_theta_max, rnd)), Box::new(move |t| (t - cos_theta_max) / (1.0 - cos_theta_max))),
+            (cosines(&|rnd| pow_cos_hemisphere_sample(20.0, rnd)), Box::new(|t| t.powf(21.0))),
+        ];
+        for (i, (mut samples, cdf)) in cases.into_iter().enumerate() {
+            let d = ks_distance(&mut samples, |t| cdf(t));
+            assert!(d < ks_limit(n), "sampler {}: {}", i, d);
+        }
+    }
+
+    #[test]
+    fn pcg32_matches_the_reference() {
+        // the first numbers of pcg32-demo, seeded with 42 and stream 54
+        let mut rng = Pcg32::new(42, 54);
+        let numbers = (0..6).map(|_| rng.next_u32()).collect::<Vec<_>>();
+        assert_eq!(numbers, [0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]);
+    }
+
+    #[test]
+    fn streams_are_independent() {
+        let n = 10000;
+        let limit = 4.0 / (n as f32).sqrt();
+        // neighbouring pixels, the same pixel in the next iteration and its path samples
+        let pixel = draw(5, (3 << 32) | 100, n);
+        for &stream in [(3 << 32) | 101, (4 << 32) | 100, (1 << 63) | (3 << 32) | 100].iter() {
+            let r = correlation(&pixel, &draw(5, stream, n));
+            assert!(r.abs() < limit, "stream {:x}: {}", stream, r);
+        }
+        // other seeds of the same stream
+        let r = correlation(&pixel, &draw(6, (3 << 32) | 100, n));
+        assert!(r.abs() < limit, "{}", r);
+    }
+}